@@ -0,0 +1,9 @@
+-- Add up migration script here
+create table auth.device_codes (
+  device_code text not null primary key,
+  user_code text unique not null,
+  user_id uuid references auth.users (id) on delete cascade,
+  expires timestamptz not null,
+  last_polled timestamptz,
+  created timestamptz not null default now()
+);
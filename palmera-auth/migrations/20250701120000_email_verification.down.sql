@@ -0,0 +1,5 @@
+-- Add down migration script here
+alter table auth.users
+  drop column email_verified,
+  drop column pending_email,
+  drop column verification_sent_at;
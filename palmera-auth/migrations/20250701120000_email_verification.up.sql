@@ -0,0 +1,5 @@
+-- Add up migration script here
+alter table auth.users
+  add column email_verified boolean not null default false,
+  add column pending_email text,
+  add column verification_sent_at timestamptz;
@@ -0,0 +1,2 @@
+-- Add down migration script here
+alter table auth.users drop column status;
@@ -0,0 +1,4 @@
+-- Add up migration script here
+alter table auth.users
+  add column status text not null default 'active'
+    check (status in ('active', 'disabled', 'locked', 'pending_deletion'));
@@ -0,0 +1,15 @@
+-- Add up migration script here
+create table auth.security_events (
+  id bigint generated always as identity primary key,
+  user_id uuid references auth.users (id) on delete cascade,
+  email text,
+  kind text not null check (kind in ('login', 'password_change', 'mfa')),
+  success boolean not null,
+  ip text,
+  user_agent text,
+  detail text,
+  created timestamptz not null default now()
+);
+
+create index security_events_user_id_created_idx on auth.security_events (user_id, created desc);
+create index security_events_created_idx on auth.security_events (created desc);
@@ -0,0 +1,18 @@
+-- Add down migration script here
+drop table auth.otp_codes;
+
+-- Users who signed up with a phone number have no email and nothing to sign in with
+-- once the phone columns are gone, so refuse to drop them silently.
+do $$
+begin
+  if exists (select 1 from auth.users where email is null) then
+    raise exception 'auth.users has users without an email; give them one or delete them first';
+  end if;
+end
+$$;
+
+alter table auth.users
+  drop constraint users_email_or_phone,
+  drop column phone,
+  drop column phone_verified,
+  alter column email set not null;
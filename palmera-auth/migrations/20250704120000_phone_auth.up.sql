@@ -0,0 +1,17 @@
+-- Add up migration script here
+alter table auth.users
+  alter column email drop not null,
+  add column phone text unique,
+  add column phone_verified boolean not null default false,
+  add constraint users_email_or_phone check (email is not null or phone is not null);
+
+create table auth.otp_codes (
+  id bigint generated always as identity primary key,
+  phone text not null,
+  code_hash text not null,
+  attempts integer not null default 0,
+  expires timestamptz not null,
+  created timestamptz not null default now()
+);
+
+create index otp_codes_phone_created_idx on auth.otp_codes (phone, created desc);
@@ -0,0 +1,14 @@
+-- Add up migration script here
+create table auth.providers (
+  name text not null primary key,
+  kind text not null check (kind in ('password', 'oauth', 'magic_link', 'passkey', 'phone')),
+  enabled boolean not null default true,
+  client_id text,
+  client_secret text,
+  config jsonb not null default '{}',
+  created timestamptz not null default now(),
+  updated timestamptz not null default now()
+);
+
+-- Methods built into the crate stay available until an operator turns them off.
+insert into auth.providers (name, kind) values ('password', 'password'), ('phone', 'phone');
@@ -0,0 +1,2 @@
+-- Add down migration script here
+alter table auth.security_events drop column actor_id;
@@ -0,0 +1,2 @@
+-- Add up migration script here
+alter table auth.security_events add column actor_id uuid;
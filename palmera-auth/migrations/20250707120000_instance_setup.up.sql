@@ -0,0 +1,9 @@
+-- Add up migration script here
+-- Written once by the first-run setup; the `id` check keeps it a single row.
+create table auth.instance (
+  id boolean not null primary key default true check (id),
+  name text not null,
+  storage text not null check (storage in ('local', 's3', 'memory')),
+  admin_id uuid references auth.users (id) on delete set null,
+  completed timestamptz not null default now()
+);
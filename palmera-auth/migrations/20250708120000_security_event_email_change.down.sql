@@ -0,0 +1,6 @@
+-- Add down migration script here
+delete from auth.security_events where kind = 'email_change';
+alter table auth.security_events
+  drop constraint security_events_kind_check,
+  add constraint security_events_kind_check
+    check (kind in ('login', 'password_change', 'mfa'));
@@ -0,0 +1,5 @@
+-- Add up migration script here
+alter table auth.security_events
+  drop constraint security_events_kind_check,
+  add constraint security_events_kind_check
+    check (kind in ('login', 'password_change', 'mfa', 'email_change'));
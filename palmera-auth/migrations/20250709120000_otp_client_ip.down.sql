@@ -0,0 +1,4 @@
+-- Add down migration script here
+drop index auth.otp_codes_ip_created_idx;
+
+alter table auth.otp_codes drop column ip;
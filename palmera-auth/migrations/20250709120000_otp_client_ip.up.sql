@@ -0,0 +1,4 @@
+-- Add up migration script here
+alter table auth.otp_codes add column ip text;
+
+create index otp_codes_ip_created_idx on auth.otp_codes (ip, created desc);
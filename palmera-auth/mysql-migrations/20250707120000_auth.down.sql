@@ -0,0 +1,7 @@
+-- Add down migration script here
+drop table auth_instance;
+drop table auth_providers;
+drop table auth_otp_codes;
+drop table auth_security_events;
+drop table auth_device_codes;
+drop table auth_users;
@@ -0,0 +1,81 @@
+-- Add up migration script here
+-- The auth schema of `migrations/` as of 20250708120000_security_event_email_change,
+-- for MySQL 8.0.13+ and MariaDB 10.5+. MySQL schemas are databases, so the tables live
+-- in the current database with an `auth_` prefix instead of in an `auth` schema. Foreign
+-- keys are declared per table, since MySQL ignores column level `references`.
+create table auth_users (
+  id char(36) not null primary key default (uuid()),
+  email varchar(255) unique,
+  password text not null,
+  created timestamp(6) not null default current_timestamp(6),
+  updated timestamp(6) not null default current_timestamp(6),
+  email_verified boolean not null default false,
+  pending_email varchar(255),
+  verification_sent_at timestamp(6) null,
+  status varchar(32) not null default 'active'
+    check (status in ('active', 'disabled', 'locked', 'pending_deletion')),
+  phone varchar(32) unique,
+  phone_verified boolean not null default false,
+  constraint users_email_or_phone check (email is not null or phone is not null)
+);
+
+create table auth_device_codes (
+  device_code varchar(255) not null primary key,
+  user_code varchar(64) unique not null,
+  user_id char(36),
+  expires timestamp(6) not null,
+  last_polled timestamp(6) null,
+  created timestamp(6) not null default current_timestamp(6),
+  foreign key (user_id) references auth_users (id) on delete cascade
+);
+
+create table auth_security_events (
+  id bigint not null auto_increment primary key,
+  user_id char(36),
+  email varchar(255),
+  kind varchar(32) not null
+    check (kind in ('login', 'password_change', 'mfa', 'email_change')),
+  success boolean not null,
+  ip varchar(64),
+  user_agent text,
+  detail text,
+  actor_id char(36),
+  created timestamp(6) not null default current_timestamp(6),
+  index security_events_user_id_created_idx (user_id, created desc),
+  index security_events_created_idx (created desc),
+  foreign key (user_id) references auth_users (id) on delete cascade
+);
+
+create table auth_otp_codes (
+  id bigint not null auto_increment primary key,
+  phone varchar(32) not null,
+  code_hash text not null,
+  attempts integer not null default 0,
+  expires timestamp(6) not null,
+  created timestamp(6) not null default current_timestamp(6),
+  index otp_codes_phone_created_idx (phone, created desc)
+);
+
+create table auth_providers (
+  name varchar(64) not null primary key,
+  kind varchar(32) not null check (kind in ('password', 'oauth', 'magic_link', 'passkey', 'phone')),
+  enabled boolean not null default true,
+  client_id text,
+  client_secret text,
+  config json not null default (json_object()),
+  created timestamp(6) not null default current_timestamp(6),
+  updated timestamp(6) not null default current_timestamp(6)
+);
+
+-- Methods built into the crate stay available until an operator turns them off.
+insert into auth_providers (name, kind) values ('password', 'password'), ('phone', 'phone');
+
+-- Written once by the first-run setup; the `id` check keeps it a single row.
+create table auth_instance (
+  id boolean not null primary key default true check (id = true),
+  name varchar(255) not null,
+  storage varchar(16) not null check (storage in ('local', 's3', 'memory')),
+  admin_id char(36),
+  completed timestamp(6) not null default current_timestamp(6),
+  foreign key (admin_id) references auth_users (id) on delete set null
+);
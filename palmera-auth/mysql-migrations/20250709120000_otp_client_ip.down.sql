@@ -0,0 +1,4 @@
+-- Add down migration script here
+alter table auth_otp_codes
+  drop index otp_codes_ip_created_idx,
+  drop column ip;
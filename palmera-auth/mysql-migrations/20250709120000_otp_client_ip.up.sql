@@ -0,0 +1,5 @@
+-- Add up migration script here
+-- Kept in step with `migrations/20250709120000_otp_client_ip`.
+alter table auth_otp_codes
+  add column ip varchar(64),
+  add index otp_codes_ip_created_idx (ip, created desc);
@@ -0,0 +1,370 @@
+//! # Auth admin endpoints
+//!
+//! Routes for operators managing users. The router does not perform any authorization
+//! of its own and is expected to be mounted behind an admin guard such as
+//! [`RequireAdmin`](crate::extract::RequireAdmin); it is not part of
+//! [`crate::router::router`].
+//!
+//! - `PUT /admin/users/{id}/status` changes the account status of a user (see
+//!   [`crate::status`]), rejecting transitions that are not allowed with `409`, and fires
+//!   [`AuthHooks::on_status_change`] when [`AuthHooks`] are in the request extensions.
+//! - `GET /admin/security-events` queries the security log, filtered by the query
+//!   parameters of [`SecurityEventFilter`].
+//! - `GET /admin/stats/signups` counts the users created per day over the last `days`
+//!   (30 by default), for dashboard widgets.
+//! - `GET /admin/auth-providers` lists the sign-in methods (see [`crate::providers`]),
+//!   `PUT /admin/auth-providers/{name}` creates or replaces one, rejecting invalid
+//!   settings with `400`, and `DELETE /admin/auth-providers/{name}` removes one. Client
+//!   secrets are write-only, and encrypted with the `Extension<AuthConfig>`.
+
+use axum::{
+    Extension, Json,
+    extract::{Path, Query},
+    http::StatusCode,
+};
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use utoipa::{IntoParams, ToSchema};
+use utoipa_axum::{router::OpenApiRouter, routes};
+use uuid::Uuid;
+
+use crate::{
+    AuthConfig,
+    hooks::AuthHooks,
+    openapi,
+    providers::{AuthProvider, AuthProviderPayload},
+    schemas::AuthUser,
+    security::{SecurityEvent, SecurityEventFilter},
+    status::{AccountStatus, AccountStatusChange},
+};
+
+#[derive(Debug, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct AccountStatusPayload {
+    pub status: AccountStatus,
+}
+
+/// Changes the account status of a user.
+#[utoipa::path(
+    put,
+    path = "/admin/users/{id}/status",
+    request_body = AccountStatusPayload,
+    responses((status = 200, body = AccountStatusPayload), (status = 404), (status = 409)),
+    security(("bearer_auth" = []))
+)]
+async fn set_user_status(
+    Extension(db): Extension<Pool<Postgres>>,
+    hooks: Option<Extension<AuthHooks>>,
+    Path(id): Path<Uuid>,
+    Json(payload): Json<AccountStatusPayload>,
+) -> Result<Json<AccountStatusPayload>, StatusCode> {
+    let user = AuthUser::find_by_id(&id.to_string(), &db)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    if !user.status.can_transition_to(payload.status) {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    let updated = user
+        .set_status(payload.status, &db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::CONFLICT)?;
+
+    if let Some(Extension(hooks)) = hooks {
+        hooks.status_changed(&AccountStatusChange {
+            user_id: updated.id,
+            from: user.status,
+            to: updated.status,
+        });
+    }
+
+    Ok(Json(AccountStatusPayload {
+        status: updated.status,
+    }))
+}
+
+/// Lists security events matching the query parameters, newest first.
+#[utoipa::path(
+    get,
+    path = "/admin/security-events",
+    responses((status = 200, body = Vec<SecurityEvent>)),
+    security(("bearer_auth" = []))
+)]
+async fn query_security_events(
+    Extension(db): Extension<Pool<Postgres>>,
+    Query(filter): Query<SecurityEventFilter>,
+) -> Result<Json<Vec<SecurityEvent>>, StatusCode> {
+    SecurityEvent::query(&filter, &db)
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Number of users created on a day (UTC).
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize, PartialEq, sqlx::FromRow)]
+pub struct SignupCount {
+    pub day: NaiveDate,
+    pub count: i64,
+}
+
+/// Counts the users created per day over the last `days` days, including today. Days
+/// without signups are included with a count of zero.
+pub async fn signups_per_day(days: u32, db: &Pool<Postgres>) -> sqlx::Result<Vec<SignupCount>> {
+    sqlx::query_as(
+        "SELECT day::date AS day, count(users.id) AS count \
+         FROM generate_series( \
+             (now() AT TIME ZONE 'utc')::date - ($1::int - 1), \
+             (now() AT TIME ZONE 'utc')::date, \
+             interval '1 day') AS day \
+         LEFT JOIN auth.users ON (users.created AT TIME ZONE 'utc')::date = day::date \
+         GROUP BY day ORDER BY day",
+    )
+    .bind(days.max(1) as i32)
+    .fetch_all(db)
+    .await
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct SignupStatsQuery {
+    /// Number of days to report, including today.
+    pub days: Option<u32>,
+}
+
+/// Counts the users created per day.
+#[utoipa::path(
+    get,
+    path = "/admin/stats/signups",
+    params(SignupStatsQuery),
+    responses((status = 200, body = Vec<SignupCount>)),
+    security(("bearer_auth" = []))
+)]
+async fn signup_stats(
+    Extension(db): Extension<Pool<Postgres>>,
+    Query(query): Query<SignupStatsQuery>,
+) -> Result<Json<Vec<SignupCount>>, StatusCode> {
+    signups_per_day(query.days.unwrap_or(30).min(366), &db)
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Lists all sign-in methods, enabled or not.
+#[utoipa::path(
+    get,
+    path = "/admin/auth-providers",
+    responses((status = 200, body = Vec<AuthProvider>)),
+    security(("bearer_auth" = []))
+)]
+async fn list_auth_providers(
+    Extension(db): Extension<Pool<Postgres>>,
+) -> Result<Json<Vec<AuthProvider>>, StatusCode> {
+    AuthProvider::list(&db)
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Creates a sign-in method or replaces its settings.
+#[utoipa::path(
+    put,
+    path = "/admin/auth-providers/{name}",
+    request_body = AuthProviderPayload,
+    responses((status = 200, body = AuthProvider), (status = 400)),
+    security(("bearer_auth" = []))
+)]
+async fn put_auth_provider(
+    Extension(db): Extension<Pool<Postgres>>,
+    Extension(config): Extension<AuthConfig>,
+    Path(name): Path<String>,
+    Json(payload): Json<AuthProviderPayload>,
+) -> Result<Json<AuthProvider>, (StatusCode, String)> {
+    let internal = |_| (StatusCode::INTERNAL_SERVER_ERROR, String::new());
+
+    let existing = AuthProvider::find(&name, &db).await.map_err(internal)?;
+    payload
+        .check(&name, existing.as_ref())
+        .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+
+    AuthProvider::upsert(&name, payload, &config, &db)
+        .await
+        .map(Json)
+        .map_err(internal)
+}
+
+/// Removes a sign-in method.
+#[utoipa::path(
+    delete,
+    path = "/admin/auth-providers/{name}",
+    responses((status = 204), (status = 404)),
+    security(("bearer_auth" = []))
+)]
+async fn delete_auth_provider(
+    Extension(db): Extension<Pool<Postgres>>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    match AuthProvider::delete(&name, &db).await {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+pub fn router() -> OpenApiRouter {
+    OpenApiRouter::with_openapi(openapi::document())
+        .routes(routes!(set_user_status))
+        .routes(routes!(query_security_events))
+        .routes(routes!(signup_stats))
+        .routes(routes!(list_auth_providers))
+        .routes(routes!(put_auth_provider, delete_auth_provider))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_set_user_status(db: Pool<Postgres>) -> anyhow::Result<()> {
+        let user = AuthUser::new("admin-status@example.com", "password")
+            .insert(&db)
+            .await?;
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let seen = changes.clone();
+        let hooks = AuthHooks::new().on_status_change(move |change| {
+            seen.lock().unwrap().push(*change);
+        });
+
+        let set = |status| {
+            set_user_status(
+                Extension(db.clone()),
+                Some(Extension(hooks.clone())),
+                Path(user.id),
+                Json(AccountStatusPayload { status }),
+            )
+        };
+
+        let Json(response) = set(AccountStatus::PendingDeletion).await.unwrap();
+        assert_eq!(response.status, AccountStatus::PendingDeletion);
+        assert_eq!(
+            set(AccountStatus::Locked).await.unwrap_err(),
+            StatusCode::CONFLICT
+        );
+        let Json(response) = set(AccountStatus::Active).await.unwrap();
+        assert_eq!(response.status, AccountStatus::Active);
+
+        assert_eq!(
+            *changes.lock().unwrap(),
+            [
+                AccountStatusChange {
+                    user_id: user.id,
+                    from: AccountStatus::Active,
+                    to: AccountStatus::PendingDeletion,
+                },
+                AccountStatusChange {
+                    user_id: user.id,
+                    from: AccountStatus::PendingDeletion,
+                    to: AccountStatus::Active,
+                },
+            ]
+        );
+
+        let missing = set_user_status(
+            Extension(db),
+            None,
+            Path(Uuid::new_v4()),
+            Json(AccountStatusPayload {
+                status: AccountStatus::Disabled,
+            }),
+        )
+        .await;
+        assert_eq!(missing.unwrap_err(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_query_security_events(db: Pool<Postgres>) -> anyhow::Result<()> {
+        use crate::security::SecurityEventKind;
+
+        for success in [true, false, false] {
+            SecurityEvent::new(SecurityEventKind::Login, success)
+                .with_email("someone@example.com")
+                .record(&db)
+                .await?;
+        }
+
+        let filter = SecurityEventFilter {
+            success: Some(false),
+            ..Default::default()
+        };
+        let Json(events) = query_security_events(Extension(db), Query(filter))
+            .await
+            .unwrap();
+        assert_eq!(events.len(), 2);
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_signup_stats(db: Pool<Postgres>) -> anyhow::Result<()> {
+        AuthUser::new("signup-today@example.com", "password")
+            .insert(&db)
+            .await?;
+        let old = AuthUser::new("signup-old@example.com", "password")
+            .insert(&db)
+            .await?;
+        sqlx::query("UPDATE auth.users SET created = now() - interval '2 days' WHERE id = $1")
+            .bind(old.id)
+            .execute(&db)
+            .await?;
+
+        let query = SignupStatsQuery { days: Some(3) };
+        let Json(counts) = signup_stats(Extension(db), Query(query)).await.unwrap();
+        assert_eq!(
+            counts.iter().map(|c| c.count).collect::<Vec<_>>(),
+            [1, 0, 1]
+        );
+        let today = chrono::Utc::now().date_naive();
+        assert_eq!(counts[2].day, today);
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_auth_providers(db: Pool<Postgres>) -> anyhow::Result<()> {
+        use crate::providers::AuthProviderKind;
+
+        let config = AuthConfig::builder()
+            .secret("0123456789abcdef0123456789abcdef")
+            .build()?;
+        let put = |name: &str, payload| {
+            put_auth_provider(
+                Extension(db.clone()),
+                Extension(config.clone()),
+                Path(name.to_string()),
+                Json(payload),
+            )
+        };
+
+        let missing_secret = put("github", AuthProviderPayload::new(AuthProviderKind::Oauth))
+            .await
+            .unwrap_err();
+        assert_eq!(missing_secret.0, StatusCode::BAD_REQUEST);
+
+        let Json(github) = put(
+            "github",
+            AuthProviderPayload::new(AuthProviderKind::Oauth).with_client("id", "secret"),
+        )
+        .await
+        .unwrap();
+        assert!(github.enabled);
+
+        let Json(providers) = list_auth_providers(Extension(db.clone())).await.unwrap();
+        assert_eq!(providers.len(), 3);
+
+        let delete =
+            |name: &str| delete_auth_provider(Extension(db.clone()), Path(name.to_string()));
+        assert_eq!(delete("github").await.unwrap(), StatusCode::NO_CONTENT);
+        assert_eq!(delete("github").await.unwrap_err(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+}
@@ -0,0 +1,45 @@
+//! Operator commands for Palmera auth.
+//!
+//! ```text
+//! palmera-auth rotate-secret [--grace SECONDS]
+//! ```
+//!
+//! `rotate-secret` loads the auth configuration from the environment (see
+//! [`AuthConfig::from_env`]), rotates to a newly generated secret and prints the
+//! environment of the rotated configuration, to be applied before restarting the app.
+
+use chrono::Duration;
+use palmera_auth::{AuthConfig, config::generate_secret};
+
+const USAGE: &str = "usage: palmera-auth rotate-secret [--grace SECONDS]";
+
+fn rotate_secret(grace: Option<Duration>) -> anyhow::Result<()> {
+    let mut config = AuthConfig::from_env()?;
+    if let Some(grace) = grace {
+        config = config.with_rotation_grace(grace)?;
+    }
+
+    for (name, value) in config.rotate(&generate_secret())?.env_vars() {
+        println!("{}={}", name, value);
+    }
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    match args[..] {
+        ["rotate-secret"] => rotate_secret(None),
+        ["rotate-secret", "--grace", seconds] => {
+            let seconds = seconds
+                .parse()
+                .map_err(|_| anyhow::anyhow!("--grace must be a number of seconds"))?;
+            rotate_secret(Some(Duration::seconds(seconds)))
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    }
+}
@@ -0,0 +1,284 @@
+//! # Bot challenges
+//!
+//! With [`AuthConfig::require_challenge`], the routes that sign users in or create
+//! accounts (`POST /login`, `POST /session`, `POST /device` and `POST /otp/request`)
+//! only accept requests that passed a CAPTCHA such as Cloudflare Turnstile, hCaptcha or
+//! reCAPTCHA. The client renders the provider's widget and sends the token it produced
+//! in the [`CHALLENGE_HEADER`] header; the [`Challenge`] extractor hands it to the
+//! [`ChallengeVerifier`] found in the request extensions. Requests without a token, or
+//! whose token the provider rejects, are rejected with `403`, and with `502` if the
+//! provider cannot be reached.
+//!
+//! With the `captcha` feature, [`SiteVerify`] checks tokens with the `siteverify` API
+//! shared by Turnstile, hCaptcha and reCAPTCHA.
+//!
+//! # Example
+//!
+//! ```rust
+//! use std::{net::IpAddr, sync::Arc};
+//!
+//! use axum::Extension;
+//! use palmera_auth::challenge::{ChallengeVerifier, VerifyFuture};
+//!
+//! /// Accepts a fixed token, e.g. in end-to-end tests.
+//! struct FixedToken(&'static str);
+//!
+//! impl ChallengeVerifier for FixedToken {
+//!     fn name(&self) -> &str {
+//!         "fixed"
+//!     }
+//!
+//!     fn verify<'a>(&'a self, token: &'a str, _remote_ip: Option<IpAddr>) -> VerifyFuture<'a> {
+//!         Box::pin(async move { Ok(token == self.0) })
+//!     }
+//! }
+//!
+//! let verifier: Arc<dyn ChallengeVerifier> = Arc::new(FixedToken("e2e"));
+//! let router = palmera_auth::router::router().layer(Extension(verifier));
+//! ```
+
+use std::{future::Future, net::IpAddr, pin::Pin, sync::Arc};
+
+use axum::{
+    extract::FromRequestParts,
+    http::{StatusCode, request::Parts},
+};
+
+use crate::{AuthConfig, extract::ClientInfo};
+
+/// Header carrying the token produced by the challenge widget.
+pub const CHALLENGE_HEADER: &str = "x-challenge-token";
+
+/// Future returned by [`ChallengeVerifier::verify`].
+pub type VerifyFuture<'a> = Pin<Box<dyn Future<Output = anyhow::Result<bool>> + Send + 'a>>;
+
+/// Checks challenge tokens with a CAPTCHA provider.
+///
+/// Routes look the verifier up as an `Extension<Arc<dyn ChallengeVerifier>>`.
+pub trait ChallengeVerifier: Send + Sync {
+    /// Name of the provider, used in error messages.
+    fn name(&self) -> &str;
+
+    /// Resolves to whether `token` is a valid, unused token, solved from `remote_ip`
+    /// if known. Errors mean the provider could not be asked.
+    fn verify<'a>(&'a self, token: &'a str, remote_ip: Option<IpAddr>) -> VerifyFuture<'a>;
+}
+
+/// Proof that a request passed the bot challenge, or that none is required.
+///
+/// Rejects the request with `403` without a valid token in [`CHALLENGE_HEADER`] when
+/// [`AuthConfig::require_challenge`] is set, and with `500` if the router lacks the
+/// config or the verifier.
+#[derive(Debug, Clone, Copy)]
+pub struct Challenge(());
+
+impl Challenge {
+    #[cfg(test)]
+    pub(crate) fn passed() -> Self {
+        Self(())
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Challenge {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let config = parts
+            .extensions
+            .get::<AuthConfig>()
+            .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        if !config.require_challenge() {
+            return Ok(Self(()));
+        }
+
+        let verifier = parts
+            .extensions
+            .get::<Arc<dyn ChallengeVerifier>>()
+            .cloned()
+            .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        let token = parts
+            .headers
+            .get(CHALLENGE_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|token| !token.is_empty())
+            .ok_or(StatusCode::FORBIDDEN)?
+            .to_string();
+
+        let Ok(client) = ClientInfo::from_request_parts(parts, state).await;
+
+        match verifier.verify(&token, client.ip).await {
+            Ok(true) => Ok(Self(())),
+            Ok(false) => Err(StatusCode::FORBIDDEN),
+            Err(_) => Err(StatusCode::BAD_GATEWAY),
+        }
+    }
+}
+
+/// Checks tokens with the `siteverify` API of Cloudflare Turnstile, hCaptcha or Google
+/// reCAPTCHA.
+#[cfg(feature = "captcha")]
+#[derive(Clone)]
+pub struct SiteVerify {
+    name: &'static str,
+    url: &'static str,
+    secret: String,
+    min_score: Option<f64>,
+    client: reqwest::Client,
+}
+
+#[cfg(feature = "captcha")]
+#[derive(serde::Deserialize)]
+struct SiteVerifyResponse {
+    success: bool,
+    /// Only returned by reCAPTCHA v3 and hCaptcha Enterprise.
+    score: Option<f64>,
+}
+
+#[cfg(feature = "captcha")]
+impl SiteVerify {
+    fn new(name: &'static str, url: &'static str, secret: &str) -> Self {
+        Self {
+            name,
+            url,
+            secret: secret.to_string(),
+            min_score: None,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Cloudflare Turnstile, with the widget's secret key.
+    pub fn turnstile(secret: &str) -> Self {
+        Self::new(
+            "turnstile",
+            "https://challenges.cloudflare.com/turnstile/v0/siteverify",
+            secret,
+        )
+    }
+
+    /// hCaptcha, with the account's secret key.
+    pub fn hcaptcha(secret: &str) -> Self {
+        Self::new("hcaptcha", "https://api.hcaptcha.com/siteverify", secret)
+    }
+
+    /// Google reCAPTCHA, with the site's secret key.
+    pub fn recaptcha(secret: &str) -> Self {
+        Self::new(
+            "recaptcha",
+            "https://www.google.com/recaptcha/api/siteverify",
+            secret,
+        )
+    }
+
+    /// Also rejects tokens scored below `score`, from `0.0` (likely a bot) to `1.0`.
+    /// Tokens without a score are rejected too.
+    pub fn with_min_score(mut self, score: f64) -> Self {
+        self.min_score = Some(score);
+        self
+    }
+}
+
+#[cfg(feature = "captcha")]
+impl std::fmt::Debug for SiteVerify {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SiteVerify")
+            .field("name", &self.name)
+            .field("min_score", &self.min_score)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "captcha")]
+impl ChallengeVerifier for SiteVerify {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn verify<'a>(&'a self, token: &'a str, remote_ip: Option<IpAddr>) -> VerifyFuture<'a> {
+        Box::pin(async move {
+            let mut form = vec![
+                ("secret", self.secret.clone()),
+                ("response", token.to_string()),
+            ];
+            if let Some(ip) = remote_ip {
+                form.push(("remoteip", ip.to_string()));
+            }
+
+            let response = self.client.post(self.url).form(&form).send().await?;
+            let status = response.status();
+            if !status.is_success() {
+                anyhow::bail!("{} responded with {}", self.name, status);
+            }
+
+            let result: SiteVerifyResponse = response.json().await?;
+            let scored = match self.min_score {
+                Some(min_score) => result.score.is_some_and(|score| score >= min_score),
+                None => true,
+            };
+
+            Ok(result.success && scored)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Extension, Router, body::Body, http::Request, routing::post};
+    use tower::ServiceExt;
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+    struct FixedToken(&'static str);
+
+    impl ChallengeVerifier for FixedToken {
+        fn name(&self) -> &str {
+            "fixed"
+        }
+
+        fn verify<'a>(&'a self, token: &'a str, _remote_ip: Option<IpAddr>) -> VerifyFuture<'a> {
+            Box::pin(async move {
+                if token == "unreachable" {
+                    anyhow::bail!("provider down");
+                }
+                Ok(token == self.0)
+            })
+        }
+    }
+
+    async fn status(required: bool, token: Option<&str>) -> StatusCode {
+        let config = AuthConfig::builder()
+            .secret(SECRET)
+            .require_challenge(required)
+            .build()
+            .unwrap();
+        let verifier: Arc<dyn ChallengeVerifier> = Arc::new(FixedToken("solved"));
+        let app = Router::new()
+            .route("/login", post(|_: Challenge| async { StatusCode::OK }))
+            .layer(Extension(verifier))
+            .layer(Extension(config));
+
+        let mut request = Request::post("/login");
+        if let Some(token) = token {
+            request = request.header(CHALLENGE_HEADER, token);
+        }
+        let response = app
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        response.status()
+    }
+
+    #[tokio::test]
+    async fn test_challenge() {
+        assert_eq!(status(false, None).await, StatusCode::OK);
+        assert_eq!(status(true, None).await, StatusCode::FORBIDDEN);
+        assert_eq!(status(true, Some("guess")).await, StatusCode::FORBIDDEN);
+        assert_eq!(
+            status(true, Some("unreachable")).await,
+            StatusCode::BAD_GATEWAY
+        );
+        assert_eq!(status(true, Some("solved")).await, StatusCode::OK);
+    }
+}
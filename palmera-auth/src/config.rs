@@ -0,0 +1,846 @@
+//! # Auth configuration
+//!
+//! [`AuthConfig`] holds the values used to mint and verify tokens. It is created through
+//! [`AuthConfig::builder`] or loaded from the environment with [`AuthConfig::from_env`];
+//! both validate the configuration, rejecting empty values and secrets shorter than
+//! [`MIN_SECRET_LENGTH`] bytes. The clock skew tolerated when verifying tokens defaults to
+//! [`DEFAULT_LEEWAY`].
+//!
+//! # Rotating the secret
+//!
+//! [`AuthConfig::rotate`] switches to a new signing secret. The previous one is kept as
+//! a [`RetiredKey`] and still verifies tokens for the config's rotation grace window,
+//! [`DEFAULT_ROTATION_GRACE`] by default, so tokens issued before the rotation stay
+//! valid until they are refreshed. The `palmera-auth rotate-secret` command prints the
+//! environment for a rotated configuration:
+//!
+//! ```text
+//! $ palmera-auth rotate-secret --grace 3600
+//! PALMERA_AUTH_SECRET=5f1d...
+//! PALMERA_AUTH_PREVIOUS_SECRET=0123...
+//! PALMERA_AUTH_PREVIOUS_SECRET_RETIRED_AT=2025-07-04T12:00:00+00:00
+//! PALMERA_AUTH_ROTATION_GRACE=3600
+//! ```
+//!
+//! # Example
+//!
+//! ```rust
+//! use palmera_auth::AuthConfig;
+//!
+//! let config = AuthConfig::builder()
+//!     .issuer("https://api.example.com")
+//!     .audience("example-app")
+//!     .secret("a-secret-that-is-at-least-32-bytes-long")
+//!     .build()
+//!     .unwrap();
+//!
+//! assert_eq!(config.issuer(), "https://api.example.com");
+//! assert!(AuthConfig::builder().secret("too short").build().is_err());
+//! ```
+
+use anyhow::{anyhow, bail};
+use chrono::{DateTime, Duration, Utc};
+use uuid::Uuid;
+
+use crate::{
+    jwt::{DEFAULT_LEEWAY, JWTClaims, VerifyOptions},
+    scope::Scopes,
+    urls::UrlBuilder,
+};
+
+/// Minimum length of the signing secret in bytes.
+pub const MIN_SECRET_LENGTH: usize = 32;
+
+/// Issuer and audience used when none are configured.
+const DEFAULT_ISSUER: &str = "palmera";
+const DEFAULT_AUDIENCE: &str = "palmera";
+
+/// Where users enter device codes when none is configured; relative to the app's URL.
+const DEFAULT_DEVICE_VERIFICATION_URI: &str = "/auth/device";
+
+/// Minimum delay between two verification emails to the same user when none is
+/// configured.
+pub const DEFAULT_VERIFICATION_COOLDOWN: Duration = Duration::seconds(60);
+
+/// How long a retired secret still verifies tokens when no grace window is configured.
+pub const DEFAULT_ROTATION_GRACE: Duration = Duration::days(1);
+
+/// A signing secret replaced by [`AuthConfig::rotate`]. It no longer signs tokens, but
+/// verifies them until the rotation grace window after `retired_at` ends. Its `Debug`
+/// output leaves out the secret.
+#[derive(Clone)]
+pub struct RetiredKey {
+    pub(crate) key: String,
+    pub(crate) retired_at: DateTime<Utc>,
+}
+
+impl std::fmt::Debug for RetiredKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RetiredKey")
+            .field("retired_at", &self.retired_at)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RetiredKey {
+    pub fn retired_at(&self) -> DateTime<Utc> {
+        self.retired_at
+    }
+}
+
+/// Settings of the auth routes and the tokens they issue. Its `Debug` output leaves out
+/// the signing secret and the retired ones.
+#[derive(Clone)]
+pub struct AuthConfig {
+    pub(crate) issuer: String,
+    pub(crate) audience: String,
+    pub(crate) key: String,
+    pub(crate) retired_keys: Vec<RetiredKey>,
+    pub(crate) rotation_grace: Duration,
+    pub(crate) leeway: Duration,
+    pub(crate) device_verification_uri: String,
+    pub(crate) cookie_sessions: bool,
+    pub(crate) require_verified_email: bool,
+    pub(crate) verification_cooldown: Duration,
+    pub(crate) require_challenge: bool,
+    pub(crate) urls: Option<UrlBuilder>,
+}
+
+impl std::fmt::Debug for AuthConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuthConfig")
+            .field("issuer", &self.issuer)
+            .field("audience", &self.audience)
+            .field("retired_keys", &self.retired_keys)
+            .field("rotation_grace", &self.rotation_grace)
+            .field("leeway", &self.leeway)
+            .field("device_verification_uri", &self.device_verification_uri)
+            .field("cookie_sessions", &self.cookie_sessions)
+            .field("require_verified_email", &self.require_verified_email)
+            .field("verification_cooldown", &self.verification_cooldown)
+            .field("require_challenge", &self.require_challenge)
+            .field("urls", &self.urls)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AuthConfig {
+    pub fn builder() -> AuthConfigBuilder {
+        AuthConfigBuilder::default()
+    }
+
+    /// Loads the configuration from `PALMERA_AUTH_ISSUER`, `PALMERA_AUTH_AUDIENCE`,
+    /// `PALMERA_AUTH_SECRET`, `PALMERA_AUTH_LEEWAY` (in seconds),
+    /// `PALMERA_AUTH_REQUIRE_VERIFIED_EMAIL` and `PALMERA_AUTH_REQUIRE_CHALLENGE` (`true`
+    /// or `false`). Only the secret is required.
+    ///
+    /// A rotated secret is read from `PALMERA_AUTH_PREVIOUS_SECRET` and
+    /// `PALMERA_AUTH_PREVIOUS_SECRET_RETIRED_AT` (RFC 3339), with the grace window in
+    /// `PALMERA_AUTH_ROTATION_GRACE` (in seconds); see [`Self::env_vars`].
+    pub fn from_env() -> anyhow::Result<Self> {
+        let mut builder = Self::builder();
+
+        if let Ok(issuer) = std::env::var("PALMERA_AUTH_ISSUER") {
+            builder = builder.issuer(&issuer);
+        }
+        if let Ok(audience) = std::env::var("PALMERA_AUTH_AUDIENCE") {
+            builder = builder.audience(&audience);
+        }
+        if let Ok(leeway) = std::env::var("PALMERA_AUTH_LEEWAY") {
+            let seconds = leeway
+                .parse()
+                .map_err(|_| anyhow!("PALMERA_AUTH_LEEWAY must be a number of seconds"))?;
+            builder = builder.leeway(Duration::seconds(seconds));
+        }
+        if let Ok(required) = std::env::var("PALMERA_AUTH_REQUIRE_VERIFIED_EMAIL") {
+            let required = required.parse().map_err(|_| {
+                anyhow!("PALMERA_AUTH_REQUIRE_VERIFIED_EMAIL must be `true` or `false`")
+            })?;
+            builder = builder.require_verified_email(required);
+        }
+        if let Ok(required) = std::env::var("PALMERA_AUTH_REQUIRE_CHALLENGE") {
+            let required = required
+                .parse()
+                .map_err(|_| anyhow!("PALMERA_AUTH_REQUIRE_CHALLENGE must be `true` or `false`"))?;
+            builder = builder.require_challenge(required);
+        }
+        if let Ok(grace) = std::env::var("PALMERA_AUTH_ROTATION_GRACE") {
+            let seconds = grace
+                .parse()
+                .map_err(|_| anyhow!("PALMERA_AUTH_ROTATION_GRACE must be a number of seconds"))?;
+            builder = builder.rotation_grace(Duration::seconds(seconds));
+        }
+        if let Ok(previous) = std::env::var("PALMERA_AUTH_PREVIOUS_SECRET") {
+            let retired_at = std::env::var("PALMERA_AUTH_PREVIOUS_SECRET_RETIRED_AT")
+                .map_err(|_| anyhow!("PALMERA_AUTH_PREVIOUS_SECRET_RETIRED_AT is not set"))?;
+            let retired_at = DateTime::parse_from_rfc3339(&retired_at).map_err(|_| {
+                anyhow!("PALMERA_AUTH_PREVIOUS_SECRET_RETIRED_AT must be an RFC 3339 date")
+            })?;
+            builder = builder.previous_secret(&previous, retired_at.to_utc());
+        }
+        let secret = std::env::var("PALMERA_AUTH_SECRET")
+            .map_err(|_| anyhow!("PALMERA_AUTH_SECRET is not set"))?;
+
+        builder.secret(&secret).build()
+    }
+
+    /// Returns a copy of the config signing with `secret`, e.g. one read from a secret
+    /// store. Fails if the secret is shorter than [`MIN_SECRET_LENGTH`] bytes.
+    pub fn with_secret(&self, secret: &str) -> anyhow::Result<Self> {
+        check_secret(secret)?;
+
+        Ok(Self {
+            key: secret.to_string(),
+            ..self.clone()
+        })
+    }
+
+    /// Returns a copy of the config signing with `secret`, keeping the current secret
+    /// as a [`RetiredKey`] for the rotation grace window. Retired keys past their window
+    /// are dropped. Rotating to the current secret returns the config unchanged.
+    pub fn rotate(&self, secret: &str) -> anyhow::Result<Self> {
+        check_secret(secret)?;
+        if secret == self.key {
+            return Ok(self.clone());
+        }
+
+        let now = Utc::now();
+        let mut retired_keys = vec![RetiredKey {
+            key: self.key.clone(),
+            retired_at: now,
+        }];
+        retired_keys.extend(
+            self.retired_keys
+                .iter()
+                .filter(|retired| self.accepts(retired, now) && retired.key != secret)
+                .cloned(),
+        );
+
+        Ok(Self {
+            key: secret.to_string(),
+            retired_keys,
+            ..self.clone()
+        })
+    }
+
+    /// Secrets replaced by [`Self::rotate`], most recently retired first.
+    pub fn retired_keys(&self) -> &[RetiredKey] {
+        &self.retired_keys
+    }
+
+    /// How long a retired secret still verifies tokens.
+    pub fn rotation_grace(&self) -> Duration {
+        self.rotation_grace
+    }
+
+    /// Returns a copy of the config with a rotation grace window of `grace`.
+    pub fn with_rotation_grace(&self, grace: Duration) -> anyhow::Result<Self> {
+        if grace < Duration::zero() {
+            bail!("Rotation grace window must not be negative");
+        }
+
+        Ok(Self {
+            rotation_grace: grace,
+            ..self.clone()
+        })
+    }
+
+    fn accepts(&self, retired: &RetiredKey, now: DateTime<Utc>) -> bool {
+        now <= retired.retired_at + self.rotation_grace
+    }
+
+    /// Environment variables reproducing this config's secrets for
+    /// [`Self::from_env`]; only the most recently retired secret is included.
+    pub fn env_vars(&self) -> Vec<(&'static str, String)> {
+        let mut vars = vec![("PALMERA_AUTH_SECRET", self.key.clone())];
+        if let Some(retired) = self.retired_keys.first() {
+            vars.push(("PALMERA_AUTH_PREVIOUS_SECRET", retired.key.clone()));
+            vars.push((
+                "PALMERA_AUTH_PREVIOUS_SECRET_RETIRED_AT",
+                retired.retired_at.to_rfc3339(),
+            ));
+        }
+        vars.push((
+            "PALMERA_AUTH_ROTATION_GRACE",
+            self.rotation_grace.num_seconds().to_string(),
+        ));
+        vars
+    }
+
+    pub fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    pub fn audience(&self) -> &str {
+        &self.audience
+    }
+
+    pub fn leeway(&self) -> Duration {
+        self.leeway
+    }
+
+    /// Whether browser sessions carried in cookies are enabled; see [`crate::session`].
+    pub fn cookie_sessions(&self) -> bool {
+        self.cookie_sessions
+    }
+
+    /// Whether users must verify their email before they can sign in; see
+    /// [`crate::verification`].
+    pub fn require_verified_email(&self) -> bool {
+        self.require_verified_email
+    }
+
+    /// Minimum delay between two verification emails to the same user.
+    pub fn verification_cooldown(&self) -> Duration {
+        self.verification_cooldown
+    }
+
+    /// Whether sign-in and sign-up requests must pass a bot challenge; see
+    /// [`crate::challenge`].
+    pub fn require_challenge(&self) -> bool {
+        self.require_challenge
+    }
+
+    /// Page shown to users of the device authorization flow.
+    pub fn device_verification_uri(&self) -> &str {
+        &self.device_verification_uri
+    }
+
+    /// Builder of the absolute links the auth routes hand out, if configured.
+    pub fn urls(&self) -> Option<&UrlBuilder> {
+        self.urls.as_ref()
+    }
+
+    /// Options for verifying tokens against this config: its leeway, and its issuer
+    /// and audience as the expected values.
+    pub fn verify_options(&self) -> VerifyOptions {
+        VerifyOptions::default()
+            .with_leeway(self.leeway)
+            .with_issuer(&self.issuer)
+            .with_audience(&self.audience)
+    }
+
+    /// Verifies a token signed with this config's secret, or a retired secret within
+    /// its grace window, with [`Self::verify_options`].
+    pub fn verify_token(&self, token: &str) -> anyhow::Result<JWTClaims> {
+        self.verify_token_with(token, &self.verify_options())
+    }
+
+    /// Like [`Self::verify_token`], applying the checks in `options`.
+    pub fn verify_token_with(
+        &self,
+        token: &str,
+        options: &VerifyOptions,
+    ) -> anyhow::Result<JWTClaims> {
+        let now = Utc::now();
+        let result = JWTClaims::verify_at(token, &self.key, options, now);
+        if result.is_ok() {
+            return result;
+        }
+
+        self.retired_keys
+            .iter()
+            .filter(|retired| self.accepts(retired, now))
+            .find_map(|retired| JWTClaims::verify_at(token, &retired.key, options, now).ok())
+            .map_or(result, Ok)
+    }
+
+    /// Signs a token for `subject` valid for `ttl`, with this config's issuer and
+    /// audience.
+    pub fn issue_token(&self, subject: Uuid, ttl: Duration) -> anyhow::Result<String> {
+        JWTClaims::new(subject, ttl, self.issuer.clone(), self.audience.clone()).sign(&self.key)
+    }
+
+    /// Like [`Self::issue_token`], restricting the token to `scopes`, e.g. for an
+    /// integration that only needs to read records.
+    pub fn issue_scoped_token(
+        &self,
+        subject: Uuid,
+        ttl: Duration,
+        scopes: Scopes,
+    ) -> anyhow::Result<String> {
+        JWTClaims::new(subject, ttl, self.issuer.clone(), self.audience.clone())
+            .with_scopes(scopes)
+            .sign(&self.key)
+    }
+
+    /// Like [`Self::issue_token`], for `actor` acting on behalf of `subject`, e.g. an
+    /// admin impersonating a user. See [`JWTClaims::actor_id`].
+    pub fn issue_impersonation_token(
+        &self,
+        subject: Uuid,
+        actor: Uuid,
+        ttl: Duration,
+    ) -> anyhow::Result<String> {
+        JWTClaims::new(subject, ttl, self.issuer.clone(), self.audience.clone())
+            .with_actor(actor)
+            .sign(&self.key)
+    }
+
+    /// Token for the API key `key_id` acting on behalf of `subject`, restricted to
+    /// `scopes`. Audit records name the key as the actor, see [`JWTClaims::actor_id`].
+    pub fn issue_api_key_token(
+        &self,
+        subject: Uuid,
+        key_id: Uuid,
+        ttl: Duration,
+        scopes: Scopes,
+    ) -> anyhow::Result<String> {
+        JWTClaims::new(subject, ttl, self.issuer.clone(), self.audience.clone())
+            .with_scopes(scopes)
+            .with_actor(key_id)
+            .sign(&self.key)
+    }
+}
+
+/// Builds an [`AuthConfig`]. Like the config, its `Debug` output leaves out the secrets.
+#[derive(Clone, Default)]
+pub struct AuthConfigBuilder {
+    issuer: Option<String>,
+    audience: Option<String>,
+    secret: Option<String>,
+    previous_secrets: Vec<RetiredKey>,
+    rotation_grace: Option<Duration>,
+    leeway: Option<Duration>,
+    device_verification_uri: Option<String>,
+    cookie_sessions: bool,
+    require_verified_email: bool,
+    verification_cooldown: Option<Duration>,
+    require_challenge: bool,
+    urls: Option<UrlBuilder>,
+}
+
+impl std::fmt::Debug for AuthConfigBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuthConfigBuilder")
+            .field("issuer", &self.issuer)
+            .field("audience", &self.audience)
+            .field("previous_secrets", &self.previous_secrets)
+            .field("rotation_grace", &self.rotation_grace)
+            .field("leeway", &self.leeway)
+            .field("device_verification_uri", &self.device_verification_uri)
+            .field("cookie_sessions", &self.cookie_sessions)
+            .field("require_verified_email", &self.require_verified_email)
+            .field("verification_cooldown", &self.verification_cooldown)
+            .field("require_challenge", &self.require_challenge)
+            .field("urls", &self.urls)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AuthConfigBuilder {
+    pub fn issuer(mut self, issuer: &str) -> Self {
+        self.issuer = Some(issuer.to_string());
+        self
+    }
+
+    pub fn audience(mut self, audience: &str) -> Self {
+        self.audience = Some(audience.to_string());
+        self
+    }
+
+    /// HMAC secret used to sign tokens.
+    pub fn secret(mut self, secret: &str) -> Self {
+        self.secret = Some(secret.to_string());
+        self
+    }
+
+    /// Secret replaced at `retired_at`, still verifying tokens for the rotation grace
+    /// window. Can be given several times.
+    pub fn previous_secret(mut self, secret: &str, retired_at: DateTime<Utc>) -> Self {
+        self.previous_secrets.push(RetiredKey {
+            key: secret.to_string(),
+            retired_at,
+        });
+        self
+    }
+
+    /// How long previous secrets still verify tokens, [`DEFAULT_ROTATION_GRACE`] by
+    /// default.
+    pub fn rotation_grace(mut self, grace: Duration) -> Self {
+        self.rotation_grace = Some(grace);
+        self
+    }
+
+    /// Clock skew tolerated when checking token expiry, [`DEFAULT_LEEWAY`] by default.
+    pub fn leeway(mut self, leeway: Duration) -> Self {
+        self.leeway = Some(leeway);
+        self
+    }
+
+    /// Public URL of the device verification page, e.g.
+    /// `https://api.example.com/auth/device`; `/auth/device` by default.
+    pub fn device_verification_uri(mut self, uri: &str) -> Self {
+        self.device_verification_uri = Some(uri.to_string());
+        self
+    }
+
+    /// Enables browser sessions carried in cookies, off by default. Routes accepting
+    /// them must be protected against CSRF; see [`crate::session`].
+    pub fn cookie_sessions(mut self, enabled: bool) -> Self {
+        self.cookie_sessions = enabled;
+        self
+    }
+
+    /// Rejects sign-ins of users who have not verified their email, off by default.
+    pub fn require_verified_email(mut self, required: bool) -> Self {
+        self.require_verified_email = required;
+        self
+    }
+
+    /// Minimum delay between two verification emails to the same user,
+    /// [`DEFAULT_VERIFICATION_COOLDOWN`] by default.
+    pub fn verification_cooldown(mut self, cooldown: Duration) -> Self {
+        self.verification_cooldown = Some(cooldown);
+        self
+    }
+
+    /// Requires sign-in and sign-up requests to pass a bot challenge, off by default.
+    /// The router must then be layered with a [`crate::challenge::ChallengeVerifier`].
+    pub fn require_challenge(mut self, required: bool) -> Self {
+        self.require_challenge = required;
+        self
+    }
+
+    /// Makes the links the auth routes hand out, such as the device verification URI,
+    /// absolute URLs on the instance's public address; relative by default.
+    pub fn urls(mut self, urls: UrlBuilder) -> Self {
+        self.urls = Some(urls);
+        self
+    }
+
+    pub fn build(self) -> anyhow::Result<AuthConfig> {
+        let issuer = self.issuer.unwrap_or_else(|| DEFAULT_ISSUER.to_string());
+        let audience = self
+            .audience
+            .unwrap_or_else(|| DEFAULT_AUDIENCE.to_string());
+        let key = self
+            .secret
+            .ok_or_else(|| anyhow!("Auth secret is required"))?;
+
+        if issuer.trim().is_empty() {
+            bail!("Auth issuer must not be empty");
+        }
+        if audience.trim().is_empty() {
+            bail!("Auth audience must not be empty");
+        }
+        let leeway = self.leeway.unwrap_or(DEFAULT_LEEWAY);
+        if leeway < Duration::zero() {
+            bail!("Auth leeway must not be negative");
+        }
+        let verification_cooldown = self
+            .verification_cooldown
+            .unwrap_or(DEFAULT_VERIFICATION_COOLDOWN);
+        if verification_cooldown < Duration::zero() {
+            bail!("Verification cooldown must not be negative");
+        }
+        check_secret(&key)?;
+
+        let rotation_grace = self.rotation_grace.unwrap_or(DEFAULT_ROTATION_GRACE);
+        if rotation_grace < Duration::zero() {
+            bail!("Rotation grace window must not be negative");
+        }
+        let mut retired_keys = self.previous_secrets;
+        for retired in &retired_keys {
+            check_secret(&retired.key)?;
+        }
+        retired_keys.sort_by_key(|retired| std::cmp::Reverse(retired.retired_at));
+
+        Ok(AuthConfig {
+            issuer,
+            audience,
+            key,
+            retired_keys,
+            rotation_grace,
+            leeway,
+            device_verification_uri: self
+                .device_verification_uri
+                .unwrap_or_else(|| DEFAULT_DEVICE_VERIFICATION_URI.to_string()),
+            cookie_sessions: self.cookie_sessions,
+            require_verified_email: self.require_verified_email,
+            verification_cooldown,
+            require_challenge: self.require_challenge,
+            urls: self.urls,
+        })
+    }
+}
+
+/// Opens the sessions of an embedded `PalmeraClient` for the users of tokens this config
+/// verifies. Scoped tokens, such as API key tokens, are rejected because sessions can't
+/// be narrowed to their scopes.
+#[cfg(feature = "server")]
+impl palmera_database::embedded::Authenticator for AuthConfig {
+    fn verify(&self, token: &str) -> Option<palmera_database::embedded::Caller> {
+        let claims = self.verify_token(token).ok()?;
+        if claims.scope.is_some() {
+            return None;
+        }
+
+        Some(palmera_database::embedded::Caller {
+            user_id: claims.subject.to_string(),
+            actor_id: claims.actor.map(|actor| actor.subject.to_string()),
+        })
+    }
+}
+
+/// Generates a random signing secret of 64 hex characters.
+#[cfg(feature = "server")]
+pub fn generate_secret() -> String {
+    use password_hash::rand_core::{OsRng, RngCore};
+
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn check_secret(secret: &str) -> anyhow::Result<()> {
+    if secret.len() < MIN_SECRET_LENGTH {
+        bail!(
+            "Auth secret must be at least {} bytes long",
+            MIN_SECRET_LENGTH
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn test_builder_defaults() {
+        let config = AuthConfig::builder().secret(SECRET).build().unwrap();
+        assert_eq!(config.issuer(), DEFAULT_ISSUER);
+        assert_eq!(config.audience(), DEFAULT_AUDIENCE);
+        assert_eq!(config.key, SECRET);
+        assert_eq!(config.leeway(), DEFAULT_LEEWAY);
+        assert!(!config.require_verified_email());
+        assert!(!config.require_challenge());
+        assert_eq!(
+            config.verification_cooldown(),
+            DEFAULT_VERIFICATION_COOLDOWN
+        );
+    }
+
+    #[test]
+    fn test_issue_token() {
+        let config = AuthConfig::builder().secret(SECRET).build().unwrap();
+        let subject = Uuid::new_v4();
+
+        let token = config.issue_token(subject, Duration::minutes(5)).unwrap();
+        let claims = JWTClaims::verify(&token, SECRET).unwrap();
+        assert_eq!(claims.subject, subject);
+        assert_eq!(claims.issuer, config.issuer);
+
+        let admin = Uuid::new_v4();
+        let token = config
+            .issue_impersonation_token(subject, admin, Duration::minutes(5))
+            .unwrap();
+        let claims = JWTClaims::verify(&token, SECRET).unwrap();
+        assert_eq!((claims.subject, claims.actor_id()), (subject, admin));
+
+        let key = Uuid::new_v4();
+        let token = config
+            .issue_api_key_token(
+                subject,
+                key,
+                Duration::minutes(5),
+                Scopes::new().with("records:read"),
+            )
+            .unwrap();
+        let claims = JWTClaims::verify(&token, SECRET).unwrap();
+        assert_eq!((claims.subject, claims.actor_id()), (subject, key));
+        assert!(claims.has_scope("records:read:posts"));
+        assert!(!claims.has_scope("records:write:posts"));
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn test_embedded_authenticator() {
+        use palmera_database::embedded::Authenticator;
+
+        let config = AuthConfig::builder().secret(SECRET).build().unwrap();
+        let (subject, admin) = (Uuid::new_v4(), Uuid::new_v4());
+
+        let token = config.issue_token(subject, Duration::minutes(5)).unwrap();
+        let caller = config.verify(&token).unwrap();
+        assert_eq!(caller.user_id, subject.to_string());
+        assert_eq!(caller.actor_id, None);
+
+        let token = config
+            .issue_impersonation_token(subject, admin, Duration::minutes(5))
+            .unwrap();
+        assert_eq!(
+            config.verify(&token).unwrap().actor_id,
+            Some(admin.to_string())
+        );
+
+        let token = config
+            .issue_scoped_token(
+                subject,
+                Duration::minutes(5),
+                Scopes::new().with("records:read"),
+            )
+            .unwrap();
+        assert!(config.verify(&token).is_none());
+        assert!(config.verify("not a token").is_none());
+    }
+
+    #[test]
+    fn test_verify_token_checks_issuer_and_audience() {
+        let config = AuthConfig::builder().secret(SECRET).build().unwrap();
+        let token = config
+            .issue_token(Uuid::new_v4(), Duration::minutes(5))
+            .unwrap();
+        assert!(config.verify_token(&token).is_ok());
+
+        let other = AuthConfig::builder()
+            .audience("other-service")
+            .secret(SECRET)
+            .build()
+            .unwrap();
+        let foreign = other
+            .issue_token(Uuid::new_v4(), Duration::minutes(5))
+            .unwrap();
+        assert!(config.verify_token(&foreign).is_err());
+    }
+
+    #[test]
+    fn test_with_secret() {
+        let config = AuthConfig::builder().secret(SECRET).build().unwrap();
+        let rotated = config
+            .with_secret("fedcba9876543210fedcba9876543210")
+            .unwrap();
+        let token = rotated
+            .issue_token(Uuid::new_v4(), Duration::minutes(5))
+            .unwrap();
+
+        assert!(rotated.verify_token(&token).is_ok());
+        assert!(config.verify_token(&token).is_err());
+        assert!(config.with_secret("short").is_err());
+    }
+
+    #[test]
+    fn test_rotate() {
+        let config = AuthConfig::builder().secret(SECRET).build().unwrap();
+        let old = config
+            .issue_token(Uuid::new_v4(), Duration::minutes(5))
+            .unwrap();
+
+        let rotated = config.rotate("fedcba9876543210fedcba9876543210").unwrap();
+        let new = rotated
+            .issue_token(Uuid::new_v4(), Duration::minutes(5))
+            .unwrap();
+        assert!(rotated.verify_token(&old).is_ok());
+        assert!(rotated.verify_token(&new).is_ok());
+        assert!(config.verify_token(&new).is_err());
+        assert_eq!(rotated.retired_keys().len(), 1);
+
+        // Rotating to the current secret changes nothing.
+        assert_eq!(
+            rotated
+                .rotate("fedcba9876543210fedcba9876543210")
+                .unwrap()
+                .retired_keys()
+                .len(),
+            1
+        );
+        assert!(rotated.rotate("short").is_err());
+
+        let debug = format!("{:?}", rotated);
+        assert!(debug.contains("retired_at"));
+        assert!(!debug.contains(SECRET) && !debug.contains("fedcba9876543210"));
+        let builder = AuthConfig::builder().secret(SECRET);
+        assert!(!format!("{:?}", builder).contains(SECRET));
+    }
+
+    #[test]
+    fn test_rotation_grace() {
+        let token = AuthConfig::builder()
+            .secret(SECRET)
+            .build()
+            .unwrap()
+            .issue_token(Uuid::new_v4(), Duration::days(7))
+            .unwrap();
+
+        let config = |retired_at| {
+            AuthConfig::builder()
+                .secret("fedcba9876543210fedcba9876543210")
+                .previous_secret(SECRET, retired_at)
+                .rotation_grace(Duration::hours(1))
+                .build()
+                .unwrap()
+        };
+
+        let recent = config(Utc::now() - Duration::minutes(30));
+        assert!(recent.verify_token(&token).is_ok());
+        let expired = config(Utc::now() - Duration::hours(2));
+        assert!(expired.verify_token(&token).is_err());
+
+        // Retired keys past their window are dropped on the next rotation.
+        let rotated = expired.rotate(&"a".repeat(MIN_SECRET_LENGTH)).unwrap();
+        assert_eq!(rotated.retired_keys().len(), 1);
+    }
+
+    #[test]
+    fn test_env_vars() {
+        let config = AuthConfig::builder()
+            .secret(SECRET)
+            .rotation_grace(Duration::hours(1))
+            .build()
+            .unwrap()
+            .rotate("fedcba9876543210fedcba9876543210")
+            .unwrap();
+
+        let vars = config.env_vars();
+        let names: Vec<_> = vars.iter().map(|(name, _)| *name).collect();
+        assert_eq!(
+            names,
+            [
+                "PALMERA_AUTH_SECRET",
+                "PALMERA_AUTH_PREVIOUS_SECRET",
+                "PALMERA_AUTH_PREVIOUS_SECRET_RETIRED_AT",
+                "PALMERA_AUTH_ROTATION_GRACE"
+            ]
+        );
+        assert_eq!(vars[1].1, SECRET);
+        assert_eq!(vars[3].1, "3600");
+        assert!(DateTime::parse_from_rfc3339(&vars[2].1).is_ok());
+    }
+
+    #[test]
+    fn test_builder_validation() {
+        assert!(AuthConfig::builder().build().is_err());
+        assert!(AuthConfig::builder().secret("short").build().is_err());
+        assert!(
+            AuthConfig::builder()
+                .issuer(" ")
+                .secret(SECRET)
+                .build()
+                .is_err()
+        );
+        assert!(
+            AuthConfig::builder()
+                .audience("")
+                .secret(SECRET)
+                .build()
+                .is_err()
+        );
+        assert!(
+            AuthConfig::builder()
+                .secret(SECRET)
+                .previous_secret("short", Utc::now())
+                .build()
+                .is_err()
+        );
+        assert!(
+            AuthConfig::builder()
+                .leeway(Duration::seconds(-1))
+                .secret(SECRET)
+                .build()
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,641 @@
+//! # Device authorization grant
+//!
+//! The OAuth 2.0 device authorization grant (RFC 8628) lets clients without a browser or
+//! keyboard, such as CLIs and TVs, sign users in without handling their password:
+//!
+//! 1. The client calls `POST /device/code` and receives a `device_code` and a short
+//!    `user_code`.
+//! 2. It asks the user to open the `verification_uri` (see
+//!    [`AuthConfig::device_verification_uri`], made absolute with [`AuthConfig::urls`]
+//!    when configured) and enter the user code there, signing in with their email and
+//!    password.
+//! 3. Meanwhile it polls `POST /device/token` with the device code every `interval`
+//!    seconds until it receives an access token, or an error other than
+//!    `authorization_pending` or `slow_down`.
+//!
+//! Pending authorizations are stored in `auth.device_codes` and expire after
+//! [`DEVICE_CODE_TTL`].
+//!
+//! ## Example
+//!
+//! ```rust
+//! use palmera_auth::device::normalize_user_code;
+//!
+//! assert_eq!(normalize_user_code("bcdf ghjk").as_deref(), Some("BCDF-GHJK"));
+//! assert_eq!(normalize_user_code("not a code"), None);
+//! ```
+
+use axum::{
+    Extension, Form, Json,
+    extract::Query,
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+};
+use chrono::{DateTime, Duration, Utc};
+use password_hash::rand_core::{OsRng, RngCore};
+use sea_query::{Alias, Asterisk, Expr, PostgresQueryBuilder, Query as SqlQuery};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres, prelude::FromRow};
+use utoipa::ToSchema;
+use utoipa_axum::{router::OpenApiRouter, routes};
+use uuid::Uuid;
+
+use crate::{
+    AuthConfig,
+    challenge::Challenge,
+    extract::ClientInfo,
+    hooks::AuthHooks,
+    router::{ACCESS_TOKEN_TTL, LoginPayload},
+    urls::RequestUrls,
+};
+
+/// How long a device code can be exchanged for a token.
+pub const DEVICE_CODE_TTL: Duration = Duration::seconds(600);
+
+/// Minimum delay between two polls of the token endpoint, in seconds.
+pub const POLL_INTERVAL: i64 = 5;
+
+/// `grant_type` of device token requests.
+pub const DEVICE_CODE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+/// Characters of user codes: consonants only, so codes cannot spell words and are easy
+/// to type.
+const USER_CODE_ALPHABET: &[u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+const USER_CODE_LENGTH: usize = 8;
+
+/// A pending device authorization, stored in `auth.device_codes`.
+#[derive(Debug, FromRow, Clone, Serialize, Deserialize)]
+pub struct DeviceAuthorization {
+    /// Secret code the device polls with.
+    pub device_code: String,
+    /// Code the user enters on the verification page, formatted as `XXXX-XXXX`.
+    pub user_code: String,
+    /// The user who approved the request, once approved.
+    pub user_id: Option<Uuid>,
+    pub expires: DateTime<Utc>,
+    pub last_polled: Option<DateTime<Utc>>,
+    pub created: DateTime<Utc>,
+}
+
+impl DeviceAuthorization {
+    /// Creates a pending authorization with random codes, valid for [`DEVICE_CODE_TTL`].
+    pub fn new() -> Self {
+        let now = Utc::now();
+
+        let mut secret = [0u8; 32];
+        OsRng.fill_bytes(&mut secret);
+        let device_code = secret.iter().map(|b| format!("{:02x}", b)).collect();
+
+        let user_code: String = (0..USER_CODE_LENGTH)
+            .map(|_| {
+                let i = OsRng.next_u32() as usize % USER_CODE_ALPHABET.len();
+                USER_CODE_ALPHABET[i] as char
+            })
+            .collect();
+
+        Self {
+            device_code,
+            user_code: format_user_code(&user_code),
+            user_id: None,
+            expires: now + DEVICE_CODE_TTL,
+            last_polled: None,
+            created: now,
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        Utc::now() > self.expires
+    }
+
+    // database operation
+
+    fn table() -> (Alias, Alias) {
+        (Alias::new("auth"), Alias::new("device_codes"))
+    }
+
+    /// Insert this authorization into the database and return it as stored.
+    pub async fn insert(self, db: &Pool<Postgres>) -> anyhow::Result<Self> {
+        let sql = SqlQuery::insert()
+            .into_table(Self::table())
+            .columns([
+                Alias::new("device_code"),
+                Alias::new("user_code"),
+                Alias::new("expires"),
+                Alias::new("created"),
+            ])
+            .values([
+                self.device_code.into(),
+                self.user_code.into(),
+                self.expires.into(),
+                self.created.into(),
+            ])?
+            .returning_all()
+            .to_string(PostgresQueryBuilder);
+
+        let result = sqlx::query_as::<_, Self>(&sql).fetch_one(db).await?;
+
+        Ok(result)
+    }
+
+    /// Find an authorization by its device code.
+    pub async fn find_by_device_code(
+        device_code: &str,
+        db: &Pool<Postgres>,
+    ) -> anyhow::Result<Option<Self>> {
+        let sql = SqlQuery::select()
+            .from(Self::table())
+            .column(Asterisk)
+            .and_where(Expr::col("device_code").eq(device_code))
+            .to_string(PostgresQueryBuilder);
+
+        let result = sqlx::query_as::<_, Self>(&sql).fetch_optional(db).await?;
+
+        Ok(result)
+    }
+
+    /// Approve the pending, unexpired authorization with `user_code` for `user_id`.
+    ///
+    /// Returns `false` if there is no such authorization.
+    pub async fn approve(
+        user_code: &str,
+        user_id: Uuid,
+        db: &Pool<Postgres>,
+    ) -> anyhow::Result<bool> {
+        let sql = SqlQuery::update()
+            .table(Self::table())
+            .value(Alias::new("user_id"), user_id)
+            .and_where(Expr::col("user_code").eq(user_code))
+            .and_where(Expr::col("user_id").is_null())
+            .and_where(Expr::col("expires").gt(Utc::now()))
+            .to_string(PostgresQueryBuilder);
+
+        let result = sqlx::query(&sql).execute(db).await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Record a poll of the token endpoint.
+    pub async fn touch(&self, db: &Pool<Postgres>) -> anyhow::Result<()> {
+        let sql = SqlQuery::update()
+            .table(Self::table())
+            .value(Alias::new("last_polled"), Utc::now())
+            .and_where(Expr::col("device_code").eq(self.device_code.as_str()))
+            .to_string(PostgresQueryBuilder);
+
+        sqlx::query(&sql).execute(db).await?;
+
+        Ok(())
+    }
+
+    /// Delete this authorization, returning `false` if it was already deleted.
+    pub async fn delete(&self, db: &Pool<Postgres>) -> anyhow::Result<bool> {
+        let sql = SqlQuery::delete()
+            .from_table(Self::table())
+            .and_where(Expr::col("device_code").eq(self.device_code.as_str()))
+            .to_string(PostgresQueryBuilder);
+
+        let result = sqlx::query(&sql).execute(db).await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+}
+
+impl Default for DeviceAuthorization {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn format_user_code(code: &str) -> String {
+    let (head, tail) = code.split_at(USER_CODE_LENGTH / 2);
+    format!("{}-{}", head, tail)
+}
+
+/// Normalizes a user code as typed by a user, ignoring case, spaces and dashes.
+///
+/// Returns `None` if the input cannot be a user code.
+pub fn normalize_user_code(input: &str) -> Option<String> {
+    let code: String = input
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+
+    let valid =
+        code.len() == USER_CODE_LENGTH && code.bytes().all(|b| USER_CODE_ALPHABET.contains(&b));
+
+    valid.then(|| format_user_code(&code))
+}
+
+/// Response of the device authorization endpoint.
+#[derive(Debug, ToSchema, Serialize, Deserialize)]
+pub struct DeviceCodeResponse {
+    pub device_code: String,
+    pub user_code: String,
+    pub verification_uri: String,
+    /// Verification URI with the user code filled in, e.g. for a QR code.
+    pub verification_uri_complete: String,
+    /// Seconds until the codes expire.
+    pub expires_in: i64,
+    /// Seconds to wait between polls of the token endpoint.
+    pub interval: i64,
+}
+
+#[utoipa::path(post, path = "/device/code")]
+async fn device_code(
+    Extension(db): Extension<Pool<Postgres>>,
+    Extension(config): Extension<AuthConfig>,
+    urls: RequestUrls,
+) -> Result<Json<DeviceCodeResponse>, StatusCode> {
+    let authorization = DeviceAuthorization::new()
+        .insert(&db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let verification_uri = urls.url(config.device_verification_uri());
+
+    Ok(Json(DeviceCodeResponse {
+        verification_uri_complete: format!(
+            "{}?user_code={}",
+            verification_uri, authorization.user_code
+        ),
+        verification_uri,
+        device_code: authorization.device_code,
+        user_code: authorization.user_code,
+        expires_in: DEVICE_CODE_TTL.num_seconds(),
+        interval: POLL_INTERVAL,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VerificationQuery {
+    user_code: Option<String>,
+}
+
+#[derive(Debug, ToSchema, Deserialize)]
+pub struct VerificationPayload {
+    user_code: String,
+    email: String,
+    password: String,
+}
+
+/// Renders the verification form, prefilled with `user_code` when it is a valid code.
+fn verification_page(user_code: Option<&str>, message: Option<&str>) -> Html<String> {
+    // Only normalized codes are rendered, which contain no markup.
+    let user_code = user_code.and_then(normalize_user_code).unwrap_or_default();
+    let message = message
+        .map(|message| format!("<p>{}</p>", message))
+        .unwrap_or_default();
+
+    Html(format!(
+        r#"<!doctype html>
+<html>
+  <head><meta charset="utf-8"><title>Connect a device</title></head>
+  <body>
+    <h1>Connect a device</h1>
+    {message}
+    <form method="post">
+      <label>Code <input name="user_code" value="{user_code}" autocomplete="off" required></label>
+      <label>Email <input name="email" type="email" required></label>
+      <label>Password <input name="password" type="password" required></label>
+      <button type="submit">Connect</button>
+    </form>
+  </body>
+</html>"#
+    ))
+}
+
+#[utoipa::path(get, path = "/device")]
+async fn verification(Query(query): Query<VerificationQuery>) -> Html<String> {
+    verification_page(query.user_code.as_deref(), None)
+}
+
+#[utoipa::path(post, path = "/device")]
+async fn approve(
+    Extension(db): Extension<Pool<Postgres>>,
+    Extension(config): Extension<AuthConfig>,
+    client: ClientInfo,
+    hooks: Option<Extension<AuthHooks>>,
+    challenge: Result<Challenge, StatusCode>,
+    Form(form): Form<VerificationPayload>,
+) -> (StatusCode, Html<String>) {
+    if let Err(status) = challenge {
+        return (
+            status,
+            verification_page(
+                Some(&form.user_code),
+                Some("Complete the bot challenge and try again."),
+            ),
+        );
+    }
+    let login = LoginPayload {
+        email: form.email,
+        password: form.password,
+    };
+    let hooks = hooks.map(|Extension(hooks)| hooks);
+    let user = match login
+        .authenticate(&db, &config, &client, hooks.as_ref())
+        .await
+    {
+        Ok(user) => user,
+        Err(StatusCode::FORBIDDEN) => {
+            return (
+                StatusCode::FORBIDDEN,
+                verification_page(
+                    Some(&form.user_code),
+                    Some("This account is not active or its email is not verified."),
+                ),
+            );
+        }
+        Err(_) => {
+            return (
+                StatusCode::UNAUTHORIZED,
+                verification_page(Some(&form.user_code), Some("Invalid email or password.")),
+            );
+        }
+    };
+
+    let approved = match normalize_user_code(&form.user_code) {
+        Some(code) => DeviceAuthorization::approve(&code, user.id, &db).await,
+        None => Ok(false),
+    };
+
+    match approved {
+        Ok(true) => (
+            StatusCode::OK,
+            Html("<!doctype html><p>Device connected. You can return to it now.</p>".to_string()),
+        ),
+        Ok(false) => (
+            StatusCode::BAD_REQUEST,
+            verification_page(None, Some("This code is invalid or has expired.")),
+        ),
+        Err(_) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            verification_page(Some(&form.user_code), Some("Something went wrong.")),
+        ),
+    }
+}
+
+#[derive(Debug, ToSchema, Deserialize)]
+pub struct DeviceTokenPayload {
+    grant_type: String,
+    device_code: String,
+}
+
+/// Response of a successful device token request.
+#[derive(Debug, ToSchema, Serialize, Deserialize)]
+pub struct DeviceTokenResponse {
+    pub access_token: String,
+    pub token_type: String,
+    /// Seconds until the access token expires.
+    pub expires_in: i64,
+}
+
+/// Error codes of the device token endpoint, as defined by RFC 8628.
+#[derive(Debug, Clone, Copy, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceTokenError {
+    /// The user has not approved the request yet; poll again after the interval.
+    AuthorizationPending,
+    /// The device polled too fast; poll again after a longer interval.
+    SlowDown,
+    ExpiredToken,
+    InvalidGrant,
+    UnsupportedGrantType,
+    ServerError,
+}
+
+impl IntoResponse for DeviceTokenError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            DeviceTokenError::ServerError => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::BAD_REQUEST,
+        };
+
+        (status, Json(serde_json::json!({ "error": self }))).into_response()
+    }
+}
+
+#[utoipa::path(post, path = "/device/token")]
+async fn device_token(
+    Extension(db): Extension<Pool<Postgres>>,
+    Extension(config): Extension<AuthConfig>,
+    Form(form): Form<DeviceTokenPayload>,
+) -> Result<Json<DeviceTokenResponse>, DeviceTokenError> {
+    if form.grant_type != DEVICE_CODE_GRANT_TYPE {
+        return Err(DeviceTokenError::UnsupportedGrantType);
+    }
+
+    let authorization = DeviceAuthorization::find_by_device_code(&form.device_code, &db)
+        .await
+        .map_err(|_| DeviceTokenError::ServerError)?
+        .ok_or(DeviceTokenError::InvalidGrant)?;
+
+    if authorization.is_expired() {
+        let _ = authorization.delete(&db).await;
+        return Err(DeviceTokenError::ExpiredToken);
+    }
+
+    let too_fast = authorization
+        .last_polled
+        .is_some_and(|at| Utc::now() - at < Duration::seconds(POLL_INTERVAL));
+
+    authorization
+        .touch(&db)
+        .await
+        .map_err(|_| DeviceTokenError::ServerError)?;
+
+    if too_fast {
+        return Err(DeviceTokenError::SlowDown);
+    }
+
+    let Some(user_id) = authorization.user_id else {
+        return Err(DeviceTokenError::AuthorizationPending);
+    };
+
+    // Deleting first makes each device code exchangeable once, even with concurrent polls.
+    if !authorization
+        .delete(&db)
+        .await
+        .map_err(|_| DeviceTokenError::ServerError)?
+    {
+        return Err(DeviceTokenError::InvalidGrant);
+    }
+
+    let access_token = config
+        .issue_token(user_id, ACCESS_TOKEN_TTL)
+        .map_err(|_| DeviceTokenError::ServerError)?;
+
+    Ok(Json(DeviceTokenResponse {
+        access_token,
+        token_type: "Bearer".to_string(),
+        expires_in: ACCESS_TOKEN_TTL.num_seconds(),
+    }))
+}
+
+pub fn router() -> OpenApiRouter {
+    OpenApiRouter::new()
+        .routes(routes!(device_code))
+        .routes(routes!(verification, approve))
+        .routes(routes!(device_token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{schemas::AuthUser, urls::UrlBuilder};
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+    fn token_payload(device_code: &str) -> Form<DeviceTokenPayload> {
+        Form(DeviceTokenPayload {
+            grant_type: DEVICE_CODE_GRANT_TYPE.to_string(),
+            device_code: device_code.to_string(),
+        })
+    }
+
+    /// Moves the last poll back so the next poll is not rate limited.
+    async fn skip_interval(device_code: &str, db: &Pool<Postgres>) -> anyhow::Result<()> {
+        sqlx::query("update auth.device_codes set last_polled = now() - interval '1 minute' where device_code = $1")
+            .bind(device_code)
+            .execute(db)
+            .await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_user_codes() {
+        let authorization = DeviceAuthorization::new();
+        assert_eq!(authorization.user_code.len(), USER_CODE_LENGTH + 1);
+        assert_eq!(authorization.device_code.len(), 64);
+        assert_eq!(
+            normalize_user_code(&authorization.user_code.to_lowercase()),
+            Some(authorization.user_code)
+        );
+
+        assert_eq!(normalize_user_code("BCDF-GHJ"), None);
+        assert_eq!(normalize_user_code("BCDF-GHJA"), None);
+        assert_eq!(normalize_user_code("\"><script>"), None);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_device_flow(db: Pool<Postgres>) -> anyhow::Result<()> {
+        let user = AuthUser::new("device@example.com", "password")
+            .insert(&db)
+            .await?;
+        let config = AuthConfig::builder().secret(SECRET).build()?;
+
+        let Json(code) = device_code(
+            Extension(db.clone()),
+            Extension(config.clone()),
+            RequestUrls(None),
+        )
+        .await
+        .unwrap();
+        assert_eq!(code.verification_uri, "/auth/device");
+        assert!(code.verification_uri_complete.ends_with(&code.user_code));
+
+        // With a URL builder, the URI is absolute on the host the request was sent to.
+        let urls = UrlBuilder::new("https://api.example.com")?.with_custom_domain("api.acme.com");
+        let mut headers = axum::http::HeaderMap::new();
+        headers.insert("host", "api.acme.com".parse()?);
+        let Json(absolute) = device_code(
+            Extension(db.clone()),
+            Extension(config.clone()),
+            RequestUrls(Some(urls.for_request(&headers, None))),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            absolute.verification_uri,
+            "https://api.acme.com/auth/device"
+        );
+
+        let pending = device_token(
+            Extension(db.clone()),
+            Extension(config.clone()),
+            token_payload(&code.device_code),
+        )
+        .await;
+        assert_eq!(pending.unwrap_err(), DeviceTokenError::AuthorizationPending);
+
+        let slow = device_token(
+            Extension(db.clone()),
+            Extension(config.clone()),
+            token_payload(&code.device_code),
+        )
+        .await;
+        assert_eq!(slow.unwrap_err(), DeviceTokenError::SlowDown);
+
+        let (status, _) = approve(
+            Extension(db.clone()),
+            Extension(config.clone()),
+            ClientInfo::default(),
+            None,
+            Ok(Challenge::passed()),
+            Form(VerificationPayload {
+                user_code: code.user_code.to_lowercase(),
+                email: "device@example.com".to_string(),
+                password: "wrong".to_string(),
+            }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, _) = approve(
+            Extension(db.clone()),
+            Extension(config.clone()),
+            ClientInfo::default(),
+            None,
+            Ok(Challenge::passed()),
+            Form(VerificationPayload {
+                user_code: code.user_code.to_lowercase(),
+                email: "device@example.com".to_string(),
+                password: "password".to_string(),
+            }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        skip_interval(&code.device_code, &db).await?;
+        let Json(token) = device_token(
+            Extension(db.clone()),
+            Extension(config.clone()),
+            token_payload(&code.device_code),
+        )
+        .await
+        .unwrap();
+        assert_eq!(config.verify_token(&token.access_token)?.subject, user.id);
+
+        let reused = device_token(
+            Extension(db),
+            Extension(config),
+            token_payload(&code.device_code),
+        )
+        .await;
+        assert_eq!(reused.unwrap_err(), DeviceTokenError::InvalidGrant);
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_expired_device_code(db: Pool<Postgres>) -> anyhow::Result<()> {
+        let config = AuthConfig::builder().secret(SECRET).build()?;
+        let mut authorization = DeviceAuthorization::new();
+        authorization.expires = Utc::now() - Duration::seconds(1);
+        let authorization = authorization.insert(&db).await?;
+
+        let expired = device_token(
+            Extension(db.clone()),
+            Extension(config),
+            token_payload(&authorization.device_code),
+        )
+        .await;
+        assert_eq!(expired.unwrap_err(), DeviceTokenError::ExpiredToken);
+
+        let user = AuthUser::new("late@example.com", "password")
+            .insert(&db)
+            .await?;
+        assert!(!DeviceAuthorization::approve(&authorization.user_code, user.id, &db).await?);
+        Ok(())
+    }
+}
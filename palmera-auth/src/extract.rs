@@ -0,0 +1,489 @@
+//! # Auth extractor
+//!
+//! [`AuthClaims`] extracts the verified claims of the bearer token sent with a request.
+//! When [`AuthConfig::cookie_sessions`] is enabled, requests without an `Authorization`
+//! header may carry the token in the session cookie instead (see [`crate::session`]);
+//! unless the method is safe, such requests must also pass the CSRF check, or are
+//! rejected with `403`.
+//! Tokens are verified with the [`AuthConfig`] found in the request extensions, so the
+//! router must be layered with `Extension(config)`; the config's issuer, audience and
+//! leeway are enforced (see [`AuthConfig::verify_options`]). When the request extensions
+//! also hold the database pool, the token's user must have an active account (see
+//! [`crate::status`]); otherwise the request is rejected with `403`.
+//!
+//! [`RequireScope`] is a layer that rejects requests whose token does not grant a scope
+//! (see [`crate::scope`]) with `403`, and requests without a valid token with `401`.
+//! [`RequireAdmin`] likewise only lets through the instance's admin, the user recorded
+//! by the first-run setup (see [`crate::setup`]); it needs the database pool in the
+//! request extensions.
+//!
+//! # Example
+//!
+//! ```rust
+//! use axum::{Extension, Router, routing::get};
+//! use palmera_auth::{
+//!     AuthConfig,
+//!     extract::{AuthClaims, RequireScope},
+//! };
+//!
+//! async fn me(AuthClaims(claims): AuthClaims) -> String {
+//!     claims.subject.to_string()
+//! }
+//!
+//! let config = AuthConfig::builder()
+//!     .secret("a-secret-that-is-at-least-32-bytes-long")
+//!     .build()
+//!     .unwrap();
+//! let app: Router = Router::new()
+//!     .route("/me", get(me))
+//!     .route(
+//!         "/posts",
+//!         get(|| async { "posts" }).route_layer(RequireScope("records:read:posts")),
+//!     )
+//!     .layer(Extension(config));
+//! ```
+
+use std::{
+    convert::Infallible,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use axum::{
+    extract::{ConnectInfo, FromRequestParts, Request},
+    http::{StatusCode, header, request::Parts},
+    response::{IntoResponse, Response},
+};
+use palmera_database::postgres::session::SessionContext;
+use sqlx::{Pool, Postgres};
+use tower::{Layer, Service};
+
+use crate::{
+    AuthConfig,
+    jwt::JWTClaims,
+    proxy::ClientAddr,
+    schemas::AuthUser,
+    session::{SESSION_COOKIE, cookie, csrf_verified},
+    setup::InstanceSetup,
+};
+
+/// Claims of a valid bearer token; rejects the request with `401` otherwise.
+#[derive(Debug, Clone)]
+pub struct AuthClaims(pub JWTClaims);
+
+impl AuthClaims {
+    /// Postgres session running as `role` for the token's subject, naming the actor of
+    /// an impersonation or API key token, see [`JWTClaims::actor_id`].
+    pub fn session(&self, role: &str) -> SessionContext {
+        let ctx = SessionContext::new(role, &self.0.subject.to_string());
+        match self.0.is_impersonation() {
+            true => ctx.with_actor(&self.0.actor_id().to_string()),
+            false => ctx,
+        }
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for AuthClaims {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        // Already verified by a layer such as `RequireScope`.
+        if let Some(claims) = parts.extensions.get::<AuthClaims>() {
+            return Ok(claims.clone());
+        }
+
+        let config = parts
+            .extensions
+            .get::<AuthConfig>()
+            .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        let bearer = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+
+        let token = match bearer {
+            Some(token) => token,
+            None if config.cookie_sessions() => {
+                let token =
+                    cookie(&parts.headers, SESSION_COOKIE).ok_or(StatusCode::UNAUTHORIZED)?;
+                if !csrf_verified(&parts.method, &parts.headers) {
+                    return Err(StatusCode::FORBIDDEN);
+                }
+                token
+            }
+            None => return Err(StatusCode::UNAUTHORIZED),
+        };
+
+        let claims = config
+            .verify_token(token)
+            .map_err(|_| StatusCode::UNAUTHORIZED)?;
+
+        if let Some(db) = parts.extensions.get::<Pool<Postgres>>() {
+            let status = AuthUser::status_of(claims.subject, db)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+                .ok_or(StatusCode::UNAUTHORIZED)?;
+
+            if !status.is_active() {
+                return Err(StatusCode::FORBIDDEN);
+            }
+        }
+
+        Ok(AuthClaims(claims))
+    }
+}
+
+/// Network details of the client making a request, as recorded in the security log.
+///
+/// The IP address is the one resolved by [`crate::proxy::resolve_client`], or else the
+/// peer address of the connection, available when the app is served with
+/// `into_make_service_with_connect_info::<SocketAddr>()`. Never rejects.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClientInfo {
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for ClientInfo {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(ClientInfo {
+            ip: parts
+                .extensions
+                .get::<ClientAddr>()
+                .map(|client| client.ip)
+                .or_else(|| {
+                    parts
+                        .extensions
+                        .get::<ConnectInfo<SocketAddr>>()
+                        .map(|ConnectInfo(address)| address.ip())
+                }),
+            user_agent: parts
+                .headers
+                .get(header::USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string),
+        })
+    }
+}
+
+/// Layer requiring the request's token to grant a scope, e.g.
+/// `RequireScope("records:write:posts")`.
+#[derive(Debug, Clone, Copy)]
+pub struct RequireScope(pub &'static str);
+
+impl<S> Layer<S> for RequireScope {
+    type Service = RequireScopeService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequireScopeService {
+            inner,
+            scope: self.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RequireScopeService<S> {
+    inner: S,
+    scope: &'static str,
+}
+
+impl<S> Service<Request> for RequireScopeService<S>
+where
+    S: Service<Request, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        // Take the service that was polled ready, leaving a fresh clone in its place.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let scope = self.scope;
+
+        Box::pin(async move {
+            let (mut parts, body) = request.into_parts();
+
+            let claims = match AuthClaims::from_request_parts(&mut parts, &()).await {
+                Ok(claims) => claims,
+                Err(status) => return Ok(status.into_response()),
+            };
+
+            if !claims.0.has_scope(scope) {
+                return Ok(StatusCode::FORBIDDEN.into_response());
+            }
+
+            parts.extensions.insert(claims);
+            inner.call(Request::from_parts(parts, body)).await
+        })
+    }
+}
+
+/// Layer rejecting requests whose token does not belong to the instance's admin with
+/// `403`, and requests without a valid token with `401`.
+#[derive(Debug, Clone, Copy)]
+pub struct RequireAdmin;
+
+impl<S> Layer<S> for RequireAdmin {
+    type Service = RequireAdminService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequireAdminService { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RequireAdminService<S> {
+    inner: S,
+}
+
+impl<S> Service<Request> for RequireAdminService<S>
+where
+    S: Service<Request, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        Box::pin(async move {
+            let (mut parts, body) = request.into_parts();
+
+            let claims = match AuthClaims::from_request_parts(&mut parts, &()).await {
+                Ok(claims) => claims,
+                Err(status) => return Ok(status.into_response()),
+            };
+
+            let Some(db) = parts.extensions.get::<Pool<Postgres>>() else {
+                return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+            };
+            match InstanceSetup::is_admin(claims.0.subject, db).await {
+                Ok(true) => {}
+                Ok(false) => return Ok(StatusCode::FORBIDDEN.into_response()),
+                Err(_) => return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response()),
+            }
+
+            parts.extensions.insert(claims);
+            inner.call(Request::from_parts(parts, body)).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scope::Scopes;
+    use axum::{Extension, Router, body::Body, routing::get};
+    use chrono::Duration;
+    use tower::ServiceExt;
+    use uuid::Uuid;
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+    async fn extract(
+        config: AuthConfig,
+        authorization: Option<&str>,
+    ) -> Result<AuthClaims, StatusCode> {
+        let mut request = axum::http::Request::builder().extension(config);
+        if let Some(value) = authorization {
+            request = request.header(header::AUTHORIZATION, value);
+        }
+        let (mut parts, _) = request.body(()).unwrap().into_parts();
+        AuthClaims::from_request_parts(&mut parts, &()).await
+    }
+
+    #[tokio::test]
+    async fn test_extract_claims() {
+        let config = AuthConfig::builder().secret(SECRET).build().unwrap();
+        let subject = Uuid::new_v4();
+        let token = config.issue_token(subject, Duration::minutes(5)).unwrap();
+
+        let AuthClaims(claims) = extract(config.clone(), Some(&format!("Bearer {}", token)))
+            .await
+            .unwrap();
+        assert_eq!(claims.subject, subject);
+
+        assert_eq!(
+            extract(config.clone(), None).await.unwrap_err(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            extract(config, Some(&token)).await.unwrap_err(),
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rejects_token_for_other_audience() {
+        let config = AuthConfig::builder().secret(SECRET).build().unwrap();
+        let other = AuthConfig::builder()
+            .audience("other-service")
+            .secret(SECRET)
+            .build()
+            .unwrap();
+        let token = other
+            .issue_token(Uuid::new_v4(), Duration::minutes(5))
+            .unwrap();
+
+        assert_eq!(
+            extract(config, Some(&format!("Bearer {}", token)))
+                .await
+                .unwrap_err(),
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn test_require_scope() {
+        let config = AuthConfig::builder().secret(SECRET).build().unwrap();
+        let app = Router::new()
+            .route(
+                "/posts",
+                get(|AuthClaims(claims): AuthClaims| async move { claims.subject.to_string() })
+                    .route_layer(RequireScope("records:read:posts")),
+            )
+            .layer(Extension(config.clone()));
+
+        let call = |token: Option<String>| {
+            let app = app.clone();
+            async move {
+                let mut request = axum::http::Request::get("/posts");
+                if let Some(token) = token {
+                    request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+                }
+                app.oneshot(request.body(Body::empty()).unwrap())
+                    .await
+                    .unwrap()
+                    .status()
+            }
+        };
+
+        let subject = Uuid::new_v4();
+        let ttl = Duration::minutes(5);
+        let read = config
+            .issue_scoped_token(subject, ttl, Scopes::new().with("records:read"))
+            .unwrap();
+        let files = config
+            .issue_scoped_token(subject, ttl, Scopes::new().with("files:write"))
+            .unwrap();
+        let unscoped = config.issue_token(subject, ttl).unwrap();
+
+        assert_eq!(call(Some(read)).await, StatusCode::OK);
+        assert_eq!(call(Some(unscoped)).await, StatusCode::OK);
+        assert_eq!(call(Some(files)).await, StatusCode::FORBIDDEN);
+        assert_eq!(call(None).await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_rejects_inactive_accounts(db: Pool<Postgres>) -> anyhow::Result<()> {
+        let config = AuthConfig::builder().secret(SECRET).build()?;
+        let user = AuthUser::new("inactive@example.com", "password")
+            .insert(&db)
+            .await?;
+        let token = config.issue_token(user.id, Duration::minutes(5))?;
+
+        let extract_with_db = || {
+            let (mut parts, _) = axum::http::Request::builder()
+                .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                .extension(config.clone())
+                .extension(db.clone())
+                .body(())
+                .unwrap()
+                .into_parts();
+            async move { AuthClaims::from_request_parts(&mut parts, &()).await }
+        };
+
+        assert!(extract_with_db().await.is_ok());
+
+        user.set_status(crate::status::AccountStatus::Disabled, &db)
+            .await?;
+        assert_eq!(extract_with_db().await.unwrap_err(), StatusCode::FORBIDDEN);
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_require_admin(db: Pool<Postgres>) -> anyhow::Result<()> {
+        let config = AuthConfig::builder().secret(SECRET).build()?;
+        let app = Router::new()
+            .route(
+                "/admin",
+                get(|| async { "admin" }).route_layer(RequireAdmin),
+            )
+            .layer(Extension(db.clone()))
+            .layer(Extension(config.clone()));
+
+        let call = |token: String| {
+            let app = app.clone();
+            async move {
+                let request = axum::http::Request::get("/admin")
+                    .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                    .body(Body::empty())
+                    .unwrap();
+                app.oneshot(request).await.unwrap().status()
+            }
+        };
+
+        let admin = AuthUser::new("admin@example.com", "password")
+            .insert(&db)
+            .await?;
+        let user = AuthUser::new("user@example.com", "password")
+            .insert(&db)
+            .await?;
+        sqlx::query(
+            "INSERT INTO auth.instance (name, storage, admin_id) VALUES ('Acme', 'local', $1)",
+        )
+        .bind(admin.id)
+        .execute(&db)
+        .await?;
+
+        let ttl = Duration::minutes(5);
+        assert_eq!(
+            call(config.issue_token(admin.id, ttl)?).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            call(config.issue_token(user.id, ttl)?).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(call("invalid".to_string()).await, StatusCode::UNAUTHORIZED);
+        Ok(())
+    }
+
+    #[test]
+    fn test_session_names_actor() {
+        let config = AuthConfig::builder().secret(SECRET).build().unwrap();
+        let (subject, admin) = (Uuid::new_v4(), Uuid::new_v4());
+
+        let token = config.issue_token(subject, Duration::minutes(5)).unwrap();
+        let ctx = AuthClaims(config.verify_token(&token).unwrap()).session("authenticated");
+        assert_eq!(ctx.user_id, Some(subject.to_string()));
+        assert_eq!(ctx.actor_id, None);
+
+        let token = config
+            .issue_impersonation_token(subject, admin, Duration::minutes(5))
+            .unwrap();
+        let ctx = AuthClaims(config.verify_token(&token).unwrap()).session("authenticated");
+        assert_eq!(ctx.user_id, Some(subject.to_string()));
+        assert_eq!(ctx.actor_id, Some(admin.to_string()));
+    }
+}
@@ -0,0 +1,118 @@
+//! # Auth hooks
+//!
+//! [`AuthHooks`] lets the application react to what happens in the auth routes, such as
+//! an account being disabled or a sign-in from an unknown device. Handlers are
+//! registered once when the app is built, and the hooks are passed to the routes as a
+//! request extension; routes run without them when the extension is missing.
+//!
+//! Handlers are called synchronously from the request, so long running work such as
+//! sending emails should be spawned onto a task.
+//!
+//! # Example
+//!
+//! ```rust
+//! use axum::Extension;
+//! use palmera_auth::hooks::AuthHooks;
+//!
+//! let hooks = AuthHooks::new().on_status_change(|change| {
+//!     println!("{} is now {}", change.user_id, change.to);
+//! });
+//! let router = palmera_auth::admin::router().layer(Extension(hooks));
+//! ```
+
+use std::sync::Arc;
+
+use sqlx::{Pool, Postgres};
+
+use crate::{
+    security::{NewClientDetector, SecurityEvent, SuspiciousLogin, SuspiciousLoginDetector},
+    status::AccountStatusChange,
+};
+
+type Handler<T> = Arc<dyn Fn(&T) + Send + Sync>;
+
+/// Handlers for auth events.
+#[derive(Clone)]
+pub struct AuthHooks {
+    status_change: Vec<Handler<AccountStatusChange>>,
+    suspicious_login: Vec<Handler<SuspiciousLogin>>,
+    detector: Arc<dyn SuspiciousLoginDetector>,
+}
+
+impl AuthHooks {
+    pub fn new() -> Self {
+        Self {
+            status_change: vec![],
+            suspicious_login: vec![],
+            detector: Arc::new(NewClientDetector::default()),
+        }
+    }
+
+    /// Runs `handler` after a user's account status changed.
+    pub fn on_status_change<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&AccountStatusChange) + Send + Sync + 'static,
+    {
+        self.status_change.push(Arc::new(handler));
+        self
+    }
+
+    /// Runs `handler` after a successful sign-in that the detector flagged as
+    /// suspicious.
+    pub fn on_suspicious_login<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&SuspiciousLogin) + Send + Sync + 'static,
+    {
+        self.suspicious_login.push(Arc::new(handler));
+        self
+    }
+
+    /// Replaces the [`NewClientDetector`] used to flag suspicious sign-ins.
+    pub fn with_detector<D: SuspiciousLoginDetector + 'static>(mut self, detector: D) -> Self {
+        self.detector = Arc::new(detector);
+        self
+    }
+
+    /// Runs the detector on a successful sign-in that has not been recorded yet, and the
+    /// suspicious login handlers if it is flagged. Detection is skipped when no handler
+    /// is bound.
+    pub(crate) async fn check_login(&self, attempt: &SecurityEvent, db: &Pool<Postgres>) {
+        if self.suspicious_login.is_empty() {
+            return;
+        }
+
+        let reasons = match self.detector.detect(attempt, db).await {
+            Ok(reasons) if !reasons.is_empty() => reasons,
+            _ => return,
+        };
+
+        let login = SuspiciousLogin {
+            event: attempt.clone(),
+            reasons,
+        };
+        for handler in &self.suspicious_login {
+            handler(&login);
+        }
+    }
+
+    pub(crate) fn status_changed(&self, change: &AccountStatusChange) {
+        for handler in &self.status_change {
+            handler(change);
+        }
+    }
+}
+
+impl Default for AuthHooks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for AuthHooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuthHooks")
+            .field("status_change", &self.status_change.len())
+            .field("suspicious_login", &self.suspicious_login.len())
+            .finish_non_exhaustive()
+    }
+}
@@ -22,14 +22,113 @@
 //! let verified = JWTClaims::verify(&token, "secret").unwrap();
 //! assert_eq!(claims.subject, verified.subject);
 //! ```
+//!
+//! # Validation
+//!
+//! [`JWTClaims::verify`] accepts tokens up to [`DEFAULT_LEEWAY`] past their `exp` or before
+//! their `nbf`, so small clock differences between services do not reject valid tokens.
+//! [`JWTClaims::verify_with`] takes [`VerifyOptions`] to change the leeway, to require a
+//! specific issuer or audience, and to require claims beyond the standard ones:
+//!
+//! ```rust
+//! # use palmera_auth::jwt::{JWTClaims, VerifyOptions};
+//! # use chrono::Duration;
+//! # use uuid::Uuid;
+//! let token = JWTClaims::new(Uuid::new_v4(), Duration::minutes(5), "issuer".into(), "audience".into())
+//!     .sign("secret")
+//!     .unwrap();
+//!
+//! let options = VerifyOptions::default()
+//!     .with_leeway(Duration::seconds(5))
+//!     .with_issuer("issuer");
+//! assert!(JWTClaims::verify_with(&token, "secret", &options).is_ok());
+//!
+//! let options = VerifyOptions::default().with_audience("another-service");
+//! assert!(JWTClaims::verify_with(&token, "secret", &options).is_err());
+//!
+//! let options = VerifyOptions::default().with_required_claim("scope");
+//! assert!(JWTClaims::verify_with(&token, "secret", &options).is_err());
+//! ```
+//!
+//! # Impersonation
+//!
+//! A token issued to act on behalf of another user, e.g. by an admin impersonating them
+//! or an integration's API key, names that user as its subject and the party actually
+//! making the requests in the `act` claim of RFC 8693, see
+//! [`AuthConfig::issue_impersonation_token`](crate::AuthConfig::issue_impersonation_token)
+//! and [`AuthConfig::issue_api_key_token`](crate::AuthConfig::issue_api_key_token).
+//! Policies apply to the subject; [`JWTClaims::actor_id`] tells audit records who was
+//! behind the request.
+//!
+//! # Edge runtimes
+//!
+//! This module only depends on `anyhow`, `chrono`, `hmac`, `jwt`, `serde`, `serde_json`,
+//! `sha2` and `uuid`, so proxies and edge workers can verify Palmera tokens with the
+//! same rules as the server by depending on `palmera-auth` with
+//! `default-features = false` (plus the `wasm` feature on `wasm32-unknown-unknown`).
+//! Runtimes without a usable system clock can pass the current time to
+//! [`JWTClaims::verify_at`].
+
+use std::collections::BTreeMap;
 
 use chrono::{DateTime, Duration, Utc};
 use hmac::{Hmac, Mac};
-use jwt::{SignWithKey, VerifyWithKey};
+use jwt::{Header, SignWithKey, Token, VerifyWithKey};
 use serde::{Deserialize, Serialize};
 use sha2::Sha256;
 use uuid::Uuid;
 
+use crate::scope::Scopes;
+
+/// Clock skew tolerated by [`JWTClaims::verify`] when checking `exp` and `nbf`.
+pub const DEFAULT_LEEWAY: Duration = Duration::seconds(60);
+
+/// Checks applied by [`JWTClaims::verify_with`] beyond the signature.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifyOptions {
+    /// Clock skew tolerated when checking `exp` and `nbf`.
+    pub leeway: Duration,
+    /// When set, the token's `iss` must equal this value.
+    pub issuer: Option<String>,
+    /// When set, the token's `aud` must equal this value.
+    pub audience: Option<String>,
+    /// Claims that must be present in the token, e.g. custom claims added by the issuer.
+    pub required_claims: Vec<String>,
+}
+
+impl Default for VerifyOptions {
+    fn default() -> Self {
+        Self {
+            leeway: DEFAULT_LEEWAY,
+            issuer: None,
+            audience: None,
+            required_claims: vec![],
+        }
+    }
+}
+
+impl VerifyOptions {
+    pub fn with_leeway(mut self, leeway: Duration) -> Self {
+        self.leeway = leeway;
+        self
+    }
+
+    pub fn with_issuer(mut self, issuer: &str) -> Self {
+        self.issuer = Some(issuer.to_string());
+        self
+    }
+
+    pub fn with_audience(mut self, audience: &str) -> Self {
+        self.audience = Some(audience.to_string());
+        self
+    }
+
+    pub fn with_required_claim(mut self, claim: &str) -> Self {
+        self.required_claims.push(claim.to_string());
+        self
+    }
+}
+
 /// Represents the standard claims contained in a JWT (JSON Web Token).
 ///
 /// This struct is serializable and deserializable via Serde, and is compatible
@@ -57,6 +156,19 @@ pub struct JWTClaims {
     /// JWT ID (unique identifier for the token).
     #[serde(rename = "jti")]
     pub jwt_token_id: Uuid,
+    /// Scopes the token is restricted to; `None` grants the subject's full rights.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<Scopes>,
+    /// Party acting on behalf of the subject, if it isn't the subject itself.
+    #[serde(rename = "act", default, skip_serializing_if = "Option::is_none")]
+    pub actor: Option<Actor>,
+}
+
+/// The `act` claim of a token used on behalf of its subject.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Actor {
+    #[serde(rename = "sub")]
+    pub subject: Uuid,
 }
 
 impl JWTClaims {
@@ -83,9 +195,43 @@ impl JWTClaims {
             audience,
             not_before_time: now - Duration::milliseconds(250),
             jwt_token_id: Uuid::new_v4(),
+            scope: None,
+            actor: None,
         }
     }
 
+    /// Restricts the token to `scopes`.
+    pub fn with_scopes(mut self, scopes: Scopes) -> Self {
+        self.scope = Some(scopes);
+        self
+    }
+
+    /// Marks the token as used by `actor` on behalf of the subject.
+    pub fn with_actor(mut self, actor: Uuid) -> Self {
+        self.actor = Some(Actor { subject: actor });
+        self
+    }
+
+    /// Id of the party making the requests: the actor of an impersonation token, the
+    /// subject otherwise.
+    pub fn actor_id(&self) -> Uuid {
+        self.actor.map_or(self.subject, |actor| actor.subject)
+    }
+
+    /// Returns whether the token acts on behalf of its subject.
+    pub fn is_impersonation(&self) -> bool {
+        self.actor
+            .is_some_and(|actor| actor.subject != self.subject)
+    }
+
+    /// Returns whether the token may be used for `required`; unscoped tokens may be used
+    /// for anything.
+    pub fn has_scope(&self, required: &str) -> bool {
+        self.scope
+            .as_ref()
+            .is_none_or(|scopes| scopes.grants(required))
+    }
+
     /// Signs the claims and returns a JWT string using the provided secret key.
     ///
     /// # Arguments
@@ -101,7 +247,8 @@ impl JWTClaims {
         Ok(self.sign_with_key(&key)?)
     }
 
-    /// Verifies a JWT string and returns the decoded claims if valid.
+    /// Verifies a JWT string and returns the decoded claims if valid, with the default
+    /// [`VerifyOptions`].
     ///
     /// # Arguments
     ///
@@ -112,22 +259,66 @@ impl JWTClaims {
     ///
     /// Returns an error if verification fails or the key is invalid.
     pub fn verify(token: &str, key: &str) -> Result<Self, anyhow::Error> {
+        Self::verify_with(token, key, &VerifyOptions::default())
+    }
+
+    /// Verifies a JWT string, applying the checks in `options`.
+    pub fn verify_with(
+        token: &str,
+        key: &str,
+        options: &VerifyOptions,
+    ) -> Result<Self, anyhow::Error> {
+        Self::verify_at(token, key, options, Utc::now())
+    }
+
+    /// Like [`JWTClaims::verify_with`], checking the token's validity period against
+    /// `now` instead of the system clock.
+    pub fn verify_at(
+        token: &str,
+        key: &str,
+        options: &VerifyOptions,
+        now: DateTime<Utc>,
+    ) -> Result<Self, anyhow::Error> {
         let key: Hmac<Sha256> = Hmac::new_from_slice(key.as_bytes())?;
 
         let claims: JWTClaims = token.verify_with_key(&key)?;
 
-        let now = Utc::now();
-
         // Check if the token is expired
-        if now > claims.expiration {
+        if now > claims.expiration + options.leeway {
             return Err(anyhow::anyhow!("Token expired"));
         }
 
         // Check if the token is not yet valid
-        if now < claims.not_before_time {
+        if now < claims.not_before_time - options.leeway {
             return Err(anyhow::anyhow!("Token not yet valid"));
         }
 
+        if let Some(issuer) = &options.issuer
+            && &claims.issuer != issuer
+        {
+            return Err(anyhow::anyhow!("Token issuer mismatch"));
+        }
+
+        if let Some(audience) = &options.audience
+            && &claims.audience != audience
+        {
+            return Err(anyhow::anyhow!("Token audience mismatch"));
+        }
+
+        if !options.required_claims.is_empty() {
+            // The signature was checked above; this only reads the raw payload.
+            let raw: Token<Header, BTreeMap<String, serde_json::Value>, _> =
+                Token::parse_unverified(token)?;
+
+            if let Some(missing) = options
+                .required_claims
+                .iter()
+                .find(|claim| !raw.claims().contains_key(claim.as_str()))
+            {
+                return Err(anyhow::anyhow!("Token is missing claim `{}`", missing));
+            }
+        }
+
         Ok(claims)
     }
 }
@@ -136,7 +327,7 @@ impl JWTClaims {
 mod tests {
     use super::*;
     use chrono::{Duration, Utc};
-    use sqlx::types::Uuid;
+    use uuid::Uuid;
 
     const SECRET: &str = "supersecretkey";
 
@@ -159,7 +350,7 @@ mod tests {
         let subject = Uuid::new_v4();
         let claims = JWTClaims::new(
             subject,
-            Duration::seconds(-1), // already expired
+            -(DEFAULT_LEEWAY + Duration::seconds(1)), // expired, beyond the leeway
             "issuer".to_string(),
             "audience".to_string(),
         );
@@ -184,6 +375,137 @@ mod tests {
         assert!(err.to_string().contains("not yet valid"));
     }
 
+    #[test]
+    fn test_jwt_verify_at() {
+        let claims = JWTClaims::new(
+            Uuid::new_v4(),
+            Duration::minutes(10),
+            "issuer".to_string(),
+            "audience".to_string(),
+        );
+        let token = claims.clone().sign(SECRET).expect("signing failed");
+        let options = VerifyOptions::default();
+
+        assert!(JWTClaims::verify_at(&token, SECRET, &options, claims.issued_at).is_ok());
+        assert!(
+            JWTClaims::verify_at(
+                &token,
+                SECRET,
+                &options,
+                claims.expiration + DEFAULT_LEEWAY + Duration::seconds(1)
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_jwt_leeway() {
+        let claims = JWTClaims::new(
+            Uuid::new_v4(),
+            Duration::minutes(10),
+            "issuer".to_string(),
+            "audience".to_string(),
+        );
+        let token = claims.clone().sign(SECRET).expect("signing failed");
+        let late = claims.expiration + Duration::seconds(30);
+        let early = claims.not_before_time - Duration::seconds(30);
+
+        let options = VerifyOptions::default();
+        assert!(JWTClaims::verify_at(&token, SECRET, &options, late).is_ok());
+        assert!(JWTClaims::verify_at(&token, SECRET, &options, early).is_ok());
+
+        let strict = VerifyOptions::default().with_leeway(Duration::zero());
+        let err = JWTClaims::verify_at(&token, SECRET, &strict, late).unwrap_err();
+        assert!(err.to_string().contains("expired"));
+        let err = JWTClaims::verify_at(&token, SECRET, &strict, early).unwrap_err();
+        assert!(err.to_string().contains("not yet valid"));
+    }
+
+    #[test]
+    fn test_jwt_issuer_and_audience() {
+        let claims = JWTClaims::new(
+            Uuid::new_v4(),
+            Duration::minutes(10),
+            "issuer".to_string(),
+            "audience".to_string(),
+        );
+        let token = claims.sign(SECRET).expect("signing failed");
+
+        let options = VerifyOptions::default()
+            .with_issuer("issuer")
+            .with_audience("audience");
+        assert!(JWTClaims::verify_with(&token, SECRET, &options).is_ok());
+
+        let options = VerifyOptions::default().with_issuer("other");
+        let err = JWTClaims::verify_with(&token, SECRET, &options).unwrap_err();
+        assert!(err.to_string().contains("issuer"));
+
+        let options = VerifyOptions::default().with_audience("other");
+        let err = JWTClaims::verify_with(&token, SECRET, &options).unwrap_err();
+        assert!(err.to_string().contains("audience"));
+    }
+
+    #[test]
+    fn test_jwt_required_claims() {
+        let claims = JWTClaims::new(
+            Uuid::new_v4(),
+            Duration::minutes(10),
+            "issuer".to_string(),
+            "audience".to_string(),
+        );
+        let token = claims.sign(SECRET).expect("signing failed");
+
+        let options = VerifyOptions::default()
+            .with_required_claim("sub")
+            .with_required_claim("jti");
+        assert!(JWTClaims::verify_with(&token, SECRET, &options).is_ok());
+
+        let options = VerifyOptions::default().with_required_claim("scope");
+        let err = JWTClaims::verify_with(&token, SECRET, &options).unwrap_err();
+        assert!(err.to_string().contains("scope"));
+    }
+
+    #[test]
+    fn test_jwt_scopes() {
+        let claims = JWTClaims::new(
+            Uuid::new_v4(),
+            Duration::minutes(10),
+            "issuer".to_string(),
+            "audience".to_string(),
+        );
+        assert!(claims.has_scope("records:write"));
+
+        let scoped = claims.with_scopes(Scopes::new().with("records:read"));
+        let token = scoped.clone().sign(SECRET).expect("signing failed");
+        let verified = JWTClaims::verify(&token, SECRET).expect("verification failed");
+        assert_eq!(verified, scoped);
+        assert!(verified.has_scope("records:read:posts"));
+        assert!(!verified.has_scope("records:write"));
+    }
+
+    #[test]
+    fn test_jwt_actor() {
+        let (subject, admin) = (Uuid::new_v4(), Uuid::new_v4());
+        let claims = JWTClaims::new(
+            subject,
+            Duration::minutes(10),
+            "issuer".to_string(),
+            "audience".to_string(),
+        );
+        assert_eq!(claims.actor_id(), subject);
+        assert!(!claims.is_impersonation());
+
+        let impersonated = claims.with_actor(admin);
+        let token = impersonated.clone().sign(SECRET).expect("signing failed");
+        let raw: Token<Header, BTreeMap<String, serde_json::Value>, _> =
+            Token::parse_unverified(&token).unwrap();
+        assert_eq!(raw.claims()["act"], serde_json::json!({ "sub": admin }));
+        let verified = JWTClaims::verify(&token, SECRET).expect("verification failed");
+        assert_eq!(verified, impersonated);
+        assert_eq!(verified.actor_id(), admin);
+        assert!(verified.is_impersonation());
+    }
+
     #[test]
     fn test_jwt_invalid_signature() {
         let subject = Uuid::new_v4();
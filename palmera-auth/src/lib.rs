@@ -1,16 +1,50 @@
-use sqlx::{Pool, Postgres};
-
+#[cfg(feature = "server")]
+pub mod admin;
+#[cfg(feature = "server")]
+pub mod challenge;
+pub mod config;
+#[cfg(feature = "server")]
+pub mod device;
+#[cfg(feature = "server")]
+pub mod extract;
+#[cfg(feature = "server")]
+pub mod hooks;
 pub mod jwt;
+#[cfg(feature = "server")]
+pub mod openapi;
+#[cfg(feature = "mysql")]
+pub mod mysql;
+#[cfg(feature = "server")]
+pub mod otp;
+#[cfg(feature = "server")]
+pub mod permissions;
+#[cfg(feature = "server")]
+pub mod providers;
+#[cfg(feature = "server")]
+pub mod proxy;
+#[cfg(feature = "server")]
 pub mod router;
+#[cfg(feature = "server")]
 pub mod schemas;
+pub mod scope;
+#[cfg(feature = "server")]
+pub mod security;
+#[cfg(feature = "server")]
+pub mod session;
+#[cfg(feature = "server")]
+pub mod setup;
+#[cfg(feature = "server")]
+pub mod status;
+pub mod urls;
+#[cfg(feature = "server")]
+pub mod verification;
 
-#[derive(Debug, Clone)]
-pub struct AuthConfig {
-    issuer: String,
-    audience: String,
-    key: String,
-}
+pub use config::{AuthConfig, AuthConfigBuilder};
+
+#[cfg(feature = "server")]
+use sqlx::{Pool, Postgres};
 
+#[cfg(feature = "server")]
 pub async fn migrate(db: &Pool<Postgres>) -> anyhow::Result<()> {
     Ok(sqlx::migrate!("./migrations").run(db).await?)
 }
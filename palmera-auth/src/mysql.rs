@@ -0,0 +1,108 @@
+//! # MySQL auth schema
+//!
+//! The auth tables for MySQL 8.0.13+ and MariaDB 10.5+, built with the `mysql` feature.
+//! MySQL has no schemas besides databases, so the tables of the Postgres `auth` schema
+//! live in the current database with an `auth_` prefix: `auth_users`,
+//! `auth_device_codes`, `auth_security_events`, `auth_otp_codes`, `auth_providers` and
+//! `auth_instance`. Their columns match the Postgres ones, with UUIDs stored as
+//! `char(36)` text and booleans as `tinyint(1)`.
+//!
+//! [`migrate`] creates them from `mysql-migrations/`, which is kept in step with the
+//! Postgres `migrations/`.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # async fn run() -> anyhow::Result<()> {
+//! let db = sqlx::MySqlPool::connect("mysql://localhost/palmera").await?;
+//! palmera_auth::mysql::migrate(&db).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use sqlx::{MySql, Pool};
+
+/// Creates or updates the auth tables in the current database of `db`.
+pub async fn migrate(db: &Pool<MySql>) -> anyhow::Result<()> {
+    Ok(sqlx::migrate!("./mysql-migrations").run(db).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schemas::AuthUser;
+
+    #[sqlx::test(migrations = "./mysql-migrations")]
+    async fn test_users_crud(db: Pool<MySql>) -> anyhow::Result<()> {
+        let user = AuthUser::new("mysql@example.com", "password");
+        sqlx::query("INSERT INTO auth_users (id, email, password) VALUES (?, ?, ?)")
+            .bind(user.id.to_string())
+            .bind(&user.email)
+            .bind(&user.password)
+            .execute(&db)
+            .await?;
+
+        let (email, status, verified): (Option<String>, String, bool) =
+            sqlx::query_as("SELECT email, status, email_verified FROM auth_users WHERE id = ?")
+                .bind(user.id.to_string())
+                .fetch_one(&db)
+                .await?;
+        assert_eq!(email, user.email);
+        assert_eq!(status, "active");
+        assert!(!verified);
+
+        sqlx::query("UPDATE auth_users SET status = 'locked' WHERE id = ?")
+            .bind(user.id.to_string())
+            .execute(&db)
+            .await?;
+        let unknown = sqlx::query("UPDATE auth_users SET status = 'gone' WHERE id = ?")
+            .bind(user.id.to_string())
+            .execute(&db)
+            .await;
+        assert!(unknown.is_err());
+        let anonymous = sqlx::query("INSERT INTO auth_users (password) VALUES ('x')")
+            .execute(&db)
+            .await;
+        assert!(anonymous.is_err(), "an email or a phone is required");
+
+        // Deleting the user deletes their device codes and security events.
+        sqlx::query(
+            "INSERT INTO auth_device_codes (device_code, user_code, user_id, expires) \
+             VALUES ('device', 'ABCD-EFGH', ?, now())",
+        )
+        .bind(user.id.to_string())
+        .execute(&db)
+        .await?;
+        sqlx::query(
+            "INSERT INTO auth_security_events (user_id, kind, success) VALUES (?, 'login', true)",
+        )
+        .bind(user.id.to_string())
+        .execute(&db)
+        .await?;
+        sqlx::query("DELETE FROM auth_users WHERE id = ?")
+            .bind(user.id.to_string())
+            .execute(&db)
+            .await?;
+        let (codes, events): (i64, i64) = sqlx::query_as(
+            "SELECT (SELECT count(*) FROM auth_device_codes), \
+                    (SELECT count(*) FROM auth_security_events)",
+        )
+        .fetch_one(&db)
+        .await?;
+        assert_eq!((codes, events), (0, 0));
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = "./mysql-migrations")]
+    async fn test_builtin_providers(db: Pool<MySql>) -> anyhow::Result<()> {
+        let providers: Vec<(String, bool)> =
+            sqlx::query_as("SELECT name, enabled FROM auth_providers ORDER BY name")
+                .fetch_all(&db)
+                .await?;
+        assert_eq!(
+            providers,
+            [("password".to_string(), true), ("phone".to_string(), true)]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,106 @@
+//! # OpenAPI security
+//!
+//! Describes how Palmera routes authenticate in the OpenAPI document, so Swagger UI and
+//! generated clients send credentials where they are needed: [`BEARER_AUTH`], an access
+//! token in the `Authorization: Bearer` header. API keys are presented the same way, as
+//! the tokens issued by [`AuthConfig::issue_api_key_token`](crate::AuthConfig::issue_api_key_token).
+//!
+//! [`SecurityAddon`] registers the scheme, and the auth routers start from [`document`],
+//! so their OpenAPI document declares it. The routes that require it say so in their
+//! `#[utoipa::path]` annotations.
+//!
+//! # Example
+//!
+//! ```rust
+//! use palmera_auth::openapi::BEARER_AUTH;
+//!
+//! let (_router, api) = palmera_auth::router::router().split_for_parts();
+//! let schemes = &api.components.unwrap().security_schemes;
+//! assert!(schemes.contains_key(BEARER_AUTH));
+//! ```
+
+use utoipa::{
+    Modify,
+    openapi::{
+        OpenApi, OpenApiBuilder,
+        security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
+    },
+};
+
+/// Name of the bearer token security scheme.
+pub const BEARER_AUTH: &str = "bearer_auth";
+
+/// Registers the [`BEARER_AUTH`] security scheme.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SecurityAddon;
+
+impl Modify for SecurityAddon {
+    fn modify(&self, openapi: &mut OpenApi) {
+        openapi
+            .components
+            .get_or_insert_with(Default::default)
+            .add_security_scheme(
+                BEARER_AUTH,
+                SecurityScheme::Http(
+                    HttpBuilder::new()
+                        .scheme(HttpAuthScheme::Bearer)
+                        .bearer_format("JWT")
+                        .build(),
+                ),
+            );
+    }
+}
+
+/// An empty OpenAPI document with [`SecurityAddon`] applied, for routers whose routes
+/// require [`BEARER_AUTH`] to start from.
+pub fn document() -> OpenApi {
+    let mut openapi = OpenApiBuilder::new().build();
+    SecurityAddon.modify(&mut openapi);
+    openapi
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utoipa::openapi::path::HttpMethod;
+
+    fn security(api: &OpenApi, path: &str, method: HttpMethod) -> Option<Vec<String>> {
+        let item = api.paths.paths.get(path)?;
+        let operation = match method {
+            HttpMethod::Get => item.get.as_ref(),
+            HttpMethod::Post => item.post.as_ref(),
+            HttpMethod::Put => item.put.as_ref(),
+            _ => None,
+        }?;
+        let requirements = serde_json::to_value(operation.security.as_ref()?).unwrap();
+
+        Some(
+            requirements
+                .as_array()
+                .unwrap()
+                .iter()
+                .flat_map(|requirement| requirement.as_object().unwrap().keys().cloned())
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_auth_routes_declare_security() {
+        let (_, api) = crate::router::router()
+            .merge(crate::admin::router())
+            .split_for_parts();
+
+        let schemes = &api.components.as_ref().unwrap().security_schemes;
+        assert_eq!(schemes.keys().collect::<Vec<_>>(), [BEARER_AUTH]);
+
+        assert_eq!(
+            security(&api, "/userinfo", HttpMethod::Get).unwrap(),
+            [BEARER_AUTH]
+        );
+        assert_eq!(
+            security(&api, "/admin/users/{id}/status", HttpMethod::Put).unwrap(),
+            [BEARER_AUTH]
+        );
+        assert!(security(&api, "/login", HttpMethod::Post).is_none());
+    }
+}
@@ -0,0 +1,918 @@
+//! # Phone sign-in with one-time codes
+//!
+//! Users can sign in with a phone number instead of an email and password:
+//!
+//! 1. The client calls `POST /otp/request` with the phone number. A random
+//!    [`OTP_CODE_LENGTH`]-digit code is sent to it by SMS and stays valid for
+//!    [`OTP_CODE_TTL`]. A number receives at most one code per [`OTP_COOLDOWN`] and
+//!    [`OTP_MAX_PER_WINDOW`] codes per [`OTP_RATE_WINDOW`], and a client IP address
+//!    gets at most [`OTP_MAX_PER_IP`] codes sent per window, whatever the numbers;
+//!    further requests are rejected with `429` and a `Retry-After` header. The limits are
+//!    checked by the insert of the code itself, so concurrent requests cannot both pass.
+//! 2. It submits the code to `POST /otp/verify` and receives an access token. The user
+//!    with this phone number is signed in, or created if there is none.
+//!
+//! Codes are single use and only the latest one is accepted, for at most
+//! [`OTP_MAX_ATTEMPTS`] tries. Each try uses up an attempt before the code is compared,
+//! so concurrent guesses cannot get more tries between them. Codes are stored in
+//! `auth.otp_codes` as an HMAC keyed with the signing secret, never in clear.
+//!
+//! Phone-only users have no email, which is why [`AuthUser::email`] is an
+//! `Option<String>` since phone sign-in was added. This is a breaking change for code
+//! reading it: users created through `/otp/verify` have `email: None`.
+//!
+//! With [`AuthConfig::require_challenge`], code requests must also pass a bot challenge
+//! (see [`crate::challenge`]).
+//!
+//! The crate does not talk to an SMS gateway itself: the routes hand each message to
+//! the [`SmsProvider`] found in the request extensions. With the `twilio` feature,
+//! [`TwilioSms`] sends them through Twilio.
+//!
+//! # Example
+//!
+//! ```rust
+//! use std::sync::Arc;
+//!
+//! use axum::Extension;
+//! use palmera_auth::otp::{SendFuture, SmsProvider};
+//!
+//! struct ConsoleSms;
+//!
+//! impl SmsProvider for ConsoleSms {
+//!     fn name(&self) -> &str {
+//!         "console"
+//!     }
+//!
+//!     fn send<'a>(&'a self, to: &'a str, body: &'a str) -> SendFuture<'a> {
+//!         Box::pin(async move {
+//!             println!("{} -> {}", body, to);
+//!             Ok(())
+//!         })
+//!     }
+//! }
+//!
+//! let provider: Arc<dyn SmsProvider> = Arc::new(ConsoleSms);
+//! let router = palmera_auth::otp::router().layer(Extension(provider));
+//! ```
+
+use std::{future::Future, pin::Pin, sync::Arc};
+
+use axum::{
+    Extension, Form,
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac};
+use password_hash::rand_core::{OsRng, RngCore};
+use sea_query::{Alias, Asterisk, Expr, Order, PostgresQueryBuilder, Query as SqlQuery};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::{Pool, Postgres, prelude::FromRow};
+use utoipa::ToSchema;
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+use crate::{
+    AuthConfig,
+    challenge::Challenge,
+    extract::ClientInfo,
+    providers::{self, AuthProviderKind},
+    router::ACCESS_TOKEN_TTL,
+    schemas::AuthUser,
+    security::{SecurityEvent, SecurityEventKind},
+};
+
+/// How long a one-time code can be used.
+pub const OTP_CODE_TTL: Duration = Duration::seconds(300);
+
+/// Number of digits of a one-time code.
+pub const OTP_CODE_LENGTH: usize = 6;
+
+/// How many wrong codes can be submitted before the code has to be requested again.
+pub const OTP_MAX_ATTEMPTS: i32 = 5;
+
+/// Minimum delay between two codes sent to the same number.
+pub const OTP_COOLDOWN: Duration = Duration::seconds(60);
+
+/// Window over which [`OTP_MAX_PER_WINDOW`] applies.
+pub const OTP_RATE_WINDOW: Duration = Duration::seconds(3600);
+
+/// Maximum number of codes sent to the same number per [`OTP_RATE_WINDOW`].
+pub const OTP_MAX_PER_WINDOW: usize = 5;
+
+/// Maximum number of codes sent on behalf of the same client IP address per
+/// [`OTP_RATE_WINDOW`], so one client cannot spread paid messages over many numbers.
+pub const OTP_MAX_PER_IP: usize = 10;
+
+/// Future returned by [`SmsProvider::send`].
+pub type SendFuture<'a> = Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>>;
+
+/// Delivers text messages, e.g. through an SMS gateway.
+///
+/// Routes look the provider up as an `Extension<Arc<dyn SmsProvider>>`.
+pub trait SmsProvider: Send + Sync {
+    /// Name of the provider, used in error messages.
+    fn name(&self) -> &str;
+
+    /// Sends `body` to the phone number `to`, in E.164 format.
+    fn send<'a>(&'a self, to: &'a str, body: &'a str) -> SendFuture<'a>;
+}
+
+/// Sends text messages with Twilio's Programmable Messaging API.
+#[cfg(feature = "twilio")]
+#[derive(Clone)]
+pub struct TwilioSms {
+    account_sid: String,
+    auth_token: String,
+    from: String,
+    client: reqwest::Client,
+}
+
+#[cfg(feature = "twilio")]
+impl TwilioSms {
+    /// Sends messages from the Twilio number or messaging service `from`.
+    pub fn new(account_sid: &str, auth_token: &str, from: &str) -> Self {
+        Self {
+            account_sid: account_sid.to_string(),
+            auth_token: auth_token.to_string(),
+            from: from.to_string(),
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+#[cfg(feature = "twilio")]
+impl std::fmt::Debug for TwilioSms {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TwilioSms")
+            .field("account_sid", &self.account_sid)
+            .field("from", &self.from)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "twilio")]
+impl SmsProvider for TwilioSms {
+    fn name(&self) -> &str {
+        "twilio"
+    }
+
+    fn send<'a>(&'a self, to: &'a str, body: &'a str) -> SendFuture<'a> {
+        Box::pin(async move {
+            let url = format!(
+                "https://api.twilio.com/2010-04-01/Accounts/{}/Messages.json",
+                self.account_sid
+            );
+            // Messaging service ids can be used in place of a sender number.
+            let from = if self.from.starts_with("MG") {
+                "MessagingServiceSid"
+            } else {
+                "From"
+            };
+
+            let response = self
+                .client
+                .post(url)
+                .basic_auth(&self.account_sid, Some(&self.auth_token))
+                .form(&[("To", to), (from, self.from.as_str()), ("Body", body)])
+                .send()
+                .await?;
+
+            let status = response.status();
+            if !status.is_success() {
+                let detail = response.text().await.unwrap_or_default();
+                anyhow::bail!("twilio responded with {}: {}", status, detail);
+            }
+
+            Ok(())
+        })
+    }
+}
+
+/// Normalizes a phone number as typed by a user to E.164, ignoring spaces, dashes,
+/// dots and parentheses. Numbers must include their country code.
+///
+/// Returns `None` if the input cannot be a phone number.
+///
+/// ```rust
+/// use palmera_auth::otp::normalize_phone;
+///
+/// assert_eq!(normalize_phone("+1 (415) 555-0123").as_deref(), Some("+14155550123"));
+/// assert_eq!(normalize_phone("415 555 0123"), None);
+/// ```
+pub fn normalize_phone(input: &str) -> Option<String> {
+    let phone: String = input
+        .chars()
+        .filter(|c| !c.is_whitespace() && !matches!(c, '-' | '.' | '(' | ')'))
+        .collect();
+
+    let digits = phone.strip_prefix('+')?;
+    let valid = (8..=15).contains(&digits.len())
+        && !digits.starts_with('0')
+        && digits.bytes().all(|b| b.is_ascii_digit());
+
+    valid.then_some(phone)
+}
+
+/// Hashes `code` for `phone`, keyed with the signing secret so stored codes cannot be
+/// brute-forced offline.
+fn hash_code(config: &AuthConfig, phone: &str, code: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(config.key.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(phone.as_bytes());
+    mac.update(b":");
+    mac.update(code.as_bytes());
+
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// A one-time code sent to a phone number, stored in `auth.otp_codes`.
+#[derive(Debug, FromRow, Clone, Serialize, Deserialize)]
+pub struct OtpCode {
+    /// Assigned by the database; `0` until inserted.
+    pub id: i64,
+    pub phone: String,
+    /// HMAC of the code, see [`OtpCode::matches`].
+    pub code_hash: String,
+    /// Codes submitted so far. A used code holds one more than [`OTP_MAX_ATTEMPTS`],
+    /// see [`OtpCode::consume`].
+    pub attempts: i32,
+    pub expires: DateTime<Utc>,
+    pub created: DateTime<Utc>,
+    /// IP address of the client that requested the code, if known.
+    pub ip: Option<String>,
+}
+
+impl OtpCode {
+    /// Creates a random code for `phone`, valid for [`OTP_CODE_TTL`]. Returns the code
+    /// to send along with its record.
+    pub fn new(config: &AuthConfig, phone: &str) -> (Self, String) {
+        let now = Utc::now();
+        let code: String = (0..OTP_CODE_LENGTH)
+            .map(|_| char::from(b'0' + (OsRng.next_u32() % 10) as u8))
+            .collect();
+
+        let otp = Self {
+            id: 0,
+            phone: phone.to_string(),
+            code_hash: hash_code(config, phone, &code),
+            attempts: 0,
+            expires: now + OTP_CODE_TTL,
+            created: now,
+            ip: None,
+        };
+
+        (otp, code)
+    }
+
+    /// Whether `code` is the code this record was created for.
+    pub fn matches(&self, config: &AuthConfig, code: &str) -> bool {
+        hash_code(config, &self.phone, code.trim()) == self.code_hash
+    }
+
+    pub fn is_expired(&self) -> bool {
+        Utc::now() > self.expires
+    }
+
+    // database operation
+
+    fn table() -> (Alias, Alias) {
+        (Alias::new("auth"), Alias::new("otp_codes"))
+    }
+
+    /// Insert this code into the database and return it as stored.
+    pub async fn insert(self, db: &Pool<Postgres>) -> anyhow::Result<Self> {
+        let sql = SqlQuery::insert()
+            .into_table(Self::table())
+            .columns([
+                Alias::new("phone"),
+                Alias::new("code_hash"),
+                Alias::new("expires"),
+                Alias::new("created"),
+                Alias::new("ip"),
+            ])
+            .values([
+                self.phone.into(),
+                self.code_hash.into(),
+                self.expires.into(),
+                self.created.into(),
+                self.ip.into(),
+            ])?
+            .returning_all()
+            .to_string(PostgresQueryBuilder);
+
+        let result = sqlx::query_as::<_, Self>(&sql).fetch_one(db).await?;
+
+        Ok(result)
+    }
+
+    /// Insert this code unless its number reached [`OTP_COOLDOWN`] or
+    /// [`OTP_MAX_PER_WINDOW`], or its client IP address [`OTP_MAX_PER_IP`]. Returns it
+    /// as stored, or `None` if it was not inserted.
+    ///
+    /// The limits are checked by the insert itself, under transaction-level advisory
+    /// locks on the number and address, so concurrent requests cannot both pass them.
+    pub async fn insert_within_limits(self, db: &Pool<Postgres>) -> anyhow::Result<Option<Self>> {
+        let mut tx = db.begin().await?;
+        // Always the number first, then the address, so two requests cannot wait on
+        // each other.
+        for key in std::iter::once(&self.phone).chain(&self.ip) {
+            sqlx::query("select pg_advisory_xact_lock(hashtextextended($1, 0))")
+                .bind(format!("auth.otp_codes:{}", key))
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        let result = sqlx::query_as::<_, Self>(
+            r#"
+            insert into auth.otp_codes (phone, code_hash, expires, created, ip)
+            select $1, $2, $3, $4, $5
+            where not exists (
+                select 1 from auth.otp_codes where phone = $1 and created > $6
+              )
+              and (select count(*) from auth.otp_codes where phone = $1 and created > $7) < $8
+              and (
+                $5::text is null
+                or (select count(*) from auth.otp_codes where ip = $5 and created > $7) < $9
+              )
+            returning *
+            "#,
+        )
+        .bind(&self.phone)
+        .bind(&self.code_hash)
+        .bind(self.expires)
+        .bind(self.created)
+        .bind(&self.ip)
+        .bind(self.created - OTP_COOLDOWN)
+        .bind(self.created - OTP_RATE_WINDOW)
+        .bind(OTP_MAX_PER_WINDOW as i64)
+        .bind(OTP_MAX_PER_IP as i64)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(result)
+    }
+
+    /// Find the codes sent to `phone` since `since`, newest first.
+    pub async fn sent_since(
+        phone: &str,
+        since: DateTime<Utc>,
+        db: &Pool<Postgres>,
+    ) -> anyhow::Result<Vec<Self>> {
+        let sql = SqlQuery::select()
+            .from(Self::table())
+            .column(Asterisk)
+            .and_where(Expr::col("phone").eq(phone))
+            .and_where(Expr::col("created").gt(since))
+            .order_by(Alias::new("created"), Order::Desc)
+            .to_string(PostgresQueryBuilder);
+
+        let result = sqlx::query_as::<_, Self>(&sql).fetch_all(db).await?;
+
+        Ok(result)
+    }
+
+    /// Find the codes requested from the IP address `ip` since `since`, newest first.
+    pub async fn sent_from(
+        ip: &str,
+        since: DateTime<Utc>,
+        db: &Pool<Postgres>,
+    ) -> anyhow::Result<Vec<Self>> {
+        let sql = SqlQuery::select()
+            .from(Self::table())
+            .column(Asterisk)
+            .and_where(Expr::col("ip").eq(ip))
+            .and_where(Expr::col("created").gt(since))
+            .order_by(Alias::new("created"), Order::Desc)
+            .to_string(PostgresQueryBuilder);
+
+        let result = sqlx::query_as::<_, Self>(&sql).fetch_all(db).await?;
+
+        Ok(result)
+    }
+
+    /// Find the latest code sent to `phone`.
+    pub async fn latest(phone: &str, db: &Pool<Postgres>) -> anyhow::Result<Option<Self>> {
+        let sql = SqlQuery::select()
+            .from(Self::table())
+            .column(Asterisk)
+            .and_where(Expr::col("phone").eq(phone))
+            .order_by(Alias::new("created"), Order::Desc)
+            .order_by(Alias::new("id"), Order::Desc)
+            .limit(1)
+            .to_string(PostgresQueryBuilder);
+
+        let result = sqlx::query_as::<_, Self>(&sql).fetch_optional(db).await?;
+
+        Ok(result)
+    }
+
+    /// Use up one attempt of this code, returning it as stored afterwards, or `None` if
+    /// it has expired or has no attempts left. The code should only be compared once
+    /// its attempt was taken, so concurrent guesses can't exceed [`OTP_MAX_ATTEMPTS`].
+    pub async fn attempt(&self, db: &Pool<Postgres>) -> anyhow::Result<Option<Self>> {
+        let sql = SqlQuery::update()
+            .table(Self::table())
+            .value(Alias::new("attempts"), Expr::col("attempts").add(1))
+            .and_where(Expr::col("id").eq(self.id))
+            .and_where(Expr::col("attempts").lt(OTP_MAX_ATTEMPTS))
+            .and_where(Expr::col("expires").gt(Expr::current_timestamp()))
+            .returning_all()
+            .to_string(PostgresQueryBuilder);
+
+        let result = sqlx::query_as::<_, Self>(&sql).fetch_optional(db).await?;
+
+        Ok(result)
+    }
+
+    /// Mark this code as used after a matching [`OtpCode::attempt`], so it cannot be
+    /// submitted again. Returns `false` if it was used concurrently.
+    pub async fn consume(&self, db: &Pool<Postgres>) -> anyhow::Result<bool> {
+        let sql = SqlQuery::update()
+            .table(Self::table())
+            .value(Alias::new("attempts"), OTP_MAX_ATTEMPTS + 1)
+            .and_where(Expr::col("id").eq(self.id))
+            .and_where(Expr::col("attempts").lte(OTP_MAX_ATTEMPTS))
+            .to_string(PostgresQueryBuilder);
+
+        let result = sqlx::query(&sql).execute(db).await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Delete this code, e.g. when it could not be sent.
+    pub async fn delete(&self, db: &Pool<Postgres>) -> anyhow::Result<()> {
+        let sql = SqlQuery::delete()
+            .from_table(Self::table())
+            .and_where(Expr::col("id").eq(self.id))
+            .to_string(PostgresQueryBuilder);
+
+        sqlx::query(&sql).execute(db).await?;
+
+        Ok(())
+    }
+}
+
+/// Seconds until another code may be sent to a number that received `sent` within the
+/// last [`OTP_RATE_WINDOW`], for a client that requested `requested` within it, both
+/// newest first, or `None` if one may be sent now.
+fn retry_after(sent: &[OtpCode], requested: &[OtpCode], now: DateTime<Utc>) -> Option<i64> {
+    let cooldown = sent
+        .first()
+        .map(|latest| latest.created + OTP_COOLDOWN - now);
+    let window = sent
+        .get(OTP_MAX_PER_WINDOW - 1)
+        .map(|oldest| oldest.created + OTP_RATE_WINDOW - now);
+    let ip_window = requested
+        .get(OTP_MAX_PER_IP - 1)
+        .map(|oldest| oldest.created + OTP_RATE_WINDOW - now);
+
+    cooldown
+        .into_iter()
+        .chain(window)
+        .chain(ip_window)
+        .max()
+        .filter(|wait| *wait > Duration::zero())
+        .map(|wait| wait.num_seconds().max(1))
+}
+
+#[derive(Debug, ToSchema, Serialize, Deserialize)]
+pub struct OtpRequestPayload {
+    /// Phone number including the country code, e.g. `+14155550123`.
+    phone: String,
+}
+
+/// Sends a one-time code to a phone number.
+#[utoipa::path(
+    post,
+    path = "/otp/request",
+    responses((status = 202), (status = 400), (status = 403), (status = 429), (status = 502))
+)]
+async fn request_otp(
+    Extension(db): Extension<Pool<Postgres>>,
+    Extension(config): Extension<AuthConfig>,
+    Extension(provider): Extension<Arc<dyn SmsProvider>>,
+    client: ClientInfo,
+    _: Challenge,
+    Form(form): Form<OtpRequestPayload>,
+) -> Result<StatusCode, Response> {
+    providers::require_enabled(AuthProviderKind::Phone, &db)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    let phone = normalize_phone(&form.phone).ok_or(StatusCode::BAD_REQUEST.into_response())?;
+    let internal = |_| StatusCode::INTERNAL_SERVER_ERROR.into_response();
+
+    let (otp, code) = OtpCode::new(&config, &phone);
+    let otp = OtpCode {
+        ip: client.ip.map(|ip| ip.to_string()),
+        ..otp
+    };
+    let ip = otp.ip.clone();
+    let Some(otp) = otp.insert_within_limits(&db).await.map_err(internal)? else {
+        let now = Utc::now();
+        let sent = OtpCode::sent_since(&phone, now - OTP_RATE_WINDOW, &db)
+            .await
+            .map_err(internal)?;
+        let requested = match &ip {
+            Some(ip) => OtpCode::sent_from(ip, now - OTP_RATE_WINDOW, &db)
+                .await
+                .map_err(internal)?,
+            None => Vec::new(),
+        };
+        // A limit that expired in the meantime still asks for a retry, just a quick one.
+        let retry_after = retry_after(&sent, &requested, now).unwrap_or(1);
+
+        return Err((
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, retry_after.to_string())],
+        )
+            .into_response());
+    };
+
+    let body = format!(
+        "Your verification code is {}. It expires in {} minutes.",
+        code,
+        OTP_CODE_TTL.num_minutes()
+    );
+
+    if provider.send(&phone, &body).await.is_err() {
+        // The code never reached the user, so it must not count against their limit.
+        let _ = otp.delete(&db).await;
+        return Err(StatusCode::BAD_GATEWAY.into_response());
+    }
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[derive(Debug, ToSchema, Serialize, Deserialize)]
+pub struct OtpVerifyPayload {
+    phone: String,
+    code: String,
+}
+
+/// Checks the latest code sent to `phone`, using up an attempt before comparing it.
+async fn check_code(
+    db: &Pool<Postgres>,
+    config: &AuthConfig,
+    phone: &str,
+    code: &str,
+) -> Result<(), (&'static str, StatusCode)> {
+    let internal = |_| ("database error", StatusCode::INTERNAL_SERVER_ERROR);
+
+    let otp = OtpCode::latest(phone, db)
+        .await
+        .map_err(internal)?
+        .ok_or(("no code sent", StatusCode::UNAUTHORIZED))?;
+
+    let otp = otp
+        .attempt(db)
+        .await
+        .map_err(internal)?
+        .ok_or(("code expired", StatusCode::UNAUTHORIZED))?;
+
+    if !otp.matches(config, code) {
+        return Err(("invalid code", StatusCode::UNAUTHORIZED));
+    }
+
+    if !otp.consume(db).await.map_err(internal)? {
+        return Err(("code expired", StatusCode::UNAUTHORIZED));
+    }
+
+    Ok(())
+}
+
+/// Returns the user with `phone`, marking the number as verified, or creates one.
+async fn sign_in(db: &Pool<Postgres>, phone: &str) -> Result<AuthUser, (&'static str, StatusCode)> {
+    let internal = |_| ("database error", StatusCode::INTERNAL_SERVER_ERROR);
+
+    let user = match AuthUser::find_by_phone(phone, db).await.map_err(internal)? {
+        Some(user) if user.phone_verified => user,
+        Some(user) => user.confirm_phone(db).await.map_err(internal)?,
+        None => match AuthUser::with_phone(phone).insert(db).await {
+            Ok(user) => user,
+            // Another request created the user in the meantime.
+            Err(_) => AuthUser::find_by_phone(phone, db)
+                .await
+                .map_err(internal)?
+                .ok_or(("database error", StatusCode::INTERNAL_SERVER_ERROR))?,
+        },
+    };
+
+    if !user.status.is_active() {
+        return Err(("account not active", StatusCode::FORBIDDEN));
+    }
+
+    Ok(user)
+}
+
+/// Exchanges a one-time code for an access token, creating the user on first sign-in.
+#[utoipa::path(
+    post,
+    path = "/otp/verify",
+    responses((status = 200, body = String), (status = 400), (status = 401), (status = 403))
+)]
+async fn verify_otp(
+    Extension(db): Extension<Pool<Postgres>>,
+    Extension(config): Extension<AuthConfig>,
+    client: ClientInfo,
+    Form(form): Form<OtpVerifyPayload>,
+) -> Result<String, StatusCode> {
+    providers::require_enabled(AuthProviderKind::Phone, &db).await?;
+    let phone = normalize_phone(&form.phone).ok_or(StatusCode::BAD_REQUEST)?;
+
+    let result = match check_code(&db, &config, &phone, &form.code).await {
+        Ok(()) => sign_in(&db, &phone).await,
+        Err(err) => Err(err),
+    };
+
+    let event = match &result {
+        Ok(user) => SecurityEvent::new(SecurityEventKind::Login, true)
+            .with_user(user.id)
+            .with_detail("phone code"),
+        Err((detail, _)) => SecurityEvent::new(SecurityEventKind::Login, false).with_detail(detail),
+    };
+    // A failure to write the log must not lock users out.
+    let _ = event.with_client(&client).record(&db).await;
+
+    let user = result.map_err(|(_, status)| status)?;
+
+    config
+        .issue_token(user.id, ACCESS_TOKEN_TTL)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+pub fn router() -> OpenApiRouter {
+    OpenApiRouter::new()
+        .routes(routes!(request_otp))
+        .routes(routes!(verify_otp))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+    #[derive(Default)]
+    struct Outbox(Mutex<Vec<(String, String)>>);
+
+    impl SmsProvider for Outbox {
+        fn name(&self) -> &str {
+            "outbox"
+        }
+
+        fn send<'a>(&'a self, to: &'a str, body: &'a str) -> SendFuture<'a> {
+            self.0
+                .lock()
+                .unwrap()
+                .push((to.to_string(), body.to_string()));
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    impl Outbox {
+        /// The code of the last message sent.
+        fn last_code(&self) -> String {
+            let (_, body) = self.0.lock().unwrap().last().cloned().unwrap();
+            body.chars()
+                .filter(char::is_ascii_digit)
+                .take(OTP_CODE_LENGTH)
+                .collect()
+        }
+    }
+
+    fn request(phone: &str) -> Form<OtpRequestPayload> {
+        Form(OtpRequestPayload {
+            phone: phone.to_string(),
+        })
+    }
+
+    fn verify(phone: &str, code: &str) -> Form<OtpVerifyPayload> {
+        Form(OtpVerifyPayload {
+            phone: phone.to_string(),
+            code: code.to_string(),
+        })
+    }
+
+    #[test]
+    fn test_normalize_phone() {
+        assert_eq!(
+            normalize_phone("+44 20 7946 0958").as_deref(),
+            Some("+442079460958")
+        );
+        assert_eq!(
+            normalize_phone("+1.415.555.0123").as_deref(),
+            Some("+14155550123")
+        );
+        assert_eq!(normalize_phone("+0123456789"), None);
+        assert_eq!(normalize_phone("+1234"), None);
+        assert_eq!(normalize_phone("+1 415 CALL NOW"), None);
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let config = AuthConfig::builder().secret(SECRET).build().unwrap();
+        let now = Utc::now();
+        let sent_at = |ago: i64| OtpCode {
+            created: now - Duration::seconds(ago),
+            ..OtpCode::new(&config, "+14155550123").0
+        };
+
+        assert_eq!(retry_after(&[], &[], now), None);
+        assert_eq!(retry_after(&[sent_at(20)], &[], now), Some(40));
+        assert_eq!(retry_after(&[sent_at(90)], &[], now), None);
+
+        let five: Vec<_> = [100, 200, 300, 400, 3000].map(sent_at).into();
+        assert_eq!(retry_after(&five, &[], now), Some(600));
+
+        let ten: Vec<_> = (1..=10).map(|i| sent_at(i * 100)).collect();
+        assert_eq!(retry_after(&[], &ten[..9], now), None);
+        assert_eq!(retry_after(&[], &ten, now), Some(2600));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_phone_sign_in(db: Pool<Postgres>) -> anyhow::Result<()> {
+        let config = AuthConfig::builder().secret(SECRET).build()?;
+        let outbox = Arc::new(Outbox::default());
+        let provider: Arc<dyn SmsProvider> = outbox.clone();
+
+        let send = |phone: &str| {
+            request_otp(
+                Extension(db.clone()),
+                Extension(config.clone()),
+                Extension(provider.clone()),
+                ClientInfo::default(),
+                Challenge::passed(),
+                request(phone),
+            )
+        };
+        let sign_in = |phone: &str, code: &str| {
+            verify_otp(
+                Extension(db.clone()),
+                Extension(config.clone()),
+                ClientInfo::default(),
+                verify(phone, code),
+            )
+        };
+
+        assert_eq!(
+            send("not a number").await.unwrap_err().status(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(send("+1 415 555 0123").await.unwrap(), StatusCode::ACCEPTED);
+        let code = outbox.last_code();
+        assert_eq!(outbox.0.lock().unwrap()[0].0, "+14155550123");
+
+        let throttled = send("+14155550123").await.unwrap_err();
+        assert_eq!(throttled.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(throttled.headers().contains_key(header::RETRY_AFTER));
+
+        let wrong = if code == "000000" { "111111" } else { "000000" };
+        assert_eq!(
+            sign_in("+14155550123", wrong).await.unwrap_err(),
+            StatusCode::UNAUTHORIZED
+        );
+
+        // The first sign-in creates the user, with a verified phone and no email.
+        let token = sign_in("+14155550123", &code).await.unwrap();
+        let claims = config.verify_token(&token)?;
+        let user = AuthUser::find_by_id(&claims.subject.to_string(), &db).await?;
+        assert_eq!(user.phone.as_deref(), Some("+14155550123"));
+        assert!(user.phone_verified);
+        assert_eq!(user.email, None);
+
+        // Codes are single use.
+        assert_eq!(
+            sign_in("+14155550123", &code).await.unwrap_err(),
+            StatusCode::UNAUTHORIZED
+        );
+
+        let events = SecurityEvent::query(&Default::default(), &db).await?;
+        assert_eq!(events.len(), 3);
+        assert!(events[1].success);
+        assert_eq!(events[2].detail.as_deref(), Some("invalid code"));
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_ip_limit(db: Pool<Postgres>) -> anyhow::Result<()> {
+        let config = AuthConfig::builder().secret(SECRET).build()?;
+        let provider: Arc<dyn SmsProvider> = Arc::new(Outbox::default());
+        let client = ClientInfo {
+            ip: Some("203.0.113.7".parse()?),
+            user_agent: None,
+        };
+
+        let send = |phone: String, client: ClientInfo| {
+            request_otp(
+                Extension(db.clone()),
+                Extension(config.clone()),
+                Extension(provider.clone()),
+                client,
+                Challenge::passed(),
+                request(&phone),
+            )
+        };
+
+        for i in 0..OTP_MAX_PER_IP {
+            let phone = format!("+1415555{:04}", i);
+            assert_eq!(
+                send(phone, client.clone()).await.unwrap(),
+                StatusCode::ACCEPTED
+            );
+        }
+        // A fresh number from the same address is refused, not from another one.
+        let throttled = send("+14155559999".into(), client).await.unwrap_err();
+        assert_eq!(throttled.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(throttled.headers().contains_key(header::RETRY_AFTER));
+        let other = ClientInfo {
+            ip: Some("198.51.100.1".parse()?),
+            user_agent: None,
+        };
+        assert_eq!(
+            send("+14155559999".into(), other).await.unwrap(),
+            StatusCode::ACCEPTED
+        );
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_code_attempts(db: Pool<Postgres>) -> anyhow::Result<()> {
+        let config = AuthConfig::builder().secret(SECRET).build()?;
+        let existing = AuthUser::new("phone@example.com", "password")
+            .insert(&db)
+            .await?;
+        sqlx::query("update auth.users set phone = '+14155550199' where id = $1")
+            .bind(existing.id)
+            .execute(&db)
+            .await?;
+
+        let (otp, code) = OtpCode::new(&config, "+14155550199");
+        otp.insert(&db).await?;
+
+        let wrong = if code == "000000" { "111111" } else { "000000" };
+        for _ in 0..OTP_MAX_ATTEMPTS {
+            assert!(
+                check_code(&db, &config, "+14155550199", wrong)
+                    .await
+                    .is_err()
+            );
+        }
+        // Once the attempts are used up, even the right code is rejected.
+        assert_eq!(
+            check_code(&db, &config, "+14155550199", &code).await,
+            Err(("code expired", StatusCode::UNAUTHORIZED))
+        );
+
+        // Concurrent guesses share the attempts of the code.
+        let (otp, code) = OtpCode::new(&config, "+14155550199");
+        otp.insert(&db).await?;
+        let wrong = if code == "000000" { "111111" } else { "000000" };
+        let mut guesses = tokio::task::JoinSet::new();
+        for _ in 0..OTP_MAX_ATTEMPTS * 4 {
+            let (db, config) = (db.clone(), config.clone());
+            guesses.spawn(async move { check_code(&db, &config, "+14155550199", wrong).await });
+        }
+        let mut compared = 0;
+        while let Some(result) = guesses.join_next().await {
+            match result? {
+                Err(("invalid code", _)) => compared += 1,
+                Err(("code expired", _)) => {}
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+        assert_eq!(compared, OTP_MAX_ATTEMPTS);
+        assert!(
+            check_code(&db, &config, "+14155550199", &code)
+                .await
+                .is_err()
+        );
+
+        let (otp, code) = OtpCode::new(&config, "+14155550199");
+        otp.insert(&db).await?;
+        let token = verify_otp(
+            Extension(db.clone()),
+            Extension(config.clone()),
+            ClientInfo::default(),
+            verify("+1 415 555 0199", &code),
+        )
+        .await
+        .unwrap();
+
+        // Existing users sign in to their account and get their number verified.
+        assert_eq!(config.verify_token(&token)?.subject, existing.id);
+        assert!(
+            AuthUser::find_by_id(&existing.id.to_string(), &db)
+                .await?
+                .phone_verified
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,407 @@
+//! # Permission checks
+//!
+//! `POST /permissions/bulk-check` answers, for a list of table operations, whether the
+//! signed-in user may perform each of them, so frontends can decide which buttons and
+//! screens to show with one round trip instead of trying every action.
+//!
+//! An operation is allowed when:
+//!
+//! - the token grants the scope `records:read:{table}` for selects, or
+//!   `records:write:{table}` for other operations (see [`crate::scope`]);
+//! - the table's [`Exposures`] setting, if the router is layered with one, serves the
+//!   table, and allows writes for other operations than selects;
+//! - the Postgres role of API callers holds the matching privilege on the table;
+//! - for checks naming a record by its `id`, row level security lets the user select,
+//!   update or delete that record. The record policies see the user's id in the
+//!   [`USER_ID_SETTING`] setting, as for API requests.
+//!
+//! Checks run in the same session as API requests, opened with [`begin_session`] from
+//! the request's [`SessionContext`] extension, or as [`DEFAULT_ROLE`] without one. Record
+//! checks never write: the `USING` expressions of the table's update or delete policies
+//! are evaluated with a `SELECT` of the record. Unknown tables are denied. Inserts are
+//! only checked against scopes, exposure and privileges, since the record doesn't exist
+//! yet.
+//!
+//! # Example
+//!
+//! ```http
+//! POST /auth/permissions/bulk-check HTTP/1.1
+//! Authorization: Bearer ...
+//!
+//! [{ "table": "posts", "operation": "insert" },
+//!  { "table": "posts", "operation": "update", "id": 7 }]
+//! ```
+//!
+//! ```json
+//! [{ "table": "posts", "operation": "insert", "id": null, "allowed": true },
+//!  { "table": "posts", "operation": "update", "id": 7, "allowed": false }]
+//! ```
+
+use std::sync::Arc;
+
+use axum::{Extension, Json, http::StatusCode};
+use palmera_database::exposure::Exposures;
+pub use palmera_database::postgres::session::USER_ID_SETTING;
+use palmera_database::postgres::session::{SessionContext, begin_session};
+use serde::{Deserialize, Serialize};
+use sqlx::{Acquire, PgConnection, Pool, Postgres};
+use utoipa::ToSchema;
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+use crate::{extract::AuthClaims, jwt::JWTClaims};
+
+/// Postgres role checks run as when the request has no [`SessionContext`] extension.
+pub const DEFAULT_ROLE: &str = "authenticated";
+
+/// Most checks accepted in one request.
+pub const MAX_CHECKS: usize = 100;
+
+/// Operation on the records of a table.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TableOperation {
+    Select,
+    Insert,
+    Update,
+    Delete,
+}
+
+impl TableOperation {
+    /// The table privilege the operation needs.
+    pub fn privilege(self) -> &'static str {
+        match self {
+            Self::Select => "SELECT",
+            Self::Insert => "INSERT",
+            Self::Update => "UPDATE",
+            Self::Delete => "DELETE",
+        }
+    }
+
+    /// The token scope the operation needs on `table`.
+    pub fn scope(self, table: &str) -> String {
+        match self {
+            Self::Select => format!("records:read:{table}"),
+            _ => format!("records:write:{table}"),
+        }
+    }
+}
+
+/// An operation to check, optionally on a single record.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct PermissionCheck {
+    /// Table name, optionally schema-qualified, as it would be written in SQL.
+    pub table: String,
+    pub operation: TableOperation,
+    /// Id of the record the operation applies to.
+    #[serde(default)]
+    #[schema(value_type = Option<Object>)]
+    pub id: Option<serde_json::Value>,
+}
+
+/// A check and its outcome.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct PermissionDecision {
+    pub table: String,
+    pub operation: TableOperation,
+    #[schema(value_type = Option<Object>)]
+    pub id: Option<serde_json::Value>,
+    pub allowed: bool,
+}
+
+/// Returns, in order, whether the user of `claims` may perform each of `checks` in the
+/// session of `ctx`, whose user is replaced by the one of `claims`.
+pub async fn check_permissions(
+    db: &Pool<Postgres>,
+    ctx: &SessionContext,
+    exposures: Option<&Exposures>,
+    claims: &JWTClaims,
+    checks: &[PermissionCheck],
+) -> Result<Vec<bool>, sqlx::Error> {
+    let ctx = SessionContext {
+        user_id: Some(claims.subject.to_string()),
+        actor_id: claims
+            .is_impersonation()
+            .then(|| claims.actor_id().to_string()),
+        ..ctx.clone()
+    };
+    let mut tx = begin_session(db, &ctx).await?;
+
+    let mut allowed = Vec::with_capacity(checks.len());
+    for check in checks {
+        if !claims.has_scope(&check.operation.scope(&check.table)) {
+            allowed.push(false);
+            continue;
+        }
+        // Each check gets a savepoint, so a failing one doesn't abort the others.
+        let mut savepoint = tx.begin().await?;
+        let result = check_record(&mut savepoint, exposures, check).await;
+        savepoint.rollback().await?;
+        allowed.push(match result {
+            Ok(allowed) => allowed,
+            // E.g. a malformed table name, or a delete blocked by a foreign key.
+            Err(sqlx::Error::Database(_)) => false,
+            Err(err) => return Err(err),
+        });
+    }
+    tx.rollback().await?;
+
+    Ok(allowed)
+}
+
+async fn check_record(
+    conn: &mut PgConnection,
+    exposures: Option<&Exposures>,
+    check: &PermissionCheck,
+) -> Result<bool, sqlx::Error> {
+    let privilege = check.operation.privilege();
+    let table: Option<(String, String, bool, bool)> = sqlx::query_as(
+        "SELECT c::text, n.nspname || '.' || r.relname, \
+         CASE WHEN $2 = 'DELETE' THEN has_table_privilege(c::oid, $2) \
+         ELSE has_any_column_privilege(c::oid, $2) END, row_security_active(c::oid) \
+         FROM to_regclass($1) c \
+         JOIN pg_class r ON r.oid = c::oid JOIN pg_namespace n ON n.oid = r.relnamespace",
+    )
+    .bind(&check.table)
+    .bind(privilege)
+    .fetch_optional(&mut *conn)
+    .await?;
+    let Some((name, qualified, true, row_security)) = table else {
+        return Ok(false);
+    };
+    let exposed = exposures
+        .and_then(|exposures| exposures.get(&qualified))
+        .is_none_or(|exposure| {
+            exposure.enabled && (check.operation == TableOperation::Select || !exposure.read_only)
+        });
+    if !exposed {
+        return Ok(false);
+    }
+    let Some(id) = &check.id else {
+        return Ok(true);
+    };
+
+    // Selecting the record applies the select policies; the update and delete policies
+    // are added as a condition.
+    let policies = match check.operation {
+        TableOperation::Insert => return Ok(true),
+        TableOperation::Select => "true".to_string(),
+        TableOperation::Update | TableOperation::Delete if row_security => {
+            using_expression(conn, &qualified, privilege).await?
+        }
+        TableOperation::Update | TableOperation::Delete => "true".to_string(),
+    };
+    let id = match id {
+        serde_json::Value::String(id) => id.clone(),
+        id => id.to_string(),
+    };
+    let row: Option<i32> = sqlx::query_scalar(&format!(
+        "SELECT 1 FROM {name} WHERE id::text = $1 AND ({policies})"
+    ))
+    .bind(id)
+    .fetch_optional(conn)
+    .await?;
+
+    Ok(row.is_some())
+}
+
+/// The `USING` expressions of the policies of `table` (`schema.table`) for `command`
+/// that apply to the current role, combined as Postgres does: any permissive policy
+/// and every restrictive one must pass. Without a permissive policy, nothing passes.
+async fn using_expression(
+    conn: &mut PgConnection,
+    table: &str,
+    command: &str,
+) -> Result<String, sqlx::Error> {
+    let (schema, table) = table.split_once('.').unwrap_or(("public", table));
+    let policies: Vec<(Option<String>, bool)> = sqlx::query_as(
+        "SELECT qual, permissive = 'PERMISSIVE' FROM pg_policies \
+         WHERE schemaname = $1 AND tablename = $2 AND cmd IN ($3, 'ALL') \
+         AND EXISTS (SELECT 1 FROM unnest(roles) r \
+             WHERE r = 'public' OR pg_has_role(current_user, r, 'MEMBER')) \
+         ORDER BY policyname",
+    )
+    .bind(schema)
+    .bind(table)
+    .bind(command)
+    .fetch_all(conn)
+    .await?;
+
+    let expression = |qual: &Option<String>| format!("({})", qual.as_deref().unwrap_or("true"));
+    let permissive = policies
+        .iter()
+        .filter(|(_, permissive)| *permissive)
+        .map(|(qual, _)| expression(qual))
+        .collect::<Vec<_>>();
+    if permissive.is_empty() {
+        return Ok("false".to_string());
+    }
+    let mut all = vec![format!("({})", permissive.join(" OR "))];
+    all.extend(
+        policies
+            .iter()
+            .filter(|(_, permissive)| !*permissive)
+            .map(|(qual, _)| expression(qual)),
+    );
+
+    Ok(all.join(" AND "))
+}
+
+/// Reports whether the signed-in user may perform each of a list of operations.
+#[utoipa::path(
+    post,
+    path = "/permissions/bulk-check",
+    request_body = Vec<PermissionCheck>,
+    responses(
+        (status = 200, body = Vec<PermissionDecision>),
+        (status = 400),
+        (status = 401)
+    ),
+    security(("bearer_auth" = []))
+)]
+async fn bulk_check(
+    Extension(db): Extension<Pool<Postgres>>,
+    session: Option<Extension<SessionContext>>,
+    exposures: Option<Extension<Arc<Exposures>>>,
+    AuthClaims(claims): AuthClaims,
+    Json(checks): Json<Vec<PermissionCheck>>,
+) -> Result<Json<Vec<PermissionDecision>>, StatusCode> {
+    if checks.len() > MAX_CHECKS {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let ctx = session.map_or_else(
+        || SessionContext::anonymous(DEFAULT_ROLE),
+        |Extension(ctx)| ctx,
+    );
+    let exposures = exposures.as_deref().map(Arc::as_ref);
+
+    let allowed = check_permissions(&db, &ctx, exposures, &claims, &checks)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(
+        checks
+            .into_iter()
+            .zip(allowed)
+            .map(|(check, allowed)| PermissionDecision {
+                table: check.table,
+                operation: check.operation,
+                id: check.id,
+                allowed,
+            })
+            .collect(),
+    ))
+}
+
+pub fn router() -> OpenApiRouter {
+    OpenApiRouter::new().routes(routes!(bulk_check))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AuthConfig, scope::Scopes};
+    use chrono::Duration;
+    use palmera_database::exposure::TableExposure;
+    use serde_json::json;
+    use uuid::Uuid;
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_bulk_check(db: Pool<Postgres>) -> anyhow::Result<()> {
+        let user = Uuid::new_v4();
+        for sql in [
+            "CREATE TABLE notes (id integer PRIMARY KEY, owner text NOT NULL)",
+            "ALTER TABLE notes ENABLE ROW LEVEL SECURITY",
+            "CREATE POLICY visible ON notes FOR SELECT USING (true)",
+            "CREATE POLICY owned_update ON notes FOR UPDATE \
+             USING (owner = current_setting('palmera.user_id', true))",
+            "CREATE POLICY owned_delete ON notes FOR DELETE \
+             USING (owner = current_setting('palmera.user_id', true))",
+            "CREATE TABLE drafts (id integer PRIMARY KEY)",
+            "GRANT SELECT, UPDATE, DELETE ON notes, drafts TO pg_monitor",
+            // Deletes must not run, so a trigger failing them doesn't matter.
+            "CREATE FUNCTION refuse() RETURNS trigger AS \
+             $$ BEGIN RAISE EXCEPTION 'refused'; END $$ LANGUAGE plpgsql",
+            "CREATE TRIGGER refuse BEFORE DELETE ON notes FOR EACH ROW EXECUTE FUNCTION refuse()",
+        ] {
+            sqlx::query(sql).execute(&db).await?;
+        }
+        sqlx::query("INSERT INTO notes VALUES (1, $1), (2, 'someone else')")
+            .bind(user.to_string())
+            .execute(&db)
+            .await?;
+
+        let config = AuthConfig::builder().secret(SECRET).build()?;
+        let exposures =
+            Exposures::open(sqlx::SqlitePool::connect("sqlite::memory:").await?).await?;
+        exposures
+            .set(TableExposure::new("public.drafts").with_read_only(true))
+            .await?;
+        let exposures = Arc::new(exposures);
+        let check = |table: &str, operation, id: Option<i32>| PermissionCheck {
+            table: table.to_string(),
+            operation,
+            id: id.map(|id| json!(id)),
+        };
+        let checks = vec![
+            check("notes", TableOperation::Select, None),
+            check("notes", TableOperation::Insert, None),
+            check("notes", TableOperation::Update, Some(1)),
+            check("notes", TableOperation::Update, Some(2)),
+            check("notes", TableOperation::Delete, Some(1)),
+            check("notes", TableOperation::Delete, Some(2)),
+            check("notes", TableOperation::Select, Some(3)),
+            check("missing", TableOperation::Select, None),
+            check("not a table!", TableOperation::Select, None),
+            check("drafts", TableOperation::Select, None),
+            check("drafts", TableOperation::Update, None),
+        ];
+        let run = |claims: JWTClaims, checks: Vec<PermissionCheck>| {
+            bulk_check(
+                Extension(db.clone()),
+                Some(Extension(SessionContext::anonymous("pg_monitor"))),
+                Some(Extension(exposures.clone())),
+                AuthClaims(claims),
+                Json(checks),
+            )
+        };
+
+        let token = config.issue_token(user, Duration::minutes(5))?;
+        let Json(decisions) = run(config.verify_token(&token)?, checks.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            decisions.iter().map(|d| d.allowed).collect::<Vec<_>>(),
+            [
+                true, false, true, false, true, false, false, false, false, true, false
+            ]
+        );
+        assert_eq!(decisions[2].id, Some(json!(1)));
+        let count: i64 = sqlx::query_scalar("SELECT count(*) FROM notes")
+            .fetch_one(&db)
+            .await?;
+        assert_eq!(count, 2);
+
+        // Scopes narrow what the token may do.
+        let token = config.issue_scoped_token(
+            user,
+            Duration::minutes(5),
+            Scopes::new().with("records:read"),
+        )?;
+        let Json(decisions) = run(config.verify_token(&token)?, checks[..3].to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            decisions.iter().map(|d| d.allowed).collect::<Vec<_>>(),
+            [true, false, false]
+        );
+
+        let too_many = vec![check("notes", TableOperation::Select, None); MAX_CHECKS + 1];
+        let status = run(config.verify_token(&token)?, too_many)
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+}
@@ -0,0 +1,563 @@
+//! # Auth providers
+//!
+//! The sign-in methods an app offers are stored in `auth.providers`, so operators can
+//! turn them on and off at runtime instead of recompiling. Each [`AuthProvider`] has a
+//! unique name and an [`AuthProviderKind`]: the built-in `password` and `phone` methods,
+//! which the migrations create enabled, or OAuth, magic link and passkey providers added
+//! by the app. OAuth providers carry their client credentials; any other settings, such
+//! as scopes or endpoints, go in the free-form `config` object.
+//!
+//! Client secrets are stored encrypted with AES-256-GCM, under a key derived from the
+//! signing secret of the [`AuthConfig`], and read back with
+//! [`AuthProvider::decrypt_client_secret`]. Secrets encrypted before a rotation (see
+//! [`AuthConfig::rotate`]) stay readable while the previous secret is in the config;
+//! save the provider again to encrypt it under the new one.
+//!
+//! - `GET /providers` lists the enabled providers without their secrets, e.g. to render
+//!   the sign-in page. OAuth providers whose config sets a `redirect_path` are listed
+//!   with their redirect URI: that path as an absolute URL on the address the request
+//!   was sent to, see [`crate::urls`].
+//! - The `/admin/auth-providers` routes in [`crate::admin`] let operators manage them.
+//!
+//! Password sign-in (`POST /login` and the device flow) and phone sign-in (see
+//! [`crate::otp`]) are rejected with `403` while no provider of their kind is enabled.
+//!
+//! # Example
+//!
+//! ```rust
+//! use palmera_auth::providers::{AuthProviderKind, AuthProviderPayload};
+//!
+//! let github = AuthProviderPayload::new(AuthProviderKind::Oauth)
+//!     .with_client("Iv1.8a61f9b3a7aba766", "client-secret")
+//!     .with_config(serde_json::json!({ "scopes": ["read:user", "user:email"] }));
+//! assert!(github.check("github", None).is_ok());
+//! assert!(github.check("GitHub!", None).is_err());
+//! ```
+
+use std::{fmt, str::FromStr};
+
+use aes_gcm::{
+    Aes256Gcm, Key, Nonce,
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+};
+use axum::{Extension, Json, http::StatusCode};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Postgres, prelude::FromRow, types::Json as SqlJson};
+use utoipa::ToSchema;
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+use crate::{AuthConfig, urls::RequestUrls};
+
+/// Maximum length of a provider name.
+pub const MAX_PROVIDER_NAME_LENGTH: usize = 64;
+
+/// Prefix of the stored client secrets that are encrypted. Secrets saved before they
+/// were encrypted lack it and are read as they are.
+const SEALED_PREFIX: &str = "sealed:";
+
+const NONCE_LEN: usize = 12;
+
+#[derive(Debug, Clone, Copy, ToSchema, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthProviderKind {
+    /// Email and password.
+    Password,
+    /// An OAuth 2.0 or OpenID Connect identity provider.
+    Oauth,
+    /// Sign-in links sent by email.
+    MagicLink,
+    /// WebAuthn passkeys.
+    Passkey,
+    /// One-time codes sent by SMS.
+    Phone,
+}
+
+impl AuthProviderKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuthProviderKind::Password => "password",
+            AuthProviderKind::Oauth => "oauth",
+            AuthProviderKind::MagicLink => "magic_link",
+            AuthProviderKind::Passkey => "passkey",
+            AuthProviderKind::Phone => "phone",
+        }
+    }
+}
+
+impl fmt::Display for AuthProviderKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for AuthProviderKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "password" => Ok(AuthProviderKind::Password),
+            "oauth" => Ok(AuthProviderKind::Oauth),
+            "magic_link" => Ok(AuthProviderKind::MagicLink),
+            "passkey" => Ok(AuthProviderKind::Passkey),
+            "phone" => Ok(AuthProviderKind::Phone),
+            _ => Err(anyhow::anyhow!("Unknown auth provider kind `{}`", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for AuthProviderKind {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// A sign-in method, stored in `auth.providers`. Its `Debug` output leaves out the
+/// client secret.
+#[derive(FromRow, Clone, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct AuthProvider {
+    /// Unique name, e.g. `google`; lowercase letters, digits, `-` and `_`.
+    pub name: String,
+    #[sqlx(try_from = "String")]
+    pub kind: AuthProviderKind,
+    pub enabled: bool,
+    /// OAuth client id.
+    pub client_id: Option<String>,
+    /// OAuth client secret, encrypted; see [`AuthProvider::decrypt_client_secret`].
+    /// Write-only: never included in responses.
+    #[serde(skip_serializing, default)]
+    pub client_secret: Option<String>,
+    /// Provider-specific settings, e.g. scopes or endpoints.
+    #[schema(value_type = Object)]
+    pub config: SqlJson<serde_json::Value>,
+    pub created: DateTime<Utc>,
+    pub updated: DateTime<Utc>,
+}
+
+impl fmt::Debug for AuthProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthProvider")
+            .field("name", &self.name)
+            .field("kind", &self.kind)
+            .field("enabled", &self.enabled)
+            .field("client_id", &self.client_id)
+            .field("config", &self.config)
+            .field("created", &self.created)
+            .field("updated", &self.updated)
+            .finish_non_exhaustive()
+    }
+}
+
+/// AES-256-GCM cipher for client secrets, keyed with the signing secret `key`.
+fn secret_cipher(key: &str) -> Aes256Gcm {
+    let key = Sha256::new()
+        .chain_update(b"auth.providers:")
+        .chain_update(key.as_bytes())
+        .finalize();
+    Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))
+}
+
+/// Encrypts `secret` under the signing secret of `config`, hex encoded after
+/// [`SEALED_PREFIX`].
+fn seal_secret(config: &AuthConfig, secret: &str) -> anyhow::Result<String> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = secret_cipher(&config.key)
+        .encrypt(&nonce, secret.as_bytes())
+        .map_err(|_| anyhow::anyhow!("Could not encrypt the client secret"))?;
+
+    let sealed: String = nonce
+        .iter()
+        .chain(&ciphertext)
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    Ok(format!("{}{}", SEALED_PREFIX, sealed))
+}
+
+/// Decrypts a secret encrypted by [`seal_secret`] under the signing secret of `config`
+/// or one of its retired secrets.
+fn open_secret(config: &AuthConfig, stored: &str) -> anyhow::Result<String> {
+    let Some(hex) = stored.strip_prefix(SEALED_PREFIX) else {
+        return Ok(stored.to_string());
+    };
+
+    let sealed = (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+        })
+        .collect::<Option<Vec<u8>>>()
+        .filter(|sealed| sealed.len() > NONCE_LEN)
+        .ok_or_else(|| anyhow::anyhow!("Malformed client secret"))?;
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+
+    std::iter::once(&config.key)
+        .chain(config.retired_keys.iter().map(|retired| &retired.key))
+        .find_map(|key| {
+            secret_cipher(key)
+                .decrypt(Nonce::from_slice(nonce), ciphertext)
+                .ok()
+        })
+        .and_then(|secret| String::from_utf8(secret).ok())
+        .ok_or_else(|| {
+            anyhow::anyhow!("The client secret was encrypted with an unknown signing secret")
+        })
+}
+
+/// Settings of a provider to create or replace.
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct AuthProviderPayload {
+    pub kind: AuthProviderKind,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    pub client_id: Option<String>,
+    /// New client secret; the stored one is kept when omitted.
+    pub client_secret: Option<String>,
+    #[schema(value_type = Object)]
+    #[serde(default)]
+    pub config: serde_json::Value,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+impl AuthProviderPayload {
+    /// An enabled provider of `kind` without credentials or settings.
+    pub fn new(kind: AuthProviderKind) -> Self {
+        Self {
+            kind,
+            enabled: true,
+            client_id: None,
+            client_secret: None,
+            config: serde_json::Value::Null,
+        }
+    }
+
+    pub fn with_enabled(mut self, enabled: bool) -> Self {
+        self.enabled = enabled;
+        self
+    }
+
+    pub fn with_client(mut self, client_id: &str, client_secret: &str) -> Self {
+        self.client_id = Some(client_id.to_string());
+        self.client_secret = Some(client_secret.to_string());
+        self
+    }
+
+    pub fn with_config(mut self, config: serde_json::Value) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Checks these settings can be stored as provider `name`, replacing `existing`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the name is invalid, `config` is not an object, or an enabled
+    /// OAuth provider would lack its client id or secret.
+    pub fn check(&self, name: &str, existing: Option<&AuthProvider>) -> anyhow::Result<()> {
+        let valid_name = !name.is_empty()
+            && name.len() <= MAX_PROVIDER_NAME_LENGTH
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-' || b == b'_');
+        if !valid_name {
+            anyhow::bail!("Invalid provider name `{}`", name);
+        }
+
+        if !(self.config.is_object() || self.config.is_null()) {
+            anyhow::bail!("Provider config must be an object");
+        }
+
+        if self.kind == AuthProviderKind::Oauth && self.enabled {
+            let has_secret = self.client_secret.is_some()
+                || existing.is_some_and(|provider| provider.client_secret.is_some());
+            if self.client_id.is_none() || !has_secret {
+                anyhow::bail!("OAuth providers need a client id and secret");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl AuthProvider {
+    /// The OAuth client secret in clear, if any.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if it was encrypted with a signing secret `config` doesn't know.
+    pub fn decrypt_client_secret(&self, config: &AuthConfig) -> anyhow::Result<Option<String>> {
+        self.client_secret
+            .as_deref()
+            .map(|stored| open_secret(config, stored))
+            .transpose()
+    }
+
+    // database operation
+
+    /// Find all providers, by name.
+    pub async fn list(db: &Pool<Postgres>) -> anyhow::Result<Vec<Self>> {
+        let result = sqlx::query_as::<_, Self>("SELECT * FROM auth.providers ORDER BY name")
+            .fetch_all(db)
+            .await?;
+
+        Ok(result)
+    }
+
+    /// Find a provider by name.
+    pub async fn find(name: &str, db: &Pool<Postgres>) -> anyhow::Result<Option<Self>> {
+        let result = sqlx::query_as::<_, Self>("SELECT * FROM auth.providers WHERE name = $1")
+            .bind(name)
+            .fetch_optional(db)
+            .await?;
+
+        Ok(result)
+    }
+
+    /// Whether any provider of `kind` is enabled.
+    pub async fn kind_enabled(kind: AuthProviderKind, db: &Pool<Postgres>) -> anyhow::Result<bool> {
+        let result = sqlx::query_scalar(
+            "SELECT EXISTS (SELECT 1 FROM auth.providers WHERE kind = $1 AND enabled)",
+        )
+        .bind(kind.as_str())
+        .fetch_one(db)
+        .await?;
+
+        Ok(result)
+    }
+
+    /// Create provider `name`, or replace its settings. The stored client secret is
+    /// kept unless `payload` sets a new one, which is encrypted with the signing secret
+    /// of `auth`. The caller is responsible for checking the payload with
+    /// [`AuthProviderPayload::check`].
+    pub async fn upsert(
+        name: &str,
+        payload: AuthProviderPayload,
+        auth: &AuthConfig,
+        db: &Pool<Postgres>,
+    ) -> anyhow::Result<Self> {
+        let config = match payload.config {
+            serde_json::Value::Null => serde_json::json!({}),
+            config => config,
+        };
+        let client_secret = payload
+            .client_secret
+            .map(|secret| seal_secret(auth, &secret))
+            .transpose()?;
+
+        let result = sqlx::query_as::<_, Self>(
+            "INSERT INTO auth.providers (name, kind, enabled, client_id, client_secret, config) \
+             VALUES ($1, $2, $3, $4, $5, $6) \
+             ON CONFLICT (name) DO UPDATE SET \
+                 kind = excluded.kind, \
+                 enabled = excluded.enabled, \
+                 client_id = excluded.client_id, \
+                 client_secret = coalesce(excluded.client_secret, providers.client_secret), \
+                 config = excluded.config, \
+                 updated = now() \
+             RETURNING *",
+        )
+        .bind(name)
+        .bind(payload.kind.as_str())
+        .bind(payload.enabled)
+        .bind(payload.client_id)
+        .bind(client_secret)
+        .bind(SqlJson(config))
+        .fetch_one(db)
+        .await?;
+
+        Ok(result)
+    }
+
+    /// Delete provider `name`, returning `false` if there is none.
+    pub async fn delete(name: &str, db: &Pool<Postgres>) -> anyhow::Result<bool> {
+        let result = sqlx::query("DELETE FROM auth.providers WHERE name = $1")
+            .bind(name)
+            .execute(db)
+            .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+}
+
+/// Checks that sign-in with `kind` is enabled, returning `403` otherwise.
+pub(crate) async fn require_enabled(
+    kind: AuthProviderKind,
+    db: &Pool<Postgres>,
+) -> Result<(), StatusCode> {
+    match AuthProvider::kind_enabled(kind, db).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(StatusCode::FORBIDDEN),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+/// An enabled sign-in method, as shown to users.
+#[derive(Debug, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct ProviderInfo {
+    pub name: String,
+    pub kind: AuthProviderKind,
+    /// OAuth client id, for clients starting the authorization flow themselves.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    /// Where the OAuth provider sends users back to, from the `redirect_path` of its
+    /// config.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redirect_uri: Option<String>,
+}
+
+/// Lists the enabled sign-in methods.
+#[utoipa::path(get, path = "/providers", responses((status = 200, body = Vec<ProviderInfo>)))]
+async fn list_providers(
+    Extension(db): Extension<Pool<Postgres>>,
+    urls: RequestUrls,
+) -> Result<Json<Vec<ProviderInfo>>, StatusCode> {
+    let providers = AuthProvider::list(&db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(
+        providers
+            .into_iter()
+            .filter(|provider| provider.enabled)
+            .map(|provider| ProviderInfo {
+                redirect_uri: provider
+                    .config
+                    .get("redirect_path")
+                    .and_then(|path| path.as_str())
+                    .filter(|_| provider.kind == AuthProviderKind::Oauth)
+                    .map(|path| urls.url(path)),
+                name: provider.name,
+                kind: provider.kind,
+                client_id: provider.client_id,
+            })
+            .collect(),
+    ))
+}
+
+pub fn router() -> OpenApiRouter {
+    OpenApiRouter::new().routes(routes!(list_providers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::urls::UrlBuilder;
+
+    #[test]
+    fn test_check_payload() {
+        let oauth = AuthProviderPayload::new(AuthProviderKind::Oauth);
+        assert!(oauth.check("google", None).is_err());
+        assert!(
+            oauth
+                .clone()
+                .with_enabled(false)
+                .check("google", None)
+                .is_ok()
+        );
+
+        let configured = oauth.with_client("client-id", "client-secret");
+        assert!(configured.check("google", None).is_ok());
+        assert!(configured.check("", None).is_err());
+        assert!(configured.check("Google", None).is_err());
+        assert!(
+            configured
+                .clone()
+                .with_config(serde_json::json!(["openid"]))
+                .check("google", None)
+                .is_err()
+        );
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_providers(db: Pool<Postgres>) -> anyhow::Result<()> {
+        let auth = AuthConfig::builder()
+            .secret("0123456789abcdef0123456789abcdef")
+            .build()?;
+        assert!(AuthProvider::kind_enabled(AuthProviderKind::Password, &db).await?);
+        assert!(!AuthProvider::kind_enabled(AuthProviderKind::Oauth, &db).await?);
+
+        let google = AuthProviderPayload::new(AuthProviderKind::Oauth)
+            .with_client("client-id", "client-secret")
+            .with_config(serde_json::json!({ "scopes": ["openid", "email"] }));
+        AuthProvider::upsert("google", google, &auth, &db).await?;
+
+        // Updates without a secret keep the stored one.
+        let disabled = AuthProviderPayload {
+            client_id: Some("client-id".to_string()),
+            ..AuthProviderPayload::new(AuthProviderKind::Oauth).with_enabled(false)
+        };
+        let updated = AuthProvider::upsert("google", disabled, &auth, &db).await?;
+        assert!(!updated.enabled);
+        assert_eq!(
+            updated.decrypt_client_secret(&auth)?.as_deref(),
+            Some("client-secret")
+        );
+        // The secret is encrypted at rest and left out of `Debug`.
+        assert!(
+            !updated
+                .client_secret
+                .as_deref()
+                .unwrap()
+                .contains("client-secret")
+        );
+        assert!(!format!("{:?}", updated).contains("client_secret"));
+        let rotated = auth.rotate("fedcba9876543210fedcba9876543210")?;
+        assert_eq!(
+            updated.decrypt_client_secret(&rotated)?.as_deref(),
+            Some("client-secret")
+        );
+        let other = AuthConfig::builder()
+            .secret("fedcba9876543210fedcba9876543210")
+            .build()?;
+        assert!(updated.decrypt_client_secret(&other).is_err());
+        assert_eq!(updated.config.0, serde_json::json!({}));
+        assert!(
+            serde_json::to_value(&updated)?
+                .get("client_secret")
+                .is_none()
+        );
+
+        let Json(listed) = list_providers(Extension(db.clone()), RequestUrls(None))
+            .await
+            .unwrap();
+        let names: Vec<_> = listed
+            .iter()
+            .map(|provider| provider.name.as_str())
+            .collect();
+        assert_eq!(names, ["password", "phone"]);
+
+        let github = AuthProviderPayload::new(AuthProviderKind::Oauth)
+            .with_client("client-id", "client-secret")
+            .with_config(serde_json::json!({ "redirect_path": "/auth/github/callback" }));
+        AuthProvider::upsert("github", github, &auth, &db).await?;
+        let mut headers = axum::http::HeaderMap::new();
+        headers.insert("host", "api.acme.com".parse()?);
+        let urls = UrlBuilder::new("https://api.example.com")?.with_custom_domain("api.acme.com");
+        let Json(listed) = list_providers(
+            Extension(db.clone()),
+            RequestUrls(Some(urls.for_request(&headers, None))),
+        )
+        .await
+        .unwrap();
+        let github = listed.iter().find(|provider| provider.name == "github");
+        assert_eq!(
+            github.and_then(|provider| provider.redirect_uri.as_deref()),
+            Some("https://api.acme.com/auth/github/callback")
+        );
+        AuthProvider::delete("github", &db).await?;
+
+        assert!(AuthProvider::delete("password", &db).await?);
+        assert!(!AuthProvider::delete("password", &db).await?);
+        assert_eq!(
+            require_enabled(AuthProviderKind::Password, &db).await,
+            Err(StatusCode::FORBIDDEN)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,400 @@
+//! # Trusted reverse proxies
+//!
+//! Behind a reverse proxy or load balancer, the peer address of every connection is the
+//! proxy's, and the scheme the client used is lost once the proxy terminates TLS. The
+//! proxy reports both in the `Forwarded` header, or in `X-Forwarded-For` and
+//! `X-Forwarded-Proto`. Since clients can send these headers too, they are only honored
+//! on connections from the addresses allowed by [`TrustedProxies`].
+//!
+//! The [`resolve_client`] middleware adds the resulting [`ClientAddr`] to the request
+//! extensions, where it is picked up by
+//!
+//! - [`ClientInfo`](crate::extract::ClientInfo), and so the security log,
+//! - the IP key of Palmera's rate limiter,
+//! - the session routes, which leave `Secure` off their cookies only for requests a
+//!   trusted proxy reports the client sent over plain HTTP, and
+//! - [`UrlBuilder::for_request`](crate::urls::UrlBuilder::for_request), which builds
+//!   links on the host, scheme and path prefix the client used.
+//!
+//! The client is found by walking the forwarded addresses from the closest hop
+//! outwards, skipping trusted proxies; the first address that isn't trusted is the
+//! client. Its scheme and host are the ones reported with that hop: the `proto` and
+//! `host` of the same `Forwarded` element, or the `X-Forwarded-Proto` and
+//! `X-Forwarded-Host` values at the same position as its `X-Forwarded-For` address;
+//! `X-Forwarded-Prefix` likewise. The app must be served with
+//! `into_make_service_with_connect_info::<SocketAddr>()`.
+//!
+//! # Example
+//!
+//! ```rust
+//! use std::sync::Arc;
+//! use axum::{Router, middleware, routing::get};
+//! use palmera_auth::proxy::{TrustedProxies, resolve_client};
+//!
+//! let proxies = Arc::new(TrustedProxies::new(["10.0.0.0/8", "::1"]).unwrap());
+//! let app: Router = Router::new()
+//!     .route("/", get(|| async { "ok" }))
+//!     .layer(middleware::from_fn_with_state(proxies, resolve_client));
+//! ```
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+};
+
+use anyhow::{Context, bail};
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::Response,
+};
+
+/// A block of IP addresses, e.g. `10.0.0.0/8`; a bare address is a block of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, ip.to_canonical()) {
+            (IpAddr::V4(block), IpAddr::V4(ip)) => {
+                masked(block.to_bits().into(), ip.to_bits().into(), 32, self.prefix)
+            }
+            (IpAddr::V6(block), IpAddr::V6(ip)) => {
+                masked(block.to_bits(), ip.to_bits(), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether the first `prefix` of the `bits` low bits of `a` and `b` are equal.
+fn masked(a: u128, b: u128, bits: u8, prefix: u8) -> bool {
+    let shift = bits - prefix;
+    shift == bits || (a >> shift) == (b >> shift)
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> anyhow::Result<Self> {
+        let (address, prefix) = value.trim().split_once('/').unwrap_or((value.trim(), ""));
+        let address = address
+            .parse::<IpAddr>()
+            .with_context(|| format!("Invalid proxy address {value:?}"))?
+            .to_canonical();
+        let bits = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            "" => bits,
+            prefix => prefix
+                .parse::<u8>()
+                .with_context(|| format!("Invalid prefix length in {value:?}"))?,
+        };
+        if prefix > bits {
+            bail!("Prefix length of {value:?} exceeds {bits}");
+        }
+
+        Ok(Self { address, prefix })
+    }
+}
+
+/// Addresses of the proxies whose forwarding headers are honored.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrustedProxies {
+    blocks: Vec<Cidr>,
+}
+
+impl TrustedProxies {
+    /// Trusts the proxies in `blocks`, each an address or a CIDR block.
+    pub fn new<'a>(blocks: impl IntoIterator<Item = &'a str>) -> anyhow::Result<Self> {
+        Ok(Self {
+            blocks: blocks
+                .into_iter()
+                .map(str::parse)
+                .collect::<anyhow::Result<_>>()?,
+        })
+    }
+
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.blocks.iter().any(|block| block.contains(ip))
+    }
+
+    /// The client of a request received from `peer` with `headers`.
+    pub fn resolve(&self, peer: IpAddr, headers: &HeaderMap) -> ClientAddr {
+        let peer = peer.to_canonical();
+        if !self.is_trusted(peer) {
+            return ClientAddr {
+                ip: peer,
+                https: None,
+                host: None,
+                prefix: None,
+            };
+        }
+
+        let header = |name| {
+            headers
+                .get_all(name)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .collect::<Vec<_>>()
+        };
+
+        let forwarded = header("forwarded");
+        // Values of a per-hop header, in hop order; without a value per address, it is
+        // unknown which hop one belongs to.
+        let per_hop = |name, hops: usize| {
+            let values = header(name);
+            match values.len() == hops {
+                true => values
+                    .into_iter()
+                    .map(|value| Some(value.to_string()))
+                    .collect(),
+                false => Vec::new(),
+            }
+        };
+        let (hops, reported): (Vec<Option<IpAddr>>, Vec<Reported>) = if forwarded.is_empty() {
+            let hops: Vec<_> = header("x-forwarded-for")
+                .into_iter()
+                .map(parse_node)
+                .collect();
+            let protos = per_hop("x-forwarded-proto", hops.len());
+            let hosts = per_hop("x-forwarded-host", hops.len());
+            let prefixes = per_hop("x-forwarded-prefix", hops.len());
+            let reported = (0..hops.len())
+                .map(|index| Reported {
+                    proto: protos.get(index).cloned().flatten(),
+                    host: hosts.get(index).cloned().flatten(),
+                    prefix: prefixes.get(index).cloned().flatten(),
+                })
+                .collect();
+            (hops, reported)
+        } else {
+            let param = |element: &str, name: &str| {
+                element.split(';').find_map(|pair| {
+                    let (key, value) = pair.trim().split_once('=')?;
+                    key.eq_ignore_ascii_case(name)
+                        .then(|| value.trim_matches('"').to_string())
+                })
+            };
+            // `Forwarded` has no prefix parameter.
+            let prefixes = per_hop("x-forwarded-prefix", forwarded.len());
+            let reported = forwarded
+                .iter()
+                .enumerate()
+                .map(|(index, element)| Reported {
+                    proto: param(element, "proto"),
+                    host: param(element, "host"),
+                    prefix: prefixes.get(index).cloned().flatten(),
+                })
+                .collect();
+            let hops = forwarded
+                .into_iter()
+                .map(|element| param(element, "for").as_deref().and_then(parse_node))
+                .collect();
+            (hops, reported)
+        };
+
+        // From the closest hop outwards, while the address that reported the next one
+        // is a trusted proxy.
+        let mut ip = peer;
+        let mut client = None;
+        for (index, hop) in hops.into_iter().enumerate().rev() {
+            if !self.is_trusted(ip) {
+                break;
+            }
+            match hop {
+                Some(hop) => {
+                    ip = hop;
+                    client = Some(index);
+                }
+                None => break,
+            }
+        }
+
+        let reported = client
+            .and_then(|index| reported.into_iter().nth(index))
+            .unwrap_or_default();
+        ClientAddr {
+            ip,
+            https: reported
+                .proto
+                .map(|proto| proto.eq_ignore_ascii_case("https")),
+            host: reported.host,
+            prefix: reported.prefix,
+        }
+    }
+}
+
+/// What the proxy of a hop reported about the request it received.
+#[derive(Debug, Default)]
+struct Reported {
+    proto: Option<String>,
+    host: Option<String>,
+    prefix: Option<String>,
+}
+
+/// Parses a forwarded node such as `192.0.2.1`, `192.0.2.1:8080` or
+/// `[2001:db8::1]:4711`; obfuscated and `unknown` nodes give `None`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(ip.to_canonical());
+    }
+    if let Ok(address) = node.parse::<SocketAddr>() {
+        return Some(address.ip().to_canonical());
+    }
+    node.strip_prefix('[')
+        .and_then(|node| node.strip_suffix(']'))
+        .and_then(|ip| ip.parse::<IpAddr>().ok())
+}
+
+/// The client of a request as resolved by [`resolve_client`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientAddr {
+    pub ip: IpAddr,
+    /// Whether the client sent the request over HTTPS, as reported by a trusted proxy;
+    /// `None` if no trusted proxy reported it.
+    pub https: Option<bool>,
+    /// Host the client sent the request to, as reported by a trusted proxy.
+    pub host: Option<String>,
+    /// Path the proxy serves the app under, as reported by a trusted proxy in
+    /// `X-Forwarded-Prefix`.
+    pub prefix: Option<String>,
+}
+
+/// Axum middleware adding the request's [`ClientAddr`] to its extensions. Requests
+/// without a peer address pass through unchanged.
+pub async fn resolve_client(
+    State(proxies): State<Arc<TrustedProxies>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(address)| address.ip());
+
+    if let Some(peer) = peer {
+        let client = proxies.resolve(peer, request.headers());
+        request.extensions_mut().insert(client);
+    }
+
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_cidr() {
+        let block: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(block.contains(ip("10.1.2.3")));
+        assert!(block.contains(ip("::ffff:10.1.2.3")));
+        assert!(!block.contains(ip("11.0.0.1")));
+        assert!(!block.contains(ip("::1")));
+
+        let single: Cidr = "2001:db8::1".parse().unwrap();
+        assert!(single.contains(ip("2001:db8::1")));
+        assert!(!single.contains(ip("2001:db8::2")));
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(ip("8.8.8.8")));
+
+        for invalid in ["10.0.0.0/33", "proxy.local", "10.0.0.0/x"] {
+            assert!(invalid.parse::<Cidr>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_resolve_forwarded_for() {
+        let proxies = TrustedProxies::new(["10.0.0.0/8"]).unwrap();
+        let forwarded = headers(&[
+            ("x-forwarded-for", "198.51.100.7, 203.0.113.9"),
+            ("x-forwarded-for", "10.0.0.2"),
+            ("x-forwarded-proto", "http, https, http"),
+        ]);
+
+        // The closest untrusted hop is the client; addresses before it may be forged.
+        assert_eq!(
+            proxies.resolve(ip("10.0.0.1"), &forwarded),
+            ClientAddr {
+                ip: ip("203.0.113.9"),
+                https: Some(true),
+                host: None,
+                prefix: None,
+            }
+        );
+        // Headers from untrusted peers are ignored.
+        assert_eq!(
+            proxies.resolve(ip("192.0.2.1"), &forwarded),
+            ClientAddr {
+                ip: ip("192.0.2.1"),
+                https: None,
+                host: None,
+                prefix: None,
+            }
+        );
+        // A scheme that can't be matched to the client's hop is unknown.
+        let unmatched = headers(&[
+            ("x-forwarded-for", "203.0.113.9, 10.0.0.2"),
+            ("x-forwarded-proto", "http"),
+        ]);
+        assert_eq!(proxies.resolve(ip("10.0.0.1"), &unmatched).https, None);
+
+        let hosted = headers(&[
+            ("x-forwarded-for", "203.0.113.9"),
+            ("x-forwarded-host", "api.acme.com"),
+            ("x-forwarded-prefix", "/palmera"),
+        ]);
+        let client = proxies.resolve(ip("10.0.0.1"), &hosted);
+        assert_eq!(client.host.as_deref(), Some("api.acme.com"));
+        assert_eq!(client.prefix.as_deref(), Some("/palmera"));
+        assert_eq!(proxies.resolve(ip("192.0.2.1"), &hosted).host, None);
+        assert_eq!(
+            proxies.resolve(ip("10.0.0.1"), &HeaderMap::new()).ip,
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn test_resolve_forwarded() {
+        let proxies = TrustedProxies::new(["10.0.0.0/8", "::1"]).unwrap();
+        let forwarded = headers(&[(
+            "forwarded",
+            "for=\"[2001:db8::7]:4711\";proto=https;host=api.acme.com, \
+             for=10.0.0.2:8080;proto=http;host=internal",
+        )]);
+        assert_eq!(
+            proxies.resolve(ip("::1"), &forwarded),
+            ClientAddr {
+                ip: ip("2001:db8::7"),
+                https: Some(true),
+                host: Some("api.acme.com".to_string()),
+                prefix: None,
+            }
+        );
+
+        // An obfuscated node stops the walk at the proxy that reported it.
+        let hidden = headers(&[("forwarded", "for=_hidden, for=10.0.0.2")]);
+        assert_eq!(proxies.resolve(ip("::1"), &hidden).ip, ip("10.0.0.2"));
+    }
+}
@@ -1,52 +1,234 @@
-use axum::{Extension, Form, http::StatusCode};
-use chrono::Duration;
-use serde::Deserialize;
+use axum::{Extension, Form, Json, http::StatusCode};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
 use sqlx::{Pool, Postgres};
 use utoipa::ToSchema;
 use utoipa_axum::{router::OpenApiRouter, routes};
+use uuid::Uuid;
 use validator::Validate;
 
-use crate::{AuthConfig, jwt::JWTClaims, schemas::AuthUser};
+use crate::{
+    AuthConfig,
+    challenge::Challenge,
+    device,
+    extract::{AuthClaims, ClientInfo},
+    hooks::AuthHooks,
+    jwt::Actor,
+    openapi, otp, permissions,
+    providers::{self, AuthProviderKind},
+    schemas::AuthUser,
+    security::{self, SecurityEvent, SecurityEventKind},
+    session, verification,
+};
+
+/// Lifetime of the access tokens issued by the auth routes.
+pub(crate) const ACCESS_TOKEN_TTL: Duration = Duration::seconds(3600);
 
 #[derive(Debug, ToSchema, Deserialize, Validate)]
 pub struct LoginPayload {
     #[validate(email)]
-    email: String,
-    password: String,
+    pub(crate) email: String,
+    pub(crate) password: String,
+}
+
+impl LoginPayload {
+    /// Returns the user with these credentials, or `401` if there is none. Inactive
+    /// accounts and, with [`AuthConfig::require_verified_email`], unverified users are
+    /// rejected with `403`. The attempt is recorded as a [`SecurityEvent`].
+    pub(crate) async fn authenticate(
+        &self,
+        db: &Pool<Postgres>,
+        config: &AuthConfig,
+        client: &ClientInfo,
+        hooks: Option<&AuthHooks>,
+    ) -> Result<AuthUser, StatusCode> {
+        let result = self.check(db, config).await;
+
+        let event = match &result {
+            Ok(user) => SecurityEvent::new(SecurityEventKind::Login, true).with_user(user.id),
+            Err((user_id, detail, _)) => {
+                let event = SecurityEvent::new(SecurityEventKind::Login, false).with_detail(detail);
+                match user_id {
+                    Some(user_id) => event.with_user(*user_id),
+                    None => event,
+                }
+            }
+        };
+
+        let event = event.with_email(&self.email).with_client(client);
+
+        if let (Some(hooks), true) = (hooks, event.success) {
+            hooks.check_login(&event, db).await;
+        }
+
+        // A failure to write the log must not lock users out.
+        let _ = event.record(db).await;
+
+        result.map_err(|(_, _, status)| status)
+    }
+
+    async fn check(
+        &self,
+        db: &Pool<Postgres>,
+        config: &AuthConfig,
+    ) -> Result<AuthUser, (Option<Uuid>, &'static str, StatusCode)> {
+        if self.validate().is_err() {
+            return Err((None, "invalid email", StatusCode::UNAUTHORIZED));
+        }
+
+        let db_user = AuthUser::find_by_email(&self.email, db)
+            .await
+            .map_err(|_| (None, "unknown email", StatusCode::UNAUTHORIZED))?;
+
+        let failure = |detail, status| Err((Some(db_user.id), detail, status));
+
+        match providers::require_enabled(AuthProviderKind::Password, db).await {
+            Ok(()) => {}
+            Err(StatusCode::FORBIDDEN) => {
+                return failure("password sign-in disabled", StatusCode::FORBIDDEN);
+            }
+            Err(status) => return failure("database error", status),
+        }
+
+        if db_user.verify_password(&self.password).is_err() {
+            return failure("invalid password", StatusCode::UNAUTHORIZED);
+        }
+
+        if !db_user.status.is_active() {
+            return failure("account not active", StatusCode::FORBIDDEN);
+        }
+
+        if config.require_verified_email() && !db_user.email_verified {
+            return failure("email not verified", StatusCode::FORBIDDEN);
+        }
+
+        Ok(db_user)
+    }
 }
 
 #[utoipa::path(post, path = "/login")]
 async fn login(
     Extension(db): Extension<Pool<Postgres>>,
     Extension(config): Extension<AuthConfig>,
+    client: ClientInfo,
+    hooks: Option<Extension<AuthHooks>>,
+    _: Challenge,
     Form(form): Form<LoginPayload>,
 ) -> Result<String, StatusCode> {
-    if form.validate().is_err() {
-        return Err(StatusCode::UNAUTHORIZED);
-    }
+    let hooks = hooks.map(|Extension(hooks)| hooks);
+    let db_user = form
+        .authenticate(&db, &config, &client, hooks.as_ref())
+        .await?;
 
-    let db_user = AuthUser::find_by_email(&form.email, &db)
-        .await
-        .map_err(|_| StatusCode::UNAUTHORIZED)?;
+    config
+        .issue_token(db_user.id, ACCESS_TOKEN_TTL)
+        .map_err(|_| StatusCode::UNAUTHORIZED)
+}
+
+#[derive(Debug, ToSchema, Deserialize)]
+pub struct IntrospectPayload {
+    token: String,
+}
+
+/// Token introspection response, following RFC 7662. Inactive tokens only carry
+/// `active: false`.
+#[derive(Debug, Default, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct Introspection {
+    pub active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub: Option<Uuid>,
+    /// Expiration as seconds since the Unix epoch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exp: Option<i64>,
+    /// Issued at as seconds since the Unix epoch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iat: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
+    /// Space-separated scopes granted to the token.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    /// Party acting on behalf of `sub`, for impersonation tokens.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    pub act: Option<Actor>,
+}
+
+/// Reports whether a token is valid for this app, so other services can check sessions
+/// without sharing the signing secret or reading the users table.
+#[utoipa::path(post, path = "/introspect")]
+async fn introspect(
+    Extension(config): Extension<AuthConfig>,
+    Form(form): Form<IntrospectPayload>,
+) -> Json<Introspection> {
+    let Ok(claims) = config.verify_token(&form.token) else {
+        return Json(Introspection::default());
+    };
 
-    if db_user.verify_password(&form.password).is_err() {
-        return Err(StatusCode::UNAUTHORIZED);
+    Json(Introspection {
+        active: true,
+        sub: Some(claims.subject),
+        exp: Some(claims.expiration.timestamp()),
+        iat: Some(claims.issued_at.timestamp()),
+        iss: Some(claims.issuer),
+        aud: Some(claims.audience),
+        scope: claims.scope.map(|scopes| scopes.to_string()),
+        act: claims.actor,
+    })
+}
+
+/// Profile of the user a token was issued to, without the password hash.
+#[derive(Debug, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct UserInfo {
+    pub sub: Uuid,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub phone: Option<String>,
+    pub phone_verified: bool,
+    pub created: DateTime<Utc>,
+    pub updated: DateTime<Utc>,
+}
+
+impl From<AuthUser> for UserInfo {
+    fn from(user: AuthUser) -> Self {
+        Self {
+            sub: user.id,
+            email: user.email,
+            email_verified: user.email_verified,
+            phone: user.phone,
+            phone_verified: user.phone_verified,
+            created: user.created,
+            updated: user.updated,
+        }
     }
+}
 
-    let claims = JWTClaims::new(
-        db_user.id,
-        Duration::seconds(3600),
-        config.issuer,
-        config.audience,
-    );
+#[utoipa::path(get, path = "/userinfo", security(("bearer_auth" = [])))]
+async fn userinfo(
+    Extension(db): Extension<Pool<Postgres>>,
+    AuthClaims(claims): AuthClaims,
+) -> Result<Json<UserInfo>, StatusCode> {
+    let user = AuthUser::find_by_id(&claims.subject.to_string(), &db)
+        .await
+        .map_err(|_| StatusCode::UNAUTHORIZED)?;
 
-    Ok(claims
-        .sign(&config.key)
-        .map_err(|_| StatusCode::UNAUTHORIZED)?)
+    Ok(Json(user.into()))
 }
 
 pub fn router() -> OpenApiRouter {
-    OpenApiRouter::new().routes(routes!(login))
+    OpenApiRouter::with_openapi(openapi::document())
+        .routes(routes!(login))
+        .routes(routes!(introspect))
+        .routes(routes!(userinfo))
+        .merge(device::router())
+        .merge(otp::router())
+        .merge(permissions::router())
+        .merge(providers::router())
+        .merge(session::router())
+        .merge(verification::router())
+        .merge(security::router())
 }
 
 #[cfg(test)]
@@ -65,6 +247,15 @@ mod tests {
             issuer: "test-issuer".to_string(),
             audience: "test-audience".to_string(),
             key: "test-secret-key".to_string(),
+            retired_keys: vec![],
+            rotation_grace: crate::config::DEFAULT_ROTATION_GRACE,
+            leeway: crate::jwt::DEFAULT_LEEWAY,
+            device_verification_uri: "/auth/device".to_string(),
+            cookie_sessions: false,
+            require_verified_email: false,
+            verification_cooldown: crate::config::DEFAULT_VERIFICATION_COOLDOWN,
+            require_challenge: false,
+            urls: None,
         }
     }
 
@@ -79,7 +270,15 @@ mod tests {
             email: email.to_string(),
             password: password.to_string(),
         };
-        let result = login(Extension(db), Extension(config), Form(payload)).await;
+        let result = login(
+            Extension(db),
+            Extension(config),
+            ClientInfo::default(),
+            None,
+            Challenge::passed(),
+            Form(payload),
+        )
+        .await;
         assert!(
             result.is_ok(),
             "Login should succeed with correct credentials"
@@ -98,8 +297,21 @@ mod tests {
             email: email.to_string(),
             password: "wrongpass".to_string(),
         };
-        let result = login(Extension(db), Extension(config), Form(payload)).await;
+        let result = login(
+            Extension(db.clone()),
+            Extension(config),
+            ClientInfo::default(),
+            None,
+            Challenge::passed(),
+            Form(payload),
+        )
+        .await;
         assert!(result.is_err(), "Login should fail with wrong password");
+
+        let events = SecurityEvent::query(&Default::default(), &db).await?;
+        assert_eq!(events.len(), 1);
+        assert!(!events[0].success);
+        assert_eq!(events[0].detail.as_deref(), Some("invalid password"));
         Ok(())
     }
 
@@ -110,11 +322,85 @@ mod tests {
             email: "doesnotexist@example.com".to_string(),
             password: "irrelevant".to_string(),
         };
-        let result = login(Extension(db), Extension(config), Form(payload)).await;
+        let result = login(
+            Extension(db),
+            Extension(config),
+            ClientInfo::default(),
+            None,
+            Challenge::passed(),
+            Form(payload),
+        )
+        .await;
         assert!(result.is_err(), "Login should fail for nonexistent user");
         Ok(())
     }
 
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_login_rejects_inactive_accounts(db: Pool<Postgres>) -> anyhow::Result<()> {
+        let user = AuthUser::new("locked@example.com", "password")
+            .insert(&db)
+            .await?;
+        user.set_status(crate::status::AccountStatus::Locked, &db)
+            .await?;
+        let payload = LoginPayload {
+            email: "locked@example.com".to_string(),
+            password: "password".to_string(),
+        };
+
+        let result = login(
+            Extension(db),
+            Extension(test_config()),
+            ClientInfo::default(),
+            None,
+            Challenge::passed(),
+            Form(payload),
+        )
+        .await;
+        assert_eq!(result.unwrap_err(), StatusCode::FORBIDDEN);
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_login_requires_verified_email(db: Pool<Postgres>) -> anyhow::Result<()> {
+        let user = AuthUser::new("unverified@example.com", "password")
+            .insert(&db)
+            .await?;
+        let config = AuthConfig {
+            require_verified_email: true,
+            ..test_config()
+        };
+        let payload = || {
+            Form(LoginPayload {
+                email: "unverified@example.com".to_string(),
+                password: "password".to_string(),
+            })
+        };
+
+        let result = login(
+            Extension(db.clone()),
+            Extension(config.clone()),
+            ClientInfo::default(),
+            None,
+            Challenge::passed(),
+            payload(),
+        )
+        .await;
+        assert_eq!(result.unwrap_err(), StatusCode::FORBIDDEN);
+
+        user.confirm_email(&db).await?;
+        let result = login(
+            Extension(db),
+            Extension(config),
+            ClientInfo::default(),
+            None,
+            Challenge::passed(),
+            payload(),
+        )
+        .await;
+        assert!(result.is_ok());
+        Ok(())
+    }
+
     #[sqlx::test(migrations = "./migrations")]
     async fn test_jwt_structure(db: Pool<Postgres>) -> anyhow::Result<()> {
         let email = "jwtstruct@example.com";
@@ -126,9 +412,16 @@ mod tests {
             email: email.to_string(),
             password: password.to_string(),
         };
-        let result = login(Extension(db), Extension(config), Form(payload))
-            .await
-            .unwrap();
+        let result = login(
+            Extension(db),
+            Extension(config),
+            ClientInfo::default(),
+            None,
+            Challenge::passed(),
+            Form(payload),
+        )
+        .await
+        .unwrap();
         let parts: Vec<&str> = result.split('.').collect();
         assert_eq!(parts.len(), 3, "JWT should have 3 parts");
         Ok(())
@@ -145,9 +438,16 @@ mod tests {
             email: email.to_string(),
             password: password.to_string(),
         };
-        let jwt = login(Extension(db), Extension(config.clone()), Form(payload))
-            .await
-            .unwrap();
+        let jwt = login(
+            Extension(db),
+            Extension(config.clone()),
+            ClientInfo::default(),
+            None,
+            Challenge::passed(),
+            Form(payload),
+        )
+        .await
+        .unwrap();
         let claims = JWTClaims::verify(&jwt, &config.key)?;
 
         assert_eq!(claims.subject, inserted.id);
@@ -155,4 +455,95 @@ mod tests {
         assert!(claims.expiration > now, "exp should be in the future");
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_introspect() -> anyhow::Result<()> {
+        let config = test_config();
+        let subject = Uuid::new_v4();
+        let token = config.issue_token(subject, Duration::minutes(5))?;
+
+        let Json(active) =
+            introspect(Extension(config.clone()), Form(IntrospectPayload { token })).await;
+        assert!(active.active);
+        assert_eq!(active.sub, Some(subject));
+        assert_eq!(active.iss.as_deref(), Some("test-issuer"));
+
+        let Json(inactive) = introspect(
+            Extension(config),
+            Form(IntrospectPayload {
+                token: "not-a-token".to_string(),
+            }),
+        )
+        .await;
+        assert_eq!(inactive, Introspection::default());
+        assert_eq!(
+            serde_json::to_value(&inactive)?,
+            serde_json::json!({ "active": false })
+        );
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_userinfo(db: Pool<Postgres>) -> anyhow::Result<()> {
+        let inserted = AuthUser::new("userinfo@example.com", "pass")
+            .insert(&db)
+            .await?;
+        let config = test_config();
+        let token = config.issue_token(inserted.id, Duration::minutes(5))?;
+        let claims = config.verify_token(&token)?;
+
+        let Json(info) = userinfo(Extension(db.clone()), AuthClaims(claims))
+            .await
+            .unwrap();
+        assert_eq!(info.sub, inserted.id);
+        assert_eq!(info.email.as_deref(), Some("userinfo@example.com"));
+
+        let unknown =
+            config.verify_token(&config.issue_token(Uuid::new_v4(), Duration::minutes(5))?)?;
+        assert_eq!(
+            userinfo(Extension(db), AuthClaims(unknown))
+                .await
+                .unwrap_err(),
+            StatusCode::UNAUTHORIZED
+        );
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_login_fires_suspicious_login_hook(db: Pool<Postgres>) -> anyhow::Result<()> {
+        use std::sync::{Arc, Mutex};
+
+        AuthUser::new("traveler@example.com", "password")
+            .insert(&db)
+            .await?;
+        let flagged = Arc::new(Mutex::new(Vec::new()));
+        let seen = flagged.clone();
+        let hooks = AuthHooks::new().on_suspicious_login(move |login| {
+            seen.lock().unwrap().push(login.reasons.clone());
+        });
+
+        for ip in ["203.0.113.7", "203.0.113.7", "198.51.100.1"] {
+            let client = ClientInfo {
+                ip: Some(ip.parse()?),
+                user_agent: None,
+            };
+            let payload = LoginPayload {
+                email: "traveler@example.com".to_string(),
+                password: "password".to_string(),
+            };
+            login(
+                Extension(db.clone()),
+                Extension(test_config()),
+                client,
+                Some(Extension(hooks.clone())),
+                Challenge::passed(),
+                Form(payload),
+            )
+            .await
+            .unwrap();
+        }
+
+        assert_eq!(*flagged.lock().unwrap(), [vec!["new IP address"]]);
+        Ok(())
+    }
 }
@@ -9,6 +9,9 @@
 //! - Secure password storage with Argon2 and random salt
 //! - Password verification
 //! - Insert and query users from a PostgreSQL database
+//! - Email verification state, including a pending change of address
+//! - Phone numbers, for users signing in with one-time codes (see [`crate::otp`])
+//! - Account status (see [`crate::status`])
 //!
 //! ## Example
 //!
@@ -19,33 +22,61 @@
 //! ```
 
 use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
-use chrono::{DateTime, Utc};
-use password_hash::{SaltString, rand_core::OsRng};
+use chrono::{DateTime, Duration, Utc};
+use password_hash::{
+    SaltString,
+    rand_core::{OsRng, RngCore},
+};
 use sea_query::{Alias, Asterisk, Expr, PostgresQueryBuilder, Query};
 use serde::{Deserialize, Serialize};
-use sqlx::{Pool, Postgres, prelude::FromRow};
+use sqlx::{PgExecutor, Pool, Postgres, prelude::FromRow};
 use uuid::Uuid;
 
+use crate::status::AccountStatus;
+
 #[derive(Debug, FromRow, Clone, Serialize, Deserialize)]
 /// Represents an authenticated user in the Palmera system.
 ///
 /// Fields:
 /// - `id`: Unique identifier (UUID)
-/// - `email`: User's email address
+/// - `email`: User's email address, if they signed up with one
 /// - `password`: Argon2-hashed password (with salt and parameters)
 /// - `created`: UTC timestamp of creation
 /// - `updated`: UTC timestamp of last update
+/// - `email_verified`: Whether `email` has been confirmed
+/// - `pending_email`: Address the user asked to change to, until confirmed
+/// - `verification_sent_at`: When the last verification email was sent
+/// - `status`: Whether the account is active, disabled, locked or pending deletion
+/// - `phone`: User's phone number in E.164 format, if they added one
+/// - `phone_verified`: Whether `phone` has been confirmed with a one-time code
 pub struct AuthUser {
     /// Unique identifier for the user (UUID).
     pub id: Uuid,
-    /// User's email address.
-    pub email: String,
+    /// User's email address; `None` for users who signed up with a phone number.
+    ///
+    /// This was a `String` before phone sign-in (see [`crate::otp`]): code that reads it
+    /// has to handle users without an email.
+    pub email: Option<String>,
     /// Argon2-hashed password (including salt and parameters).
     pub password: String,
     /// Timestamp of when the user was created (UTC).
     pub created: DateTime<Utc>,
     /// Timestamp of when the user was last updated (UTC).
     pub updated: DateTime<Utc>,
+    /// Whether the user confirmed they own `email`.
+    pub email_verified: bool,
+    /// New address awaiting confirmation; replaces `email` once verified.
+    pub pending_email: Option<String>,
+    /// When the last verification email was sent (UTC). Tokens issued before it are no
+    /// longer accepted.
+    pub verification_sent_at: Option<DateTime<Utc>>,
+    /// Account status; only active users can sign in and use their tokens.
+    #[sqlx(try_from = "String")]
+    pub status: AccountStatus,
+    /// User's phone number in E.164 format, e.g. `+14155550123`.
+    pub phone: Option<String>,
+    /// Whether the user confirmed they own `phone`.
+    pub phone_verified: bool,
 }
 
 impl AuthUser {
@@ -68,13 +99,40 @@ impl AuthUser {
 
         Self {
             id: Uuid::new_v4(),
-            email: email.to_string(),
+            email: Some(email.to_string()),
             password: argon2
                 .hash_password(password.as_bytes(), &salt)
                 .unwrap()
                 .to_string(),
             created: now,
             updated: now,
+            email_verified: false,
+            pending_email: None,
+            verification_sent_at: None,
+            status: AccountStatus::Active,
+            phone: None,
+            phone_verified: false,
+        }
+    }
+
+    /// Create a new `AuthUser` identified by a verified phone number, without an email.
+    ///
+    /// The user gets a random password nobody knows, so they can only sign in with
+    /// one-time codes sent to `phone`.
+    ///
+    /// # Arguments
+    ///
+    /// * `phone` - The user's phone number in E.164 format.
+    pub fn with_phone(phone: &str) -> Self {
+        let mut secret = [0u8; 32];
+        OsRng.fill_bytes(&mut secret);
+        let password: String = secret.iter().map(|b| format!("{:02x}", b)).collect();
+
+        Self {
+            email: None,
+            phone: Some(phone.to_string()),
+            phone_verified: true,
+            ..Self::new("", &password)
         }
     }
 
@@ -101,7 +159,7 @@ impl AuthUser {
     ///
     /// # Arguments
     ///
-    /// * `db` - Reference to a SQLx Postgres connection pool.
+    /// * `db` - Reference to a SQLx Postgres connection pool, or a transaction.
     ///
     /// # Returns
     ///
@@ -110,7 +168,7 @@ impl AuthUser {
     /// # Errors
     ///
     /// Returns an error if the database operation fails.
-    pub async fn insert(self, db: &Pool<Postgres>) -> anyhow::Result<Self> {
+    pub async fn insert(self, db: impl PgExecutor<'_>) -> anyhow::Result<Self> {
         let sql = Query::insert()
             .into_table((Alias::new("auth"), Alias::new("users")))
             .columns([
@@ -119,6 +177,10 @@ impl AuthUser {
                 Alias::new("password"),
                 Alias::new("created"),
                 Alias::new("updated"),
+                Alias::new("email_verified"),
+                Alias::new("status"),
+                Alias::new("phone"),
+                Alias::new("phone_verified"),
             ])
             .values([
                 self.id.into(),
@@ -126,6 +188,10 @@ impl AuthUser {
                 self.password.into(),
                 self.created.into(),
                 self.updated.into(),
+                self.email_verified.into(),
+                self.status.as_str().into(),
+                self.phone.into(),
+                self.phone_verified.into(),
             ])?
             .returning_all()
             .to_string(PostgresQueryBuilder);
@@ -178,6 +244,160 @@ impl AuthUser {
 
         Ok(result)
     }
+
+    /// Find an `AuthUser` by their phone number.
+    ///
+    /// # Arguments
+    ///
+    /// * `phone` - The user's phone number in E.164 format.
+    /// * `db` - Reference to a SQLx Postgres connection pool.
+    ///
+    /// # Returns
+    ///
+    /// The `AuthUser`, or `None` if no user has this phone number.
+    pub async fn find_by_phone(phone: &str, db: &Pool<Postgres>) -> anyhow::Result<Option<Self>> {
+        let sql = Query::select()
+            .from((Alias::new("auth"), Alias::new("users")))
+            .column(Asterisk)
+            .and_where(Expr::col("phone").eq(phone))
+            .to_string(PostgresQueryBuilder);
+
+        let result = sqlx::query_as::<_, Self>(&sql).fetch_optional(db).await?;
+
+        Ok(result)
+    }
+
+    /// Look up the account status of the user with `id`.
+    ///
+    /// # Returns
+    ///
+    /// The status, or `None` if there is no such user.
+    pub async fn status_of(id: Uuid, db: &Pool<Postgres>) -> anyhow::Result<Option<AccountStatus>> {
+        let sql = Query::select()
+            .from((Alias::new("auth"), Alias::new("users")))
+            .column(Alias::new("status"))
+            .and_where(Expr::col("id").eq(id))
+            .to_string(PostgresQueryBuilder);
+
+        let status: Option<String> = sqlx::query_scalar(&sql).fetch_optional(db).await?;
+
+        status.map(|status| status.parse()).transpose()
+    }
+
+    /// Move the account to `status`, provided it still has the status of `self`.
+    ///
+    /// The caller is responsible for checking the transition is allowed with
+    /// [`AccountStatus::can_transition_to`].
+    ///
+    /// # Returns
+    ///
+    /// The updated `AuthUser`, or `None` if the status was changed concurrently.
+    pub async fn set_status(
+        &self,
+        status: AccountStatus,
+        db: &Pool<Postgres>,
+    ) -> anyhow::Result<Option<Self>> {
+        let sql = Query::update()
+            .table((Alias::new("auth"), Alias::new("users")))
+            .value(Alias::new("status"), status.as_str())
+            .value(Alias::new("updated"), Utc::now())
+            .and_where(Expr::col("id").eq(self.id))
+            .and_where(Expr::col("status").eq(self.status.as_str()))
+            .returning_all()
+            .to_string(PostgresQueryBuilder);
+
+        let result = sqlx::query_as::<_, Self>(&sql).fetch_optional(db).await?;
+
+        Ok(result)
+    }
+
+    /// Record that a verification email is being sent at `now`, unless one was sent
+    /// less than `cooldown` before.
+    ///
+    /// # Arguments
+    ///
+    /// * `pending_email` - New address to verify instead of the current one, if any.
+    /// * `now` - Send time; verification tokens must be issued at or after it.
+    /// * `cooldown` - Minimum delay between two verification emails.
+    /// * `db` - Reference to a SQLx Postgres connection pool.
+    ///
+    /// # Returns
+    ///
+    /// The updated `AuthUser`, or `None` if the cooldown has not elapsed yet.
+    pub async fn start_verification(
+        &self,
+        pending_email: Option<&str>,
+        now: DateTime<Utc>,
+        cooldown: Duration,
+        db: &Pool<Postgres>,
+    ) -> anyhow::Result<Option<Self>> {
+        let mut update = Query::update();
+        update
+            .table((Alias::new("auth"), Alias::new("users")))
+            .value(Alias::new("verification_sent_at"), now)
+            .value(Alias::new("updated"), now)
+            .and_where(Expr::col("id").eq(self.id))
+            .cond_where(
+                Expr::col("verification_sent_at")
+                    .is_null()
+                    .or(Expr::col("verification_sent_at").lte(now - cooldown)),
+            )
+            .returning_all();
+
+        if let Some(pending_email) = pending_email {
+            update.value(Alias::new("pending_email"), pending_email);
+        }
+
+        let sql = update.to_string(PostgresQueryBuilder);
+        let result = sqlx::query_as::<_, Self>(&sql).fetch_optional(db).await?;
+
+        Ok(result)
+    }
+
+    /// Mark the user's email as verified, replacing it with the pending address if
+    /// there is one. Outstanding verification tokens are invalidated.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the pending address is taken by another user or the database
+    /// operation fails.
+    pub async fn confirm_email(&self, db: &Pool<Postgres>) -> anyhow::Result<Self> {
+        let sql = Query::update()
+            .table((Alias::new("auth"), Alias::new("users")))
+            .value(
+                Alias::new("email"),
+                Expr::cust("coalesce(pending_email, email)"),
+            )
+            .value(Alias::new("pending_email"), Option::<String>::None)
+            .value(Alias::new("email_verified"), true)
+            .value(
+                Alias::new("verification_sent_at"),
+                Option::<DateTime<Utc>>::None,
+            )
+            .value(Alias::new("updated"), Utc::now())
+            .and_where(Expr::col("id").eq(self.id))
+            .returning_all()
+            .to_string(PostgresQueryBuilder);
+
+        let result = sqlx::query_as::<_, Self>(&sql).fetch_one(db).await?;
+
+        Ok(result)
+    }
+
+    /// Mark the user's phone number as verified.
+    pub async fn confirm_phone(&self, db: &Pool<Postgres>) -> anyhow::Result<Self> {
+        let sql = Query::update()
+            .table((Alias::new("auth"), Alias::new("users")))
+            .value(Alias::new("phone_verified"), true)
+            .value(Alias::new("updated"), Utc::now())
+            .and_where(Expr::col("id").eq(self.id))
+            .returning_all()
+            .to_string(PostgresQueryBuilder);
+
+        let result = sqlx::query_as::<_, Self>(&sql).fetch_one(db).await?;
+
+        Ok(result)
+    }
 }
 
 #[cfg(test)]
@@ -190,7 +410,7 @@ mod tests {
         let email = "test@example.com";
         let password = "securepassword";
         let user = AuthUser::new(email, password);
-        assert_eq!(user.email, email);
+        assert_eq!(user.email.as_deref(), Some(email));
         assert!(user.verify_password(password).is_ok());
     }
 
@@ -224,10 +444,10 @@ mod tests {
         let user = AuthUser::new(email, password);
         // Insert user
         let inserted = user.clone().insert(&db).await?;
-        assert_eq!(inserted.email, email);
+        assert_eq!(inserted.email.as_deref(), Some(email));
         // Find by id
         let found_by_id = AuthUser::find_by_id(&inserted.id.to_string(), &db).await?;
-        assert_eq!(found_by_id.email, email);
+        assert_eq!(found_by_id.email.as_deref(), Some(email));
         // Find by email
         let found_by_email = AuthUser::find_by_email(email, &db).await?;
         assert_eq!(found_by_email.id, inserted.id);
@@ -247,4 +467,69 @@ mod tests {
         assert!(result.is_err());
         Ok(())
     }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_email_verification_lifecycle(db: Pool<Postgres>) -> anyhow::Result<()> {
+        let user = AuthUser::new("verify@example.com", "password")
+            .insert(&db)
+            .await?;
+        assert!(!user.email_verified);
+
+        let cooldown = Duration::seconds(60);
+        let now = Utc::now();
+        let started = user
+            .start_verification(None, now, cooldown, &db)
+            .await?
+            .unwrap();
+        assert!(started.verification_sent_at.is_some());
+
+        // Within the cooldown no further email may be sent.
+        assert!(
+            started
+                .start_verification(Some("new@example.com"), now, cooldown, &db)
+                .await?
+                .is_none()
+        );
+
+        let verified = started.confirm_email(&db).await?;
+        assert!(verified.email_verified);
+        assert_eq!(verified.email.as_deref(), Some("verify@example.com"));
+        assert_eq!(verified.verification_sent_at, None);
+
+        let changing = verified
+            .start_verification(Some("new@example.com"), Utc::now(), cooldown, &db)
+            .await?
+            .unwrap();
+        assert_eq!(changing.pending_email.as_deref(), Some("new@example.com"));
+        assert_eq!(changing.email.as_deref(), Some("verify@example.com"));
+
+        let changed = changing.confirm_email(&db).await?;
+        assert_eq!(changed.email.as_deref(), Some("new@example.com"));
+        assert_eq!(changed.pending_email, None);
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_set_status(db: Pool<Postgres>) -> anyhow::Result<()> {
+        let user = AuthUser::new("status@example.com", "password")
+            .insert(&db)
+            .await?;
+        assert_eq!(user.status, AccountStatus::Active);
+
+        let locked = user.set_status(AccountStatus::Locked, &db).await?.unwrap();
+        assert_eq!(locked.status, AccountStatus::Locked);
+        assert_eq!(
+            AuthUser::status_of(user.id, &db).await?,
+            Some(AccountStatus::Locked)
+        );
+
+        // `user` is stale: its status is no longer the stored one.
+        assert!(
+            user.set_status(AccountStatus::Disabled, &db)
+                .await?
+                .is_none()
+        );
+        assert_eq!(AuthUser::status_of(Uuid::new_v4(), &db).await?, None);
+        Ok(())
+    }
 }
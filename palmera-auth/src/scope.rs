@@ -0,0 +1,135 @@
+//! # Token scopes
+//!
+//! Scopes restrict what a token may be used for. A scope is a colon-separated path from
+//! a resource to an action and optionally a target, e.g. `records:read`,
+//! `records:write:posts` or `files:write`. A granted scope covers every scope it is a
+//! prefix of, and `*` matches any segment, so `records:write` grants
+//! `records:write:posts` and `records:*` grants `records:read`.
+//!
+//! Tokens carry their scopes in the space-separated `scope` claim. Tokens without that
+//! claim, such as the ones issued at login, act with the full rights of their subject.
+//!
+//! # Example
+//!
+//! ```rust
+//! use palmera_auth::scope::Scopes;
+//!
+//! let scopes: Scopes = "records:read files:write".parse().unwrap();
+//! assert!(scopes.grants("records:read:posts"));
+//! assert!(!scopes.grants("records:write:posts"));
+//! assert_eq!(scopes.to_string(), "records:read files:write");
+//! ```
+
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Returns whether the granted scope `granted` covers `required`.
+pub fn scope_grants(granted: &str, required: &str) -> bool {
+    let mut required = required.split(':');
+
+    granted.split(':').all(|segment| match required.next() {
+        Some(part) => segment == "*" || segment == part,
+        None => false,
+    })
+}
+
+/// A set of granted scopes, serialized as a space-separated string.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Scopes(Vec<String>);
+
+impl Scopes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `scope`, ignoring duplicates.
+    pub fn with(mut self, scope: &str) -> Self {
+        if !self.0.iter().any(|s| s == scope) {
+            self.0.push(scope.to_string());
+        }
+        self
+    }
+
+    /// Returns whether any of the scopes covers `required`.
+    pub fn grants(&self, required: &str) -> bool {
+        self.0.iter().any(|granted| scope_grants(granted, required))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().map(String::as_str)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl<'a> FromIterator<&'a str> for Scopes {
+    fn from_iter<T: IntoIterator<Item = &'a str>>(iter: T) -> Self {
+        iter.into_iter().fold(Scopes::new(), Scopes::with)
+    }
+}
+
+impl FromStr for Scopes {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let scopes: Scopes = s.split_whitespace().collect();
+
+        if let Some(invalid) = scopes
+            .iter()
+            .find(|scope| scope.split(':').any(str::is_empty))
+        {
+            return Err(anyhow::anyhow!("Invalid scope `{}`", invalid));
+        }
+
+        Ok(scopes)
+    }
+}
+
+impl fmt::Display for Scopes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0.join(" "))
+    }
+}
+
+impl Serialize for Scopes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Scopes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scope_grants() {
+        assert!(scope_grants("records:read", "records:read"));
+        assert!(scope_grants("records:write", "records:write:posts"));
+        assert!(scope_grants("records:*", "records:read"));
+        assert!(scope_grants("*", "files:write"));
+        assert!(!scope_grants("records:write:posts", "records:write"));
+        assert!(!scope_grants("records:write:posts", "records:write:users"));
+        assert!(!scope_grants("records:read", "files:read"));
+    }
+
+    #[test]
+    fn test_parse_scopes() {
+        let scopes: Scopes = "  records:read files:write records:read ".parse().unwrap();
+        assert_eq!(
+            scopes.iter().collect::<Vec<_>>(),
+            ["records:read", "files:write"]
+        );
+        assert!("records::read".parse::<Scopes>().is_err());
+        assert!("".parse::<Scopes>().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,508 @@
+//! # Security events
+//!
+//! Every sign-in attempt made through the auth routes, successful or not, is recorded in
+//! `auth.security_events` together with the client's IP address and user agent (see
+//! [`ClientInfo`]). Apps record password changes and MFA events with
+//! [`SecurityEvent::record`], so the table holds a user's full security history for
+//! anomaly detection and compliance reporting.
+//! Events caused on a user's behalf, e.g. by an admin impersonating them or an API key,
+//! also name the actor; see [`SecurityEvent::with_claims`]. Email changes started with
+//! `POST /email` (see [`crate::verification`]) are recorded this way.
+//!
+//! - `GET /me/security-events` lists the signed-in user's latest events.
+//! - `GET /admin/security-events` in [`crate::admin`] queries all events, see
+//!   [`SecurityEventFilter`].
+//!
+//! Successful sign-ins are also checked by a [`SuspiciousLoginDetector`], by default
+//! [`NewClientDetector`], when handlers are bound with
+//! [`AuthHooks::on_suspicious_login`](crate::hooks::AuthHooks::on_suspicious_login).
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # async fn run(db: sqlx::Pool<sqlx::Postgres>, user_id: uuid::Uuid) -> anyhow::Result<()> {
+//! use palmera_auth::security::{SecurityEvent, SecurityEventKind};
+//!
+//! SecurityEvent::new(SecurityEventKind::Mfa, true)
+//!     .with_user(user_id)
+//!     .with_detail("totp enrolled")
+//!     .record(&db)
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{fmt, future::Future, pin::Pin, str::FromStr};
+
+use axum::{Extension, Json, extract::Query, http::StatusCode};
+use chrono::{DateTime, Utc};
+use sea_query::{Alias, Asterisk, Expr, Order, PostgresQueryBuilder, Query as SqlQuery};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres, prelude::FromRow};
+use utoipa::ToSchema;
+use utoipa_axum::{router::OpenApiRouter, routes};
+use uuid::Uuid;
+
+use crate::{
+    extract::{AuthClaims, ClientInfo},
+    jwt::JWTClaims,
+};
+
+/// Number of events returned when a query sets no limit.
+pub const DEFAULT_LIMIT: u64 = 50;
+
+/// Maximum number of events returned by a single query.
+pub const MAX_LIMIT: u64 = 500;
+
+#[derive(Debug, Clone, Copy, ToSchema, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SecurityEventKind {
+    /// A sign-in attempt with a password.
+    Login,
+    PasswordChange,
+    /// Enrollment, removal or challenge of a second factor.
+    Mfa,
+    /// A change of the account's email address was started.
+    EmailChange,
+}
+
+impl SecurityEventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SecurityEventKind::Login => "login",
+            SecurityEventKind::PasswordChange => "password_change",
+            SecurityEventKind::Mfa => "mfa",
+            SecurityEventKind::EmailChange => "email_change",
+        }
+    }
+}
+
+impl fmt::Display for SecurityEventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for SecurityEventKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "login" => Ok(SecurityEventKind::Login),
+            "password_change" => Ok(SecurityEventKind::PasswordChange),
+            "mfa" => Ok(SecurityEventKind::Mfa),
+            "email_change" => Ok(SecurityEventKind::EmailChange),
+            _ => Err(anyhow::anyhow!("Unknown security event kind `{}`", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for SecurityEventKind {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// An entry of the security log, stored in `auth.security_events`.
+#[derive(Debug, FromRow, Clone, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct SecurityEvent {
+    /// Assigned by the database; `0` until recorded.
+    pub id: i64,
+    /// The user the event concerns; `None` for sign-ins with an unknown email.
+    pub user_id: Option<Uuid>,
+    /// Who caused the event on behalf of the user, e.g. an admin impersonating them.
+    pub actor_id: Option<Uuid>,
+    /// Email given in a sign-in attempt.
+    pub email: Option<String>,
+    #[sqlx(try_from = "String")]
+    pub kind: SecurityEventKind,
+    pub success: bool,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    /// Free-form context, e.g. why a sign-in failed.
+    pub detail: Option<String>,
+    pub created: DateTime<Utc>,
+}
+
+impl SecurityEvent {
+    pub fn new(kind: SecurityEventKind, success: bool) -> Self {
+        Self {
+            id: 0,
+            user_id: None,
+            actor_id: None,
+            email: None,
+            kind,
+            success,
+            ip: None,
+            user_agent: None,
+            detail: None,
+            created: Utc::now(),
+        }
+    }
+
+    pub fn with_user(mut self, user_id: Uuid) -> Self {
+        self.user_id = Some(user_id);
+        self
+    }
+
+    /// Sets the user the event concerns, and its actor, from the claims of the token
+    /// used. See [`JWTClaims::actor_id`].
+    pub fn with_claims(mut self, claims: &JWTClaims) -> Self {
+        self.user_id = Some(claims.subject);
+        self.actor_id = claims.is_impersonation().then(|| claims.actor_id());
+        self
+    }
+
+    pub fn with_email(mut self, email: &str) -> Self {
+        self.email = Some(email.to_string());
+        self
+    }
+
+    /// Sets the IP address and user agent of the client that caused the event.
+    pub fn with_client(mut self, client: &ClientInfo) -> Self {
+        self.ip = client.ip.map(|ip| ip.to_string());
+        self.user_agent = client.user_agent.clone();
+        self
+    }
+
+    pub fn with_detail(mut self, detail: &str) -> Self {
+        self.detail = Some(detail.to_string());
+        self
+    }
+
+    // database operation
+
+    fn table() -> (Alias, Alias) {
+        (Alias::new("auth"), Alias::new("security_events"))
+    }
+
+    /// Insert this event into the database and return it as stored.
+    pub async fn record(self, db: &Pool<Postgres>) -> anyhow::Result<Self> {
+        let sql = SqlQuery::insert()
+            .into_table(Self::table())
+            .columns([
+                Alias::new("user_id"),
+                Alias::new("actor_id"),
+                Alias::new("email"),
+                Alias::new("kind"),
+                Alias::new("success"),
+                Alias::new("ip"),
+                Alias::new("user_agent"),
+                Alias::new("detail"),
+                Alias::new("created"),
+            ])
+            .values([
+                self.user_id.into(),
+                self.actor_id.into(),
+                self.email.into(),
+                self.kind.as_str().into(),
+                self.success.into(),
+                self.ip.into(),
+                self.user_agent.into(),
+                self.detail.into(),
+                self.created.into(),
+            ])?
+            .returning_all()
+            .to_string(PostgresQueryBuilder);
+
+        let result = sqlx::query_as::<_, Self>(&sql).fetch_one(db).await?;
+
+        Ok(result)
+    }
+
+    /// Find the events matching `filter`, newest first.
+    pub async fn query(
+        filter: &SecurityEventFilter,
+        db: &Pool<Postgres>,
+    ) -> anyhow::Result<Vec<Self>> {
+        let mut select = SqlQuery::select();
+        select
+            .from(Self::table())
+            .column(Asterisk)
+            .order_by(Alias::new("created"), Order::Desc)
+            .order_by(Alias::new("id"), Order::Desc)
+            .limit(filter.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT));
+
+        if let Some(user_id) = filter.user_id {
+            select.and_where(Expr::col("user_id").eq(user_id));
+        }
+        if let Some(kind) = filter.kind {
+            select.and_where(Expr::col("kind").eq(kind.as_str()));
+        }
+        if let Some(success) = filter.success {
+            select.and_where(Expr::col("success").eq(success));
+        }
+        if let Some(since) = filter.since {
+            select.and_where(Expr::col("created").gte(since));
+        }
+        if let Some(until) = filter.until {
+            select.and_where(Expr::col("created").lt(until));
+        }
+
+        let sql = select.to_string(PostgresQueryBuilder);
+        let result = sqlx::query_as::<_, Self>(&sql).fetch_all(db).await?;
+
+        Ok(result)
+    }
+}
+
+/// Criteria for [`SecurityEvent::query`], also accepted as query parameters.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SecurityEventFilter {
+    pub user_id: Option<Uuid>,
+    pub kind: Option<SecurityEventKind>,
+    pub success: Option<bool>,
+    /// Only events at or after this time.
+    pub since: Option<DateTime<Utc>>,
+    /// Only events before this time.
+    pub until: Option<DateTime<Utc>>,
+    /// At most this many events, [`DEFAULT_LIMIT`] by default and [`MAX_LIMIT`] at most.
+    pub limit: Option<u64>,
+}
+
+/// A successful sign-in that a [`SuspiciousLoginDetector`] flagged.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SuspiciousLogin {
+    /// The sign-in attempt.
+    pub event: SecurityEvent,
+    /// Why the sign-in looks suspicious, e.g. `new IP address`.
+    pub reasons: Vec<String>,
+}
+
+/// Decides whether a successful sign-in looks unusual for its user.
+///
+/// Implementations can compare the attempt with the user's history in
+/// `auth.security_events`, or look up the IP address in a geolocation database.
+pub trait SuspiciousLoginDetector: Send + Sync {
+    /// Returns why `attempt` is suspicious, or nothing if it is not. `attempt` has not
+    /// been recorded yet.
+    fn detect<'a>(
+        &'a self,
+        attempt: &'a SecurityEvent,
+        db: &'a Pool<Postgres>,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<Vec<String>>> + Send + 'a>>;
+}
+
+/// Flags sign-ins from an IP address or user agent that none of the user's recent
+/// successful sign-ins used. A user's first sign-in is never flagged.
+#[derive(Debug, Clone, Copy)]
+pub struct NewClientDetector {
+    /// Number of previous sign-ins compared against.
+    pub history: u64,
+}
+
+impl Default for NewClientDetector {
+    fn default() -> Self {
+        Self { history: 20 }
+    }
+}
+
+impl SuspiciousLoginDetector for NewClientDetector {
+    fn detect<'a>(
+        &'a self,
+        attempt: &'a SecurityEvent,
+        db: &'a Pool<Postgres>,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<Vec<String>>> + Send + 'a>> {
+        Box::pin(async move {
+            let Some(user_id) = attempt.user_id else {
+                return Ok(vec![]);
+            };
+
+            let filter = SecurityEventFilter {
+                user_id: Some(user_id),
+                kind: Some(SecurityEventKind::Login),
+                success: Some(true),
+                limit: Some(self.history),
+                ..Default::default()
+            };
+            let history = SecurityEvent::query(&filter, db).await?;
+            if history.is_empty() {
+                return Ok(vec![]);
+            }
+
+            let mut reasons = vec![];
+            if attempt.ip.is_some() && !history.iter().any(|event| event.ip == attempt.ip) {
+                reasons.push("new IP address".to_string());
+            }
+            if attempt.user_agent.is_some()
+                && !history
+                    .iter()
+                    .any(|event| event.user_agent == attempt.user_agent)
+            {
+                reasons.push("new device".to_string());
+            }
+
+            Ok(reasons)
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MySecurityEventsQuery {
+    kind: Option<SecurityEventKind>,
+    limit: Option<u64>,
+}
+
+/// Lists the signed-in user's security events, newest first.
+#[utoipa::path(
+    get,
+    path = "/me/security-events",
+    responses((status = 200, body = Vec<SecurityEvent>)),
+    security(("bearer_auth" = []))
+)]
+async fn my_security_events(
+    Extension(db): Extension<Pool<Postgres>>,
+    AuthClaims(claims): AuthClaims,
+    Query(query): Query<MySecurityEventsQuery>,
+) -> Result<Json<Vec<SecurityEvent>>, StatusCode> {
+    let filter = SecurityEventFilter {
+        user_id: Some(claims.subject),
+        kind: query.kind,
+        limit: query.limit,
+        ..Default::default()
+    };
+
+    SecurityEvent::query(&filter, &db)
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+pub fn router() -> OpenApiRouter {
+    OpenApiRouter::new().routes(routes!(my_security_events))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AuthConfig, schemas::AuthUser};
+    use chrono::Duration;
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_record_and_query(db: Pool<Postgres>) -> anyhow::Result<()> {
+        let user = AuthUser::new("events@example.com", "password")
+            .insert(&db)
+            .await?;
+        let client = ClientInfo {
+            ip: Some("203.0.113.7".parse()?),
+            user_agent: Some("curl/8.0".to_string()),
+        };
+
+        let failed = SecurityEvent::new(SecurityEventKind::Login, false)
+            .with_user(user.id)
+            .with_email("events@example.com")
+            .with_client(&client)
+            .with_detail("invalid password")
+            .record(&db)
+            .await?;
+        assert!(failed.id > 0);
+        assert_eq!(failed.ip.as_deref(), Some("203.0.113.7"));
+
+        // An admin changing the password while impersonating the user.
+        let admin = Uuid::new_v4();
+        let claims =
+            JWTClaims::new(user.id, Duration::minutes(5), "i".into(), "a".into()).with_actor(admin);
+        SecurityEvent::new(SecurityEventKind::PasswordChange, true)
+            .with_claims(&claims)
+            .record(&db)
+            .await?;
+        SecurityEvent::new(SecurityEventKind::Login, false)
+            .with_email("nobody@example.com")
+            .record(&db)
+            .await?;
+
+        let all = SecurityEvent::query(&SecurityEventFilter::default(), &db).await?;
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[0].email.as_deref(), Some("nobody@example.com"));
+        assert_eq!(
+            (all[1].user_id, all[1].actor_id),
+            (Some(user.id), Some(admin))
+        );
+        assert_eq!(all[2].actor_id, None);
+
+        let failures = SecurityEvent::query(
+            &SecurityEventFilter {
+                kind: Some(SecurityEventKind::Login),
+                success: Some(false),
+                user_id: Some(user.id),
+                ..Default::default()
+            },
+            &db,
+        )
+        .await?;
+        assert_eq!(failures, [failed]);
+
+        let future = SecurityEventFilter {
+            since: Some(Utc::now() + Duration::minutes(1)),
+            ..Default::default()
+        };
+        assert!(SecurityEvent::query(&future, &db).await?.is_empty());
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_my_security_events(db: Pool<Postgres>) -> anyhow::Result<()> {
+        let config = AuthConfig::builder().secret(SECRET).build()?;
+        let user = AuthUser::new("mine@example.com", "password")
+            .insert(&db)
+            .await?;
+        let other = AuthUser::new("theirs@example.com", "password")
+            .insert(&db)
+            .await?;
+        for id in [user.id, other.id] {
+            SecurityEvent::new(SecurityEventKind::Login, true)
+                .with_user(id)
+                .record(&db)
+                .await?;
+        }
+
+        let token = config.issue_token(user.id, Duration::minutes(5))?;
+        let Json(events) = my_security_events(
+            Extension(db),
+            AuthClaims(config.verify_token(&token)?),
+            Query(MySecurityEventsQuery {
+                kind: None,
+                limit: None,
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].user_id, Some(user.id));
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_new_client_detector(db: Pool<Postgres>) -> anyhow::Result<()> {
+        let user = AuthUser::new("detect@example.com", "password")
+            .insert(&db)
+            .await?;
+        let login = |ip: &str, user_agent: &str| {
+            SecurityEvent::new(SecurityEventKind::Login, true)
+                .with_user(user.id)
+                .with_client(&ClientInfo {
+                    ip: Some(ip.parse().unwrap()),
+                    user_agent: Some(user_agent.to_string()),
+                })
+        };
+        let detector = NewClientDetector::default();
+
+        let first = login("203.0.113.7", "firefox");
+        assert!(detector.detect(&first, &db).await?.is_empty());
+        first.record(&db).await?;
+
+        let same = login("203.0.113.7", "firefox");
+        assert!(detector.detect(&same, &db).await?.is_empty());
+
+        let elsewhere = login("198.51.100.1", "curl");
+        assert_eq!(
+            detector.detect(&elsewhere, &db).await?,
+            ["new IP address", "new device"]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,438 @@
+//! # Cookie sessions and CSRF protection
+//!
+//! Browser apps can keep their token in an `HttpOnly` cookie instead of handling it in
+//! JavaScript. The mode is off unless enabled with
+//! [`AuthConfigBuilder::cookie_sessions`](crate::AuthConfigBuilder::cookie_sessions):
+//!
+//! - `GET /csrf` returns a CSRF token and sets it in the readable [`CSRF_COOKIE`].
+//! - `POST /session` signs in with an email and password and sets the token in the
+//!   [`SESSION_COOKIE`]; `DELETE /session` clears it.
+//! - [`AuthClaims`](crate::extract::AuthClaims) accepts the session cookie when a request
+//!   has no `Authorization` header.
+//!
+//! Cookies are marked `Secure`, unless a trusted proxy explicitly reported that the
+//! client sent the request over plain HTTP, as recorded in the [`ClientAddr`] (see
+//! [`crate::proxy`]). Requests whose scheme is unknown get `Secure` cookies.
+//!
+//! Because browsers attach cookies to cross-site requests, a session cookie only
+//! authenticates a mutating request that passes the CSRF check. `AuthClaims` applies it
+//! to every request it accepts a cookie for; routes reading the cookie otherwise must be
+//! layered with [`verify_csrf`]. The check requires mutating requests that
+//! carry a session cookie to repeat the CSRF cookie in the [`CSRF_HEADER`] header, which
+//! other sites cannot do since they cannot read the cookie (double-submit).
+//!
+//! # Example
+//!
+//! ```rust
+//! use axum::{Router, middleware, routing::post};
+//!
+//! let app: Router = Router::new()
+//!     .route("/posts", post(|| async { "created" }))
+//!     .layer(middleware::from_fn(palmera_auth::session::verify_csrf));
+//! ```
+
+use axum::{
+    Extension, Form, Json,
+    extract::Request,
+    http::{HeaderMap, HeaderValue, Method, StatusCode, header},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+};
+use password_hash::rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use utoipa::ToSchema;
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+use crate::{
+    AuthConfig,
+    challenge::Challenge,
+    extract::ClientInfo,
+    hooks::AuthHooks,
+    proxy::ClientAddr,
+    router::{ACCESS_TOKEN_TTL, LoginPayload},
+};
+
+/// Cookie holding the session token; `HttpOnly`.
+pub const SESSION_COOKIE: &str = "palmera_session";
+
+/// Cookie holding the CSRF token; readable by scripts of the app's own origin.
+pub const CSRF_COOKIE: &str = "palmera_csrf";
+
+/// Header mutating requests must repeat the CSRF token in.
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+/// Returns the value of the cookie `name` sent with a request.
+pub fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+fn set_cookie(
+    name: &str,
+    value: &str,
+    max_age: i64,
+    http_only: bool,
+    client: Option<&Extension<ClientAddr>>,
+) -> HeaderValue {
+    let same_site = if http_only { "Lax" } else { "Strict" };
+    let http_only = if http_only { "; HttpOnly" } else { "" };
+    let secure = match client {
+        Some(Extension(client)) if client.https == Some(false) => "",
+        _ => "; Secure",
+    };
+
+    // Tokens are hex or base64url encoded, which are valid header characters.
+    HeaderValue::from_str(&format!(
+        "{}={}; Path=/; Max-Age={}{}; SameSite={}{}",
+        name, value, max_age, secure, same_site, http_only
+    ))
+    .expect("cookie values are valid header values")
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Whether a request with `method` and `headers` may use its session cookie: safe
+/// methods always may, others must repeat the CSRF cookie in the [`CSRF_HEADER`] header.
+pub(crate) fn csrf_verified(method: &Method, headers: &HeaderMap) -> bool {
+    if matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS) {
+        return true;
+    }
+
+    let expected = cookie(headers, CSRF_COOKIE);
+    let actual = headers
+        .get(CSRF_HEADER)
+        .and_then(|value| value.to_str().ok());
+    matches!(
+        (expected, actual),
+        (Some(expected), Some(actual)) if constant_time_eq(expected.as_bytes(), actual.as_bytes())
+    )
+}
+
+/// Middleware rejecting mutating requests that carry a session cookie without a matching
+/// CSRF token in the [`CSRF_HEADER`] header. Safe methods and requests authenticated by
+/// other means pass through.
+///
+/// [`AuthClaims`](crate::extract::AuthClaims) applies the same check when it accepts a
+/// session cookie, so routes authenticating with it are covered without the layer.
+pub async fn verify_csrf(request: Request, next: Next) -> Response {
+    if cookie(request.headers(), SESSION_COOKIE).is_none()
+        || csrf_verified(request.method(), request.headers())
+    {
+        next.run(request).await
+    } else {
+        StatusCode::FORBIDDEN.into_response()
+    }
+}
+
+#[derive(Debug, ToSchema, Serialize, Deserialize)]
+pub struct CsrfToken {
+    pub csrf_token: String,
+}
+
+/// Issues a CSRF token, reusing the one already set in the request's cookie.
+#[utoipa::path(get, path = "/csrf")]
+async fn csrf(
+    Extension(config): Extension<AuthConfig>,
+    client: Option<Extension<ClientAddr>>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    if !config.cookie_sessions() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let token = match cookie(&headers, CSRF_COOKIE) {
+        Some(token) => token.to_string(),
+        None => {
+            let mut bytes = [0u8; 32];
+            OsRng.fill_bytes(&mut bytes);
+            bytes.iter().map(|b| format!("{:02x}", b)).collect()
+        }
+    };
+
+    let cookie = set_cookie(
+        CSRF_COOKIE,
+        &token,
+        ACCESS_TOKEN_TTL.num_seconds(),
+        false,
+        client.as_ref(),
+    );
+
+    Ok((
+        [(header::SET_COOKIE, cookie)],
+        Json(CsrfToken { csrf_token: token }),
+    )
+        .into_response())
+}
+
+#[utoipa::path(post, path = "/session")]
+async fn create_session(
+    Extension(db): Extension<Pool<Postgres>>,
+    Extension(config): Extension<AuthConfig>,
+    client: ClientInfo,
+    address: Option<Extension<ClientAddr>>,
+    hooks: Option<Extension<AuthHooks>>,
+    _: Challenge,
+    Form(form): Form<LoginPayload>,
+) -> Result<Response, StatusCode> {
+    if !config.cookie_sessions() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let hooks = hooks.map(|Extension(hooks)| hooks);
+    let db_user = form
+        .authenticate(&db, &config, &client, hooks.as_ref())
+        .await?;
+
+    let token = config
+        .issue_token(db_user.id, ACCESS_TOKEN_TTL)
+        .map_err(|_| StatusCode::UNAUTHORIZED)?;
+
+    let cookie = set_cookie(
+        SESSION_COOKIE,
+        &token,
+        ACCESS_TOKEN_TTL.num_seconds(),
+        true,
+        address.as_ref(),
+    );
+
+    Ok((StatusCode::NO_CONTENT, [(header::SET_COOKIE, cookie)]).into_response())
+}
+
+#[utoipa::path(delete, path = "/session")]
+async fn delete_session(client: Option<Extension<ClientAddr>>) -> Response {
+    let cookie = set_cookie(SESSION_COOKIE, "", 0, true, client.as_ref());
+    (StatusCode::NO_CONTENT, [(header::SET_COOKIE, cookie)]).into_response()
+}
+
+pub fn router() -> OpenApiRouter {
+    OpenApiRouter::new()
+        .routes(routes!(csrf))
+        .routes(routes!(create_session, delete_session))
+        .route_layer(middleware::from_fn(verify_csrf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{extract::AuthClaims, schemas::AuthUser};
+    use axum::{Router, body::Body, extract::FromRequestParts, routing::post};
+    use tower::ServiceExt;
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+    fn cookie_value(response: &Response) -> String {
+        let set_cookie = response.headers()[header::SET_COOKIE].to_str().unwrap();
+        let (pair, _) = set_cookie.split_once(';').unwrap();
+        pair.to_string()
+    }
+
+    #[test]
+    fn test_cookie() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::COOKIE,
+            HeaderValue::from_static("theme=dark; palmera_session=abc; palmera_csrf=def"),
+        );
+
+        assert_eq!(cookie(&headers, SESSION_COOKIE), Some("abc"));
+        assert_eq!(cookie(&headers, CSRF_COOKIE), Some("def"));
+        assert_eq!(cookie(&headers, "missing"), None);
+    }
+
+    #[tokio::test]
+    async fn test_verify_csrf() {
+        let app = Router::new()
+            .route(
+                "/posts",
+                post(|| async { "created" }).get(|| async { "list" }),
+            )
+            .layer(middleware::from_fn(verify_csrf));
+
+        let call = |method: &str, cookies: Option<&'static str>, csrf: Option<&'static str>| {
+            let mut request = axum::http::Request::builder().method(method).uri("/posts");
+            if let Some(cookies) = cookies {
+                request = request.header(header::COOKIE, cookies);
+            }
+            if let Some(csrf) = csrf {
+                request = request.header(CSRF_HEADER, csrf);
+            }
+            let app = app.clone();
+            async move {
+                app.oneshot(request.body(Body::empty()).unwrap())
+                    .await
+                    .unwrap()
+                    .status()
+            }
+        };
+
+        let session = "palmera_session=token; palmera_csrf=secret";
+        assert_eq!(call("POST", None, None).await, StatusCode::OK);
+        assert_eq!(call("GET", Some(session), None).await, StatusCode::OK);
+        assert_eq!(
+            call("POST", Some(session), Some("secret")).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            call("POST", Some(session), None).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            call("POST", Some(session), Some("forged")).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            call("POST", Some("palmera_session=token"), Some("secret")).await,
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_cookie_session(db: Pool<Postgres>) -> anyhow::Result<()> {
+        let user = AuthUser::new("cookie@example.com", "password")
+            .insert(&db)
+            .await?;
+        let config = AuthConfig::builder()
+            .secret(SECRET)
+            .cookie_sessions(true)
+            .build()?;
+
+        let response = create_session(
+            Extension(db.clone()),
+            Extension(config.clone()),
+            ClientInfo::default(),
+            None,
+            None,
+            Challenge::passed(),
+            Form(LoginPayload {
+                email: "cookie@example.com".to_string(),
+                password: "password".to_string(),
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let set_cookie = response.headers()[header::SET_COOKIE].to_str()?;
+        assert!(set_cookie.contains("HttpOnly"));
+        assert!(set_cookie.contains("SameSite=Lax"));
+
+        let (mut parts, _) = axum::http::Request::builder()
+            .header(header::COOKIE, cookie_value(&response))
+            .extension(config.clone())
+            .body(())?
+            .into_parts();
+        let AuthClaims(claims) = AuthClaims::from_request_parts(&mut parts, &())
+            .await
+            .unwrap();
+        assert_eq!(claims.subject, user.id);
+
+        // Mutating requests authenticated by the cookie need the CSRF token.
+        let post = |csrf: Option<&str>| {
+            let mut request = axum::http::Request::builder()
+                .method("POST")
+                .header(
+                    header::COOKIE,
+                    format!("{}; {CSRF_COOKIE}=secret", cookie_value(&response)),
+                )
+                .extension(config.clone());
+            if let Some(csrf) = csrf {
+                request = request.header(CSRF_HEADER, csrf);
+            }
+            request.body(()).unwrap().into_parts().0
+        };
+        assert_eq!(
+            AuthClaims::from_request_parts(&mut post(None), &())
+                .await
+                .unwrap_err(),
+            StatusCode::FORBIDDEN
+        );
+        assert!(
+            AuthClaims::from_request_parts(&mut post(Some("secret")), &())
+                .await
+                .is_ok()
+        );
+
+        // Cookies are ignored unless cookie sessions are enabled.
+        let disabled = AuthConfig::builder().secret(SECRET).build()?;
+        parts.extensions.insert(disabled);
+        assert_eq!(
+            AuthClaims::from_request_parts(&mut parts, &())
+                .await
+                .unwrap_err(),
+            StatusCode::UNAUTHORIZED
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_csrf_token() {
+        let config = AuthConfig::builder()
+            .secret(SECRET)
+            .cookie_sessions(true)
+            .build()
+            .unwrap();
+
+        let response = csrf(Extension(config.clone()), None, HeaderMap::new())
+            .await
+            .unwrap();
+        let cookie = cookie_value(&response);
+        assert!(cookie.starts_with("palmera_csrf="));
+        assert!(
+            response.headers()[header::SET_COOKIE]
+                .to_str()
+                .unwrap()
+                .contains("; Secure")
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::COOKIE, HeaderValue::from_str(&cookie).unwrap());
+        let again = csrf(Extension(config.clone()), None, headers)
+            .await
+            .unwrap();
+        assert_eq!(cookie_value(&again), cookie);
+
+        // Without a reported scheme, cookies stay `Secure`.
+        let unknown = ClientAddr {
+            ip: "203.0.113.9".parse().unwrap(),
+            https: None,
+            host: None,
+            prefix: None,
+        };
+        let response = csrf(
+            Extension(config.clone()),
+            Some(Extension(unknown.clone())),
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+        let set_cookie = response.headers()[header::SET_COOKIE].to_str().unwrap();
+        assert!(set_cookie.contains("; Secure"));
+
+        // Plain HTTP requests reported by a trusted proxy get cookies without `Secure`.
+        let client = ClientAddr {
+            https: Some(false),
+            ..unknown
+        };
+        let plain = csrf(Extension(config), Some(Extension(client)), HeaderMap::new())
+            .await
+            .unwrap();
+        let set_cookie = plain.headers()[header::SET_COOKIE].to_str().unwrap();
+        assert!(!set_cookie.contains("Secure"));
+
+        let disabled = AuthConfig::builder().secret(SECRET).build().unwrap();
+        assert_eq!(
+            csrf(Extension(disabled), None, HeaderMap::new())
+                .await
+                .unwrap_err(),
+            StatusCode::NOT_FOUND
+        );
+    }
+}
@@ -0,0 +1,330 @@
+//! # First-run setup
+//!
+//! A new deployment starts without users, so nobody could sign in to administer it. The
+//! setup routes let whoever reaches the instance first create its admin account, without
+//! running SQL by hand:
+//!
+//! - `GET /setup` reports whether setup is still required: no user exists and it was
+//!   never completed.
+//! - `POST /setup` creates the first admin user, with a verified email, and records the
+//!   instance's name, its storage backend and the admin in `auth.instance`. It returns an
+//!   access token for the admin. Once setup is completed, or as soon as any user exists,
+//!   it is rejected with `409`, even if the admin is later deleted.
+//!
+//! The app reads the recorded choices with [`InstanceSetup::find`] and applies them with
+//! `palmera_core::base::App::apply_setup`. The admin recorded here is the one
+//! [`InstanceSetup::is_admin`] and the [`RequireAdmin`](crate::extract::RequireAdmin)
+//! layer guarding the admin routes recognize. Like the admin routes, [`router`] is not
+//! part of [`crate::router::router`]: deployments opt in by mounting it, and those
+//! creating their admin another way simply don't.
+
+use std::{fmt, str::FromStr};
+
+use axum::{Extension, Json, http::StatusCode};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres, prelude::FromRow};
+use utoipa::ToSchema;
+use utoipa_axum::{router::OpenApiRouter, routes};
+use uuid::Uuid;
+use validator::Validate;
+
+use crate::{AuthConfig, router::ACCESS_TOKEN_TTL, schemas::AuthUser};
+
+/// Shortest password accepted for the admin account.
+pub const MIN_ADMIN_PASSWORD_LENGTH: u64 = 8;
+
+/// Where the instance stores uploaded files.
+#[derive(Debug, Clone, Copy, ToSchema, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageBackend {
+    /// A directory on the server.
+    Local,
+    /// An S3 compatible object store.
+    S3,
+    /// Process memory, lost on restart; meant for trying the instance out.
+    Memory,
+}
+
+impl StorageBackend {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StorageBackend::Local => "local",
+            StorageBackend::S3 => "s3",
+            StorageBackend::Memory => "memory",
+        }
+    }
+}
+
+impl fmt::Display for StorageBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for StorageBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "local" => Ok(StorageBackend::Local),
+            "s3" => Ok(StorageBackend::S3),
+            "memory" => Ok(StorageBackend::Memory),
+            _ => Err(anyhow::anyhow!("Unknown storage backend `{}`", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for StorageBackend {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// The choices made during setup, stored in `auth.instance`.
+#[derive(Debug, FromRow, Clone, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct InstanceSetup {
+    pub name: String,
+    #[sqlx(try_from = "String")]
+    pub storage: StorageBackend,
+    /// The admin created during setup; `None` once that user is deleted.
+    pub admin_id: Option<Uuid>,
+    pub completed: DateTime<Utc>,
+}
+
+/// Body of `POST /setup`.
+#[derive(Debug, Clone, ToSchema, Deserialize, Validate)]
+pub struct SetupPayload {
+    #[validate(email)]
+    pub email: String,
+    #[validate(length(min = MIN_ADMIN_PASSWORD_LENGTH))]
+    pub password: String,
+    #[validate(length(min = 1, max = 64))]
+    pub instance_name: String,
+    pub storage: StorageBackend,
+}
+
+impl InstanceSetup {
+    // database operation
+
+    /// The completed setup, or `None` if the instance was never set up.
+    pub async fn find(db: &Pool<Postgres>) -> anyhow::Result<Option<Self>> {
+        let result = sqlx::query_as::<_, Self>(
+            "SELECT name, storage, admin_id, completed FROM auth.instance",
+        )
+        .fetch_optional(db)
+        .await?;
+
+        Ok(result)
+    }
+
+    /// Whether setup is still required: it was never completed and no user exists.
+    pub async fn is_required(db: &Pool<Postgres>) -> anyhow::Result<bool> {
+        let result = sqlx::query_scalar(
+            "SELECT NOT EXISTS (SELECT 1 FROM auth.instance) \
+                 AND NOT EXISTS (SELECT 1 FROM auth.users)",
+        )
+        .fetch_one(db)
+        .await?;
+
+        Ok(result)
+    }
+
+    /// Whether `user_id` is the admin recorded during setup.
+    pub async fn is_admin(user_id: Uuid, db: &Pool<Postgres>) -> anyhow::Result<bool> {
+        let result =
+            sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM auth.instance WHERE admin_id = $1)")
+                .bind(user_id)
+                .fetch_one(db)
+                .await?;
+
+        Ok(result)
+    }
+
+    /// Creates the admin user and records the setup in one transaction. Returns `None`
+    /// without changing anything if setup is no longer required. The caller is
+    /// responsible for validating the payload.
+    pub async fn complete(
+        payload: &SetupPayload,
+        db: &Pool<Postgres>,
+    ) -> anyhow::Result<Option<(Self, AuthUser)>> {
+        let mut tx = db.begin().await?;
+
+        // Concurrent setups wait here, and then find the instance set up; concurrent
+        // signups wait too, so no user can appear between the check and the insert.
+        sqlx::query("LOCK TABLE auth.instance, auth.users IN SHARE ROW EXCLUSIVE MODE")
+            .execute(&mut *tx)
+            .await?;
+        let required: bool = sqlx::query_scalar(
+            "SELECT NOT EXISTS (SELECT 1 FROM auth.instance) \
+                 AND NOT EXISTS (SELECT 1 FROM auth.users)",
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        if !required {
+            return Ok(None);
+        }
+
+        let admin = AuthUser {
+            email_verified: true,
+            ..AuthUser::new(&payload.email, &payload.password)
+        }
+        .insert(&mut *tx)
+        .await?;
+
+        let setup = sqlx::query_as::<_, Self>(
+            "INSERT INTO auth.instance (name, storage, admin_id) VALUES ($1, $2, $3) \
+             RETURNING name, storage, admin_id, completed",
+        )
+        .bind(&payload.instance_name)
+        .bind(payload.storage.as_str())
+        .bind(admin.id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(Some((setup, admin)))
+    }
+}
+
+#[derive(Debug, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct SetupStatus {
+    pub required: bool,
+}
+
+#[derive(Debug, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct SetupResponse {
+    /// Access token of the admin user.
+    pub access_token: String,
+    pub instance: InstanceSetup,
+}
+
+/// Reports whether the instance still needs to be set up.
+#[utoipa::path(get, path = "/setup", responses((status = 200, body = SetupStatus)))]
+async fn setup_status(
+    Extension(db): Extension<Pool<Postgres>>,
+) -> Result<Json<SetupStatus>, StatusCode> {
+    let required = InstanceSetup::is_required(&db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(SetupStatus { required }))
+}
+
+/// Creates the first admin user and records the instance settings.
+#[utoipa::path(
+    post,
+    path = "/setup",
+    request_body = SetupPayload,
+    responses((status = 200, body = SetupResponse), (status = 400), (status = 409))
+)]
+async fn complete_setup(
+    Extension(db): Extension<Pool<Postgres>>,
+    Extension(config): Extension<AuthConfig>,
+    Json(payload): Json<SetupPayload>,
+) -> Result<Json<SetupResponse>, StatusCode> {
+    if payload.validate().is_err() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let (instance, admin) = InstanceSetup::complete(&payload, &db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::CONFLICT)?;
+
+    let access_token = config
+        .issue_token(admin.id, ACCESS_TOKEN_TTL)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(SetupResponse {
+        access_token,
+        instance,
+    }))
+}
+
+pub fn router() -> OpenApiRouter {
+    OpenApiRouter::new().routes(routes!(setup_status, complete_setup))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+    fn payload(email: &str) -> SetupPayload {
+        SetupPayload {
+            email: email.to_string(),
+            password: "correct horse".to_string(),
+            instance_name: "Acme".to_string(),
+            storage: StorageBackend::Local,
+        }
+    }
+
+    async fn setup(
+        db: &Pool<Postgres>,
+        payload: SetupPayload,
+    ) -> Result<SetupResponse, StatusCode> {
+        let config = AuthConfig::builder().secret(SECRET).build().unwrap();
+        complete_setup(Extension(db.clone()), Extension(config), Json(payload))
+            .await
+            .map(|Json(response)| response)
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_setup_locks_after_completion(db: Pool<Postgres>) -> anyhow::Result<()> {
+        let Json(status) = setup_status(Extension(db.clone())).await.unwrap();
+        assert!(status.required);
+
+        let invalid = SetupPayload {
+            password: "short".to_string(),
+            ..payload("admin@example.com")
+        };
+        assert_eq!(
+            setup(&db, invalid).await.unwrap_err(),
+            StatusCode::BAD_REQUEST
+        );
+
+        let response = setup(&db, payload("admin@example.com")).await.unwrap();
+        assert_eq!(response.instance.name, "Acme");
+        assert_eq!(response.instance.storage, StorageBackend::Local);
+        let config = AuthConfig::builder().secret(SECRET).build()?;
+        let claims = config.verify_token(&response.access_token)?;
+        assert_eq!(Some(claims.subject), response.instance.admin_id);
+
+        let admin = AuthUser::find_by_id(&claims.subject.to_string(), &db).await?;
+        assert!(admin.email_verified);
+        assert!(admin.verify_password("correct horse").is_ok());
+        assert!(InstanceSetup::is_admin(admin.id, &db).await?);
+        assert_eq!(InstanceSetup::find(&db).await?, Some(response.instance));
+
+        let Json(status) = setup_status(Extension(db.clone())).await.unwrap();
+        assert!(!status.required);
+        assert_eq!(
+            setup(&db, payload("intruder@example.com"))
+                .await
+                .unwrap_err(),
+            StatusCode::CONFLICT
+        );
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_setup_requires_empty_database(db: Pool<Postgres>) -> anyhow::Result<()> {
+        AuthUser::new("existing@example.com", "password")
+            .insert(&db)
+            .await?;
+
+        assert!(!InstanceSetup::is_required(&db).await?);
+        assert_eq!(
+            setup(&db, payload("admin@example.com")).await.unwrap_err(),
+            StatusCode::CONFLICT
+        );
+        assert_eq!(InstanceSetup::find(&db).await?, None);
+        let existing = AuthUser::find_by_email("existing@example.com", &db).await?;
+        assert!(!InstanceSetup::is_admin(existing.id, &db).await?);
+        Ok(())
+    }
+}
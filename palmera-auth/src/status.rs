@@ -0,0 +1,141 @@
+//! # Account status
+//!
+//! Users can be taken out of service without deleting them. An account is in one of the
+//! [`AccountStatus`] states, stored in the `status` column of `auth.users`, and only
+//! `active` accounts can sign in or use their tokens:
+//!
+//! - `disabled`: turned off by an operator until further notice.
+//! - `locked`: blocked temporarily, e.g. after too many failed sign-ins.
+//! - `pending_deletion`: scheduled for removal; can still be restored.
+//!
+//! Not every change is allowed; see [`AccountStatus::can_transition_to`]. Operators
+//! change statuses through the admin routes in [`crate::admin`], which fire
+//! [`AuthHooks::on_status_change`](crate::hooks::AuthHooks::on_status_change).
+//!
+//! # Example
+//!
+//! ```rust
+//! use palmera_auth::status::AccountStatus;
+//!
+//! let status: AccountStatus = "locked".parse().unwrap();
+//! assert!(status.can_transition_to(AccountStatus::Active));
+//! assert!(!AccountStatus::PendingDeletion.can_transition_to(AccountStatus::Locked));
+//! ```
+
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, Default, ToSchema, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountStatus {
+    #[default]
+    Active,
+    Disabled,
+    Locked,
+    PendingDeletion,
+}
+
+impl AccountStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AccountStatus::Active => "active",
+            AccountStatus::Disabled => "disabled",
+            AccountStatus::Locked => "locked",
+            AccountStatus::PendingDeletion => "pending_deletion",
+        }
+    }
+
+    /// Whether the account may sign in and use its tokens.
+    pub fn is_active(&self) -> bool {
+        *self == AccountStatus::Active
+    }
+
+    /// Returns whether an account can move from this status to `to`.
+    ///
+    /// Any account can be scheduled for deletion, and every status but `active` can be
+    /// returned to `active`. Accounts pending deletion must be restored before they can
+    /// be disabled or locked.
+    pub fn can_transition_to(&self, to: AccountStatus) -> bool {
+        use AccountStatus::*;
+
+        match (self, to) {
+            (from, to) if *from == to => false,
+            (_, Active | PendingDeletion) => true,
+            (Active | Locked, Disabled) => true,
+            (Active, Locked) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for AccountStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for AccountStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "active" => Ok(AccountStatus::Active),
+            "disabled" => Ok(AccountStatus::Disabled),
+            "locked" => Ok(AccountStatus::Locked),
+            "pending_deletion" => Ok(AccountStatus::PendingDeletion),
+            _ => Err(anyhow::anyhow!("Unknown account status `{}`", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for AccountStatus {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// A status change of a user's account, passed to
+/// [`AuthHooks::on_status_change`](crate::hooks::AuthHooks::on_status_change).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccountStatusChange {
+    pub user_id: Uuid,
+    pub from: AccountStatus,
+    pub to: AccountStatus,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use AccountStatus::*;
+
+    #[test]
+    fn test_transitions() {
+        assert!(Active.can_transition_to(Disabled));
+        assert!(Active.can_transition_to(Locked));
+        assert!(Locked.can_transition_to(Disabled));
+        assert!(Disabled.can_transition_to(Active));
+        assert!(PendingDeletion.can_transition_to(Active));
+        assert!(Disabled.can_transition_to(PendingDeletion));
+
+        assert!(!Active.can_transition_to(Active));
+        assert!(!Disabled.can_transition_to(Locked));
+        assert!(!PendingDeletion.can_transition_to(Disabled));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for status in [Active, Disabled, Locked, PendingDeletion] {
+            assert_eq!(status.as_str().parse::<AccountStatus>().unwrap(), status);
+            assert_eq!(
+                serde_json::to_value(status).unwrap(),
+                serde_json::json!(status.as_str())
+            );
+        }
+        assert!("deleted".parse::<AccountStatus>().is_err());
+    }
+}
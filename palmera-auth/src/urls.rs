@@ -0,0 +1,267 @@
+//! # Absolute URLs
+//!
+//! Links that leave the API, such as those in emails, signed file links, device
+//! verification URIs, OAuth redirect URIs or webhook payloads, must be absolute and use
+//! the address users reach the instance at. Behind a reverse proxy or on a custom
+//! domain, that address differs from the one the server listens on. [`UrlBuilder`]
+//! builds these links from:
+//!
+//! - the configured base URL, e.g. `https://api.example.com/v1`, used by default and for
+//!   links built outside of a request;
+//! - for links built while handling a request, the host the request was sent to, when
+//!   it is the base URL's host or one of the instance's custom domains. The host,
+//!   scheme and path prefix reported by a trusted proxy, as resolved into the request's
+//!   [`ClientAddr`](crate::proxy::ClientAddr) by the
+//!   [`crate::proxy`] middleware, take precedence over `Host`; the forwarding headers of
+//!   other peers are never read.
+//!
+//! Other hosts are ignored, so a forged `Host` header can't send a password reset link
+//! to someone else's server.
+//!
+//! Handlers get the builder for their request with the [`RequestUrls`] extractor, which
+//! uses the builder of the [`AuthConfig`](crate::AuthConfig) in the request extensions.
+//!
+//! # Example
+//!
+//! ```rust
+//! use axum::http::{HeaderMap, HeaderValue, header};
+//! use palmera_auth::{proxy::ClientAddr, urls::UrlBuilder};
+//!
+//! let urls = UrlBuilder::new("https://api.example.com/v1")
+//!     .unwrap()
+//!     .with_custom_domain("api.acme.com");
+//! assert_eq!(urls.url("/auth/device"), "https://api.example.com/v1/auth/device");
+//!
+//! let mut headers = HeaderMap::new();
+//! headers.insert(header::HOST, HeaderValue::from_static("internal:3000"));
+//! let client = ClientAddr {
+//!     ip: "203.0.113.9".parse().unwrap(),
+//!     https: Some(true),
+//!     host: Some("api.acme.com".to_string()),
+//!     prefix: None,
+//! };
+//! let request = urls.for_request(&headers, Some(&client));
+//! assert_eq!(request.url("/auth/device"), "https://api.acme.com/v1/auth/device");
+//! ```
+
+use anyhow::bail;
+#[cfg(feature = "server")]
+use axum::{
+    extract::FromRequestParts,
+    http::{HeaderMap, header, request::Parts},
+};
+
+#[cfg(feature = "server")]
+use crate::{AuthConfig, proxy::ClientAddr};
+
+/// Builds absolute URLs of an instance; see the [module docs](self).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UrlBuilder {
+    scheme: String,
+    /// Host, with the port if any, lowercased.
+    host: String,
+    /// Path every URL starts with, without a trailing slash; empty for the root.
+    prefix: String,
+    /// Further hosts links may use, lowercased.
+    domains: Vec<String>,
+}
+
+impl UrlBuilder {
+    /// Builds URLs under `base_url`, an `http` or `https` URL without query or fragment.
+    pub fn new(base_url: &str) -> anyhow::Result<Self> {
+        let Some((scheme, rest)) = base_url.trim().split_once("://") else {
+            bail!("Base URL {base_url:?} has no scheme");
+        };
+        let scheme = scheme.to_ascii_lowercase();
+        if scheme != "http" && scheme != "https" {
+            bail!("Base URL {base_url:?} must use http or https");
+        }
+        if rest.contains(['?', '#']) {
+            bail!("Base URL {base_url:?} must not have a query or fragment");
+        }
+        let (host, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+        if host.is_empty() {
+            bail!("Base URL {base_url:?} has no host");
+        }
+
+        Ok(Self {
+            scheme,
+            host: host.to_ascii_lowercase(),
+            prefix: normalize_prefix(prefix),
+            domains: Vec::new(),
+        })
+    }
+
+    /// Lets requests sent to `host`, e.g. `api.acme.com`, get links on that host.
+    pub fn with_custom_domain(mut self, host: &str) -> Self {
+        self.domains.push(host.trim().to_ascii_lowercase());
+        self
+    }
+
+    /// The base URL, without a trailing slash.
+    pub fn base_url(&self) -> String {
+        format!("{}://{}{}", self.scheme, self.host, self.prefix)
+    }
+
+    /// The absolute URL of `path`. URLs that are already absolute are returned as is.
+    pub fn url(&self, path: &str) -> String {
+        if path.contains("://") {
+            return path.to_string();
+        }
+        format!("{}/{}", self.base_url(), path.trim_start_matches('/'))
+    }
+
+    /// A builder for links in the response to a request with `headers`, sent by
+    /// `client` as resolved by [`crate::proxy::resolve_client`]. Falls back to the base
+    /// URL when the request's host isn't one of the instance's.
+    #[cfg(feature = "server")]
+    pub fn for_request(&self, headers: &HeaderMap, client: Option<&ClientAddr>) -> Self {
+        let host = client
+            .and_then(|client| client.host.as_deref())
+            .or_else(|| headers.get(header::HOST)?.to_str().ok());
+        let Some(host) = host else {
+            return self.clone();
+        };
+        let host = host.trim().to_ascii_lowercase();
+        if host != self.host && !self.domains.contains(&host) {
+            return self.clone();
+        }
+        let scheme = match client.and_then(|client| client.https) {
+            Some(true) => "https".to_string(),
+            Some(false) => "http".to_string(),
+            None => self.scheme.clone(),
+        };
+
+        Self {
+            scheme,
+            host,
+            prefix: client
+                .and_then(|client| client.prefix.as_deref())
+                .map_or_else(|| self.prefix.clone(), normalize_prefix),
+            ..self.clone()
+        }
+    }
+}
+
+/// The [`UrlBuilder`] of the [`AuthConfig`] in the request extensions, adjusted to the
+/// request with [`UrlBuilder::for_request`]; `None` when the config has none. Never
+/// rejects.
+#[cfg(feature = "server")]
+#[derive(Debug, Clone, Default)]
+pub struct RequestUrls(pub Option<UrlBuilder>);
+
+#[cfg(feature = "server")]
+impl RequestUrls {
+    /// The absolute URL of `path`, or `path` itself without a builder.
+    pub fn url(&self, path: &str) -> String {
+        match &self.0 {
+            Some(urls) => urls.url(path),
+            None => path.to_string(),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl<S: Send + Sync> FromRequestParts<S> for RequestUrls {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let urls = parts
+            .extensions
+            .get::<AuthConfig>()
+            .and_then(AuthConfig::urls)
+            .map(|urls| urls.for_request(&parts.headers, parts.extensions.get::<ClientAddr>()));
+
+        Ok(RequestUrls(urls))
+    }
+}
+
+fn normalize_prefix(prefix: &str) -> String {
+    let prefix = prefix.trim().trim_matches('/');
+    if prefix.is_empty() {
+        String::new()
+    } else {
+        format!("/{prefix}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_url_builder() {
+        let urls = UrlBuilder::new("https://API.example.com/v1/").unwrap();
+        assert_eq!(urls.base_url(), "https://api.example.com/v1");
+        assert_eq!(urls.url("files/a"), "https://api.example.com/v1/files/a");
+        assert_eq!(
+            urls.url("https://cdn.example.com/a"),
+            "https://cdn.example.com/a"
+        );
+        assert_eq!(
+            UrlBuilder::new("http://localhost:3000")
+                .unwrap()
+                .url("/auth/device"),
+            "http://localhost:3000/auth/device"
+        );
+        for invalid in [
+            "api.example.com",
+            "ftp://api.example.com",
+            "https://",
+            "https://a/?b",
+        ] {
+            assert!(UrlBuilder::new(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[cfg(feature = "server")]
+    mod request {
+        use super::*;
+        use axum::http::HeaderValue;
+
+        fn host(value: &'static str) -> HeaderMap {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::HOST, HeaderValue::from_static(value));
+            headers
+        }
+
+        fn proxied(https: bool, host: &str, prefix: Option<&str>) -> ClientAddr {
+            ClientAddr {
+                ip: "203.0.113.9".parse().unwrap(),
+                https: Some(https),
+                host: Some(host.to_string()),
+                prefix: prefix.map(str::to_string),
+            }
+        }
+
+        #[test]
+        fn test_request_hosts() {
+            let urls = UrlBuilder::new("https://api.example.com")
+                .unwrap()
+                .with_custom_domain("api.acme.com");
+
+            let acme = urls.for_request(&host("API.acme.com"), None);
+            assert_eq!(acme.url("/a"), "https://api.acme.com/a");
+            // Unknown hosts are ignored.
+            assert_eq!(urls.for_request(&host("evil.com"), None), urls);
+            assert_eq!(urls.for_request(&HeaderMap::new(), None), urls);
+        }
+
+        #[test]
+        fn test_proxied_requests() {
+            let urls = UrlBuilder::new("https://api.example.com")
+                .unwrap()
+                .with_custom_domain("api.acme.com");
+
+            let client = proxied(false, "api.acme.com", Some("/palmera/"));
+            let request = urls.for_request(&host("internal:3000"), Some(&client));
+            assert_eq!(request.url("/a"), "http://api.acme.com/palmera/a");
+
+            let forged = proxied(true, "evil.com", None);
+            assert_eq!(
+                urls.for_request(&host("internal:3000"), Some(&forged)),
+                urls
+            );
+        }
+    }
+}
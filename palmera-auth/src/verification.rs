@@ -0,0 +1,497 @@
+//! # Email verification
+//!
+//! New users start with an unverified email. Verification emails carry a signed token,
+//! issued for the config's audience with a `#verify-email` suffix so it cannot be used
+//! as an access token, and valid for [`VERIFICATION_TOKEN_TTL`]:
+//!
+//! - `POST /verify-email/resend` sends a verification email to the signed-in user,
+//!   at most once per [`AuthConfig::verification_cooldown`]; earlier requests are
+//!   rejected with `429` and a `Retry-After` header.
+//! - `POST /email` starts a change of address. The new address is kept in
+//!   `pending_email` and only replaces the current one once verified. The change is
+//!   recorded as a [`SecurityEvent`], naming the actor of impersonation tokens.
+//! - `POST /verify-email` confirms the address a token was sent to. Tokens are single
+//!   use, and sending a new email invalidates the ones sent before.
+//!
+//! The crate does not send emails itself: the routes hand each [`VerificationEmail`] to
+//! the [`VerificationSender`] found in the request extensions. Its link opens
+//! [`VERIFICATION_LINK_PATH`] with the token, as an absolute URL on the address the
+//! request was sent to when [`AuthConfig::urls`] is configured (see [`crate::urls`]). With
+//! [`AuthConfig::require_verified_email`], unverified users cannot sign in.
+//!
+//! # Example
+//!
+//! ```rust
+//! use axum::Extension;
+//! use palmera_auth::verification::VerificationSender;
+//!
+//! let sender = VerificationSender::new(|email| {
+//!     println!("{} -> {}", email.link, email.email);
+//! });
+//! let router = palmera_auth::verification::router().layer(Extension(sender));
+//! ```
+
+use std::sync::Arc;
+
+use axum::{
+    Extension, Form,
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use utoipa::ToSchema;
+use utoipa_axum::{router::OpenApiRouter, routes};
+use uuid::Uuid;
+use validator::Validate;
+
+use crate::{
+    AuthConfig,
+    extract::{AuthClaims, ClientInfo},
+    jwt::JWTClaims,
+    schemas::AuthUser,
+    security::{SecurityEvent, SecurityEventKind},
+    urls::RequestUrls,
+};
+
+/// How long a verification token can be used.
+pub const VERIFICATION_TOKEN_TTL: Duration = Duration::seconds(86_400);
+
+/// Path of the page verification links open, with the token as the `token` query
+/// parameter; the page submits it to `POST /verify-email`.
+pub const VERIFICATION_LINK_PATH: &str = "/verify-email";
+
+/// A verification email to deliver.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerificationEmail {
+    pub user_id: Uuid,
+    /// Address to send the email to and that the token verifies.
+    pub email: String,
+    /// Token to submit to `POST /verify-email`.
+    pub token: String,
+    /// Link to [`VERIFICATION_LINK_PATH`] with the token, to put in the email.
+    pub link: String,
+}
+
+/// Delivers verification emails, e.g. by passing them to the app's mailer.
+#[derive(Clone)]
+pub struct VerificationSender(Arc<dyn Fn(VerificationEmail) + Send + Sync>);
+
+impl VerificationSender {
+    pub fn new<F>(send: F) -> Self
+    where
+        F: Fn(VerificationEmail) + Send + Sync + 'static,
+    {
+        Self(Arc::new(send))
+    }
+
+    pub fn send(&self, email: VerificationEmail) {
+        (self.0)(email)
+    }
+}
+
+impl std::fmt::Debug for VerificationSender {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("VerificationSender").finish_non_exhaustive()
+    }
+}
+
+fn verification_audience(config: &AuthConfig) -> String {
+    format!("{}#verify-email", config.audience())
+}
+
+/// Signs a verification token for `user_id`.
+pub fn issue_verification_token(config: &AuthConfig, user_id: Uuid) -> anyhow::Result<String> {
+    JWTClaims::new(
+        user_id,
+        VERIFICATION_TOKEN_TTL,
+        config.issuer().to_string(),
+        verification_audience(config),
+    )
+    .sign(&config.key)
+}
+
+/// Verifies a token issued by [`issue_verification_token`]. Access tokens are rejected.
+pub fn verify_verification_token(config: &AuthConfig, token: &str) -> anyhow::Result<JWTClaims> {
+    config.verify_token_with(
+        token,
+        &config
+            .verify_options()
+            .with_audience(&verification_audience(config)),
+    )
+}
+
+/// Sends a verification email for `user`, to `pending_email` if given and otherwise to
+/// the address awaiting verification.
+async fn send_verification(
+    db: &Pool<Postgres>,
+    config: &AuthConfig,
+    sender: &VerificationSender,
+    urls: &RequestUrls,
+    user: &AuthUser,
+    pending_email: Option<&str>,
+) -> Result<(), Response> {
+    let cooldown = config.verification_cooldown();
+    let now = Utc::now();
+
+    let Some(user) = user
+        .start_verification(pending_email, now, cooldown, db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?
+    else {
+        let retry_after = user
+            .verification_sent_at
+            .map(|sent_at| (sent_at + cooldown - now).num_seconds().max(1))
+            .unwrap_or(1);
+
+        return Err((
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, retry_after.to_string())],
+        )
+            .into_response());
+    };
+
+    let token = issue_verification_token(config, user.id)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+
+    let email = user
+        .pending_email
+        .or(user.email)
+        .ok_or_else(|| StatusCode::CONFLICT.into_response())?;
+
+    sender.send(VerificationEmail {
+        user_id: user.id,
+        email,
+        link: urls.url(&format!("{}?token={}", VERIFICATION_LINK_PATH, token)),
+        token,
+    });
+
+    Ok(())
+}
+
+/// Sends a new verification email to the signed-in user, or `409` if there is nothing
+/// to verify.
+#[utoipa::path(post, path = "/verify-email/resend", security(("bearer_auth" = [])))]
+async fn resend_verification(
+    Extension(db): Extension<Pool<Postgres>>,
+    Extension(config): Extension<AuthConfig>,
+    Extension(sender): Extension<VerificationSender>,
+    AuthClaims(claims): AuthClaims,
+    urls: RequestUrls,
+) -> Result<StatusCode, Response> {
+    let user = AuthUser::find_by_id(&claims.subject.to_string(), &db)
+        .await
+        .map_err(|_| StatusCode::UNAUTHORIZED.into_response())?;
+
+    if user.pending_email.is_none() && (user.email_verified || user.email.is_none()) {
+        return Err(StatusCode::CONFLICT.into_response());
+    }
+
+    send_verification(&db, &config, &sender, &urls, &user, None).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, ToSchema, Serialize, Deserialize, Validate)]
+pub struct ChangeEmailPayload {
+    #[validate(email)]
+    email: String,
+}
+
+/// Starts changing the signed-in user's email; the new address must be verified
+/// before it is used.
+#[utoipa::path(post, path = "/email", security(("bearer_auth" = [])))]
+async fn change_email(
+    Extension(db): Extension<Pool<Postgres>>,
+    Extension(config): Extension<AuthConfig>,
+    Extension(sender): Extension<VerificationSender>,
+    AuthClaims(claims): AuthClaims,
+    client: ClientInfo,
+    urls: RequestUrls,
+    Form(form): Form<ChangeEmailPayload>,
+) -> Result<StatusCode, Response> {
+    if form.validate().is_err() {
+        return Err(StatusCode::BAD_REQUEST.into_response());
+    }
+
+    let user = AuthUser::find_by_id(&claims.subject.to_string(), &db)
+        .await
+        .map_err(|_| StatusCode::UNAUTHORIZED.into_response())?;
+
+    if user.email.as_deref() == Some(form.email.as_str()) {
+        return Err(StatusCode::BAD_REQUEST.into_response());
+    }
+    if AuthUser::find_by_email(&form.email, &db).await.is_ok() {
+        return Err(StatusCode::CONFLICT.into_response());
+    }
+
+    send_verification(&db, &config, &sender, &urls, &user, Some(&form.email)).await?;
+
+    SecurityEvent::new(SecurityEventKind::EmailChange, true)
+        .with_claims(&claims)
+        .with_email(&form.email)
+        .with_client(&client)
+        .record(&db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[derive(Debug, ToSchema, Serialize, Deserialize)]
+pub struct VerifyEmailPayload {
+    token: String,
+}
+
+/// Confirms the address a verification token was sent to.
+#[utoipa::path(post, path = "/verify-email")]
+async fn verify_email(
+    Extension(db): Extension<Pool<Postgres>>,
+    Extension(config): Extension<AuthConfig>,
+    Form(form): Form<VerifyEmailPayload>,
+) -> Result<StatusCode, StatusCode> {
+    let claims =
+        verify_verification_token(&config, &form.token).map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let user = AuthUser::find_by_id(&claims.subject.to_string(), &db)
+        .await
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    // Only the token of the latest email is valid, and only until it is used.
+    match user.verification_sent_at {
+        Some(sent_at) if claims.issued_at >= sent_at => {}
+        _ => return Err(StatusCode::BAD_REQUEST),
+    }
+
+    if let Some(pending_email) = &user.pending_email
+        && AuthUser::find_by_email(pending_email, &db).await.is_ok()
+    {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    user.confirm_email(&db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub fn router() -> OpenApiRouter {
+    OpenApiRouter::new()
+        .routes(routes!(resend_verification))
+        .routes(routes!(change_email))
+        .routes(routes!(verify_email))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::security::SecurityEventFilter;
+    use std::sync::Mutex;
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+    fn capture() -> (VerificationSender, Arc<Mutex<Vec<VerificationEmail>>>) {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let outbox = sent.clone();
+        let sender = VerificationSender::new(move |email| outbox.lock().unwrap().push(email));
+        (sender, sent)
+    }
+
+    fn claims_for(config: &AuthConfig, user: &AuthUser) -> AuthClaims {
+        let token = config.issue_token(user.id, Duration::minutes(5)).unwrap();
+        AuthClaims(config.verify_token(&token).unwrap())
+    }
+
+    fn verify_payload(token: &str) -> Form<VerifyEmailPayload> {
+        Form(VerifyEmailPayload {
+            token: token.to_string(),
+        })
+    }
+
+    #[test]
+    fn test_verification_token_is_not_an_access_token() {
+        let config = AuthConfig::builder().secret(SECRET).build().unwrap();
+        let subject = Uuid::new_v4();
+
+        let token = issue_verification_token(&config, subject).unwrap();
+        assert_eq!(
+            verify_verification_token(&config, &token).unwrap().subject,
+            subject
+        );
+        assert!(config.verify_token(&token).is_err());
+
+        let access = config.issue_token(subject, Duration::minutes(5)).unwrap();
+        assert!(verify_verification_token(&config, &access).is_err());
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_resend_and_verify(db: Pool<Postgres>) -> anyhow::Result<()> {
+        let user = AuthUser::new("resend@example.com", "password")
+            .insert(&db)
+            .await?;
+        let config = AuthConfig::builder().secret(SECRET).build()?;
+        let (sender, sent) = capture();
+
+        let status = resend_verification(
+            Extension(db.clone()),
+            Extension(config.clone()),
+            Extension(sender.clone()),
+            claims_for(&config, &user),
+            RequestUrls(None),
+        )
+        .await
+        .unwrap();
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let throttled = resend_verification(
+            Extension(db.clone()),
+            Extension(config.clone()),
+            Extension(sender.clone()),
+            claims_for(&config, &user),
+            RequestUrls(None),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(throttled.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(throttled.headers().contains_key(header::RETRY_AFTER));
+
+        let email = sent.lock().unwrap().pop().unwrap();
+        assert_eq!(email.email, "resend@example.com");
+        assert_eq!(email.link, format!("/verify-email?token={}", email.token));
+        assert!(sent.lock().unwrap().is_empty());
+
+        let status = verify_email(
+            Extension(db.clone()),
+            Extension(config.clone()),
+            verify_payload(&email.token),
+        )
+        .await
+        .unwrap();
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(
+            AuthUser::find_by_id(&user.id.to_string(), &db)
+                .await?
+                .email_verified
+        );
+
+        // Tokens are single use, and verified users have nothing to resend.
+        let reused = verify_email(
+            Extension(db.clone()),
+            Extension(config.clone()),
+            verify_payload(&email.token),
+        )
+        .await;
+        assert_eq!(reused.unwrap_err(), StatusCode::BAD_REQUEST);
+
+        let conflict = resend_verification(
+            Extension(db),
+            Extension(config.clone()),
+            Extension(sender),
+            claims_for(&config, &user),
+            RequestUrls(None),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(conflict.status(), StatusCode::CONFLICT);
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_change_email(db: Pool<Postgres>) -> anyhow::Result<()> {
+        let user = AuthUser::new("old@example.com", "password")
+            .insert(&db)
+            .await?;
+        AuthUser::new("taken@example.com", "password")
+            .insert(&db)
+            .await?;
+        let config = AuthConfig::builder()
+            .secret(SECRET)
+            .verification_cooldown(Duration::zero())
+            .build()?;
+        let (sender, sent) = capture();
+
+        let change = |email: &str| {
+            change_email(
+                Extension(db.clone()),
+                Extension(config.clone()),
+                Extension(sender.clone()),
+                claims_for(&config, &user),
+                ClientInfo::default(),
+                RequestUrls(None),
+                Form(ChangeEmailPayload {
+                    email: email.to_string(),
+                }),
+            )
+        };
+
+        assert_eq!(
+            change("not an email").await.unwrap_err().status(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            change("taken@example.com").await.unwrap_err().status(),
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            change("typo@example.com").await.unwrap(),
+            StatusCode::ACCEPTED
+        );
+        let stale = sent.lock().unwrap().pop().unwrap();
+
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        assert_eq!(
+            change("new@example.com").await.unwrap(),
+            StatusCode::ACCEPTED
+        );
+        let email = sent.lock().unwrap().pop().unwrap();
+        assert_eq!(email.email, "new@example.com");
+
+        // Sending a new email invalidates the token sent to the mistyped address.
+        let status = verify_email(
+            Extension(db.clone()),
+            Extension(config.clone()),
+            verify_payload(&stale.token),
+        )
+        .await;
+        assert_eq!(status.unwrap_err(), StatusCode::BAD_REQUEST);
+
+        verify_email(
+            Extension(db.clone()),
+            Extension(config.clone()),
+            verify_payload(&email.token),
+        )
+        .await
+        .unwrap();
+        let user = AuthUser::find_by_id(&user.id.to_string(), &db).await?;
+        assert_eq!(user.email.as_deref(), Some("new@example.com"));
+        assert_eq!(user.pending_email, None);
+
+        // Changes made by an admin impersonating the user name the admin.
+        let admin = Uuid::new_v4();
+        let token = config.issue_impersonation_token(user.id, admin, Duration::minutes(5))?;
+        change_email(
+            Extension(db.clone()),
+            Extension(config.clone()),
+            Extension(sender.clone()),
+            AuthClaims(config.verify_token(&token)?),
+            ClientInfo::default(),
+            RequestUrls(None),
+            Form(ChangeEmailPayload {
+                email: "other@example.com".to_string(),
+            }),
+        )
+        .await
+        .unwrap();
+        let filter = SecurityEventFilter {
+            user_id: Some(user.id),
+            kind: Some(SecurityEventKind::EmailChange),
+            ..Default::default()
+        };
+        let events = SecurityEvent::query(&filter, &db).await?;
+        let actors: Vec<_> = events.iter().map(|event| event.actor_id).collect();
+        assert_eq!(actors, [Some(admin), None, None]);
+        Ok(())
+    }
+}
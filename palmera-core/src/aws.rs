@@ -0,0 +1,109 @@
+//! Signature version 4 signing of AWS API requests, shared by the AWS backed secret
+//! provider and mail transport.
+
+use anyhow::anyhow;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+/// Credentials of an AWS account in one region.
+#[derive(Clone)]
+pub(crate) struct AwsCredentials {
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// Temporary credentials' session token.
+    pub session_token: Option<String>,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("any key length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+impl AwsCredentials {
+    pub fn new(region: &str, access_key: &str, secret_key: &str) -> Self {
+        Self {
+            region: region.to_string(),
+            access_key: access_key.to_string(),
+            secret_key: secret_key.to_string(),
+            session_token: None,
+        }
+    }
+
+    /// Reads `AWS_REGION`, `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and the
+    /// optional `AWS_SESSION_TOKEN`.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let var = |name: &str| std::env::var(name).map_err(|_| anyhow!("{} is not set", name));
+
+        let mut credentials = Self::new(
+            &var("AWS_REGION")?,
+            &var("AWS_ACCESS_KEY_ID")?,
+            &var("AWS_SECRET_ACCESS_KEY")?,
+        );
+        credentials.session_token = std::env::var("AWS_SESSION_TOKEN").ok();
+        Ok(credentials)
+    }
+
+    /// Headers of a signed `POST` of `payload` to `path` of `service`, given the
+    /// request's own lower-case `headers`, which must include `host`.
+    pub fn sign(
+        &self,
+        service: &str,
+        path: &str,
+        mut headers: Vec<(String, String)>,
+        payload: &str,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Vec<(String, String)> {
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let scope = format!("{}/{}/{}/aws4_request", date, self.region, service);
+
+        headers.push(("x-amz-date".to_string(), amz_date.clone()));
+        if let Some(token) = &self.session_token {
+            headers.push(("x-amz-security-token".to_string(), token.clone()));
+        }
+        // sorted by name, as signature version 4 requires
+        headers.sort();
+
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value))
+            .collect();
+        let signed: Vec<_> = headers.iter().map(|(name, _)| name.as_str()).collect();
+        let signed = signed.join(";");
+
+        let canonical_request = format!(
+            "POST\n{}\n\n{}\n{}\n{}",
+            path,
+            canonical_headers,
+            signed,
+            hex(&Sha256::digest(payload))
+        );
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex(&Sha256::digest(canonical_request))
+        );
+
+        let key = hmac(format!("AWS4{}", self.secret_key).as_bytes(), &date);
+        let key = hmac(&key, &self.region);
+        let key = hmac(&key, service);
+        let key = hmac(&key, "aws4_request");
+        let signature = hex(&hmac(&key, &string_to_sign));
+
+        headers.push((
+            "authorization".to_string(),
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                self.access_key, scope, signed, signature
+            ),
+        ));
+        headers
+    }
+}
@@ -1,52 +1,863 @@
-use std::{any::Any, collections::BTreeMap};
+use std::{
+    any::Any,
+    collections::BTreeMap,
+    future::Future,
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+};
 
-use axum::Router;
-use tokio::net::TcpListener;
+use axum::{Extension, Json, Router, middleware, response::Response, routing::get};
+use palmera_auth::{
+    AuthConfig,
+    hooks::AuthHooks,
+    proxy::{self, TrustedProxies},
+    setup::{InstanceSetup, StorageBackend},
+};
+use palmera_database::embedded::RecordHooks;
+use tokio::{net::TcpListener, sync::watch};
 
 use crate::{
-    events::{BackupEvent, MailerEvent, ServeEvent, TerminateEvent},
+    builder::AppBuilder,
+    events::{
+        BackupEvent, BootstrapEvent, MailerEvent, RecordChangeEvent, ServeEvent,
+        SuspiciousLoginEvent, TerminateEvent,
+    },
     hook::Hook,
+    intercept::{self, Interceptor, Next, RequestContext},
+    meta::{self, Capabilities, InstanceMeta},
+    plugin::Plugin,
+    realtime::Realtime,
+    request_id,
+    scheduler::{WorkScheduler, limit_work},
+    secrets::{AUTH_SECRET, S3_ACCESS_KEY, S3_SECRET_KEY, Secrets},
 };
 
+/// Where uploaded files are stored.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StorageConfig {
+    Local {
+        dir: PathBuf,
+    },
+    S3 {
+        endpoint: String,
+        access_key: String,
+        secret_key: String,
+    },
+    /// Kept in process memory and lost on restart; meant for tests.
+    Memory,
+}
+
+impl StorageConfig {
+    /// Directory of [`StorageConfig::Local`] when chosen by the first-run setup.
+    pub const DEFAULT_DIR: &str = "uploads";
+
+    pub fn backend(&self) -> StorageBackend {
+        match self {
+            StorageConfig::Local { .. } => StorageBackend::Local,
+            StorageConfig::S3 { .. } => StorageBackend::S3,
+            StorageConfig::Memory => StorageBackend::Memory,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Lifecycle {
+    Running,
+    Restart,
+    Shutdown,
+}
+
+/// Controls a serving [`App`] from other tasks; obtained with [`App::handle`].
+#[derive(Clone)]
+pub struct AppHandle {
+    control: watch::Sender<Lifecycle>,
+}
+
+impl AppHandle {
+    /// Gracefully stops the server, bootstraps the app again and resumes listening on
+    /// the same address, within the same process.
+    pub fn restart(&self) {
+        self.control.send_replace(Lifecycle::Restart);
+    }
+
+    /// Gracefully stops the server; [`App::serve`] returns once in-flight requests finish.
+    pub fn shutdown(&self) {
+        self.control.send_replace(Lifecycle::Shutdown);
+    }
+}
+
 pub struct App {
     pub store: BTreeMap<String, Box<dyn Any + Send + Sync>>,
+    name: String,
+    metadata: serde_json::Map<String, serde_json::Value>,
     router: Router,
+    // routers passed to `mount`, replayed when the app bootstraps again on restart
+    mounts: Vec<(String, Router)>,
+    control: watch::Sender<Lifecycle>,
+    pub(crate) database_url: Option<String>,
+    pub(crate) auth: Option<AuthConfig>,
+    pub(crate) storage: Option<StorageConfig>,
+    pub(crate) address: SocketAddr,
+    pub(crate) secrets: Option<Secrets>,
+    pub(crate) trusted_proxies: Option<Arc<TrustedProxies>>,
+    pub(crate) work: Arc<WorkScheduler>,
+    interceptors: Vec<Interceptor>,
+    plugins: Vec<String>,
     // core events
+    pub on_bootstrap: Hook<BootstrapEvent<'static>>,
     pub on_serve: Hook<ServeEvent<'static>>,
     pub on_terminate: Hook<TerminateEvent>,
     pub on_backup: Hook<BackupEvent>,
     // mail events
     pub on_mail_send: Hook<MailerEvent>,
+    // auth events
+    pub on_suspicious_login: Hook<SuspiciousLoginEvent>,
+    // record events
+    pub on_record_change: Hook<RecordChangeEvent>,
+    // realtime channels and their subscribe hook
+    pub realtime: Realtime,
+    // whether a transport was mounted with `mount_realtime`
+    realtime_mounted: bool,
 }
 
 impl App {
     pub fn new() -> Self {
         Self {
             store: BTreeMap::new(),
+            name: "palmera".to_string(),
+            metadata: serde_json::Map::new(),
             router: Router::new(),
+            mounts: vec![],
+            control: watch::Sender::new(Lifecycle::Running),
+            database_url: None,
+            auth: None,
+            storage: None,
+            address: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 3000)),
+            secrets: None,
+            trusted_proxies: None,
+            work: Arc::new(WorkScheduler::new()),
+            interceptors: vec![],
+            plugins: vec![],
+            on_bootstrap: Hook::new(),
             on_serve: Hook::new(),
             on_terminate: Hook::new(),
             on_backup: Hook::new(),
             on_mail_send: Hook::new(),
+            on_suspicious_login: Hook::new(),
+            on_record_change: Hook::new(),
+            realtime: Realtime::new(),
+            realtime_mounted: false,
+        }
+    }
+
+    pub fn builder() -> AppBuilder {
+        AppBuilder::new()
+    }
+
+    /// Database connection string, when built with [`AppBuilder::database`].
+    pub fn database_url(&self) -> Option<&str> {
+        self.database_url.as_deref()
+    }
+
+    pub fn auth_config(&self) -> Option<&AuthConfig> {
+        self.auth.as_ref()
+    }
+
+    pub fn storage_config(&self) -> Option<&StorageConfig> {
+        self.storage.as_ref()
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Secret providers, when built with [`AppBuilder::secrets`].
+    pub fn secrets(&self) -> Option<&Secrets> {
+        self.secrets.as_ref()
+    }
+
+    /// Proxies whose forwarding headers are honored, when built with
+    /// [`AppBuilder::trusted_proxies`].
+    pub fn trusted_proxies(&self) -> Option<&TrustedProxies> {
+        self.trusted_proxies.as_deref()
+    }
+
+    /// Replaces the configured auth secret and S3 credentials with the ones known to
+    /// the app's [`Secrets`]. Called each time the app starts serving. A changed auth
+    /// secret is rotated in with [`AuthConfig::rotate`], so tokens signed with the
+    /// previous one stay valid for the grace window.
+    pub async fn resolve_secrets(&mut self) -> anyhow::Result<()> {
+        let Some(secrets) = &self.secrets else {
+            return Ok(());
+        };
+
+        if let Some(auth) = &self.auth
+            && let Some(secret) = secrets.get(AUTH_SECRET).await?
+        {
+            self.auth = Some(auth.rotate(&secret)?);
+        }
+
+        if let Some(StorageConfig::S3 {
+            access_key,
+            secret_key,
+            ..
+        }) = &mut self.storage
+        {
+            if let Some(value) = secrets.get(S3_ACCESS_KEY).await? {
+                *access_key = value;
+            }
+            if let Some(value) = secrets.get(S3_SECRET_KEY).await? {
+                *secret_key = value;
+            }
         }
+
+        Ok(())
+    }
+
+    /// Names of the registered plugins, in registration order.
+    pub fn plugins(&self) -> &[String] {
+        &self.plugins
+    }
+
+    /// Name of the instance, `palmera` by default.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn set_name(&mut self, name: &str) {
+        self.name = name.to_string();
+    }
+
+    /// Applies the choices recorded by the first-run setup (see [`palmera_auth::setup`]):
+    /// the instance name, and the storage backend. Without a configured storage, a local
+    /// or memory backend is used as recorded; S3 needs its endpoint and credentials
+    /// configured. Fails when the configured storage is a different backend.
+    pub fn apply_setup(&mut self, setup: &InstanceSetup) -> anyhow::Result<()> {
+        match &self.storage {
+            Some(storage) if storage.backend() != setup.storage => {
+                anyhow::bail!(
+                    "The instance was set up with {} storage, but {} is configured",
+                    setup.storage,
+                    storage.backend()
+                );
+            }
+            Some(_) => {}
+            None => {
+                self.storage = Some(match setup.storage {
+                    StorageBackend::Local => StorageConfig::Local {
+                        dir: StorageConfig::DEFAULT_DIR.into(),
+                    },
+                    StorageBackend::Memory => StorageConfig::Memory,
+                    StorageBackend::S3 => {
+                        anyhow::bail!(
+                            "The instance was set up with s3 storage, but none is configured"
+                        )
+                    }
+                });
+            }
+        }
+        self.name = setup.name.clone();
+        Ok(())
+    }
+
+    /// Adds `key` to the `metadata` of [`App::meta`], replacing an earlier value.
+    pub fn set_metadata(&mut self, key: &str, value: impl Into<serde_json::Value>) {
+        self.metadata.insert(key.to_string(), value.into());
+    }
+
+    /// Description of the instance served at `GET /api/meta`; see [`crate::meta`].
+    pub fn meta(&self) -> InstanceMeta {
+        let mut auth = vec![];
+        if let Some(config) = &self.auth {
+            auth.push("bearer".to_string());
+            if config.cookie_sessions() {
+                auth.push("cookie".to_string());
+            }
+        }
+        let storage = self
+            .storage
+            .as_ref()
+            .map(|storage| storage.backend().to_string());
+
+        InstanceMeta {
+            name: self.name.clone(),
+            version: meta::VERSION.to_string(),
+            features: meta::compiled_features(),
+            plugins: self.plugins.clone(),
+            capabilities: Capabilities {
+                auth,
+                realtime: self.realtime_mounted,
+                storage,
+            },
+            metadata: self.metadata.clone(),
+        }
+    }
+
+    /// Serves `router` under `path`; a path of `/` merges it into the root router.
+    pub fn mount(&mut self, path: &str, router: Router) {
+        self.mounts.push((path.to_string(), router.clone()));
+        self.apply_mount(path, router);
+    }
+
+    /// Serves `router`, the transport clients reach [`App::realtime`] through (e.g. a
+    /// WebSocket route), under `path`. [`App::meta`] only reports realtime once one is
+    /// mounted, since the channels alone are not reachable over HTTP.
+    pub fn mount_realtime(&mut self, path: &str, router: Router) {
+        self.realtime_mounted = true;
+        self.mount(path, router);
+    }
+
+    /// Serves `router` under `path` like [`App::mount`], running its requests as work of
+    /// `category` in the [`App::work_scheduler`].
+    pub fn mount_work(&mut self, path: &str, router: Router, category: &'static str) {
+        let limit = middleware::from_fn_with_state((self.work.clone(), category), limit_work);
+        self.mount(path, router.layer(limit));
+    }
+
+    /// Limits the expensive work of the app, configured with
+    /// [`AppBuilder::work_limit`]; see [`crate::scheduler`].
+    pub fn work_scheduler(&self) -> &Arc<WorkScheduler> {
+        &self.work
+    }
+
+    fn apply_mount(&mut self, path: &str, router: Router) {
+        let root = std::mem::take(&mut self.router);
+        self.router = match path {
+            "" | "/" => root.merge(router),
+            path => root.nest(path, router),
+        };
+    }
+
+    /// Runs `interceptor` on every request the app serves, after authentication and
+    /// before the route handler; see [`crate::intercept`]. Interceptors run in
+    /// registration order.
+    pub fn intercept<F, Fut>(&mut self, interceptor: F)
+    where
+        F: Fn(RequestContext, Next) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Response> + Send + 'static,
+    {
+        self.interceptors.push(intercept::interceptor(interceptor));
+    }
+
+    /// Hooks for the auth routes, forwarding their events to the app's hooks such as
+    /// [`App::on_suspicious_login`]. Add them to the auth router as an extension.
+    ///
+    /// The handlers run on a background task, so this must be called inside a tokio
+    /// runtime.
+    pub fn auth_hooks(&self) -> AuthHooks {
+        let trigger = self.on_suspicious_login.listen(64);
+
+        AuthHooks::new().on_suspicious_login(move |login| {
+            if trigger
+                .try_emit(SuspiciousLoginEvent::new(login.clone()))
+                .is_err()
+            {
+                tracing::warn!("dropped suspicious login event");
+            }
+        })
+    }
+
+    /// Hooks for an embedded `PalmeraClient`, forwarding its committed changes to
+    /// [`App::on_record_change`]. Attach them with `PalmeraClient::with_hooks`.
+    ///
+    /// The handlers run on a background task, so this must be called inside a tokio
+    /// runtime.
+    pub fn record_hooks(&self) -> RecordHooks {
+        let trigger = self.on_record_change.listen(64);
+
+        RecordHooks::new().on_change(move |change| {
+            if trigger
+                .try_emit(RecordChangeEvent::new(change.clone()))
+                .is_err()
+            {
+                tracing::warn!("dropped record change event");
+            }
+        })
+    }
+
+    pub fn register(&mut self, plugin: &dyn Plugin) -> anyhow::Result<()> {
+        plugin.register(self)?;
+        self.plugins.push(plugin.name().to_string());
+        Ok(())
+    }
+
+    pub fn router(&self) -> &Router {
+        &self.router
+    }
+
+    /// The router as it is served: the mounted routes and `GET /api/meta` wrapped in
+    /// the interceptors, with every request assigned a
+    /// [`RequestId`](crate::request_id::RequestId) and, with trusted proxies, a
+    /// [`ClientAddr`](palmera_auth::proxy::ClientAddr).
+    pub fn service(&self) -> Router {
+        let meta = Json(self.meta());
+        let mut router = self
+            .router
+            .clone()
+            .route(meta::META_PATH, get(move || async move { meta }));
+        if !self.interceptors.is_empty() {
+            let interceptors: Arc<[Interceptor]> = self.interceptors.clone().into();
+            router = router.layer(middleware::from_fn(move |request, next| {
+                intercept::run(interceptors.clone(), request, next)
+            }));
+            if let Some(auth) = &self.auth {
+                router = router.layer(Extension(auth.clone()));
+            }
+        }
+        router = router.layer(middleware::from_fn(request_id::propagate));
+        if let Some(proxies) = &self.trusted_proxies {
+            router = router.layer(middleware::from_fn_with_state(
+                proxies.clone(),
+                proxy::resolve_client,
+            ));
+        }
+        router
+    }
+
+    /// Handle for restarting or stopping the app while it serves.
+    pub fn handle(&self) -> AppHandle {
+        AppHandle {
+            control: self.control.clone(),
+        }
+    }
+
+    /// Restarts the app if it is serving; see [`AppHandle::restart`]. While
+    /// [`App::serve`] runs, restart it through [`App::handle`] instead.
+    pub fn restart(&self) {
+        self.handle().restart();
     }
 
     pub async fn start(&mut self) -> anyhow::Result<()> {
-        // SAFETY: We are extending the lifetime to 'static for the router reference,
-        // which is valid because self lives for the duration of App.
-        let router_ptr: *mut Router = &mut self.router;
-        let router_static: &'static mut Router = unsafe { &mut *router_ptr };
-
-        self.on_serve
-            .trigger(&mut ServeEvent {
-                router: router_static,
+        let listener = TcpListener::bind(self.address).await?;
+        self.serve(listener).await
+    }
+
+    /// Serves the app on an already bound listener, e.g. one bound to port 0 in tests.
+    ///
+    /// Runs until [`AppHandle::shutdown`] is called. On [`AppHandle::restart`] the server
+    /// stops gracefully, `on_terminate` runs with [`TerminateEvent::is_restart`] set, the
+    /// router is rebuilt from the mounted routers, secrets are resolved and
+    /// `on_bootstrap` runs again, and the app listens on the same address.
+    pub async fn serve(&mut self, listener: TcpListener) -> anyhow::Result<()> {
+        let mut listener = listener;
+
+        loop {
+            self.control.send_replace(Lifecycle::Running);
+            let mut signal = self.control.subscribe();
+            let address = listener.local_addr()?;
+            self.resolve_secrets().await?;
+
+            // SAFETY: We are extending the lifetime to 'static for the router reference,
+            // which is valid because self lives for the duration of App.
+            let router_static: &'static mut Router =
+                unsafe { &mut *(&mut self.router as *mut Router) };
+            self.on_bootstrap
+                .trigger(&BootstrapEvent::new(router_static))
+                .await;
+
+            // SAFETY: as above; the bootstrap event has been dropped.
+            let router_static: &'static mut Router =
+                unsafe { &mut *(&mut self.router as *mut Router) };
+            self.on_serve.trigger(&ServeEvent::new(router_static)).await;
+            tracing::info!("{}", self.meta().banner(&address.to_string()));
+
+            axum::serve(
+                listener,
+                self.service()
+                    .into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(async move {
+                let _ = signal.wait_for(|state| *state != Lifecycle::Running).await;
             })
-            .await;
+            .await?;
 
-        let listener = TcpListener::bind("0.0.0.0:3000").await?;
+            let is_restart = *self.control.borrow() == Lifecycle::Restart;
+            self.on_terminate
+                .trigger(&TerminateEvent::new(is_restart))
+                .await;
 
-        axum::serve(listener, self.router.clone().into_make_service()).await?;
+            if !is_restart {
+                return Ok(());
+            }
 
-        Ok(())
+            self.rebuild_router();
+            listener = TcpListener::bind(address).await?;
+        }
+    }
+
+    fn rebuild_router(&mut self) {
+        self.router = Router::new();
+        for (path, router) in self.mounts.clone() {
+            self.apply_mount(&path, router);
+        }
+    }
+}
+
+impl Default for App {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::Request, routing::get};
+    use tower::ServiceExt;
+
+    struct HelloPlugin;
+
+    impl Plugin for HelloPlugin {
+        fn name(&self) -> &str {
+            "hello"
+        }
+
+        fn register(&self, app: &mut App) -> anyhow::Result<()> {
+            app.mount(
+                "/hello",
+                Router::new().route("/", get(|| async { "hello" })),
+            );
+            Ok(())
+        }
+    }
+
+    async fn http_get(address: SocketAddr, path: &str) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            path
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_restart() {
+        use std::sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        };
+
+        let bootstraps = Arc::new(AtomicUsize::new(0));
+        let restarts = Arc::new(AtomicUsize::new(0));
+
+        let mut app = App::new();
+        app.mount("/", Router::new().route("/health", get(|| async { "ok" })));
+
+        let counter = bootstraps.clone();
+        app.on_bootstrap.bind_fn(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async { Err(anyhow::anyhow!("nothing to return")) })
+        });
+        let counter = restarts.clone();
+        app.on_terminate.bind_fn(move |event: &TerminateEvent| {
+            if event.is_restart() {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+            let event = *event;
+            Box::pin(async move { Ok(event) })
+        });
+
+        let listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+            .await
+            .unwrap();
+        let address = listener.local_addr().unwrap();
+        let handle = app.handle();
+        let server = tokio::spawn(async move { app.serve(listener).await });
+
+        while bootstraps.load(Ordering::SeqCst) < 1 {
+            tokio::task::yield_now().await;
+        }
+        assert!(http_get(address, "/health").await.ends_with("ok"));
+
+        handle.restart();
+        while bootstraps.load(Ordering::SeqCst) < 2 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(restarts.load(Ordering::SeqCst), 1);
+        assert!(http_get(address, "/health").await.ends_with("ok"));
+
+        handle.shutdown();
+        server.await.unwrap().unwrap();
+        assert_eq!(restarts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_secrets() {
+        use crate::secrets::FileSecrets;
+
+        let dir = std::env::temp_dir().join(format!("palmera-app-secrets-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        tokio::fs::write(dir.join(AUTH_SECRET), "fedcba9876543210fedcba9876543210")
+            .await
+            .unwrap();
+        tokio::fs::write(dir.join(S3_SECRET_KEY), "s3-secret")
+            .await
+            .unwrap();
+
+        let auth = AuthConfig::builder()
+            .secret("0123456789abcdef0123456789abcdef")
+            .build()
+            .unwrap();
+        let mut app = App::builder()
+            .database("sqlite::memory:")
+            .auth(auth.clone())
+            .storage(StorageConfig::S3 {
+                endpoint: "http://localhost:9000".to_string(),
+                access_key: "configured".to_string(),
+                secret_key: "configured".to_string(),
+            })
+            .secrets(Secrets::new().with(FileSecrets::new(&dir)))
+            .build()
+            .unwrap();
+        app.resolve_secrets().await.unwrap();
+
+        let token = app
+            .auth_config()
+            .unwrap()
+            .issue_token(uuid::Uuid::new_v4(), chrono::Duration::minutes(5))
+            .unwrap();
+        assert!(auth.verify_token(&token).is_err());
+        let previous = auth
+            .issue_token(uuid::Uuid::new_v4(), chrono::Duration::minutes(5))
+            .unwrap();
+        assert!(app.auth_config().unwrap().verify_token(&previous).is_ok());
+        assert_eq!(
+            app.storage_config(),
+            Some(&StorageConfig::S3 {
+                endpoint: "http://localhost:9000".to_string(),
+                access_key: "configured".to_string(),
+                secret_key: "s3-secret".to_string(),
+            })
+        );
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_intercept() {
+        use axum::{
+            http::{StatusCode, header},
+            response::IntoResponse,
+        };
+
+        let auth = AuthConfig::builder()
+            .secret("0123456789abcdef0123456789abcdef")
+            .build()
+            .unwrap();
+        let mut app = App::builder()
+            .database("sqlite::memory:")
+            .auth(auth.clone())
+            .mount(
+                "/",
+                Router::new().route(
+                    "/tenant",
+                    get(|Extension(tenant): Extension<String>| async move { tenant }),
+                ),
+            )
+            .build()
+            .unwrap();
+
+        app.intercept(|ctx, next| async move {
+            assert!(ctx.request_id().is_some());
+            if ctx.header("x-blocked").is_some() {
+                return StatusCode::TOO_MANY_REQUESTS.into_response();
+            }
+            next.run(ctx).await
+        });
+        app.intercept(|mut ctx, next| async move {
+            let tenant = match (ctx.subject(), ctx.actor()) {
+                (Some(subject), Some(actor)) if actor != subject => {
+                    format!("user-{} by {}", subject, actor)
+                }
+                (Some(subject), _) => format!("user-{}", subject),
+                _ => "anonymous".to_string(),
+            };
+            ctx.insert(tenant);
+            next.run(ctx).await
+        });
+
+        let call = |request: Request<Body>| async {
+            let response = app.service().oneshot(request).await.unwrap();
+            let status = response.status();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            (status, String::from_utf8(body.to_vec()).unwrap())
+        };
+
+        let anonymous = Request::get("/tenant").body(Body::empty()).unwrap();
+        assert_eq!(
+            call(anonymous).await,
+            (StatusCode::OK, "anonymous".to_string())
+        );
+
+        let subject = uuid::Uuid::new_v4();
+        let token = auth
+            .issue_token(subject, chrono::Duration::minutes(5))
+            .unwrap();
+        let user = Request::get("/tenant")
+            .header(header::AUTHORIZATION, format!("Bearer {}", token))
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(call(user).await.1, format!("user-{}", subject));
+
+        let admin = uuid::Uuid::new_v4();
+        let token = auth
+            .issue_impersonation_token(subject, admin, chrono::Duration::minutes(5))
+            .unwrap();
+        let impersonated = Request::get("/tenant")
+            .header(header::AUTHORIZATION, format!("Bearer {}", token))
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            call(impersonated).await.1,
+            format!("user-{} by {}", subject, admin)
+        );
+
+        let blocked = Request::get("/tenant")
+            .header("x-blocked", "1")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(call(blocked).await.0, StatusCode::TOO_MANY_REQUESTS);
+
+        // Responses answered by an interceptor still carry the request id.
+        let blocked = Request::get("/tenant")
+            .header("x-blocked", "1")
+            .header("x-request-id", "blocked-1")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.service().oneshot(blocked).await.unwrap();
+        assert_eq!(response.headers()["x-request-id"], "blocked-1");
+    }
+
+    #[tokio::test]
+    async fn test_mount_and_register() {
+        let mut app = App::new();
+        app.mount("/", Router::new().route("/health", get(|| async { "ok" })));
+        app.register(&HelloPlugin).unwrap();
+
+        assert_eq!(app.plugins(), ["hello"]);
+
+        for uri in ["/health", "/hello"] {
+            let response = app
+                .router()
+                .clone()
+                .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), 200, "{}", uri);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_record_hooks() {
+        use palmera_database::embedded::PalmeraClient;
+        use std::sync::Mutex;
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut app = App::new();
+        let seen_clone = seen.clone();
+        app.on_record_change
+            .bind_fn(move |event: &RecordChangeEvent| {
+                seen_clone
+                    .lock()
+                    .unwrap()
+                    .push(event.change().table.clone());
+                Box::pin(std::future::ready(Ok(event.clone())))
+            });
+
+        let db = sqlx::SqlitePool::connect("sqlite::memory:").await.unwrap();
+        sqlx::query("CREATE TABLE notes (id INTEGER PRIMARY KEY, title TEXT)")
+            .execute(&db)
+            .await
+            .unwrap();
+        let client = PalmeraClient::open(db)
+            .await
+            .unwrap()
+            .with_hooks(app.record_hooks());
+        client
+            .anonymous()
+            .create("notes", serde_json::json!({ "title": "hi" }))
+            .await
+            .unwrap();
+
+        while seen.lock().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(*seen.lock().unwrap(), ["notes"]);
+    }
+
+    #[tokio::test]
+    async fn test_meta() {
+        let mut app = App::new();
+        app.register(&HelloPlugin).unwrap();
+        app.storage = Some(StorageConfig::Memory);
+        app.set_metadata("region", "eu-west-1");
+
+        let response = app
+            .service()
+            .oneshot(Request::get("/api/meta").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let meta: InstanceMeta = serde_json::from_slice(&body).unwrap();
+        assert_eq!(meta.name, "palmera");
+        assert_eq!(meta.version, meta::VERSION);
+        assert_eq!(meta.plugins, ["hello"]);
+        assert_eq!(meta.capabilities.auth, Vec::<String>::new());
+        assert_eq!(meta.capabilities.storage.as_deref(), Some("memory"));
+        assert!(!meta.capabilities.realtime);
+        assert_eq!(meta.metadata["region"], "eu-west-1");
+
+        app.mount_realtime("/api/realtime", Router::new().route("/", get(|| async {})));
+        assert!(app.meta().capabilities.realtime);
+
+        app.auth = Some(
+            AuthConfig::builder()
+                .secret("0123456789abcdef0123456789abcdef")
+                .cookie_sessions(true)
+                .build()
+                .unwrap(),
+        );
+        assert_eq!(app.meta().capabilities.auth, ["bearer", "cookie"]);
+    }
+
+    #[test]
+    fn test_apply_setup() {
+        let setup = InstanceSetup {
+            name: "Acme".to_string(),
+            storage: StorageBackend::Local,
+            admin_id: None,
+            completed: chrono::Utc::now(),
+        };
+
+        let mut app = App::new();
+        app.apply_setup(&setup).unwrap();
+        assert_eq!(app.name(), "Acme");
+        assert_eq!(
+            app.storage_config(),
+            Some(&StorageConfig::Local {
+                dir: StorageConfig::DEFAULT_DIR.into()
+            })
+        );
+
+        let mut app = App::new();
+        app.storage = Some(StorageConfig::Memory);
+        assert!(app.apply_setup(&setup).is_err());
+        assert_eq!(app.name(), "palmera");
+
+        let s3 = InstanceSetup {
+            storage: StorageBackend::S3,
+            ..setup
+        };
+        assert!(App::new().apply_setup(&s3).is_err());
     }
 }
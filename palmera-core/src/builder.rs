@@ -0,0 +1,234 @@
+//! # App builder
+//!
+//! [`AppBuilder`] assembles an [`App`] from its configuration. The database and auth
+//! configuration are required: `build` only exists once both have been set, so a
+//! missing piece is a compile error rather than a panic at startup.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! use axum::{Router, routing::get};
+//! use palmera_auth::AuthConfig;
+//! use palmera_core::base::{App, StorageConfig};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let auth_config = AuthConfig::from_env()?;
+//! let app = App::builder()
+//!     .database("postgres://localhost/palmera")
+//!     .auth(auth_config)
+//!     .storage(StorageConfig::Local { dir: "uploads".into() })
+//!     .mount("/hello", Router::new().route("/", get(|| async { "hello" })))
+//!     .build()?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::Router;
+use palmera_auth::{AuthConfig, proxy::TrustedProxies};
+
+use crate::{
+    base::{App, StorageConfig},
+    plugin::Plugin,
+    scheduler::{WorkLimit, WorkScheduler},
+    secrets::Secrets,
+};
+
+/// Marker for a required piece of configuration that has not been set yet.
+pub struct Missing;
+
+/// Database connection string set on the builder.
+pub struct Database(String);
+
+/// Auth configuration set on the builder.
+pub struct Auth(AuthConfig);
+
+pub struct AppBuilder<D = Missing, A = Missing> {
+    database: D,
+    auth: A,
+    storage: Option<StorageConfig>,
+    address: Option<SocketAddr>,
+    secrets: Option<Secrets>,
+    trusted_proxies: Option<TrustedProxies>,
+    name: Option<String>,
+    routes: Vec<(String, Router)>,
+    plugins: Vec<Box<dyn Plugin>>,
+    work: WorkScheduler,
+}
+
+impl AppBuilder {
+    pub fn new() -> Self {
+        Self {
+            database: Missing,
+            auth: Missing,
+            storage: None,
+            address: None,
+            secrets: None,
+            trusted_proxies: None,
+            name: None,
+            routes: vec![],
+            plugins: vec![],
+            work: WorkScheduler::new(),
+        }
+    }
+}
+
+impl Default for AppBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<A> AppBuilder<Missing, A> {
+    pub fn database(self, url: &str) -> AppBuilder<Database, A> {
+        AppBuilder {
+            database: Database(url.to_string()),
+            auth: self.auth,
+            storage: self.storage,
+            address: self.address,
+            secrets: self.secrets,
+            trusted_proxies: self.trusted_proxies,
+            name: self.name,
+            routes: self.routes,
+            plugins: self.plugins,
+            work: self.work,
+        }
+    }
+}
+
+impl<D> AppBuilder<D, Missing> {
+    pub fn auth(self, config: AuthConfig) -> AppBuilder<D, Auth> {
+        AppBuilder {
+            database: self.database,
+            auth: Auth(config),
+            storage: self.storage,
+            address: self.address,
+            secrets: self.secrets,
+            trusted_proxies: self.trusted_proxies,
+            name: self.name,
+            routes: self.routes,
+            plugins: self.plugins,
+            work: self.work,
+        }
+    }
+}
+
+impl<D, A> AppBuilder<D, A> {
+    pub fn storage(mut self, config: StorageConfig) -> Self {
+        self.storage = Some(config);
+        self
+    }
+
+    /// Address to listen on, `0.0.0.0:3000` by default.
+    pub fn address(mut self, address: SocketAddr) -> Self {
+        self.address = Some(address);
+        self
+    }
+
+    /// Name of the instance, reported by `GET /api/meta`; `palmera` by default.
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// Reads secrets from `secrets` instead of the plain configuration each time the
+    /// app starts serving; see [`crate::secrets`].
+    pub fn secrets(mut self, secrets: Secrets) -> Self {
+        self.secrets = Some(secrets);
+        self
+    }
+
+    /// Honors the forwarding headers of requests from `proxies`, so rate limits, the
+    /// security log and session cookies see the real client; see
+    /// [`palmera_auth::proxy`].
+    pub fn trusted_proxies(mut self, proxies: TrustedProxies) -> Self {
+        self.trusted_proxies = Some(proxies);
+        self
+    }
+
+    /// Limits how many operations of `category` run at once; see
+    /// [`App::mount_work`].
+    pub fn work_limit(mut self, category: &str, limit: WorkLimit) -> Self {
+        self.work = self.work.with_category(category, limit);
+        self
+    }
+
+    /// Serves `router` under `path`; see [`App::mount`].
+    pub fn mount(mut self, path: &str, router: Router) -> Self {
+        self.routes.push((path.to_string(), router));
+        self
+    }
+
+    /// Registers `plugin` when the app is built, after all routes are mounted.
+    pub fn plugin(mut self, plugin: impl Plugin) -> Self {
+        self.plugins.push(Box::new(plugin));
+        self
+    }
+}
+
+impl AppBuilder<Database, Auth> {
+    /// Builds the app, failing if a plugin fails to register.
+    pub fn build(self) -> anyhow::Result<App> {
+        let mut app = App::new();
+        app.database_url = Some(self.database.0);
+        app.auth = Some(self.auth.0);
+        app.storage = self.storage;
+        app.secrets = self.secrets;
+        app.trusted_proxies = self.trusted_proxies.map(Arc::new);
+        app.work = Arc::new(self.work);
+
+        if let Some(address) = self.address {
+            app.address = address;
+        }
+        if let Some(name) = self.name {
+            app.set_name(&name);
+        }
+
+        for (path, router) in self.routes {
+            app.mount(&path, router);
+        }
+
+        for plugin in self.plugins {
+            app.register(plugin.as_ref())?;
+        }
+
+        Ok(app)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_build_app() {
+        let auth = AuthConfig::builder()
+            .secret("0123456789abcdef0123456789abcdef")
+            .build()
+            .unwrap();
+        let address = SocketAddr::from((Ipv4Addr::LOCALHOST, 8080));
+
+        let app = App::builder()
+            .auth(auth)
+            .database("sqlite::memory:")
+            .address(address)
+            .name("notes")
+            .trusted_proxies(TrustedProxies::new(["10.0.0.0/8"]).unwrap())
+            .storage(StorageConfig::Local {
+                dir: "uploads".into(),
+            })
+            .work_limit("export", WorkLimit::new(2, 10).unwrap())
+            .build()
+            .unwrap();
+
+        assert_eq!(app.database_url(), Some("sqlite::memory:"));
+        assert_eq!(app.auth_config().unwrap().issuer(), "palmera");
+        assert_eq!(app.address(), address);
+        assert!(app.storage_config().is_some());
+        assert_eq!(app.name(), "notes");
+        assert!(app.trusted_proxies().is_some());
+        assert_eq!(app.work_scheduler().stats()[0].category, "export");
+    }
+}
@@ -1,29 +1,234 @@
+use std::sync::Arc;
+
 use axum::Router;
-use lettre::SmtpTransport;
+use palmera_auth::{jwt::JWTClaims, security::SuspiciousLogin};
+use palmera_database::embedded::RecordChange;
 
-use crate::base::App;
+use crate::mailer::MailTransport;
 
 // app events data
 
+/// Emitted through [`App::on_terminate`](crate::base::App::on_terminate) when the app
+/// stops serving.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct TerminateEvent {
+    /// Whether the app is about to start again in the same process; handlers releasing
+    /// resources (pools, background tasks) can keep the ones a restart reuses.
     is_restart: bool,
 }
 
+impl TerminateEvent {
+    pub fn new(is_restart: bool) -> Self {
+        Self { is_restart }
+    }
+
+    pub fn is_restart(&self) -> bool {
+        self.is_restart
+    }
+
+    pub fn set_restart(&mut self, is_restart: bool) {
+        self.is_restart = is_restart;
+    }
+}
+
+/// Emitted through [`App::on_backup`](crate::base::App::on_backup) before a backup is
+/// taken.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct BackupEvent {
+    /// Name of the backup archive, shown to operators and used as the storage key.
     name: String,
+    /// Paths left out of the archive; plugins add the data they can rebuild, such as
+    /// caches.
     exclude: Vec<String>,
 }
 
+impl BackupEvent {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            exclude: vec![],
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn set_name(&mut self, name: &str) {
+        self.name = name.to_string();
+    }
+
+    pub fn exclude(&self) -> &[String] {
+        &self.exclude
+    }
+
+    /// Leaves `path` out of the backup.
+    pub fn add_exclude(&mut self, path: &str) {
+        if !self.exclude.iter().any(|p| p == path) {
+            self.exclude.push(path.to_string());
+        }
+    }
+
+    pub fn with_exclude(mut self, path: &str) -> Self {
+        self.add_exclude(path);
+        self
+    }
+
+    /// Returns whether `path` is excluded from the backup.
+    pub fn is_excluded(&self, path: &str) -> bool {
+        self.exclude.iter().any(|p| p == path)
+    }
+}
+
+/// Emitted through [`App::on_bootstrap`](crate::base::App::on_bootstrap) each time the
+/// app is set up to serve, including after a restart; handlers can reload configuration
+/// and work on the freshly built `router`.
 pub struct BootstrapEvent<'a> {
     pub router: &'a mut Router,
 }
 
+impl<'a> BootstrapEvent<'a> {
+    pub fn new(router: &'a mut Router) -> Self {
+        Self { router }
+    }
+}
+
+/// Emitted through [`App::on_serve`](crate::base::App::on_serve) right before the app
+/// starts listening; handlers can add final routes and layers on `router`.
 pub struct ServeEvent<'a> {
     pub router: &'a mut Router,
 }
 
+impl<'a> ServeEvent<'a> {
+    pub fn new(router: &'a mut Router) -> Self {
+        Self { router }
+    }
+}
+
 // mailer event
 
+/// Emitted through [`App::on_mail_send`](crate::base::App::on_mail_send) before mail is
+/// sent.
+#[derive(Clone)]
 pub struct MailerEvent {
-    mailer: SmtpTransport,
+    /// Transport the mail is sent with; handlers can swap it, e.g. for a test transport.
+    mailer: Arc<dyn MailTransport>,
+}
+
+impl MailerEvent {
+    pub fn new(mailer: Arc<dyn MailTransport>) -> Self {
+        Self { mailer }
+    }
+
+    pub fn mailer(&self) -> &Arc<dyn MailTransport> {
+        &self.mailer
+    }
+
+    pub fn set_mailer(&mut self, mailer: Arc<dyn MailTransport>) {
+        self.mailer = mailer;
+    }
+}
+
+// auth events
+
+/// Emitted through [`App::on_suspicious_login`](crate::base::App::on_suspicious_login)
+/// after a user signed in from an unfamiliar client.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SuspiciousLoginEvent {
+    login: SuspiciousLogin,
+}
+
+impl SuspiciousLoginEvent {
+    pub fn new(login: SuspiciousLogin) -> Self {
+        Self { login }
+    }
+
+    /// The sign-in and the reasons it was flagged.
+    pub fn login(&self) -> &SuspiciousLogin {
+        &self.login
+    }
+
+    pub fn reasons(&self) -> &[String] {
+        &self.login.reasons
+    }
+}
+
+// record events
+
+/// Emitted through [`App::on_record_change`](crate::base::App::on_record_change) after
+/// a change made through an embedded `PalmeraClient` was committed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordChangeEvent {
+    change: RecordChange,
+}
+
+impl RecordChangeEvent {
+    pub fn new(change: RecordChange) -> Self {
+        Self { change }
+    }
+
+    /// The table, action and record of the change.
+    pub fn change(&self) -> &RecordChange {
+        &self.change
+    }
+}
+
+// realtime events
+
+#[derive(Debug, Clone)]
+pub struct SubscribeEvent {
+    channel: String,
+    member_id: String,
+    claims: Option<JWTClaims>,
+}
+
+impl SubscribeEvent {
+    pub fn new(channel: &str, member_id: &str, claims: Option<JWTClaims>) -> Self {
+        Self {
+            channel: channel.to_string(),
+            member_id: member_id.to_string(),
+            claims,
+        }
+    }
+
+    /// Name of the channel being subscribed to.
+    pub fn channel(&self) -> &str {
+        &self.channel
+    }
+
+    /// Presence id of the subscribing member.
+    pub fn member_id(&self) -> &str {
+        &self.member_id
+    }
+
+    /// Verified claims of the subscriber, or `None` for anonymous clients.
+    pub fn claims(&self) -> Option<&JWTClaims> {
+        self.claims.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_terminate_event() {
+        let mut event = TerminateEvent::new(false);
+        assert!(!event.is_restart());
+        event.set_restart(true);
+        assert!(event.is_restart());
+    }
+
+    #[test]
+    fn test_backup_event() {
+        let mut event = BackupEvent::new("nightly").with_exclude("cache");
+        event.add_exclude("cache");
+        event.add_exclude("tmp");
+        event.set_name("weekly");
+
+        assert_eq!(event.name(), "weekly");
+        assert_eq!(event.exclude(), ["cache", "tmp"]);
+        assert!(event.is_excluded("tmp"));
+        assert!(!event.is_excluded("uploads"));
+    }
 }
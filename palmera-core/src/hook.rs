@@ -1,5 +1,10 @@
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tracing::Instrument;
 use uuid::Uuid;
 
 // New HandlerFn with Higher-Ranked Trait Bound (HRTB)
@@ -7,20 +12,200 @@ pub type HandlerFn<T> = Box<
     dyn Fn(&T) -> Pin<Box<dyn Future<Output = anyhow::Result<T>> + Send>> + Send + Sync + 'static,
 >;
 
+pub type ConditionFn<T> = Box<dyn Fn(&T) -> bool + Send + Sync + 'static>;
+
 pub struct Handler<T> {
     func: HandlerFn<T>,
     id: Option<String>,
     priority: Option<i16>,
+    label: Option<String>,
+    tags: Vec<String>,
+    condition: Option<ConditionFn<T>>,
+    once: bool,
+    // set once a `once` handler has succeeded, until it is removed
+    done: AtomicBool,
+    // claimed by the dispatch running a `once` handler, so no other runs it meanwhile
+    running: AtomicBool,
+    stats: HandlerStats,
+}
+
+#[derive(Default)]
+struct HandlerStats {
+    calls: AtomicU64,
+    total_nanos: AtomicU64,
+    max_nanos: AtomicU64,
+}
+
+impl HandlerStats {
+    fn record(&self, elapsed: Duration) {
+        let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        self.total_nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.max_nanos.fetch_max(nanos, Ordering::Relaxed);
+    }
+}
+
+/// Metadata and timings of a bound handler, as returned by [`Hook::handlers`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct HandlerInfo {
+    pub id: String,
+    pub priority: i16,
+    pub label: Option<String>,
+    pub tags: Vec<String>,
+    /// Number of times the handler ran.
+    pub calls: u64,
+    /// Time spent in the handler over all calls.
+    pub total_duration: Duration,
+    /// Slowest single call.
+    pub max_duration: Duration,
+}
+
+impl<T> Handler<T> {
+    pub fn new<F>(callback: F) -> Self
+    where
+        F: Fn(&T) -> Pin<Box<dyn Future<Output = anyhow::Result<T>> + Send>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        Self {
+            func: Box::new(callback),
+            id: None,
+            priority: None,
+            label: None,
+            tags: vec![],
+            condition: None,
+            once: false,
+            done: AtomicBool::new(false),
+            running: AtomicBool::new(false),
+            stats: HandlerStats::default(),
+        }
+    }
+
+    /// Binds the handler under `id` instead of a generated one.
+    pub fn with_id(mut self, id: &str) -> Self {
+        self.id = Some(id.to_string());
+        self
+    }
+
+    /// Handlers run in ascending priority, `0` by default.
+    pub fn with_priority(mut self, priority: i16) -> Self {
+        self.priority = Some(priority);
+        self
+    }
+
+    /// Human readable name shown in [`Hook::handlers`] and tracing spans.
+    pub fn with_label(mut self, label: &str) -> Self {
+        self.label = Some(label.to_string());
+        self
+    }
+
+    /// Adds a tag, so the handler can be unbound with others via [`Hook::unbind_tag`].
+    pub fn with_tag(mut self, tag: &str) -> Self {
+        self.tags.push(tag.to_string());
+        self
+    }
+
+    /// Unbinds the handler after its first successful run.
+    pub fn once(mut self) -> Self {
+        self.once = true;
+        self
+    }
+
+    /// Only runs the handler for values matching `predicate`.
+    pub fn when<P>(mut self, predicate: P) -> Self
+    where
+        P: Fn(&T) -> bool + Send + Sync + 'static,
+    {
+        self.condition = Some(Box::new(predicate));
+        self
+    }
+
+    fn info(&self) -> HandlerInfo {
+        HandlerInfo {
+            id: self.id.clone().unwrap_or_default(),
+            priority: self.priority.unwrap_or(0),
+            label: self.label.clone(),
+            tags: self.tags.clone(),
+            calls: self.stats.calls.load(Ordering::Relaxed),
+            total_duration: Duration::from_nanos(self.stats.total_nanos.load(Ordering::Relaxed)),
+            max_duration: Duration::from_nanos(self.stats.max_nanos.load(Ordering::Relaxed)),
+        }
+    }
 }
 
+// Shared with the dispatchers started by `listen`, so handlers bound later still run.
+type Handlers<T> = Arc<RwLock<Vec<Arc<Handler<T>>>>>;
+
 pub struct Hook<T> {
-    handlers: Vec<Handler<T>>,
+    handlers: Handlers<T>,
+}
+
+/// Error returned when a [`HookTrigger`] cannot queue a value; the value is handed back.
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum TriggerError<T> {
+    #[error("hook queue is full")]
+    Full(T),
+    #[error("hook dispatcher has stopped")]
+    Closed(T),
+}
+
+/// Sends values to the dispatcher started by [`Hook::listen`]. Cloneable, and usable
+/// from other tasks and from synchronous code; the dispatcher stops once every trigger
+/// has been dropped.
+pub struct HookTrigger<T> {
+    sender: mpsc::Sender<T>,
+}
+
+impl<T> Clone for HookTrigger<T> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+impl<T> HookTrigger<T> {
+    /// Queues `value`, waiting for room when the queue is full.
+    pub async fn emit(&self, value: T) -> Result<(), TriggerError<T>> {
+        self.sender
+            .send(value)
+            .await
+            .map_err(|err| TriggerError::Closed(err.0))
+    }
+
+    /// Queues `value` without waiting, failing with [`TriggerError::Full`] when the queue
+    /// is full.
+    pub fn try_emit(&self, value: T) -> Result<(), TriggerError<T>> {
+        self.sender.try_send(value).map_err(|err| match err {
+            mpsc::error::TrySendError::Full(value) => TriggerError::Full(value),
+            mpsc::error::TrySendError::Closed(value) => TriggerError::Closed(value),
+        })
+    }
+
+    /// Queues `value` from synchronous code, blocking the thread while the queue is full.
+    ///
+    /// # Panics
+    ///
+    /// Panics when called from within an async runtime; use [`Self::emit`] there.
+    pub fn blocking_emit(&self, value: T) -> Result<(), TriggerError<T>> {
+        self.sender
+            .blocking_send(value)
+            .map_err(|err| TriggerError::Closed(err.0))
+    }
+
+    /// Number of values that can be queued before emitting waits or fails.
+    pub fn remaining_capacity(&self) -> usize {
+        self.sender.capacity()
+    }
 }
 
 impl<T: Send + 'static> Hook<T> {
     // T must be Send if you want to use it across awaits
     pub fn new() -> Self {
-        Self { handlers: vec![] }
+        Self {
+            handlers: Arc::new(RwLock::new(vec![])),
+        }
     }
 
     pub fn bind(&mut self, handler: Handler<T>) -> String {
@@ -29,8 +214,9 @@ impl<T: Send + 'static> Hook<T> {
             handler.id = Some(generate_hook_id());
         }
         let id = handler.id.clone().unwrap();
-        self.handlers.push(handler);
-        self.handlers.sort_by_key(|h| h.priority.unwrap_or(0));
+        let mut handlers = self.handlers.write().unwrap();
+        handlers.push(Arc::new(handler));
+        handlers.sort_by_key(|h| h.priority.unwrap_or(0));
         id
     }
 
@@ -42,20 +228,36 @@ impl<T: Send + 'static> Hook<T> {
             + Sync
             + 'static,
     {
-        let func: HandlerFn<T> = Box::new(move |value: &T| Box::pin(callback(value)));
-        self.bind(Handler {
-            func,
-            id: None,
-            priority: None,
-        })
+        self.bind(Handler::new(callback))
+    }
+
+    /// Binds `handler` to run until it first succeeds.
+    pub fn bind_once(&mut self, handler: Handler<T>) -> String {
+        self.bind(handler.once())
+    }
+
+    /// Binds `handler` to run only for values matching `predicate`.
+    pub fn bind_if<P>(&mut self, predicate: P, handler: Handler<T>) -> String
+    where
+        P: Fn(&T) -> bool + Send + Sync + 'static,
+    {
+        self.bind(handler.when(predicate))
+    }
+
+    /// Unbinds every handler tagged with `tag`, returning how many were removed.
+    pub fn unbind_tag(&mut self, tag: &str) -> usize {
+        let mut handlers = self.handlers.write().unwrap();
+        let original_len = handlers.len();
+        handlers.retain(|handler| !handler.tags.iter().any(|t| t == tag));
+        original_len - handlers.len()
     }
 
     // Unchanged methods
     pub fn unbind(&mut self, id: String) -> anyhow::Result<()> {
-        let original_len = self.handlers.len();
-        self.handlers
-            .retain(|handler| handler.id.as_deref() != Some(&id));
-        if self.handlers.len() == original_len {
+        let mut handlers = self.handlers.write().unwrap();
+        let original_len = handlers.len();
+        handlers.retain(|handler| handler.id.as_deref() != Some(&id));
+        if handlers.len() == original_len {
             Err(anyhow::anyhow!("Handler with id {} not found", id))
         } else {
             Ok(())
@@ -63,19 +265,121 @@ impl<T: Send + 'static> Hook<T> {
     }
 
     pub fn length(&self) -> usize {
-        self.handlers.len()
+        self.handlers.read().unwrap().len()
+    }
+
+    /// Bound handlers in the order they run.
+    pub fn handlers(&self) -> Vec<HandlerInfo> {
+        self.handlers
+            .read()
+            .unwrap()
+            .iter()
+            .map(|handler| handler.info())
+            .collect()
+    }
+
+    /// Starts a background dispatcher that runs the handlers for every value sent through
+    /// the returned trigger, in order. At most `capacity` values are queued; see
+    /// [`HookTrigger`] for how emitting behaves when the queue is full. Handler errors
+    /// are logged.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside a tokio runtime, or if `capacity` is zero.
+    pub fn listen(&self, capacity: usize) -> HookTrigger<T>
+    where
+        T: Sync,
+    {
+        let (sender, mut receiver) = mpsc::channel::<T>(capacity);
+        let handlers = self.handlers.clone();
+
+        tokio::spawn(async move {
+            while let Some(value) = receiver.recv().await {
+                for result in run_handlers(&handlers, &value).await {
+                    if let Err(err) = result {
+                        tracing::warn!(error = %err, "hook handler failed");
+                    }
+                }
+            }
+        });
+
+        HookTrigger { sender }
     }
 
-    pub fn listen(&self) {
-        todo!("starts a tokio channel and return trigger")
+    /// Runs the matching handlers in order, returning one result per handler that ran.
+    pub async fn trigger(&self, value: &T) -> Vec<anyhow::Result<T>> {
+        run_handlers(&self.handlers, value).await
     }
+}
+
+async fn run_handlers<T>(handlers: &Handlers<T>, value: &T) -> Vec<anyhow::Result<T>> {
+    // Run a snapshot so handlers can be bound while the lock is not held across awaits.
+    let snapshot = handlers.read().unwrap().clone();
+    let mut errors = vec![];
+    let mut finished_once = false;
 
-    pub async fn trigger(&mut self, value: &T) -> Vec<anyhow::Result<T>> {
-        let mut errors = vec![];
-        for handler in &self.handlers {
-            errors.push((handler.func)(value).await);
+    for handler in &snapshot {
+        if handler.done.load(Ordering::Acquire)
+            || handler
+                .condition
+                .as_ref()
+                .is_some_and(|matches| !matches(value))
+        {
+            continue;
+        }
+        if handler.once
+            && handler
+                .running
+                .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+                .is_err()
+        {
+            continue;
         }
-        errors
+        // Another dispatch may have finished the handler before it was claimed.
+        if handler.once && handler.done.load(Ordering::Acquire) {
+            handler.running.store(false, Ordering::Release);
+            continue;
+        }
+
+        let span = tracing::debug_span!(
+            "hook_handler",
+            id = handler.id.as_deref(),
+            label = handler.label.as_deref(),
+            priority = handler.priority.unwrap_or(0),
+        );
+        let started = Instant::now();
+        let result = (handler.func)(value).instrument(span.clone()).await;
+        let elapsed = started.elapsed();
+
+        handler.stats.record(elapsed);
+        tracing::debug!(
+            parent: &span,
+            elapsed_us = elapsed.as_micros() as u64,
+            ok = result.is_ok(),
+            "hook handler finished"
+        );
+        if handler.once {
+            if result.is_ok() {
+                handler.done.store(true, Ordering::Release);
+                finished_once = true;
+            }
+            handler.running.store(false, Ordering::Release);
+        }
+        errors.push(result);
+    }
+
+    if finished_once {
+        handlers
+            .write()
+            .unwrap()
+            .retain(|handler| !handler.done.load(Ordering::Acquire));
+    }
+    errors
+}
+
+impl<T: Send + 'static> Default for Hook<T> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -124,41 +428,32 @@ mod tests {
         let order2 = order_ref.clone();
         let order3 = order_ref.clone();
         // Handler with priority 2
-        let handler1 = Handler {
-            func: Box::new(move |_| {
-                let order = order1.clone();
-                Box::pin(async move {
-                    order.lock().unwrap().push(2);
-                    Ok(2)
-                })
-            }),
-            id: None,
-            priority: Some(2),
-        };
+        let handler1 = Handler::new(move |_| {
+            let order = order1.clone();
+            Box::pin(async move {
+                order.lock().unwrap().push(2);
+                Ok(2)
+            })
+        })
+        .with_priority(2);
         // Handler with priority 1
-        let handler2 = Handler {
-            func: Box::new(move |_| {
-                let order = order2.clone();
-                Box::pin(async move {
-                    order.lock().unwrap().push(1);
-                    Ok(1)
-                })
-            }),
-            id: None,
-            priority: Some(1),
-        };
+        let handler2 = Handler::new(move |_| {
+            let order = order2.clone();
+            Box::pin(async move {
+                order.lock().unwrap().push(1);
+                Ok(1)
+            })
+        })
+        .with_priority(1);
         // Handler with priority 3
-        let handler3 = Handler {
-            func: Box::new(move |_| {
-                let order = order3.clone();
-                Box::pin(async move {
-                    order.lock().unwrap().push(3);
-                    Ok(3)
-                })
-            }),
-            id: None,
-            priority: Some(3),
-        };
+        let handler3 = Handler::new(move |_| {
+            let order = order3.clone();
+            Box::pin(async move {
+                order.lock().unwrap().push(3);
+                Ok(3)
+            })
+        })
+        .with_priority(3);
         hook.bind(handler1);
         hook.bind(handler2);
         hook.bind(handler3);
@@ -167,6 +462,140 @@ mod tests {
         assert_eq!(order, vec![1, 2, 3]);
     }
 
+    #[tokio::test]
+    async fn test_handler_metadata() {
+        let mut hook = Hook::new();
+        hook.bind(
+            Handler::new(|val: &i32| Box::pin(future::ready(Ok(*val))))
+                .with_id("audit")
+                .with_label("audit log")
+                .with_priority(5),
+        );
+        let id = hook.bind_fn(|val: &i32| Box::pin(future::ready(Ok(*val))));
+
+        let _ = hook.trigger(&1).await;
+        let _ = hook.trigger(&2).await;
+
+        let handlers = hook.handlers();
+        assert_eq!(handlers.len(), 2);
+        assert_eq!(handlers[0].id, id);
+        assert_eq!(handlers[0].label, None);
+        assert_eq!(handlers[1].id, "audit");
+        assert_eq!(handlers[1].label.as_deref(), Some("audit log"));
+        assert_eq!(handlers[1].priority, 5);
+        assert_eq!(handlers[1].calls, 2);
+        assert!(handlers[1].max_duration <= handlers[1].total_duration);
+    }
+
+    #[tokio::test]
+    async fn test_listen_dispatches_in_background() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut hook = Hook::new();
+        let trigger = hook.listen(4);
+
+        // Handlers bound after `listen` are picked up by the dispatcher.
+        let seen_clone = seen.clone();
+        hook.bind_fn(move |val: &i32| {
+            seen_clone.lock().unwrap().push(*val);
+            Box::pin(future::ready(Ok(*val)))
+        });
+
+        trigger.emit(1).await.unwrap();
+        let sync_trigger = trigger.clone();
+        tokio::task::spawn_blocking(move || sync_trigger.blocking_emit(2))
+            .await
+            .unwrap()
+            .unwrap();
+        trigger.try_emit(3).unwrap();
+
+        while seen.lock().unwrap().len() < 3 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(*seen.lock().unwrap(), vec![1, 2, 3]);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_trigger_backpressure() {
+        let hook = Hook::<i32>::new();
+        let trigger = hook.listen(1);
+
+        // The dispatcher cannot run before this task yields, so the queue fills up.
+        trigger.try_emit(1).unwrap();
+        assert_eq!(trigger.remaining_capacity(), 0);
+        assert_eq!(trigger.try_emit(2), Err(TriggerError::Full(2)));
+
+        trigger.emit(2).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bind_once() {
+        let attempts = Arc::new(Mutex::new(0));
+        let attempts_clone = attempts.clone();
+        let mut hook = Hook::new();
+        hook.bind_once(Handler::new(move |_val: &i32| {
+            let mut attempts = attempts_clone.lock().unwrap();
+            *attempts += 1;
+            // fails on the first attempt, succeeds on the second
+            let result = if *attempts == 1 {
+                Err(anyhow::anyhow!("not yet"))
+            } else {
+                Ok(*attempts)
+            };
+            Box::pin(future::ready(result))
+        }));
+
+        assert!(hook.trigger(&0).await[0].is_err());
+        assert_eq!(hook.length(), 1);
+        assert_eq!(hook.trigger(&0).await[0].as_ref().unwrap(), &2);
+        assert_eq!(hook.length(), 0);
+        assert!(hook.trigger(&0).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_bind_once_concurrent() {
+        let calls = Arc::new(Mutex::new(0));
+        let calls_clone = calls.clone();
+        let mut hook = Hook::new();
+        hook.bind_once(Handler::new(move |val: &i32| {
+            *calls_clone.lock().unwrap() += 1;
+            let val = *val;
+            Box::pin(async move {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                Ok(val)
+            })
+        }));
+
+        let (first, second) = tokio::join!(hook.trigger(&1), hook.trigger(&2));
+        assert_eq!(first.len() + second.len(), 1);
+        assert_eq!(*calls.lock().unwrap(), 1);
+        assert_eq!(hook.length(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_bind_if() {
+        let mut hook = Hook::new();
+        hook.bind_if(
+            |val: &i32| *val % 2 == 0,
+            Handler::new(|val: &i32| Box::pin(future::ready(Ok(*val)))),
+        );
+
+        assert_eq!(hook.trigger(&1).await.len(), 0);
+        assert_eq!(hook.trigger(&2).await.len(), 1);
+        assert_eq!(hook.handlers()[0].calls, 1);
+    }
+
+    #[tokio::test]
+    async fn test_unbind_tag() {
+        let mut hook = Hook::new();
+        for tag in ["plugin:a", "plugin:a", "plugin:b"] {
+            hook.bind(Handler::new(|val: &i32| Box::pin(future::ready(Ok(*val)))).with_tag(tag));
+        }
+
+        assert_eq!(hook.unbind_tag("plugin:a"), 2);
+        assert_eq!(hook.unbind_tag("plugin:a"), 0);
+        assert_eq!(hook.handlers()[0].tags, vec!["plugin:b".to_string()]);
+    }
+
     #[tokio::test]
     async fn test_unbind_nonexistent() {
         let mut hook = Hook::<i32>::new();
@@ -0,0 +1,412 @@
+//! # Localization
+//!
+//! A [`Catalog`] holds translated messages by locale: API error strings and the email
+//! templates of [`crate::mailer`]. English messages are built in; other locales are
+//! added with [`Catalog::with_messages`] or loaded from `{locale}.json` files with
+//! [`Catalog::load_dir`], each a flat object of message keys to templates. Templates
+//! refer to arguments as `{name}`.
+//!
+//! A message is looked up along a fallback chain: each requested locale, then its
+//! language without the region (`pt-BR`, then `pt`), then the catalog's default locale,
+//! then the built-in English messages, then the key itself.
+//!
+//! The [`Locales`] extractor reads the preferred locales of a request: a
+//! [`PreferredLocale`] extension first, e.g. set by an interceptor from the user's
+//! profile, then the `Accept-Language` header. The [`localize_errors`] middleware uses it
+//! to give error responses without a body a localized JSON message.
+//!
+//! # Example
+//!
+//! ```rust
+//! use palmera_core::i18n::Catalog;
+//!
+//! let catalog = Catalog::new().with_messages(
+//!     "pt",
+//!     [("error.404", "Não encontrado"), ("greeting", "Olá, {name}")],
+//! );
+//!
+//! assert_eq!(catalog.translate(&["pt-BR"], "error.404", &[]), "Não encontrado");
+//! assert_eq!(catalog.translate(&["pt"], "greeting", &[("name", "Ana")]), "Olá, Ana");
+//! assert_eq!(catalog.translate(&["de"], "error.404", &[]), "Not found");
+//! ```
+
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+use axum::{
+    Json,
+    extract::{FromRequestParts, Request, State},
+    http::{HeaderMap, header, request::Parts},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+/// Locale messages fall back to when no requested locale has them.
+pub const DEFAULT_LOCALE: &str = "en";
+
+const ENGLISH: &[(&str, &str)] = &[
+    ("error.400", "The request is invalid."),
+    ("error.401", "Authentication is required."),
+    ("error.403", "You don't have permission to do this."),
+    ("error.404", "Not found"),
+    ("error.405", "This method is not allowed here."),
+    ("error.406", "None of the accepted formats can be produced."),
+    (
+        "error.409",
+        "This conflicts with the current state of the resource.",
+    ),
+    ("error.413", "The request is too large."),
+    ("error.422", "The request could not be processed."),
+    ("error.429", "Too many requests. Try again later."),
+    ("error.500", "Something went wrong on our side."),
+    ("error.503", "The service is temporarily unavailable."),
+    (
+        "mail.suspicious_login.subject",
+        "New sign-in to your account",
+    ),
+    (
+        "mail.suspicious_login.body",
+        "We noticed a new sign-in to your account ({reasons}).\n\n\
+         Time: {time}\n\
+         IP address: {ip}\n\
+         Device: {device}\n\n\
+         If this was you, you can ignore this email. Otherwise, change your password \
+         right away.\n",
+    ),
+    ("mail.unknown", "unknown"),
+    ("mail.verify_email.subject", "Confirm your email address"),
+    (
+        "mail.verify_email.body",
+        "Follow this link to confirm your email address:\n\n{link}\n",
+    ),
+    ("mail.test.subject", "Test email"),
+    (
+        "mail.test.body",
+        "This is a test email. If you can read it, sending email works.\n",
+    ),
+];
+
+/// Translated messages by locale.
+#[derive(Debug, Clone)]
+pub struct Catalog {
+    default_locale: String,
+    messages: HashMap<String, HashMap<String, String>>,
+}
+
+impl Catalog {
+    /// A catalog with the built-in English messages and [`DEFAULT_LOCALE`] as fallback.
+    pub fn new() -> Self {
+        Self {
+            default_locale: DEFAULT_LOCALE.to_string(),
+            messages: HashMap::new(),
+        }
+        .with_messages(DEFAULT_LOCALE, ENGLISH.iter().copied())
+    }
+
+    /// Falls back to `locale` instead of [`DEFAULT_LOCALE`].
+    pub fn with_default_locale(mut self, locale: &str) -> Self {
+        self.default_locale = normalize(locale);
+        self
+    }
+
+    /// Adds or replaces messages of `locale`.
+    pub fn with_messages<I, K, V>(mut self, locale: &str, messages: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.messages.entry(normalize(locale)).or_default().extend(
+            messages
+                .into_iter()
+                .map(|(key, value)| (key.into(), value.into())),
+        );
+        self
+    }
+
+    /// Adds the messages of every `{locale}.json` file in `dir`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the directory can't be read or a file isn't a flat JSON object of
+    /// strings.
+    pub fn load_dir(mut self, dir: impl AsRef<Path>) -> anyhow::Result<Self> {
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            let Some(locale) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+
+            let messages: HashMap<String, String> = serde_json::from_slice(&std::fs::read(&path)?)
+                .map_err(|err| anyhow::anyhow!("{}: {}", path.display(), err))?;
+            self = self.with_messages(locale, messages);
+        }
+        Ok(self)
+    }
+
+    pub fn default_locale(&self) -> &str {
+        &self.default_locale
+    }
+
+    /// Returns the template of `key` for the first of `locales` that has it, following
+    /// the fallback chain described in the [module docs](self).
+    pub fn message(&self, locales: &[impl AsRef<str>], key: &str) -> Option<&str> {
+        locales
+            .iter()
+            .flat_map(|locale| fallbacks(locale.as_ref()))
+            .chain([self.default_locale.clone(), DEFAULT_LOCALE.to_string()])
+            .find_map(|locale| self.messages.get(&locale)?.get(key))
+            .map(String::as_str)
+    }
+
+    /// Translates `key` for `locales` and fills in `args`. Unknown keys translate to
+    /// themselves.
+    pub fn translate(
+        &self,
+        locales: &[impl AsRef<str>],
+        key: &str,
+        args: &[(&str, &str)],
+    ) -> String {
+        let template = self.message(locales, key).unwrap_or(key);
+        args.iter()
+            .fold(template.to_string(), |message, (name, value)| {
+                message.replace(&format!("{{{}}}", name), value)
+            })
+    }
+}
+
+impl Default for Catalog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Lowercases a language tag and normalizes `_` to `-`, so `pt_BR` and `pt-br` match.
+fn normalize(locale: &str) -> String {
+    locale.trim().replace('_', "-").to_ascii_lowercase()
+}
+
+/// `locale` followed by its shorter prefixes: `zh-hant-tw`, `zh-hant`, `zh`.
+fn fallbacks(locale: &str) -> Vec<String> {
+    let locale = normalize(locale);
+    let mut chain = vec![locale.clone()];
+    let mut rest = locale.as_str();
+    while let Some((prefix, _)) = rest.rsplit_once('-') {
+        chain.push(prefix.to_string());
+        rest = prefix;
+    }
+    chain
+}
+
+/// Locales listed in an `Accept-Language` header, most preferred first. Wildcards and
+/// locales with `q=0` are left out.
+pub fn accept_language(headers: &HeaderMap) -> Vec<String> {
+    let mut locales: Vec<(String, f32)> = headers
+        .get_all(header::ACCEPT_LANGUAGE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|entry| {
+            let mut parts = entry.split(';');
+            let locale = parts.next()?.trim();
+            let quality = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+            (!locale.is_empty() && locale != "*" && quality > 0.0)
+                .then(|| (normalize(locale), quality))
+        })
+        .collect();
+
+    // Stable, so equally weighted locales keep their order.
+    locales.sort_by(|a, b| b.1.total_cmp(&a.1));
+    locales.into_iter().map(|(locale, _)| locale).collect()
+}
+
+/// A user's preferred locale, e.g. from their profile. Takes precedence over
+/// `Accept-Language` when present in the request extensions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreferredLocale(pub String);
+
+/// The preferred locales of a request, most preferred first; see the
+/// [module docs](self).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Locales(pub Vec<String>);
+
+impl Locales {
+    fn from_parts(parts: &Parts) -> Self {
+        let preferred = parts
+            .extensions
+            .get::<PreferredLocale>()
+            .map(|PreferredLocale(locale)| normalize(locale));
+        Self(
+            preferred
+                .into_iter()
+                .chain(accept_language(&parts.headers))
+                .collect(),
+        )
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Locales {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self::from_parts(parts))
+    }
+}
+
+/// Axum middleware giving error responses without a body a localized JSON body:
+/// `{"error": {"status": 404, "message": "Not found"}}`. Responses that already have a
+/// body are left alone.
+pub async fn localize_errors(
+    State(catalog): State<Arc<Catalog>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let (parts, body) = request.into_parts();
+    let locales = Locales::from_parts(&parts);
+    let response = next.run(Request::from_parts(parts, body)).await;
+
+    let status = response.status();
+    let is_empty = response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .is_none_or(|length| length == "0")
+        && response.headers().get(header::CONTENT_TYPE).is_none();
+    if !(status.is_client_error() || status.is_server_error()) || !is_empty {
+        return response;
+    }
+
+    let key = format!("error.{}", status.as_u16());
+    let message = match catalog.message(&locales.0, &key) {
+        Some(message) => message.to_string(),
+        None => status.canonical_reason().unwrap_or_default().to_string(),
+    };
+
+    let (mut parts, _) = response.into_parts();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    let body = Json(serde_json::json!({
+        "error": { "status": status.as_u16(), "message": message }
+    }));
+    (parts, body).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Router, body::Body, http::StatusCode, middleware, routing::get};
+    use tower::ServiceExt;
+
+    #[test]
+    fn test_fallback_chain() {
+        let catalog = Catalog::new()
+            .with_messages("pt", [("error.404", "Não encontrado")])
+            .with_messages("pt_BR", [("error.403", "Sem permissão")]);
+
+        assert_eq!(
+            catalog.translate(&["pt-br"], "error.403", &[]),
+            "Sem permissão"
+        );
+        assert_eq!(
+            catalog.translate(&["pt-BR"], "error.404", &[]),
+            "Não encontrado"
+        );
+        assert_eq!(
+            catalog.translate(&["de", "pt"], "error.404", &[]),
+            "Não encontrado"
+        );
+        assert_eq!(catalog.translate(&["de"], "error.404", &[]), "Not found");
+        assert_eq!(
+            catalog.translate(&["de"], "missing.key", &[]),
+            "missing.key"
+        );
+
+        let catalog = catalog.with_default_locale("pt");
+        assert_eq!(
+            catalog.translate(&["de"], "error.404", &[]),
+            "Não encontrado"
+        );
+        assert_eq!(
+            catalog.translate(&["de"], "error.401", &[]),
+            "Authentication is required."
+        );
+    }
+
+    #[test]
+    fn test_accept_language() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::ACCEPT_LANGUAGE,
+            "fr-CH, fr;q=0.9, en;q=0.8, de;q=0, *;q=0.5"
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(accept_language(&headers), ["fr-ch", "fr", "en"]);
+        assert!(accept_language(&HeaderMap::new()).is_empty());
+    }
+
+    #[test]
+    fn test_load_dir() {
+        let dir = std::env::temp_dir().join(format!("palmera-i18n-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("es.json"), r#"{"error.404": "No encontrado"}"#).unwrap();
+        std::fs::write(dir.join("README.md"), "not a catalog").unwrap();
+
+        let catalog = Catalog::new().load_dir(&dir).unwrap();
+        assert_eq!(
+            catalog.translate(&["es-MX"], "error.404", &[]),
+            "No encontrado"
+        );
+
+        std::fs::write(dir.join("it.json"), "[]").unwrap();
+        assert!(Catalog::new().load_dir(&dir).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_localize_errors() {
+        let catalog =
+            Arc::new(Catalog::new().with_messages("es", [("error.404", "No encontrado")]));
+        let app = Router::new()
+            .route("/missing", get(|| async { StatusCode::NOT_FOUND }))
+            .route(
+                "/explained",
+                get(|| async { (StatusCode::BAD_REQUEST, "title is required") }),
+            )
+            .layer(middleware::from_fn_with_state(catalog, localize_errors));
+
+        let call = |uri: &str, language: Option<&str>, preferred: Option<&str>| {
+            let mut request = Request::get(uri);
+            if let Some(language) = language {
+                request = request.header(header::ACCEPT_LANGUAGE, language);
+            }
+            if let Some(locale) = preferred {
+                request = request.extension(PreferredLocale(locale.to_string()));
+            }
+            let request = request.body(Body::empty()).unwrap();
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                String::from_utf8(body.to_vec()).unwrap()
+            }
+        };
+
+        assert_eq!(
+            call("/missing", Some("es-ES,en;q=0.5"), None).await,
+            r#"{"error":{"message":"No encontrado","status":404}}"#
+        );
+        assert_eq!(
+            call("/missing", Some("es"), Some("en")).await,
+            r#"{"error":{"message":"Not found","status":404}}"#
+        );
+        assert_eq!(
+            call("/explained", Some("es"), None).await,
+            "title is required"
+        );
+    }
+}
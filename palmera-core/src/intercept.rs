@@ -0,0 +1,189 @@
+//! # Request interceptors
+//!
+//! Interceptors registered with [`App::intercept`](crate::base::App::intercept) wrap
+//! every request the app serves, so applications can implement custom throttling,
+//! tenant mapping or header rewriting without writing tower layers.
+//!
+//! They run inside Palmera's context: after authentication, so
+//! [`RequestContext::claims`] holds the caller's verified token, and before the route
+//! handlers evaluate their policies. [`RequestContext::subject`] and
+//! [`RequestContext::actor`] tell apart the user a request acts for and the party making
+//! it, e.g. an impersonating admin. Authentication failures are not rejected here; a
+//! request without valid credentials simply has no claims. Interceptors run in
+//! registration order; each either answers the request itself or passes it on with
+//! [`Next::run`].
+//!
+//! # Example
+//!
+//! ```rust
+//! use axum::{http::StatusCode, response::IntoResponse};
+//! use palmera_core::base::App;
+//!
+//! let mut app = App::new();
+//! app.intercept(|mut ctx, next| async move {
+//!     let Some(tenant) = ctx.header("x-tenant").map(str::to_string) else {
+//!         return StatusCode::BAD_REQUEST.into_response();
+//!     };
+//!     ctx.insert(tenant);
+//!     next.run(ctx).await
+//! });
+//! ```
+
+use std::{future::Future, pin::Pin, sync::Arc};
+
+use axum::{
+    extract::{FromRequestParts, Request},
+    http::{HeaderMap, Method, Uri},
+    middleware,
+    response::Response,
+};
+use palmera_auth::{AuthConfig, extract::AuthClaims, jwt::JWTClaims};
+use uuid::Uuid;
+
+use crate::request_id::RequestId;
+
+type InterceptorFuture = Pin<Box<dyn Future<Output = Response> + Send>>;
+
+/// A registered interceptor.
+pub(crate) type Interceptor = Arc<dyn Fn(RequestContext, Next) -> InterceptorFuture + Send + Sync>;
+
+/// Boxes `interceptor` for [`App::intercept`](crate::base::App::intercept).
+pub(crate) fn interceptor<F, Fut>(interceptor: F) -> Interceptor
+where
+    F: Fn(RequestContext, Next) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Response> + Send + 'static,
+{
+    Arc::new(move |ctx, next| Box::pin(interceptor(ctx, next)))
+}
+
+/// A request passing through the interceptors.
+#[derive(Debug)]
+pub struct RequestContext {
+    request: Request,
+    claims: Option<JWTClaims>,
+}
+
+impl RequestContext {
+    /// Verified claims of the caller's token, if the request carried a valid one.
+    pub fn claims(&self) -> Option<&JWTClaims> {
+        self.claims.as_ref()
+    }
+
+    /// Id of the user the request acts for, whose rights and policies apply.
+    pub fn subject(&self) -> Option<Uuid> {
+        self.claims.as_ref().map(|claims| claims.subject)
+    }
+
+    /// Id of the party making the request. It differs from [`Self::subject`] when an
+    /// admin impersonates a user or an API key acts on a user's behalf, see
+    /// [`JWTClaims::actor_id`].
+    pub fn actor(&self) -> Option<Uuid> {
+        self.claims.as_ref().map(JWTClaims::actor_id)
+    }
+
+    /// Id of the request, see [`crate::request_id`].
+    pub fn request_id(&self) -> Option<&RequestId> {
+        self.get()
+    }
+
+    pub fn method(&self) -> &Method {
+        self.request.method()
+    }
+
+    pub fn uri(&self) -> &Uri {
+        self.request.uri()
+    }
+
+    pub fn headers(&self) -> &HeaderMap {
+        self.request.headers()
+    }
+
+    /// Headers of the request, e.g. to add or rewrite them before the handler runs.
+    pub fn headers_mut(&mut self) -> &mut HeaderMap {
+        self.request.headers_mut()
+    }
+
+    /// Value of the header `name`, if it is present and valid UTF-8.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    }
+
+    /// Returns the request extension of type `T`.
+    pub fn get<T: Clone + Send + Sync + 'static>(&self) -> Option<&T> {
+        self.request.extensions().get()
+    }
+
+    /// Adds a request extension, which handlers read with axum's `Extension` extractor.
+    pub fn insert<T: Clone + Send + Sync + 'static>(&mut self, value: T) {
+        self.request.extensions_mut().insert(value);
+    }
+
+    /// The underlying request, for anything the accessors above don't cover.
+    pub fn request_mut(&mut self) -> &mut Request {
+        &mut self.request
+    }
+}
+
+/// The rest of the interceptor chain and the route handler.
+pub struct Next {
+    interceptors: Arc<[Interceptor]>,
+    index: usize,
+    inner: middleware::Next,
+}
+
+impl std::fmt::Debug for Next {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Next")
+            .field("remaining", &(self.interceptors.len() - self.index))
+            .finish_non_exhaustive()
+    }
+}
+
+impl Next {
+    /// Passes the request to the next interceptor, or to the route once all ran.
+    pub async fn run(self, ctx: RequestContext) -> Response {
+        match self.interceptors.get(self.index).cloned() {
+            Some(interceptor) => {
+                let next = Next {
+                    index: self.index + 1,
+                    ..self
+                };
+                interceptor(ctx, next).await
+            }
+            None => self.inner.run(ctx.request).await,
+        }
+    }
+}
+
+/// Axum middleware running `interceptors` in order. Claims of a valid bearer token are
+/// verified with the [`AuthConfig`] extension, if present, and kept as [`AuthClaims`]
+/// so handlers don't verify the token again.
+pub(crate) async fn run(
+    interceptors: Arc<[Interceptor]>,
+    request: Request,
+    inner: middleware::Next,
+) -> Response {
+    let (mut parts, body) = request.into_parts();
+
+    let claims = if parts.extensions.get::<AuthConfig>().is_some() {
+        AuthClaims::from_request_parts(&mut parts, &()).await.ok()
+    } else {
+        parts.extensions.get::<AuthClaims>().cloned()
+    };
+    if let Some(claims) = &claims {
+        parts.extensions.insert(claims.clone());
+    }
+
+    let ctx = RequestContext {
+        request: Request::from_parts(parts, body),
+        claims: claims.map(|AuthClaims(claims)| claims),
+    };
+    let next = Next {
+        interceptors,
+        index: 0,
+        inner,
+    };
+    next.run(ctx).await
+}
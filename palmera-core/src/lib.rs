@@ -1,4 +1,20 @@
+#[cfg(any(feature = "aws", feature = "ses"))]
+mod aws;
 pub mod base;
+pub mod builder;
 pub mod errors;
 pub mod events;
 pub mod hook;
+pub mod i18n;
+pub mod intercept;
+pub mod mail_preview;
+pub mod mailer;
+pub mod meta;
+pub mod notifications;
+pub mod plugin;
+pub mod ratelimit;
+pub mod realtime;
+pub mod request_id;
+pub mod scheduler;
+pub mod secrets;
+pub mod stats;
@@ -0,0 +1,419 @@
+//! # Mail previews
+//!
+//! Admin routes for checking emails before users receive them:
+//!
+//! - `GET /admin/mail/templates` lists the templates that can be previewed.
+//! - `GET /admin/mail/preview?template=verify_email&locale=de` renders a template with
+//!   sample data, without sending anything. The locale defaults to the mailer's.
+//! - `POST /admin/mail/test` sends a template, the `test` one unless the body names
+//!   another, to an address through the mailer's transport, so operators can verify the
+//!   SMTP or API settings. Transport failures are answered with `502 Bad Gateway` and
+//!   the transport's error.
+//!
+//! The built-in templates are rendered with sample data by the same functions that
+//! build the real emails, such as [`suspicious_login_text`] and [`verification_text`], in
+//! the mailer's [`Catalog`](crate::i18n::Catalog) with the same fallback chain. Apps
+//! register their own with [`MailPreview::with_render`], or with
+//! [`MailPreview::with_template`] for the pair of `mail.{template}.subject` and
+//! `mail.{template}.body` catalog messages filled in with sample arguments.
+//!
+//! Like the other admin routers, [`MailPreview::router`] does no authorization of its own
+//! and is expected to be mounted behind the application's admin guard.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! use palmera_core::{
+//!     mail_preview::MailPreview,
+//!     mailer::{MailConfig, Mailer},
+//! };
+//!
+//! # fn run(config: MailConfig) -> anyhow::Result<()> {
+//! let mailer = Mailer::new(config.transport()?, "Palmera <no-reply@example.com>".parse()?);
+//! let preview = MailPreview::new(mailer)
+//!     .with_template("invite", &[("team", "Acme"), ("link", "https://example.com/join")]);
+//!
+//! let (router, _api) = preview.router().split_for_parts();
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use axum::{
+    Extension, Json,
+    extract::Query,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use chrono::{TimeZone, Utc};
+use lettre::{Message, message::Mailbox};
+use palmera_auth::{
+    security::{SecurityEvent, SecurityEventKind, SuspiciousLogin},
+    verification::{VERIFICATION_LINK_PATH, VerificationEmail},
+};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use utoipa_axum::{router::OpenApiRouter, routes};
+use uuid::Uuid;
+
+use crate::{
+    events::SuspiciousLoginEvent,
+    i18n::Catalog,
+    mailer::{MailText, Mailer, suspicious_login_text, verification_text},
+};
+
+/// Template sent by `POST /admin/mail/test` when the request names none.
+pub const TEST_TEMPLATE: &str = "test";
+
+/// A template rendered in one locale.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+pub struct RenderedMail {
+    pub template: String,
+    pub locale: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Renders a template with sample data in a locale.
+type Render = Arc<dyn Fn(&Catalog, &str) -> MailText + Send + Sync>;
+
+/// Renders and test-sends the templates of a [`Mailer`]; see the [module docs](self).
+#[derive(Clone)]
+pub struct MailPreview {
+    mailer: Mailer,
+    templates: BTreeMap<String, Render>,
+}
+
+impl std::fmt::Debug for MailPreview {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MailPreview")
+            .field("templates", &self.templates.keys().collect::<Vec<_>>())
+            .finish_non_exhaustive()
+    }
+}
+
+impl MailPreview {
+    /// Previews of the built-in templates, sent with `mailer`. Their sample links are
+    /// built by [`Mailer::url`].
+    pub fn new(mailer: Mailer) -> Self {
+        let mut login = SecurityEvent::new(SecurityEventKind::Login, true);
+        login.email = Some("user@example.com".to_string());
+        login.ip = Some("198.51.100.1".to_string());
+        login.user_agent = Some("Firefox on Linux".to_string());
+        login.created = Utc.with_ymd_and_hms(2025, 1, 1, 9, 30, 0).unwrap();
+        let suspicious_login = SuspiciousLoginEvent::new(SuspiciousLogin {
+            event: login,
+            reasons: vec!["new IP address".to_string()],
+        });
+        let verification = VerificationEmail {
+            user_id: Uuid::nil(),
+            email: "user@example.com".to_string(),
+            token: "sample".to_string(),
+            link: mailer.url(&format!("{}?token=sample", VERIFICATION_LINK_PATH)),
+        };
+
+        Self {
+            mailer,
+            templates: BTreeMap::new(),
+        }
+        .with_render("suspicious_login", move |catalog, locale| {
+            suspicious_login_text(catalog, locale, &suspicious_login)
+        })
+        .with_render("verify_email", move |catalog, locale| {
+            verification_text(catalog, locale, &verification)
+        })
+        .with_template(TEST_TEMPLATE, &[])
+    }
+
+    /// Adds the template `name`, rendered by `render` from the catalog and locale, e.g.
+    /// with the function building the app's real messages and sample data. A template
+    /// registered again replaces the earlier one.
+    pub fn with_render<F>(mut self, name: &str, render: F) -> Self
+    where
+        F: Fn(&Catalog, &str) -> MailText + Send + Sync + 'static,
+    {
+        self.templates.insert(name.to_string(), Arc::new(render));
+        self
+    }
+
+    /// Adds the template `name`, rendered from its catalog messages with the `sample`
+    /// arguments. A template registered again replaces the earlier one.
+    pub fn with_template(self, name: &str, sample: &[(&str, &str)]) -> Self {
+        let template = name.to_string();
+        let sample: Vec<(String, String)> = sample
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        self.with_render(name, move |catalog, locale| {
+            let args: Vec<_> = sample
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str()))
+                .collect();
+            MailText::render(catalog, locale, &template, &args)
+        })
+    }
+
+    /// Names of the registered templates, in alphabetical order.
+    pub fn templates(&self) -> impl Iterator<Item = &str> {
+        self.templates.keys().map(String::as_str)
+    }
+
+    /// Renders `template` with its sample data in `locale`, or the mailer's locale, or
+    /// returns `None` if the template isn't registered.
+    pub fn render(&self, template: &str, locale: Option<&str>) -> Option<RenderedMail> {
+        let render = self.templates.get(template)?;
+        let locale = locale.unwrap_or(self.mailer.locale());
+        let text = render(self.mailer.catalog(), locale);
+
+        Some(RenderedMail {
+            template: template.to_string(),
+            locale: locale.to_string(),
+            subject: text.subject,
+            body: text.body,
+        })
+    }
+
+    /// Builds the email of `rendered` to `to`, from the mailer's sender.
+    pub fn message(&self, rendered: &RenderedMail, to: Mailbox) -> anyhow::Result<Message> {
+        MailText {
+            subject: rendered.subject.clone(),
+            body: rendered.body.clone(),
+        }
+        .message(self.mailer.from(), to)
+    }
+
+    /// Router serving the routes of the [module docs](self).
+    pub fn router(self) -> OpenApiRouter {
+        OpenApiRouter::new()
+            .routes(routes!(list_templates))
+            .routes(routes!(preview))
+            .routes(routes!(send_test))
+            .layer(Extension(Arc::new(self)))
+    }
+}
+
+/// Lists the templates that can be previewed.
+#[utoipa::path(
+    get,
+    path = "/admin/mail/templates",
+    responses((status = 200, body = Vec<String>))
+)]
+async fn list_templates(Extension(preview): Extension<Arc<MailPreview>>) -> Json<Vec<String>> {
+    Json(preview.templates().map(str::to_string).collect())
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+struct PreviewQuery {
+    template: String,
+    /// Defaults to the mailer's locale.
+    locale: Option<String>,
+}
+
+/// Renders a template with sample data, without sending anything.
+#[utoipa::path(
+    get,
+    path = "/admin/mail/preview",
+    params(PreviewQuery),
+    responses((status = 200, body = RenderedMail), (status = 404))
+)]
+async fn preview(
+    Extension(preview): Extension<Arc<MailPreview>>,
+    Query(query): Query<PreviewQuery>,
+) -> Result<Json<RenderedMail>, StatusCode> {
+    preview
+        .render(&query.template, query.locale.as_deref())
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Body of `POST /admin/mail/test`.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+pub struct TestMail {
+    /// Address to send to.
+    pub to: String,
+    #[serde(default)]
+    pub template: Option<String>,
+    #[serde(default)]
+    pub locale: Option<String>,
+}
+
+/// Sends a template to an address through the mailer's transport.
+#[utoipa::path(
+    post,
+    path = "/admin/mail/test",
+    request_body = TestMail,
+    responses(
+        (status = 200, body = RenderedMail),
+        (status = 400),
+        (status = 404),
+        (status = 502, description = "The transport failed to send the email")
+    )
+)]
+async fn send_test(
+    Extension(preview): Extension<Arc<MailPreview>>,
+    Json(request): Json<TestMail>,
+) -> Result<Json<RenderedMail>, Response> {
+    let template = request.template.as_deref().unwrap_or(TEST_TEMPLATE);
+    let rendered = preview
+        .render(template, request.locale.as_deref())
+        .ok_or_else(|| StatusCode::NOT_FOUND.into_response())?;
+    let to: Mailbox = request.to.parse().map_err(|err| {
+        (StatusCode::BAD_REQUEST, format!("invalid address: {err}")).into_response()
+    })?;
+    let message = preview
+        .message(&rendered, to)
+        .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()).into_response())?;
+
+    if let Err(err) = preview.mailer.send(message).await {
+        tracing::warn!(template, "failed to send test email: {err:#}");
+        return Err((StatusCode::BAD_GATEWAY, err.to_string()).into_response());
+    }
+    Ok(Json(rendered))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mailer::{MailTransport, SendFuture};
+    use axum::{Router, body::Body, extract::Request};
+    use palmera_auth::urls::UrlBuilder;
+    use serde_json::{Value, json};
+    use std::sync::Mutex;
+    use tower::ServiceExt;
+
+    #[derive(Default)]
+    struct Outbox {
+        sent: Mutex<Vec<Message>>,
+        fail: bool,
+    }
+
+    impl MailTransport for Outbox {
+        fn name(&self) -> &str {
+            "outbox"
+        }
+
+        fn send<'a>(&'a self, message: &'a Message) -> SendFuture<'a> {
+            Box::pin(async move {
+                anyhow::ensure!(!self.fail, "connection refused");
+                self.sent.lock().unwrap().push(message.clone());
+                Ok(())
+            })
+        }
+    }
+
+    fn preview(outbox: Arc<Outbox>) -> MailPreview {
+        let catalog = Catalog::new().with_messages(
+            "de",
+            [("mail.verify_email.subject", "E-Mail-Adresse bestätigen")],
+        );
+        let mailer = Mailer::new(outbox, "no-reply@example.com".parse().unwrap())
+            .with_catalog(Arc::new(catalog))
+            .with_urls(UrlBuilder::new("https://example.com").unwrap());
+        MailPreview::new(mailer).with_template("invite", &[("team", "Acme")])
+    }
+
+    async fn call(app: Router, request: Request) -> (StatusCode, Value) {
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn test_preview() {
+        let outbox = Arc::new(Outbox::default());
+        let (app, _) = preview(outbox.clone()).router().split_for_parts();
+        let get = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+
+        let (status, templates) = call(app.clone(), get("/admin/mail/templates")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            templates,
+            json!(["invite", "suspicious_login", "test", "verify_email"])
+        );
+
+        let (status, rendered) = call(
+            app.clone(),
+            get("/admin/mail/preview?template=verify_email&locale=de"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(rendered["locale"], "de");
+        assert_eq!(rendered["subject"], "E-Mail-Adresse bestätigen");
+        // The body falls back to English, with the sample link filled in.
+        let body = rendered["body"].as_str().unwrap();
+        assert!(body.contains("https://example.com/verify-email?token=sample"));
+
+        // Built-in templates render like the real messages.
+        let (_, rendered) = call(
+            app.clone(),
+            get("/admin/mail/preview?template=suspicious_login"),
+        )
+        .await;
+        let sample = MailPreview::new(Mailer::new(
+            outbox.clone(),
+            "no-reply@example.com".parse().unwrap(),
+        ));
+        let expected = sample.render("suspicious_login", None).unwrap();
+        assert_eq!(rendered["body"], expected.body);
+        assert!(expected.body.contains("Time: 2025-01-01 09:30 UTC"));
+        assert!(expected.body.contains("Device: Firefox on Linux"));
+
+        let (_, rendered) = call(app.clone(), get("/admin/mail/preview?template=invite")).await;
+        assert_eq!(rendered["locale"], "en");
+        assert_eq!(rendered["subject"], "mail.invite.subject");
+
+        let (status, _) = call(app, get("/admin/mail/preview?template=missing")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(outbox.sent.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_send_test_email() {
+        let post = |body: Value| {
+            Request::post("/admin/mail/test")
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        let outbox = Arc::new(Outbox::default());
+        let (app, _) = preview(outbox.clone()).router().split_for_parts();
+        let (status, rendered) = call(app.clone(), post(json!({ "to": "ops@example.com" }))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(rendered["template"], TEST_TEMPLATE);
+        let raw = String::from_utf8(outbox.sent.lock().unwrap()[0].formatted()).unwrap();
+        assert!(raw.contains("To: ops@example.com"));
+        assert!(raw.contains("Subject: Test email"));
+
+        let (status, _) = call(
+            app.clone(),
+            post(json!({ "to": "ops@example.com", "template": "missing" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = call(app, post(json!({ "to": "not an address" }))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(outbox.sent.lock().unwrap().len(), 1);
+
+        let failing = Arc::new(Outbox {
+            fail: true,
+            ..Default::default()
+        });
+        let (app, _) = preview(failing).router().split_for_parts();
+        let response = app
+            .oneshot(post(
+                json!({ "to": "ops@example.com", "template": "verify_email" }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("connection refused"));
+    }
+}
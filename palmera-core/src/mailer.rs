@@ -0,0 +1,1076 @@
+//! # Mailer
+//!
+//! [`Mailer`] sends email from a fixed sender address through a [`MailTransport`]:
+//!
+//! - [`SmtpTransport`] talks SMTP. Sending blocks on the SMTP conversation, so it runs on
+//!   tokio's blocking thread pool.
+//! - `SendGridTransport` uses the SendGrid v3 API (feature `sendgrid`).
+//! - `MailgunTransport` uses the Mailgun API (feature `mailgun`).
+//! - `SesTransport` uses the Amazon SES v2 API (feature `ses`).
+//!
+//! The HTTP APIs suit deployments that can't reach an SMTP server. The transport is
+//! chosen by a [`MailConfig`], typically deserialized from the app's configuration:
+//!
+//! ```json
+//! { "transport": "smtp", "host": "smtp.example.com", "username": "palmera", "password": "..." }
+//! { "transport": "sendgrid", "api_key": "SG...." }
+//! { "transport": "mailgun", "domain": "mg.example.com", "api_key": "...", "eu": true }
+//! { "transport": "ses", "region": "eu-west-1", "access_key": "...", "secret_key": "..." }
+//! ```
+//!
+//! The module also provides the default handlers that notify users by email, such as
+//! [`suspicious_login_notifier`] for
+//! [`App::on_suspicious_login`](crate::base::App::on_suspicious_login) and
+//! [`verification_sender`] for the verification links of
+//! [`palmera_auth::verification`]. Their messages are rendered from the mailer's
+//! [`Catalog`] by functions such as [`suspicious_login_text`], which
+//! [`crate::mail_preview`] renders too; see [`crate::i18n`]. Each message is written in
+//! its recipient's locale, as looked up by [`Mailer::with_recipient_locale`], e.g. from
+//! their profile, falling back to the mailer's locale, English by default.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! use palmera_core::{
+//!     base::App,
+//!     mailer::{MailConfig, Mailer, suspicious_login_notifier},
+//! };
+//!
+//! # fn run(config: &str) -> anyhow::Result<()> {
+//! let config: MailConfig = serde_json::from_str(config)?;
+//! let mailer = Mailer::new(config.transport()?, "Palmera <no-reply@example.com>".parse()?);
+//!
+//! let mut app = App::new();
+//! app.on_suspicious_login.bind(suspicious_login_notifier(mailer));
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{future::Future, pin::Pin, sync::Arc};
+
+use anyhow::anyhow;
+use lettre::{
+    Message, SmtpTransport, Transport, message::Mailbox,
+    transport::smtp::authentication::Credentials,
+};
+use palmera_auth::{
+    urls::UrlBuilder,
+    verification::{VerificationEmail, VerificationSender},
+};
+use serde::Deserialize;
+use uuid::Uuid;
+
+#[cfg(feature = "ses")]
+use crate::aws::AwsCredentials;
+use crate::{
+    events::SuspiciousLoginEvent,
+    hook::Handler,
+    i18n::{Catalog, DEFAULT_LOCALE},
+};
+
+pub type SendFuture<'a> = Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>>;
+
+/// Future of a recipient locale lookup, see [`Mailer::with_recipient_locale`].
+pub type LocaleFuture = Pin<Box<dyn Future<Output = Option<String>> + Send>>;
+
+type RecipientLocale = Arc<dyn Fn(Option<Uuid>, &str) -> LocaleFuture + Send + Sync>;
+
+/// Delivers email messages.
+pub trait MailTransport: Send + Sync {
+    /// Name of the transport, used in error messages.
+    fn name(&self) -> &str;
+
+    /// Delivers `message` to the recipients of its envelope.
+    fn send<'a>(&'a self, message: &'a Message) -> SendFuture<'a>;
+}
+
+impl MailTransport for SmtpTransport {
+    fn name(&self) -> &str {
+        "smtp"
+    }
+
+    fn send<'a>(&'a self, message: &'a Message) -> SendFuture<'a> {
+        let transport = self.clone();
+        let message = message.clone();
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || Transport::send(&transport, &message)).await??;
+            Ok(())
+        })
+    }
+}
+
+/// Which [`MailTransport`] to send with and its settings. Its `Debug` output leaves
+/// out passwords and API keys.
+#[derive(Clone, Deserialize, PartialEq, Eq)]
+#[serde(tag = "transport", rename_all = "snake_case")]
+pub enum MailConfig {
+    /// An SMTP relay, over TLS unless `port` says otherwise.
+    Smtp {
+        host: String,
+        #[serde(default)]
+        port: Option<u16>,
+        #[serde(default)]
+        username: Option<String>,
+        #[serde(default)]
+        password: Option<String>,
+    },
+    #[cfg(feature = "sendgrid")]
+    Sendgrid { api_key: String },
+    #[cfg(feature = "mailgun")]
+    Mailgun {
+        /// Sending domain, e.g. `mg.example.com`.
+        domain: String,
+        api_key: String,
+        /// Whether the domain is in Mailgun's EU region.
+        #[serde(default)]
+        eu: bool,
+    },
+    #[cfg(feature = "ses")]
+    Ses {
+        region: String,
+        access_key: String,
+        secret_key: String,
+        #[serde(default)]
+        session_token: Option<String>,
+    },
+}
+
+impl std::fmt::Debug for MailConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MailConfig::Smtp {
+                host,
+                port,
+                username,
+                ..
+            } => f
+                .debug_struct("Smtp")
+                .field("host", host)
+                .field("port", port)
+                .field("username", username)
+                .finish_non_exhaustive(),
+            #[cfg(feature = "sendgrid")]
+            MailConfig::Sendgrid { .. } => f.debug_struct("Sendgrid").finish_non_exhaustive(),
+            #[cfg(feature = "mailgun")]
+            MailConfig::Mailgun { domain, eu, .. } => f
+                .debug_struct("Mailgun")
+                .field("domain", domain)
+                .field("eu", eu)
+                .finish_non_exhaustive(),
+            #[cfg(feature = "ses")]
+            MailConfig::Ses { region, .. } => f
+                .debug_struct("Ses")
+                .field("region", region)
+                .finish_non_exhaustive(),
+        }
+    }
+}
+
+impl MailConfig {
+    /// Builds the configured transport.
+    pub fn transport(&self) -> anyhow::Result<Arc<dyn MailTransport>> {
+        Ok(match self {
+            MailConfig::Smtp {
+                host,
+                port,
+                username,
+                password,
+            } => {
+                let mut builder = SmtpTransport::relay(host)?;
+                if let Some(port) = port {
+                    builder = builder.port(*port);
+                }
+                if let Some(username) = username {
+                    builder = builder.credentials(Credentials::new(
+                        username.clone(),
+                        password.clone().unwrap_or_default(),
+                    ));
+                }
+                Arc::new(builder.build())
+            }
+            #[cfg(feature = "sendgrid")]
+            MailConfig::Sendgrid { api_key } => Arc::new(SendGridTransport::new(api_key)),
+            #[cfg(feature = "mailgun")]
+            MailConfig::Mailgun {
+                domain,
+                api_key,
+                eu,
+            } => {
+                let transport = MailgunTransport::new(domain, api_key);
+                Arc::new(if *eu { transport.in_eu() } else { transport })
+            }
+            #[cfg(feature = "ses")]
+            MailConfig::Ses {
+                region,
+                access_key,
+                secret_key,
+                session_token,
+            } => {
+                let mut credentials = AwsCredentials::new(region, access_key, secret_key);
+                credentials.session_token = session_token.clone();
+                Arc::new(SesTransport {
+                    client: reqwest::Client::new(),
+                    credentials,
+                })
+            }
+        })
+    }
+}
+
+/// Sends email through a [`MailTransport`].
+#[derive(Clone)]
+pub struct Mailer {
+    transport: Arc<dyn MailTransport>,
+    from: Mailbox,
+    catalog: Arc<Catalog>,
+    locale: String,
+    recipient_locale: Option<RecipientLocale>,
+    urls: Option<UrlBuilder>,
+}
+
+impl Mailer {
+    pub fn new(transport: Arc<dyn MailTransport>, from: Mailbox) -> Self {
+        Self {
+            transport,
+            from,
+            catalog: Arc::new(Catalog::new()),
+            locale: DEFAULT_LOCALE.to_string(),
+            recipient_locale: None,
+            urls: None,
+        }
+    }
+
+    /// Renders the default handlers' messages from `catalog`.
+    pub fn with_catalog(mut self, catalog: Arc<Catalog>) -> Self {
+        self.catalog = catalog;
+        self
+    }
+
+    /// Writes the default handlers' messages in `locale` to recipients without a locale
+    /// of their own.
+    pub fn with_locale(mut self, locale: &str) -> Self {
+        self.locale = locale.to_string();
+        self
+    }
+
+    /// Looks up the locale of each message of the default handlers with `lookup`, given
+    /// the recipient's user id, if known, and address. Recipients it returns `None` for
+    /// get the mailer's locale.
+    pub fn with_recipient_locale<F>(mut self, lookup: F) -> Self
+    where
+        F: Fn(Option<Uuid>, &str) -> LocaleFuture + Send + Sync + 'static,
+    {
+        self.recipient_locale = Some(Arc::new(lookup));
+        self
+    }
+
+    /// Sender of every message built by the default handlers.
+    pub fn from(&self) -> &Mailbox {
+        &self.from
+    }
+
+    pub fn catalog(&self) -> &Catalog {
+        &self.catalog
+    }
+
+    pub fn locale(&self) -> &str {
+        &self.locale
+    }
+
+    /// The locale to write to the recipient `email` in.
+    pub async fn locale_for(&self, user_id: Option<Uuid>, email: &str) -> String {
+        let locale = match &self.recipient_locale {
+            Some(lookup) => lookup(user_id, email).await,
+            None => None,
+        };
+        locale.unwrap_or_else(|| self.locale.clone())
+    }
+
+    /// Builds the links of messages with `urls`, so they point at the instance's public
+    /// address rather than the one the server listens on.
+    pub fn with_urls(mut self, urls: UrlBuilder) -> Self {
+        self.urls = Some(urls);
+        self
+    }
+
+    /// The link to `path` for a message: absolute with the builder of
+    /// [`Mailer::with_urls`], `path` itself otherwise.
+    pub fn url(&self, path: &str) -> String {
+        match &self.urls {
+            Some(urls) => urls.url(path),
+            None => path.to_string(),
+        }
+    }
+
+    pub fn transport(&self) -> &Arc<dyn MailTransport> {
+        &self.transport
+    }
+
+    pub async fn send(&self, message: Message) -> anyhow::Result<()> {
+        self.transport
+            .send(&message)
+            .await
+            .map_err(|err| anyhow!("{} failed to send mail: {}", self.transport.name(), err))
+    }
+}
+
+/// Fails with the status and body of an unsuccessful API response.
+#[cfg(any(feature = "sendgrid", feature = "mailgun", feature = "ses"))]
+async fn check_response(response: reqwest::Response) -> anyhow::Result<()> {
+    let status = response.status();
+    if !status.is_success() {
+        anyhow::bail!("{} {}", status, response.text().await.unwrap_or_default());
+    }
+    Ok(())
+}
+
+/// Sends email through the SendGrid v3 mail send API.
+#[cfg(feature = "sendgrid")]
+#[derive(Clone)]
+pub struct SendGridTransport {
+    client: reqwest::Client,
+    api_key: String,
+    endpoint: String,
+}
+
+#[cfg(feature = "sendgrid")]
+impl SendGridTransport {
+    pub fn new(api_key: &str) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            api_key: api_key.to_string(),
+            endpoint: "https://api.sendgrid.com".to_string(),
+        }
+    }
+
+    /// Sends through another API host, e.g. `https://api.eu.sendgrid.com`.
+    pub fn with_endpoint(mut self, endpoint: &str) -> Self {
+        self.endpoint = endpoint.trim_end_matches('/').to_string();
+        self
+    }
+}
+
+#[cfg(feature = "sendgrid")]
+impl std::fmt::Debug for SendGridTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SendGridTransport")
+            .field("endpoint", &self.endpoint)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "sendgrid")]
+impl MailTransport for SendGridTransport {
+    fn name(&self) -> &str {
+        "sendgrid"
+    }
+
+    fn send<'a>(&'a self, message: &'a Message) -> SendFuture<'a> {
+        Box::pin(async move {
+            let response = self
+                .client
+                .post(format!("{}/v3/mail/send", self.endpoint))
+                .bearer_auth(&self.api_key)
+                .json(&sendgrid_payload(message)?)
+                .send()
+                .await?;
+            check_response(response).await
+        })
+    }
+}
+
+/// The SendGrid request of `message`. SendGrid takes the parts of a message rather than
+/// MIME, so its text and HTML bodies are read back from the formatted message.
+#[cfg(feature = "sendgrid")]
+fn sendgrid_payload(message: &Message) -> anyhow::Result<serde_json::Value> {
+    use lettre::message::{Mailboxes, header};
+    use serde_json::json;
+
+    fn addresses(mailboxes: Option<Mailboxes>) -> Vec<serde_json::Value> {
+        mailboxes
+            .into_iter()
+            .flatten()
+            .map(|mailbox| match &mailbox.name {
+                Some(name) => json!({ "email": mailbox.email.to_string(), "name": name }),
+                None => json!({ "email": mailbox.email.to_string() }),
+            })
+            .collect()
+    }
+
+    let headers = message.headers();
+    let from = addresses(headers.get::<header::From>().map(Into::into))
+        .pop()
+        .ok_or_else(|| anyhow!("the message has no sender"))?;
+    let to = addresses(headers.get::<header::To>().map(Into::into));
+    let cc = addresses(headers.get::<header::Cc>().map(Into::into));
+    // Blind copies only appear in the envelope.
+    let listed: Vec<_> = to.iter().chain(&cc).map(|a| a["email"].clone()).collect();
+    let bcc: Vec<_> = message
+        .envelope()
+        .to()
+        .iter()
+        .map(|address| json!({ "email": address.to_string() }))
+        .filter(|address| !listed.contains(&address["email"]))
+        .collect();
+
+    let mut personalization = json!({ "to": to });
+    if !cc.is_empty() {
+        personalization["cc"] = json!(cc);
+    }
+    if !bcc.is_empty() {
+        personalization["bcc"] = json!(bcc);
+    }
+    let content: Vec<_> = mime::text_parts(&message.formatted())?
+        .into_iter()
+        .map(|(kind, value)| json!({ "type": kind, "value": value }))
+        .collect();
+
+    let mut payload = json!({
+        "personalizations": [personalization],
+        "from": from,
+        "subject": headers.get_raw("Subject").unwrap_or_default(),
+        "content": content,
+    });
+    if let Some(reply_to) = addresses(headers.get::<header::ReplyTo>().map(Into::into)).pop() {
+        payload["reply_to"] = reply_to;
+    }
+    Ok(payload)
+}
+
+/// Just enough MIME to read back the text bodies of the messages lettre builds.
+#[cfg(feature = "sendgrid")]
+mod mime {
+    use anyhow::anyhow;
+    use base64::Engine;
+
+    /// Splits a part into its header lines, unfolded, and its body.
+    fn split(part: &str) -> (Vec<String>, &str) {
+        let (head, body) = part.split_once("\r\n\r\n").unwrap_or((part, ""));
+        let mut headers: Vec<String> = Vec::new();
+        for line in head.split("\r\n") {
+            match headers.last_mut() {
+                Some(last) if line.starts_with([' ', '\t']) => last.push_str(line),
+                _ => headers.push(line.to_string()),
+            }
+        }
+        (headers, body)
+    }
+
+    fn header<'a>(headers: &'a [String], name: &str) -> Option<&'a str> {
+        headers.iter().find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.eq_ignore_ascii_case(name).then(|| value.trim())
+        })
+    }
+
+    fn parameter<'a>(value: &'a str, name: &str) -> Option<&'a str> {
+        value.split(';').skip(1).find_map(|param| {
+            let (key, value) = param.split_once('=')?;
+            key.trim()
+                .eq_ignore_ascii_case(name)
+                .then(|| value.trim().trim_matches('"'))
+        })
+    }
+
+    fn decode_quoted_printable(body: &str) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(body.len());
+        let mut rest = body.as_bytes();
+        while let Some((&byte, tail)) = rest.split_first() {
+            rest = tail;
+            if byte != b'=' {
+                bytes.push(byte);
+            } else if rest.starts_with(b"\r\n") {
+                rest = &rest[2..];
+            } else if let Some(hex) = rest.get(..2)
+                && let Ok(decoded) = u8::from_str_radix(&String::from_utf8_lossy(hex), 16)
+            {
+                bytes.push(decoded);
+                rest = &rest[2..];
+            } else {
+                bytes.push(byte);
+            }
+        }
+        bytes
+    }
+
+    fn collect(part: &str, parts: &mut Vec<(String, String)>) -> anyhow::Result<()> {
+        let (headers, body) = split(part);
+        let content_type = header(&headers, "Content-Type").unwrap_or("text/plain");
+        let kind = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+
+        if kind.starts_with("multipart/") {
+            let boundary = parameter(content_type, "boundary")
+                .ok_or_else(|| anyhow!("multipart without a boundary"))?;
+            let delimiter = format!("--{}", boundary);
+            for part in body.split(&delimiter).skip(1) {
+                if part.starts_with("--") {
+                    break;
+                }
+                collect(
+                    part.trim_start_matches("\r\n").trim_end_matches("\r\n"),
+                    parts,
+                )?;
+            }
+        } else if (kind == "text/plain" || kind == "text/html")
+            && !header(&headers, "Content-Disposition").is_some_and(|d| d.starts_with("attachment"))
+        {
+            let encoding = header(&headers, "Content-Transfer-Encoding").unwrap_or("7bit");
+            let bytes = match encoding.to_ascii_lowercase().as_str() {
+                "base64" => base64::engine::general_purpose::STANDARD
+                    .decode(body.split_whitespace().collect::<String>())?,
+                "quoted-printable" => decode_quoted_printable(body),
+                _ => body.as_bytes().to_vec(),
+            };
+            parts.push((kind, String::from_utf8(bytes)?));
+        }
+        Ok(())
+    }
+
+    /// The `text/plain` and `text/html` bodies of a formatted message, in order, as
+    /// content type and text.
+    pub fn text_parts(formatted: &[u8]) -> anyhow::Result<Vec<(String, String)>> {
+        let mut parts = Vec::new();
+        collect(std::str::from_utf8(formatted)?, &mut parts)?;
+        Ok(parts)
+    }
+}
+
+/// Sends email through the Mailgun API, as MIME.
+#[cfg(feature = "mailgun")]
+#[derive(Clone)]
+pub struct MailgunTransport {
+    client: reqwest::Client,
+    domain: String,
+    api_key: String,
+    endpoint: String,
+}
+
+#[cfg(feature = "mailgun")]
+impl MailgunTransport {
+    pub fn new(domain: &str, api_key: &str) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            domain: domain.to_string(),
+            api_key: api_key.to_string(),
+            endpoint: "https://api.mailgun.net".to_string(),
+        }
+    }
+
+    /// Sends through the API of Mailgun's EU region.
+    pub fn in_eu(mut self) -> Self {
+        self.endpoint = "https://api.eu.mailgun.net".to_string();
+        self
+    }
+
+    /// The `multipart/form-data` body of a request sending `message`, and its boundary.
+    fn form(message: &Message) -> (String, Vec<u8>) {
+        let boundary = format!("palmera-{}", uuid::Uuid::new_v4().simple());
+        let to: Vec<_> = message
+            .envelope()
+            .to()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+
+        let mut body = format!(
+            "--{boundary}\r\nContent-Disposition: form-data; name=\"to\"\r\n\r\n{}\r\n\
+             --{boundary}\r\nContent-Disposition: form-data; name=\"message\"; filename=\"message.mime\"\r\n\
+             Content-Type: message/rfc822\r\n\r\n",
+            to.join(",")
+        )
+        .into_bytes();
+        body.extend_from_slice(&message.formatted());
+        body.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+        (boundary, body)
+    }
+}
+
+#[cfg(feature = "mailgun")]
+impl std::fmt::Debug for MailgunTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MailgunTransport")
+            .field("domain", &self.domain)
+            .field("endpoint", &self.endpoint)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "mailgun")]
+impl MailTransport for MailgunTransport {
+    fn name(&self) -> &str {
+        "mailgun"
+    }
+
+    fn send<'a>(&'a self, message: &'a Message) -> SendFuture<'a> {
+        Box::pin(async move {
+            let (boundary, body) = Self::form(message);
+            let response = self
+                .client
+                .post(format!(
+                    "{}/v3/{}/messages.mime",
+                    self.endpoint, self.domain
+                ))
+                .basic_auth("api", Some(&self.api_key))
+                .header(
+                    reqwest::header::CONTENT_TYPE,
+                    format!("multipart/form-data; boundary={}", boundary),
+                )
+                .body(body)
+                .send()
+                .await?;
+            check_response(response).await
+        })
+    }
+}
+
+/// Sends email through the Amazon SES v2 API, as MIME.
+#[cfg(feature = "ses")]
+#[derive(Clone)]
+pub struct SesTransport {
+    client: reqwest::Client,
+    credentials: AwsCredentials,
+}
+
+#[cfg(feature = "ses")]
+impl SesTransport {
+    pub fn new(region: &str, access_key: &str, secret_key: &str) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            credentials: AwsCredentials::new(region, access_key, secret_key),
+        }
+    }
+
+    /// Uses the credentials of `AWS_REGION`, `AWS_ACCESS_KEY_ID`,
+    /// `AWS_SECRET_ACCESS_KEY` and the optional `AWS_SESSION_TOKEN`.
+    pub fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            client: reqwest::Client::new(),
+            credentials: AwsCredentials::from_env()?,
+        })
+    }
+
+    /// The `SendEmail` request of `message`. Blind copies are only in the envelope, so
+    /// every recipient is listed as a destination.
+    fn payload(message: &Message) -> serde_json::Value {
+        use base64::Engine;
+
+        let to: Vec<_> = message
+            .envelope()
+            .to()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        serde_json::json!({
+            "Destination": { "ToAddresses": to },
+            "Content": {
+                "Raw": {
+                    "Data": base64::engine::general_purpose::STANDARD.encode(message.formatted())
+                }
+            },
+        })
+    }
+}
+
+#[cfg(feature = "ses")]
+impl std::fmt::Debug for SesTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SesTransport")
+            .field("region", &self.credentials.region)
+            .field("access_key", &self.credentials.access_key)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "ses")]
+impl MailTransport for SesTransport {
+    fn name(&self) -> &str {
+        "ses"
+    }
+
+    fn send<'a>(&'a self, message: &'a Message) -> SendFuture<'a> {
+        Box::pin(async move {
+            const PATH: &str = "/v2/email/outbound-emails";
+            let host = format!("email.{}.amazonaws.com", self.credentials.region);
+            let payload = Self::payload(message).to_string();
+            let headers = vec![
+                ("content-type".to_string(), "application/json".to_string()),
+                ("host".to_string(), host.clone()),
+            ];
+
+            let mut request = self.client.post(format!("https://{}{}", host, PATH));
+            for (name, value) in
+                self.credentials
+                    .sign("ses", PATH, headers, &payload, chrono::Utc::now())
+            {
+                if name != "host" {
+                    request = request.header(name, value);
+                }
+            }
+            check_response(request.body(payload).send().await?).await
+        })
+    }
+}
+
+/// The subject and plain text body of an email, rendered from a [`Catalog`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MailText {
+    pub subject: String,
+    pub body: String,
+}
+
+impl MailText {
+    /// Renders the `mail.{template}.subject` and `mail.{template}.body` messages of
+    /// `catalog` in `locale`, filling in `args`.
+    pub fn render(catalog: &Catalog, locale: &str, template: &str, args: &[(&str, &str)]) -> Self {
+        let locales = [locale];
+        Self {
+            subject: catalog.translate(&locales, &format!("mail.{template}.subject"), args),
+            body: catalog.translate(&locales, &format!("mail.{template}.body"), args),
+        }
+    }
+
+    /// Builds the email from `from` to `to`.
+    pub fn message(self, from: &Mailbox, to: Mailbox) -> anyhow::Result<Message> {
+        Ok(Message::builder()
+            .from(from.clone())
+            .to(to)
+            .subject(self.subject)
+            .body(self.body)?)
+    }
+}
+
+/// Renders the email warning a user about a suspicious sign-in in `locale`.
+pub fn suspicious_login_text(
+    catalog: &Catalog,
+    locale: &str,
+    event: &SuspiciousLoginEvent,
+) -> MailText {
+    let login = &event.login().event;
+    let unknown = catalog.translate(&[locale], "mail.unknown", &[]);
+    MailText::render(
+        catalog,
+        locale,
+        "suspicious_login",
+        &[
+            ("reasons", &event.reasons().join(", ")),
+            (
+                "time",
+                &login.created.format("%Y-%m-%d %H:%M UTC").to_string(),
+            ),
+            ("ip", login.ip.as_deref().unwrap_or(&unknown)),
+            ("device", login.user_agent.as_deref().unwrap_or(&unknown)),
+        ],
+    )
+}
+
+/// Builds the email warning a user about a suspicious sign-in in `locale`, or `None`
+/// when the sign-in has no email address to write to.
+pub fn suspicious_login_message(
+    from: &Mailbox,
+    catalog: &Catalog,
+    locale: &str,
+    event: &SuspiciousLoginEvent,
+) -> anyhow::Result<Option<Message>> {
+    let Some(email) = &event.login().event.email else {
+        return Ok(None);
+    };
+    suspicious_login_text(catalog, locale, event)
+        .message(from, email.parse()?)
+        .map(Some)
+}
+
+/// Renders the email asking a user to confirm their address in `locale`.
+pub fn verification_text(catalog: &Catalog, locale: &str, email: &VerificationEmail) -> MailText {
+    MailText::render(catalog, locale, "verify_email", &[("link", &email.link)])
+}
+
+/// Builds the email asking a user to confirm their address in `locale`.
+pub fn verification_message(
+    from: &Mailbox,
+    catalog: &Catalog,
+    locale: &str,
+    email: &VerificationEmail,
+) -> anyhow::Result<Message> {
+    verification_text(catalog, locale, email).message(from, email.email.parse()?)
+}
+
+/// [`VerificationSender`] emailing the verification links with `mailer`. Emails are sent
+/// in the background; failures are logged.
+pub fn verification_sender(mailer: Mailer) -> VerificationSender {
+    VerificationSender::new(move |email| {
+        let mailer = mailer.clone();
+        tokio::spawn(async move {
+            let locale = mailer.locale_for(Some(email.user_id), &email.email).await;
+            let sent = match verification_message(mailer.from(), mailer.catalog(), &locale, &email)
+            {
+                Ok(message) => mailer.send(message).await,
+                Err(err) => Err(err),
+            };
+            if let Err(err) = sent {
+                tracing::warn!(user_id = %email.user_id, "failed to send verification email: {err:#}");
+            }
+        });
+    })
+}
+
+/// Handler for [`App::on_suspicious_login`](crate::base::App::on_suspicious_login)
+/// emailing the user about the sign-in.
+pub fn suspicious_login_notifier(mailer: Mailer) -> Handler<SuspiciousLoginEvent> {
+    Handler::new(move |event: &SuspiciousLoginEvent| {
+        let mailer = mailer.clone();
+        let event = event.clone();
+        Box::pin(async move {
+            let login = &event.login().event;
+            let locale = match &login.email {
+                Some(email) => mailer.locale_for(login.user_id, email).await,
+                None => mailer.locale().to_string(),
+            };
+            if let Some(message) =
+                suspicious_login_message(mailer.from(), mailer.catalog(), &locale, &event)?
+            {
+                mailer.send(message).await?;
+            }
+            Ok(event)
+        })
+    })
+    .with_label("suspicious login email")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use palmera_auth::security::{SecurityEvent, SecurityEventKind, SuspiciousLogin};
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Outbox(Mutex<Vec<Message>>);
+
+    impl MailTransport for Outbox {
+        fn name(&self) -> &str {
+            "outbox"
+        }
+
+        fn send<'a>(&'a self, message: &'a Message) -> SendFuture<'a> {
+            self.0.lock().unwrap().push(message.clone());
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    fn message() -> Message {
+        Message::builder()
+            .from("Palmera <no-reply@example.com>".parse().unwrap())
+            .to("Ann <ann@example.com>".parse().unwrap())
+            .bcc("audit@example.com".parse().unwrap())
+            .subject("Welcome")
+            .body("Hello, Ann! Ünïcode and a line long enough to need soft breaks in quoted printable encoding.".to_string())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_mailer_transport() {
+        let outbox = Arc::new(Outbox::default());
+        let mailer = Mailer::new(outbox.clone(), "no-reply@example.com".parse().unwrap());
+
+        mailer.send(message()).await.unwrap();
+        assert_eq!(mailer.transport().name(), "outbox");
+        assert_eq!(outbox.0.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_mail_config() {
+        let config: MailConfig = serde_json::from_str(
+            r#"{ "transport": "smtp", "host": "smtp.example.com", "port": 2525, "username": "palmera" }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            MailConfig::Smtp {
+                host: "smtp.example.com".to_string(),
+                port: Some(2525),
+                username: Some("palmera".to_string()),
+                password: None,
+            }
+        );
+        assert_eq!(config.transport().unwrap().name(), "smtp");
+
+        let secret: MailConfig = serde_json::from_str(
+            r#"{ "transport": "smtp", "host": "smtp.example.com", "password": "hunter2" }"#,
+        )
+        .unwrap();
+        assert!(!format!("{:?}", secret).contains("hunter2"));
+
+        assert!(serde_json::from_str::<MailConfig>(r#"{ "transport": "pigeon" }"#).is_err());
+    }
+
+    #[cfg(feature = "sendgrid")]
+    #[test]
+    fn test_sendgrid_payload() {
+        let payload = sendgrid_payload(&message()).unwrap();
+        assert_eq!(
+            payload,
+            serde_json::json!({
+                "personalizations": [{
+                    "to": [{ "email": "ann@example.com", "name": "Ann" }],
+                    "bcc": [{ "email": "audit@example.com" }],
+                }],
+                "from": { "email": "no-reply@example.com", "name": "Palmera" },
+                "subject": "Welcome",
+                "content": [{
+                    "type": "text/plain",
+                    "value": "Hello, Ann! Ünïcode and a line long enough to need soft breaks in quoted printable encoding.",
+                }],
+            })
+        );
+
+        let html = Message::builder()
+            .from("no-reply@example.com".parse().unwrap())
+            .to("ann@example.com".parse().unwrap())
+            .multipart(lettre::message::MultiPart::alternative_plain_html(
+                "plain".to_string(),
+                "<p>html</p>".to_string(),
+            ))
+            .unwrap();
+        assert_eq!(
+            sendgrid_payload(&html).unwrap()["content"],
+            serde_json::json!([
+                { "type": "text/plain", "value": "plain" },
+                { "type": "text/html", "value": "<p>html</p>" },
+            ])
+        );
+    }
+
+    #[cfg(feature = "mailgun")]
+    #[test]
+    fn test_mailgun_form() {
+        let (boundary, body) = MailgunTransport::form(&message());
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.starts_with(&format!(
+            "--{boundary}\r\nContent-Disposition: form-data; name=\"to\"\r\n\r\nann@example.com,audit@example.com\r\n"
+        )));
+        assert!(body.contains("Subject: Welcome"));
+        assert!(body.ends_with(&format!("--{boundary}--\r\n")));
+    }
+
+    #[cfg(feature = "ses")]
+    #[test]
+    fn test_ses_payload() {
+        use base64::Engine;
+
+        let message = message();
+        let payload = SesTransport::payload(&message);
+        assert_eq!(
+            payload["Destination"]["ToAddresses"],
+            serde_json::json!(["ann@example.com", "audit@example.com"])
+        );
+        let raw = base64::engine::general_purpose::STANDARD
+            .decode(payload["Content"]["Raw"]["Data"].as_str().unwrap())
+            .unwrap();
+        assert_eq!(raw, message.formatted());
+    }
+
+    fn event(email: Option<&str>) -> SuspiciousLoginEvent {
+        let mut login = SecurityEvent::new(SecurityEventKind::Login, true);
+        login.email = email.map(str::to_string);
+        login.ip = Some("198.51.100.1".to_string());
+
+        SuspiciousLoginEvent::new(SuspiciousLogin {
+            event: login,
+            reasons: vec!["new IP address".to_string()],
+        })
+    }
+
+    #[test]
+    fn test_verification_message() {
+        let from: Mailbox = "Palmera <no-reply@example.com>".parse().unwrap();
+        let email = VerificationEmail {
+            user_id: uuid::Uuid::new_v4(),
+            email: "user@example.com".to_string(),
+            token: "token".to_string(),
+            link: "https://example.com/verify-email?token=token".to_string(),
+        };
+        let message = verification_message(&from, &Catalog::new(), "en", &email).unwrap();
+        let raw = String::from_utf8(message.formatted()).unwrap();
+        assert!(raw.contains("To: user@example.com"));
+        assert!(raw.contains("Subject: Confirm your email address"));
+        assert!(raw.contains("https://example.com/verify-email?token=token"));
+    }
+
+    #[test]
+    fn test_suspicious_login_message() {
+        let from: Mailbox = "Palmera <no-reply@example.com>".parse().unwrap();
+
+        let catalog = Catalog::new();
+        let message =
+            suspicious_login_message(&from, &catalog, "en", &event(Some("user@example.com")))
+                .unwrap()
+                .unwrap();
+        let raw = String::from_utf8(message.formatted()).unwrap();
+        assert!(raw.contains("To: user@example.com"));
+        assert!(raw.contains("Subject: New sign-in to your account"));
+        assert!(raw.contains("new IP address"));
+        assert!(raw.contains("198.51.100.1"));
+        assert!(raw.contains("Device: unknown"));
+
+        assert!(
+            suspicious_login_message(&from, &catalog, "en", &event(None))
+                .unwrap()
+                .is_none()
+        );
+
+        let catalog = Catalog::new().with_messages(
+            "es",
+            [
+                ("mail.suspicious_login.subject", "Nuevo acceso a tu cuenta"),
+                ("mail.unknown", "desconocido"),
+            ],
+        );
+        let message =
+            suspicious_login_message(&from, &catalog, "es-MX", &event(Some("user@example.com")))
+                .unwrap()
+                .unwrap();
+        let raw = String::from_utf8(message.formatted()).unwrap();
+        assert!(raw.contains("Subject: Nuevo acceso a tu cuenta"));
+        // The body falls back to English, with the localized placeholder.
+        assert!(raw.contains("Device: desconocido"));
+    }
+
+    #[tokio::test]
+    async fn test_recipient_locale() {
+        let outbox = Arc::new(Outbox::default());
+        let catalog = Catalog::new().with_messages(
+            "es",
+            [("mail.suspicious_login.subject", "Nuevo acceso a tu cuenta")],
+        );
+        let mailer = Mailer::new(outbox.clone(), "no-reply@example.com".parse().unwrap())
+            .with_catalog(Arc::new(catalog))
+            .with_recipient_locale(|_, email| {
+                let locale = (email == "ana@example.com").then(|| "es".to_string());
+                Box::pin(async move { locale })
+            });
+        assert_eq!(mailer.locale_for(None, "ana@example.com").await, "es");
+        assert_eq!(mailer.locale_for(None, "bob@example.com").await, "en");
+
+        let mut hook = crate::hook::Hook::new();
+        hook.bind(suspicious_login_notifier(mailer));
+        for email in ["ana@example.com", "bob@example.com"] {
+            for result in hook.trigger(&event(Some(email))).await {
+                result.unwrap();
+            }
+        }
+
+        let sent: Vec<_> = outbox
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|message| String::from_utf8(message.formatted()).unwrap())
+            .collect();
+        assert!(sent[0].contains("Subject: Nuevo acceso a tu cuenta"));
+        assert!(sent[1].contains("Subject: New sign-in to your account"));
+    }
+}
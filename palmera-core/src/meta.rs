@@ -0,0 +1,131 @@
+//! # Instance metadata
+//!
+//! Every [`App`](crate::base::App) serves `GET /api/meta`, describing the instance so
+//! SDKs can detect what it supports before calling it:
+//!
+//! ```json
+//! {
+//!   "name": "palmera",
+//!   "version": "0.1.0",
+//!   "features": ["redis"],
+//!   "plugins": ["hello"],
+//!   "capabilities": { "auth": ["bearer", "cookie"], "realtime": true, "storage": "s3" },
+//!   "metadata": { "region": "eu-west-1" }
+//! }
+//! ```
+//!
+//! `features` lists the optional features palmera-core was compiled with, and
+//! `capabilities` is derived from the app's configuration. Apps and plugins add their own
+//! keys under `metadata` with [`App::set_metadata`](crate::base::App::set_metadata). The
+//! same description is logged as a banner when the app starts serving.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// Path of the metadata route.
+pub const META_PATH: &str = "/api/meta";
+
+/// Version of Palmera the instance runs.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Description of an instance, served at [`META_PATH`].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct InstanceMeta {
+    pub name: String,
+    pub version: String,
+    pub features: Vec<String>,
+    /// Names of the registered plugins, in registration order.
+    pub plugins: Vec<String>,
+    pub capabilities: Capabilities,
+    /// Keys added by the app, see [`App::set_metadata`](crate::base::App::set_metadata).
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    pub metadata: Map<String, Value>,
+}
+
+/// What the API of an instance supports.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Ways requests can authenticate: `bearer` tokens, and `cookie` sessions when
+    /// enabled. Empty without auth configuration.
+    pub auth: Vec<String>,
+    /// Whether realtime channels are reachable, i.e. a transport was mounted with
+    /// [`App::mount_realtime`](crate::base::App::mount_realtime).
+    pub realtime: bool,
+    /// Kind of file storage, `local`, `s3` or `memory`, if any.
+    pub storage: Option<String>,
+}
+
+/// Optional features palmera-core was compiled with.
+pub fn compiled_features() -> Vec<String> {
+    [
+        ("redis", cfg!(feature = "redis")),
+        ("vault", cfg!(feature = "vault")),
+        ("aws", cfg!(feature = "aws")),
+        ("sendgrid", cfg!(feature = "sendgrid")),
+        ("mailgun", cfg!(feature = "mailgun")),
+        ("ses", cfg!(feature = "ses")),
+    ]
+    .into_iter()
+    .filter(|(_, enabled)| *enabled)
+    .map(|(feature, _)| feature.to_string())
+    .collect()
+}
+
+impl InstanceMeta {
+    /// The startup banner: name, version and what the instance serves, one item per
+    /// line.
+    pub fn banner(&self, address: &str) -> String {
+        let list = |items: &[String]| match items {
+            [] => "none".to_string(),
+            items => items.join(", "),
+        };
+        let storage = self.capabilities.storage.as_deref().unwrap_or("none");
+
+        format!(
+            "{} {} listening on http://{}\n  plugins: {}\n  features: {}\n  auth: {}\n  \
+             storage: {}",
+            self.name,
+            self.version,
+            address,
+            list(&self.plugins),
+            list(&self.features),
+            list(&self.capabilities.auth),
+            storage,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_banner() {
+        let meta = InstanceMeta {
+            name: "palmera".to_string(),
+            version: VERSION.to_string(),
+            features: vec![],
+            plugins: vec!["hello".to_string(), "audit".to_string()],
+            capabilities: Capabilities {
+                auth: vec!["bearer".to_string()],
+                realtime: true,
+                storage: None,
+            },
+            metadata: Map::new(),
+        };
+        assert_eq!(
+            meta.banner("127.0.0.1:3000"),
+            format!(
+                "palmera {VERSION} listening on http://127.0.0.1:3000\n  \
+                 plugins: hello, audit\n  features: none\n  auth: bearer\n  storage: none"
+            )
+        );
+        assert!(
+            !serde_json::to_value(&meta)
+                .unwrap()
+                .as_object()
+                .unwrap()
+                .contains_key("metadata")
+        );
+    }
+}
@@ -0,0 +1,269 @@
+//! # Notification delivery
+//!
+//! Serves the in-app notifications of `palmera_database::notifications` to the signed in
+//! user and pushes new ones to their open clients. The [`router`] expects an
+//! `Extension<Arc<Notifications>>` layer and answers `401 Unauthorized` without valid
+//! [`AuthClaims`]:
+//!
+//! - `GET /me/notifications` lists the caller's notifications, newest first, optionally
+//!   only unread ones.
+//! - `POST /me/notifications/{id}/read` marks one of them read.
+//! - `POST /me/notifications/read` marks all of them read.
+//! - `DELETE /me/notifications/{id}` deletes one of them.
+//!
+//! [`forward`] publishes every new notification, as JSON, to the [`Realtime`] channel of
+//! its user, named by [`channel`]. [`guard_channels`] binds an `on_subscribe` handler
+//! denying those channels to everyone but their user.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # async fn run(db: sqlx::Pool<sqlx::Sqlite>) -> Result<(), sqlx::Error> {
+//! use std::sync::Arc;
+//!
+//! use axum::Extension;
+//! use palmera_core::{notifications, realtime::Realtime};
+//! use palmera_database::notifications::Notifications;
+//!
+//! let store = Arc::new(Notifications::open(db).await?);
+//! let mut realtime = Realtime::new();
+//! notifications::guard_channels(&mut realtime);
+//! notifications::forward(Arc::new(realtime), &store);
+//!
+//! let (router, _api) = notifications::router()
+//!     .layer(Extension(store))
+//!     .split_for_parts();
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{future, sync::Arc};
+
+use axum::{
+    Extension, Json,
+    extract::{Path, Query},
+    http::StatusCode,
+};
+use chrono::Utc;
+use palmera_auth::extract::AuthClaims;
+pub use palmera_database::notifications::channel;
+use palmera_database::notifications::{Notification, NotificationFilter, Notifications};
+use serde::Serialize;
+use tokio::{sync::broadcast::error::RecvError, task::JoinHandle};
+use utoipa::ToSchema;
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+use crate::{events::SubscribeEvent, realtime::Realtime};
+
+/// Publishes the notifications created in `store` from now on to the channels of their
+/// users, until the store is dropped.
+pub fn forward(realtime: Arc<Realtime>, store: &Notifications) -> JoinHandle<()> {
+    let mut notifications = store.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match notifications.recv().await {
+                Ok(notification) => {
+                    if let Ok(payload) = serde_json::to_string(&notification) {
+                        realtime.publish(&channel(&notification.user_id), &payload);
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!(skipped, "notifications were not pushed to realtime");
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    })
+}
+
+/// Binds an `on_subscribe` handler letting only their user subscribe to a notification
+/// channel.
+pub fn guard_channels(realtime: &mut Realtime) -> String {
+    realtime.on_subscribe.bind_fn(|event: &SubscribeEvent| {
+        let result = match event.channel().strip_prefix(channel("").as_str()) {
+            Some(user_id)
+                if event
+                    .claims()
+                    .is_none_or(|claims| claims.subject.to_string() != user_id) =>
+            {
+                Err(anyhow::anyhow!("Not allowed to join {}", event.channel()))
+            }
+            _ => Ok(event.clone()),
+        };
+        Box::pin(future::ready(result))
+    })
+}
+
+/// Lists the caller's notifications, newest first.
+#[utoipa::path(
+    get,
+    path = "/me/notifications",
+    params(NotificationFilter),
+    responses((status = 200, body = Vec<Notification>), (status = 401))
+)]
+async fn list_notifications(
+    Extension(store): Extension<Arc<Notifications>>,
+    AuthClaims(claims): AuthClaims,
+    Query(filter): Query<NotificationFilter>,
+) -> Result<Json<Vec<Notification>>, StatusCode> {
+    store
+        .list(&claims.subject.to_string(), &filter)
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Marks one of the caller's notifications read.
+#[utoipa::path(
+    post,
+    path = "/me/notifications/{id}/read",
+    responses((status = 204), (status = 401), (status = 404))
+)]
+async fn read_notification(
+    Extension(store): Extension<Arc<Notifications>>,
+    AuthClaims(claims): AuthClaims,
+    Path(id): Path<i64>,
+) -> StatusCode {
+    match store
+        .mark_read(&claims.subject.to_string(), id, Utc::now())
+        .await
+    {
+        Ok(true) => StatusCode::NO_CONTENT,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct MarkedRead {
+    /// Notifications that were unread.
+    marked: u64,
+}
+
+/// Marks all of the caller's notifications read.
+#[utoipa::path(
+    post,
+    path = "/me/notifications/read",
+    responses((status = 200, body = MarkedRead), (status = 401))
+)]
+async fn read_all_notifications(
+    Extension(store): Extension<Arc<Notifications>>,
+    AuthClaims(claims): AuthClaims,
+) -> Result<Json<MarkedRead>, StatusCode> {
+    store
+        .mark_all_read(&claims.subject.to_string(), Utc::now())
+        .await
+        .map(|marked| Json(MarkedRead { marked }))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Deletes one of the caller's notifications.
+#[utoipa::path(
+    delete,
+    path = "/me/notifications/{id}",
+    responses((status = 204), (status = 401), (status = 404))
+)]
+async fn delete_notification(
+    Extension(store): Extension<Arc<Notifications>>,
+    AuthClaims(claims): AuthClaims,
+    Path(id): Path<i64>,
+) -> StatusCode {
+    match store.remove(&claims.subject.to_string(), id).await {
+        Ok(true) => StatusCode::NO_CONTENT,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+pub fn router() -> OpenApiRouter {
+    OpenApiRouter::new()
+        .routes(routes!(list_notifications))
+        .routes(routes!(read_all_notifications))
+        .routes(routes!(read_notification))
+        .routes(routes!(delete_notification))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use palmera_auth::AuthConfig;
+    use palmera_database::notifications::NewNotification;
+    use uuid::Uuid;
+
+    use crate::realtime::RealtimeMessage;
+
+    fn claims(subject: Uuid) -> AuthClaims {
+        let config = AuthConfig::builder()
+            .secret("a-secret-that-is-at-least-32-bytes-long")
+            .build()
+            .unwrap();
+        let token = config
+            .issue_token(subject, chrono::Duration::minutes(5))
+            .unwrap();
+        AuthClaims(config.verify_token(&token).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_notifications() {
+        let db = sqlx::SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let store = Arc::new(Notifications::open(db).await.unwrap());
+        let mut realtime = Realtime::new();
+        guard_channels(&mut realtime);
+        let realtime = Arc::new(realtime);
+        forward(realtime.clone(), &store);
+
+        let (ann, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let ann_channel = channel(&ann.to_string());
+        let stranger = realtime
+            .subscribe(&ann_channel, "bob", Some(claims(bob).0))
+            .await;
+        assert!(stranger.is_err());
+        assert!(
+            realtime
+                .subscribe(&ann_channel, "anon", None)
+                .await
+                .is_err()
+        );
+        let mut subscription = realtime
+            .subscribe(&ann_channel, "ann", Some(claims(ann).0))
+            .await
+            .unwrap();
+        assert!(matches!(
+            subscription.recv().await.unwrap(),
+            RealtimeMessage::Join(_)
+        ));
+
+        let created = store
+            .create(
+                NewNotification::new(&ann.to_string(), "comment", "Bob replied"),
+                Utc::now(),
+            )
+            .await
+            .unwrap();
+        let RealtimeMessage::Broadcast(payload) = subscription.recv().await.unwrap() else {
+            panic!("expected the notification");
+        };
+        assert_eq!(
+            serde_json::from_str::<Notification>(&payload).unwrap(),
+            created
+        );
+
+        let Json(listed) = list_notifications(
+            Extension(store.clone()),
+            claims(ann),
+            Query(NotificationFilter::default()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(listed, [created.clone()]);
+        // Other users can't touch the notification.
+        assert_eq!(
+            read_notification(Extension(store.clone()), claims(bob), Path(created.id)).await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            delete_notification(Extension(store.clone()), claims(ann), Path(created.id)).await,
+            StatusCode::NO_CONTENT
+        );
+    }
+}
@@ -0,0 +1,10 @@
+use crate::base::App;
+
+/// Extension that configures an [`App`] when it is built, e.g. by binding hooks or
+/// mounting routes.
+pub trait Plugin: Send + Sync + 'static {
+    /// Name used in logs and returned by [`App::plugins`].
+    fn name(&self) -> &str;
+
+    fn register(&self, app: &mut App) -> anyhow::Result<()>;
+}
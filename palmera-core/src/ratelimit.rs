@@ -0,0 +1,492 @@
+//! # Rate limiting
+//!
+//! Token bucket rate limiting for axum routes. A [`RateLimiter`] belongs to a route group,
+//! holds the group's [`Quota`], and decides which principal a request is counted against
+//! (API key, user id or client IP). Principals come from verified credentials only, so a
+//! client can't get a fresh bucket by sending a made-up header. Buckets live in a
+//! [`RateLimitStore`]: the in-process [`MemoryStore`], or with the `redis` feature a Redis
+//! backed store shared between nodes.
+//!
+//! Every response carries `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset`
+//! headers; rejected requests get `429 Too Many Requests` with `Retry-After`.
+//!
+//! # Example
+//!
+//! ```rust
+//! use std::{sync::Arc, time::Duration};
+//! use axum::{Router, middleware, routing::get};
+//! use palmera_core::ratelimit::{MemoryStore, Quota, RateLimiter, rate_limit};
+//!
+//! let limiter = Arc::new(RateLimiter::new(
+//!     "api",
+//!     Quota::new(100, Duration::from_secs(60))?,
+//!     MemoryStore::new(),
+//! ));
+//! let app: Router = Router::new()
+//!     .route("/", get(|| async { "ok" }))
+//!     .layer(middleware::from_fn_with_state(limiter, rate_limit::<MemoryStore>));
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::SocketAddr,
+    num::NonZeroU32,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use palmera_auth::{jwt::JWTClaims, proxy::ClientAddr};
+
+/// Number of requests allowed per period, refilled continuously.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quota {
+    /// Bucket size: the number of requests that may be made in a burst.
+    pub capacity: NonZeroU32,
+    /// Time it takes to refill an empty bucket.
+    pub period: Duration,
+}
+
+impl Quota {
+    /// Allows `capacity` requests per `period`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `capacity` or `period` is zero, which leaves the refill rate undefined.
+    pub fn new(capacity: u32, period: Duration) -> anyhow::Result<Self> {
+        let Some(capacity) = NonZeroU32::new(capacity) else {
+            anyhow::bail!("rate limit capacity must be at least 1");
+        };
+        if period.is_zero() {
+            anyhow::bail!("rate limit period must not be zero");
+        }
+        Ok(Self { capacity, period })
+    }
+
+    /// Tokens added to the bucket per second.
+    fn refill_rate(&self) -> f64 {
+        self.capacity.get() as f64 / self.period.as_secs_f64()
+    }
+
+    /// Builds the decision for a bucket holding `tokens` after the request was counted.
+    fn decision(&self, allowed: bool, tokens: f64) -> RateLimitDecision {
+        let rate = self.refill_rate();
+
+        RateLimitDecision {
+            allowed,
+            limit: self.capacity.get(),
+            remaining: tokens.floor().max(0.0) as u32,
+            reset_after: Duration::from_secs_f64(
+                (self.capacity.get() as f64 - tokens).max(0.0) / rate,
+            ),
+            retry_after: (!allowed).then(|| Duration::from_secs_f64((1.0 - tokens) / rate)),
+        }
+    }
+}
+
+/// Outcome of counting a request against a bucket.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimitDecision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// Time until the bucket is full again.
+    pub reset_after: Duration,
+    /// Time until the next request would be allowed, for rejected requests.
+    pub retry_after: Option<Duration>,
+}
+
+/// Storage for token buckets.
+pub trait RateLimitStore: Send + Sync + 'static {
+    /// Takes one token from the bucket `key`, creating it full if it does not exist.
+    fn take(
+        &self,
+        key: &str,
+        quota: &Quota,
+    ) -> impl Future<Output = anyhow::Result<RateLimitDecision>> + Send;
+}
+
+/// Process-local bucket store.
+///
+/// Buckets left alone for a whole period have refilled to capacity, which is how a
+/// missing bucket starts, so they are dropped at most once per period to keep the map
+/// from growing with every principal ever seen.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    buckets: Mutex<Buckets>,
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    tokens: HashMap<String, (f64, Instant)>,
+    swept: Option<Instant>,
+}
+
+impl Buckets {
+    /// Drops the buckets untouched for `period`, unless that was done less than
+    /// `period` ago.
+    fn sweep(&mut self, now: Instant, period: Duration) {
+        if self
+            .swept
+            .is_some_and(|swept| now.duration_since(swept) < period)
+        {
+            return;
+        }
+        self.tokens
+            .retain(|_, (_, updated)| now.duration_since(*updated) < period);
+        self.swept = Some(now);
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl RateLimitStore for MemoryStore {
+    async fn take(&self, key: &str, quota: &Quota) -> anyhow::Result<RateLimitDecision> {
+        let now = Instant::now();
+        let capacity = quota.capacity.get() as f64;
+
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.sweep(now, quota.period);
+        let (tokens, updated) = buckets
+            .tokens
+            .entry(key.to_string())
+            .or_insert((capacity, now));
+
+        *tokens = (*tokens + now.duration_since(*updated).as_secs_f64() * quota.refill_rate())
+            .min(capacity);
+        *updated = now;
+
+        let allowed = *tokens >= 1.0;
+        if allowed {
+            *tokens -= 1.0;
+        }
+
+        Ok(quota.decision(allowed, *tokens))
+    }
+}
+
+/// Redis backed bucket store for deployments running several nodes.
+#[cfg(feature = "redis")]
+pub struct RedisStore {
+    connection: redis::aio::ConnectionManager,
+}
+
+#[cfg(feature = "redis")]
+impl RedisStore {
+    const SCRIPT: &str = r#"
+        local capacity = tonumber(ARGV[1])
+        local rate = tonumber(ARGV[2])
+        local time = redis.call('TIME')
+        local now = time[1] * 1000 + math.floor(time[2] / 1000)
+        local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'updated')
+        local tokens = tonumber(bucket[1]) or capacity
+        local updated = tonumber(bucket[2]) or now
+        tokens = math.min(capacity, tokens + (now - updated) * rate)
+        local allowed = 0
+        if tokens >= 1 then
+            tokens = tokens - 1
+            allowed = 1
+        end
+        redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'updated', now)
+        redis.call('PEXPIRE', KEYS[1], math.ceil(capacity / rate))
+        return { allowed, tostring(tokens) }
+    "#;
+
+    pub fn new(connection: redis::aio::ConnectionManager) -> Self {
+        Self { connection }
+    }
+}
+
+#[cfg(feature = "redis")]
+impl RateLimitStore for RedisStore {
+    async fn take(&self, key: &str, quota: &Quota) -> anyhow::Result<RateLimitDecision> {
+        let (allowed, tokens): (i32, String) = redis::Script::new(Self::SCRIPT)
+            .key(format!("palmera:ratelimit:{key}"))
+            .arg(quota.capacity.get())
+            .arg(quota.refill_rate() / 1000.0)
+            .invoke_async(&mut self.connection.clone())
+            .await?;
+
+        Ok(quota.decision(allowed == 1, tokens.parse()?))
+    }
+}
+
+/// Source of the principal a request is counted against.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyBy {
+    /// Id of the API key of the request's verified [`JWTClaims`] extension, for tokens
+    /// issued by [`palmera_auth::AuthConfig::issue_api_key_token`].
+    ApiKey,
+    /// Subject of the request's verified [`JWTClaims`] extension.
+    UserId,
+    /// Client address resolved behind trusted proxies (see [`palmera_auth::proxy`]), or
+    /// else the peer address from axum's `ConnectInfo<SocketAddr>`.
+    Ip,
+}
+
+/// Rate limiter for one route group.
+pub struct RateLimiter<S> {
+    group: String,
+    quota: Quota,
+    keys: Vec<KeyBy>,
+    store: S,
+}
+
+impl<S: RateLimitStore> RateLimiter<S> {
+    /// Creates a limiter keyed by API key, then user id, then client IP.
+    pub fn new(group: &str, quota: Quota, store: S) -> Self {
+        Self {
+            group: group.to_string(),
+            quota,
+            keys: vec![KeyBy::ApiKey, KeyBy::UserId, KeyBy::Ip],
+            store,
+        }
+    }
+
+    /// Replaces the principal sources; the first one present on a request is used.
+    pub fn keyed_by(mut self, keys: Vec<KeyBy>) -> Self {
+        self.keys = keys;
+        self
+    }
+
+    /// Returns the bucket key for `request`.
+    pub fn key(&self, request: &Request) -> String {
+        let principal = self
+            .keys
+            .iter()
+            .find_map(|key| match key {
+                KeyBy::ApiKey => request
+                    .extensions()
+                    .get::<JWTClaims>()
+                    .filter(|claims| claims.scope.is_some())
+                    .and_then(|claims| claims.actor)
+                    .map(|key| format!("key:{}", key.subject)),
+                KeyBy::UserId => request
+                    .extensions()
+                    .get::<JWTClaims>()
+                    .map(|claims| format!("user:{}", claims.subject)),
+                KeyBy::Ip => request
+                    .extensions()
+                    .get::<ClientAddr>()
+                    .map(|client| client.ip)
+                    .or_else(|| {
+                        request
+                            .extensions()
+                            .get::<ConnectInfo<SocketAddr>>()
+                            .map(|ConnectInfo(addr)| addr.ip())
+                    })
+                    .map(|ip| format!("ip:{ip}")),
+            })
+            .unwrap_or_else(|| "anonymous".to_string());
+
+        format!("{}:{}", self.group, principal)
+    }
+
+    /// Counts one request against the bucket `key`.
+    pub async fn check(&self, key: &str) -> anyhow::Result<RateLimitDecision> {
+        self.store.take(key, &self.quota).await
+    }
+}
+
+/// Axum middleware enforcing `limiter` on every request it wraps.
+///
+/// If the store fails (e.g. Redis is unreachable) the request is let through without
+/// rate limit headers rather than taking the API down with it.
+pub async fn rate_limit<S: RateLimitStore>(
+    State(limiter): State<Arc<RateLimiter<S>>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let key = limiter.key(&request);
+    let Ok(decision) = limiter.check(&key).await else {
+        return next.run(request).await;
+    };
+
+    let mut response = if decision.allowed {
+        next.run(request).await
+    } else {
+        StatusCode::TOO_MANY_REQUESTS.into_response()
+    };
+
+    insert_headers(response.headers_mut(), &decision);
+
+    response
+}
+
+fn insert_headers(headers: &mut HeaderMap, decision: &RateLimitDecision) {
+    headers.insert("ratelimit-limit", HeaderValue::from(decision.limit));
+    headers.insert("ratelimit-remaining", HeaderValue::from(decision.remaining));
+    headers.insert(
+        "ratelimit-reset",
+        HeaderValue::from(decision.reset_after.as_secs_f64().ceil() as u64),
+    );
+    if let Some(retry_after) = decision.retry_after {
+        headers.insert(
+            "retry-after",
+            HeaderValue::from(retry_after.as_secs_f64().ceil().max(1.0) as u64),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Router, body::Body, middleware, routing::get};
+    use tower::ServiceExt;
+
+    fn limited_router(capacity: u32) -> Router {
+        let limiter = Arc::new(RateLimiter::new(
+            "test",
+            Quota::new(capacity, Duration::from_secs(60)).unwrap(),
+            MemoryStore::new(),
+        ));
+        Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(middleware::from_fn_with_state(
+                limiter,
+                rate_limit::<MemoryStore>,
+            ))
+    }
+
+    fn claims(subject: u128) -> JWTClaims {
+        JWTClaims::new(
+            uuid::Uuid::from_u128(subject),
+            chrono::Duration::minutes(1),
+            "issuer".to_string(),
+            "audience".to_string(),
+        )
+    }
+
+    /// A request authenticated with the token of the API key `key` of user 1.
+    fn request_with_key(key: u128) -> Request {
+        let mut request = Request::builder().uri("/").body(Body::empty()).unwrap();
+        request.extensions_mut().insert(
+            claims(1)
+                .with_scopes(palmera_auth::scope::Scopes::new().with("read"))
+                .with_actor(uuid::Uuid::from_u128(key)),
+        );
+        request
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_token_bucket() {
+        let store = MemoryStore::new();
+        let quota = Quota::new(2, Duration::from_secs(60)).unwrap();
+
+        let first = store.take("k", &quota).await.unwrap();
+        assert!(first.allowed);
+        assert_eq!(first.remaining, 1);
+        assert!(store.take("k", &quota).await.unwrap().allowed);
+
+        let third = store.take("k", &quota).await.unwrap();
+        assert!(!third.allowed);
+        assert!(third.retry_after.unwrap() > Duration::from_secs(29));
+
+        assert!(store.take("other", &quota).await.unwrap().allowed);
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_drops_idle_buckets() {
+        let store = MemoryStore::new();
+        let quota = Quota::new(1, Duration::from_millis(50)).unwrap();
+
+        for key in ["a", "b", "c"] {
+            store.take(key, &quota).await.unwrap();
+        }
+        assert_eq!(store.buckets.lock().unwrap().tokens.len(), 3);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        let decision = store.take("d", &quota).await.unwrap();
+        assert!(decision.allowed);
+        let buckets = store.buckets.lock().unwrap();
+        assert_eq!(buckets.tokens.keys().collect::<Vec<_>>(), ["d"]);
+    }
+
+    #[test]
+    fn test_zero_quota() {
+        assert!(Quota::new(0, Duration::from_secs(60)).is_err());
+        assert!(Quota::new(1, Duration::ZERO).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_middleware_sets_headers_and_rejects() {
+        let app = limited_router(1);
+
+        let ok = app.clone().oneshot(request_with_key(2)).await.unwrap();
+        assert_eq!(ok.status(), StatusCode::OK);
+        assert_eq!(ok.headers()["ratelimit-limit"], "1");
+        assert_eq!(ok.headers()["ratelimit-remaining"], "0");
+
+        let limited = app.clone().oneshot(request_with_key(2)).await.unwrap();
+        assert_eq!(limited.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(limited.headers().contains_key("retry-after"));
+
+        let other = app.oneshot(request_with_key(3)).await.unwrap();
+        assert_eq!(other.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_key_prefers_api_key_then_user() {
+        let limiter = RateLimiter::new(
+            "api",
+            Quota::new(1, Duration::from_secs(1)).unwrap(),
+            MemoryStore::new(),
+        );
+        assert_eq!(
+            limiter.key(&request_with_key(2)),
+            format!("api:key:{}", uuid::Uuid::from_u128(2))
+        );
+
+        let mut request = Request::new(Body::empty());
+        request.extensions_mut().insert(claims(1));
+        assert_eq!(
+            limiter.key(&request),
+            format!("api:user:{}", uuid::Uuid::from_u128(1))
+        );
+
+        // Unverified headers don't pick the bucket.
+        let mut request = Request::builder()
+            .header("x-api-key", "made-up")
+            .body(Body::empty())
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 4000))));
+        assert_eq!(limiter.key(&request), "api:ip:10.0.0.1");
+
+        assert_eq!(limiter.key(&Request::new(Body::empty())), "api:anonymous");
+    }
+
+    #[test]
+    fn test_key_prefers_resolved_client_ip() {
+        let limiter = RateLimiter::new(
+            "api",
+            Quota::new(1, Duration::from_secs(1)).unwrap(),
+            MemoryStore::new(),
+        );
+        let mut request = Request::new(Body::empty());
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 4000))));
+        assert_eq!(limiter.key(&request), "api:ip:10.0.0.1");
+
+        request.extensions_mut().insert(ClientAddr {
+            ip: [203, 0, 113, 9].into(),
+            https: Some(true),
+            host: None,
+            prefix: None,
+        });
+        assert_eq!(limiter.key(&request), "api:ip:203.0.113.9");
+    }
+}
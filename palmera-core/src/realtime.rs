@@ -0,0 +1,266 @@
+//! # Realtime channels
+//!
+//! [`Realtime`] is an in-process hub of named broadcast channels. Clients subscribe to a
+//! channel with their (optional) JWT claims; every bound `on_subscribe` handler is asked
+//! to authorize the subscription and any handler returning an error denies it. With no
+//! handlers bound, subscriptions are allowed.
+//!
+//! Each channel tracks presence: which members are currently subscribed. Joins and
+//! leaves are broadcast to the channel as [`RealtimeMessage::Join`] and
+//! [`RealtimeMessage::Leave`], and [`Realtime::presence`] returns the current members.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use palmera_auth::jwt::JWTClaims;
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use crate::{events::SubscribeEvent, hook::Hook};
+
+/// Number of undelivered messages a channel buffers per subscriber before lagging.
+const CHANNEL_CAPACITY: usize = 256;
+
+/// A member currently present in a channel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PresenceMember {
+    /// Connection-independent member id (e.g. the user id or a client generated id).
+    pub id: String,
+    /// Subject of the claims the member subscribed with, if authenticated.
+    pub subject: Option<Uuid>,
+    /// Number of open subscriptions for this member, e.g. one per browser tab.
+    pub connections: usize,
+}
+
+/// A message delivered to channel subscribers.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RealtimeMessage {
+    /// Application payload published to the channel.
+    Broadcast(String),
+    /// A member joined the channel.
+    Join(PresenceMember),
+    /// A member's last subscription to the channel closed.
+    Leave(PresenceMember),
+}
+
+struct Channel {
+    sender: broadcast::Sender<RealtimeMessage>,
+    members: HashMap<String, PresenceMember>,
+}
+
+type Channels = Arc<Mutex<HashMap<String, Channel>>>;
+
+pub struct Realtime {
+    channels: Channels,
+    /// Authorizes subscriptions. Returning an error from any handler denies the subscription.
+    pub on_subscribe: Hook<SubscribeEvent>,
+}
+
+impl Default for Realtime {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Realtime {
+    pub fn new() -> Self {
+        Self {
+            channels: Arc::new(Mutex::new(HashMap::new())),
+            on_subscribe: Hook::new(),
+        }
+    }
+
+    /// Subscribes `member_id` to `channel` after running the `on_subscribe` handlers.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first handler error if the subscription is denied.
+    pub async fn subscribe(
+        &self,
+        channel: &str,
+        member_id: &str,
+        claims: Option<JWTClaims>,
+    ) -> anyhow::Result<Subscription> {
+        let event = SubscribeEvent::new(channel, member_id, claims);
+
+        for result in self.on_subscribe.trigger(&event).await {
+            result?;
+        }
+
+        let mut channels = self.channels.lock().unwrap();
+        let entry = channels
+            .entry(channel.to_string())
+            .or_insert_with(|| Channel {
+                sender: broadcast::channel(CHANNEL_CAPACITY).0,
+                members: HashMap::new(),
+            });
+
+        let receiver = entry.sender.subscribe();
+
+        let member = entry
+            .members
+            .entry(member_id.to_string())
+            .or_insert_with(|| PresenceMember {
+                id: member_id.to_string(),
+                subject: event.claims().map(|claims| claims.subject),
+                connections: 0,
+            });
+        member.connections += 1;
+
+        if member.connections == 1 {
+            let _ = entry.sender.send(RealtimeMessage::Join(member.clone()));
+        }
+
+        Ok(Subscription {
+            channel: channel.to_string(),
+            member_id: member_id.to_string(),
+            receiver,
+            channels: self.channels.clone(),
+        })
+    }
+
+    /// Publishes `payload` to every subscriber of `channel`, returning how many received it.
+    pub fn publish(&self, channel: &str, payload: &str) -> usize {
+        self.channels
+            .lock()
+            .unwrap()
+            .get(channel)
+            .and_then(|channel| {
+                channel
+                    .sender
+                    .send(RealtimeMessage::Broadcast(payload.to_string()))
+                    .ok()
+            })
+            .unwrap_or(0)
+    }
+
+    /// Returns the members currently present in `channel`, ordered by id.
+    pub fn presence(&self, channel: &str) -> Vec<PresenceMember> {
+        let mut members: Vec<PresenceMember> = self
+            .channels
+            .lock()
+            .unwrap()
+            .get(channel)
+            .map(|channel| channel.members.values().cloned().collect())
+            .unwrap_or_default();
+        members.sort_by(|a, b| a.id.cmp(&b.id));
+        members
+    }
+}
+
+/// An open subscription to a channel. Dropping it leaves the channel.
+pub struct Subscription {
+    channel: String,
+    member_id: String,
+    receiver: broadcast::Receiver<RealtimeMessage>,
+    channels: Channels,
+}
+
+impl Subscription {
+    pub fn channel(&self) -> &str {
+        &self.channel
+    }
+
+    /// Waits for the next message on the channel.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the subscriber lagged behind and messages were dropped.
+    pub async fn recv(&mut self) -> Result<RealtimeMessage, broadcast::error::RecvError> {
+        self.receiver.recv().await
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut channels = self.channels.lock().unwrap();
+        let Some(channel) = channels.get_mut(&self.channel) else {
+            return;
+        };
+
+        if let Some(member) = channel.members.get_mut(&self.member_id) {
+            member.connections -= 1;
+            if member.connections == 0 {
+                let member = channel.members.remove(&self.member_id).unwrap();
+                let _ = channel.sender.send(RealtimeMessage::Leave(member));
+            }
+        }
+
+        if channel.members.is_empty() {
+            channels.remove(&self.channel);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future;
+
+    #[tokio::test]
+    async fn test_presence_tracks_members() {
+        let realtime = Realtime::new();
+        let first = realtime.subscribe("room", "alice", None).await.unwrap();
+        let second = realtime.subscribe("room", "alice", None).await.unwrap();
+        let _bob = realtime.subscribe("room", "bob", None).await.unwrap();
+
+        let presence = realtime.presence("room");
+        assert_eq!(presence.len(), 2);
+        assert_eq!(presence[0].id, "alice");
+        assert_eq!(presence[0].connections, 2);
+
+        drop(first);
+        assert_eq!(realtime.presence("room")[0].connections, 1);
+        drop(second);
+        assert_eq!(realtime.presence("room").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_join_leave_and_broadcast_messages() {
+        let realtime = Realtime::new();
+        let mut watcher = realtime.subscribe("room", "watcher", None).await.unwrap();
+        assert!(matches!(watcher.recv().await, Ok(RealtimeMessage::Join(m)) if m.id == "watcher"));
+
+        let bob = realtime.subscribe("room", "bob", None).await.unwrap();
+        assert!(matches!(watcher.recv().await, Ok(RealtimeMessage::Join(m)) if m.id == "bob"));
+
+        assert_eq!(realtime.publish("room", "hello"), 2);
+        assert_eq!(
+            watcher.recv().await.unwrap(),
+            RealtimeMessage::Broadcast("hello".to_string())
+        );
+
+        drop(bob);
+        assert!(matches!(watcher.recv().await, Ok(RealtimeMessage::Leave(m)) if m.id == "bob"));
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_denied_by_handler() {
+        let mut realtime = Realtime::new();
+        realtime.on_subscribe.bind_fn(|event: &SubscribeEvent| {
+            let result = if event.channel().starts_with("private:") && event.claims().is_none() {
+                Err(anyhow::anyhow!("Not allowed to join {}", event.channel()))
+            } else {
+                Ok(event.clone())
+            };
+            Box::pin(future::ready(result))
+        });
+
+        assert!(
+            realtime
+                .subscribe("private:ops", "anon", None)
+                .await
+                .is_err()
+        );
+        assert!(realtime.presence("private:ops").is_empty());
+        assert!(realtime.subscribe("public", "anon", None).await.is_ok());
+    }
+
+    #[test]
+    fn test_publish_to_empty_channel() {
+        let realtime = Realtime::new();
+        assert_eq!(realtime.publish("nobody", "hi"), 0);
+    }
+}
@@ -0,0 +1,169 @@
+//! # Request IDs
+//!
+//! Every request the app serves gets a [`RequestId`]: the caller's `X-Request-Id` if it
+//! sent a usable one, otherwise a fresh UUID. The id is
+//!
+//! - written back to the request's `X-Request-Id` header, so handlers in crates that
+//!   don't depend on Palmera's core can read it,
+//! - added as a request extension and exposed as
+//!   [`RequestContext::request_id`](crate::intercept::RequestContext::request_id),
+//! - recorded on a `request` tracing span wrapping the handler, so logs of the handler
+//!   and the hooks it triggers carry it,
+//! - available to code running for the request through [`RequestId::current`], e.g. to
+//!   set it on outbound webhook calls or store it with queued jobs, and
+//! - returned in the response's `X-Request-Id` header.
+//!
+//! Work spawned onto other tasks keeps the id with [`RequestId::scope`].
+
+use std::future::Future;
+
+use axum::{
+    extract::Request,
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use tracing::Instrument;
+use uuid::Uuid;
+
+/// Header carrying the request id, both on requests and responses.
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest request id accepted from a caller.
+const MAX_LENGTH: usize = 128;
+
+tokio::task_local! {
+    static CURRENT: RequestId;
+}
+
+/// Identifies a request across logs, hooks, jobs and webhooks.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RequestId(String);
+
+impl RequestId {
+    /// A fresh random id.
+    pub fn new() -> Self {
+        Self(Uuid::new_v4().to_string())
+    }
+
+    /// Accepts a caller supplied id of 1 to 128 visible ASCII characters.
+    pub fn parse(id: &str) -> Option<Self> {
+        let valid = !id.is_empty()
+            && id.len() <= MAX_LENGTH
+            && id.bytes().all(|byte| byte.is_ascii_graphic());
+        valid.then(|| Self(id.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Id of the request the current task is serving, if any.
+    pub fn current() -> Option<Self> {
+        CURRENT.try_with(Clone::clone).ok()
+    }
+
+    /// Runs `future` with this id as [`RequestId::current`], e.g. for a task spawned
+    /// while serving the request.
+    pub fn scope<F: Future>(self, future: F) -> impl Future<Output = F::Output> {
+        let span = tracing::info_span!("request", request_id = %self);
+        CURRENT.scope(self, future.instrument(span))
+    }
+}
+
+impl Default for RequestId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Axum middleware assigning each request its [`RequestId`].
+pub async fn propagate(mut request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(RequestId::parse)
+        .unwrap_or_default();
+    // Only visible ASCII gets here, which is always a valid header value.
+    let value = HeaderValue::from_str(id.as_str()).expect("request id is a valid header");
+
+    request
+        .headers_mut()
+        .insert(REQUEST_ID_HEADER, value.clone());
+    request.extensions_mut().insert(id.clone());
+
+    let mut response = id.scope(next.run(request)).await;
+    response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Extension, Router, body::Body, middleware, routing::get};
+    use tower::ServiceExt;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(RequestId::parse("abc-123").unwrap().as_str(), "abc-123");
+        assert!(RequestId::parse("").is_none());
+        assert!(RequestId::parse("has space").is_none());
+        assert!(RequestId::parse(&"a".repeat(MAX_LENGTH + 1)).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_propagate() {
+        let app = Router::new()
+            .route(
+                "/",
+                get(|Extension(id): Extension<RequestId>| async move {
+                    // Spawned work keeps the id through `scope`.
+                    let current = RequestId::current().unwrap();
+                    let spawned =
+                        tokio::spawn(current.clone().scope(async { RequestId::current() }))
+                            .await
+                            .unwrap();
+                    assert_eq!(spawned, Some(current.clone()));
+                    assert_eq!(current, id);
+                    id.to_string()
+                }),
+            )
+            .layer(middleware::from_fn(propagate));
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::get("/")
+                    .header("x-request-id", "trace-1")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.headers()["x-request-id"], "trace-1");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "trace-1");
+
+        let response = app
+            .oneshot(
+                Request::get("/")
+                    .header("x-request-id", "not valid")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let generated = response.headers()["x-request-id"].to_str().unwrap();
+        assert!(Uuid::parse_str(generated).is_ok());
+        assert!(RequestId::current().is_none());
+    }
+}
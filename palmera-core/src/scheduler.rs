@@ -0,0 +1,359 @@
+//! # Work scheduling
+//!
+//! Exports, imports, backups and thumbnail batches each hold a database connection or a
+//! lot of memory for a long time. [`WorkScheduler`] caps how many operations of each
+//! category run at once; further ones wait in a bounded queue, and once that is full they
+//! are turned away with [`WorkError::QueueFull`], answered as `503 Service Unavailable`
+//! with `Retry-After`. A burst of export requests then waits its turn instead of taking
+//! every connection the API needs.
+//!
+//! Work runs through [`WorkScheduler::run`], or, for routes, behind the [`limit_work`]
+//! middleware, which keeps the slot until a streamed response body has been sent.
+//! Categories without a [`WorkLimit`] aren't limited.
+//!
+//! An [`App`](crate::base::App) holds the scheduler configured with
+//! [`AppBuilder::work_limit`](crate::builder::AppBuilder::work_limit):
+//! [`App::mount_work`](crate::base::App::mount_work) serves routes behind
+//! [`limit_work`], and [`App::work_scheduler`](crate::base::App::work_scheduler) runs
+//! other work, such as backups started by a plugin.
+//!
+//! # Example
+//!
+//! ```rust
+//! use std::time::Duration;
+//! use axum::{Router, routing::get};
+//! use palmera_auth::AuthConfig;
+//! use palmera_core::{base::App, scheduler::WorkLimit};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let mut app = App::builder()
+//!     .database("sqlite::memory:")
+//!     .auth(AuthConfig::builder().secret("a-secret-that-is-at-least-32-bytes-long").build()?)
+//!     .work_limit("export", WorkLimit::new(2, 10)?)
+//!     .work_limit(
+//!         "backup",
+//!         WorkLimit::new(1, 1)?.with_queue_timeout(Duration::from_secs(30)),
+//!     )
+//!     .build()?;
+//! app.mount_work(
+//!     "/export",
+//!     Router::new().route("/", get(|| async { "rows" })),
+//!     "export",
+//! );
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    num::NonZeroUsize,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{HeaderValue, StatusCode, header::RETRY_AFTER},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use futures::StreamExt;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Seconds rejected callers are asked to wait before retrying.
+const RETRY_AFTER_SECS: u32 = 5;
+
+/// How many operations of a category run at once and how many may wait.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WorkLimit {
+    pub concurrency: NonZeroUsize,
+    /// Operations waiting for a slot before new ones are rejected.
+    pub queue: usize,
+    /// Longest an operation waits for a slot, unbounded when `None`.
+    pub queue_timeout: Option<Duration>,
+}
+
+impl WorkLimit {
+    /// Runs `concurrency` operations at once, with `queue` more waiting.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `concurrency` is zero, since no operation could ever run.
+    pub fn new(concurrency: usize, queue: usize) -> anyhow::Result<Self> {
+        let Some(concurrency) = NonZeroUsize::new(concurrency) else {
+            anyhow::bail!("work concurrency must be at least 1");
+        };
+        Ok(Self {
+            concurrency,
+            queue,
+            queue_timeout: None,
+        })
+    }
+
+    pub fn with_queue_timeout(mut self, timeout: Duration) -> Self {
+        self.queue_timeout = Some(timeout);
+        self
+    }
+}
+
+/// Error returned when an operation doesn't get a slot.
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum WorkError {
+    #[error("too many {0} operations are queued")]
+    QueueFull(String),
+    #[error("timed out waiting to run a {0} operation")]
+    TimedOut(String),
+}
+
+impl IntoResponse for WorkError {
+    fn into_response(self) -> Response {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(RETRY_AFTER, HeaderValue::from(RETRY_AFTER_SECS))],
+            self.to_string(),
+        )
+            .into_response()
+    }
+}
+
+/// Load of a category, e.g. for a status page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorkStats {
+    pub category: String,
+    pub limit: WorkLimit,
+    pub running: usize,
+    pub queued: usize,
+}
+
+#[derive(Debug)]
+struct Category {
+    limit: WorkLimit,
+    slots: Arc<Semaphore>,
+    queued: AtomicUsize,
+}
+
+/// Holds a slot of a category until dropped.
+#[derive(Debug)]
+pub struct WorkPermit {
+    _slot: Option<OwnedSemaphorePermit>,
+}
+
+/// Removes an operation from its queue when it stops waiting, including when the
+/// waiting future is dropped.
+struct Queued<'a>(&'a AtomicUsize);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Limits concurrent expensive operations per category.
+#[derive(Debug, Default)]
+pub struct WorkScheduler {
+    categories: HashMap<String, Category>,
+}
+
+impl WorkScheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limits the operations of `category`.
+    pub fn with_category(mut self, category: &str, limit: WorkLimit) -> Self {
+        self.categories.insert(
+            category.to_string(),
+            Category {
+                limit,
+                slots: Arc::new(Semaphore::new(limit.concurrency.get())),
+                queued: AtomicUsize::new(0),
+            },
+        );
+        self
+    }
+
+    /// Waits for a slot of `category`, queueing if every slot is taken.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`WorkError::QueueFull`] right away if the queue is full, and
+    /// [`WorkError::TimedOut`] if the category's queue timeout passes first.
+    pub async fn acquire(&self, category: &str) -> Result<WorkPermit, WorkError> {
+        let Some(limits) = self.categories.get(category) else {
+            return Ok(WorkPermit { _slot: None });
+        };
+        if let Ok(slot) = limits.slots.clone().try_acquire_owned() {
+            return Ok(WorkPermit { _slot: Some(slot) });
+        }
+
+        limits
+            .queued
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |queued| {
+                (queued < limits.limit.queue).then_some(queued + 1)
+            })
+            .map_err(|_| WorkError::QueueFull(category.to_string()))?;
+        let _queued = Queued(&limits.queued);
+
+        let slot = limits.slots.clone().acquire_owned();
+        let slot = match limits.limit.queue_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, slot)
+                .await
+                .map_err(|_| WorkError::TimedOut(category.to_string()))?,
+            None => slot.await,
+        };
+        let slot = slot.expect("work slots are never closed");
+        Ok(WorkPermit { _slot: Some(slot) })
+    }
+
+    /// Runs `work` in a slot of `category`.
+    ///
+    /// # Errors
+    ///
+    /// See [`acquire`](Self::acquire); `work` isn't started then.
+    pub async fn run<F: Future>(&self, category: &str, work: F) -> Result<F::Output, WorkError> {
+        let _permit = self.acquire(category).await?;
+        Ok(work.await)
+    }
+
+    /// Load of every limited category, ordered by name.
+    pub fn stats(&self) -> Vec<WorkStats> {
+        let mut stats = self
+            .categories
+            .iter()
+            .map(|(category, limits)| WorkStats {
+                category: category.clone(),
+                limit: limits.limit,
+                running: limits.limit.concurrency.get() - limits.slots.available_permits(),
+                queued: limits.queued.load(Ordering::SeqCst),
+            })
+            .collect::<Vec<_>>();
+        stats.sort_by(|a, b| a.category.cmp(&b.category));
+        stats
+    }
+}
+
+/// Axum middleware running the requests it wraps as work of a category.
+///
+/// The slot is held until the response body has been sent, so exports streaming their
+/// rows count as running for as long as they stream.
+pub async fn limit_work(
+    State((scheduler, category)): State<(Arc<WorkScheduler>, &'static str)>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let permit = match scheduler.acquire(category).await {
+        Ok(permit) => permit,
+        Err(err) => return err.into_response(),
+    };
+
+    next.run(request).await.map(|body| {
+        Body::from_stream(body.into_data_stream().map(move |chunk| {
+            let _ = &permit;
+            chunk
+        }))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Router, middleware, routing::get};
+    use tokio::sync::oneshot;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_run_queues_and_rejects() {
+        let scheduler =
+            Arc::new(WorkScheduler::new().with_category("export", WorkLimit::new(1, 1).unwrap()));
+        let (release, released) = oneshot::channel::<()>();
+
+        let running = tokio::spawn({
+            let scheduler = scheduler.clone();
+            async move { scheduler.run("export", released).await }
+        });
+        tokio::task::yield_now().await;
+        let queued = tokio::spawn({
+            let scheduler = scheduler.clone();
+            async move { scheduler.run("export", async { "queued" }).await }
+        });
+        tokio::task::yield_now().await;
+
+        let stats = &scheduler.stats()[0];
+        assert_eq!((stats.running, stats.queued), (1, 1));
+        assert_eq!(
+            scheduler.run("export", async {}).await,
+            Err(WorkError::QueueFull("export".to_string()))
+        );
+        // Other categories aren't limited.
+        assert_eq!(scheduler.run("backup", async { 1 }).await, Ok(1));
+
+        release.send(()).unwrap();
+        assert!(running.await.unwrap().is_ok());
+        assert_eq!(queued.await.unwrap(), Ok("queued"));
+        let stats = &scheduler.stats()[0];
+        assert_eq!((stats.running, stats.queued), (0, 0));
+    }
+
+    #[test]
+    fn test_zero_concurrency() {
+        assert!(WorkLimit::new(0, 10).is_err());
+        assert_eq!(WorkLimit::new(1, 0).unwrap().concurrency.get(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_queue_timeout() {
+        let scheduler = WorkScheduler::new().with_category(
+            "backup",
+            WorkLimit::new(1, 5)
+                .unwrap()
+                .with_queue_timeout(Duration::from_secs(1)),
+        );
+        let _permit = scheduler.acquire("backup").await.unwrap();
+
+        assert_eq!(
+            scheduler.acquire("backup").await.unwrap_err(),
+            WorkError::TimedOut("backup".to_string())
+        );
+        assert_eq!(scheduler.stats()[0].queued, 0);
+    }
+
+    #[tokio::test]
+    async fn test_limit_work() {
+        let scheduler =
+            Arc::new(WorkScheduler::new().with_category("export", WorkLimit::new(1, 0).unwrap()));
+        let app = Router::new().route("/", get(|| async { "rows" })).layer(
+            middleware::from_fn_with_state((scheduler.clone(), "export"), limit_work),
+        );
+
+        let response = app
+            .clone()
+            .oneshot(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // The unsent body still holds the slot.
+        let busy = app
+            .clone()
+            .oneshot(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(busy.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(busy.headers()["retry-after"], "5");
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "rows");
+        let response = app
+            .oneshot(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,423 @@
+//! # Secrets
+//!
+//! Secrets such as the JWT signing key, the SMTP password or S3 credentials don't
+//! have to live in plain configuration. A [`SecretProvider`] looks them up by name, and
+//! [`Secrets`] chains providers: the first one knowing a secret wins.
+//!
+//! - [`EnvSecrets`] reads environment variables, e.g. `PALMERA_AUTH_SECRET`.
+//! - [`FileSecrets`] reads one file per secret, as mounted by Docker and Kubernetes.
+//! - `VaultSecrets` reads a HashiCorp Vault KV v2 secret (feature `vault`).
+//! - `AwsSecrets` reads AWS Secrets Manager (feature `aws`).
+//!
+//! Secrets set with [`AppBuilder::secrets`](crate::builder::AppBuilder::secrets) are
+//! resolved each time the app starts serving, including on
+//! [`AppHandle::restart`](crate::base::AppHandle::restart), so a rotated secret is
+//! picked up by restarting. Resolved secrets replace the configured
+//! [`AUTH_SECRET`], [`S3_ACCESS_KEY`] and [`S3_SECRET_KEY`]; secrets no provider knows
+//! keep their configured value.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! use palmera_core::secrets::{EnvSecrets, FileSecrets, SMTP_PASSWORD, Secrets};
+//!
+//! # async fn run() -> anyhow::Result<()> {
+//! let secrets = Secrets::new()
+//!     .with(FileSecrets::new("/run/secrets"))
+//!     .with(EnvSecrets::new());
+//!
+//! let password = secrets.require(SMTP_PASSWORD).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{future::Future, path::PathBuf, pin::Pin};
+
+use anyhow::anyhow;
+
+#[cfg(feature = "aws")]
+use crate::aws::AwsCredentials;
+
+/// Signing secret of the [`AuthConfig`](palmera_auth::AuthConfig).
+pub const AUTH_SECRET: &str = "auth_secret";
+/// Password of the SMTP server used by the [`Mailer`](crate::mailer::Mailer).
+pub const SMTP_PASSWORD: &str = "smtp_password";
+/// Access key of [`StorageConfig::S3`](crate::base::StorageConfig::S3).
+pub const S3_ACCESS_KEY: &str = "s3_access_key";
+/// Secret key of [`StorageConfig::S3`](crate::base::StorageConfig::S3).
+pub const S3_SECRET_KEY: &str = "s3_secret_key";
+
+pub type SecretFuture<'a> =
+    Pin<Box<dyn Future<Output = anyhow::Result<Option<String>>> + Send + 'a>>;
+
+/// Looks up secrets by name.
+pub trait SecretProvider: Send + Sync {
+    /// Name of the provider, used in error messages.
+    fn name(&self) -> &str;
+
+    /// Returns the secret called `key`, or `None` if the provider doesn't know it.
+    fn get<'a>(&'a self, key: &'a str) -> SecretFuture<'a>;
+}
+
+/// Chain of [`SecretProvider`]s, asked in the order they were added.
+#[derive(Default)]
+pub struct Secrets {
+    providers: Vec<Box<dyn SecretProvider>>,
+}
+
+impl Secrets {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with<P: SecretProvider + 'static>(mut self, provider: P) -> Self {
+        self.providers.push(Box::new(provider));
+        self
+    }
+
+    /// Returns the secret from the first provider that knows it. Fails if a provider
+    /// fails before one returns the secret.
+    pub async fn get(&self, key: &str) -> anyhow::Result<Option<String>> {
+        for provider in &self.providers {
+            let value = provider
+                .get(key)
+                .await
+                .map_err(|err| anyhow!("{} failed to read `{}`: {}", provider.name(), key, err))?;
+
+            if value.is_some() {
+                return Ok(value);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Like [`Self::get`], failing if no provider knows the secret.
+    pub async fn require(&self, key: &str) -> anyhow::Result<String> {
+        self.get(key)
+            .await?
+            .ok_or_else(|| anyhow!("Secret `{}` is not set", key))
+    }
+}
+
+impl std::fmt::Debug for Secrets {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names: Vec<_> = self.providers.iter().map(|p| p.name()).collect();
+        f.debug_struct("Secrets")
+            .field("providers", &names)
+            .finish()
+    }
+}
+
+/// Reads secrets from environment variables named after the upper-cased key with a
+/// prefix, `PALMERA_` by default: [`AUTH_SECRET`] is read from `PALMERA_AUTH_SECRET`.
+#[derive(Debug, Clone)]
+pub struct EnvSecrets {
+    prefix: String,
+}
+
+impl EnvSecrets {
+    pub fn new() -> Self {
+        Self::with_prefix("PALMERA_")
+    }
+
+    pub fn with_prefix(prefix: &str) -> Self {
+        Self {
+            prefix: prefix.to_string(),
+        }
+    }
+
+    /// Name of the variable holding `key`.
+    pub fn variable(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key.to_uppercase())
+    }
+}
+
+impl Default for EnvSecrets {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SecretProvider for EnvSecrets {
+    fn name(&self) -> &str {
+        "env"
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> SecretFuture<'a> {
+        let value = std::env::var(self.variable(key)).ok();
+        Box::pin(async move { Ok(value) })
+    }
+}
+
+/// Reads each secret from the file named after its key in a directory, e.g.
+/// `/run/secrets/auth_secret`. Trailing newlines are removed.
+#[derive(Debug, Clone)]
+pub struct FileSecrets {
+    dir: PathBuf,
+}
+
+impl FileSecrets {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+
+impl SecretProvider for FileSecrets {
+    fn name(&self) -> &str {
+        "file"
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> SecretFuture<'a> {
+        Box::pin(async move {
+            match tokio::fs::read_to_string(self.dir.join(key)).await {
+                Ok(value) => Ok(Some(value.trim_end_matches(['\r', '\n']).to_string())),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(err) => Err(err.into()),
+            }
+        })
+    }
+}
+
+/// Reads secrets from the fields of one HashiCorp Vault KV v2 secret.
+#[cfg(feature = "vault")]
+#[derive(Clone)]
+pub struct VaultSecrets {
+    client: reqwest::Client,
+    address: String,
+    token: String,
+    mount: String,
+    path: String,
+}
+
+#[cfg(feature = "vault")]
+impl VaultSecrets {
+    /// Reads the secret at `path` of the `secret` mount on the Vault server at
+    /// `address`, e.g. `https://vault.example.com:8200`.
+    pub fn new(address: &str, token: &str, path: &str) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            address: address.trim_end_matches('/').to_string(),
+            token: token.to_string(),
+            mount: "secret".to_string(),
+            path: path.trim_matches('/').to_string(),
+        }
+    }
+
+    /// Uses the KV engine mounted at `mount` instead of `secret`.
+    pub fn with_mount(mut self, mount: &str) -> Self {
+        self.mount = mount.trim_matches('/').to_string();
+        self
+    }
+}
+
+#[cfg(feature = "vault")]
+impl std::fmt::Debug for VaultSecrets {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VaultSecrets")
+            .field("address", &self.address)
+            .field("mount", &self.mount)
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "vault")]
+impl SecretProvider for VaultSecrets {
+    fn name(&self) -> &str {
+        "vault"
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> SecretFuture<'a> {
+        Box::pin(async move {
+            let url = format!("{}/v1/{}/data/{}", self.address, self.mount, self.path);
+            let response = self
+                .client
+                .get(url)
+                .header("X-Vault-Token", &self.token)
+                .send()
+                .await?;
+
+            if response.status() == reqwest::StatusCode::NOT_FOUND {
+                return Ok(None);
+            }
+            let body: serde_json::Value = response.error_for_status()?.json().await?;
+
+            Ok(body["data"]["data"][key].as_str().map(str::to_string))
+        })
+    }
+}
+
+/// Reads secrets from AWS Secrets Manager, each key being a secret id with an optional
+/// prefix, e.g. `palmera/auth_secret`.
+#[cfg(feature = "aws")]
+#[derive(Clone)]
+pub struct AwsSecrets {
+    client: reqwest::Client,
+    credentials: AwsCredentials,
+    prefix: String,
+}
+
+#[cfg(feature = "aws")]
+impl AwsSecrets {
+    pub fn new(region: &str, access_key: &str, secret_key: &str) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            credentials: AwsCredentials::new(region, access_key, secret_key),
+            prefix: String::new(),
+        }
+    }
+
+    /// Uses the credentials of `AWS_REGION`, `AWS_ACCESS_KEY_ID`,
+    /// `AWS_SECRET_ACCESS_KEY` and the optional `AWS_SESSION_TOKEN`.
+    pub fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            client: reqwest::Client::new(),
+            credentials: AwsCredentials::from_env()?,
+            prefix: String::new(),
+        })
+    }
+
+    /// Temporary credentials' session token.
+    pub fn with_session_token(mut self, token: &str) -> Self {
+        self.credentials.session_token = Some(token.to_string());
+        self
+    }
+
+    /// Prepended to keys to form secret ids, e.g. `palmera/`.
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.to_string();
+        self
+    }
+
+    /// Headers of a signature version 4 signed Secrets Manager request.
+    fn signed_headers(
+        &self,
+        target: &str,
+        payload: &str,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Vec<(String, String)> {
+        let headers = vec![
+            (
+                "content-type".to_string(),
+                "application/x-amz-json-1.1".to_string(),
+            ),
+            (
+                "host".to_string(),
+                format!("secretsmanager.{}.amazonaws.com", self.credentials.region),
+            ),
+            ("x-amz-target".to_string(), target.to_string()),
+        ];
+        self.credentials
+            .sign("secretsmanager", "/", headers, payload, now)
+    }
+}
+
+#[cfg(feature = "aws")]
+impl std::fmt::Debug for AwsSecrets {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AwsSecrets")
+            .field("region", &self.credentials.region)
+            .field("access_key", &self.credentials.access_key)
+            .field("prefix", &self.prefix)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "aws")]
+impl SecretProvider for AwsSecrets {
+    fn name(&self) -> &str {
+        "aws"
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> SecretFuture<'a> {
+        Box::pin(async move {
+            let payload =
+                serde_json::json!({ "SecretId": format!("{}{}", self.prefix, key) }).to_string();
+            let url = format!(
+                "https://secretsmanager.{}.amazonaws.com/",
+                self.credentials.region
+            );
+
+            let mut request = self.client.post(url);
+            for (name, value) in self.signed_headers(
+                "secretsmanager.GetSecretValue",
+                &payload,
+                chrono::Utc::now(),
+            ) {
+                if name != "host" {
+                    request = request.header(name, value);
+                }
+            }
+            let response = request.body(payload).send().await?;
+
+            let status = response.status();
+            let body: serde_json::Value = response.json().await?;
+            if !status.is_success() {
+                let kind = body["__type"].as_str().unwrap_or_default();
+                if kind.ends_with("ResourceNotFoundException") {
+                    return Ok(None);
+                }
+                anyhow::bail!("{} {}", status, kind);
+            }
+
+            Ok(body["SecretString"].as_str().map(str::to_string))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    struct MapSecrets(HashMap<&'static str, &'static str>);
+
+    impl SecretProvider for MapSecrets {
+        fn name(&self) -> &str {
+            "map"
+        }
+
+        fn get<'a>(&'a self, key: &'a str) -> SecretFuture<'a> {
+            let value = self.0.get(key).map(|v| v.to_string());
+            Box::pin(async move { Ok(value) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_chain() {
+        let dir = std::env::temp_dir().join(format!("palmera-secrets-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        tokio::fs::write(dir.join(AUTH_SECRET), "from-file\n")
+            .await
+            .unwrap();
+
+        let secrets = Secrets::new()
+            .with(FileSecrets::new(&dir))
+            .with(MapSecrets(HashMap::from([
+                (AUTH_SECRET, "from-map"),
+                (SMTP_PASSWORD, "hunter2"),
+            ])));
+
+        assert_eq!(
+            secrets.get(AUTH_SECRET).await.unwrap().as_deref(),
+            Some("from-file")
+        );
+        assert_eq!(secrets.require(SMTP_PASSWORD).await.unwrap(), "hunter2");
+        assert!(secrets.get(S3_SECRET_KEY).await.unwrap().is_none());
+        assert!(secrets.require(S3_SECRET_KEY).await.is_err());
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[test]
+    fn test_env_variable() {
+        assert_eq!(
+            EnvSecrets::new().variable(AUTH_SECRET),
+            "PALMERA_AUTH_SECRET"
+        );
+        assert_eq!(
+            EnvSecrets::with_prefix("APP_").variable(S3_ACCESS_KEY),
+            "APP_S3_ACCESS_KEY"
+        );
+    }
+}
@@ -0,0 +1,339 @@
+//! # Admin statistics
+//!
+//! `GET /admin/stats` gathers the numbers behind dashboard widgets in one response.
+//! Each section comes from a source registered with [`AdminStats::with_source`], such
+//! as the record counts of `palmera_database::stats`, the signups of
+//! `palmera_auth::admin::signups_per_day` or the files and bytes of each storage bucket
+//! from `palmera_storage::bucket::Buckets::usage`. Sources run concurrently; one that
+//! fails is reported as `{"error": "..."}` in its section instead of failing the whole
+//! response.
+//!
+//! Request volume comes from [`RequestMetrics`], which counts the responses passing
+//! through [`record_requests`] per minute and keeps the last [`RequestMetrics::retention`].
+//!
+//! Like the other admin routers, [`AdminStats::router`] does no authorization of its own
+//! and is expected to be mounted behind the application's admin guard.
+//!
+//! # Example
+//!
+//! ```rust
+//! use std::{sync::Arc, time::Duration};
+//! use axum::{Router, middleware, routing::get};
+//! use palmera_core::stats::{AdminStats, RequestMetrics, record_requests};
+//!
+//! let metrics = Arc::new(RequestMetrics::new());
+//! let stats = AdminStats::new()
+//!     .with_requests(metrics.clone(), Duration::from_secs(24 * 60 * 60))
+//!     .with_source("storage", || async {
+//!         Ok(serde_json::json!([{ "bucket": "avatars", "files": 2, "bytes": 1024 }]))
+//!     });
+//!
+//! let (stats, _api) = stats.router().split_for_parts();
+//! let app: Router = Router::new()
+//!     .route("/", get(|| async { "ok" }))
+//!     .merge(stats)
+//!     .layer(middleware::from_fn_with_state(metrics, record_requests));
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    Extension, Json,
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+use serde_json::{Map, Value, json};
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+/// Request counts of one minute, by status class.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RequestCounts {
+    pub total: u64,
+    /// Responses with a 4xx status.
+    pub client_errors: u64,
+    /// Responses with a 5xx status.
+    pub server_errors: u64,
+}
+
+impl RequestCounts {
+    fn count(&mut self, status: StatusCode) {
+        self.total += 1;
+        if status.is_client_error() {
+            self.client_errors += 1;
+        } else if status.is_server_error() {
+            self.server_errors += 1;
+        }
+    }
+
+    fn add(&mut self, other: &RequestCounts) {
+        self.total += other.total;
+        self.client_errors += other.client_errors;
+        self.server_errors += other.server_errors;
+    }
+}
+
+/// Per-minute request counts, kept in memory for [`RequestMetrics::retention`].
+#[derive(Debug)]
+pub struct RequestMetrics {
+    retention: Duration,
+    /// Counts keyed by the minute since the Unix epoch.
+    minutes: Mutex<BTreeMap<u64, RequestCounts>>,
+}
+
+impl Default for RequestMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RequestMetrics {
+    /// Metrics keeping the last 24 hours.
+    pub fn new() -> Self {
+        Self {
+            retention: Duration::from_secs(24 * 60 * 60),
+            minutes: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn with_retention(mut self, retention: Duration) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    pub fn retention(&self) -> Duration {
+        self.retention
+    }
+
+    /// Counts a response with `status`.
+    pub fn record(&self, status: StatusCode) {
+        self.record_at(current_minute(), status);
+    }
+
+    fn record_at(&self, minute: u64, status: StatusCode) {
+        let oldest = minute.saturating_sub(self.retention.as_secs() / 60);
+        let mut minutes = self.minutes.lock().unwrap();
+        minutes.entry(minute).or_default().count(status);
+        // Retention is enforced on write, so idle metrics don't need a cleanup task.
+        *minutes = minutes.split_off(&oldest);
+    }
+
+    /// Summarizes the requests of the last `window`.
+    pub fn summary(&self, window: Duration) -> RequestSummary {
+        self.summary_at(current_minute(), window)
+    }
+
+    fn summary_at(&self, now: u64, window: Duration) -> RequestSummary {
+        let since = now.saturating_sub(window.as_secs() / 60);
+        let minutes = self.minutes.lock().unwrap();
+
+        let mut summary = RequestSummary::default();
+        for (&minute, counts) in minutes.range(since..=now) {
+            summary.totals.add(counts);
+            summary.per_minute.push((minute * 60, counts.total));
+        }
+        summary
+    }
+}
+
+/// Request volume over a window, as returned by [`RequestMetrics::summary`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RequestSummary {
+    pub totals: RequestCounts,
+    /// Request count per minute that saw requests, keyed by the minute's Unix timestamp.
+    pub per_minute: Vec<(u64, u64)>,
+}
+
+impl RequestSummary {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "total": self.totals.total,
+            "client_errors": self.totals.client_errors,
+            "server_errors": self.totals.server_errors,
+            "per_minute": self
+                .per_minute
+                .iter()
+                .map(|(minute, count)| json!({ "minute": minute, "count": count }))
+                .collect::<Vec<_>>(),
+        })
+    }
+}
+
+fn current_minute() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / 60
+}
+
+/// Axum middleware counting every response in `metrics`.
+pub async fn record_requests(
+    State(metrics): State<Arc<RequestMetrics>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let response = next.run(request).await;
+    metrics.record(response.status());
+    response
+}
+
+type SourceFuture = Pin<Box<dyn Future<Output = anyhow::Result<Value>> + Send>>;
+type Source = Arc<dyn Fn() -> SourceFuture + Send + Sync>;
+
+/// Sections served by `GET /admin/stats`.
+#[derive(Clone, Default)]
+pub struct AdminStats {
+    sources: Vec<(String, Source)>,
+}
+
+impl std::fmt::Debug for AdminStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AdminStats")
+            .field(
+                "sources",
+                &self
+                    .sources
+                    .iter()
+                    .map(|(name, _)| name)
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl AdminStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the section `name`, filled with the value `source` resolves to on each
+    /// request. A section registered again replaces the earlier one.
+    pub fn with_source<F, Fut>(mut self, name: impl Into<String>, source: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<Value>> + Send + 'static,
+    {
+        let name = name.into();
+        self.sources.retain(|(existing, _)| *existing != name);
+        self.sources
+            .push((name, Arc::new(move || Box::pin(source()))));
+        self
+    }
+
+    /// Adds the `requests` section: the summary of `metrics` over the last `window`.
+    pub fn with_requests(self, metrics: Arc<RequestMetrics>, window: Duration) -> Self {
+        self.with_source("requests", move || {
+            let summary = metrics.summary(window);
+            async move { Ok(summary.to_json()) }
+        })
+    }
+
+    /// Collects every section.
+    pub async fn collect(&self) -> Map<String, Value> {
+        let values =
+            futures::future::join_all(self.sources.iter().map(|(_, source)| source())).await;
+
+        self.sources
+            .iter()
+            .zip(values)
+            .map(|((name, _), value)| {
+                let value = value.unwrap_or_else(|err| {
+                    tracing::warn!(section = %name, "failed to collect admin stats: {err:#}");
+                    json!({ "error": err.to_string() })
+                });
+                (name.clone(), value)
+            })
+            .collect()
+    }
+
+    /// Router serving `GET /admin/stats`.
+    pub fn router(self) -> OpenApiRouter {
+        OpenApiRouter::new()
+            .routes(routes!(stats))
+            .layer(Extension(Arc::new(self)))
+    }
+}
+
+/// Collects the sections of the dashboard, by name.
+#[utoipa::path(get, path = "/admin/stats", responses((status = 200, body = Object)))]
+async fn stats(Extension(stats): Extension<Arc<AdminStats>>) -> Json<Map<String, Value>> {
+    Json(stats.collect().await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use tower::ServiceExt;
+
+    const MINUTE: u64 = 29_000_000;
+
+    #[test]
+    fn test_request_summary() {
+        let metrics = RequestMetrics::new();
+        metrics.record_at(MINUTE - 10, StatusCode::OK);
+        metrics.record_at(MINUTE - 1, StatusCode::OK);
+        metrics.record_at(MINUTE - 1, StatusCode::NOT_FOUND);
+        metrics.record_at(MINUTE, StatusCode::INTERNAL_SERVER_ERROR);
+
+        let summary = metrics.summary_at(MINUTE, Duration::from_secs(5 * 60));
+        assert_eq!(
+            summary.totals,
+            RequestCounts {
+                total: 3,
+                client_errors: 1,
+                server_errors: 1,
+            }
+        );
+        assert_eq!(
+            summary.per_minute,
+            [((MINUTE - 1) * 60, 2), (MINUTE * 60, 1)]
+        );
+    }
+
+    #[test]
+    fn test_request_metrics_retention() {
+        let metrics = RequestMetrics::new().with_retention(Duration::from_secs(60 * 60));
+        metrics.record_at(MINUTE - 61, StatusCode::OK);
+        metrics.record_at(MINUTE, StatusCode::OK);
+
+        let summary = metrics.summary_at(MINUTE, Duration::from_secs(24 * 60 * 60));
+        assert_eq!(summary.totals.total, 1);
+    }
+
+    #[tokio::test]
+    async fn test_admin_stats() -> anyhow::Result<()> {
+        let metrics = Arc::new(RequestMetrics::new());
+        let stats = AdminStats::new()
+            .with_requests(metrics.clone(), Duration::from_secs(60 * 60))
+            .with_source("tables", || async {
+                Ok(json!([{ "table": "posts", "records": 2 }]))
+            })
+            .with_source("storage", || async { anyhow::bail!("bucket unavailable") });
+        let (app, _) = stats.router().split_for_parts();
+        let app = app.layer(axum::middleware::from_fn_with_state(
+            metrics,
+            record_requests,
+        ));
+
+        let request = || Request::get("/admin/stats").body(Body::empty()).unwrap();
+        app.clone().oneshot(request()).await?;
+        let response = app.oneshot(request()).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await?;
+        let body: Value = serde_json::from_slice(&body)?;
+        assert_eq!(body["requests"]["total"], 1);
+        assert_eq!(body["tables"][0]["records"], 2);
+        assert_eq!(body["storage"], json!({ "error": "bucket unavailable" }));
+        Ok(())
+    }
+}
@@ -0,0 +1,1892 @@
+//! # Database admin endpoints
+//!
+//! Diagnostic routes for operators. The router does not perform any authorization of
+//! its own and is expected to be mounted behind the application's admin guard.
+//!
+//! Besides diagnostics it serves `/admin/settings`, which reads and writes the runtime
+//! [`Settings`] store, returning the values of secret settings as `null`, and
+//! `/admin/fields`, which manages the [`Fields`] metadata form builders use to render
+//! inputs such as dropdowns. `/admin/comments` edits the
+//! [`Comments`] of tables and columns, shown as help texts next to those inputs, and
+//! returns those Postgres stores too when the `Pool<Postgres>` extension is set.
+//!
+//! `/admin/tables` creates and alters tables through [`crate::ddl`]. With
+//! `?dry_run=true` these routes return the SQL they would run and its risks without
+//! applying it. Destructive changes, such as dropping a column, are rejected with `428`
+//! unless confirmed with `?force=true`. Renames and type changes that copy data run as
+//! background jobs started with `POST /admin/tables/{table}/column-jobs` and followed at
+//! `/admin/column-jobs`; see [`crate::column_jobs`]. `/admin/tables/{table}/exposure`
+//! edits how a table is served by the REST API; see [`crate::exposure`].
+//! `/admin/stats/tables` counts the records of each table.
+//!
+//! `POST /admin/explain` shows the SQL a filtered and sorted listing runs and the plan
+//! SQLite picks for it, optionally timing the query; see [`crate::explain`].
+//! `/admin/index-suggestions` proposes indexes for the columns queries filter and sort
+//! on most; see [`crate::index_advisor`].
+//!
+//! `/admin/retention` manages the [`Retention`] rules purging expired rows, and
+//! `POST /admin/retention/run` applies them immediately instead of waiting for the
+//! scheduled run.
+//!
+//! `/admin/trash` lists soft-deleted rows across tables, restores them and purges them
+//! for good; see [`crate::trash`]. `/admin/history` turns row history on and off per
+//! table, lists the previous versions of a record and restores one; see
+//! [`crate::history`].
+//!
+//! `/admin/quotas` sets the limits of users and tenants and reports their usage; see
+//! [`crate::quotas`]. `/admin/flags` manages feature flags; see
+//! [`crate::feature_flags`].
+//!
+//! `GET /admin/export-config` and `POST /admin/import-config` move the configuration of
+//! an instance to another one, e.g. from staging to production, leaving secret settings
+//! out unless `?include_secrets=true`; see [`crate::config_bundle`].
+//! `GET /admin/types.ts` generates TypeScript interfaces for the Postgres tables the
+//! REST API serves, read from the `Pool<Postgres>` extension, and
+//! `GET /admin/schemas.json` their OpenAPI schemas, described by their comments; see
+//! [`crate::codegen`].
+//!
+//! The list routes answer in JSON, CSV or NDJSON depending on the `Accept` header; see
+//! [`crate::export`].
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use axum::{
+    Extension, Json,
+    extract::{Path, Query},
+    http::{HeaderMap, StatusCode, header},
+    response::Response,
+};
+use futures::{StreamExt, stream};
+use serde::Deserialize;
+use sqlx::{Pool, Postgres, Sqlite};
+use utoipa::{IntoParams, ToSchema, openapi::Components};
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+use crate::{
+    codegen,
+    column_jobs::{ColumnChange, ColumnJob, ColumnJobs},
+    comments::{Comments, TableComments, postgres_comments},
+    config_bundle::{self, ConfigBundle, ImportReport},
+    constraint::ConstraintViolation,
+    ddl::{self, AlterTable, ColumnType, CreateTable, Migration, SchemaError},
+    error::StoreError,
+    explain::{self, ExplainRequest, Explanation},
+    export::{self, Format},
+    exposure::{Exposures, TableExposure},
+    feature_flags::{FeatureFlag, FeatureFlags, FlagRule},
+    fields::{Field, FieldKind, Fields},
+    history::{History, HistoryEntry},
+    index_advisor::{DEFAULT_MIN_USES, IndexSuggestion, QueryUsage},
+    instrument::{SlowQuery, SlowQueryLog},
+    manifest::TableManifest,
+    postgres::session::SessionContext,
+    quotas::{QuotaLimits, QuotaUsage, Quotas, SubjectLimits},
+    retention::{PurgeReport, Retention, RetentionAction, RetentionRule, TimestampFormat},
+    settings::{Setting, Settings},
+    statement_cache::{StatementCache, StatementCacheStats},
+    stats::{TableCount, sqlite_table_counts},
+    trash::{Trash, TrashFilter, TrashedRecord},
+};
+
+/// Lists recorded slow queries, slowest first.
+#[utoipa::path(
+    get,
+    path = "/admin/slow-queries",
+    responses(
+        (status = 200, content(
+            (Vec<SlowQuery> = "application/json"),
+            (String = "text/csv"),
+            (String = "application/x-ndjson")
+        )),
+        (status = 406)
+    )
+)]
+async fn list_slow_queries(
+    Extension(log): Extension<Arc<SlowQueryLog>>,
+    format: Format,
+) -> Response {
+    let entries = log
+        .entries()
+        .into_iter()
+        .map(Ok::<_, std::convert::Infallible>);
+    export::rows(format, stream::iter(entries))
+}
+
+/// Clears the slow query log.
+#[utoipa::path(delete, path = "/admin/slow-queries", responses((status = 204)))]
+async fn clear_slow_queries(Extension(log): Extension<Arc<SlowQueryLog>>) -> StatusCode {
+    log.clear();
+    StatusCode::NO_CONTENT
+}
+
+/// Returns hit rate and size of the dynamic statement cache.
+#[utoipa::path(get, path = "/admin/statement-cache", responses((status = 200, body = StatementCacheStats)))]
+async fn statement_cache_stats(
+    Extension(cache): Extension<Arc<StatementCache>>,
+) -> Json<StatementCacheStats> {
+    Json(cache.stats())
+}
+
+/// Lists every setting, ordered by key. Secret values are left out, see
+/// [`Setting::redacted`].
+#[utoipa::path(
+    get,
+    path = "/admin/settings",
+    responses(
+        (status = 200, content(
+            (Vec<Setting> = "application/json"),
+            (String = "text/csv"),
+            (String = "application/x-ndjson")
+        )),
+        (status = 406)
+    )
+)]
+async fn list_settings(Extension(settings): Extension<Arc<Settings>>, format: Format) -> Response {
+    export::rows(
+        format,
+        settings
+            .stream()
+            .map(|setting| setting.map(Setting::redacted)),
+    )
+}
+
+/// Returns a single setting, without its value if it is a secret.
+#[utoipa::path(get, path = "/admin/settings/{key}", responses((status = 200, body = Setting), (status = 404)))]
+async fn get_setting(
+    Extension(settings): Extension<Arc<Settings>>,
+    Path(key): Path<String>,
+) -> Result<Json<Setting>, StatusCode> {
+    settings
+        .find(&key)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map(|setting| Json(setting.redacted()))
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Creates or replaces a setting with the JSON request body. The response leaves out
+/// the value of a secret.
+#[utoipa::path(put, path = "/admin/settings/{key}", request_body = Object, responses((status = 200, body = Setting)))]
+async fn put_setting(
+    Extension(settings): Extension<Arc<Settings>>,
+    Path(key): Path<String>,
+    Json(value): Json<serde_json::Value>,
+) -> Result<Json<Setting>, StatusCode> {
+    settings
+        .set_value(&key, value)
+        .await
+        .map(|setting| Json(setting.redacted()))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Removes a setting.
+#[utoipa::path(delete, path = "/admin/settings/{key}", responses((status = 204), (status = 404)))]
+async fn delete_setting(
+    Extension(settings): Extension<Arc<Settings>>,
+    Path(key): Path<String>,
+) -> StatusCode {
+    match settings.remove(&key).await {
+        Ok(true) => StatusCode::NO_CONTENT,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Lists the managed fields of a table, ordered by column.
+#[utoipa::path(get, path = "/admin/fields/{table}", responses((status = 200, body = Vec<Field>)))]
+async fn list_fields(
+    Extension(fields): Extension<Arc<Fields>>,
+    Path(table): Path<String>,
+) -> Result<Json<Vec<Field>>, StatusCode> {
+    fields
+        .for_table(&table)
+        .await
+        .map(|table| Json(table.fields().to_vec()))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Defines or replaces a managed field.
+#[utoipa::path(
+    put,
+    path = "/admin/fields/{table}/{column}",
+    request_body = FieldKind,
+    responses((status = 200, body = Field), (status = 400))
+)]
+async fn put_field(
+    Extension(fields): Extension<Arc<Fields>>,
+    Path((table, column)): Path<(String, String)>,
+    Json(kind): Json<FieldKind>,
+) -> Result<Json<Field>, StoreError> {
+    fields.define(&table, &column, kind).await.map(Json)
+}
+
+/// Removes a managed field definition. The column itself is left untouched.
+#[utoipa::path(delete, path = "/admin/fields/{table}/{column}", responses((status = 204), (status = 404)))]
+async fn delete_field(
+    Extension(fields): Extension<Arc<Fields>>,
+    Path((table, column)): Path<(String, String)>,
+) -> StatusCode {
+    match fields.remove(&table, &column).await {
+        Ok(true) => StatusCode::NO_CONTENT,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Body of the routes setting a comment.
+#[derive(Debug, Deserialize, ToSchema)]
+struct CommentPayload {
+    comment: String,
+}
+
+/// The comments of `table`: those edited in `comments`, completed with those Postgres
+/// stores when `db` is set.
+async fn table_comments(
+    comments: &Comments,
+    db: Option<&Pool<Postgres>>,
+    table: &str,
+) -> Result<Json<TableComments>, StatusCode> {
+    let mut merged = comments
+        .for_table(table)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if let Some(db) = db {
+        let stored = postgres_comments(db, table).await.map_err(|err| {
+            tracing::error!(%err, "reading the Postgres comments failed");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        merged = merged.or(stored);
+    }
+    Ok(Json(merged))
+}
+
+/// Returns the comments of a table and its columns, including those stored by Postgres.
+#[utoipa::path(get, path = "/admin/comments/{table}", responses((status = 200, body = TableComments)))]
+async fn get_comments(
+    Extension(comments): Extension<Arc<Comments>>,
+    db: Option<Extension<Pool<Postgres>>>,
+    Path(table): Path<String>,
+) -> Result<Json<TableComments>, StatusCode> {
+    table_comments(&comments, db.as_deref(), &table).await
+}
+
+async fn set_comment(
+    comments: &Comments,
+    db: Option<&Pool<Postgres>>,
+    table: &str,
+    column: Option<&str>,
+    comment: &str,
+) -> Result<Json<TableComments>, StatusCode> {
+    comments
+        .set(table, column, comment)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    table_comments(comments, db, table).await
+}
+
+async fn remove_comment(comments: &Comments, table: &str, column: Option<&str>) -> StatusCode {
+    match comments.remove(table, column).await {
+        Ok(true) => StatusCode::NO_CONTENT,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Sets the comment of a table, returning the table's comments.
+#[utoipa::path(
+    put,
+    path = "/admin/comments/{table}",
+    request_body = CommentPayload,
+    responses((status = 200, body = TableComments))
+)]
+async fn put_table_comment(
+    Extension(comments): Extension<Arc<Comments>>,
+    db: Option<Extension<Pool<Postgres>>>,
+    Path(table): Path<String>,
+    Json(payload): Json<CommentPayload>,
+) -> Result<Json<TableComments>, StatusCode> {
+    set_comment(&comments, db.as_deref(), &table, None, &payload.comment).await
+}
+
+/// Removes the comment of a table.
+#[utoipa::path(delete, path = "/admin/comments/{table}", responses((status = 204), (status = 404)))]
+async fn delete_table_comment(
+    Extension(comments): Extension<Arc<Comments>>,
+    Path(table): Path<String>,
+) -> StatusCode {
+    remove_comment(&comments, &table, None).await
+}
+
+/// Sets the comment of a column, returning the table's comments.
+#[utoipa::path(
+    put,
+    path = "/admin/comments/{table}/{column}",
+    request_body = CommentPayload,
+    responses((status = 200, body = TableComments))
+)]
+async fn put_column_comment(
+    Extension(comments): Extension<Arc<Comments>>,
+    db: Option<Extension<Pool<Postgres>>>,
+    Path((table, column)): Path<(String, String)>,
+    Json(payload): Json<CommentPayload>,
+) -> Result<Json<TableComments>, StatusCode> {
+    set_comment(
+        &comments,
+        db.as_deref(),
+        &table,
+        Some(&column),
+        &payload.comment,
+    )
+    .await
+}
+
+/// Removes the comment of a column.
+#[utoipa::path(delete, path = "/admin/comments/{table}/{column}", responses((status = 204), (status = 404)))]
+async fn delete_column_comment(
+    Extension(comments): Extension<Arc<Comments>>,
+    Path((table, column)): Path<(String, String)>,
+) -> StatusCode {
+    remove_comment(&comments, &table, Some(&column)).await
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+struct ApplyParams {
+    /// Return the planned migration without applying it.
+    #[serde(default)]
+    dry_run: bool,
+    /// Confirm a destructive migration.
+    #[serde(default)]
+    force: bool,
+}
+
+async fn migrate(
+    db: &Pool<Sqlite>,
+    migration: Result<Migration, SchemaError>,
+    params: ApplyParams,
+    applied: StatusCode,
+) -> Result<(StatusCode, Json<Migration>), StatusCode> {
+    let migration = migration.map_err(|err| err.status())?;
+    if params.dry_run {
+        return Ok((StatusCode::OK, Json(migration)));
+    }
+    migration.check(params.force).map_err(|err| err.status())?;
+
+    ddl::apply(db, &migration).await.map_err(|err| {
+        tracing::warn!(%err, "schema migration failed");
+        match err {
+            // Statements that fail on the current data, e.g. a NOT NULL column without a
+            // default on a non-empty table.
+            SchemaError::Database(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            err => err.status(),
+        }
+    })?;
+    Ok((applied, Json(migration)))
+}
+
+/// Creates a table, or with `dry_run` returns the SQL that would create it.
+#[utoipa::path(
+    post,
+    path = "/admin/tables",
+    params(ApplyParams),
+    request_body = CreateTable,
+    responses(
+        (status = 200, description = "Dry run", body = Migration),
+        (status = 201, body = Migration),
+        (status = 400),
+        (status = 409)
+    )
+)]
+async fn create_table(
+    Extension(db): Extension<Pool<Sqlite>>,
+    Query(params): Query<ApplyParams>,
+    Json(table): Json<CreateTable>,
+) -> Result<(StatusCode, Json<Migration>), StatusCode> {
+    let migration = ddl::plan_create(&db, &table).await;
+    migrate(&db, migration, params, StatusCode::CREATED).await
+}
+
+/// Alters a table, or with `dry_run` returns the SQL that would alter it and the risks of
+/// each statement.
+#[utoipa::path(
+    patch,
+    path = "/admin/tables/{table}",
+    params(ApplyParams),
+    request_body = AlterTable,
+    responses(
+        (status = 200, body = Migration),
+        (status = 400),
+        (status = 404),
+        (status = 409),
+        (status = 422),
+        (status = 428)
+    )
+)]
+async fn alter_table(
+    Extension(db): Extension<Pool<Sqlite>>,
+    Path(table): Path<String>,
+    Query(params): Query<ApplyParams>,
+    Json(change): Json<AlterTable>,
+) -> Result<(StatusCode, Json<Migration>), StatusCode> {
+    let migration = ddl::plan_alter(&db, &table, &change).await;
+    migrate(&db, migration, params, StatusCode::OK).await
+}
+
+/// Returns how a table is served by the REST API.
+#[utoipa::path(get, path = "/admin/tables/{table}/exposure", responses((status = 200, body = TableExposure)))]
+async fn get_exposure(
+    Extension(exposures): Extension<Arc<Exposures>>,
+    Path(table): Path<String>,
+) -> Json<TableExposure> {
+    Json(
+        exposures
+            .get(&table)
+            .unwrap_or_else(|| TableExposure::new(&table)),
+    )
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+struct ExposureSettings {
+    enabled: bool,
+    read_only: bool,
+    alias: Option<String>,
+}
+
+/// Changes how a table is served by the REST API.
+#[utoipa::path(
+    put,
+    path = "/admin/tables/{table}/exposure",
+    request_body = ExposureSettings,
+    responses((status = 200, body = TableExposure), (status = 400), (status = 409))
+)]
+async fn put_exposure(
+    Extension(exposures): Extension<Arc<Exposures>>,
+    Path(table): Path<String>,
+    Json(settings): Json<ExposureSettings>,
+) -> Result<Json<TableExposure>, StatusCode> {
+    let exposure = TableExposure {
+        table_name: table,
+        enabled: settings.enabled,
+        read_only: settings.read_only,
+        alias: settings.alias,
+    };
+
+    exposures
+        .set(exposure)
+        .await
+        .map(Json)
+        .map_err(|err| match err {
+            StoreError::Database(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+                StatusCode::CONFLICT
+            }
+            err => err.status(),
+        })
+}
+
+/// Restores the default exposure of a table.
+#[utoipa::path(delete, path = "/admin/tables/{table}/exposure", responses((status = 204), (status = 404)))]
+async fn delete_exposure(
+    Extension(exposures): Extension<Arc<Exposures>>,
+    Path(table): Path<String>,
+) -> StatusCode {
+    match exposures.remove(&table).await {
+        Ok(true) => StatusCode::NO_CONTENT,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+struct ColumnJobSettings {
+    column: String,
+    rename_to: Option<String>,
+    data_type: Option<ColumnType>,
+    using: Option<String>,
+    batch_size: Option<u32>,
+}
+
+/// Starts renaming or changing the type of a column on a background job that copies
+/// its data.
+#[utoipa::path(
+    post,
+    path = "/admin/tables/{table}/column-jobs",
+    request_body = ColumnJobSettings,
+    responses(
+        (status = 202, body = ColumnJob),
+        (status = 400),
+        (status = 404),
+        (status = 409)
+    )
+)]
+async fn start_column_job(
+    Extension(jobs): Extension<Arc<ColumnJobs>>,
+    Path(table): Path<String>,
+    headers: HeaderMap,
+    Json(settings): Json<ColumnJobSettings>,
+) -> Result<(StatusCode, Json<ColumnJob>), StatusCode> {
+    let change = ColumnChange {
+        table_name: table,
+        column: settings.column,
+        rename_to: settings.rename_to,
+        data_type: settings.data_type,
+        using: settings.using,
+        batch_size: settings
+            .batch_size
+            .unwrap_or(crate::column_jobs::DEFAULT_BATCH_SIZE),
+    };
+
+    let request_id = headers
+        .get("x-request-id")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+
+    let job = jobs
+        .start(change, request_id)
+        .await
+        .map_err(|err| err.status())?;
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+/// Lists column migration jobs and their progress, most recent first.
+#[utoipa::path(get, path = "/admin/column-jobs", responses((status = 200, body = Vec<ColumnJob>)))]
+async fn list_column_jobs(
+    Extension(jobs): Extension<Arc<ColumnJobs>>,
+) -> Result<Json<Vec<ColumnJob>>, StatusCode> {
+    jobs.list()
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Returns a column migration job and its progress.
+#[utoipa::path(
+    get,
+    path = "/admin/column-jobs/{id}",
+    responses((status = 200, body = ColumnJob), (status = 404))
+)]
+async fn get_column_job(
+    Extension(jobs): Extension<Arc<ColumnJobs>>,
+    Path(id): Path<i64>,
+) -> Result<Json<ColumnJob>, StatusCode> {
+    jobs.get(id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Resumes a failed or interrupted column migration job from the step it stopped at.
+#[utoipa::path(
+    post,
+    path = "/admin/column-jobs/{id}/resume",
+    responses((status = 202, body = ColumnJob), (status = 404))
+)]
+async fn resume_column_job(
+    Extension(jobs): Extension<Arc<ColumnJobs>>,
+    Path(id): Path<i64>,
+) -> Result<(StatusCode, Json<ColumnJob>), StatusCode> {
+    jobs.resume(id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map(|job| (StatusCode::ACCEPTED, Json(job)))
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Counts the records of each table.
+#[utoipa::path(get, path = "/admin/stats/tables", responses((status = 200, body = Vec<TableCount>)))]
+async fn table_stats(
+    Extension(db): Extension<Pool<Sqlite>>,
+) -> Result<Json<Vec<TableCount>>, StatusCode> {
+    sqlite_table_counts(&db)
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Shows the SQL and query plan of a filtered, sorted table listing.
+#[utoipa::path(
+    post,
+    path = "/admin/explain",
+    request_body = ExplainRequest,
+    responses((status = 200, body = Explanation), (status = 400))
+)]
+async fn explain_query(
+    Extension(db): Extension<Pool<Sqlite>>,
+    Json(request): Json<ExplainRequest>,
+) -> Result<Json<Explanation>, StatusCode> {
+    explain::explain(&db, &request)
+        .await
+        .map(Json)
+        .map_err(|err| err.status())
+}
+
+/// Query parameters of `/admin/index-suggestions`.
+#[derive(Debug, Clone, Default, Deserialize, IntoParams)]
+struct SuggestionParams {
+    /// Uses of a column before an index is suggested.
+    min_uses: Option<u64>,
+}
+
+/// Suggests indexes for frequently filtered and sorted columns, most used first.
+#[utoipa::path(
+    get,
+    path = "/admin/index-suggestions",
+    params(SuggestionParams),
+    responses((status = 200, body = Vec<IndexSuggestion>))
+)]
+async fn index_suggestions(
+    Extension(usage): Extension<Arc<QueryUsage>>,
+    slow_log: Option<Extension<Arc<SlowQueryLog>>>,
+    Query(params): Query<SuggestionParams>,
+) -> Result<Json<Vec<IndexSuggestion>>, StatusCode> {
+    let slow_queries = slow_log
+        .map(|Extension(log)| log.entries())
+        .unwrap_or_default();
+    usage
+        .suggestions(&slow_queries, params.min_uses.unwrap_or(DEFAULT_MIN_USES))
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Lists the retention rules, ordered by table.
+#[utoipa::path(get, path = "/admin/retention", responses((status = 200, body = Vec<RetentionRule>)))]
+async fn list_retention(
+    Extension(retention): Extension<Arc<Retention>>,
+) -> Result<Json<Vec<RetentionRule>>, StatusCode> {
+    retention
+        .list()
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+struct RetentionSettings {
+    timestamp_column: String,
+    #[serde(default)]
+    timestamp_format: TimestampFormat,
+    max_age_days: u32,
+    #[serde(default)]
+    action: RetentionAction,
+    batch_size: Option<u32>,
+    #[serde(default = "enabled_by_default")]
+    enabled: bool,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+/// Creates or replaces the retention rule of a table.
+#[utoipa::path(
+    put,
+    path = "/admin/retention/{table}",
+    request_body = RetentionSettings,
+    responses((status = 200, body = RetentionRule), (status = 400))
+)]
+async fn put_retention(
+    Extension(retention): Extension<Arc<Retention>>,
+    Path(table): Path<String>,
+    Json(settings): Json<RetentionSettings>,
+) -> Result<Json<RetentionRule>, StatusCode> {
+    let mut rule = RetentionRule::new(&table, &settings.timestamp_column, settings.max_age_days)
+        .with_format(settings.timestamp_format)
+        .with_action(settings.action)
+        .with_enabled(settings.enabled);
+    if let Some(batch_size) = settings.batch_size {
+        rule = rule.with_batch_size(batch_size);
+    }
+
+    retention
+        .set(rule)
+        .await
+        .map(Json)
+        .map_err(|err| err.status())
+}
+
+/// Removes the retention rule of a table.
+#[utoipa::path(delete, path = "/admin/retention/{table}", responses((status = 204), (status = 404)))]
+async fn delete_retention(
+    Extension(retention): Extension<Arc<Retention>>,
+    Path(table): Path<String>,
+) -> StatusCode {
+    match retention.remove(&table).await {
+        Ok(true) => StatusCode::NO_CONTENT,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Applies the enabled retention rules now.
+#[utoipa::path(post, path = "/admin/retention/run", responses((status = 200, body = Vec<PurgeReport>)))]
+async fn run_retention(
+    Extension(retention): Extension<Arc<Retention>>,
+) -> Result<Json<Vec<PurgeReport>>, StatusCode> {
+    retention
+        .run(chrono::Utc::now())
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Lists soft-deleted rows, most recently deleted first.
+#[utoipa::path(
+    get,
+    path = "/admin/trash",
+    params(TrashFilter),
+    responses((status = 200, body = Vec<TrashedRecord>), (status = 400))
+)]
+async fn list_trash(
+    Extension(trash): Extension<Arc<Trash>>,
+    Query(filter): Query<TrashFilter>,
+) -> Result<Json<Vec<TrashedRecord>>, StatusCode> {
+    trash.list(&filter).await.map(Json).map_err(store_status)
+}
+
+/// Restores a soft-deleted row.
+#[utoipa::path(
+    post,
+    path = "/admin/trash/{table}/{id}/restore",
+    responses((status = 204), (status = 400), (status = 404))
+)]
+async fn restore_trash(
+    Extension(trash): Extension<Arc<Trash>>,
+    Path((table, id)): Path<(String, String)>,
+) -> StatusCode {
+    match trash.restore(&table, &id).await {
+        Ok(true) => StatusCode::NO_CONTENT,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(err) => store_status(err),
+    }
+}
+
+/// Permanently deletes a soft-deleted row.
+#[utoipa::path(
+    delete,
+    path = "/admin/trash/{table}/{id}",
+    responses((status = 204), (status = 400), (status = 404))
+)]
+async fn purge_trash(
+    Extension(trash): Extension<Arc<Trash>>,
+    Path((table, id)): Path<(String, String)>,
+) -> StatusCode {
+    match trash.purge(&table, &id).await {
+        Ok(true) => StatusCode::NO_CONTENT,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(err) => store_status(err),
+    }
+}
+
+/// Lists the tables whose row history is tracked.
+#[utoipa::path(get, path = "/admin/history", responses((status = 200, body = Vec<String>)))]
+async fn list_history_tables(
+    Extension(history): Extension<Arc<History>>,
+) -> Result<Json<Vec<String>>, StatusCode> {
+    history
+        .tables()
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Starts tracking the row history of a table.
+#[utoipa::path(
+    put,
+    path = "/admin/history/{table}",
+    responses((status = 204), (status = 400))
+)]
+async fn enable_history(
+    Extension(history): Extension<Arc<History>>,
+    Path(table): Path<String>,
+) -> StatusCode {
+    match history.enable(&table).await {
+        Ok(()) => StatusCode::NO_CONTENT,
+        Err(err) => store_status(err),
+    }
+}
+
+/// Stops tracking the row history of a table, keeping the recorded history.
+#[utoipa::path(
+    delete,
+    path = "/admin/history/{table}",
+    responses((status = 204), (status = 404))
+)]
+async fn disable_history(
+    Extension(history): Extension<Arc<History>>,
+    Path(table): Path<String>,
+) -> StatusCode {
+    match history.disable(&table).await {
+        Ok(true) => StatusCode::NO_CONTENT,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(err) => store_status(err.into()),
+    }
+}
+
+/// Lists the previous versions of a record, newest first.
+#[utoipa::path(
+    get,
+    path = "/admin/history/{table}/{id}",
+    responses((status = 200, body = Vec<HistoryEntry>), (status = 400))
+)]
+async fn list_history(
+    Extension(history): Extension<Arc<History>>,
+    Path((table, id)): Path<(String, String)>,
+) -> Result<Json<Vec<HistoryEntry>>, StatusCode> {
+    history
+        .list(&table, &id)
+        .await
+        .map(Json)
+        .map_err(store_status)
+}
+
+/// Writes a previous version of a record back, recreating the record if it was
+/// deleted, and returns it. The restore is recorded as made by the caller.
+#[utoipa::path(
+    post,
+    path = "/admin/history/{table}/{id}/{version}/restore",
+    responses((status = 200, body = Object), (status = 400), (status = 404))
+)]
+async fn restore_history(
+    Extension(history): Extension<Arc<History>>,
+    session: Option<Extension<SessionContext>>,
+    Path((table, id, version)): Path<(String, String, i64)>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let actor = session
+        .as_ref()
+        .and_then(|Extension(ctx)| ctx.actor_id.as_deref().or(ctx.user_id.as_deref()));
+    match history.restore(&table, &id, version, actor).await {
+        Ok(Some(record)) => Ok(Json(record)),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(err) => Err(store_status(err)),
+    }
+}
+
+fn store_status(err: StoreError) -> StatusCode {
+    match err {
+        // E.g. purging a row other rows still reference.
+        StoreError::Database(err) => ConstraintViolation::from_sqlx(&err)
+            .map(|violation| violation.status())
+            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+        err => err.status(),
+    }
+}
+
+/// Lists the subjects with limits of their own, and the defaults.
+#[utoipa::path(get, path = "/admin/quotas", responses((status = 200, body = Vec<SubjectLimits>)))]
+async fn list_quotas(
+    Extension(quotas): Extension<Arc<Quotas>>,
+) -> Result<Json<Vec<SubjectLimits>>, StatusCode> {
+    quotas
+        .list()
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Reports the usage of a subject next to the limits in effect for it.
+#[utoipa::path(get, path = "/admin/quotas/{subject}", responses((status = 200, body = QuotaUsage)))]
+async fn get_quota(
+    Extension(quotas): Extension<Arc<Quotas>>,
+    Path(subject): Path<String>,
+) -> Result<Json<QuotaUsage>, StatusCode> {
+    quotas
+        .usage(&subject, chrono::Utc::now())
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Sets the limits of a subject, `*` for the defaults.
+#[utoipa::path(
+    put,
+    path = "/admin/quotas/{subject}",
+    request_body = QuotaLimits,
+    responses((status = 200, body = QuotaLimits), (status = 400))
+)]
+async fn put_quota(
+    Extension(quotas): Extension<Arc<Quotas>>,
+    Path(subject): Path<String>,
+    Json(limits): Json<QuotaLimits>,
+) -> Result<Json<QuotaLimits>, StatusCode> {
+    quotas
+        .set_limits(&subject, limits)
+        .await
+        .map(Json)
+        .map_err(|err| err.status())
+}
+
+/// Removes the limits of a subject, which falls back to the defaults.
+#[utoipa::path(delete, path = "/admin/quotas/{subject}", responses((status = 204), (status = 404)))]
+async fn delete_quota(
+    Extension(quotas): Extension<Arc<Quotas>>,
+    Path(subject): Path<String>,
+) -> StatusCode {
+    match quotas.remove_limits(&subject).await {
+        Ok(true) => StatusCode::NO_CONTENT,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Lists the feature flags, ordered by key.
+#[utoipa::path(get, path = "/admin/flags", responses((status = 200, body = Vec<FeatureFlag>)))]
+async fn list_feature_flags(
+    Extension(flags): Extension<Arc<FeatureFlags>>,
+) -> Json<Vec<FeatureFlag>> {
+    Json(flags.list())
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+struct FlagSettings {
+    description: Option<String>,
+    #[serde(default = "enabled_by_default")]
+    enabled: bool,
+    #[serde(default)]
+    rules: Vec<FlagRule>,
+}
+
+/// Creates or replaces a feature flag.
+#[utoipa::path(
+    put,
+    path = "/admin/flags/{key}",
+    request_body = FlagSettings,
+    responses((status = 200, body = FeatureFlag), (status = 400))
+)]
+async fn put_feature_flag(
+    Extension(flags): Extension<Arc<FeatureFlags>>,
+    Path(key): Path<String>,
+    Json(settings): Json<FlagSettings>,
+) -> Result<Json<FeatureFlag>, StatusCode> {
+    let flag = FeatureFlag {
+        key,
+        description: settings.description,
+        enabled: settings.enabled,
+        rules: settings.rules,
+    };
+
+    flags.set(flag).await.map(Json).map_err(|err| err.status())
+}
+
+/// Removes a feature flag, turning it off for everybody.
+#[utoipa::path(delete, path = "/admin/flags/{key}", responses((status = 204), (status = 404)))]
+async fn delete_feature_flag(
+    Extension(flags): Extension<Arc<FeatureFlags>>,
+    Path(key): Path<String>,
+) -> StatusCode {
+    match flags.remove(&key).await {
+        Ok(true) => StatusCode::NO_CONTENT,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, IntoParams)]
+struct ExportParams {
+    /// Include the settings holding credentials.
+    #[serde(default)]
+    include_secrets: bool,
+}
+
+/// Exports the tables, policies, fields and settings of the instance as a bundle to
+/// import into another one. Settings holding credentials are left out unless
+/// `include_secrets` is set.
+#[utoipa::path(
+    get,
+    path = "/admin/export-config",
+    params(ExportParams),
+    responses((status = 200, body = ConfigBundle))
+)]
+async fn export_config(
+    Extension(db): Extension<Pool<Sqlite>>,
+    Query(params): Query<ExportParams>,
+) -> Result<Json<ConfigBundle>, StatusCode> {
+    config_bundle::export(&db, params.include_secrets)
+        .await
+        .map(Json)
+        .map_err(|err| err.status())
+}
+
+/// Imports a configuration bundle, or with `dry_run` returns what importing it would
+/// change.
+#[utoipa::path(
+    post,
+    path = "/admin/import-config",
+    params(ApplyParams),
+    request_body = ConfigBundle,
+    responses(
+        (status = 200, body = ImportReport),
+        (status = 400),
+        (status = 422),
+        (status = 428)
+    )
+)]
+async fn import_config(
+    Extension(db): Extension<Pool<Sqlite>>,
+    Extension(settings): Extension<Arc<Settings>>,
+    Query(params): Query<ApplyParams>,
+    Json(bundle): Json<ConfigBundle>,
+) -> Result<Json<ImportReport>, StatusCode> {
+    let report = if params.dry_run {
+        config_bundle::plan_import(&db, &bundle).await
+    } else {
+        config_bundle::import(&db, &settings, &bundle, params.force).await
+    };
+
+    report.map(Json).map_err(|err| {
+        tracing::warn!(%err, "configuration import failed");
+        match err {
+            SchemaError::Database(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            err => err.status(),
+        }
+    })
+}
+
+/// Query parameters of `/admin/types.ts`.
+#[derive(Debug, Clone, Default, Deserialize, IntoParams)]
+struct TypesParams {
+    /// Comma separated Postgres schemas to generate, `public` by default.
+    schemas: Option<String>,
+}
+
+impl TypesParams {
+    fn schemas(&self) -> Vec<String> {
+        self.schemas
+            .as_deref()
+            .unwrap_or("public")
+            .split(',')
+            .map(|schema| schema.trim().to_string())
+            .filter(|schema| !schema.is_empty())
+            .collect()
+    }
+}
+
+async fn postgres_tables(
+    db: &Pool<Postgres>,
+    params: &TypesParams,
+) -> Result<Vec<TableManifest>, StatusCode> {
+    codegen::postgres_tables(db, &params.schemas())
+        .await
+        .map_err(|err| {
+            tracing::error!(%err, "reading the Postgres catalog failed");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+}
+
+/// Generates TypeScript interfaces for the Postgres tables served by the REST API.
+#[utoipa::path(
+    get,
+    path = "/admin/types.ts",
+    params(TypesParams),
+    responses((status = 200, content_type = "application/typescript", body = String))
+)]
+async fn typescript_types(
+    Extension(db): Extension<Pool<Postgres>>,
+    Extension(exposures): Extension<Arc<Exposures>>,
+    Query(params): Query<TypesParams>,
+) -> Result<([(header::HeaderName, &'static str); 1], String), StatusCode> {
+    let tables = postgres_tables(&db, &params).await?;
+    Ok((
+        [(header::CONTENT_TYPE, "application/typescript")],
+        codegen::typescript(&tables, &exposures),
+    ))
+}
+
+/// Generates the OpenAPI schemas of the Postgres tables served by the REST API, with
+/// the comments of their tables and columns as descriptions.
+#[utoipa::path(
+    get,
+    path = "/admin/schemas.json",
+    params(TypesParams),
+    responses((status = 200, body = Object))
+)]
+async fn openapi_schemas(
+    Extension(db): Extension<Pool<Postgres>>,
+    Extension(exposures): Extension<Arc<Exposures>>,
+    Extension(comments): Extension<Arc<Comments>>,
+    Query(params): Query<TypesParams>,
+) -> Result<Json<Components>, StatusCode> {
+    let tables = postgres_tables(&db, &params).await?;
+    let mut described = BTreeMap::new();
+    for table in &tables {
+        let Json(merged) = table_comments(&comments, Some(&db), &table.name).await?;
+        described.insert(table.name.clone(), merged);
+    }
+    Ok(Json(codegen::openapi(&tables, &exposures, &described)))
+}
+
+pub fn router() -> OpenApiRouter {
+    OpenApiRouter::new()
+        .routes(routes!(list_slow_queries, clear_slow_queries))
+        .routes(routes!(statement_cache_stats))
+        .routes(routes!(list_settings))
+        .routes(routes!(get_setting, put_setting, delete_setting))
+        .routes(routes!(list_fields))
+        .routes(routes!(put_field, delete_field))
+        .routes(routes!(
+            get_comments,
+            put_table_comment,
+            delete_table_comment
+        ))
+        .routes(routes!(put_column_comment, delete_column_comment))
+        .routes(routes!(create_table))
+        .routes(routes!(alter_table))
+        .routes(routes!(start_column_job))
+        .routes(routes!(list_column_jobs))
+        .routes(routes!(get_column_job))
+        .routes(routes!(resume_column_job))
+        .routes(routes!(get_exposure, put_exposure, delete_exposure))
+        .routes(routes!(table_stats))
+        .routes(routes!(explain_query))
+        .routes(routes!(index_suggestions))
+        .routes(routes!(list_retention))
+        .routes(routes!(put_retention, delete_retention))
+        .routes(routes!(run_retention))
+        .routes(routes!(list_trash))
+        .routes(routes!(restore_trash))
+        .routes(routes!(purge_trash))
+        .routes(routes!(list_history_tables))
+        .routes(routes!(enable_history, disable_history))
+        .routes(routes!(list_history))
+        .routes(routes!(restore_history))
+        .routes(routes!(list_quotas))
+        .routes(routes!(get_quota, put_quota, delete_quota))
+        .routes(routes!(list_feature_flags))
+        .routes(routes!(put_feature_flag, delete_feature_flag))
+        .routes(routes!(export_config))
+        .routes(routes!(import_config))
+        .routes(routes!(typescript_types))
+        .routes(routes!(openapi_schemas))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    async fn body_text(response: Response) -> String {
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_list_and_clear_slow_queries() {
+        let log = Arc::new(SlowQueryLog::new(Duration::from_millis(10)));
+        log.record("SELECT 1", &[], Duration::from_millis(20), None);
+
+        let response = list_slow_queries(Extension(log.clone()), Format::Json).await;
+        let entries: Vec<SlowQuery> = serde_json::from_str(&body_text(response).await).unwrap();
+        assert_eq!(entries.len(), 1);
+
+        let status = clear_slow_queries(Extension(log.clone())).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(log.entries().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_statement_cache_stats() {
+        let cache = Arc::new(StatementCache::new(8));
+        let shape = crate::statement_cache::QueryShape::new("posts", &["id"], "");
+        cache.get_or_insert(&shape, || "SELECT id FROM posts".to_string());
+        cache.get_or_insert(&shape, || unreachable!());
+
+        let Json(stats) = statement_cache_stats(Extension(cache)).await;
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.size, 1);
+    }
+
+    #[sqlx::test]
+    async fn test_settings_endpoints(db: Pool<Sqlite>) -> sqlx::Result<()> {
+        let settings = Arc::new(Settings::open(db).await?);
+
+        let Json(setting) = put_setting(
+            Extension(settings.clone()),
+            Path("app_name".to_string()),
+            Json(serde_json::json!("Palmera")),
+        )
+        .await
+        .unwrap();
+        assert_eq!(setting.value, "Palmera");
+
+        let Json(found) = get_setting(Extension(settings.clone()), Path("app_name".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(found.value, "Palmera");
+
+        let response = list_settings(Extension(settings.clone()), Format::Json).await;
+        let all: Vec<Setting> = serde_json::from_str(&body_text(response).await).unwrap();
+        assert_eq!(all.len(), 1);
+
+        let response = list_settings(Extension(settings.clone()), Format::Csv).await;
+        let csv = body_text(response).await;
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines[0], "key,updated,value");
+        assert!(lines[1].starts_with("app_name,"));
+        assert!(lines[1].ends_with(",Palmera"));
+
+        let response = list_settings(Extension(settings.clone()), Format::Ndjson).await;
+        assert_eq!(body_text(response).await.lines().count(), 1);
+
+        // Secrets are write-only.
+        let Json(setting) = put_setting(
+            Extension(settings.clone()),
+            Path("smtp_password".to_string()),
+            Json(serde_json::json!("hunter2")),
+        )
+        .await
+        .unwrap();
+        assert_eq!(setting.value, serde_json::Value::Null);
+        let Json(found) = get_setting(
+            Extension(settings.clone()),
+            Path("smtp_password".to_string()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(found.value, serde_json::Value::Null);
+        let response = list_settings(Extension(settings.clone()), Format::Json).await;
+        assert!(!body_text(response).await.contains("hunter2"));
+        assert_eq!(
+            settings.get::<String>("smtp_password").await?.as_deref(),
+            Some("hunter2")
+        );
+
+        let status =
+            delete_setting(Extension(settings.clone()), Path("app_name".to_string())).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(
+            get_setting(Extension(settings), Path("app_name".to_string()))
+                .await
+                .unwrap_err(),
+            StatusCode::NOT_FOUND
+        );
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_fields_endpoints(db: Pool<Sqlite>) -> sqlx::Result<()> {
+        let fields = Arc::new(Fields::open(db).await?);
+        let path = || Path(("posts".to_string(), "status".to_string()));
+
+        let Json(field) = put_field(
+            Extension(fields.clone()),
+            path(),
+            Json(FieldKind::select(["draft", "published"])),
+        )
+        .await
+        .unwrap();
+        assert_eq!(field.column_name, "status");
+
+        let err = put_field(
+            Extension(fields.clone()),
+            Path(("posts".to_string(), "shout".to_string())),
+            Json(FieldKind::computed(
+                "1); DELETE FROM _policies; --",
+                crate::fields::ValueType::String,
+            )),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+
+        let Json(listed) = list_fields(Extension(fields.clone()), Path("posts".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(listed, [field]);
+
+        assert_eq!(
+            delete_field(Extension(fields.clone()), path()).await,
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(
+            delete_field(Extension(fields), path()).await,
+            StatusCode::NOT_FOUND
+        );
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_comment_endpoints(db: Pool<Sqlite>) -> sqlx::Result<()> {
+        let comments = Arc::new(Comments::open(db).await?);
+        let comment = |text: &str| {
+            Json(CommentPayload {
+                comment: text.to_string(),
+            })
+        };
+        let column = || Path(("posts".to_string(), "slug".to_string()));
+
+        put_table_comment(
+            Extension(comments.clone()),
+            None,
+            Path("posts".to_string()),
+            comment("Blog articles"),
+        )
+        .await
+        .unwrap();
+        let Json(posts) = put_column_comment(
+            Extension(comments.clone()),
+            None,
+            column(),
+            comment("URL path"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(posts.table.as_deref(), Some("Blog articles"));
+        assert_eq!(posts.column("slug"), Some("URL path"));
+
+        assert_eq!(
+            delete_column_comment(Extension(comments.clone()), column()).await,
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(
+            delete_column_comment(Extension(comments.clone()), column()).await,
+            StatusCode::NOT_FOUND
+        );
+        let Json(posts) = get_comments(Extension(comments), None, Path("posts".to_string()))
+            .await
+            .unwrap();
+        assert!(posts.columns.is_empty());
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_table_endpoints_dry_run(db: Pool<Sqlite>) -> sqlx::Result<()> {
+        let table: CreateTable = serde_json::from_value(serde_json::json!({
+            "name": "posts",
+            "columns": [{ "name": "id", "data_type": "integer", "primary_key": true }]
+        }))
+        .unwrap();
+
+        let (status, Json(migration)) = create_table(
+            Extension(db.clone()),
+            Query(ApplyParams {
+                dry_run: true,
+                ..Default::default()
+            }),
+            Json(table.clone()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(migration.statements.len(), 1);
+
+        let (status, _) = create_table(
+            Extension(db.clone()),
+            Query(ApplyParams::default()),
+            Json(table),
+        )
+        .await
+        .unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+
+        let change: AlterTable = serde_json::from_value(serde_json::json!({
+            "operations": [{ "op": "drop_column", "name": "id" }]
+        }))
+        .unwrap();
+        let (_, Json(migration)) = alter_table(
+            Extension(db.clone()),
+            Path("posts".to_string()),
+            Query(ApplyParams {
+                dry_run: true,
+                ..Default::default()
+            }),
+            Json(change.clone()),
+        )
+        .await
+        .unwrap();
+        assert!(migration.is_destructive());
+
+        // Destructive changes must be forced.
+        let status = alter_table(
+            Extension(db.clone()),
+            Path("posts".to_string()),
+            Query(ApplyParams::default()),
+            Json(change.clone()),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::PRECONDITION_REQUIRED);
+
+        // SQLite refuses to drop the primary key, which surfaces when applying.
+        let status = alter_table(
+            Extension(db),
+            Path("posts".to_string()),
+            Query(ApplyParams {
+                force: true,
+                ..Default::default()
+            }),
+            Json(change),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_exposure_endpoints(db: Pool<Sqlite>) -> sqlx::Result<()> {
+        let exposures = Arc::new(Exposures::open(db).await?);
+        let path = |table: &str| Path(table.to_string());
+        let settings = |alias: &str| {
+            Json(ExposureSettings {
+                enabled: true,
+                read_only: true,
+                alias: Some(alias.to_string()),
+            })
+        };
+
+        let Json(default) = get_exposure(Extension(exposures.clone()), path("posts")).await;
+        assert_eq!(default, TableExposure::new("posts"));
+
+        let Json(exposure) = put_exposure(
+            Extension(exposures.clone()),
+            path("posts"),
+            settings("/api/posts"),
+        )
+        .await
+        .unwrap();
+        assert!(exposure.read_only);
+
+        let conflict = put_exposure(
+            Extension(exposures.clone()),
+            path("users"),
+            settings("/api/posts"),
+        )
+        .await;
+        assert_eq!(conflict.unwrap_err(), StatusCode::CONFLICT);
+        let invalid =
+            put_exposure(Extension(exposures.clone()), path("users"), settings("api")).await;
+        assert_eq!(invalid.unwrap_err(), StatusCode::BAD_REQUEST);
+
+        assert_eq!(
+            delete_exposure(Extension(exposures), path("posts")).await,
+            StatusCode::NO_CONTENT
+        );
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_retention_endpoints(db: Pool<Sqlite>) -> sqlx::Result<()> {
+        sqlx::query("CREATE TABLE logs (id INTEGER PRIMARY KEY, created TEXT)")
+            .execute(&db)
+            .await?;
+        sqlx::query("INSERT INTO logs (created) VALUES ('2020-01-01 00:00:00')")
+            .execute(&db)
+            .await?;
+        let retention = Arc::new(Retention::open(db.clone()).await?);
+        let settings = |column: &str| RetentionSettings {
+            timestamp_column: column.to_string(),
+            timestamp_format: TimestampFormat::Text,
+            max_age_days: 30,
+            action: RetentionAction::Delete,
+            batch_size: None,
+            enabled: true,
+        };
+        let path = || Path("logs".to_string());
+
+        let Json(rule) = put_retention(
+            Extension(retention.clone()),
+            path(),
+            Json(settings("created")),
+        )
+        .await
+        .unwrap();
+        assert_eq!(rule.batch_size, crate::retention::DEFAULT_BATCH_SIZE);
+        assert_eq!(
+            put_retention(
+                Extension(retention.clone()),
+                path(),
+                Json(settings("missing"))
+            )
+            .await
+            .unwrap_err(),
+            StatusCode::BAD_REQUEST
+        );
+        let Json(rules) = list_retention(Extension(retention.clone())).await.unwrap();
+        assert_eq!(rules, [rule]);
+
+        let Json(reports) = run_retention(Extension(retention.clone())).await.unwrap();
+        assert_eq!(reports[0].purged, 1);
+
+        assert_eq!(
+            delete_retention(Extension(retention.clone()), path()).await,
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(
+            delete_retention(Extension(retention), path()).await,
+            StatusCode::NOT_FOUND
+        );
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_quota_endpoints(db: Pool<Sqlite>) -> sqlx::Result<()> {
+        let quotas = Arc::new(Quotas::open(db).await?);
+        let path = |subject: &str| Path(subject.to_string());
+
+        let Json(limits) = put_quota(
+            Extension(quotas.clone()),
+            path("*"),
+            Json(QuotaLimits::default().with_records("public.posts", 5)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(limits.records["posts"], 5);
+        let invalid = put_quota(
+            Extension(quotas.clone()),
+            path("ann"),
+            Json(QuotaLimits::default().with_requests(10, 0)),
+        )
+        .await;
+        assert_eq!(invalid.unwrap_err(), StatusCode::BAD_REQUEST);
+
+        let Json(usage) = get_quota(Extension(quotas.clone()), path("ann"))
+            .await
+            .unwrap();
+        assert_eq!(usage.limits, limits);
+        let Json(subjects) = list_quotas(Extension(quotas.clone())).await.unwrap();
+        assert_eq!(subjects.len(), 1);
+
+        assert_eq!(
+            delete_quota(Extension(quotas.clone()), path("*")).await,
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(
+            delete_quota(Extension(quotas), path("*")).await,
+            StatusCode::NOT_FOUND
+        );
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_feature_flag_endpoints(db: Pool<Sqlite>) -> sqlx::Result<()> {
+        let flags = Arc::new(FeatureFlags::open(db).await?);
+        let settings = |percent: u8| FlagSettings {
+            description: None,
+            enabled: true,
+            rules: vec![FlagRule::Percentage { percent }],
+        };
+        let path = || Path("editor".to_string());
+
+        let Json(flag) = put_feature_flag(Extension(flags.clone()), path(), Json(settings(20)))
+            .await
+            .unwrap();
+        assert_eq!(flag.key, "editor");
+        assert_eq!(
+            put_feature_flag(Extension(flags.clone()), path(), Json(settings(120)))
+                .await
+                .unwrap_err(),
+            StatusCode::BAD_REQUEST
+        );
+        let Json(listed) = list_feature_flags(Extension(flags.clone())).await;
+        assert_eq!(listed, [flag]);
+
+        assert_eq!(
+            delete_feature_flag(Extension(flags.clone()), path()).await,
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(
+            delete_feature_flag(Extension(flags), path()).await,
+            StatusCode::NOT_FOUND
+        );
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_config_endpoints(db: Pool<Sqlite>) -> sqlx::Result<()> {
+        sqlx::query("CREATE TABLE posts (id INTEGER PRIMARY KEY, title TEXT NOT NULL)")
+            .execute(&db)
+            .await?;
+        let settings = Arc::new(Settings::open(db.clone()).await?);
+        settings.set("app_name", &"Palmera").await?;
+
+        let Json(mut bundle) = export_config(Extension(db.clone()), Query(ExportParams::default()))
+            .await
+            .unwrap();
+        assert_eq!(bundle.schema.tables.len(), 1);
+        bundle
+            .settings
+            .insert("app_name".into(), "Palmera Staging".into());
+
+        let import = |dry_run: bool, bundle: ConfigBundle| {
+            import_config(
+                Extension(db.clone()),
+                Extension(settings.clone()),
+                Query(ApplyParams {
+                    dry_run,
+                    ..Default::default()
+                }),
+                Json(bundle),
+            )
+        };
+        let Json(planned) = import(true, bundle.clone()).await.unwrap();
+        assert_eq!(planned.settings, ["app_name"]);
+        assert_eq!(settings.get_value("app_name").await?.unwrap(), "Palmera");
+
+        let Json(report) = import(false, bundle.clone()).await.unwrap();
+        assert_eq!(report, planned);
+        assert_eq!(
+            settings.get_value("app_name").await?.unwrap(),
+            "Palmera Staging"
+        );
+
+        bundle.format += 1;
+        assert_eq!(
+            import(true, bundle).await.unwrap_err(),
+            StatusCode::BAD_REQUEST
+        );
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_typescript_types(db: Pool<Postgres>) -> sqlx::Result<()> {
+        sqlx::query("CREATE TABLE posts (id serial PRIMARY KEY, title text NOT NULL)")
+            .execute(&db)
+            .await?;
+        sqlx::query("CREATE TABLE drafts (id serial PRIMARY KEY)")
+            .execute(&db)
+            .await?;
+        let exposures =
+            Arc::new(Exposures::open(sqlx::SqlitePool::connect("sqlite::memory:").await?).await?);
+        exposures
+            .set(TableExposure::new("public.drafts").with_enabled(false))
+            .await
+            .unwrap();
+
+        let (headers, types) = typescript_types(
+            Extension(db),
+            Extension(exposures),
+            Query(TypesParams::default()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(headers[0].1, "application/typescript");
+        assert!(types.contains("export interface Posts {\n  id: number;\n  title: string;\n}"));
+        assert!(!types.contains("Drafts"));
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_postgres_comments(db: Pool<Postgres>) -> sqlx::Result<()> {
+        for statement in [
+            "CREATE TABLE posts (id serial PRIMARY KEY, title text NOT NULL, slug text)",
+            "COMMENT ON TABLE posts IS 'Blog articles'",
+            "COMMENT ON COLUMN posts.title IS 'Headline'",
+            "COMMENT ON COLUMN posts.slug IS 'Path'",
+        ] {
+            sqlx::query(statement).execute(&db).await?;
+        }
+        let sqlite = sqlx::SqlitePool::connect("sqlite::memory:").await?;
+        let comments = Arc::new(Comments::open(sqlite.clone()).await?);
+        let exposures = Arc::new(Exposures::open(sqlite).await.unwrap());
+
+        let Json(posts) = put_column_comment(
+            Extension(comments.clone()),
+            Some(Extension(db.clone())),
+            Path(("public.posts".to_string(), "slug".to_string())),
+            Json(CommentPayload {
+                comment: "URL path".to_string(),
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(posts.table.as_deref(), Some("Blog articles"));
+        assert_eq!(posts.column("title"), Some("Headline"));
+        assert_eq!(posts.column("slug"), Some("URL path"));
+        let Json(fetched) = get_comments(
+            Extension(comments.clone()),
+            Some(Extension(db.clone())),
+            Path("public.posts".to_string()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(fetched, posts);
+
+        let Json(components) = openapi_schemas(
+            Extension(db),
+            Extension(exposures),
+            Extension(comments),
+            Query(TypesParams::default()),
+        )
+        .await
+        .unwrap();
+        let schema = serde_json::to_value(&components.schemas["Posts"]).unwrap();
+        assert_eq!(schema["description"], "Blog articles");
+        assert_eq!(schema["properties"]["title"]["description"], "Headline");
+        assert_eq!(schema["properties"]["slug"]["description"], "URL path");
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_trash_endpoints(db: Pool<Sqlite>) -> sqlx::Result<()> {
+        sqlx::query("CREATE TABLE posts (id INTEGER PRIMARY KEY, title TEXT, deleted_at INTEGER)")
+            .execute(&db)
+            .await?;
+        sqlx::query("INSERT INTO posts (title, deleted_at) VALUES ('a', 100), ('b', 200)")
+            .execute(&db)
+            .await?;
+        let trash = Arc::new(Trash::new(db.clone()));
+        let path = |table: &str, id: &str| Path((table.to_string(), id.to_string()));
+
+        let Json(records) = list_trash(Extension(trash.clone()), Query(TrashFilter::default()))
+            .await
+            .unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].record["title"], "b");
+        let unknown = list_trash(
+            Extension(trash.clone()),
+            Query(TrashFilter {
+                table: Some("missing".to_string()),
+                ..Default::default()
+            }),
+        )
+        .await;
+        assert_eq!(unknown.unwrap_err(), StatusCode::BAD_REQUEST);
+
+        assert_eq!(
+            restore_trash(Extension(trash.clone()), path("posts", "1")).await,
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(
+            purge_trash(Extension(trash.clone()), path("posts", "1")).await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            purge_trash(Extension(trash), path("posts", "2")).await,
+            StatusCode::NO_CONTENT
+        );
+
+        let titles: Vec<String> = sqlx::query_scalar("SELECT title FROM posts")
+            .fetch_all(&db)
+            .await?;
+        assert_eq!(titles, ["a"]);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_history_endpoints(db: Pool<Sqlite>) -> sqlx::Result<()> {
+        sqlx::query("CREATE TABLE posts (id INTEGER PRIMARY KEY, title TEXT)")
+            .execute(&db)
+            .await?;
+        sqlx::query("INSERT INTO posts (title) VALUES ('a')")
+            .execute(&db)
+            .await?;
+        let history = Arc::new(History::open(db.clone()).await?);
+        let table = |table: &str| Path(table.to_string());
+
+        assert_eq!(
+            enable_history(Extension(history.clone()), table("missing")).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            enable_history(Extension(history.clone()), table("posts")).await,
+            StatusCode::NO_CONTENT
+        );
+        let Json(tables) = list_history_tables(Extension(history.clone()))
+            .await
+            .unwrap();
+        assert_eq!(tables, ["posts"]);
+
+        sqlx::query("UPDATE posts SET title = 'b'")
+            .execute(&db)
+            .await?;
+        let Json(versions) = list_history(
+            Extension(history.clone()),
+            Path(("posts".into(), "1".into())),
+        )
+        .await
+        .unwrap();
+        assert_eq!(versions.len(), 1);
+        assert_eq!(versions[0].record["title"], "a");
+
+        let Json(record) = restore_history(
+            Extension(history.clone()),
+            Some(Extension(SessionContext::new("service_role", "admin"))),
+            Path(("posts".into(), "1".into(), versions[0].version)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(record["title"], "a");
+        let versions = history.list("posts", "1").await.unwrap();
+        assert_eq!(versions[0].actor.as_deref(), Some("admin"));
+        let missing = restore_history(
+            Extension(history.clone()),
+            None,
+            Path(("posts".into(), "2".into(), versions[0].version)),
+        )
+        .await;
+        assert_eq!(missing.unwrap_err(), StatusCode::NOT_FOUND);
+
+        assert_eq!(
+            disable_history(Extension(history.clone()), table("posts")).await,
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(
+            disable_history(Extension(history), table("posts")).await,
+            StatusCode::NOT_FOUND
+        );
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_column_job_endpoints(db: Pool<Sqlite>) -> sqlx::Result<()> {
+        sqlx::query("CREATE TABLE products (id INTEGER PRIMARY KEY, price TEXT)")
+            .execute(&db)
+            .await?;
+        sqlx::query("INSERT INTO products (price) VALUES ('10'), ('12')")
+            .execute(&db)
+            .await?;
+        let jobs = Arc::new(ColumnJobs::open(db.clone()).await?);
+        let settings = |column: &str| ColumnJobSettings {
+            column: column.to_string(),
+            rename_to: None,
+            data_type: Some(ColumnType::Integer),
+            using: None,
+            batch_size: Some(1),
+        };
+
+        let (status, Json(job)) = start_column_job(
+            Extension(jobs.clone()),
+            Path("products".to_string()),
+            HeaderMap::from_iter([("x-request-id".parse().unwrap(), "req-1".parse().unwrap())]),
+            Json(settings("price")),
+        )
+        .await
+        .unwrap();
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(job.request_id.as_deref(), Some("req-1"));
+        let missing = start_column_job(
+            Extension(jobs.clone()),
+            Path("products".to_string()),
+            HeaderMap::new(),
+            Json(settings("missing")),
+        )
+        .await;
+        assert_eq!(missing.unwrap_err(), StatusCode::NOT_FOUND);
+
+        let mut finished = None;
+        for _ in 0..100 {
+            let Json(current) = get_column_job(Extension(jobs.clone()), Path(job.id))
+                .await
+                .unwrap();
+            if current.status != crate::column_jobs::JobStatus::Running {
+                finished = Some(current);
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let finished = finished.expect("the job finishes");
+        assert_eq!(finished.status, crate::column_jobs::JobStatus::Completed);
+        assert_eq!((finished.processed, finished.total), (2, 2));
+
+        let Json(listed) = list_column_jobs(Extension(jobs.clone())).await.unwrap();
+        assert_eq!(listed, [finished]);
+        assert_eq!(
+            resume_column_job(Extension(jobs), Path(99))
+                .await
+                .unwrap_err(),
+            StatusCode::NOT_FOUND
+        );
+        Ok(())
+    }
+}
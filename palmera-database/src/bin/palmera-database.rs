@@ -0,0 +1,266 @@
+//! Operator commands for Palmera databases.
+//!
+//! ```text
+//! palmera-database index-suggestions DATABASE_URL [--min-uses N]
+//! palmera-database schema-apply DATABASE_URL MANIFEST [--dry-run] [--force]
+//! palmera-database export-config DATABASE_URL [--include-secrets]
+//! palmera-database import-config DATABASE_URL BUNDLE [--dry-run] [--force]
+//! palmera-database codegen DATABASE_URL [--lang rust]
+//! palmera-database codegen DATABASE_URL --lang typescript --postgres POSTGRES_URL
+//! palmera-database init DATABASE_URL --template blog|crm|chat [--dir DIR]
+//! palmera-database restore DATABASE_FILE --snapshots DIR --at TIMESTAMP
+//! ```
+//!
+//! `index-suggestions` prints the indexes the advisor of
+//! [`palmera_database::index_advisor`] suggests for the SQLite database at
+//! `DATABASE_URL`, as a SQL script that can be reviewed and applied.
+//!
+//! `schema-apply` brings the database in line with a schema manifest, see
+//! [`palmera_database::manifest`], and prints the statements it ran, their risks and the
+//! drift it left alone. With `--dry-run` it only prints them. Destructive changes, such
+//! as deleting policies the manifest no longer lists, are refused without `--force`.
+//!
+//! `export-config` prints the configuration bundle of the database, see
+//! [`palmera_database::config_bundle`], with the settings holding credentials only if
+//! `--include-secrets` is given, and `import-config` imports a bundle read from
+//! the file `BUNDLE` and prints what changed, or with `--dry-run` what would change. It
+//! takes `--force` like `schema-apply`.
+//!
+//! `codegen` prints typed Rust models of the tables of the database, or with `--lang
+//! typescript` the interfaces of the tables the REST API serves from the `public` schema
+//! of the Postgres database at `POSTGRES_URL`, leaving out those the exposures stored in
+//! `DATABASE_URL` disable; see [`palmera_database::codegen`].
+//!
+//! `init` starts a project from a template, see [`palmera_database::templates`]: it
+//! applies the template's manifest, seeds its empty tables and writes `schema.toml` and
+//! `buckets.json` into `DIR`, the current directory by default, keeping existing files.
+//! The buckets are only written, not created; the app declares them from the file.
+//!
+//! `restore` replaces the SQLite file `DATABASE_FILE` with the newest snapshot in `DIR`
+//! taken at or before `TIMESTAMP`, an RFC 3339 time such as `2025-06-01T12:00:00Z`; see
+//! [`palmera_database::sqlite::snapshot`]. The server must be stopped first.
+
+use palmera_database::{
+    codegen,
+    config_bundle::{self, ConfigBundle, ImportReport},
+    exposure::Exposures,
+    index_advisor::{DEFAULT_MIN_USES, QueryUsage, report},
+    manifest::{self, ManifestPlan, SchemaManifest},
+    settings::Settings,
+    sqlite::snapshot::SnapshotStore,
+    templates::{self, InitReport},
+};
+use sqlx::{PgPool, SqlitePool};
+
+const USAGE: &str = "usage: palmera-database index-suggestions DATABASE_URL [--min-uses N]\n       \
+                     palmera-database schema-apply DATABASE_URL MANIFEST [--dry-run] [--force]\n       \
+                     palmera-database export-config DATABASE_URL [--include-secrets]\n       \
+                     palmera-database import-config DATABASE_URL BUNDLE [--dry-run] [--force]\n       \
+                     palmera-database codegen DATABASE_URL [--lang rust]\n       \
+                     palmera-database codegen DATABASE_URL --lang typescript --postgres POSTGRES_URL\n       \
+                     palmera-database init DATABASE_URL --template blog|crm|chat [--dir DIR]\n       \
+                     palmera-database restore DATABASE_FILE --snapshots DIR --at TIMESTAMP";
+
+async fn index_suggestions(url: &str, min_uses: u64) -> Result<(), Box<dyn std::error::Error>> {
+    let db = SqlitePool::connect(url).await?;
+    let suggestions = QueryUsage::open(db)
+        .await?
+        .suggestions(&[], min_uses)
+        .await?;
+    print!("{}", report(&suggestions));
+    Ok(())
+}
+
+/// Flags of the commands changing the schema.
+#[derive(Debug, Clone, Copy, Default)]
+struct ApplyFlags {
+    dry_run: bool,
+    force: bool,
+}
+
+impl ApplyFlags {
+    /// Parses `--dry-run` and `--force`, or returns `None` for any other argument.
+    fn parse(args: &[&str]) -> Option<Self> {
+        let mut flags = Self::default();
+        for arg in args {
+            match *arg {
+                "--dry-run" => flags.dry_run = true,
+                "--force" => flags.force = true,
+                _ => return None,
+            }
+        }
+        Some(flags)
+    }
+}
+
+async fn schema_apply(
+    url: &str,
+    path: &str,
+    flags: ApplyFlags,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let db = SqlitePool::connect(url).await?;
+    let manifest = SchemaManifest::load(path).await?;
+    let plan = if flags.dry_run {
+        manifest::plan(&db, &manifest).await?
+    } else {
+        manifest::apply(&db, &manifest, flags.force).await?
+    };
+    print!("{}", describe(&plan));
+    Ok(())
+}
+
+fn describe(plan: &ManifestPlan) -> String {
+    let mut out = format!("-- schema manifest version {}\n", plan.version);
+    for (index, statement) in plan.migration.statements.iter().enumerate() {
+        for warning in plan
+            .migration
+            .warnings
+            .iter()
+            .filter(|warning| warning.statement == index)
+        {
+            out.push_str(&format!("-- {:?}: {}\n", warning.risk, warning.message));
+        }
+        out.push_str(&format!("{};\n", statement));
+    }
+    for drift in &plan.drift {
+        out.push_str(&format!("-- drift: {}\n", drift));
+    }
+    out
+}
+
+async fn export_config(url: &str, include_secrets: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let db = SqlitePool::connect(url).await?;
+    let bundle = config_bundle::export(&db, include_secrets).await?;
+    println!("{}", serde_json::to_string_pretty(&bundle)?);
+    Ok(())
+}
+
+async fn import_config(
+    url: &str,
+    path: &str,
+    flags: ApplyFlags,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let db = SqlitePool::connect(url).await?;
+    let bundle: ConfigBundle = serde_json::from_str(&tokio::fs::read_to_string(path).await?)?;
+    let report = if flags.dry_run {
+        config_bundle::plan_import(&db, &bundle).await?
+    } else {
+        let settings = Settings::open(db.clone()).await?;
+        config_bundle::import(&db, &settings, &bundle, flags.force).await?
+    };
+    print!("{}", describe_import(&report));
+    Ok(())
+}
+
+async fn generate(url: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let db = SqlitePool::connect(url).await?;
+    let (tables, fields) = codegen::sqlite_tables(&db).await?;
+    print!("{}", codegen::rust(&tables, &fields));
+    Ok(())
+}
+
+async fn generate_typescript(url: &str, postgres: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let exposures = Exposures::open(SqlitePool::connect(url).await?).await?;
+    let db = PgPool::connect(postgres).await?;
+    let tables = codegen::postgres_tables(&db, &["public".to_string()]).await?;
+    print!("{}", codegen::typescript(&tables, &exposures));
+    Ok(())
+}
+
+async fn init(url: &str, name: &str, dir: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let template = templates::find(name).ok_or_else(|| format!("unknown template {}", name))?;
+    let db = SqlitePool::connect(url).await?;
+    let report = templates::init(&db, template).await?;
+    print!("{}", describe_init(&report));
+
+    tokio::fs::create_dir_all(dir).await?;
+    for (file, contents) in template.files() {
+        let path = std::path::Path::new(dir).join(file);
+        if tokio::fs::try_exists(&path).await? {
+            println!("-- kept: {}", path.display());
+        } else {
+            tokio::fs::write(&path, contents).await?;
+            println!("-- wrote: {}", path.display());
+        }
+    }
+    Ok(())
+}
+
+async fn restore(path: &str, dir: &str, at: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let at = chrono::DateTime::parse_from_rfc3339(at)
+        .map_err(|_| "--at must be an RFC 3339 time such as 2025-06-01T12:00:00Z")?
+        .to_utc();
+    let snapshot = SnapshotStore::new(dir).restore_at(at, path).await?;
+    println!(
+        "-- restored: {} (taken at {})",
+        snapshot.path.display(),
+        snapshot.taken_at.to_rfc3339()
+    );
+    Ok(())
+}
+
+fn describe_init(report: &InitReport) -> String {
+    let mut out = describe(&report.plan);
+    for (table, rows) in &report.seeded {
+        out.push_str(&format!("-- seeded: {} ({} rows)\n", table, rows));
+    }
+    out
+}
+
+fn describe_import(report: &ImportReport) -> String {
+    let mut out = describe(&report.schema);
+    for field in &report.fields {
+        out.push_str(&format!(
+            "-- field: {}.{}\n",
+            field.table_name, field.column_name
+        ));
+    }
+    for key in &report.settings {
+        out.push_str(&format!("-- setting: {}\n", key));
+    }
+    out
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    match args[..] {
+        ["index-suggestions", url] => index_suggestions(url, DEFAULT_MIN_USES).await,
+        ["index-suggestions", url, "--min-uses", min_uses] => {
+            let min_uses = min_uses
+                .parse()
+                .map_err(|_| "--min-uses must be a number")?;
+            index_suggestions(url, min_uses).await
+        }
+        ["schema-apply", url, path, ref flags @ ..]
+            if let Some(flags) = ApplyFlags::parse(flags) =>
+        {
+            schema_apply(url, path, flags).await
+        }
+        ["export-config", url] => export_config(url, false).await,
+        ["export-config", url, "--include-secrets"] => export_config(url, true).await,
+        ["import-config", url, path, ref flags @ ..]
+            if let Some(flags) = ApplyFlags::parse(flags) =>
+        {
+            import_config(url, path, flags).await
+        }
+        ["codegen", url] | ["codegen", url, "--lang", "rust"] => generate(url).await,
+        [
+            "codegen",
+            url,
+            "--lang",
+            "typescript",
+            "--postgres",
+            postgres,
+        ] => generate_typescript(url, postgres).await,
+        ["init", url, "--template", name] => init(url, name, ".").await,
+        ["init", url, "--template", name, "--dir", dir] => init(url, name, dir).await,
+        ["restore", path, "--snapshots", dir, "--at", at] => restore(path, dir, at).await,
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    }
+}
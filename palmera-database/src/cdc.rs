@@ -0,0 +1,62 @@
+//! # Change data capture
+//!
+//! Captures row changes made directly against the database, including writes that did
+//! not go through Palmera's API, and publishes them on a [`ChangeFeed`]. Backends feed
+//! the same bus:
+//!
+//! - Postgres: a trigger sends each change with `NOTIFY`, see [`crate::postgres::cdc`].
+//! - SQLite: a per-connection update hook, see [`crate::sqlite::cdc`].
+//!
+//! Consumers such as realtime channels or webhooks subscribe to the feed and receive
+//! every [`ChangeEvent`] regardless of where it came from.
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+/// Kind of row change.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum ChangeOperation {
+    Insert,
+    Update,
+    Delete,
+}
+
+/// A single row change.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ChangeEvent {
+    /// Schema (Postgres) or database name (SQLite) of the changed table.
+    pub schema: String,
+    pub table: String,
+    pub operation: ChangeOperation,
+    /// New row for inserts and updates, when the backend provides it.
+    pub record: Option<serde_json::Value>,
+    /// Previous row for updates and deletes, when the backend provides it.
+    pub old_record: Option<serde_json::Value>,
+    /// SQLite rowid of the changed row.
+    pub rowid: Option<i64>,
+}
+
+/// Broadcast bus of [`ChangeEvent`]s shared by all capture sources.
+#[derive(Debug, Clone)]
+pub struct ChangeFeed {
+    sender: broadcast::Sender<ChangeEvent>,
+}
+
+impl ChangeFeed {
+    /// Creates a feed buffering up to `capacity` events per lagging subscriber.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            sender: broadcast::channel(capacity).0,
+        }
+    }
+
+    /// Publishes an event, returning how many subscribers received it.
+    pub fn publish(&self, event: ChangeEvent) -> usize {
+        self.sender.send(event).unwrap_or(0)
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ChangeEvent> {
+        self.sender.subscribe()
+    }
+}
@@ -0,0 +1,961 @@
+//! # Code generation
+//!
+//! Generates typed models from the live schema, so that applications embedding Palmera,
+//! frontends and SDK authors get compile-time checked records instead of untyped JSON.
+//!
+//! ## Rust
+//!
+//! [`rust`] emits one struct per table, as read from a SQLite database by
+//! [`sqlite_tables`], deriving `serde` and `sqlx::FromRow`, with:
+//!
+//! - a field per column, optional when the column is nullable or an integer primary key
+//!   SQLite assigns on insert;
+//! - an enum per [`FieldKind::Select`] field, and a read-only field per
+//!   [`FieldKind::Computed`] one;
+//! - for tables with an `id` column, helpers running the record operations of a
+//!   [`Session`](crate::embedded::Session), so policies and validation still apply.
+//!
+//! Records read through the embedded client come back as SQLite's `json_object()`
+//! renders them: booleans as `0` and `1`, and JSON columns as text. The generated
+//! models decode them with the [`de`] helpers. Timestamps are kept as the text stored.
+//! Names are converted to Rust's casing, keywords escaped and names that collide once
+//! converted numbered, e.g. `created_at_2` for a `createdAt` column next to `created_at`.
+//!
+//! ## TypeScript
+//!
+//! [`typescript`] emits an interface per table the REST API serves, describing its
+//! records, and another describing the records inserted into it unless it is read-only.
+//! Since the REST API is served from Postgres, the tables are read from its catalog with
+//! [`postgres_tables`] and named `schema.table`, the key of their
+//! [`TableExposure`](crate::exposure::TableExposure); tables it disables are left out.
+//! Booleans and `json`/`jsonb` columns come back as JSON booleans and values there, so
+//! they are typed `boolean` and `Json`. Managed [`Field`]s are only applied by the
+//! embedded client, so they appear in the Rust models alone. The `Tables` interface maps
+//! qualified table names to their record types. `GET /admin/types.ts` in
+//! [`crate::admin`] serves the result.
+//!
+//! ## OpenAPI
+//!
+//! [`openapi`] describes the records of the same tables as OpenAPI schemas, with the
+//! comments of the tables and their columns, read by
+//! [`postgres_comments`](crate::comments::postgres_comments), as descriptions.
+//! `GET /admin/schemas.json` in [`crate::admin`] serves them.
+//!
+//! `palmera-database codegen DATABASE_URL [--lang rust]` prints the Rust models of a
+//! SQLite database, and `palmera-database codegen DATABASE_URL --lang typescript
+//! --postgres POSTGRES_URL` the interfaces of the Postgres database, with the exposures
+//! stored in `DATABASE_URL`.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # async fn run(db: sqlx::Pool<sqlx::Sqlite>) -> Result<(), Box<dyn std::error::Error>> {
+//! use palmera_database::codegen;
+//!
+//! let (tables, fields) = codegen::sqlite_tables(&db).await?;
+//! std::fs::write("src/models.rs", codegen::rust(&tables, &fields))?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The models of a `posts` table then read:
+//!
+//! ```rust,ignore
+//! let session = client.as_user(user_id);
+//! let post = Posts { id: None, title: "Hello".into(), status: PostsStatus::Draft }
+//!     .create(&session)
+//!     .await?;
+//! let posts = Posts::list(&session).await?;
+//! ```
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+};
+
+use serde::de::DeserializeOwned;
+use sqlx::{Pool, Postgres, Sqlite};
+use utoipa::openapi::{
+    Components, ComponentsBuilder, KnownFormat, ObjectBuilder, Schema, SchemaFormat, Type,
+    schema::SchemaType,
+};
+
+use crate::{
+    comments::TableComments,
+    config_bundle,
+    ddl::{self, ColumnDefinition, ColumnType, SchemaError},
+    embedded::ClientError,
+    exposure::Exposures,
+    fields::{Field, FieldKind, Fields, ValueType},
+    manifest::TableManifest,
+    postgres::batch::PRIMARY_KEY,
+};
+
+/// Decodes a record returned by a [`Session`](crate::embedded::Session) into a model.
+///
+/// # Errors
+///
+/// Returns [`ClientError::Database`] with [`sqlx::Error::Decode`] if the record doesn't
+/// match the model, e.g. because the schema changed since it was generated.
+pub fn decode<T: DeserializeOwned>(record: serde_json::Value) -> Result<T, ClientError> {
+    serde_json::from_value(record)
+        .map_err(|err| ClientError::Database(sqlx::Error::Decode(err.into())))
+}
+
+/// Encodes a model into a record for a [`Session`](crate::embedded::Session).
+pub fn encode<T: serde::Serialize>(model: &T) -> Result<serde_json::Value, ClientError> {
+    serde_json::to_value(model)
+        .map_err(|err| ClientError::Database(sqlx::Error::Encode(err.into())))
+}
+
+/// `deserialize_with` helpers for values as `json_object()` renders them.
+pub mod de {
+    use serde::{Deserialize, Deserializer, de::Error};
+    use sqlx::types::Json;
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Flag {
+        Bool(bool),
+        Int(i64),
+    }
+
+    /// A boolean stored as `0` or `1`.
+    pub fn bool<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+        match Flag::deserialize(deserializer)? {
+            Flag::Bool(value) => Ok(value),
+            Flag::Int(value) => Ok(value != 0),
+        }
+    }
+
+    /// A nullable boolean stored as `0` or `1`.
+    pub fn option_bool<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<bool>, D::Error> {
+        Ok(match Option::<Flag>::deserialize(deserializer)? {
+            Some(Flag::Bool(value)) => Some(value),
+            Some(Flag::Int(value)) => Some(value != 0),
+            None => None,
+        })
+    }
+
+    /// A JSON column, rendered as text.
+    pub fn json<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Json<serde_json::Value>, D::Error> {
+        match serde_json::Value::deserialize(deserializer)? {
+            serde_json::Value::String(text) => serde_json::from_str(&text)
+                .map(Json)
+                .map_err(D::Error::custom),
+            value => Ok(Json(value)),
+        }
+    }
+
+    /// A nullable JSON column, rendered as text.
+    pub fn option_json<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Json<serde_json::Value>>, D::Error> {
+        match Option::<serde_json::Value>::deserialize(deserializer)? {
+            Some(serde_json::Value::String(text)) => serde_json::from_str(&text)
+                .map(|value| Some(Json(value)))
+                .map_err(D::Error::custom),
+            Some(value) => Ok(Some(Json(value))),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Reads the tables of the SQLite database `db`, ordered by name, and the managed fields
+/// defined on them, without changing the database.
+pub async fn sqlite_tables(
+    db: &Pool<Sqlite>,
+) -> Result<(Vec<TableManifest>, Vec<Field>), SchemaError> {
+    let tables = config_bundle::live_tables(db).await?;
+    let fields = if ddl::table_exists(db, "_fields").await? {
+        Fields::open(db.clone()).await?.list().await?
+    } else {
+        Vec::new()
+    };
+    Ok((tables, fields))
+}
+
+/// Names the generated code uses besides the models.
+const RESERVED_TYPES: [&str; 8] = [
+    "ClientError",
+    "Deserialize",
+    "Option",
+    "Self",
+    "Serialize",
+    "Session",
+    "String",
+    "Vec",
+];
+
+/// Generates the Rust models of `tables` and their managed `fields`, as read by
+/// [`sqlite_tables`].
+pub fn rust(tables: &[TableManifest], fields: &[Field]) -> String {
+    let mut out = String::from(
+        "// Generated by `palmera-database codegen --lang rust`. Do not edit.\n\n\
+         #[allow(unused_imports)]\n\
+         use palmera_database::embedded::{ClientError, Session};\n\
+         use serde::{Deserialize, Serialize};\n",
+    );
+    let mut types: BTreeSet<String> = RESERVED_TYPES.map(String::from).into();
+    let names: Vec<String> = tables
+        .iter()
+        .map(|table| unique(&pascal_case(&table.name), "", &mut types))
+        .collect();
+    for (table, name) in tables.iter().zip(&names) {
+        let fields: Vec<&Field> = fields
+            .iter()
+            .filter(|field| field.table_name == table.name)
+            .collect();
+        out.push('\n');
+        model(&mut out, table, name, &fields, &mut types);
+    }
+    out
+}
+
+/// A generated struct field.
+struct Member {
+    /// Column name, as stored.
+    column: String,
+    ident: String,
+    ty: String,
+    attributes: Vec<String>,
+}
+
+/// Emits the struct `name` of `table`, numbering the names of its select enums that
+/// collide with `types`.
+fn model(
+    out: &mut String,
+    table: &TableManifest,
+    name: &str,
+    fields: &[&Field],
+    types: &mut BTreeSet<String>,
+) {
+    let mut members = Vec::new();
+    let mut idents = BTreeSet::new();
+
+    for column in &table.columns {
+        let field = fields.iter().find(|field| field.column_name == column.name);
+        let select = match field.map(|field| &field.kind) {
+            Some(FieldKind::Select { values }) => select_enum(out, name, column, values, types),
+            _ => None,
+        };
+        members.push(column_member(column, select, &mut idents));
+    }
+    for field in fields {
+        if let FieldKind::Computed { value_type, .. } = &field.kind {
+            let ty = match value_type {
+                ValueType::String => "String",
+                ValueType::Integer => "i64",
+                ValueType::Number => "f64",
+                ValueType::Boolean => "bool",
+            };
+            let mut attributes = vec![
+                "serde(default, skip_serializing)".to_string(),
+                "sqlx(default)".to_string(),
+            ];
+            if *value_type == ValueType::Boolean {
+                attributes[0] = "serde(default, skip_serializing, deserialize_with = \
+                                 \"palmera_database::codegen::de::option_bool\")"
+                    .to_string();
+            }
+            members.push(member(
+                &field.column_name,
+                format!("Option<{}>", ty),
+                attributes,
+                &mut idents,
+            ));
+        }
+    }
+
+    writeln!(out, "/// A record of table `{}`.", table.name).unwrap();
+    writeln!(
+        out,
+        "#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, sqlx::FromRow)]"
+    )
+    .unwrap();
+    writeln!(out, "pub struct {} {{", name).unwrap();
+    for member in &members {
+        for attribute in &member.attributes {
+            writeln!(out, "    #[{}]", attribute).unwrap();
+        }
+        writeln!(out, "    pub {}: {},", member.ident, member.ty).unwrap();
+    }
+    writeln!(out, "}}\n").unwrap();
+
+    writeln!(out, "impl {} {{", name).unwrap();
+    writeln!(out, "    pub const TABLE: &'static str = {:?};", table.name).unwrap();
+    if let Some(id) = members.iter().find(|member| member.column == PRIMARY_KEY) {
+        helpers(out, id);
+    }
+    writeln!(out, "}}").unwrap();
+}
+
+fn column_member(
+    column: &ColumnDefinition,
+    select: Option<String>,
+    idents: &mut BTreeSet<String>,
+) -> Member {
+    let auto_id = column.primary_key && column.data_type == ColumnType::Integer;
+    let nullable = auto_id || !(column.not_null || column.primary_key);
+
+    let (ty, deserialize_with) = match (select, column.data_type) {
+        (Some(ty), _) => (ty, None),
+        (None, ColumnType::Text | ColumnType::Timestamp) => ("String".to_string(), None),
+        (None, ColumnType::Integer) => ("i64".to_string(), None),
+        (None, ColumnType::Real) => ("f64".to_string(), None),
+        (None, ColumnType::Blob) => ("Vec<u8>".to_string(), None),
+        (None, ColumnType::Boolean) => ("bool".to_string(), Some("bool")),
+        (None, ColumnType::Json) => (
+            "sqlx::types::Json<serde_json::Value>".to_string(),
+            Some("json"),
+        ),
+    };
+
+    let mut attributes = Vec::new();
+    match (deserialize_with, nullable) {
+        (Some(helper), false) => attributes.push(format!(
+            "serde(deserialize_with = \"palmera_database::codegen::de::{}\")",
+            helper
+        )),
+        (Some(helper), true) => attributes.push(format!(
+            "serde(default, deserialize_with = \"palmera_database::codegen::de::option_{}\")",
+            helper
+        )),
+        (None, true) => attributes.push("serde(default)".to_string()),
+        (None, false) => {}
+    }
+
+    let ty = if nullable {
+        format!("Option<{}>", ty)
+    } else {
+        ty
+    };
+    member(&column.name, ty, attributes, idents)
+}
+
+/// A member for `column`, named so it doesn't collide with the other `idents`.
+fn member(
+    column: &str,
+    ty: String,
+    mut attributes: Vec<String>,
+    idents: &mut BTreeSet<String>,
+) -> Member {
+    let ident = unique(&raw_ident(snake_case(column)), "_", idents);
+    if ident != column {
+        attributes.push(format!("serde(rename = {:?})", column));
+        attributes.push(format!("sqlx(rename = {:?})", column));
+    }
+    Member {
+        column: column.to_string(),
+        ident,
+        ty,
+        attributes,
+    }
+}
+
+/// Emits the enum of a select field, returning its name, or `None` if its values don't
+/// make distinct variants.
+fn select_enum(
+    out: &mut String,
+    model: &str,
+    column: &ColumnDefinition,
+    values: &[String],
+    types: &mut BTreeSet<String>,
+) -> Option<String> {
+    let variants: Vec<String> = values.iter().map(|value| pascal_case(value)).collect();
+    let distinct = variants.iter().enumerate().all(|(i, variant)| {
+        !variant.is_empty() && variant != "Self" && !variants[..i].contains(variant)
+    });
+    if !distinct || variants.is_empty() {
+        return None;
+    }
+
+    let name = unique(
+        &format!("{}{}", model, pascal_case(&column.name)),
+        "",
+        types,
+    );
+    writeln!(out, "/// Values of `{}`.", column.name).unwrap();
+    writeln!(
+        out,
+        "#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]"
+    )
+    .unwrap();
+    writeln!(out, "pub enum {} {{", name).unwrap();
+    for (variant, value) in variants.iter().zip(values) {
+        writeln!(out, "    #[serde(rename = {:?})]", value).unwrap();
+        writeln!(out, "    #[sqlx(rename = {:?})]", value).unwrap();
+        writeln!(out, "    {},", variant).unwrap();
+    }
+    writeln!(out, "}}\n").unwrap();
+    Some(name)
+}
+
+/// Emits the record operations of a table identified by `id`.
+fn helpers(out: &mut String, id: &Member) {
+    let id_type = id
+        .ty
+        .strip_prefix("Option<")
+        .and_then(|ty| ty.strip_suffix('>'))
+        .unwrap_or(&id.ty);
+    let id_value = if id.ty.starts_with("Option<") {
+        format!("self.{}.ok_or(ClientError::NotFound)?", id.ident)
+    } else {
+        format!("self.{}.clone()", id.ident)
+    };
+
+    write!(
+        out,
+        r#"
+    /// Lists the records visible to `session`, ordered by id.
+    pub async fn list(session: &Session<'_>) -> Result<Vec<Self>, ClientError> {{
+        session
+            .list(Self::TABLE)
+            .await?
+            .into_iter()
+            .map(palmera_database::codegen::decode)
+            .collect()
+    }}
+
+    /// Returns the record with `id`, if `session` can see it.
+    pub async fn get(session: &Session<'_>, id: {id_type}) -> Result<Self, ClientError> {{
+        palmera_database::codegen::decode(session.get(Self::TABLE, id).await?)
+    }}
+
+    /// Inserts the record and returns it as stored.
+    pub async fn create(&self, session: &Session<'_>) -> Result<Self, ClientError> {{
+        let record = palmera_database::codegen::encode(self)?;
+        palmera_database::codegen::decode(session.create(Self::TABLE, record).await?)
+    }}
+
+    /// Writes every column of the record and returns it as stored.
+    pub async fn update(&self, session: &Session<'_>) -> Result<Self, ClientError> {{
+        let record = palmera_database::codegen::encode(self)?;
+        let id = {id_value};
+        palmera_database::codegen::decode(session.update(Self::TABLE, id, record).await?)
+    }}
+
+    /// Deletes the record with `id`, returning it as it was.
+    pub async fn delete(session: &Session<'_>, id: {id_type}) -> Result<Self, ClientError> {{
+        palmera_database::codegen::decode(session.delete(Self::TABLE, id).await?)
+    }}
+"#
+    )
+    .unwrap();
+}
+
+/// Reads the tables of the Postgres schemas `schemas` from the catalog, named
+/// `schema.table` and ordered by name, with their columns in table order.
+///
+/// Columns are mapped to the [`ColumnType`] their values are rendered as in JSON:
+/// integers, floating point and `numeric` columns to numbers, `json`, `jsonb` and arrays
+/// to JSON, `bytea` to a blob, date and time types to timestamps and anything else,
+/// e.g. enums or `uuid`, to text. Identity columns and columns with a default get a
+/// [`ColumnDefinition::default`] holding the default's expression.
+pub async fn postgres_tables(
+    db: &Pool<Postgres>,
+    schemas: &[String],
+) -> Result<Vec<TableManifest>, sqlx::Error> {
+    let rows: Vec<(String, String, String, String, bool, bool, Option<String>)> = sqlx::query_as(
+        "SELECT n.nspname::text || '.' || c.relname::text, a.attname::text, \
+                coalesce(b.typname, t.typname)::text, \
+                coalesce(b.typcategory, t.typcategory)::text, \
+                a.attnotnull, \
+                EXISTS (SELECT 1 FROM pg_index i \
+                        WHERE i.indrelid = c.oid AND i.indisprimary \
+                          AND a.attnum = ANY(i.indkey)), \
+                CASE WHEN a.attidentity <> '' THEN 'identity' \
+                     ELSE pg_get_expr(d.adbin, d.adrelid) END \
+         FROM pg_class c \
+         JOIN pg_namespace n ON n.oid = c.relnamespace \
+         JOIN pg_attribute a ON a.attrelid = c.oid AND a.attnum > 0 AND NOT a.attisdropped \
+         JOIN pg_type t ON t.oid = a.atttypid \
+         LEFT JOIN pg_type b ON b.oid = t.typbasetype \
+         LEFT JOIN pg_attrdef d ON d.adrelid = c.oid AND d.adnum = a.attnum \
+         WHERE c.relkind IN ('r', 'p') AND n.nspname::text = ANY($1) AND NOT c.relispartition \
+         ORDER BY n.nspname, c.relname, a.attnum",
+    )
+    .bind(schemas)
+    .fetch_all(db)
+    .await?;
+
+    let mut tables: Vec<TableManifest> = Vec::new();
+    for (table, column, type_name, category, not_null, primary_key, default) in rows {
+        let data_type = match (type_name.as_str(), category.as_str()) {
+            ("int2" | "int4" | "int8", _) => ColumnType::Integer,
+            (_, "N") => ColumnType::Real,
+            (_, "B") => ColumnType::Boolean,
+            ("json" | "jsonb", _) | (_, "A") => ColumnType::Json,
+            ("bytea", _) => ColumnType::Blob,
+            (_, "D") => ColumnType::Timestamp,
+            _ => ColumnType::Text,
+        };
+        let column = ColumnDefinition {
+            name: column,
+            data_type,
+            not_null,
+            primary_key,
+            unique: false,
+            default: default.map(serde_json::Value::String),
+        };
+        match tables.last_mut() {
+            Some(last) if last.name == table => last.columns.push(column),
+            _ => tables.push(TableManifest {
+                name: table,
+                columns: vec![column],
+                checks: Vec::new(),
+                foreign_keys: Vec::new(),
+                indexes: Vec::new(),
+            }),
+        }
+    }
+    Ok(tables)
+}
+
+/// Generates the TypeScript interfaces of the Postgres `tables`, as read by
+/// [`postgres_tables`], that `exposures` lets the REST API serve.
+pub fn typescript(tables: &[TableManifest], exposures: &Exposures) -> String {
+    let mut out = String::from(
+        "// Generated by `palmera-database codegen --lang typescript`. Do not edit.\n\n\
+         export type Json = string | number | boolean | null | Json[] | { [key: string]: Json };\n",
+    );
+    let mut names = Vec::new();
+    for table in tables {
+        let exposure = exposures.get(&table.name);
+        if exposure.as_ref().is_some_and(|exposure| !exposure.enabled) {
+            continue;
+        }
+        let read_only = exposure.is_some_and(|exposure| exposure.read_only);
+        let name = interface_name(&table.name);
+
+        out.push('\n');
+        interfaces(&mut out, table, &name, read_only);
+        names.push((table.name.as_str(), name));
+    }
+
+    out.push_str("\n/** Record types by table name. */\nexport interface Tables {\n");
+    for (table, name) in names {
+        writeln!(out, "  {}: {};", property(table), name).unwrap();
+    }
+    out.push_str("}\n");
+    out
+}
+
+/// `public.blog_posts` to `BlogPosts`, and `billing.invoices` to `BillingInvoices`.
+fn interface_name(table: &str) -> String {
+    pascal_case(table.strip_prefix("public.").unwrap_or(table))
+}
+
+fn interfaces(out: &mut String, table: &TableManifest, name: &str, read_only: bool) {
+    let types: Vec<String> = table
+        .columns
+        .iter()
+        .map(|column| {
+            let ty = match column.data_type {
+                ColumnType::Integer | ColumnType::Real => "number",
+                ColumnType::Boolean => "boolean",
+                ColumnType::Json => "Json",
+                ColumnType::Text | ColumnType::Timestamp | ColumnType::Blob => "string",
+            };
+            if column.not_null || column.primary_key {
+                ty.to_string()
+            } else {
+                format!("{} | null", ty)
+            }
+        })
+        .collect();
+
+    writeln!(out, "/** A record of table `{}`. */", table.name).unwrap();
+    writeln!(out, "export interface {} {{", name).unwrap();
+    for (column, ty) in table.columns.iter().zip(&types) {
+        writeln!(out, "  {}: {};", property(&column.name), ty).unwrap();
+    }
+    writeln!(out, "}}").unwrap();
+
+    if read_only {
+        return;
+    }
+    writeln!(
+        out,
+        "\n/** A record inserted into table `{}`. */",
+        table.name
+    )
+    .unwrap();
+    writeln!(out, "export interface {}Insert {{", name).unwrap();
+    for (column, ty) in table.columns.iter().zip(&types) {
+        let nullable = !(column.not_null || column.primary_key);
+        let optional = nullable || column.default.is_some();
+        writeln!(
+            out,
+            "  {}{}: {};",
+            property(&column.name),
+            if optional { "?" } else { "" },
+            ty
+        )
+        .unwrap();
+    }
+    writeln!(out, "}}").unwrap();
+}
+
+/// Generates the OpenAPI schemas of the Postgres `tables`, as read by
+/// [`postgres_tables`], that `exposures` lets the REST API serve, named like their
+/// TypeScript interfaces. The `comments` of each table, by qualified name, become the
+/// descriptions of its schema and properties.
+pub fn openapi(
+    tables: &[TableManifest],
+    exposures: &Exposures,
+    comments: &BTreeMap<String, TableComments>,
+) -> Components {
+    let mut components = ComponentsBuilder::new();
+    for table in tables {
+        if exposures
+            .get(&table.name)
+            .is_some_and(|exposure| !exposure.enabled)
+        {
+            continue;
+        }
+        let mut schema = ObjectBuilder::new().title(Some(&table.name));
+        for column in &table.columns {
+            let not_null = column.not_null || column.primary_key;
+            let ty = match column.data_type {
+                ColumnType::Integer => Some(Type::Integer),
+                ColumnType::Real => Some(Type::Number),
+                ColumnType::Boolean => Some(Type::Boolean),
+                ColumnType::Json => None,
+                ColumnType::Text | ColumnType::Timestamp | ColumnType::Blob => Some(Type::String),
+            };
+            let schema_type = match ty {
+                Some(ty) if not_null => SchemaType::Type(ty),
+                Some(ty) => SchemaType::from_iter([ty, Type::Null]),
+                None => SchemaType::AnyValue,
+            };
+            let format = match column.data_type {
+                ColumnType::Timestamp => Some(SchemaFormat::KnownFormat(KnownFormat::DateTime)),
+                ColumnType::Blob => Some(SchemaFormat::KnownFormat(KnownFormat::Byte)),
+                _ => None,
+            };
+            schema = schema.property(
+                &column.name,
+                ObjectBuilder::new().schema_type(schema_type).format(format),
+            );
+            if not_null {
+                schema = schema.required(&column.name);
+            }
+        }
+        let mut schema = schema.build();
+        if let Some(comments) = comments.get(&table.name) {
+            comments.apply(&mut schema);
+        }
+        components = components.schema(interface_name(&table.name), Schema::Object(schema));
+    }
+    components.build()
+}
+
+/// A TypeScript property name, quoted unless it is an identifier.
+fn property(name: &str) -> String {
+    let identifier = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '$')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$');
+    if identifier {
+        name.to_string()
+    } else {
+        string_literal(name)
+    }
+}
+
+fn string_literal(value: &str) -> String {
+    serde_json::Value::from(value).to_string()
+}
+
+/// `blog_posts` and `blog-posts` to `BlogPosts`.
+fn pascal_case(name: &str) -> String {
+    let mut out = String::new();
+    for word in name.split(|c: char| !c.is_ascii_alphanumeric()) {
+        let mut chars = word.chars();
+        if let Some(first) = chars.next() {
+            out.push(first.to_ascii_uppercase());
+            out.extend(chars);
+        }
+    }
+    if out.starts_with(|c: char| c.is_ascii_digit()) {
+        out.insert(0, '_');
+    }
+    out
+}
+
+/// `createdAt` and `created at` to `created_at`.
+fn snake_case(name: &str) -> String {
+    let mut out = String::new();
+    for c in name.chars() {
+        if c.is_ascii_uppercase() {
+            if !out.is_empty() && !out.ends_with('_') {
+                out.push('_');
+            }
+            out.push(c.to_ascii_lowercase());
+        } else if c.is_ascii_alphanumeric() {
+            out.push(c);
+        } else if !out.ends_with('_') {
+            out.push('_');
+        }
+    }
+    if out.is_empty() || out.starts_with(|c: char| c.is_ascii_digit()) {
+        out.insert(0, '_');
+    }
+    out
+}
+
+/// Strict and reserved keywords of edition 2024.
+const KEYWORDS: [&str; 52] = [
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "crate",
+    "do", "dyn", "else", "enum", "extern", "false", "final", "fn", "for", "gen", "if", "impl",
+    "in", "let", "loop", "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref",
+    "return", "self", "Self", "static", "struct", "super", "trait", "true", "try", "type",
+    "typeof", "unsafe", "unsized", "use", "virtual", "where", "while", "yield",
+];
+
+fn raw_ident(ident: String) -> String {
+    match ident.as_str() {
+        // Can't be raw identifiers.
+        "self" | "super" | "crate" | "Self" => format!("{}_", ident),
+        ident if KEYWORDS.contains(&ident) => format!("r#{}", ident),
+        _ => ident,
+    }
+}
+
+/// Returns `name`, numbered with `separator` if it is in `taken` already, and adds it to
+/// `taken`.
+fn unique(name: &str, separator: &str, taken: &mut BTreeSet<String>) -> String {
+    let mut candidate = name.to_string();
+    let mut number = 1;
+    while !taken.insert(candidate.clone()) {
+        number += 1;
+        candidate = format!("{}{}{}", name.trim_start_matches("r#"), separator, number);
+    }
+    candidate
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{embedded::PalmeraClient, exposure::TableExposure, manifest::SchemaManifest};
+    use serde::{Deserialize, Serialize};
+    use sqlx::Sqlite;
+    use std::path::Path;
+
+    const MANIFEST: &str = r#"
+        version = 1
+
+        [[tables]]
+        name = "blog_posts"
+        columns = [
+          { name = "id", data_type = "integer", primary_key = true },
+          { name = "title", data_type = "text", not_null = true },
+          { name = "type", data_type = "text" },
+          { name = "isDraft", data_type = "boolean", not_null = true, default = true },
+          { name = "meta", data_type = "json" },
+        ]
+    "#;
+
+    #[test]
+    fn test_rust() {
+        let schema = SchemaManifest::parse(Path::new("schema.toml"), MANIFEST).unwrap();
+        let fields = [
+            Field {
+                table_name: "blog_posts".into(),
+                column_name: "type".into(),
+                kind: FieldKind::select(["news", "how-to"]),
+            },
+            Field {
+                table_name: "blog_posts".into(),
+                column_name: "slug".into(),
+                kind: FieldKind::computed("lower(title)", ValueType::String),
+            },
+        ];
+
+        let code = rust(&schema.tables, &fields);
+        for expected in [
+            "pub struct BlogPosts {",
+            "    pub id: Option<i64>,",
+            "    pub title: String,",
+            "pub enum BlogPostsType {",
+            "    #[serde(rename = \"how-to\")]\n    #[sqlx(rename = \"how-to\")]\n    HowTo,",
+            "    #[serde(rename = \"type\")]\n    #[sqlx(rename = \"type\")]\n    pub r#type: Option<BlogPostsType>,",
+            "    #[serde(rename = \"isDraft\")]\n    #[sqlx(rename = \"isDraft\")]\n    pub is_draft: bool,",
+            "de::option_json\")]\n    pub meta: Option<sqlx::types::Json<serde_json::Value>>,",
+            "    #[serde(default, skip_serializing)]\n    #[sqlx(default)]\n    pub slug: Option<String>,",
+            "    pub const TABLE: &'static str = \"blog_posts\";",
+            "pub async fn get(session: &Session<'_>, id: i64)",
+            "let id = self.id.ok_or(ClientError::NotFound)?;",
+        ] {
+            assert!(code.contains(expected), "missing {expected:?} in:\n{code}");
+        }
+        assert_eq!(snake_case("created at"), "created_at");
+        assert_eq!(pascal_case("2fa_codes"), "_2faCodes");
+        assert_eq!(raw_ident("self".into()), "self_");
+        assert_eq!(raw_ident("gen".into()), "r#gen");
+    }
+
+    #[test]
+    fn test_rust_collisions() {
+        let schema = SchemaManifest::parse(
+            Path::new("schema.toml"),
+            r#"
+            version = 1
+
+            [[tables]]
+            name = "blog_posts"
+            columns = [
+              { name = "createdAt", data_type = "text" },
+              { name = "created_at", data_type = "text" },
+              { name = "type", data_type = "text" },
+              { name = "Type", data_type = "text" },
+            ]
+
+            [[tables]]
+            name = "blog-posts"
+            columns = [{ name = "id", data_type = "integer", primary_key = true }]
+
+            [[tables]]
+            name = "session"
+            columns = [{ name = "id", data_type = "integer", primary_key = true }]
+            "#,
+        )
+        .unwrap();
+        let code = rust(&schema.tables, &[]);
+        for expected in [
+            "pub struct BlogPosts {",
+            "    pub created_at: Option<String>,",
+            "    #[sqlx(rename = \"created_at\")]\n    pub created_at_2: Option<String>,",
+            "    pub r#type: Option<String>,",
+            "    #[sqlx(rename = \"Type\")]\n    pub type_2: Option<String>,",
+            "pub struct BlogPosts2 {",
+            "pub struct Session2 {",
+        ] {
+            assert!(code.contains(expected), "missing {expected:?} in:\n{code}");
+        }
+    }
+
+    #[sqlx::test]
+    async fn test_typescript(db: Pool<Postgres>) -> Result<(), crate::error::StoreError> {
+        for statement in [
+            "CREATE TYPE kind AS ENUM ('news', 'how-to')",
+            "CREATE TABLE blog_posts (id bigint GENERATED ALWAYS AS IDENTITY PRIMARY KEY, \
+             title text NOT NULL, type kind, \"isDraft\" boolean NOT NULL DEFAULT true, \
+             meta jsonb, tags text[], created_at timestamptz NOT NULL DEFAULT now())",
+            "CREATE TABLE \"audit-log\" (id serial PRIMARY KEY, message text NOT NULL)",
+            "CREATE TABLE secrets (id uuid PRIMARY KEY, value text NOT NULL)",
+            "CREATE SCHEMA billing",
+            "CREATE TABLE billing.invoices (id integer PRIMARY KEY, total numeric NOT NULL)",
+        ] {
+            sqlx::query(statement).execute(&db).await?;
+        }
+        let tables = postgres_tables(&db, &["public".into(), "billing".into()]).await?;
+        assert_eq!(
+            tables
+                .iter()
+                .map(|table| &table.name[..])
+                .collect::<Vec<_>>(),
+            [
+                "billing.invoices",
+                "public.audit-log",
+                "public.blog_posts",
+                "public.secrets"
+            ]
+        );
+
+        let exposures =
+            Exposures::open(sqlx::SqlitePool::connect("sqlite::memory:").await?).await?;
+        exposures
+            .set(TableExposure::new("public.audit-log").with_read_only(true))
+            .await?;
+        exposures
+            .set(TableExposure::new("public.secrets").with_enabled(false))
+            .await?;
+
+        let code = typescript(&tables, &exposures);
+        for expected in [
+            "export interface BlogPosts {\n  id: number;\n  title: string;\n  type: string | null;\n  \
+             isDraft: boolean;\n  meta: Json | null;\n  tags: Json | null;\n  created_at: string;\n}",
+            "export interface BlogPostsInsert {\n  id?: number;\n  title: string;\n  \
+             type?: string | null;\n  isDraft?: boolean;\n",
+            "export interface AuditLog {",
+            "export interface BillingInvoices {\n  id: number;\n  total: number;\n}",
+            "export interface BillingInvoicesInsert {\n  id: number;\n",
+            "export interface Tables {\n  \"billing.invoices\": BillingInvoices;\n  \
+             \"public.audit-log\": AuditLog;\n  \"public.blog_posts\": BlogPosts;\n}",
+        ] {
+            assert!(code.contains(expected), "missing {expected:?} in:\n{code}");
+        }
+        assert!(!code.contains("AuditLogInsert"));
+        assert!(!code.contains("Secrets"));
+
+        sqlx::query("COMMENT ON COLUMN blog_posts.title IS 'Headline of the post'")
+            .execute(&db)
+            .await?;
+        let comments = BTreeMap::from([(
+            "public.blog_posts".to_string(),
+            crate::comments::postgres_comments(&db, "public.blog_posts").await?,
+        )]);
+        let components = openapi(&tables, &exposures, &comments);
+        assert_eq!(
+            components.schemas.keys().collect::<Vec<_>>(),
+            ["AuditLog", "BillingInvoices", "BlogPosts"]
+        );
+        let schema = serde_json::to_value(&components.schemas["BlogPosts"]).unwrap();
+        assert_eq!(schema["title"], "public.blog_posts");
+        assert_eq!(
+            schema["required"],
+            serde_json::json!(["id", "title", "isDraft", "created_at"])
+        );
+        assert_eq!(
+            schema["properties"]["title"],
+            serde_json::json!({ "type": "string", "description": "Headline of the post" })
+        );
+        assert_eq!(
+            schema["properties"]["type"]["type"],
+            serde_json::json!(["string", "null"])
+        );
+        assert_eq!(schema["properties"]["created_at"]["format"], "date-time");
+        Ok(())
+    }
+
+    /// What the generator emits for `blog_posts`, without the select field.
+    #[derive(Debug, Serialize, Deserialize)]
+    struct BlogPosts {
+        #[serde(default)]
+        id: Option<i64>,
+        title: String,
+        #[serde(default)]
+        r#type: Option<String>,
+        #[serde(rename = "isDraft", deserialize_with = "de::bool")]
+        is_draft: bool,
+        #[serde(default, deserialize_with = "de::option_json")]
+        meta: Option<sqlx::types::Json<serde_json::Value>>,
+    }
+
+    #[sqlx::test]
+    async fn test_decode_embedded_records(db: Pool<Sqlite>) -> Result<(), ClientError> {
+        let manifest = SchemaManifest::parse(Path::new("schema.toml"), MANIFEST).unwrap();
+        crate::manifest::apply(&db, &manifest, false).await.unwrap();
+        let client = PalmeraClient::open(db).await?;
+        let session = client.anonymous();
+
+        let post = BlogPosts {
+            id: None,
+            title: "Hello".into(),
+            r#type: None,
+            is_draft: true,
+            meta: Some(sqlx::types::Json(serde_json::json!({ "tags": ["a"] }))),
+        };
+        let stored: BlogPosts = decode(session.create("blog_posts", encode(&post)?).await?)?;
+        assert_eq!(stored.id, Some(1));
+        assert!(stored.is_draft);
+        assert_eq!(stored.meta.unwrap().0["tags"][0], "a");
+        Ok(())
+    }
+}
@@ -0,0 +1,838 @@
+//! # Column migrations with data backfill
+//!
+//! Changing the type of a column, or renaming it while copying its values, can't be
+//! done by a single statement on a large table without blocking writers. A
+//! [`ColumnChange`] instead runs as a background job in four steps:
+//!
+//! 1. add the new column under a temporary name, with triggers keeping it in sync with
+//!    rows written while the job runs;
+//! 2. backfill it in batches of [`ColumnChange::batch_size`] rows, each in its own
+//!    transaction;
+//! 3. swap the columns: the old one is renamed to `{column}__old` and the new one takes
+//!    its final name;
+//! 4. drop the old column.
+//!
+//! Jobs and their progress are stored in the SQLite `_column_jobs` table after every
+//! step and batch, so a job interrupted by a failure or a restart resumes where it
+//! stopped: see [`ColumnJobs::resume`] and [`ColumnJobs::resume_all`]. The
+//! `/admin/tables/{table}/column-jobs` and `/admin/column-jobs` routes in
+//! [`crate::admin`] start jobs and report their progress.
+//!
+//! A job remembers the id of the request that started it, so its logs and the request's
+//! can be correlated: the job's task runs in a `column_job` tracing span carrying it.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # async fn run(db: sqlx::Pool<sqlx::Sqlite>) -> Result<(), palmera_database::ddl::SchemaError> {
+//! use std::sync::Arc;
+//! use palmera_database::{column_jobs::{ColumnChange, ColumnJobs}, ddl::ColumnType};
+//!
+//! let jobs = Arc::new(ColumnJobs::open(db).await?);
+//! // Pick up jobs interrupted by the last shutdown.
+//! jobs.clone().resume_all().await?;
+//!
+//! let change = ColumnChange::new("products", "price").with_type(ColumnType::Integer);
+//! let job = jobs.clone().start(change, None).await?;
+//! println!("job {} started", job.id);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Utc};
+use sea_query::{Alias, SqliteQueryBuilder, Table};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite, types::Json};
+use tokio::task::JoinHandle;
+use tracing::Instrument;
+use utoipa::ToSchema;
+
+use crate::{
+    ddl::{ColumnDefinition, ColumnType, SchemaError, quote, table_exists},
+    sqlite::helpers::create_column_jobs_table,
+};
+
+/// Rows backfilled per transaction unless a change says otherwise.
+pub const DEFAULT_BATCH_SIZE: u32 = 1000;
+
+/// A rename or type change of a column that copies its data.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct ColumnChange {
+    pub table_name: String,
+    pub column: String,
+    /// New name of the column, unchanged if absent.
+    #[serde(default)]
+    pub rename_to: Option<String>,
+    /// New type of the column, the declared type is kept if absent.
+    #[serde(default)]
+    pub data_type: Option<ColumnType>,
+    /// SQL expression computing the new value from the row, e.g.
+    /// `CAST(round(price * 100) AS INTEGER)`. Defaults to the old value cast to the new
+    /// type.
+    #[serde(default)]
+    pub using: Option<String>,
+    /// Rows backfilled per transaction.
+    #[serde(default = "default_batch_size")]
+    pub batch_size: u32,
+}
+
+fn default_batch_size() -> u32 {
+    DEFAULT_BATCH_SIZE
+}
+
+impl ColumnChange {
+    pub fn new(table: &str, column: &str) -> Self {
+        Self {
+            table_name: table.to_string(),
+            column: column.to_string(),
+            rename_to: None,
+            data_type: None,
+            using: None,
+            batch_size: DEFAULT_BATCH_SIZE,
+        }
+    }
+
+    pub fn with_rename(mut self, to: &str) -> Self {
+        self.rename_to = Some(to.to_string());
+        self
+    }
+
+    pub fn with_type(mut self, data_type: ColumnType) -> Self {
+        self.data_type = Some(data_type);
+        self
+    }
+
+    pub fn with_using(mut self, expression: &str) -> Self {
+        self.using = Some(expression.to_string());
+        self
+    }
+
+    pub fn with_batch_size(mut self, batch_size: u32) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Final name of the column.
+    pub fn target(&self) -> &str {
+        self.rename_to.as_deref().unwrap_or(&self.column)
+    }
+
+    fn temporary(&self) -> String {
+        format!("{}__new", self.target())
+    }
+
+    fn retired(&self) -> String {
+        format!("{}__old", self.column)
+    }
+
+    fn expression(&self) -> String {
+        if let Some(using) = &self.using {
+            return using.clone();
+        }
+        let column = quote(&self.column);
+        match self.data_type {
+            None => column,
+            Some(ColumnType::Text | ColumnType::Timestamp) => format!("CAST({column} AS TEXT)"),
+            Some(ColumnType::Integer | ColumnType::Boolean) => {
+                format!("CAST({column} AS INTEGER)")
+            }
+            Some(ColumnType::Real) => format!("CAST({column} AS REAL)"),
+            Some(ColumnType::Blob) => format!("CAST({column} AS BLOB)"),
+            Some(ColumnType::Json) => format!("json({column})"),
+        }
+    }
+
+    fn trigger(&self, event: &str) -> String {
+        quote(&format!(
+            "_column_job_{}_{}_{event}",
+            self.table_name,
+            self.temporary()
+        ))
+    }
+}
+
+/// Where a job is in the migration.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStep {
+    AddColumn,
+    Backfill,
+    Swap,
+    DropOld,
+    Done,
+}
+
+impl JobStep {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::AddColumn => "add_column",
+            Self::Backfill => "backfill",
+            Self::Swap => "swap",
+            Self::DropOld => "drop_old",
+            Self::Done => "done",
+        }
+    }
+
+    fn parse(step: &str) -> Result<Self, sqlx::Error> {
+        match step {
+            "add_column" => Ok(Self::AddColumn),
+            "backfill" => Ok(Self::Backfill),
+            "swap" => Ok(Self::Swap),
+            "drop_old" => Ok(Self::DropOld),
+            "done" => Ok(Self::Done),
+            other => Err(sqlx::Error::Decode(
+                format!("unknown job step {other:?}").into(),
+            )),
+        }
+    }
+}
+
+/// Whether a job is running.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    /// Running, or interrupted by a restart and waiting to be resumed.
+    Running,
+    /// Stopped by an error; resuming retries the failed step.
+    Failed,
+    Completed,
+}
+
+impl JobStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Running => "running",
+            Self::Failed => "failed",
+            Self::Completed => "completed",
+        }
+    }
+
+    fn parse(status: &str) -> Result<Self, sqlx::Error> {
+        match status {
+            "running" => Ok(Self::Running),
+            "failed" => Ok(Self::Failed),
+            "completed" => Ok(Self::Completed),
+            other => Err(sqlx::Error::Decode(
+                format!("unknown job status {other:?}").into(),
+            )),
+        }
+    }
+}
+
+/// A column migration and its progress.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct ColumnJob {
+    pub id: i64,
+    pub change: ColumnChange,
+    pub step: JobStep,
+    pub status: JobStatus,
+    /// Rows backfilled so far.
+    pub processed: u64,
+    /// Rows in the table when the backfill started.
+    pub total: u64,
+    pub error: Option<String>,
+    /// `X-Request-Id` of the request that started the job.
+    pub request_id: Option<String>,
+    pub updated: DateTime<Utc>,
+}
+
+type JobRow = (
+    i64,
+    Json<ColumnChange>,
+    String,
+    String,
+    i64,
+    i64,
+    Option<String>,
+    Option<String>,
+    i64,
+);
+
+const JOB_COLUMNS: &str = "id, change, step, status, processed, total, error, request_id, updated";
+
+/// Column migration jobs persisted in SQLite.
+#[derive(Debug)]
+pub struct ColumnJobs {
+    db: Pool<Sqlite>,
+    /// Jobs with a task currently working on them.
+    active: Mutex<HashSet<i64>>,
+}
+
+impl ColumnJobs {
+    /// Opens the store, creating the `_column_jobs` table if it does not exist.
+    pub async fn open(db: Pool<Sqlite>) -> Result<Self, sqlx::Error> {
+        sqlx::query(&create_column_jobs_table().to_string(SqliteQueryBuilder))
+            .execute(&db)
+            .await?;
+
+        Ok(Self {
+            db,
+            active: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Returns the job `id`, if it exists.
+    pub async fn get(&self, id: i64) -> Result<Option<ColumnJob>, sqlx::Error> {
+        let row: Option<JobRow> = sqlx::query_as(&format!(
+            "SELECT {JOB_COLUMNS} FROM _column_jobs WHERE id = ?"
+        ))
+        .bind(id)
+        .fetch_optional(&self.db)
+        .await?;
+
+        row.map(into_job).transpose()
+    }
+
+    /// Returns every job, most recent first.
+    pub async fn list(&self) -> Result<Vec<ColumnJob>, sqlx::Error> {
+        let rows: Vec<JobRow> = sqlx::query_as(&format!(
+            "SELECT {JOB_COLUMNS} FROM _column_jobs ORDER BY id DESC"
+        ))
+        .fetch_all(&self.db)
+        .await?;
+
+        rows.into_iter().map(into_job).collect()
+    }
+
+    /// Checks `change` against the table, records it as a new job and starts it on a
+    /// background task. `request_id` is the id of the request asking for it, if any.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`SchemaError::NotFound`] if the table or column doesn't exist,
+    /// [`SchemaError::Conflict`] if the target name is taken or the table already has
+    /// a job in progress, and [`SchemaError::Invalid`] if the change does nothing, its
+    /// batch size is zero or its expression doesn't compile.
+    pub async fn start(
+        self: Arc<Self>,
+        change: ColumnChange,
+        request_id: Option<String>,
+    ) -> Result<ColumnJob, SchemaError> {
+        let job = self.create(change, request_id).await?;
+        self.spawn(&job);
+        Ok(job)
+    }
+
+    /// Resumes a job that failed or was interrupted on a background task, returning it,
+    /// or `None` if it doesn't exist.
+    pub async fn resume(self: Arc<Self>, id: i64) -> Result<Option<ColumnJob>, sqlx::Error> {
+        let Some(job) = self.get(id).await? else {
+            return Ok(None);
+        };
+        if job.status != JobStatus::Completed {
+            self.clone().spawn(&job);
+        }
+        Ok(Some(job))
+    }
+
+    /// Resumes every running job, e.g. after a restart. Failed jobs wait for an
+    /// explicit [`ColumnJobs::resume`].
+    pub async fn resume_all(self: Arc<Self>) -> Result<Vec<ColumnJob>, sqlx::Error> {
+        let jobs = self
+            .list()
+            .await?
+            .into_iter()
+            .filter(|job| job.status == JobStatus::Running)
+            .collect::<Vec<_>>();
+        for job in &jobs {
+            self.clone().spawn(job);
+        }
+        Ok(jobs)
+    }
+
+    fn spawn(self: Arc<Self>, job: &ColumnJob) -> Option<JoinHandle<()>> {
+        let id = job.id;
+        if !self.active.lock().unwrap().insert(id) {
+            // Another task is already working on it.
+            return None;
+        }
+
+        let span = tracing::info_span!(
+            "column_job",
+            job = id,
+            request_id = job.request_id.as_deref(),
+        );
+        Some(tokio::spawn(
+            async move {
+                if let Err(err) = self.run(id).await {
+                    tracing::warn!(error = %err, "column migration failed");
+                }
+                self.active.lock().unwrap().remove(&id);
+            }
+            .instrument(span),
+        ))
+    }
+
+    async fn create(
+        &self,
+        change: ColumnChange,
+        request_id: Option<String>,
+    ) -> Result<ColumnJob, SchemaError> {
+        let table = &change.table_name;
+        if !table_exists(&self.db, table).await? {
+            return Err(SchemaError::NotFound(format!("table {} not found", table)));
+        }
+        let columns: Vec<String> =
+            sqlx::query_scalar("SELECT name FROM pragma_table_info(?) ORDER BY cid")
+                .bind(table)
+                .fetch_all(&self.db)
+                .await?;
+        if !columns.contains(&change.column) {
+            return Err(SchemaError::NotFound(format!(
+                "column {} not found",
+                change.column
+            )));
+        }
+        if change.rename_to.is_none() && change.data_type.is_none() {
+            return Err(SchemaError::Invalid(
+                "a column change needs a new name or a new type".to_string(),
+            ));
+        }
+        if change.batch_size == 0 {
+            return Err(SchemaError::Invalid(
+                "batch_size must be at least 1".to_string(),
+            ));
+        }
+        for name in [change.temporary(), change.retired()]
+            .iter()
+            .chain(change.rename_to.as_ref())
+        {
+            if columns.contains(name) {
+                return Err(SchemaError::Conflict(format!(
+                    "column {} already exists",
+                    name
+                )));
+            }
+        }
+        let active: bool = sqlx::query_scalar(
+            "SELECT EXISTS (SELECT 1 FROM _column_jobs WHERE table_name = ? AND status != 'completed')",
+        )
+        .bind(table)
+        .fetch_one(&self.db)
+        .await?;
+        if active {
+            return Err(SchemaError::Conflict(format!(
+                "table {} already has a column migration in progress",
+                table
+            )));
+        }
+        sqlx::query(&format!(
+            "SELECT {} FROM {} LIMIT 0",
+            change.expression(),
+            quote(table)
+        ))
+        .execute(&self.db)
+        .await
+        .map_err(|err| SchemaError::Invalid(format!("invalid expression: {err}")))?;
+
+        let id: i64 = sqlx::query_scalar(
+            "INSERT INTO _column_jobs (table_name, change, step, status, request_id, updated) \
+             VALUES (?, ?, ?, ?, ?, ?) RETURNING id",
+        )
+        .bind(table)
+        .bind(Json(&change))
+        .bind(JobStep::AddColumn.as_str())
+        .bind(JobStatus::Running.as_str())
+        .bind(request_id)
+        .bind(Utc::now().timestamp_millis())
+        .fetch_one(&self.db)
+        .await?;
+
+        Ok(self.get(id).await?.expect("job was just inserted"))
+    }
+
+    /// Runs job `id` to completion from its current step. Errors are also recorded on
+    /// the job.
+    async fn run(&self, id: i64) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE _column_jobs SET status = ?, error = NULL, updated = ? WHERE id = ?")
+            .bind(JobStatus::Running.as_str())
+            .bind(Utc::now().timestamp_millis())
+            .bind(id)
+            .execute(&self.db)
+            .await?;
+
+        let result = self.steps(id).await;
+        let (status, error) = match &result {
+            Ok(()) => (JobStatus::Completed, None),
+            Err(err) => (JobStatus::Failed, Some(err.to_string())),
+        };
+        sqlx::query("UPDATE _column_jobs SET status = ?, error = ?, updated = ? WHERE id = ?")
+            .bind(status.as_str())
+            .bind(error)
+            .bind(Utc::now().timestamp_millis())
+            .bind(id)
+            .execute(&self.db)
+            .await?;
+
+        result
+    }
+
+    async fn steps(&self, id: i64) -> Result<(), sqlx::Error> {
+        let (Json(change), step, last_rowid): (Json<ColumnChange>, String, i64) =
+            sqlx::query_as("SELECT change, step, last_rowid FROM _column_jobs WHERE id = ?")
+                .bind(id)
+                .fetch_one(&self.db)
+                .await?;
+        let mut step = JobStep::parse(&step)?;
+
+        if step == JobStep::AddColumn {
+            self.add_column(id, &change).await?;
+            step = JobStep::Backfill;
+        }
+        if step == JobStep::Backfill {
+            self.backfill(id, &change, last_rowid).await?;
+            step = JobStep::Swap;
+        }
+        if step == JobStep::Swap {
+            self.swap(id, &change).await?;
+            step = JobStep::DropOld;
+        }
+        if step == JobStep::DropOld {
+            let mut conn = self.db.acquire().await?;
+            // DROP COLUMN fails with "no such column" on a connection whose cached
+            // schema predates the swap, so make it reload the schema first.
+            sqlx::query("SELECT count(*) FROM sqlite_master")
+                .execute(&mut *conn)
+                .await?;
+            sqlx::query(&format!(
+                "ALTER TABLE {} DROP COLUMN {}",
+                quote(&change.table_name),
+                quote(&change.retired())
+            ))
+            .execute(&mut *conn)
+            .await?;
+            self.set_step(id, JobStep::Done).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Adds the temporary column and the triggers keeping it in sync.
+    async fn add_column(&self, id: i64, change: &ColumnChange) -> Result<(), sqlx::Error> {
+        let table = quote(&change.table_name);
+        let temporary = quote(&change.temporary());
+        let mut tx = self.db.begin().await?;
+        let add = match change.data_type {
+            Some(data_type) => Table::alter()
+                .table(Alias::new(&change.table_name))
+                .add_column(
+                    ColumnDefinition {
+                        name: change.temporary(),
+                        data_type,
+                        not_null: false,
+                        primary_key: false,
+                        unique: false,
+                        default: None,
+                    }
+                    .to_column_def(),
+                )
+                .to_string(SqliteQueryBuilder),
+            None => {
+                let declared: String =
+                    sqlx::query_scalar("SELECT type FROM pragma_table_info(?) WHERE name = ?")
+                        .bind(&change.table_name)
+                        .bind(&change.column)
+                        .fetch_one(&mut *tx)
+                        .await?;
+                format!("ALTER TABLE {table} ADD COLUMN {temporary} {declared}")
+            }
+        };
+        sqlx::query(&add).execute(&mut *tx).await?;
+
+        // Rows written during the backfill are copied as they change.
+        let sync = format!(
+            "BEGIN UPDATE {table} SET {temporary} = {} WHERE rowid = NEW.rowid; END",
+            change.expression()
+        );
+        sqlx::query(&format!(
+            "CREATE TRIGGER {} AFTER INSERT ON {table} {sync}",
+            change.trigger("insert")
+        ))
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(&format!(
+            "CREATE TRIGGER {} AFTER UPDATE OF {} ON {table} {sync}",
+            change.trigger("update"),
+            quote(&change.column)
+        ))
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query(&format!(
+            "UPDATE _column_jobs SET step = ?, total = (SELECT count(*) FROM {table}), \
+             updated = ? WHERE id = ?"
+        ))
+        .bind(JobStep::Backfill.as_str())
+        .bind(Utc::now().timestamp_millis())
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Copies the old column into the temporary one, batch by batch, starting after
+    /// `last_rowid`.
+    async fn backfill(
+        &self,
+        id: i64,
+        change: &ColumnChange,
+        last_rowid: i64,
+    ) -> Result<(), sqlx::Error> {
+        let table = quote(&change.table_name);
+        let temporary = quote(&change.temporary());
+        let mut last_rowid = last_rowid;
+        loop {
+            let mut tx = self.db.begin().await?;
+            let end: Option<i64> = sqlx::query_scalar(&format!(
+                "SELECT max(rowid) FROM (SELECT rowid FROM {table} WHERE rowid > ? \
+                 ORDER BY rowid LIMIT ?)"
+            ))
+            .bind(last_rowid)
+            .bind(change.batch_size)
+            .fetch_one(&mut *tx)
+            .await?;
+            let Some(end) = end else {
+                break;
+            };
+
+            let result = sqlx::query(&format!(
+                "UPDATE {table} SET {temporary} = {} WHERE rowid > ? AND rowid <= ?",
+                change.expression()
+            ))
+            .bind(last_rowid)
+            .bind(end)
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query(
+                "UPDATE _column_jobs SET last_rowid = ?, processed = processed + ?, \
+                 updated = ? WHERE id = ?",
+            )
+            .bind(end)
+            .bind(result.rows_affected() as i64)
+            .bind(Utc::now().timestamp_millis())
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+            tx.commit().await?;
+
+            last_rowid = end;
+        }
+
+        self.set_step(id, JobStep::Swap).await
+    }
+
+    /// Retires the old column and gives the new one its final name.
+    async fn swap(&self, id: i64, change: &ColumnChange) -> Result<(), sqlx::Error> {
+        let table = quote(&change.table_name);
+        let temporary = quote(&change.temporary());
+        let mut tx = self.db.begin().await?;
+        for event in ["insert", "update"] {
+            sqlx::query(&format!("DROP TRIGGER IF EXISTS {}", change.trigger(event)))
+                .execute(&mut *tx)
+                .await?;
+        }
+        sqlx::query(&format!(
+            "ALTER TABLE {table} RENAME COLUMN {} TO {}",
+            quote(&change.column),
+            quote(&change.retired())
+        ))
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(&format!(
+            "ALTER TABLE {table} RENAME COLUMN {temporary} TO {}",
+            quote(change.target())
+        ))
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("UPDATE _column_jobs SET step = ?, updated = ? WHERE id = ?")
+            .bind(JobStep::DropOld.as_str())
+            .bind(Utc::now().timestamp_millis())
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn set_step(&self, id: i64, step: JobStep) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE _column_jobs SET step = ?, updated = ? WHERE id = ?")
+            .bind(step.as_str())
+            .bind(Utc::now().timestamp_millis())
+            .bind(id)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+}
+
+fn into_job(
+    (id, Json(change), step, status, processed, total, error, request_id, updated): JobRow,
+) -> Result<ColumnJob, sqlx::Error> {
+    Ok(ColumnJob {
+        id,
+        change,
+        step: JobStep::parse(&step)?,
+        status: JobStatus::parse(&status)?,
+        processed: processed as u64,
+        total: total as u64,
+        error,
+        request_id,
+        updated: DateTime::from_timestamp_millis(updated).unwrap_or_default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn create_products(db: &Pool<Sqlite>) -> sqlx::Result<()> {
+        sqlx::query("CREATE TABLE products (id INTEGER PRIMARY KEY, name TEXT, price TEXT)")
+            .execute(db)
+            .await?;
+        for (name, price) in [("a", "1.50"), ("b", "20"), ("c", "3.25")] {
+            sqlx::query("INSERT INTO products (name, price) VALUES (?, ?)")
+                .bind(name)
+                .bind(price)
+                .execute(db)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn columns(db: &Pool<Sqlite>) -> sqlx::Result<Vec<(String, String)>> {
+        sqlx::query_as("SELECT name, type FROM pragma_table_info('products') ORDER BY cid")
+            .fetch_all(db)
+            .await
+    }
+
+    #[sqlx::test]
+    async fn test_type_change_with_rename(db: Pool<Sqlite>) -> Result<(), SchemaError> {
+        create_products(&db).await?;
+        let jobs = ColumnJobs::open(db.clone()).await?;
+
+        let change = ColumnChange::new("products", "price")
+            .with_rename("price_cents")
+            .with_type(ColumnType::Integer)
+            .with_using("CAST(round(price * 100) AS INTEGER)")
+            .with_batch_size(2);
+        let job = jobs.create(change, Some("req-1".to_string())).await?;
+        assert_eq!(job.step, JobStep::AddColumn);
+        assert_eq!(job.request_id.as_deref(), Some("req-1"));
+
+        jobs.run(job.id).await?;
+        let job = jobs.get(job.id).await?.unwrap();
+        assert_eq!(job.status, JobStatus::Completed);
+        assert_eq!(job.step, JobStep::Done);
+        assert_eq!((job.processed, job.total), (3, 3));
+
+        assert_eq!(
+            columns(&db).await?,
+            [
+                ("id".to_string(), "INTEGER".to_string()),
+                ("name".to_string(), "TEXT".to_string()),
+                ("price_cents".to_string(), "INTEGER".to_string()),
+            ]
+        );
+        let prices: Vec<i64> = sqlx::query_scalar("SELECT price_cents FROM products ORDER BY id")
+            .fetch_all(&db)
+            .await?;
+        assert_eq!(prices, [150, 2000, 325]);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_resume_after_failure(db: Pool<Sqlite>) -> Result<(), SchemaError> {
+        create_products(&db).await?;
+        let jobs = ColumnJobs::open(db.clone()).await?;
+        let job = jobs
+            .create(
+                ColumnChange::new("products", "price")
+                    .with_type(ColumnType::Real)
+                    .with_batch_size(1),
+                None,
+            )
+            .await?;
+
+        // An index on the old column makes dropping it fail.
+        sqlx::query("CREATE INDEX products_price ON products (price)")
+            .execute(&db)
+            .await?;
+        assert!(jobs.run(job.id).await.is_err());
+        let failed = jobs.get(job.id).await?.unwrap();
+        assert_eq!(failed.status, JobStatus::Failed);
+        assert_eq!(failed.step, JobStep::DropOld);
+        assert!(failed.error.is_some());
+
+        // Rows written meanwhile are kept in sync by the triggers until the swap.
+        sqlx::query("DROP INDEX products_price")
+            .execute(&db)
+            .await?;
+        jobs.run(job.id).await?;
+        assert_eq!(
+            jobs.get(job.id).await?.unwrap().status,
+            JobStatus::Completed
+        );
+        let prices: Vec<f64> = sqlx::query_scalar("SELECT price FROM products ORDER BY id")
+            .fetch_all(&db)
+            .await?;
+        assert_eq!(prices, [1.5, 20.0, 3.25]);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_sync_during_backfill(db: Pool<Sqlite>) -> Result<(), SchemaError> {
+        create_products(&db).await?;
+        let jobs = ColumnJobs::open(db.clone()).await?;
+        let job = jobs
+            .create(
+                ColumnChange::new("products", "price").with_rename("cost"),
+                None,
+            )
+            .await?;
+        jobs.add_column(job.id, &job.change).await?;
+        sqlx::query("INSERT INTO products (name, price) VALUES ('d', '9')")
+            .execute(&db)
+            .await?;
+        sqlx::query("UPDATE products SET price = '2' WHERE name = 'a'")
+            .execute(&db)
+            .await?;
+        let synced: Vec<Option<String>> =
+            sqlx::query_scalar("SELECT cost__new FROM products ORDER BY id")
+                .fetch_all(&db)
+                .await?;
+        assert_eq!(
+            synced,
+            [Some("2".to_string()), None, None, Some("9".to_string())]
+        );
+
+        jobs.run(job.id).await?;
+        let costs: Vec<String> = sqlx::query_scalar("SELECT cost FROM products ORDER BY id")
+            .fetch_all(&db)
+            .await?;
+        assert_eq!(costs, ["2", "20", "3.25", "9"]);
+
+        let invalid = jobs
+            .create(
+                ColumnChange::new("products", "name")
+                    .with_type(ColumnType::Text)
+                    .with_using("nope("),
+                None,
+            )
+            .await;
+        assert!(matches!(invalid, Err(SchemaError::Invalid(_))));
+        let missing = jobs
+            .create(
+                ColumnChange::new("products", "price").with_rename("x"),
+                None,
+            )
+            .await;
+        assert!(matches!(missing, Err(SchemaError::NotFound(_))));
+        Ok(())
+    }
+}
@@ -0,0 +1,271 @@
+//! # Schema comments
+//!
+//! Tables and columns can carry a comment documenting what they hold, so the
+//! documentation lives with the schema instead of drifting apart from it. Comments are
+//! published as the `description` of the table's OpenAPI schema and of its properties
+//! with [`TableComments::apply`], which [`crate::codegen::openapi`] does, and returned by
+//! the `/admin/comments` routes in [`crate::admin`] as help texts for the admin UI's
+//! forms.
+//!
+//! Postgres stores comments itself (`COMMENT ON COLUMN posts.title IS '...'`); they are
+//! read from `pg_description` with [`postgres_comments`]. SQLite has no comments, so
+//! [`Comments`] keeps them in the `_comments` table, keyed by table and column. The
+//! admin routes return both, those edited in `_comments` first.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # async fn run(db: sqlx::Pool<sqlx::Sqlite>) -> Result<(), sqlx::Error> {
+//! use palmera_database::comments::Comments;
+//!
+//! let comments = Comments::open(db).await?;
+//! comments.set("posts", None, "Articles shown on the blog").await?;
+//! comments
+//!     .set("posts", Some("slug"), "URL path of the post, unique per blog")
+//!     .await?;
+//!
+//! let posts = comments.for_table("posts").await?;
+//! assert_eq!(posts.column("slug"), Some("URL path of the post, unique per blog"));
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::BTreeMap;
+
+use sea_query::SqliteQueryBuilder;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres, Sqlite};
+use utoipa::{
+    ToSchema,
+    openapi::{RefOr, Schema, schema::Object},
+};
+
+use crate::{postgres::helpers::quote_qualified, sqlite::helpers::create_comments_table};
+
+/// The comments of a table and its columns.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+pub struct TableComments {
+    /// Comment of the table itself.
+    pub table: Option<String>,
+    /// Comments of the columns, by column name.
+    pub columns: BTreeMap<String, String>,
+}
+
+impl TableComments {
+    /// Returns the comment of `column`, if it has one.
+    pub fn column(&self, column: &str) -> Option<&str> {
+        self.columns.get(column).map(String::as_str)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.table.is_none() && self.columns.is_empty()
+    }
+
+    /// Completes these comments with those of `fallback` they don't set, e.g. the
+    /// comments stored by Postgres under those edited in [`Comments`].
+    pub fn or(mut self, fallback: TableComments) -> Self {
+        self.table = self.table.or(fallback.table);
+        for (column, comment) in fallback.columns {
+            self.columns.entry(column).or_insert(comment);
+        }
+        self
+    }
+
+    /// Sets the comments as descriptions of the table's object `schema` and of its
+    /// properties. Apply it after [`TableFields::apply`](crate::fields::TableFields::apply),
+    /// which replaces the properties of managed fields. Comments of columns that aren't
+    /// in `schema` are ignored.
+    pub fn apply(&self, schema: &mut Object) {
+        if let Some(comment) = &self.table {
+            schema.description = Some(comment.clone());
+        }
+        for (column, comment) in &self.columns {
+            if let Some(RefOr::T(Schema::Object(property))) = schema.properties.get_mut(column) {
+                property.description = Some(comment.clone());
+            }
+        }
+    }
+}
+
+/// Reads the comments of the Postgres table `table`, optionally schema qualified
+/// (`auth.users`), from `pg_description`. A table that doesn't exist has none.
+pub async fn postgres_comments(
+    db: &Pool<Postgres>,
+    table: &str,
+) -> Result<TableComments, sqlx::Error> {
+    let rows: Vec<(Option<String>, String)> = sqlx::query_as(
+        "SELECT a.attname::text, d.description \
+         FROM pg_description d \
+         LEFT JOIN pg_attribute a ON a.attrelid = d.objoid AND a.attnum = d.objsubid \
+         WHERE d.classoid = 'pg_class'::regclass AND d.objoid = to_regclass($1) \
+           AND (d.objsubid = 0 OR NOT a.attisdropped) \
+         ORDER BY d.objsubid",
+    )
+    .bind(quote_qualified(table))
+    .fetch_all(db)
+    .await?;
+
+    Ok(into_comments(rows))
+}
+
+fn into_comments(rows: Vec<(Option<String>, String)>) -> TableComments {
+    let mut comments = TableComments::default();
+    for (column, comment) in rows {
+        match column {
+            Some(column) => {
+                comments.columns.insert(column, comment);
+            }
+            None => comments.table = Some(comment),
+        }
+    }
+    comments
+}
+
+/// Comments of SQLite tables and columns, persisted in the `_comments` table.
+#[derive(Debug, Clone)]
+pub struct Comments {
+    db: Pool<Sqlite>,
+}
+
+impl Comments {
+    /// Opens the store, creating the `_comments` table if it does not exist.
+    pub async fn open(db: Pool<Sqlite>) -> Result<Self, sqlx::Error> {
+        sqlx::query(&create_comments_table().to_string(SqliteQueryBuilder))
+            .execute(&db)
+            .await?;
+
+        Ok(Self { db })
+    }
+
+    /// Sets the comment of `column` of `table`, or of the table itself without a
+    /// column, replacing any previous one.
+    pub async fn set(
+        &self,
+        table: &str,
+        column: Option<&str>,
+        comment: &str,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO _comments (table_name, column_name, comment) VALUES (?, ?, ?) \
+             ON CONFLICT (table_name, column_name) DO UPDATE SET comment = excluded.comment",
+        )
+        .bind(table)
+        .bind(column.unwrap_or_default())
+        .bind(comment)
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Returns the comments of `table` and its columns.
+    pub async fn for_table(&self, table: &str) -> Result<TableComments, sqlx::Error> {
+        let rows: Vec<(Option<String>, String)> = sqlx::query_as(
+            "SELECT NULLIF(column_name, ''), comment FROM _comments \
+             WHERE table_name = ? ORDER BY column_name",
+        )
+        .bind(table)
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(into_comments(rows))
+    }
+
+    /// Removes the comment of `column`, or of the table itself without a column,
+    /// returning whether there was one.
+    pub async fn remove(&self, table: &str, column: Option<&str>) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM _comments WHERE table_name = ? AND column_name = ?")
+            .bind(table)
+            .bind(column.unwrap_or_default())
+            .execute(&self.db)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utoipa::openapi::schema::{ObjectBuilder, Type};
+
+    fn description(schema: &Object, property: &str) -> Option<String> {
+        let RefOr::T(Schema::Object(property)) = &schema.properties[property] else {
+            panic!("{property} is not an object schema");
+        };
+        property.description.clone()
+    }
+
+    #[sqlx::test]
+    async fn test_sqlite_comments(db: Pool<Sqlite>) -> sqlx::Result<()> {
+        let comments = Comments::open(db).await?;
+        comments.set("posts", None, "Blog articles").await?;
+        comments.set("posts", Some("slug"), "URL path").await?;
+        comments
+            .set("posts", Some("slug"), "URL path of the post")
+            .await?;
+        comments.set("users", Some("slug"), "Profile path").await?;
+
+        let posts = comments.for_table("posts").await?;
+        assert_eq!(posts.table.as_deref(), Some("Blog articles"));
+        assert_eq!(posts.column("slug"), Some("URL path of the post"));
+        assert_eq!(posts.columns.len(), 1);
+
+        assert!(comments.remove("posts", None).await?);
+        assert!(!comments.remove("posts", None).await?);
+        let posts = comments.for_table("posts").await?;
+        assert_eq!(posts.table, None);
+        assert_eq!(posts.column("slug"), Some("URL path of the post"));
+        assert!(comments.for_table("tags").await?.is_empty());
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_postgres_comments(db: Pool<Postgres>) -> sqlx::Result<()> {
+        sqlx::query("CREATE TABLE posts (id serial PRIMARY KEY, title text, legacy text)")
+            .execute(&db)
+            .await?;
+        sqlx::query("COMMENT ON TABLE posts IS 'Blog articles'")
+            .execute(&db)
+            .await?;
+        sqlx::query("COMMENT ON COLUMN posts.title IS 'Headline shown in lists'")
+            .execute(&db)
+            .await?;
+        sqlx::query("COMMENT ON COLUMN posts.legacy IS 'Unused'")
+            .execute(&db)
+            .await?;
+        sqlx::query("ALTER TABLE posts DROP COLUMN legacy")
+            .execute(&db)
+            .await?;
+
+        let posts = postgres_comments(&db, "public.posts").await?;
+        assert_eq!(posts.table.as_deref(), Some("Blog articles"));
+        assert_eq!(
+            posts.columns,
+            BTreeMap::from([("title".to_string(), "Headline shown in lists".to_string())])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_sets_descriptions() {
+        let comments = TableComments {
+            table: Some("Blog articles".to_string()),
+            columns: BTreeMap::from([
+                ("title".to_string(), "Headline".to_string()),
+                ("missing".to_string(), "Not a column".to_string()),
+            ]),
+        };
+
+        let mut schema = ObjectBuilder::new()
+            .property("id", ObjectBuilder::new().schema_type(Type::Integer))
+            .property("title", ObjectBuilder::new().schema_type(Type::String))
+            .build();
+        comments.apply(&mut schema);
+
+        assert_eq!(schema.description.as_deref(), Some("Blog articles"));
+        assert_eq!(description(&schema, "title").as_deref(), Some("Headline"));
+        assert_eq!(description(&schema, "id"), None);
+        assert!(!schema.properties.contains_key("missing"));
+    }
+}
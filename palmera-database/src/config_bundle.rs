@@ -0,0 +1,669 @@
+//! # Configuration bundles
+//!
+//! A [`ConfigBundle`] is a portable JSON snapshot of how an instance is configured: its
+//! tables with their columns and indexes, the policies of [`crate::embedded`], the
+//! managed [`Fields`] validating records, and the runtime [`Settings`]. Exporting a
+//! bundle from one environment and importing it into another promotes a configuration
+//! from development to staging to production without replaying admin calls by hand.
+//! Records are not part of the bundle.
+//!
+//! [`import`] works like a [`manifest`](crate::manifest) without a version: it creates
+//! missing tables, columns and indexes, brings the policies of the bundle's tables in
+//! line with it, defines its fields and sets its settings. Nothing else is dropped, and
+//! live columns the bundle doesn't declare are reported as drift. Deleting policies is
+//! destructive and must be forced. [`plan_import`] returns the same report without
+//! changing anything.
+//!
+//! Settings holding credentials, as told by [`settings::is_secret`], are left out of the
+//! bundle unless [`export`] is asked to include them, in which case the bundle must be
+//! handled as a secret. Importing a bundle without them keeps the target's own.
+//! Exporting only reads: it creates none of the tables it looks at.
+//!
+//! The `palmera-database export-config` and `import-config` commands, and the
+//! `/admin/export-config` and `/admin/import-config` routes of [`crate::admin`], expose
+//! the bundles.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # async fn run(
+//! #     dev: sqlx::Pool<sqlx::Sqlite>,
+//! #     prod: sqlx::Pool<sqlx::Sqlite>,
+//! # ) -> Result<(), palmera_database::ddl::SchemaError> {
+//! use palmera_database::{config_bundle, settings::Settings};
+//!
+//! let bundle = config_bundle::export(&dev, false).await?;
+//! let settings = Settings::open(prod.clone()).await?;
+//! let report = config_bundle::import(&prod, &settings, &bundle, false).await?;
+//! for drift in &report.schema.drift {
+//!     tracing::warn!(%drift, "production drifted from development");
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use sea_query::SqliteQueryBuilder;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite, types::Json};
+use utoipa::ToSchema;
+
+use crate::{
+    ddl::{self, ColumnDefinition, ColumnType, ForeignKey, SchemaError},
+    fields::{Field, Fields},
+    manifest::{self, IndexManifest, ManifestPlan, SchemaManifest, TableManifest},
+    settings::{self, Settings},
+    sqlite::helpers::create_fields_table,
+};
+
+/// Format of the bundles [`export`] produces. Bundles of a newer format are rejected.
+pub const BUNDLE_FORMAT: u32 = 1;
+
+/// The configuration of an instance.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct ConfigBundle {
+    /// Format of the bundle, see [`BUNDLE_FORMAT`].
+    pub format: u32,
+    /// When the bundle was exported (UTC).
+    pub exported_at: DateTime<Utc>,
+    /// Tables and policies. Its version is that of the last manifest applied to the
+    /// exporting instance, and is ignored on import.
+    #[schema(value_type = Object)]
+    pub schema: SchemaManifest,
+    /// Managed fields, ordered by table and column.
+    #[serde(default)]
+    pub fields: Vec<Field>,
+    /// Settings by key.
+    #[serde(default)]
+    #[schema(value_type = Object)]
+    pub settings: BTreeMap<String, serde_json::Value>,
+}
+
+/// What importing a bundle changes.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct ImportReport {
+    /// Statements bringing the schema and policies in line with the bundle, and drift.
+    #[schema(value_type = Object)]
+    pub schema: ManifestPlan,
+    /// Fields that are missing or defined differently.
+    pub fields: Vec<Field>,
+    /// Keys of the settings that are missing or hold another value.
+    pub settings: Vec<String>,
+}
+
+impl ImportReport {
+    /// Returns whether the instance already matches the bundle.
+    pub fn is_empty(&self) -> bool {
+        self.schema.is_empty() && self.fields.is_empty() && self.settings.is_empty()
+    }
+}
+
+/// Exports the configuration of the instance at `db`, with the secret settings only if
+/// `include_secrets` is set.
+pub async fn export(db: &Pool<Sqlite>, include_secrets: bool) -> Result<ConfigBundle, SchemaError> {
+    let mut schema = SchemaManifest {
+        version: 0,
+        tables: live_tables(db).await?,
+        policies: Vec::new(),
+    };
+    if ddl::table_exists(db, "_policies").await? {
+        schema.policies = manifest::live_policies(db).await?;
+    }
+    if ddl::table_exists(db, "_schema_manifests").await? {
+        let version: Option<i64> = sqlx::query_scalar("SELECT max(version) FROM _schema_manifests")
+            .fetch_one(db)
+            .await?;
+        schema.version = version
+            .and_then(|version| version.try_into().ok())
+            .unwrap_or(0);
+    }
+
+    let fields = if ddl::table_exists(db, "_fields").await? {
+        Fields::open(db.clone()).await?.list().await?
+    } else {
+        Vec::new()
+    };
+    let mut settings = BTreeMap::new();
+    if ddl::table_exists(db, "_settings").await? {
+        settings = Settings::open(db.clone())
+            .await?
+            .list()
+            .await?
+            .into_iter()
+            .filter(|setting| include_secrets || !settings::is_secret(&setting.key))
+            .map(|setting| (setting.key, setting.value))
+            .collect();
+    }
+
+    Ok(ConfigBundle {
+        format: BUNDLE_FORMAT,
+        exported_at: Utc::now(),
+        schema,
+        fields,
+        settings,
+    })
+}
+
+/// Reads the user tables of `db`, ordered by name, as they would be declared in a
+/// manifest.
+pub(crate) async fn live_tables(db: &Pool<Sqlite>) -> Result<Vec<TableManifest>, SchemaError> {
+    let names: Vec<String> = sqlx::query_scalar(
+        "SELECT name FROM sqlite_master \
+         WHERE type = 'table' AND name NOT LIKE '\\_%' ESCAPE '\\' AND name NOT LIKE 'sqlite_%' \
+         ORDER BY name",
+    )
+    .fetch_all(db)
+    .await?;
+
+    let mut tables = Vec::with_capacity(names.len());
+    for name in names {
+        tables.push(export_table(db, name).await?);
+    }
+    Ok(tables)
+}
+
+type ColumnRow = (String, String, bool, Option<String>, i64);
+
+async fn export_table(db: &Pool<Sqlite>, name: String) -> Result<TableManifest, SchemaError> {
+    let rows: Vec<ColumnRow> = sqlx::query_as(
+        "SELECT name, type, \"notnull\", dflt_value, pk FROM pragma_table_info(?) ORDER BY cid",
+    )
+    .bind(&name)
+    .fetch_all(db)
+    .await?;
+    let mut columns: Vec<ColumnDefinition> = rows
+        .into_iter()
+        .map(
+            |(column, type_name, not_null, default, pk)| ColumnDefinition {
+                name: column,
+                data_type: column_type(&type_name),
+                not_null,
+                primary_key: pk > 0,
+                unique: false,
+                default: default.and_then(|default| default_value(&default)),
+            },
+        )
+        .collect();
+
+    let index_list: Vec<(String, bool, String)> = sqlx::query_as(
+        "SELECT name, \"unique\", origin FROM pragma_index_list(?) \
+         WHERE origin IN ('c', 'u') ORDER BY name",
+    )
+    .bind(&name)
+    .fetch_all(db)
+    .await?;
+
+    let mut indexes = Vec::new();
+    for (index, unique, origin) in index_list {
+        let index_columns: Vec<Option<String>> =
+            sqlx::query_scalar("SELECT name FROM pragma_index_info(?) ORDER BY seqno")
+                .bind(&index)
+                .fetch_all(db)
+                .await?;
+        // Indexes on expressions can't be declared in a manifest.
+        let Some(index_columns) = index_columns.into_iter().collect::<Option<Vec<_>>>() else {
+            continue;
+        };
+
+        if origin == "u" {
+            // UNIQUE constraints: single columns are declared on the column, the others
+            // as unique indexes, since `sqlite_` names are reserved.
+            if let [column] = &index_columns[..]
+                && let Some(column) = columns.iter_mut().find(|c| c.name == *column)
+            {
+                column.unique = true;
+                continue;
+            }
+            indexes.push(IndexManifest {
+                name: format!("{}_{}_key", name, index_columns.join("_")),
+                columns: index_columns,
+                unique: true,
+            });
+        } else {
+            indexes.push(IndexManifest {
+                name: index,
+                columns: index_columns,
+                unique,
+            });
+        }
+    }
+
+    let sql: String = sqlx::query_scalar("SELECT sql FROM sqlite_master WHERE name = ?")
+        .bind(&name)
+        .fetch_one(db)
+        .await?;
+    let checks = check_constraints(&sql);
+    let foreign_keys = foreign_keys(db, &name).await?;
+
+    Ok(TableManifest {
+        name,
+        columns,
+        checks,
+        foreign_keys,
+        indexes,
+    })
+}
+
+type ForeignKeyRow = (i64, String, String, Option<String>, String, String);
+
+/// Reads the foreign keys of `table`, completing the referenced columns left implicit
+/// with the primary key of the referenced table.
+async fn foreign_keys(db: &Pool<Sqlite>, table: &str) -> Result<Vec<ForeignKey>, SchemaError> {
+    let rows: Vec<ForeignKeyRow> = sqlx::query_as(
+        "SELECT id, \"table\", \"from\", \"to\", on_update, on_delete \
+         FROM pragma_foreign_key_list(?) ORDER BY id, seq",
+    )
+    .bind(table)
+    .fetch_all(db)
+    .await?;
+
+    let action = |action: String| (action != "NO ACTION").then_some(action);
+    let mut keys: Vec<(i64, ForeignKey)> = Vec::new();
+    for (id, references, from, to, on_update, on_delete) in rows {
+        let key = match keys.last_mut() {
+            Some((last, key)) if *last == id => key,
+            _ => {
+                keys.push((
+                    id,
+                    ForeignKey {
+                        columns: Vec::new(),
+                        references,
+                        referenced_columns: Vec::new(),
+                        on_delete: action(on_delete),
+                        on_update: action(on_update),
+                    },
+                ));
+                &mut keys.last_mut().unwrap().1
+            }
+        };
+        key.columns.push(from);
+        if let Some(to) = to {
+            key.referenced_columns.push(to);
+        }
+    }
+
+    let mut foreign_keys = Vec::with_capacity(keys.len());
+    for (_, mut key) in keys {
+        if key.referenced_columns.is_empty() {
+            key.referenced_columns = sqlx::query_scalar(
+                "SELECT name FROM pragma_table_info(?) WHERE pk > 0 ORDER BY pk",
+            )
+            .bind(&key.references)
+            .fetch_all(db)
+            .await?;
+        }
+        foreign_keys.push(key);
+    }
+    Ok(foreign_keys)
+}
+
+/// The expressions of the `CHECK` constraints in the `CREATE TABLE` statement `sql`,
+/// whether declared on a column or the table.
+fn check_constraints(sql: &str) -> Vec<String> {
+    let bytes = sql.as_bytes();
+    let mut checks = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            quote @ (b'\'' | b'"' | b'`') => i = skip_quoted(bytes, i, quote),
+            b'[' => i = skip_quoted(bytes, i, b']'),
+            _ if bytes.len() >= i + 5
+                && bytes[i..i + 5].eq_ignore_ascii_case(b"check")
+                && (i == 0 || !is_word(bytes[i - 1]))
+                && bytes.get(i + 5).is_none_or(|&byte| !is_word(byte)) =>
+            {
+                let Some(open) = sql[i + 5..].find('(').map(|offset| i + 5 + offset) else {
+                    break;
+                };
+                let close = closing_paren(bytes, open);
+                let mut check = sql[open + 1..close].trim();
+                // Unwrap the parentheses the statement builder adds around expressions.
+                while check.starts_with('(')
+                    && closing_paren(check.as_bytes(), 0) == check.len() - 1
+                {
+                    check = check[1..check.len() - 1].trim();
+                }
+                checks.push(check.to_string());
+                i = close + 1;
+            }
+            _ => i += 1,
+        }
+    }
+    checks
+}
+
+/// Index of the parenthesis closing the one at `open`, or the length of `bytes` if it
+/// is never closed.
+fn closing_paren(bytes: &[u8], open: usize) -> usize {
+    let mut depth = 0;
+    let mut i = open;
+    while i < bytes.len() {
+        match bytes[i] {
+            quote @ (b'\'' | b'"' | b'`') => {
+                i = skip_quoted(bytes, i, quote);
+                continue;
+            }
+            b'(' => depth += 1,
+            b')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return i;
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    bytes.len()
+}
+
+/// Index after the literal or identifier quoted with `close` starting at `start`.
+fn skip_quoted(bytes: &[u8], start: usize, close: u8) -> usize {
+    let mut i = start + 1;
+    while i < bytes.len() {
+        if bytes[i] == close {
+            // Quotes are escaped by doubling them.
+            if close != b']' && bytes.get(i + 1) == Some(&close) {
+                i += 2;
+                continue;
+            }
+            return i + 1;
+        }
+        i += 1;
+    }
+    i
+}
+
+fn is_word(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'_'
+}
+
+/// Column type a live column is declared with, by its type name or else its affinity.
+fn column_type(type_name: &str) -> ColumnType {
+    match type_name.to_ascii_lowercase().as_str() {
+        "boolean" => ColumnType::Boolean,
+        "json" | "json_text" => ColumnType::Json,
+        "timestamp" | "timestamp_text" | "datetime" | "datetime_text" => ColumnType::Timestamp,
+        _ => match manifest::affinity(type_name) {
+            "INTEGER" => ColumnType::Integer,
+            "TEXT" => ColumnType::Text,
+            "BLOB" => ColumnType::Blob,
+            _ => ColumnType::Real,
+        },
+    }
+}
+
+/// Value of a column's `DEFAULT` clause. Expressions such as `CURRENT_TIMESTAMP` are kept
+/// as text.
+fn default_value(sql: &str) -> Option<serde_json::Value> {
+    if sql.eq_ignore_ascii_case("null") {
+        return None;
+    }
+    if let Some(text) = sql
+        .strip_prefix('\'')
+        .and_then(|sql| sql.strip_suffix('\''))
+    {
+        return Some(text.replace("''", "'").into());
+    }
+    if sql.eq_ignore_ascii_case("true") || sql.eq_ignore_ascii_case("false") {
+        return Some(sql.eq_ignore_ascii_case("true").into());
+    }
+    if let Ok(number) = sql.parse::<i64>() {
+        return Some(number.into());
+    }
+    if let Ok(number) = sql.parse::<f64>() {
+        return serde_json::Number::from_f64(number).map(Into::into);
+    }
+    Some(sql.into())
+}
+
+/// Plans importing `bundle` without changing anything.
+///
+/// # Errors
+///
+/// Returns [`SchemaError::Invalid`] for bundles of a newer format and inconsistent
+/// schemas.
+pub async fn plan_import(
+    db: &Pool<Sqlite>,
+    bundle: &ConfigBundle,
+) -> Result<ImportReport, SchemaError> {
+    if bundle.format > BUNDLE_FORMAT {
+        return Err(SchemaError::Invalid(format!(
+            "bundle format {} is newer than the supported format {}",
+            bundle.format, BUNDLE_FORMAT
+        )));
+    }
+    bundle.schema.check()?;
+    let schema = manifest::plan_schema(&mut *db.acquire().await?, &bundle.schema).await?;
+
+    let live_fields = if ddl::table_exists(db, "_fields").await? {
+        Fields::open(db.clone()).await?.list().await?
+    } else {
+        Vec::new()
+    };
+    let fields = bundle
+        .fields
+        .iter()
+        .filter(|field| !live_fields.contains(field))
+        .cloned()
+        .collect();
+
+    let mut settings = Vec::new();
+    let settings_exist = ddl::table_exists(db, "_settings").await?;
+    for (key, value) in &bundle.settings {
+        let live: Option<Json<serde_json::Value>> = if settings_exist {
+            sqlx::query_scalar("SELECT value FROM _settings WHERE key = ?")
+                .bind(key)
+                .fetch_optional(db)
+                .await?
+        } else {
+            None
+        };
+        if live.is_none_or(|Json(live)| live != *value) {
+            settings.push(key.clone());
+        }
+    }
+
+    Ok(ImportReport {
+        schema,
+        fields,
+        settings,
+    })
+}
+
+/// Imports `bundle` into the instance at `db`. The schema, policies and fields change in
+/// one transaction; settings are then written through `settings`, so its listeners see
+/// them.
+///
+/// # Errors
+///
+/// Returns [`SchemaError::Unsafe`] if the import deletes policies and `force` is not set,
+/// and [`SchemaError::Invalid`] if a field definition is rejected by
+/// [`FieldKind::check_definition`](crate::fields::FieldKind::check_definition).
+pub async fn import(
+    db: &Pool<Sqlite>,
+    settings: &Settings,
+    bundle: &ConfigBundle,
+    force: bool,
+) -> Result<ImportReport, SchemaError> {
+    let report = plan_import(db, bundle).await?;
+    report.schema.migration.check(force)?;
+    for field in &report.fields {
+        field.kind.check_definition().map_err(|message| {
+            SchemaError::Invalid(format!(
+                "field {}.{}: {}",
+                field.table_name, field.column_name, message
+            ))
+        })?;
+    }
+
+    let mut tx = db.begin().await?;
+    for statement in &report.schema.migration.statements {
+        sqlx::query(statement).execute(&mut *tx).await?;
+    }
+    if !report.fields.is_empty() {
+        sqlx::query(&create_fields_table().to_string(SqliteQueryBuilder))
+            .execute(&mut *tx)
+            .await?;
+    }
+    for field in &report.fields {
+        sqlx::query(
+            "INSERT INTO _fields (table_name, column_name, kind) VALUES (?, ?, ?) \
+             ON CONFLICT (table_name, column_name) DO UPDATE SET kind = excluded.kind",
+        )
+        .bind(&field.table_name)
+        .bind(&field.column_name)
+        .bind(Json(&field.kind))
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+
+    for key in &report.settings {
+        settings
+            .set_value(key, bundle.settings[key].clone())
+            .await?;
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fields::FieldKind;
+    use std::path::Path;
+
+    const MANIFEST: &str = r#"
+        version = 2
+
+        [[tables]]
+        name = "comments"
+        columns = [
+          { name = "id", data_type = "integer", primary_key = true },
+          { name = "post_id", data_type = "integer", not_null = true },
+          { name = "body", data_type = "text", not_null = true },
+        ]
+        checks = ["length(body) > 0"]
+        foreign_keys = [
+          { columns = ["post_id"], references = "posts", referenced_columns = ["id"], on_delete = "CASCADE" },
+        ]
+
+        [[tables]]
+        name = "posts"
+        columns = [
+          { name = "id", data_type = "integer", primary_key = true },
+          { name = "title", data_type = "text", not_null = true, default = "it's new" },
+          { name = "slug", data_type = "text", unique = true },
+          { name = "owner", data_type = "text" },
+          { name = "views", data_type = "integer", not_null = true, default = 0 },
+          { name = "published", data_type = "boolean", default = false },
+          { name = "meta", data_type = "json" },
+        ]
+        indexes = [{ name = "posts_owner", columns = ["owner"] }]
+
+        [[policies]]
+        name = "owners read posts"
+        table_name = "posts"
+        operation = "select"
+        using_expr = "owner = auth.uid()"
+    "#;
+
+    #[sqlx::test]
+    async fn test_export_and_import(db: Pool<Sqlite>) -> Result<(), SchemaError> {
+        let manifest = SchemaManifest::parse(Path::new("schema.toml"), MANIFEST)?;
+        manifest::apply(&db, &manifest, false).await?;
+        Fields::open(db.clone())
+            .await?
+            .define("posts", "owner", FieldKind::select(["alice", "bob"]))
+            .await?;
+        Settings::open(db.clone())
+            .await?
+            .set("app_name", &"Palmera")
+            .await?;
+        Settings::open(db.clone())
+            .await?
+            .set("smtp_password", &"hunter2")
+            .await?;
+
+        // Secret settings are only exported on request.
+        assert!(
+            export(&db, true)
+                .await?
+                .settings
+                .contains_key("smtp_password")
+        );
+        let bundle = export(&db, false).await?;
+        assert_eq!(bundle.schema, manifest);
+        assert_eq!(bundle.fields.len(), 1);
+        assert_eq!(bundle.settings.keys().collect::<Vec<_>>(), ["app_name"]);
+
+        // The exporting instance already matches its bundle.
+        assert!(plan_import(&db, &bundle).await?.is_empty());
+
+        let target = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await?;
+        // Exporting an empty instance creates no tables.
+        let empty = export(&target, false).await?;
+        assert!(empty.schema.tables.is_empty() && empty.fields.is_empty());
+        assert!(!ddl::table_exists(&target, "_settings").await?);
+        assert!(!ddl::table_exists(&target, "_fields").await?);
+        let settings = Settings::open(target.clone()).await?;
+        settings.set("app_name", &"Staging").await?;
+        let changed = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let listener = changed.clone();
+        settings.on_change(move |change| listener.lock().unwrap().push(change.key.clone()));
+
+        let json = serde_json::to_string(&bundle).unwrap();
+        let bundle: ConfigBundle = serde_json::from_str(&json).unwrap();
+        let planned = plan_import(&target, &bundle).await?;
+        assert_eq!(planned.settings, ["app_name"]);
+        assert!(!ddl::table_exists(&target, "posts").await?);
+
+        let report = import(&target, &settings, &bundle, false).await?;
+        assert_eq!(report, planned);
+        assert_eq!(*changed.lock().unwrap(), ["app_name"]);
+        assert_eq!(export(&target, false).await?.schema.tables, manifest.tables);
+        assert!(plan_import(&target, &bundle).await?.is_empty());
+
+        let newer = ConfigBundle {
+            format: BUNDLE_FORMAT + 1,
+            ..bundle
+        };
+        assert!(matches!(
+            plan_import(&target, &newer).await,
+            Err(SchemaError::Invalid(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_constraints() {
+        assert_eq!(
+            check_constraints(
+                "CREATE TABLE t (a TEXT CHECK ((a <> 'check (x)')), \"check\" INT, \
+                 b INT check(b > (0)), CHECK ((a IS NULL) OR (b IS NULL)))"
+            ),
+            ["a <> 'check (x)'", "b > (0)", "(a IS NULL) OR (b IS NULL)"]
+        );
+    }
+
+    #[test]
+    fn test_default_value() {
+        assert_eq!(default_value("'it''s'"), Some("it's".into()));
+        assert_eq!(default_value("0"), Some(0.into()));
+        assert_eq!(default_value("1.5"), Some(1.5.into()));
+        assert_eq!(default_value("FALSE"), Some(false.into()));
+        assert_eq!(default_value("NULL"), None);
+        assert_eq!(
+            default_value("CURRENT_TIMESTAMP"),
+            Some("CURRENT_TIMESTAMP".into())
+        );
+        assert!(matches!(column_type("VARCHAR(20)"), ColumnType::Text));
+        assert!(matches!(column_type("json_text"), ColumnType::Json));
+    }
+}
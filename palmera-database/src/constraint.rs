@@ -0,0 +1,261 @@
+//! # Constraint violations
+//!
+//! Turns the constraint errors Postgres and SQLite report through sqlx into a
+//! [`ConstraintViolation`] naming the kind of constraint and, as far as the database
+//! tells, the table, column and constraint at fault. Routes answer with it instead of a
+//! bare status:
+//!
+//! | Kind | Status |
+//! |------|--------|
+//! | [`ConstraintKind::Unique`] | `409 Conflict` |
+//! | [`ConstraintKind::ForeignKey`] | `409 Conflict` |
+//! | [`ConstraintKind::NotNull`] | `422 Unprocessable Entity` |
+//! | [`ConstraintKind::Check`] | `422 Unprocessable Entity` |
+//!
+//! Postgres reports the table, column and constraint as error fields. SQLite only
+//! reports them in the message, e.g. `UNIQUE constraint failed: users.email`, and
+//! doesn't name the foreign key that failed.
+
+use axum::{
+    Json,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{error::DatabaseError, postgres::PgDatabaseError, sqlite::SqliteError};
+use utoipa::ToSchema;
+
+/// Kind of a violated constraint.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConstraintKind {
+    /// A unique constraint or primary key.
+    Unique,
+    ForeignKey,
+    NotNull,
+    Check,
+}
+
+/// A write rejected by a constraint.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+pub struct ConstraintViolation {
+    pub kind: ConstraintKind,
+    pub table: Option<String>,
+    /// The first column involved, if known.
+    pub column: Option<String>,
+    pub constraint: Option<String>,
+    pub message: String,
+}
+
+impl ConstraintViolation {
+    /// Returns the violation `err` reports, or `None` if it isn't a constraint error.
+    pub fn from_sqlx(err: &sqlx::Error) -> Option<Self> {
+        let err = err.as_database_error()?;
+        if let Some(err) = err.try_downcast_ref::<PgDatabaseError>() {
+            return Self::from_postgres(err);
+        }
+        if let Some(err) = err.try_downcast_ref::<SqliteError>() {
+            return Self::from_sqlite(err.code()?.as_ref(), err.message());
+        }
+        None
+    }
+
+    fn from_postgres(err: &PgDatabaseError) -> Option<Self> {
+        let kind = match err.code() {
+            "23505" => ConstraintKind::Unique,
+            "23503" => ConstraintKind::ForeignKey,
+            "23502" => ConstraintKind::NotNull,
+            "23514" => ConstraintKind::Check,
+            _ => return None,
+        };
+        // Unique and foreign key violations name their columns in the detail, e.g.
+        // `Key (email)=(a@example.com) already exists.`
+        let column = err.column().map(str::to_string).or_else(|| {
+            let detail = err.detail()?;
+            let columns = detail.strip_prefix("Key (")?.split(")=").next()?;
+            columns.split(", ").next().map(str::to_string)
+        });
+        let message = match kind {
+            ConstraintKind::Unique | ConstraintKind::ForeignKey => err
+                .detail()
+                .map(str::to_string)
+                .unwrap_or_else(|| err.message().to_string()),
+            _ => err.message().to_string(),
+        };
+
+        Some(Self {
+            kind,
+            table: err.table().map(str::to_string),
+            column,
+            constraint: err.constraint().map(str::to_string),
+            message,
+        })
+    }
+
+    fn from_sqlite(code: &str, message: &str) -> Option<Self> {
+        // Extended result codes, see https://www.sqlite.org/rescode.html.
+        let kind = match code {
+            "1555" | "2067" => ConstraintKind::Unique,
+            "787" => ConstraintKind::ForeignKey,
+            "1299" => ConstraintKind::NotNull,
+            "275" => ConstraintKind::Check,
+            _ => return None,
+        };
+        let subject = message
+            .split_once("constraint failed: ")
+            .map(|(_, subject)| subject);
+
+        let (table, column, constraint) = match (kind, subject) {
+            // `table.column[, table.column]`
+            (ConstraintKind::Unique | ConstraintKind::NotNull, Some(subject)) => {
+                let first = subject.split(", ").next().unwrap_or(subject);
+                match first.split_once('.') {
+                    Some((table, column)) => {
+                        (Some(table.to_string()), Some(column.to_string()), None)
+                    }
+                    None => (None, None, None),
+                }
+            }
+            // The constraint name, or its expression if it is unnamed.
+            (ConstraintKind::Check, Some(subject)) => (None, None, Some(subject.to_string())),
+            _ => (None, None, None),
+        };
+
+        Some(Self {
+            kind,
+            table,
+            column,
+            constraint,
+            message: message.to_string(),
+        })
+    }
+
+    /// HTTP status the violation is reported with.
+    pub fn status(&self) -> StatusCode {
+        match self.kind {
+            ConstraintKind::Unique | ConstraintKind::ForeignKey => StatusCode::CONFLICT,
+            ConstraintKind::NotNull | ConstraintKind::Check => StatusCode::UNPROCESSABLE_ENTITY,
+        }
+    }
+}
+
+impl std::fmt::Display for ConstraintViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ConstraintViolation {}
+
+impl IntoResponse for ConstraintViolation {
+    fn into_response(self) -> Response {
+        (self.status(), Json(self)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::{Pool, Postgres, Sqlite};
+
+    async fn violation<'e, E>(executor: E, sql: &str) -> ConstraintViolation
+    where
+        E: sqlx::Executor<'e>,
+    {
+        let Err(err) = executor.execute(sql).await else {
+            panic!("{sql} succeeded");
+        };
+        ConstraintViolation::from_sqlx(&err).unwrap()
+    }
+
+    #[sqlx::test]
+    async fn test_postgres(db: Pool<Postgres>) -> sqlx::Result<()> {
+        sqlx::raw_sql(
+            "CREATE TABLE teams (id int PRIMARY KEY); \
+             CREATE TABLE users (id int PRIMARY KEY, email text UNIQUE, name text NOT NULL, \
+                 age int CONSTRAINT adult CHECK (age >= 18), team_id int REFERENCES teams); \
+             INSERT INTO users (id, email, name) VALUES (1, 'a@example.com', 'a');",
+        )
+        .execute(&db)
+        .await?;
+
+        let unique = violation(
+            &db,
+            "INSERT INTO users (id, email, name) VALUES (2, 'a@example.com', 'b')",
+        )
+        .await;
+        assert_eq!(unique.kind, ConstraintKind::Unique);
+        assert_eq!(unique.column.as_deref(), Some("email"));
+        assert_eq!(unique.constraint.as_deref(), Some("users_email_key"));
+        assert_eq!(
+            unique.message,
+            "Key (email)=(a@example.com) already exists."
+        );
+        assert_eq!(unique.status(), StatusCode::CONFLICT);
+
+        let not_null = violation(&db, "INSERT INTO users (id) VALUES (3)").await;
+        assert_eq!(not_null.kind, ConstraintKind::NotNull);
+        assert_eq!(not_null.table.as_deref(), Some("users"));
+        assert_eq!(not_null.column.as_deref(), Some("name"));
+        assert_eq!(not_null.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let check = violation(&db, "UPDATE users SET age = 3").await;
+        assert_eq!(check.kind, ConstraintKind::Check);
+        assert_eq!(check.constraint.as_deref(), Some("adult"));
+
+        let foreign_key = violation(&db, "UPDATE users SET team_id = 7").await;
+        assert_eq!(foreign_key.kind, ConstraintKind::ForeignKey);
+        assert_eq!(foreign_key.column.as_deref(), Some("team_id"));
+
+        let err = sqlx::query("SELECT * FROM missing")
+            .execute(&db)
+            .await
+            .unwrap_err();
+        assert!(ConstraintViolation::from_sqlx(&err).is_none());
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_sqlite(db: Pool<Sqlite>) -> sqlx::Result<()> {
+        sqlx::raw_sql(
+            "PRAGMA foreign_keys = ON; \
+             CREATE TABLE teams (id INTEGER PRIMARY KEY); \
+             CREATE TABLE users (id INTEGER PRIMARY KEY, email TEXT UNIQUE, name TEXT NOT NULL, \
+                 age INTEGER CONSTRAINT adult CHECK (age >= 18), team_id INTEGER REFERENCES teams); \
+             INSERT INTO users (id, email, name) VALUES (1, 'a@example.com', 'a');",
+        )
+        .execute(&db)
+        .await?;
+        let mut conn = db.acquire().await?;
+        sqlx::query("PRAGMA foreign_keys = ON")
+            .execute(&mut *conn)
+            .await?;
+
+        let unique = violation(
+            &mut *conn,
+            "INSERT INTO users (id, email, name) VALUES (2, 'a@example.com', 'b')",
+        )
+        .await;
+        assert_eq!(unique.kind, ConstraintKind::Unique);
+        assert_eq!(unique.table.as_deref(), Some("users"));
+        assert_eq!(unique.column.as_deref(), Some("email"));
+
+        let primary_key =
+            violation(&mut *conn, "INSERT INTO users (id, name) VALUES (1, 'b')").await;
+        assert_eq!(primary_key.kind, ConstraintKind::Unique);
+        assert_eq!(primary_key.column.as_deref(), Some("id"));
+
+        let not_null = violation(&mut *conn, "INSERT INTO users (id) VALUES (3)").await;
+        assert_eq!(not_null.kind, ConstraintKind::NotNull);
+        assert_eq!(not_null.column.as_deref(), Some("name"));
+
+        let check = violation(&mut *conn, "UPDATE users SET age = 3").await;
+        assert_eq!(check.kind, ConstraintKind::Check);
+        assert_eq!(check.constraint.as_deref(), Some("adult"));
+
+        let foreign_key = violation(&mut *conn, "UPDATE users SET team_id = 7").await;
+        assert_eq!(foreign_key.kind, ConstraintKind::ForeignKey);
+        assert_eq!(foreign_key.status(), StatusCode::CONFLICT);
+        Ok(())
+    }
+}
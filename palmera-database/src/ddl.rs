@@ -0,0 +1,790 @@
+//! # Schema changes
+//!
+//! Creates and alters SQLite tables from JSON descriptions, for the `/admin/tables`
+//! routes in [`crate::admin`]. A change is first planned into a [`Migration`]: the exact
+//! SQL statements it would run, plus [`Warning`]s about statements that lose data,
+//! break clients of the running API version, block writers while they rewrite a table
+//! or are likely to fail on the current data. Applying the migration runs its
+//! statements in one transaction.
+//!
+//! The warnings are what a blue/green deploy needs to know: a migration is safe to run
+//! under the old version of an application when [`Migration::is_compatible`] holds.
+//! Destructive migrations must be confirmed with [`Migration::check`], which the admin
+//! routes do with `?force=true`.
+//!
+//! The admin routes accept `?dry_run=true` to return the plan without applying it.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # async fn run(db: sqlx::Pool<sqlx::Sqlite>) -> Result<(), palmera_database::ddl::SchemaError> {
+//! use palmera_database::ddl::{self, AlterOperation, AlterTable};
+//!
+//! let change = AlterTable {
+//!     operations: vec![AlterOperation::DropColumn { name: "legacy_id".to_string() }],
+//! };
+//! let migration = ddl::plan_alter(&db, "posts", &change).await?;
+//! for warning in &migration.warnings {
+//!     println!("{:?}: {}", warning.risk, warning.message);
+//! }
+//! // Dropping a column loses data, so it has to be forced.
+//! migration.check(true)?;
+//! ddl::apply(&db, &migration).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use axum::http::StatusCode;
+use sea_query::{
+    Alias, ColumnDef, Expr, ForeignKeyAction, ForeignKeyCreateStatement, SimpleExpr,
+    SqliteQueryBuilder, Table,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{Executor, Pool, Sqlite, SqliteConnection};
+use utoipa::ToSchema;
+
+use crate::{error::StoreError, history};
+
+/// Storage type of a column.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ColumnType {
+    Text,
+    Integer,
+    Real,
+    Boolean,
+    Blob,
+    Json,
+    Timestamp,
+}
+
+/// A column to create.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct ColumnDefinition {
+    pub name: String,
+    pub data_type: ColumnType,
+    #[serde(default)]
+    pub not_null: bool,
+    #[serde(default)]
+    pub primary_key: bool,
+    #[serde(default)]
+    pub unique: bool,
+    /// Default value for new rows.
+    #[serde(default)]
+    #[schema(value_type = Option<Object>)]
+    pub default: Option<serde_json::Value>,
+}
+
+impl ColumnDefinition {
+    pub(crate) fn to_column_def(&self) -> ColumnDef {
+        let mut column = ColumnDef::new(Alias::new(&self.name));
+        match self.data_type {
+            ColumnType::Text => column.text(),
+            ColumnType::Integer => column.integer(),
+            ColumnType::Real => column.double(),
+            ColumnType::Boolean => column.boolean(),
+            ColumnType::Blob => column.blob(),
+            ColumnType::Json => column.json(),
+            ColumnType::Timestamp => column.timestamp(),
+        };
+        if self.not_null {
+            column.not_null();
+        }
+        if self.primary_key {
+            column.primary_key();
+        }
+        if self.unique {
+            column.unique_key();
+        }
+        if let Some(default) = &self.default {
+            column.default(default_expr(default));
+        }
+        column
+    }
+}
+
+fn default_expr(value: &serde_json::Value) -> SimpleExpr {
+    match value {
+        serde_json::Value::Bool(value) => (*value).into(),
+        serde_json::Value::Number(value) => match value.as_i64() {
+            Some(value) => value.into(),
+            None => value.as_f64().unwrap_or_default().into(),
+        },
+        serde_json::Value::String(value) => value.as_str().into(),
+        value => value.to_string().into(),
+    }
+}
+
+/// A foreign key of a table.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+pub struct ForeignKey {
+    pub columns: Vec<String>,
+    /// Referenced table.
+    pub references: String,
+    /// Referenced columns, in the order of `columns`.
+    pub referenced_columns: Vec<String>,
+    /// `ON DELETE` action: `CASCADE`, `SET NULL`, `SET DEFAULT` or `RESTRICT`. No action
+    /// when unset.
+    #[serde(default)]
+    pub on_delete: Option<String>,
+    /// `ON UPDATE` action, like `on_delete`.
+    #[serde(default)]
+    pub on_update: Option<String>,
+}
+
+impl ForeignKey {
+    fn to_foreign_key(&self, table: &str) -> Result<ForeignKeyCreateStatement, SchemaError> {
+        if self.columns.is_empty() || self.columns.len() != self.referenced_columns.len() {
+            return Err(SchemaError::Invalid(format!(
+                "foreign key of table {} to {} needs as many columns as it references",
+                table, self.references
+            )));
+        }
+        let mut key = sea_query::ForeignKey::create();
+        key.from_tbl(Alias::new(table))
+            .to_tbl(Alias::new(&self.references));
+        for column in &self.columns {
+            key.from_col(Alias::new(column));
+        }
+        for column in &self.referenced_columns {
+            key.to_col(Alias::new(column));
+        }
+        if let Some(action) = &self.on_delete {
+            key.on_delete(foreign_key_action(action)?);
+        }
+        if let Some(action) = &self.on_update {
+            key.on_update(foreign_key_action(action)?);
+        }
+        Ok(key)
+    }
+}
+
+fn foreign_key_action(action: &str) -> Result<ForeignKeyAction, SchemaError> {
+    match action.to_ascii_uppercase().as_str() {
+        "CASCADE" => Ok(ForeignKeyAction::Cascade),
+        "SET NULL" => Ok(ForeignKeyAction::SetNull),
+        "SET DEFAULT" => Ok(ForeignKeyAction::SetDefault),
+        "RESTRICT" => Ok(ForeignKeyAction::Restrict),
+        "NO ACTION" => Ok(ForeignKeyAction::NoAction),
+        _ => Err(SchemaError::Invalid(format!(
+            "unknown foreign key action {}",
+            action
+        ))),
+    }
+}
+
+/// A table to create.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct CreateTable {
+    pub name: String,
+    pub columns: Vec<ColumnDefinition>,
+    /// `CHECK` constraints, as SQL expressions.
+    #[serde(default)]
+    pub checks: Vec<String>,
+    #[serde(default)]
+    pub foreign_keys: Vec<ForeignKey>,
+}
+
+/// A change to an existing table.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum AlterOperation {
+    AddColumn { column: ColumnDefinition },
+    DropColumn { name: String },
+    RenameColumn { from: String, to: String },
+    RenameTable { to: String },
+}
+
+/// Changes applied to a table in order.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct AlterTable {
+    pub operations: Vec<AlterOperation>,
+}
+
+/// How a planned statement can hurt.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Risk {
+    /// Permanently removes data.
+    Destructive,
+    /// Breaks clients of the running API version, e.g. by changing names that they and
+    /// policies refer to.
+    Breaking,
+    /// Is likely to fail on the table's current data.
+    MayFail,
+    /// Holds the database write lock while it reads or rewrites every row of a table,
+    /// blocking other writers until it finishes.
+    Locking,
+}
+
+/// A risk found while planning a change.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct Warning {
+    pub risk: Risk,
+    /// Index of the statement the warning is about.
+    pub statement: usize,
+    pub message: String,
+}
+
+/// The statements a change runs and the risks they carry.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct Migration {
+    pub statements: Vec<String>,
+    pub warnings: Vec<Warning>,
+}
+
+impl Migration {
+    /// Returns whether any statement permanently removes data.
+    pub fn is_destructive(&self) -> bool {
+        self.warnings
+            .iter()
+            .any(|warning| warning.risk == Risk::Destructive)
+    }
+
+    /// Returns whether clients of the running API version keep working once the
+    /// migration is applied, i.e. whether it neither breaks them nor removes data.
+    pub fn is_compatible(&self) -> bool {
+        !self
+            .warnings
+            .iter()
+            .any(|warning| matches!(warning.risk, Risk::Destructive | Risk::Breaking))
+    }
+
+    /// Checks that the migration may be applied, `force` confirming destructive ones.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`SchemaError::Unsafe`] if the migration is destructive and not forced.
+    pub fn check(&self, force: bool) -> Result<(), SchemaError> {
+        if force || !self.is_destructive() {
+            return Ok(());
+        }
+
+        let reasons: Vec<&str> = self
+            .warnings
+            .iter()
+            .filter(|warning| warning.risk == Risk::Destructive)
+            .map(|warning| warning.message.as_str())
+            .collect();
+        Err(SchemaError::Unsafe(format!(
+            "destructive migration must be forced: {}",
+            reasons.join("; ")
+        )))
+    }
+
+    fn push(&mut self, statement: String) -> usize {
+        self.statements.push(statement);
+        self.statements.len() - 1
+    }
+
+    fn warn(&mut self, risk: Risk, statement: usize, message: String) {
+        self.warnings.push(Warning {
+            risk,
+            statement,
+            message,
+        });
+    }
+}
+
+/// Errors from planning or applying a change.
+#[derive(Debug)]
+pub enum SchemaError {
+    /// The table or a column does not exist.
+    NotFound(String),
+    /// The table or a column already exists.
+    Conflict(String),
+    /// The change can't be expressed, e.g. a table without columns.
+    Invalid(String),
+    /// The change is destructive and was not forced.
+    Unsafe(String),
+    Database(sqlx::Error),
+}
+
+impl SchemaError {
+    /// HTTP status the error is reported with.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::Conflict(_) => StatusCode::CONFLICT,
+            Self::Invalid(_) => StatusCode::BAD_REQUEST,
+            Self::Unsafe(_) => StatusCode::PRECONDITION_REQUIRED,
+            Self::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl std::fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotFound(message)
+            | Self::Conflict(message)
+            | Self::Invalid(message)
+            | Self::Unsafe(message) => f.write_str(message),
+            Self::Database(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for SchemaError {}
+
+impl From<sqlx::Error> for SchemaError {
+    fn from(err: sqlx::Error) -> Self {
+        Self::Database(err)
+    }
+}
+
+impl From<StoreError> for SchemaError {
+    fn from(err: StoreError) -> Self {
+        match err {
+            StoreError::Invalid(message) => Self::Invalid(message),
+            StoreError::NotFound(message) => Self::NotFound(message),
+            StoreError::Database(err) => Self::Database(err),
+        }
+    }
+}
+
+/// Plans creating `table`.
+pub async fn plan_create(db: &Pool<Sqlite>, table: &CreateTable) -> Result<Migration, SchemaError> {
+    plan_create_in(&mut *db.acquire().await?, table).await
+}
+
+/// Plans creating `table` on `conn`, e.g. inside the transaction applying the plan.
+pub(crate) async fn plan_create_in(
+    conn: &mut SqliteConnection,
+    table: &CreateTable,
+) -> Result<Migration, SchemaError> {
+    if table.columns.is_empty() {
+        return Err(SchemaError::Invalid(format!(
+            "table {} needs at least one column",
+            table.name
+        )));
+    }
+    if table_exists(&mut *conn, &table.name).await? {
+        return Err(SchemaError::Conflict(format!(
+            "table {} already exists",
+            table.name
+        )));
+    }
+
+    let mut create = Table::create();
+    create.table(Alias::new(&table.name));
+    for column in &table.columns {
+        create.col(column.to_column_def());
+    }
+    for check in &table.checks {
+        create.check(Expr::cust(format!("({})", check)));
+    }
+    for key in &table.foreign_keys {
+        create.foreign_key(&mut key.to_foreign_key(&table.name)?);
+    }
+
+    let mut migration = Migration::default();
+    migration.push(create.to_string(SqliteQueryBuilder));
+    Ok(migration)
+}
+
+/// Plans altering `table`, checking each operation against the table's current columns
+/// and rows.
+pub async fn plan_alter(
+    db: &Pool<Sqlite>,
+    table: &str,
+    change: &AlterTable,
+) -> Result<Migration, SchemaError> {
+    plan_alter_in(&mut *db.acquire().await?, table, change).await
+}
+
+/// Plans altering `table` on `conn`, e.g. inside the transaction applying the plan.
+pub(crate) async fn plan_alter_in(
+    conn: &mut SqliteConnection,
+    table: &str,
+    change: &AlterTable,
+) -> Result<Migration, SchemaError> {
+    if !table_exists(&mut *conn, table).await? {
+        return Err(SchemaError::NotFound(format!("table {} not found", table)));
+    }
+
+    let mut columns: Vec<String> =
+        sqlx::query_scalar("SELECT name FROM pragma_table_info(?) ORDER BY cid")
+            .bind(table)
+            .fetch_all(&mut *conn)
+            .await?;
+    let rows: i64 = sqlx::query_scalar(&format!("SELECT count(*) FROM {}", quote(table)))
+        .fetch_one(&mut *conn)
+        .await?;
+
+    let mut migration = Migration::default();
+    // History triggers name the columns they copy, so they are re-created afterwards.
+    let tracked = history::is_tracked(&mut *conn, table).await?;
+    if tracked {
+        for statement in history::drop_triggers(table) {
+            migration.push(statement);
+        }
+    }
+    let mut name = table.to_string();
+    for operation in &change.operations {
+        match operation {
+            AlterOperation::AddColumn { column } => {
+                if columns.contains(&column.name) {
+                    return Err(SchemaError::Conflict(format!(
+                        "column {} already exists",
+                        column.name
+                    )));
+                }
+                if column.primary_key || column.unique {
+                    return Err(SchemaError::Invalid(format!(
+                        "column {} can't be added as a primary key or unique",
+                        column.name
+                    )));
+                }
+
+                let statement = migration.push(
+                    Table::alter()
+                        .table(Alias::new(&name))
+                        .add_column(column.to_column_def())
+                        .to_string(SqliteQueryBuilder),
+                );
+                if column.not_null && column.default.is_none() {
+                    if rows > 0 {
+                        migration.warn(
+                            Risk::MayFail,
+                            statement,
+                            format!(
+                                "column {} is NOT NULL without a default, but the table has {} rows",
+                                column.name, rows
+                            ),
+                        );
+                    }
+                    migration.warn(
+                        Risk::Breaking,
+                        statement,
+                        format!(
+                            "inserts from clients not sending column {} fail",
+                            column.name
+                        ),
+                    );
+                }
+                columns.push(column.name.clone());
+            }
+            AlterOperation::DropColumn { name: column } => {
+                let Some(position) = columns.iter().position(|c| c == column) else {
+                    return Err(SchemaError::NotFound(format!(
+                        "column {} not found",
+                        column
+                    )));
+                };
+
+                let statement = migration.push(
+                    Table::alter()
+                        .table(Alias::new(&name))
+                        .drop_column(Alias::new(column))
+                        .to_string(SqliteQueryBuilder),
+                );
+                migration.warn(
+                    Risk::Destructive,
+                    statement,
+                    format!("drops column {} and its values in {} rows", column, rows),
+                );
+                migration.warn(
+                    Risk::Breaking,
+                    statement,
+                    format!("clients reading or writing column {} fail", column),
+                );
+                if rows > 0 {
+                    migration.warn(
+                        Risk::Locking,
+                        statement,
+                        format!("rewrites the {} rows of table {}", rows, name),
+                    );
+                }
+                columns.remove(position);
+            }
+            AlterOperation::RenameColumn { from, to } => {
+                let Some(position) = columns.iter().position(|c| c == from) else {
+                    return Err(SchemaError::NotFound(format!("column {} not found", from)));
+                };
+                if columns.contains(to) {
+                    return Err(SchemaError::Conflict(format!(
+                        "column {} already exists",
+                        to
+                    )));
+                }
+
+                let statement = migration.push(
+                    Table::alter()
+                        .table(Alias::new(&name))
+                        .rename_column(Alias::new(from), Alias::new(to))
+                        .to_string(SqliteQueryBuilder),
+                );
+                migration.warn(
+                    Risk::Breaking,
+                    statement,
+                    format!("clients reading column {} must switch to {}", from, to),
+                );
+                columns[position] = to.clone();
+            }
+            AlterOperation::RenameTable { to } => {
+                if table_exists(&mut *conn, to).await?
+                    || (tracked
+                        && table_exists(&mut *conn, &format!("{to}{}", history::HISTORY_SUFFIX))
+                            .await?)
+                {
+                    return Err(SchemaError::Conflict(format!(
+                        "table {} already exists",
+                        to
+                    )));
+                }
+
+                let statement = migration.push(
+                    Table::rename()
+                        .table(Alias::new(&name), Alias::new(to))
+                        .to_string(SqliteQueryBuilder),
+                );
+                migration.warn(
+                    Risk::Breaking,
+                    statement,
+                    format!(
+                        "routes and policies of table {} must switch to {}",
+                        name, to
+                    ),
+                );
+                name = to.clone();
+            }
+        }
+    }
+
+    if tracked {
+        if name != table {
+            migration.push(history::rename_history(table, &name));
+        }
+        for statement in history::create_triggers(&name, &columns) {
+            migration.push(statement);
+        }
+    }
+    Ok(migration)
+}
+
+/// Runs the statements of `migration` in one transaction.
+pub async fn apply(db: &Pool<Sqlite>, migration: &Migration) -> Result<(), SchemaError> {
+    let mut tx = db.begin().await?;
+    for statement in &migration.statements {
+        sqlx::query(statement).execute(&mut *tx).await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+pub(crate) async fn table_exists<'e, E>(db: E, table: &str) -> Result<bool, sqlx::Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?)",
+    )
+    .bind(table)
+    .fetch_one(db)
+    .await
+}
+
+pub(crate) fn quote(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn column(name: &str, data_type: ColumnType) -> ColumnDefinition {
+        ColumnDefinition {
+            name: name.to_string(),
+            data_type,
+            not_null: false,
+            primary_key: false,
+            unique: false,
+            default: None,
+        }
+    }
+
+    #[sqlx::test]
+    async fn test_plan_and_apply_create(db: Pool<Sqlite>) -> Result<(), SchemaError> {
+        let table = CreateTable {
+            name: "posts".to_string(),
+            columns: vec![
+                ColumnDefinition {
+                    primary_key: true,
+                    ..column("id", ColumnType::Integer)
+                },
+                ColumnDefinition {
+                    default: Some(json!("draft")),
+                    ..column("status", ColumnType::Text)
+                },
+            ],
+            checks: Vec::new(),
+            foreign_keys: Vec::new(),
+        };
+
+        let migration = plan_create(&db, &table).await?;
+        assert_eq!(
+            migration.statements,
+            [r#"CREATE TABLE "posts" ( "id" integer PRIMARY KEY, "status" text DEFAULT 'draft' )"#]
+        );
+        assert!(migration.warnings.is_empty());
+        assert!(!table_exists(&db, "posts").await?);
+
+        apply(&db, &migration).await?;
+        assert!(table_exists(&db, "posts").await?);
+        assert!(matches!(
+            plan_create(&db, &table).await,
+            Err(SchemaError::Conflict(_))
+        ));
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_create_constraints(db: Pool<Sqlite>) -> Result<(), SchemaError> {
+        sqlx::query("CREATE TABLE posts (id INTEGER PRIMARY KEY)")
+            .execute(&db)
+            .await?;
+        sqlx::query("INSERT INTO posts (id) VALUES (1)")
+            .execute(&db)
+            .await?;
+        let mut table = CreateTable {
+            name: "comments".to_string(),
+            columns: vec![
+                ColumnDefinition {
+                    primary_key: true,
+                    ..column("id", ColumnType::Integer)
+                },
+                column("post_id", ColumnType::Integer),
+                column("body", ColumnType::Text),
+            ],
+            checks: vec!["length(body) > 0".to_string()],
+            foreign_keys: vec![ForeignKey {
+                columns: vec!["post_id".to_string()],
+                references: "posts".to_string(),
+                referenced_columns: vec!["id".to_string()],
+                on_delete: Some("cascade".to_string()),
+                on_update: None,
+            }],
+        };
+        apply(&db, &plan_create(&db, &table).await?).await?;
+
+        let mut conn = db.acquire().await?;
+        sqlx::query("PRAGMA foreign_keys = ON")
+            .execute(&mut *conn)
+            .await?;
+        for (sql, ok) in [
+            (
+                "INSERT INTO comments (post_id, body) VALUES (1, 'Nice')",
+                true,
+            ),
+            ("INSERT INTO comments (post_id, body) VALUES (1, '')", false),
+            (
+                "INSERT INTO comments (post_id, body) VALUES (2, 'Lost')",
+                false,
+            ),
+        ] {
+            assert_eq!(
+                sqlx::query(sql).execute(&mut *conn).await.is_ok(),
+                ok,
+                "{sql}"
+            );
+        }
+
+        table.name = "replies".to_string();
+        table.foreign_keys[0].on_delete = Some("explode".to_string());
+        assert!(matches!(
+            plan_create(&db, &table).await,
+            Err(SchemaError::Invalid(_))
+        ));
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_plan_alter_warnings(db: Pool<Sqlite>) -> Result<(), SchemaError> {
+        sqlx::query("CREATE TABLE posts (id INTEGER PRIMARY KEY, title TEXT, legacy TEXT)")
+            .execute(&db)
+            .await?;
+        sqlx::query("INSERT INTO posts (title) VALUES ('a'), ('b')")
+            .execute(&db)
+            .await?;
+
+        let change = AlterTable {
+            operations: vec![
+                AlterOperation::DropColumn {
+                    name: "legacy".to_string(),
+                },
+                AlterOperation::RenameColumn {
+                    from: "title".to_string(),
+                    to: "headline".to_string(),
+                },
+                AlterOperation::AddColumn {
+                    column: ColumnDefinition {
+                        not_null: true,
+                        ..column("views", ColumnType::Integer)
+                    },
+                },
+                AlterOperation::RenameTable {
+                    to: "articles".to_string(),
+                },
+            ],
+        };
+
+        let migration = plan_alter(&db, "posts", &change).await?;
+        assert_eq!(migration.statements.len(), 4);
+        assert_eq!(
+            migration.statements[0],
+            r#"ALTER TABLE "posts" DROP COLUMN "legacy""#
+        );
+        let risks: Vec<_> = migration
+            .warnings
+            .iter()
+            .map(|warning| (warning.statement, warning.risk))
+            .collect();
+        assert_eq!(
+            risks,
+            [
+                (0, Risk::Destructive),
+                (0, Risk::Breaking),
+                (0, Risk::Locking),
+                (1, Risk::Breaking),
+                (2, Risk::MayFail),
+                (2, Risk::Breaking),
+                (3, Risk::Breaking),
+            ]
+        );
+        assert!(migration.is_destructive());
+        assert!(!migration.is_compatible());
+        assert!(matches!(
+            migration.check(false),
+            Err(SchemaError::Unsafe(_))
+        ));
+        assert!(migration.check(true).is_ok());
+        assert_eq!(
+            migration.warnings[0].message,
+            "drops column legacy and its values in 2 rows"
+        );
+
+        // The plan is checked against the columns as earlier operations left them.
+        let change = AlterTable {
+            operations: vec![
+                AlterOperation::RenameColumn {
+                    from: "title".to_string(),
+                    to: "headline".to_string(),
+                },
+                AlterOperation::DropColumn {
+                    name: "title".to_string(),
+                },
+            ],
+        };
+        assert!(matches!(
+            plan_alter(&db, "posts", &change).await,
+            Err(SchemaError::NotFound(_))
+        ));
+        assert!(matches!(
+            plan_alter(&db, "missing", &change).await,
+            Err(SchemaError::NotFound(_))
+        ));
+        Ok(())
+    }
+}
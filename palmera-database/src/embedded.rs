@@ -0,0 +1,1202 @@
+//! # Embedded mode
+//!
+//! [`PalmeraClient`] runs the record, auth and storage operations of the REST API
+//! in-process, without HTTP, so Rust applications such as desktop apps can embed Palmera
+//! over a local SQLite database. Operations go through the same rules as API requests:
+//!
+//! - records are validated against the managed [`Fields`] of their table, and computed
+//!   fields are included in the records read back;
+//! - enabled rows of `_policies` restrict which records a caller reads and writes;
+//! - the [`RecordHooks`] attached with [`PalmeraClient::with_hooks`] run after every
+//!   committed change, and with [`PalmeraClient::with_outbox`] the change is also
+//!   recorded in the [`Outbox`] by the transaction making it;
+//! - with [`PalmeraClient::with_history`], updates and deletes of tables tracked by
+//!   [`History`] are attributed to the session's user, and to the actor set with
+//!   [`Session::with_actor`] when someone acts on the user's behalf;
+//! - records in the trash of tables with a [`DELETED_AT`] column are hidden, and
+//!   deleting a record of such a table moves it to the trash; see [`crate::trash`].
+//!
+//! Records are identified by their `id` column. Internal tables, whose names start with
+//! `_`, are not reachable.
+//!
+//! ## Policies
+//!
+//! A table without policies for an operation is unrestricted. Otherwise a record must
+//! match at least one permissive policy and every restrictive one: `using_expr` decides
+//! which existing records are visible to `select`, `update` and `delete`, and
+//! `check_expr` (or `using_expr` when it is missing) which records `insert` and `update`
+//! may leave behind. Expressions are SQL evaluated against the record and can read the
+//! caller's id with `(SELECT user_id FROM _auth)`, which is `NULL` for
+//! [`PalmeraClient::anonymous`] sessions.
+//!
+//! ## Auth and storage
+//!
+//! [`PalmeraClient::as_token`] opens a session for the user of a bearer token, verified
+//! by the [`Authenticator`] attached with [`PalmeraClient::with_authenticator`], such as
+//! `palmera_auth`'s `AuthConfig`. Impersonation tokens open the session with their actor.
+//!
+//! [`Session::upload`], [`Session::download`] and [`Session::delete_file`] act on the
+//! files of the [`Storage`] attached with [`PalmeraClient::with_storage`], such as
+//! `palmera_storage`'s `Buckets`, which applies its bucket rules and file policies to
+//! the session's user.
+//!
+//! The app's hooks in `palmera_core` receive the changes of a client built with
+//! `App::record_hooks`.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # async fn run(db: sqlx::Pool<sqlx::Sqlite>) -> Result<(), palmera_database::embedded::ClientError> {
+//! use palmera_database::embedded::{PalmeraClient, RecordHooks};
+//! use serde_json::json;
+//!
+//! let hooks = RecordHooks::new()
+//!     .on_change(|change| tracing::info!(table = %change.table, "record changed"));
+//! let client = PalmeraClient::open(db).await?.with_hooks(hooks);
+//!
+//! let session = client.as_user("9b2c...");
+//! let note = session
+//!     .create("notes", json!({ "title": "Groceries", "owner": "9b2c..." }))
+//!     .await?;
+//! let notes = session.list("notes").await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{future::Future, pin::Pin, sync::Arc};
+
+use chrono::Utc;
+use sea_query::SqliteQueryBuilder;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite, SqliteConnection, types::Json};
+
+use crate::{
+    constraint::ConstraintViolation,
+    fields::{FieldKind, FieldViolation, Fields, TableFields},
+    history::{self, History},
+    outbox::Outbox,
+    postgres::batch::PRIMARY_KEY,
+    sqlite::helpers::{create_policy_table, quote_ident, row_json, table_columns},
+    trash::DELETED_AT,
+};
+
+/// Error of a [`Session`] operation.
+#[derive(Debug)]
+pub enum ClientError {
+    /// The table or record does not exist, or the caller may not see it.
+    NotFound,
+    /// A policy rejected the record being written.
+    Forbidden,
+    /// The record has unknown columns or values the managed fields reject.
+    Invalid(Vec<FieldViolation>),
+    /// A constraint of the table rejected the write.
+    Constraint(ConstraintViolation),
+    /// The token is not valid, or the operation needs a signed-in user.
+    Unauthorized,
+    Database(sqlx::Error),
+    /// The [`Storage`] failed, e.g. its backend is unreachable or a file breaks a
+    /// bucket's limits.
+    Storage(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotFound => f.write_str("record not found"),
+            Self::Forbidden => f.write_str("record rejected by policy"),
+            Self::Invalid(violations) => {
+                f.write_str("invalid record:")?;
+                for violation in violations {
+                    write!(f, " {} {};", violation.column, violation.message)?;
+                }
+                Ok(())
+            }
+            Self::Constraint(violation) => write!(f, "{}", violation),
+            Self::Unauthorized => f.write_str("not authenticated"),
+            Self::Database(err) => write!(f, "{}", err),
+            Self::Storage(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<sqlx::Error> for ClientError {
+    fn from(err: sqlx::Error) -> Self {
+        match ConstraintViolation::from_sqlx(&err) {
+            Some(violation) => Self::Constraint(violation),
+            None => Self::Database(err),
+        }
+    }
+}
+
+/// Kind of a committed [`RecordChange`].
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeAction {
+    Create,
+    Update,
+    Delete,
+}
+
+/// A committed change, passed to the [`RecordHooks::on_change`] handlers.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RecordChange {
+    pub table: String,
+    pub action: ChangeAction,
+    /// The record after the change, or before it for deletes.
+    pub record: serde_json::Value,
+    /// Id of the user the change was made for, if any.
+    pub user_id: Option<String>,
+    /// Id of who made the change on the user's behalf, e.g. an impersonating admin, if
+    /// not the user.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub actor_id: Option<String>,
+}
+
+type Handler<T> = Arc<dyn Fn(&T) + Send + Sync>;
+
+/// Handlers for the changes made through a [`PalmeraClient`], attached with
+/// [`PalmeraClient::with_hooks`].
+///
+/// Handlers are called synchronously after the commit, so long running work should be
+/// spawned onto a task.
+#[derive(Clone, Default)]
+pub struct RecordHooks {
+    change: Vec<Handler<RecordChange>>,
+}
+
+impl RecordHooks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs `handler` after every committed change.
+    pub fn on_change<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&RecordChange) + Send + Sync + 'static,
+    {
+        self.change.push(Arc::new(handler));
+        self
+    }
+
+    fn changed(&self, change: &RecordChange) {
+        for handler in &self.change {
+            handler(change);
+        }
+    }
+}
+
+impl std::fmt::Debug for RecordHooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RecordHooks")
+            .field("change", &self.change.len())
+            .finish()
+    }
+}
+
+/// The user a token was issued to, as verified by an [`Authenticator`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Caller {
+    pub user_id: String,
+    /// Who uses the token on the user's behalf, e.g. an impersonating admin.
+    pub actor_id: Option<String>,
+}
+
+/// Verifies the tokens passed to [`PalmeraClient::as_token`].
+pub trait Authenticator: Send + Sync {
+    /// The caller `token` was issued to, or `None` if it is not valid.
+    fn verify(&self, token: &str) -> Option<Caller>;
+}
+
+/// Future returned by [`Storage`] methods.
+pub type StorageFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, ClientError>> + Send + 'a>>;
+
+/// Files behind the storage operations of a [`Session`], by bucket and name.
+///
+/// Implementations decide what `user_id`, the session's user or `None` for anonymous
+/// sessions, may do, answering [`ClientError::Unauthorized`] or
+/// [`ClientError::Forbidden`] otherwise.
+pub trait Storage: Send + Sync {
+    /// Stores `bytes` of type `mime` as the file `name` of `bucket`.
+    fn upload<'a>(
+        &'a self,
+        user_id: Option<&'a str>,
+        bucket: &'a str,
+        name: &'a str,
+        mime: &'a str,
+        bytes: &'a [u8],
+    ) -> StorageFuture<'a, ()>;
+
+    /// Contents of the file `name` of `bucket`.
+    fn download<'a>(
+        &'a self,
+        user_id: Option<&'a str>,
+        bucket: &'a str,
+        name: &'a str,
+    ) -> StorageFuture<'a, Vec<u8>>;
+
+    /// Deletes the file `name` of `bucket`.
+    fn delete<'a>(
+        &'a self,
+        user_id: Option<&'a str>,
+        bucket: &'a str,
+        name: &'a str,
+    ) -> StorageFuture<'a, ()>;
+}
+
+/// In-process access to the records of a SQLite database.
+pub struct PalmeraClient {
+    db: Pool<Sqlite>,
+    fields: Fields,
+    hooks: RecordHooks,
+    outbox: Option<Arc<Outbox>>,
+    history: Option<Arc<History>>,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    storage: Option<Arc<dyn Storage>>,
+}
+
+impl std::fmt::Debug for PalmeraClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PalmeraClient")
+            .field("hooks", &self.hooks)
+            .field("outbox", &self.outbox)
+            .field("history", &self.history)
+            .field("authenticator", &self.authenticator.is_some())
+            .field("storage", &self.storage.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl PalmeraClient {
+    /// Opens the client, creating the `_policies` and `_fields` tables if they do not
+    /// exist.
+    pub async fn open(db: Pool<Sqlite>) -> Result<Self, sqlx::Error> {
+        sqlx::query(&create_policy_table().to_string(SqliteQueryBuilder))
+            .execute(&db)
+            .await?;
+        let fields = Fields::open(db.clone()).await?;
+
+        Ok(Self {
+            db,
+            fields,
+            hooks: RecordHooks::new(),
+            outbox: None,
+            history: None,
+            authenticator: None,
+            storage: None,
+        })
+    }
+
+    /// Runs `hooks` after every committed change.
+    pub fn with_hooks(mut self, hooks: RecordHooks) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    /// Verifies the tokens of [`Self::as_token`] with `authenticator`.
+    pub fn with_authenticator(mut self, authenticator: Arc<dyn Authenticator>) -> Self {
+        self.authenticator = Some(authenticator);
+        self
+    }
+
+    /// Serves the file operations of the sessions from `storage`.
+    pub fn with_storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// Records every change in `outbox`, in the transaction making it.
+    pub fn with_outbox(mut self, outbox: Arc<Outbox>) -> Self {
+        self.outbox = Some(outbox);
+        self
+    }
+
+    /// Attributes the updates and deletes of each session to its user in the row
+    /// history of `history`.
+    pub fn with_history(mut self, history: Arc<History>) -> Self {
+        self.history = Some(history);
+        self
+    }
+
+    /// The managed fields validating written records.
+    pub fn fields(&self) -> &Fields {
+        &self.fields
+    }
+
+    /// Operations on behalf of the user `user_id`.
+    pub fn as_user(&self, user_id: &str) -> Session<'_> {
+        Session {
+            client: self,
+            user_id: Some(user_id.to_string()),
+            actor_id: None,
+        }
+    }
+
+    /// Operations on behalf of the user of the bearer `token`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ClientError::Unauthorized`] if the token is not valid or the client
+    /// has no [`Authenticator`].
+    pub fn as_token(&self, token: &str) -> Result<Session<'_>, ClientError> {
+        let caller = self
+            .authenticator
+            .as_ref()
+            .and_then(|authenticator| authenticator.verify(token))
+            .ok_or(ClientError::Unauthorized)?;
+
+        Ok(Session {
+            client: self,
+            user_id: Some(caller.user_id),
+            actor_id: caller.actor_id,
+        })
+    }
+
+    /// Operations on behalf of an unauthenticated caller.
+    pub fn anonymous(&self) -> Session<'_> {
+        Session {
+            client: self,
+            user_id: None,
+            actor_id: None,
+        }
+    }
+}
+
+/// Record operations on behalf of one caller, obtained from [`PalmeraClient`].
+#[derive(Debug)]
+pub struct Session<'a> {
+    client: &'a PalmeraClient,
+    user_id: Option<String>,
+    actor_id: Option<String>,
+}
+
+/// Table metadata needed to build statements.
+struct TableInfo {
+    name: String,
+    columns: Vec<String>,
+    fields: TableFields,
+}
+
+impl TableInfo {
+    /// JSON object of a row, computed fields included.
+    fn record(&self) -> String {
+        let computed: Vec<_> = self
+            .fields
+            .fields()
+            .iter()
+            .filter_map(|field| match &field.kind {
+                FieldKind::Computed { expression, .. } => Some(format!(
+                    "{}, ({})",
+                    json_path(&field.column_name),
+                    expression
+                )),
+                _ => None,
+            })
+            .collect();
+        if computed.is_empty() {
+            row_json(&self.columns)
+        } else {
+            format!(
+                "json_set({}, {})",
+                row_json(&self.columns),
+                computed.join(", ")
+            )
+        }
+    }
+
+    fn table(&self) -> String {
+        quote_ident(&self.name)
+    }
+
+    /// Whether the table supports soft deletes.
+    fn soft_deletes(&self) -> bool {
+        self.columns.iter().any(|column| column == DELETED_AT)
+    }
+
+    /// Condition leaving out the rows in the trash.
+    fn live(&self) -> String {
+        if self.soft_deletes() {
+            format!("{} IS NULL", quote_ident(DELETED_AT))
+        } else {
+            "1".to_string()
+        }
+    }
+}
+
+/// Prefix binding the caller's id to `?1` as `_auth.user_id`.
+pub(crate) const AUTH_CTE: &str = "WITH _auth(user_id) AS (SELECT ?1) ";
+
+impl<'a> Session<'a> {
+    /// Attributes the session's changes to `actor_id` acting on behalf of its user, e.g.
+    /// an admin impersonating them. Policies still apply to the user.
+    pub fn with_actor(mut self, actor_id: &str) -> Session<'a> {
+        self.actor_id = Some(actor_id.to_string());
+        self
+    }
+
+    pub fn user_id(&self) -> Option<&str> {
+        self.user_id.as_deref()
+    }
+
+    /// Who makes the session's changes: the actor if set, otherwise the user.
+    pub fn actor_id(&self) -> Option<&str> {
+        self.actor_id.as_deref().or(self.user_id())
+    }
+
+    /// Lists the records of `table` visible to the caller, ordered by id.
+    pub async fn list(&self, table: &str) -> Result<Vec<serde_json::Value>, ClientError> {
+        let info = self.table(table).await?;
+        let mut conn = self.client.db.acquire().await?;
+        let visible = self.using(&mut conn, table, "select").await?;
+
+        let rows: Vec<Json<serde_json::Value>> = sqlx::query_scalar(&format!(
+            "{AUTH_CTE}SELECT {} FROM {} WHERE {visible} AND {} ORDER BY {}",
+            info.record(),
+            info.table(),
+            info.live(),
+            quote_ident(PRIMARY_KEY),
+        ))
+        .bind(&self.user_id)
+        .fetch_all(&mut *conn)
+        .await?;
+
+        Ok(rows.into_iter().map(|Json(row)| row).collect())
+    }
+
+    /// Returns the record of `table` with `id`, if the caller can see it.
+    pub async fn get(
+        &self,
+        table: &str,
+        id: impl Into<serde_json::Value>,
+    ) -> Result<serde_json::Value, ClientError> {
+        let info = self.table(table).await?;
+        let mut conn = self.client.db.acquire().await?;
+        let visible = self.using(&mut conn, table, "select").await?;
+
+        let row: Option<Json<serde_json::Value>> = sqlx::query_scalar(&format!(
+            "{AUTH_CTE}SELECT {} FROM {} WHERE {} = ?2 AND {visible} AND {}",
+            info.record(),
+            info.table(),
+            quote_ident(PRIMARY_KEY),
+            info.live(),
+        ))
+        .bind(&self.user_id)
+        .bind(sql_value(id.into()))
+        .fetch_optional(&mut *conn)
+        .await?;
+
+        row.map(|Json(row)| row).ok_or(ClientError::NotFound)
+    }
+
+    /// Inserts `record` into `table` and returns it as stored.
+    pub async fn create(
+        &self,
+        table: &str,
+        record: serde_json::Value,
+    ) -> Result<serde_json::Value, ClientError> {
+        let info = self.table(table).await?;
+        let record = self.validate(&info, record)?;
+        let columns: Vec<_> = record.keys().collect();
+
+        let mut tx = self.client.db.begin().await?;
+        let check = self.check(&mut tx, table, "insert").await?;
+        let sql = if columns.is_empty() {
+            format!("{AUTH_CTE}INSERT INTO {} DEFAULT VALUES", info.table())
+        } else {
+            format!(
+                "{AUTH_CTE}INSERT INTO {} ({}) VALUES ({})",
+                info.table(),
+                columns
+                    .iter()
+                    .map(|column| quote_ident(column))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                columns
+                    .iter()
+                    .map(|column| extract(column))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            )
+        };
+        let sql = format!("{sql} RETURNING rowid, {}", info.record());
+        let mut query = sqlx::query_as(&sql).bind(&self.user_id);
+        if !columns.is_empty() {
+            query = query.bind(Json(&record));
+        }
+        let (rowid, Json(stored)): (i64, Json<serde_json::Value>) =
+            query.fetch_one(&mut *tx).await?;
+
+        self.ensure(&mut tx, &info, &check, rowid).await?;
+        self.commit(tx, table, ChangeAction::Create, &stored)
+            .await?;
+        Ok(stored)
+    }
+
+    /// Changes the columns in `changes` of the record of `table` with `id`, returning
+    /// the updated record.
+    pub async fn update(
+        &self,
+        table: &str,
+        id: impl Into<serde_json::Value>,
+        changes: serde_json::Value,
+    ) -> Result<serde_json::Value, ClientError> {
+        let info = self.table(table).await?;
+        let changes = self.validate(&info, changes)?;
+        if changes.is_empty() {
+            return self.get(table, id).await;
+        }
+
+        let mut tx = self.client.db.begin().await?;
+        self.set_actor(&mut tx).await?;
+        let visible = self.using(&mut tx, table, "update").await?;
+        let check = self.check(&mut tx, table, "update").await?;
+        let row: Option<(i64, Json<serde_json::Value>)> = sqlx::query_as(&format!(
+            "{AUTH_CTE}UPDATE {} SET {} WHERE {} = ?3 AND {visible} AND {} \
+             RETURNING rowid, {}",
+            info.table(),
+            changes
+                .keys()
+                .map(|column| format!("{} = {}", quote_ident(column), extract(column)))
+                .collect::<Vec<_>>()
+                .join(", "),
+            quote_ident(PRIMARY_KEY),
+            info.live(),
+            info.record(),
+        ))
+        .bind(&self.user_id)
+        .bind(Json(&changes))
+        .bind(sql_value(id.into()))
+        .fetch_optional(&mut *tx)
+        .await?;
+        let (rowid, Json(stored)) = row.ok_or(ClientError::NotFound)?;
+
+        self.ensure(&mut tx, &info, &check, rowid).await?;
+        self.commit(tx, table, ChangeAction::Update, &stored)
+            .await?;
+        Ok(stored)
+    }
+
+    /// Deletes the record of `table` with `id`, returning it as it was. Records of
+    /// tables supporting soft deletes are moved to the trash instead, and returned with
+    /// their [`DELETED_AT`] stamp.
+    pub async fn delete(
+        &self,
+        table: &str,
+        id: impl Into<serde_json::Value>,
+    ) -> Result<serde_json::Value, ClientError> {
+        let info = self.table(table).await?;
+
+        let mut tx = self.client.db.begin().await?;
+        self.set_actor(&mut tx).await?;
+        let visible = self.using(&mut tx, table, "delete").await?;
+        let sql = if info.soft_deletes() {
+            format!(
+                "{AUTH_CTE}UPDATE {} SET {} = ?3 WHERE {} = ?2 AND {visible} AND {} RETURNING {}",
+                info.table(),
+                quote_ident(DELETED_AT),
+                quote_ident(PRIMARY_KEY),
+                info.live(),
+                info.record(),
+            )
+        } else {
+            format!(
+                "{AUTH_CTE}DELETE FROM {} WHERE {} = ?2 AND {visible} RETURNING {}",
+                info.table(),
+                quote_ident(PRIMARY_KEY),
+                info.record(),
+            )
+        };
+        let mut query = sqlx::query_scalar(&sql)
+            .bind(&self.user_id)
+            .bind(sql_value(id.into()));
+        if info.soft_deletes() {
+            query = query.bind(Utc::now().timestamp());
+        }
+        let row: Option<Json<serde_json::Value>> = query.fetch_optional(&mut *tx).await?;
+        let Json(deleted) = row.ok_or(ClientError::NotFound)?;
+        self.commit(tx, table, ChangeAction::Delete, &deleted)
+            .await?;
+        Ok(deleted)
+    }
+
+    async fn table(&self, table: &str) -> Result<TableInfo, ClientError> {
+        if table.starts_with('_') {
+            return Err(ClientError::NotFound);
+        }
+        let columns = table_columns(&self.client.db, table).await?;
+        if columns.is_empty() {
+            return Err(ClientError::NotFound);
+        }
+
+        Ok(TableInfo {
+            name: table.to_string(),
+            columns,
+            fields: self.client.fields.for_table(table).await?,
+        })
+    }
+
+    /// Checks `record` is an object of known, valid columns.
+    fn validate(
+        &self,
+        info: &TableInfo,
+        record: serde_json::Value,
+    ) -> Result<serde_json::Map<String, serde_json::Value>, ClientError> {
+        let serde_json::Value::Object(record) = record else {
+            return Err(ClientError::Invalid(vec![FieldViolation {
+                column: String::new(),
+                message: "record must be an object".to_string(),
+            }]));
+        };
+
+        let mut violations: Vec<_> = record
+            .keys()
+            .filter(|column| !info.columns.contains(column))
+            .filter(|column| info.fields.get(column).is_none())
+            .map(|column| FieldViolation {
+                column: column.clone(),
+                message: "unknown column".to_string(),
+            })
+            .collect();
+        if let Err(rejected) = info.fields.validate(&record) {
+            violations.extend(rejected);
+        }
+
+        if violations.is_empty() {
+            Ok(record)
+        } else {
+            Err(ClientError::Invalid(violations))
+        }
+    }
+
+    /// Condition selecting the existing records `operation` may touch.
+    async fn using(
+        &self,
+        conn: &mut SqliteConnection,
+        table: &str,
+        operation: &str,
+    ) -> Result<String, sqlx::Error> {
+        using(conn, table, operation).await
+    }
+
+    /// Condition the records written by `operation` must satisfy.
+    async fn check(
+        &self,
+        conn: &mut SqliteConnection,
+        table: &str,
+        operation: &str,
+    ) -> Result<String, sqlx::Error> {
+        let policies = policies(conn, table, operation).await?;
+        Ok(combine(policies.into_iter().map(
+            |(permissive, using, check)| (permissive, check.or(using)),
+        )))
+    }
+
+    /// Fails if the written row `rowid` doesn't satisfy `check`.
+    async fn ensure(
+        &self,
+        conn: &mut SqliteConnection,
+        info: &TableInfo,
+        check: &str,
+        rowid: i64,
+    ) -> Result<(), ClientError> {
+        let allowed: bool = sqlx::query_scalar(&format!(
+            "{AUTH_CTE}SELECT EXISTS (SELECT 1 FROM {} WHERE rowid = ?2 AND {check})",
+            info.table()
+        ))
+        .bind(&self.user_id)
+        .bind(rowid)
+        .fetch_one(&mut *conn)
+        .await?;
+
+        if allowed {
+            Ok(())
+        } else {
+            Err(ClientError::Forbidden)
+        }
+    }
+
+    /// Attributes the history recorded in `tx` to the actor and user of the session, if
+    /// the client tracks history.
+    async fn set_actor(&self, tx: &mut SqliteConnection) -> Result<(), sqlx::Error> {
+        match &self.client.history {
+            Some(_) => history::set_actor(tx, self.actor_id(), self.user_id()).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Records the change in the outbox, if any, commits `tx` and notifies the
+    /// listeners.
+    async fn commit(
+        &self,
+        mut tx: sqlx::Transaction<'_, Sqlite>,
+        table: &str,
+        action: ChangeAction,
+        record: &serde_json::Value,
+    ) -> Result<(), ClientError> {
+        let change = RecordChange {
+            table: table.to_string(),
+            action,
+            record: record.clone(),
+            user_id: self.user_id.clone(),
+            actor_id: self.actor_id.clone(),
+        };
+        if let Some(outbox) = &self.client.outbox {
+            outbox.record(&mut tx, &change).await?;
+        }
+        if self.client.history.is_some() {
+            history::set_actor(&mut tx, None, None).await?;
+        }
+        tx.commit().await?;
+
+        if let Some(outbox) = &self.client.outbox {
+            outbox.wake();
+        }
+        self.client.hooks.changed(&change);
+        Ok(())
+    }
+
+    /// Stores `bytes` of type `mime` as the file `name` of `bucket`.
+    pub async fn upload(
+        &self,
+        bucket: &str,
+        name: &str,
+        mime: &str,
+        bytes: &[u8],
+    ) -> Result<(), ClientError> {
+        self.storage()?
+            .upload(self.user_id(), bucket, name, mime, bytes)
+            .await
+    }
+
+    /// Contents of the file `name` of `bucket`.
+    pub async fn download(&self, bucket: &str, name: &str) -> Result<Vec<u8>, ClientError> {
+        self.storage()?.download(self.user_id(), bucket, name).await
+    }
+
+    /// Deletes the file `name` of `bucket`.
+    pub async fn delete_file(&self, bucket: &str, name: &str) -> Result<(), ClientError> {
+        self.storage()?.delete(self.user_id(), bucket, name).await
+    }
+
+    /// The client's storage. Without one, no bucket exists.
+    fn storage(&self) -> Result<&dyn Storage, ClientError> {
+        self.client.storage.as_deref().ok_or(ClientError::NotFound)
+    }
+}
+
+/// Condition selecting the existing records of `table` that `operation` may touch,
+/// reading the caller's id from [`AUTH_CTE`].
+pub(crate) async fn using(
+    conn: &mut SqliteConnection,
+    table: &str,
+    operation: &str,
+) -> Result<String, sqlx::Error> {
+    let policies = policies(conn, table, operation).await?;
+    Ok(combine(
+        policies
+            .into_iter()
+            .map(|(permissive, using, _)| (permissive, using)),
+    ))
+}
+
+/// Enabled policies of `table` applying to `operation`, as
+/// `(permissive, using_expr, check_expr)`.
+async fn policies(
+    conn: &mut SqliteConnection,
+    table: &str,
+    operation: &str,
+) -> Result<Vec<(bool, Option<String>, Option<String>)>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT policy_type = 'PERMISSIVE', using_expr, check_expr FROM _policies \
+         WHERE table_name = ? AND is_enabled = 1 AND operation IN (?, 'all') ORDER BY id",
+    )
+    .bind(table)
+    .bind(operation)
+    .fetch_all(conn)
+    .await
+}
+
+/// ORs the permissive and ANDs the restrictive expressions. Without any expression
+/// everything is allowed; with only restrictive ones nothing is.
+fn combine(policies: impl Iterator<Item = (bool, Option<String>)>) -> String {
+    let (mut permissive, mut restrictive) = (Vec::new(), Vec::new());
+    let mut any = false;
+    for (is_permissive, expr) in policies {
+        any = true;
+        let Some(expr) = expr else { continue };
+        if is_permissive {
+            permissive.push(format!("({})", expr));
+        } else {
+            restrictive.push(format!("({})", expr));
+        }
+    }
+    if !any {
+        return "1".to_string();
+    }
+
+    let permissive = if permissive.is_empty() {
+        "0".to_string()
+    } else {
+        format!("({})", permissive.join(" OR "))
+    };
+    std::iter::once(permissive)
+        .chain(restrictive)
+        .collect::<Vec<_>>()
+        .join(" AND ")
+}
+
+/// Reads `column` from the JSON record bound to `?2`.
+fn extract(column: &str) -> String {
+    format!("json_extract(?2, {})", json_path(column))
+}
+
+/// JSON path literal of the member `key`.
+fn json_path(key: &str) -> String {
+    format!("'$.\"{}\"'", key.replace('\'', "''").replace('"', "\\\""))
+}
+
+/// Converts an id to a value SQLite compares like the stored one.
+fn sql_value(value: serde_json::Value) -> Option<String> {
+    match value {
+        serde_json::Value::Null => None,
+        serde_json::Value::String(value) => Some(value),
+        value => Some(value.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+
+    struct Tokens;
+
+    impl Authenticator for Tokens {
+        fn verify(&self, token: &str) -> Option<Caller> {
+            let (user_id, actor_id) = match token.split_once(" as ") {
+                Some((actor, user)) => (user, Some(actor.to_string())),
+                None => (token, None),
+            };
+            (!user_id.is_empty()).then(|| Caller {
+                user_id: user_id.to_string(),
+                actor_id,
+            })
+        }
+    }
+
+    /// Files anyone signed in may write, but only their uploader delete.
+    #[derive(Default)]
+    struct MemoryStorage(Mutex<std::collections::HashMap<String, (String, Vec<u8>)>>);
+
+    impl Storage for MemoryStorage {
+        fn upload<'a>(
+            &'a self,
+            user_id: Option<&'a str>,
+            bucket: &'a str,
+            name: &'a str,
+            _mime: &'a str,
+            bytes: &'a [u8],
+        ) -> StorageFuture<'a, ()> {
+            let result = user_id.ok_or(ClientError::Unauthorized).map(|user_id| {
+                self.0.lock().unwrap().insert(
+                    format!("{bucket}/{name}"),
+                    (user_id.to_string(), bytes.to_vec()),
+                );
+            });
+            Box::pin(std::future::ready(result))
+        }
+
+        fn download<'a>(
+            &'a self,
+            _user_id: Option<&'a str>,
+            bucket: &'a str,
+            name: &'a str,
+        ) -> StorageFuture<'a, Vec<u8>> {
+            let files = self.0.lock().unwrap();
+            let result = files
+                .get(&format!("{bucket}/{name}"))
+                .map(|(_, bytes)| bytes.clone())
+                .ok_or(ClientError::NotFound);
+            Box::pin(std::future::ready(result))
+        }
+
+        fn delete<'a>(
+            &'a self,
+            user_id: Option<&'a str>,
+            bucket: &'a str,
+            name: &'a str,
+        ) -> StorageFuture<'a, ()> {
+            let key = format!("{bucket}/{name}");
+            let mut files = self.0.lock().unwrap();
+            let result = match files.get(&key) {
+                None => Err(ClientError::NotFound),
+                Some((owner, _)) if Some(owner.as_str()) != user_id => Err(ClientError::Forbidden),
+                Some(_) => {
+                    files.remove(&key);
+                    Ok(())
+                }
+            };
+            Box::pin(std::future::ready(result))
+        }
+    }
+
+    async fn client(db: &Pool<Sqlite>) -> sqlx::Result<PalmeraClient> {
+        sqlx::query(
+            "CREATE TABLE notes (id INTEGER PRIMARY KEY, title TEXT NOT NULL, owner TEXT, \
+             status TEXT DEFAULT 'draft')",
+        )
+        .execute(db)
+        .await?;
+        PalmeraClient::open(db.clone()).await
+    }
+
+    async fn policy(
+        db: &Pool<Sqlite>,
+        name: &str,
+        operation: &str,
+        using: Option<&str>,
+        check: Option<&str>,
+    ) -> sqlx::Result<()> {
+        sqlx::query(
+            "INSERT INTO _policies (name, table_name, operation, using_expr, check_expr) \
+             VALUES (?, 'notes', ?, ?, ?)",
+        )
+        .bind(name)
+        .bind(operation)
+        .bind(using)
+        .bind(check)
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_crud(db: Pool<Sqlite>) -> sqlx::Result<()> {
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let seen = changes.clone();
+        let client = client(&db).await?.with_hooks(
+            RecordHooks::new().on_change(move |change| seen.lock().unwrap().push(change.action)),
+        );
+        let session = client.anonymous();
+
+        let note = session
+            .create("notes", json!({ "title": "Groceries" }))
+            .await
+            .unwrap();
+        assert_eq!(
+            note,
+            json!({ "id": 1, "title": "Groceries", "owner": null, "status": "draft" })
+        );
+
+        let note = session
+            .update("notes", 1, json!({ "status": "done" }))
+            .await
+            .unwrap();
+        assert_eq!(note["status"], "done");
+        assert_eq!(session.get("notes", 1).await.unwrap(), note);
+        assert_eq!(
+            session.list("notes").await.unwrap(),
+            std::slice::from_ref(&note)
+        );
+
+        assert_eq!(session.delete("notes", 1).await.unwrap(), note);
+        assert!(matches!(
+            session.get("notes", 1).await,
+            Err(ClientError::NotFound)
+        ));
+        assert!(matches!(
+            session.list("_policies").await,
+            Err(ClientError::NotFound)
+        ));
+        assert_eq!(
+            *changes.lock().unwrap(),
+            [
+                ChangeAction::Create,
+                ChangeAction::Update,
+                ChangeAction::Delete
+            ]
+        );
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_fields(db: Pool<Sqlite>) -> Result<(), crate::error::StoreError> {
+        let client = client(&db).await?;
+        client
+            .fields()
+            .define("notes", "status", FieldKind::select(["draft", "done"]))
+            .await?;
+        client
+            .fields()
+            .define(
+                "notes",
+                "shout",
+                FieldKind::computed("upper(title)", crate::fields::ValueType::String),
+            )
+            .await?;
+        let session = client.anonymous();
+
+        let note = session
+            .create("notes", json!({ "title": "hi" }))
+            .await
+            .unwrap();
+        assert_eq!(note["shout"], "HI");
+
+        let Err(ClientError::Invalid(violations)) = session
+            .create(
+                "notes",
+                json!({ "title": "x", "status": "lost", "color": "red", "shout": "no" }),
+            )
+            .await
+        else {
+            panic!("expected violations");
+        };
+        let mut columns: Vec<_> = violations.iter().map(|v| v.column.as_str()).collect();
+        columns.sort();
+        assert_eq!(columns, ["color", "shout", "status"]);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_policies(db: Pool<Sqlite>) -> sqlx::Result<()> {
+        let client = client(&db).await?;
+        let owned = "owner = (SELECT user_id FROM _auth)";
+        policy(&db, "owner reads", "select", Some(owned), None).await?;
+        policy(&db, "owner writes", "insert", None, Some(owned)).await?;
+        policy(&db, "owner updates", "update", Some(owned), None).await?;
+        policy(&db, "no deletes", "delete", Some("0"), None).await?;
+
+        let alice = client.as_user("alice");
+        let bob = client.as_user("bob");
+        alice
+            .create("notes", json!({ "title": "mine", "owner": "alice" }))
+            .await
+            .unwrap();
+        assert!(matches!(
+            alice
+                .create("notes", json!({ "title": "forged", "owner": "bob" }))
+                .await,
+            Err(ClientError::Forbidden)
+        ));
+
+        assert_eq!(alice.list("notes").await.unwrap().len(), 1);
+        assert!(bob.list("notes").await.unwrap().is_empty());
+        assert!(client.anonymous().list("notes").await.unwrap().is_empty());
+
+        assert!(matches!(
+            bob.update("notes", 1, json!({ "title": "taken" })).await,
+            Err(ClientError::NotFound)
+        ));
+        assert!(matches!(
+            alice.update("notes", 1, json!({ "owner": "bob" })).await,
+            Err(ClientError::Forbidden)
+        ));
+        alice
+            .update("notes", 1, json!({ "title": "still mine" }))
+            .await
+            .unwrap();
+        assert!(matches!(
+            alice.delete("notes", 1).await,
+            Err(ClientError::NotFound)
+        ));
+
+        let titles: Vec<String> = sqlx::query_scalar("SELECT title FROM notes")
+            .fetch_all(&db)
+            .await?;
+        assert_eq!(titles, ["still mine"]);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_soft_deletes(db: Pool<Sqlite>) -> sqlx::Result<()> {
+        sqlx::query("CREATE TABLE posts (id INTEGER PRIMARY KEY, title TEXT, deleted_at INTEGER)")
+            .execute(&db)
+            .await?;
+        sqlx::query(
+            "INSERT INTO posts (id, title, deleted_at) VALUES (1, 'old', 1), (2, 'new', NULL)",
+        )
+        .execute(&db)
+        .await?;
+        let client = PalmeraClient::open(db.clone()).await?;
+        let session = client.anonymous();
+
+        let titles = |records: Vec<serde_json::Value>| {
+            records
+                .into_iter()
+                .map(|record| record["title"].clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(titles(session.list("posts").await.unwrap()), ["new"]);
+        for result in [
+            session.get("posts", 1).await,
+            session.update("posts", 1, json!({ "title": "back" })).await,
+            session.delete("posts", 1).await,
+        ] {
+            assert!(matches!(result, Err(ClientError::NotFound)));
+        }
+
+        let deleted = session.delete("posts", 2).await.unwrap();
+        assert!(deleted["deleted_at"].is_i64());
+        assert!(session.list("posts").await.unwrap().is_empty());
+        // The row stays in the trash, where it can be restored.
+        let trash = crate::trash::Trash::new(db);
+        assert!(trash.restore("posts", "2").await.unwrap());
+        assert_eq!(titles(session.list("posts").await.unwrap()), ["new"]);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_tokens(db: Pool<Sqlite>) -> sqlx::Result<()> {
+        let client = client(&db).await?;
+        assert!(matches!(
+            client.as_token("alice"),
+            Err(ClientError::Unauthorized)
+        ));
+
+        let client = client.with_authenticator(Arc::new(Tokens));
+        assert!(matches!(
+            client.as_token(""),
+            Err(ClientError::Unauthorized)
+        ));
+        let session = client.as_token("admin as alice").unwrap();
+        assert_eq!(session.user_id(), Some("alice"));
+        assert_eq!(session.actor_id(), Some("admin"));
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_storage(db: Pool<Sqlite>) -> sqlx::Result<()> {
+        let client = client(&db).await?;
+        assert!(matches!(
+            client.as_user("alice").download("avatars", "a.png").await,
+            Err(ClientError::NotFound)
+        ));
+
+        let client = client.with_storage(Arc::new(MemoryStorage::default()));
+        let (alice, bob) = (client.as_user("alice"), client.as_user("bob"));
+        assert!(matches!(
+            client
+                .anonymous()
+                .upload("avatars", "a.png", "image/png", b"png")
+                .await,
+            Err(ClientError::Unauthorized)
+        ));
+        alice
+            .upload("avatars", "a.png", "image/png", b"png")
+            .await
+            .unwrap();
+        assert_eq!(bob.download("avatars", "a.png").await.unwrap(), b"png");
+        assert!(matches!(
+            bob.delete_file("avatars", "a.png").await,
+            Err(ClientError::Forbidden)
+        ));
+        alice.delete_file("avatars", "a.png").await.unwrap();
+        assert!(matches!(
+            alice.download("avatars", "a.png").await,
+            Err(ClientError::NotFound)
+        ));
+        Ok(())
+    }
+}
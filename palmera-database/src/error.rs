@@ -0,0 +1,61 @@
+//! # Store errors
+//!
+//! The SQLite backed stores of this crate, such as [`crate::quotas::Quotas`] or
+//! [`crate::saved_queries::SavedQueries`], validate their input before touching the
+//! database. [`StoreError`] keeps those rejections apart from driver failures, so routes
+//! answer `400 Bad Request` with the reason for the former and `500 Internal Server
+//! Error` for the latter.
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+
+/// Error of a store operation.
+#[derive(Debug)]
+pub enum StoreError {
+    /// The input was rejected, e.g. a filter naming an unknown column.
+    Invalid(String),
+    /// The table, record or setting does not exist.
+    NotFound(String),
+    Database(sqlx::Error),
+}
+
+impl StoreError {
+    /// HTTP status the error is reported with.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::Invalid(_) => StatusCode::BAD_REQUEST,
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::Database(sqlx::Error::RowNotFound) => StatusCode::NOT_FOUND,
+            Self::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Invalid(message) | Self::NotFound(message) => f.write_str(message),
+            Self::Database(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<sqlx::Error> for StoreError {
+    fn from(err: sqlx::Error) -> Self {
+        Self::Database(err)
+    }
+}
+
+impl IntoResponse for StoreError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        match self {
+            Self::Invalid(message) | Self::NotFound(message) => (status, message).into_response(),
+            Self::Database(_) => status.into_response(),
+        }
+    }
+}
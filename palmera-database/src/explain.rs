@@ -0,0 +1,251 @@
+//! # Query explain
+//!
+//! Shows admins how Palmera answers a list request: [`explain`] renders the statement
+//! generated for a table, filter, sort and field selection, the same one
+//! [`SavedQueries::run`](crate::saved_queries::SavedQueries::run) executes, and the plan
+//! SQLite picks for it with `EXPLAIN QUERY PLAN`. A `SCAN` step over a large table
+//! where a filter or sort column is involved usually calls for an index.
+//!
+//! With [`ExplainRequest::analyze`] the statement is also run, and the time it took and
+//! the rows it returned are reported. SQLite has no `EXPLAIN ANALYZE`; running the
+//! query is the closest equivalent.
+//!
+//! Each explanation also carries the [`QueryCost`] that [`crate::query_cost`] charges
+//! for the statement.
+//!
+//! `POST /admin/explain` in [`crate::admin`] serves it.
+
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Row, Sqlite, types::Json};
+use utoipa::ToSchema;
+
+use crate::{
+    error::StoreError,
+    query_cost::{self, QueryCost},
+    saved_queries::{check_columns, select_sql},
+};
+
+/// A list request to explain.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct ExplainRequest {
+    pub table: String,
+    /// Column values records must match, as in a saved query.
+    #[serde(default)]
+    #[schema(value_type = Object)]
+    pub filter: serde_json::Map<String, serde_json::Value>,
+    /// Columns to order by, each prefixed with `-` for descending order.
+    #[serde(default)]
+    pub sort: Vec<String>,
+    /// Columns to return, every column when empty.
+    #[serde(default)]
+    pub fields: Vec<String>,
+    /// Also run the statement and report its timing.
+    #[serde(default)]
+    pub analyze: bool,
+}
+
+/// A step of a query plan, as reported by `EXPLAIN QUERY PLAN`.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct PlanStep {
+    pub id: i64,
+    /// Step this one is nested in, 0 at the top level.
+    pub parent: i64,
+    /// E.g. `SCAN tickets` or `SEARCH tickets USING INDEX tickets_status (status=?)`.
+    pub detail: String,
+    /// Table a `SCAN` or `SEARCH` step reads, which `detail` names by its alias; see
+    /// [`query_cost::name_relations`].
+    #[serde(default)]
+    pub relation: Option<String>,
+}
+
+/// Timing of a statement that was run.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct Analysis {
+    pub rows: u64,
+    pub duration_ms: f64,
+}
+
+/// How a list request is answered.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct Explanation {
+    pub sql: String,
+    /// Values bound to the statement's parameters, in order.
+    #[schema(value_type = Vec<Object>)]
+    pub params: Vec<serde_json::Value>,
+    pub plan: Vec<PlanStep>,
+    pub cost: QueryCost,
+    pub analysis: Option<Analysis>,
+}
+
+/// Explains `request` against `db`.
+///
+/// # Errors
+///
+/// Returns [`StoreError::Invalid`] if the table doesn't exist or the request
+/// references a column it doesn't have.
+pub async fn explain(
+    db: &Pool<Sqlite>,
+    request: &ExplainRequest,
+) -> Result<Explanation, StoreError> {
+    explain_as(db, request, None, "1").await
+}
+
+/// Explains `request` run by `user_id`, with `visible` restricting the records it may
+/// read as the policies do.
+pub(crate) async fn explain_as(
+    db: &Pool<Sqlite>,
+    request: &ExplainRequest,
+    user_id: Option<&str>,
+    visible: &str,
+) -> Result<Explanation, StoreError> {
+    let columns = check_columns(
+        db,
+        &request.table,
+        &request.filter,
+        &request.sort,
+        &request.fields,
+    )
+    .await?;
+    let fields = if request.fields.is_empty() {
+        columns
+    } else {
+        request.fields.clone()
+    };
+    let sql = select_sql(
+        &request.table,
+        &fields,
+        &request.filter,
+        &request.sort,
+        visible,
+    );
+    let filter = Json(&request.filter);
+
+    // `EXPLAIN QUERY PLAN` doesn't check whether the connection's copy of the schema is
+    // current, so an index created on another connection would go unnoticed. Reading
+    // the schema table refreshes it, and the plan is prepared afresh.
+    let mut conn = db.acquire().await?;
+    sqlx::query("SELECT count(*) FROM sqlite_schema")
+        .execute(&mut *conn)
+        .await?;
+    let plan: Vec<(i64, i64, i64, String)> = sqlx::query_as(&format!("EXPLAIN QUERY PLAN {sql}"))
+        .bind(user_id)
+        .bind(filter)
+        .persistent(false)
+        .fetch_all(&mut *conn)
+        .await?;
+    let mut plan: Vec<PlanStep> = plan
+        .into_iter()
+        .map(|(id, parent, _, detail)| PlanStep {
+            id,
+            parent,
+            detail,
+            relation: None,
+        })
+        .collect();
+    let program = sqlx::query(&format!("EXPLAIN {sql}"))
+        .bind(user_id)
+        .bind(filter)
+        .persistent(false)
+        .fetch_all(&mut *conn)
+        .await?;
+    let mut roots = Vec::new();
+    for instruction in &program {
+        let opcode: String = instruction.try_get("opcode")?;
+        // `p3` is the database of the cursor, `main` being 0.
+        let main = instruction.try_get::<i64, _>("p3")? == 0;
+        if main && (opcode == "OpenRead" || opcode == "OpenWrite") {
+            roots.push(instruction.try_get::<i64, _>("p2")?);
+        }
+    }
+    query_cost::name_relations(&mut conn, &roots, &mut plan).await?;
+
+    let analysis = if request.analyze {
+        let started = Instant::now();
+        let rows = sqlx::query(&sql)
+            .bind(user_id)
+            .bind(filter)
+            .fetch_all(&mut *conn)
+            .await?;
+        Some(Analysis {
+            rows: rows.len() as u64,
+            duration_ms: started.elapsed().as_secs_f64() * 1000.0,
+        })
+    } else {
+        None
+    };
+    drop(conn);
+
+    let cost = query_cost::estimate(db, &plan).await?;
+
+    Ok(Explanation {
+        sql,
+        params: vec![
+            user_id.into(),
+            serde_json::Value::Object(request.filter.clone()),
+        ],
+        plan,
+        cost,
+        analysis,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[sqlx::test]
+    async fn test_explain(db: Pool<Sqlite>) -> Result<(), StoreError> {
+        sqlx::raw_sql(
+            "CREATE TABLE tickets (id INTEGER PRIMARY KEY, status TEXT, title TEXT); \
+             INSERT INTO tickets (status, title) VALUES ('open', 'a'), ('closed', 'b');",
+        )
+        .execute(&db)
+        .await?;
+        let request = ExplainRequest {
+            table: "tickets".to_string(),
+            filter: json!({ "status": "open" }).as_object().cloned().unwrap(),
+            sort: vec!["-title".to_string()],
+            fields: vec!["id".to_string()],
+            analyze: true,
+        };
+
+        let explanation = explain(&db, &request).await?;
+        assert!(explanation.sql.contains(r#"ORDER BY "title" DESC"#));
+        assert!(explanation.plan[0].detail.starts_with("SCAN tickets"));
+        assert_eq!(explanation.plan[0].relation.as_deref(), Some("tickets"));
+        assert_eq!(explanation.analysis.unwrap().rows, 1);
+
+        sqlx::query("CREATE INDEX tickets_status ON tickets (status)")
+            .execute(&db)
+            .await?;
+        let explanation = explain(
+            &db,
+            &ExplainRequest {
+                analyze: false,
+                ..request.clone()
+            },
+        )
+        .await?;
+        assert!(
+            explanation
+                .plan
+                .iter()
+                .any(|step| step.detail.contains("USING INDEX tickets_status"))
+        );
+        assert_eq!(explanation.analysis, None);
+
+        let missing = explain(
+            &db,
+            &ExplainRequest {
+                sort: vec!["priority".to_string()],
+                ..request
+            },
+        )
+        .await;
+        assert!(matches!(missing, Err(StoreError::Invalid(_))));
+        Ok(())
+    }
+}
@@ -0,0 +1,415 @@
+//! # Export formats
+//!
+//! List endpoints answer in the format asked for by the `Accept` header, so analysts
+//! and data pipelines can pull rows without the JSON envelope:
+//!
+//! - `application/json` (the default): a JSON array.
+//! - `text/csv`: a header line with the columns of the first row, then one line per
+//!   row. Nested values are written as JSON.
+//! - `application/x-ndjson`: one JSON object per line.
+//!
+//! [`Format`] extracts the negotiated format, rejecting requests accepting none of them
+//! with `406`. [`rows`] encodes a stream of rows into a response body as they are
+//! fetched, without collecting the result first.
+//!
+//! # Large exports
+//!
+//! Bodies are sent with chunked transfer encoding, so exports of millions of rows, e.g.
+//! from a [server-side cursor](crate::postgres::cursor), never sit in memory. Each
+//! export is capped at [`StreamOptions::max_rows`] rows; the cap is announced in the
+//! [`ROW_LIMIT_HEADER`] header, and a body holding that many rows may be truncated.
+//! While the database is busy producing the next row, a blank line is sent every
+//! [`StreamOptions::keep_alive`] so proxies don't close the idle connection. Blank lines
+//! are whitespace in JSON and skipped by CSV and NDJSON readers.
+//!
+//! # Example
+//!
+//! ```rust
+//! use axum::response::Response;
+//! use futures::stream;
+//! use palmera_database::export::{self, Format};
+//!
+//! async fn list(format: Format) -> Response {
+//!     let rows = stream::iter([Ok::<_, std::io::Error>(serde_json::json!({ "id": 1 }))]);
+//!     export::rows(format, rows)
+//! }
+//! ```
+
+use std::{fmt, pin::Pin, time::Duration};
+
+use axum::{
+    body::Body,
+    extract::FromRequestParts,
+    http::{HeaderMap, HeaderValue, StatusCode, header, request::Parts},
+    response::Response,
+};
+use futures::{Stream, StreamExt, stream};
+use serde::Serialize;
+use serde_json::Value;
+use tokio::sync::mpsc;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Rows buffered between the query and the response body.
+pub(crate) const ROW_BUFFER: usize = 256;
+
+/// Most rows a single export sends when no cap is configured.
+pub const DEFAULT_MAX_ROWS: u64 = 1_000_000;
+
+/// Idle time after which a keep-alive line is sent when none is configured.
+pub const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// Response header announcing the row cap of an export.
+pub const ROW_LIMIT_HEADER: &str = "x-row-limit";
+
+/// Bounds of a streamed export.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamOptions {
+    /// Rows sent before the body is closed. `None` streams every row.
+    pub max_rows: Option<u64>,
+    /// Idle time after which a keep-alive line is sent. `None` never sends one.
+    pub keep_alive: Option<Duration>,
+}
+
+impl Default for StreamOptions {
+    fn default() -> Self {
+        Self {
+            max_rows: Some(DEFAULT_MAX_ROWS),
+            keep_alive: Some(DEFAULT_KEEP_ALIVE),
+        }
+    }
+}
+
+/// Response format of a list endpoint.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Json,
+    Csv,
+    Ndjson,
+}
+
+impl Format {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Csv => "text/csv; charset=utf-8",
+            Format::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    /// Picks the format from an `Accept` header, preferring higher `q` values and then
+    /// the order of the header. Missing headers and wildcards select JSON; `None` means
+    /// no supported format is acceptable.
+    pub fn negotiate(headers: &HeaderMap) -> Option<Self> {
+        let Some(accept) = headers.get(header::ACCEPT) else {
+            return Some(Format::Json);
+        };
+        let accept = accept.to_str().ok()?;
+
+        let mut ranges: Vec<(&str, f32)> = accept
+            .split(',')
+            .map(|range| {
+                let mut parts = range.split(';').map(str::trim);
+                let media = parts.next().unwrap_or_default();
+                let quality = parts
+                    .find_map(|param| param.strip_prefix("q="))
+                    .and_then(|q| q.parse().ok())
+                    .unwrap_or(1.0);
+                (media, quality)
+            })
+            .filter(|(_, quality)| *quality > 0.0)
+            .collect();
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        ranges
+            .into_iter()
+            .find_map(|(media, _)| match media.to_ascii_lowercase().as_str() {
+                "application/json" | "application/*" | "*/*" => Some(Format::Json),
+                "text/csv" | "text/*" => Some(Format::Csv),
+                "application/x-ndjson" | "application/ndjson" => Some(Format::Ndjson),
+                _ => None,
+            })
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Format::Json => "json",
+            Format::Csv => "csv",
+            Format::Ndjson => "ndjson",
+        })
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Format {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Format::negotiate(&parts.headers).ok_or(StatusCode::NOT_ACCEPTABLE)
+    }
+}
+
+/// Encodes rows one at a time.
+#[derive(Debug)]
+pub(crate) struct Encoder {
+    format: Format,
+    rows: u64,
+    columns: Vec<String>,
+}
+
+impl Encoder {
+    pub(crate) fn new(format: Format) -> Self {
+        Self {
+            format,
+            rows: 0,
+            columns: vec![],
+        }
+    }
+
+    pub(crate) fn row<T: Serialize>(&mut self, row: &T) -> Result<String, serde_json::Error> {
+        let first = self.rows == 0;
+        self.rows += 1;
+
+        match self.format {
+            Format::Json => {
+                let json = serde_json::to_string(row)?;
+                Ok(format!("{}{}", if first { "[" } else { "," }, json))
+            }
+            Format::Ndjson => Ok(format!("{}\n", serde_json::to_string(row)?)),
+            Format::Csv => {
+                let value = serde_json::to_value(row)?;
+                let mut out = String::new();
+
+                if first {
+                    self.columns = match &value {
+                        Value::Object(map) => map.keys().cloned().collect(),
+                        _ => vec!["value".to_string()],
+                    };
+                    out.push_str(&csv_line(self.columns.iter().map(String::as_str)));
+                }
+
+                let fields: Vec<String> = match &value {
+                    Value::Object(map) => self
+                        .columns
+                        .iter()
+                        .map(|column| csv_field(map.get(column).unwrap_or(&Value::Null)))
+                        .collect(),
+                    value => vec![csv_field(value)],
+                };
+                out.push_str(&csv_line(fields.iter().map(String::as_str)));
+                Ok(out)
+            }
+        }
+    }
+
+    /// Blank line keeping an idle connection open without changing the output.
+    pub(crate) fn keep_alive(&self) -> &'static str {
+        match self.format {
+            Format::Csv => "\r\n",
+            Format::Json | Format::Ndjson => "\n",
+        }
+    }
+
+    /// Closes the encoded output.
+    pub(crate) fn finish(&self) -> String {
+        match self.format {
+            Format::Json if self.rows == 0 => "[]".to_string(),
+            Format::Json => "]".to_string(),
+            Format::Csv | Format::Ndjson => String::new(),
+        }
+    }
+}
+
+fn csv_field(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+fn csv_line<'a>(fields: impl Iterator<Item = &'a str>) -> String {
+    let fields: Vec<_> = fields
+        .map(|field| {
+            if field.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                field.to_string()
+            }
+        })
+        .collect();
+    format!("{}\r\n", fields.join(","))
+}
+
+/// Streams `rows` into a response in `format`, with the default [`StreamOptions`]. An
+/// error while fetching ends the body early, which clients see as an aborted transfer.
+pub fn rows<T, E, S>(format: Format, rows: S) -> Response
+where
+    T: Serialize,
+    E: Into<BoxError>,
+    S: Stream<Item = Result<T, E>> + Send + 'static,
+{
+    rows_with(format, rows, StreamOptions::default())
+}
+
+/// Like [`rows`], with the row cap and keep-alive interval of `options`. The response
+/// carries the cap in the [`ROW_LIMIT_HEADER`] header.
+pub fn rows_with<T, E, S>(format: Format, rows: S, options: StreamOptions) -> Response
+where
+    T: Serialize,
+    E: Into<BoxError>,
+    S: Stream<Item = Result<T, E>> + Send + 'static,
+{
+    let state = Export {
+        rows: Box::pin(rows),
+        encoder: Encoder::new(format),
+        remaining: options.max_rows,
+        keep_alive: options.keep_alive,
+    };
+
+    let body = stream::unfold(Some(state), |state| async move {
+        let mut export = state?;
+
+        if export.remaining == Some(0) {
+            return Some((Ok(export.encoder.finish()), None));
+        }
+
+        let next = match export.keep_alive {
+            Some(period) => match tokio::time::timeout(period, export.rows.next()).await {
+                Ok(next) => next,
+                Err(_) => {
+                    let chunk = export.encoder.keep_alive().to_string();
+                    return Some((Ok(chunk), Some(export)));
+                }
+            },
+            None => export.rows.next().await,
+        };
+
+        let chunk: Result<String, BoxError> = match next {
+            Some(Ok(row)) => match export.encoder.row(&row) {
+                Ok(chunk) => {
+                    if let Some(remaining) = &mut export.remaining {
+                        *remaining -= 1;
+                    }
+                    return Some((Ok(chunk), Some(export)));
+                }
+                Err(err) => Err(err.into()),
+            },
+            Some(Err(err)) => Err(err.into()),
+            None => Ok(export.encoder.finish()),
+        };
+        Some((chunk, None))
+    });
+
+    let mut response = Response::new(Body::from_stream(body));
+    let headers = response.headers_mut();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(format.content_type()),
+    );
+    if let Some(max_rows) = options.max_rows {
+        headers.insert(ROW_LIMIT_HEADER, HeaderValue::from(max_rows));
+    }
+    response
+}
+
+struct Export<S> {
+    rows: Pin<Box<S>>,
+    encoder: Encoder,
+    remaining: Option<u64>,
+    keep_alive: Option<Duration>,
+}
+
+/// Yields what is sent on `receiver`; lets a task owning a pool feed a `'static` body.
+pub(crate) fn receive<T: Send + 'static>(
+    receiver: mpsc::Receiver<T>,
+) -> impl Stream<Item = T> + Send + 'static {
+    stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|item| (item, receiver))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn accept(value: &str) -> Option<Format> {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, HeaderValue::from_str(value).unwrap());
+        Format::negotiate(&headers)
+    }
+
+    async fn encode(format: Format, values: Vec<Value>) -> String {
+        let rows = stream::iter(values.into_iter().map(Ok::<_, std::io::Error>));
+        let body = axum::body::to_bytes(super::rows(format, rows).into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(Format::negotiate(&HeaderMap::new()), Some(Format::Json));
+        assert_eq!(accept("text/csv"), Some(Format::Csv));
+        assert_eq!(accept("application/x-ndjson"), Some(Format::Ndjson));
+        assert_eq!(accept("text/html, */*;q=0.8"), Some(Format::Json));
+        assert_eq!(
+            accept("application/json;q=0.5, text/csv;q=0.9"),
+            Some(Format::Csv)
+        );
+        assert_eq!(accept("text/csv;q=0, application/xml"), None);
+    }
+
+    #[tokio::test]
+    async fn test_encode() {
+        let values = vec![
+            json!({ "key": "a", "value": { "x": 1 } }),
+            json!({ "key": "b,\"c\"", "value": null }),
+        ];
+
+        assert_eq!(
+            encode(Format::Json, values.clone()).await,
+            r#"[{"key":"a","value":{"x":1}},{"key":"b,\"c\"","value":null}]"#
+        );
+        assert_eq!(
+            encode(Format::Ndjson, values.clone()).await,
+            "{\"key\":\"a\",\"value\":{\"x\":1}}\n{\"key\":\"b,\\\"c\\\"\",\"value\":null}\n"
+        );
+        assert_eq!(
+            encode(Format::Csv, values).await,
+            "key,value\r\na,\"{\"\"x\"\":1}\"\r\n\"b,\"\"c\"\"\",\r\n"
+        );
+        assert_eq!(encode(Format::Json, vec![]).await, "[]");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cap_and_keep_alive() {
+        let options = StreamOptions {
+            max_rows: Some(3),
+            keep_alive: Some(Duration::from_secs(15)),
+        };
+
+        // A slow first row gets two keep-alive lines ahead of it.
+        let slow = stream::once(async {
+            tokio::time::sleep(Duration::from_secs(40)).await;
+            Ok::<_, std::io::Error>(json!({ "id": 0 }))
+        });
+        let rows = slow.chain(stream::iter(
+            (1..10).map(|id| Ok::<_, std::io::Error>(json!({ "id": id }))),
+        ));
+
+        let response = rows_with(Format::Ndjson, rows, options);
+        assert_eq!(response.headers()[ROW_LIMIT_HEADER], "3");
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(body.to_vec()).unwrap(),
+            "\n\n{\"id\":0}\n{\"id\":1}\n{\"id\":2}\n"
+        );
+    }
+}
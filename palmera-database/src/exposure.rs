@@ -0,0 +1,433 @@
+//! # Table exposure
+//!
+//! Controls how each table is served by the REST API. A [`TableExposure`] can:
+//!
+//! - disable the table's routes entirely,
+//! - make the table read-only, rejecting writes with `405 Method Not Allowed`,
+//! - serve the table under a custom path alias, e.g. `/api/posts` instead of
+//!   `/public/posts`. The canonical path stops answering once an alias is set.
+//!
+//! Settings are stored in the SQLite `_exposures` table and cached in memory by
+//! [`Exposures`]; tables without settings are served as usual. They are edited through
+//! the `/admin/tables/{table}/exposure` routes in [`crate::admin`].
+//!
+//! The [`enforce`] middleware applies the settings and rewrites aliased paths to the
+//! canonical ones. Since it changes the path, it must wrap the router rather than be
+//! added with `Router::layer`, which runs after routing.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # async fn run(
+//! #     db: sqlx::Pool<sqlx::Sqlite>,
+//! #     api: axum::Router,
+//! # ) -> Result<(), Box<dyn std::error::Error>> {
+//! use std::sync::Arc;
+//!
+//! use axum::middleware;
+//! use palmera_database::exposure::{Exposures, TableExposure, enforce};
+//! use tower::Layer;
+//!
+//! let exposures = Arc::new(Exposures::open(db).await?);
+//! exposures
+//!     .set(TableExposure::new("public.posts").with_alias("/api/posts"))
+//!     .await?;
+//!
+//! let app = middleware::from_fn_with_state(exposures, enforce).layer(api);
+//! let app = axum::ServiceExt::<axum::extract::Request>::into_make_service(app);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{collections::HashMap, sync::Arc, sync::RwLock};
+
+use axum::{
+    extract::{Request, State},
+    http::{Method, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use sea_query::SqliteQueryBuilder;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite};
+use utoipa::ToSchema;
+
+use crate::{error::StoreError, sqlite::helpers::create_exposure_table};
+
+/// How a table is served by the REST API.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+pub struct TableExposure {
+    /// The table, optionally schema qualified (`public.posts`).
+    pub table_name: String,
+    /// Whether the table has routes at all.
+    pub enabled: bool,
+    /// Whether only reads are allowed.
+    pub read_only: bool,
+    /// Path the table is served under instead of its canonical one.
+    pub alias: Option<String>,
+}
+
+impl TableExposure {
+    /// Exposes `table` with default settings: enabled, writable, no alias.
+    pub fn new(table: &str) -> Self {
+        Self {
+            table_name: table.to_string(),
+            enabled: true,
+            read_only: false,
+            alias: None,
+        }
+    }
+
+    pub fn with_enabled(mut self, enabled: bool) -> Self {
+        self.enabled = enabled;
+        self
+    }
+
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    pub fn with_alias(mut self, alias: &str) -> Self {
+        self.alias = Some(alias.to_string());
+        self
+    }
+
+    /// The path the table is served under without an alias: `/{schema}/{table}`, or
+    /// `/{table}` for unqualified names.
+    pub fn canonical_path(&self) -> String {
+        format!("/{}", self.table_name.replace('.', "/"))
+    }
+}
+
+/// Where a request path leads after applying [`TableExposure`] settings.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Resolution {
+    /// The path doesn't belong to a table with settings; serve it as is.
+    Pass,
+    /// Serve the request at `path`, the table's canonical path.
+    Serve {
+        path: String,
+        exposure: TableExposure,
+    },
+    /// The table is disabled, or was requested by its canonical path despite an alias.
+    NotFound,
+    /// A write to a read-only table.
+    MethodNotAllowed,
+}
+
+/// Table exposure settings persisted in SQLite and cached in memory.
+pub struct Exposures {
+    db: Pool<Sqlite>,
+    cache: RwLock<HashMap<String, TableExposure>>,
+}
+
+impl std::fmt::Debug for Exposures {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Exposures")
+            .field("tables", &self.cache.read().unwrap().len())
+            .finish_non_exhaustive()
+    }
+}
+
+type ExposureRow = (String, bool, bool, Option<String>);
+
+impl Exposures {
+    /// Opens the store, creating the `_exposures` table if it does not exist, and loads
+    /// every setting.
+    pub async fn open(db: Pool<Sqlite>) -> Result<Self, sqlx::Error> {
+        sqlx::query(&create_exposure_table().to_string(SqliteQueryBuilder))
+            .execute(&db)
+            .await?;
+
+        let rows: Vec<ExposureRow> =
+            sqlx::query_as("SELECT table_name, enabled, read_only, alias FROM _exposures")
+                .fetch_all(&db)
+                .await?;
+        let cache = rows
+            .into_iter()
+            .map(|(table_name, enabled, read_only, alias)| {
+                let exposure = TableExposure {
+                    table_name: table_name.clone(),
+                    enabled,
+                    read_only,
+                    alias,
+                };
+                (table_name, exposure)
+            })
+            .collect();
+
+        Ok(Self {
+            db,
+            cache: RwLock::new(cache),
+        })
+    }
+
+    /// Returns the settings of `table`, or `None` if it uses the defaults.
+    pub fn get(&self, table: &str) -> Option<TableExposure> {
+        self.cache.read().unwrap().get(table).cloned()
+    }
+
+    /// Returns every table with settings, ordered by name.
+    pub fn list(&self) -> Vec<TableExposure> {
+        let mut exposures: Vec<_> = self.cache.read().unwrap().values().cloned().collect();
+        exposures.sort_by(|a, b| a.table_name.cmp(&b.table_name));
+        exposures
+    }
+
+    /// Stores the settings of `exposure.table_name`, replacing any previous ones.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`StoreError::Invalid`] if the alias doesn't start with `/`, and a
+    /// database error if another table already uses it.
+    pub async fn set(&self, mut exposure: TableExposure) -> Result<TableExposure, StoreError> {
+        if let Some(alias) = &exposure.alias {
+            let alias = alias.trim_end_matches('/');
+            if !alias.starts_with('/') || alias.len() < 2 {
+                return Err(StoreError::Invalid(format!(
+                    "alias must be a path such as /api/posts, got {:?}",
+                    exposure.alias.as_deref().unwrap_or_default()
+                )));
+            }
+            exposure.alias = Some(alias.to_string());
+        }
+
+        sqlx::query(
+            "INSERT INTO _exposures (table_name, enabled, read_only, alias) VALUES (?, ?, ?, ?) \
+             ON CONFLICT (table_name) DO UPDATE SET enabled = excluded.enabled, \
+             read_only = excluded.read_only, alias = excluded.alias",
+        )
+        .bind(&exposure.table_name)
+        .bind(exposure.enabled)
+        .bind(exposure.read_only)
+        .bind(&exposure.alias)
+        .execute(&self.db)
+        .await?;
+
+        self.cache
+            .write()
+            .unwrap()
+            .insert(exposure.table_name.clone(), exposure.clone());
+        Ok(exposure)
+    }
+
+    /// Restores the defaults of `table`, returning whether it had settings.
+    pub async fn remove(&self, table: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM _exposures WHERE table_name = ?")
+            .bind(table)
+            .execute(&self.db)
+            .await?;
+
+        self.cache.write().unwrap().remove(table);
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Applies the settings to a request for `path` with `method`.
+    pub fn resolve(&self, method: &Method, path: &str) -> Resolution {
+        let cache = self.cache.read().unwrap();
+
+        // Aliases take precedence, so an alias may shadow another table's canonical path.
+        let aliased = cache.values().find_map(|exposure| {
+            let rest = strip_path_prefix(path, exposure.alias.as_deref()?)?;
+            Some((exposure, format!("{}{}", exposure.canonical_path(), rest)))
+        });
+        let (exposure, path) = match aliased {
+            Some(found) => found,
+            None => {
+                let Some(exposure) = cache
+                    .values()
+                    .find(|exposure| strip_path_prefix(path, &exposure.canonical_path()).is_some())
+                else {
+                    return Resolution::Pass;
+                };
+                if exposure.alias.is_some() {
+                    return Resolution::NotFound;
+                }
+                (exposure, path.to_string())
+            }
+        };
+
+        if !exposure.enabled {
+            Resolution::NotFound
+        } else if exposure.read_only && !is_read(method) {
+            Resolution::MethodNotAllowed
+        } else {
+            Resolution::Serve {
+                path,
+                exposure: exposure.clone(),
+            }
+        }
+    }
+}
+
+/// Returns the rest of `path` after `prefix`, if `prefix` is a whole number of segments.
+fn strip_path_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    let rest = path.strip_prefix(prefix)?;
+    (rest.is_empty() || rest.starts_with('/')).then_some(rest)
+}
+
+fn is_read(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+/// Axum middleware applying `exposures` to every request it wraps. Served requests carry
+/// their table's [`TableExposure`] as an extension.
+pub async fn enforce(
+    State(exposures): State<Arc<Exposures>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    match exposures.resolve(request.method(), request.uri().path()) {
+        Resolution::Pass => next.run(request).await,
+        Resolution::NotFound => StatusCode::NOT_FOUND.into_response(),
+        Resolution::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+        Resolution::Serve { path, exposure } => {
+            if path != request.uri().path() {
+                let path_and_query = match request.uri().query() {
+                    Some(query) => format!("{}?{}", path, query),
+                    None => path,
+                };
+                let mut parts = request.uri().clone().into_parts();
+                let Ok(path_and_query) = path_and_query.parse() else {
+                    return StatusCode::BAD_REQUEST.into_response();
+                };
+                parts.path_and_query = Some(path_and_query);
+                let Ok(uri) = Uri::from_parts(parts) else {
+                    return StatusCode::BAD_REQUEST.into_response();
+                };
+                *request.uri_mut() = uri;
+            }
+
+            request.extensions_mut().insert(exposure);
+            next.run(request).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Router, body::Body, middleware, routing::get};
+    use tower::{Layer, ServiceExt};
+
+    #[sqlx::test]
+    async fn test_store(db: Pool<Sqlite>) -> Result<(), StoreError> {
+        let exposures = Exposures::open(db.clone()).await?;
+        exposures
+            .set(TableExposure::new("public.posts").with_alias("/api/posts/"))
+            .await?;
+        exposures
+            .set(TableExposure::new("audit").with_read_only(true))
+            .await?;
+
+        assert!(
+            exposures
+                .set(TableExposure::new("public.users").with_alias("/api/posts"))
+                .await
+                .is_err()
+        );
+        assert!(
+            exposures
+                .set(TableExposure::new("public.users").with_alias("users"))
+                .await
+                .is_err()
+        );
+
+        // Settings survive reopening the store.
+        let exposures = Exposures::open(db).await?;
+        assert_eq!(
+            exposures.get("public.posts").unwrap().alias.as_deref(),
+            Some("/api/posts")
+        );
+        let tables: Vec<_> = exposures
+            .list()
+            .into_iter()
+            .map(|exposure| exposure.table_name)
+            .collect();
+        assert_eq!(tables, ["audit", "public.posts"]);
+
+        assert!(exposures.remove("audit").await?);
+        assert!(exposures.get("audit").is_none());
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_enforce(db: Pool<Sqlite>) -> Result<(), StoreError> {
+        let exposures = Arc::new(Exposures::open(db).await?);
+        exposures
+            .set(TableExposure::new("public.posts").with_alias("/api/posts"))
+            .await?;
+        exposures
+            .set(TableExposure::new("public.audit").with_read_only(true))
+            .await?;
+        exposures
+            .set(TableExposure::new("public.secrets").with_enabled(false))
+            .await?;
+
+        let api = Router::new()
+            .route(
+                "/public/{table}",
+                get(|request: Request| async move {
+                    let table = request
+                        .extensions()
+                        .get::<TableExposure>()
+                        .map(|exposure| exposure.table_name.clone())
+                        .unwrap_or_default();
+                    format!("{} {}", request.uri(), table)
+                })
+                .post(|| async { "created" }),
+            )
+            .route("/public/{table}/{id}", get(|| async { "record" }));
+        let app = middleware::from_fn_with_state(exposures, enforce).layer(api);
+
+        let call = |method: Method, uri: &str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap();
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                (status, String::from_utf8(body.to_vec()).unwrap())
+            }
+        };
+
+        assert_eq!(
+            call(Method::GET, "/api/posts?limit=1").await,
+            (
+                StatusCode::OK,
+                "/public/posts?limit=1 public.posts".to_string()
+            )
+        );
+        assert_eq!(call(Method::GET, "/api/posts/7").await.0, StatusCode::OK);
+        assert_eq!(
+            call(Method::GET, "/public/posts").await.0,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            call(Method::GET, "/api/postsx").await.0,
+            StatusCode::NOT_FOUND
+        );
+
+        assert_eq!(call(Method::GET, "/public/audit").await.0, StatusCode::OK);
+        assert_eq!(
+            call(Method::POST, "/public/audit").await.0,
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+        assert_eq!(
+            call(Method::GET, "/public/secrets").await.0,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            call(Method::POST, "/public/comments").await,
+            (StatusCode::OK, "created".to_string())
+        );
+        Ok(())
+    }
+}
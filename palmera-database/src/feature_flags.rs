@@ -0,0 +1,447 @@
+//! # Feature flags
+//!
+//! Gates app features per principal, e.g. to roll a new editor out to a tenth of the
+//! users first. A [`FeatureFlag`] is on for a principal when it is enabled and the
+//! principal matches every one of its [`FlagRule`]s:
+//!
+//! - `percentage` picks a stable share of the users, by hashing the flag's key with the
+//!   user id (the tenant for anonymous callers), so raising the percentage only adds
+//!   users.
+//! - `role` matches the roles listed.
+//! - `tenant` matches the tenants listed.
+//!
+//! Flags are stored in the SQLite `_feature_flags` table and cached in memory by
+//! [`FeatureFlags`], which server code and hooks ask with [`FeatureFlags::is_enabled`].
+//! Clients get their flags from `GET /flags` of [`router`], evaluated for the
+//! [`Principal`] making the request. Flags are managed through the `/admin/flags`
+//! routes in [`crate::admin`].
+//!
+//! The principal is taken from the [`SessionContext`] extension and the [`Tenant`]
+//! extension a tenant middleware may set.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # async fn run(db: sqlx::Pool<sqlx::Sqlite>) -> Result<(), Box<dyn std::error::Error>> {
+//! use std::sync::Arc;
+//!
+//! use axum::Extension;
+//! use palmera_database::feature_flags::{self, FeatureFlag, FeatureFlags, FlagRule, Principal};
+//!
+//! let flags = Arc::new(FeatureFlags::open(db).await?);
+//! flags
+//!     .set(FeatureFlag::new("new-editor").with_rule(FlagRule::Percentage { percent: 10 }))
+//!     .await?;
+//!
+//! let principal = Principal::default().with_user("user-1");
+//! if flags.is_enabled("new-editor", &principal) {
+//!     // ...
+//! }
+//!
+//! let (router, _api) = feature_flags::router()
+//!     .layer(Extension(flags))
+//!     .split_for_parts();
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::Infallible,
+    sync::{Arc, RwLock},
+};
+
+use axum::{
+    Extension, Json,
+    extract::FromRequestParts,
+    http::{Extensions, request::Parts},
+};
+use sea_query::SqliteQueryBuilder;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Sqlite, types::Json as SqlJson};
+use utoipa::ToSchema;
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+use crate::{
+    error::StoreError, postgres::session::SessionContext,
+    sqlite::helpers::create_feature_flags_table,
+};
+
+/// A condition a principal must meet for a flag to be on.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FlagRule {
+    /// A stable `percent` of the principals, from 0 to 100.
+    Percentage { percent: u8 },
+    /// Principals with one of `roles`.
+    Role { roles: Vec<String> },
+    /// Principals of one of `tenants`.
+    Tenant { tenants: Vec<String> },
+}
+
+/// A named switch for an app feature.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+pub struct FeatureFlag {
+    pub key: String,
+    pub description: Option<String>,
+    /// Whether the flag can be on at all.
+    pub enabled: bool,
+    /// Rules a principal must all match, none meaning everybody.
+    pub rules: Vec<FlagRule>,
+}
+
+impl FeatureFlag {
+    /// An enabled flag without rules, on for everybody.
+    pub fn new(key: &str) -> Self {
+        Self {
+            key: key.to_string(),
+            description: None,
+            enabled: true,
+            rules: Vec::new(),
+        }
+    }
+
+    pub fn with_description(mut self, description: &str) -> Self {
+        self.description = Some(description.to_string());
+        self
+    }
+
+    pub fn with_enabled(mut self, enabled: bool) -> Self {
+        self.enabled = enabled;
+        self
+    }
+
+    pub fn with_rule(mut self, rule: FlagRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Whether the flag is on for `principal`.
+    pub fn evaluate(&self, principal: &Principal) -> bool {
+        self.enabled
+            && self.rules.iter().all(|rule| match rule {
+                FlagRule::Percentage { percent } => principal
+                    .bucketing_id()
+                    .is_some_and(|id| bucket(&self.key, id) < u32::from(*percent)),
+                FlagRule::Role { roles } => principal
+                    .role
+                    .as_ref()
+                    .is_some_and(|role| roles.contains(role)),
+                FlagRule::Tenant { tenants } => principal
+                    .tenant
+                    .as_ref()
+                    .is_some_and(|tenant| tenants.contains(tenant)),
+            })
+    }
+}
+
+/// Position of `id` among 100 buckets for the flag `key`.
+fn bucket(key: &str, id: &str) -> u32 {
+    let digest = Sha256::new()
+        .chain_update(key.as_bytes())
+        .chain_update([0])
+        .chain_update(id.as_bytes())
+        .finalize();
+    u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]) % 100
+}
+
+/// The tenant a request belongs to, set as an extension by a tenant middleware.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tenant(pub String);
+
+/// Who flags are evaluated for.
+///
+/// As an extractor it never fails: requests without a session are anonymous.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Principal {
+    pub user_id: Option<String>,
+    pub role: Option<String>,
+    pub tenant: Option<String>,
+}
+
+impl Principal {
+    pub fn with_user(mut self, user_id: &str) -> Self {
+        self.user_id = Some(user_id.to_string());
+        self
+    }
+
+    pub fn with_role(mut self, role: &str) -> Self {
+        self.role = Some(role.to_string());
+        self
+    }
+
+    pub fn with_tenant(mut self, tenant: &str) -> Self {
+        self.tenant = Some(tenant.to_string());
+        self
+    }
+
+    /// The principal of a request, from its [`SessionContext`] and [`Tenant`]
+    /// extensions.
+    pub fn from_extensions(extensions: &Extensions) -> Self {
+        let session = extensions.get::<SessionContext>();
+        Self {
+            user_id: session.and_then(|session| session.user_id.clone()),
+            role: session.map(|session| session.role.clone()),
+            tenant: extensions
+                .get::<Tenant>()
+                .map(|Tenant(tenant)| tenant.clone()),
+        }
+    }
+
+    /// Id percentage rollouts are stable for.
+    fn bucketing_id(&self) -> Option<&str> {
+        self.user_id.as_deref().or(self.tenant.as_deref())
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Principal {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self::from_extensions(&parts.extensions))
+    }
+}
+
+/// Feature flags persisted in SQLite and cached in memory.
+pub struct FeatureFlags {
+    db: Pool<Sqlite>,
+    cache: RwLock<HashMap<String, FeatureFlag>>,
+}
+
+impl std::fmt::Debug for FeatureFlags {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FeatureFlags")
+            .field("flags", &self.cache.read().unwrap().len())
+            .finish_non_exhaustive()
+    }
+}
+
+type FlagRow = (String, Option<String>, bool, SqlJson<Vec<FlagRule>>);
+
+impl FeatureFlags {
+    /// Opens the store, creating the `_feature_flags` table if it does not exist, and
+    /// loads every flag.
+    pub async fn open(db: Pool<Sqlite>) -> Result<Self, sqlx::Error> {
+        sqlx::query(&create_feature_flags_table().to_string(SqliteQueryBuilder))
+            .execute(&db)
+            .await?;
+
+        let rows: Vec<FlagRow> =
+            sqlx::query_as("SELECT key, description, enabled, rules FROM _feature_flags")
+                .fetch_all(&db)
+                .await?;
+        let cache = rows
+            .into_iter()
+            .map(|(key, description, enabled, rules)| {
+                let flag = FeatureFlag {
+                    key: key.clone(),
+                    description,
+                    enabled,
+                    rules: rules.0,
+                };
+                (key, flag)
+            })
+            .collect();
+
+        Ok(Self {
+            db,
+            cache: RwLock::new(cache),
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Option<FeatureFlag> {
+        self.cache.read().unwrap().get(key).cloned()
+    }
+
+    /// Returns every flag, ordered by key.
+    pub fn list(&self) -> Vec<FeatureFlag> {
+        let mut flags: Vec<_> = self.cache.read().unwrap().values().cloned().collect();
+        flags.sort_by(|a, b| a.key.cmp(&b.key));
+        flags
+    }
+
+    /// Whether the flag `key` is on for `principal`. Unknown flags are off.
+    pub fn is_enabled(&self, key: &str, principal: &Principal) -> bool {
+        self.cache
+            .read()
+            .unwrap()
+            .get(key)
+            .is_some_and(|flag| flag.evaluate(principal))
+    }
+
+    /// Evaluates every flag for `principal`.
+    pub fn evaluate(&self, principal: &Principal) -> BTreeMap<String, bool> {
+        self.cache
+            .read()
+            .unwrap()
+            .values()
+            .map(|flag| (flag.key.clone(), flag.evaluate(principal)))
+            .collect()
+    }
+
+    /// Stores `flag`, replacing the flag of the same key.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`StoreError::Invalid`] if the key is empty, a percentage exceeds 100
+    /// or a role or tenant rule lists nothing.
+    pub async fn set(&self, flag: FeatureFlag) -> Result<FeatureFlag, StoreError> {
+        if flag.key.trim().is_empty() {
+            return Err(invalid("key must not be empty".to_string()));
+        }
+        for rule in &flag.rules {
+            match rule {
+                FlagRule::Percentage { percent } if *percent > 100 => {
+                    return Err(invalid(format!("percentage {percent} exceeds 100")));
+                }
+                FlagRule::Role { roles } if roles.is_empty() => {
+                    return Err(invalid("role rule must list roles".to_string()));
+                }
+                FlagRule::Tenant { tenants } if tenants.is_empty() => {
+                    return Err(invalid("tenant rule must list tenants".to_string()));
+                }
+                _ => {}
+            }
+        }
+
+        sqlx::query(
+            "INSERT INTO _feature_flags (key, description, enabled, rules) VALUES (?, ?, ?, ?) \
+             ON CONFLICT (key) DO UPDATE SET description = excluded.description, \
+             enabled = excluded.enabled, rules = excluded.rules",
+        )
+        .bind(&flag.key)
+        .bind(&flag.description)
+        .bind(flag.enabled)
+        .bind(SqlJson(&flag.rules))
+        .execute(&self.db)
+        .await?;
+
+        self.cache
+            .write()
+            .unwrap()
+            .insert(flag.key.clone(), flag.clone());
+        Ok(flag)
+    }
+
+    /// Removes the flag `key`, returning whether it existed.
+    pub async fn remove(&self, key: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM _feature_flags WHERE key = ?")
+            .bind(key)
+            .execute(&self.db)
+            .await?;
+
+        self.cache.write().unwrap().remove(key);
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+fn invalid(message: String) -> StoreError {
+    StoreError::Invalid(message)
+}
+
+/// Evaluates every flag for the caller.
+#[utoipa::path(
+    get,
+    path = "/flags",
+    responses((status = 200, body = BTreeMap<String, bool>))
+)]
+async fn list_flags(
+    Extension(flags): Extension<Arc<FeatureFlags>>,
+    principal: Principal,
+) -> Json<BTreeMap<String, bool>> {
+    Json(flags.evaluate(&principal))
+}
+
+pub fn router() -> OpenApiRouter {
+    OpenApiRouter::new().routes(routes!(list_flags))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evaluate() {
+        let flag = FeatureFlag::new("beta")
+            .with_rule(FlagRule::Role {
+                roles: vec!["authenticated".to_string()],
+            })
+            .with_rule(FlagRule::Tenant {
+                tenants: vec!["acme".to_string()],
+            });
+        let member = Principal::default()
+            .with_user("ann")
+            .with_role("authenticated")
+            .with_tenant("acme");
+        assert!(flag.evaluate(&member));
+        assert!(!flag.evaluate(&member.clone().with_tenant("globex")));
+        assert!(!flag.evaluate(&Principal::default().with_tenant("acme")));
+        assert!(!flag.clone().with_enabled(false).evaluate(&member));
+        assert!(FeatureFlag::new("open").evaluate(&Principal::default()));
+
+        // Rollouts are stable per user and grow with the percentage.
+        let rollout =
+            |percent| FeatureFlag::new("editor").with_rule(FlagRule::Percentage { percent });
+        let users: Vec<_> = (0..1000)
+            .map(|i| Principal::default().with_user(&format!("user-{i}")))
+            .collect();
+        let on = |percent| {
+            users
+                .iter()
+                .filter(|user| rollout(percent).evaluate(user))
+                .count()
+        };
+        assert_eq!(on(0), 0);
+        assert_eq!(on(100), 1000);
+        assert!((50..150).contains(&on(10)));
+        assert!(
+            users
+                .iter()
+                .all(|user| !rollout(10).evaluate(user) || rollout(50).evaluate(user))
+        );
+        assert!(!rollout(50).evaluate(&Principal::default()));
+    }
+
+    #[sqlx::test]
+    async fn test_store(db: Pool<Sqlite>) -> Result<(), StoreError> {
+        let flags = FeatureFlags::open(db.clone()).await?;
+        flags
+            .set(FeatureFlag::new("admin-tools").with_rule(FlagRule::Role {
+                roles: vec!["admin".to_string()],
+            }))
+            .await?;
+        flags
+            .set(FeatureFlag::new("dark-mode").with_description("Dark theme"))
+            .await?;
+        let invalid = flags
+            .set(FeatureFlag::new("x").with_rule(FlagRule::Percentage { percent: 101 }))
+            .await;
+        assert!(matches!(invalid, Err(StoreError::Invalid(_))));
+
+        // Flags survive reopening the store.
+        let flags = Arc::new(FeatureFlags::open(db).await?);
+        assert_eq!(flags.list().len(), 2);
+        assert!(!flags.is_enabled("missing", &Principal::default()));
+
+        let mut extensions = Extensions::new();
+        extensions.insert(SessionContext::new("admin", "ann"));
+        let Json(evaluated) = list_flags(
+            Extension(flags.clone()),
+            Principal::from_extensions(&extensions),
+        )
+        .await;
+        assert_eq!(
+            evaluated,
+            BTreeMap::from([
+                ("admin-tools".to_string(), true),
+                ("dark-mode".to_string(), true)
+            ])
+        );
+
+        assert!(flags.remove("dark-mode").await?);
+        assert!(!flags.remove("dark-mode").await?);
+        assert_eq!(flags.evaluate(&Principal::default()).len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,566 @@
+//! # Managed fields
+//!
+//! Column metadata that a plain SQL type can't express. [`Fields`] stores it in the
+//! SQLite `_fields` table, keyed by table and column, so it survives restarts and can be
+//! edited by operators through the `/admin/fields` routes in [`crate::admin`].
+//!
+//! A [`FieldKind::Select`] column only accepts one of a fixed list of values. The list
+//! is enforced on write with [`TableFields::validate`], published as an `enum` in the
+//! OpenAPI schema of the table with [`TableFields::apply`], and returned by the admin
+//! routes so form builders can render a dropdown.
+//!
+//! A [`FieldKind::Computed`] field isn't a real column: it is an SQL expression over the
+//! table's columns, such as `first_name || ' ' || last_name`, added to generated
+//! queries with [`TableFields::select`] and to the table's schema as a read-only
+//! property. Expressions are inserted into queries verbatim, so [`Fields::define`]
+//! only accepts a single expression over the table's own columns: statement separators,
+//! comments and subqueries are rejected, and the expression must compile against the
+//! table.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # async fn run(db: sqlx::Pool<sqlx::Sqlite>) -> Result<(), sqlx::Error> {
+//! use palmera_database::fields::{FieldKind, Fields};
+//! use serde_json::json;
+//!
+//! let fields = Fields::open(db).await?;
+//! fields
+//!     .define("posts", "status", FieldKind::select(["draft", "published"]))
+//!     .await?;
+//!
+//! let posts = fields.for_table("posts").await?;
+//! let record = json!({ "title": "Hello", "status": "archived" });
+//! assert!(posts.validate(record.as_object().unwrap()).is_err());
+//! # Ok(())
+//! # }
+//! ```
+
+use sea_query::{Alias, Expr, SelectStatement, SqliteQueryBuilder};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite, types::Json};
+use utoipa::{
+    ToSchema,
+    openapi::{
+        RefOr, Schema,
+        schema::{Object, ObjectBuilder, Type},
+    },
+};
+
+use crate::{
+    error::StoreError,
+    sqlite::helpers::{create_fields_table, quote_ident},
+};
+
+/// What a managed field is and the rules it enforces.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FieldKind {
+    /// A text column restricted to `values`, in display order.
+    Select { values: Vec<String> },
+    /// A read-only value computed from `expression` when the table is queried.
+    Computed {
+        expression: String,
+        #[serde(default)]
+        value_type: ValueType,
+    },
+}
+
+/// Type of a computed field's values, as published in the OpenAPI schema.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ValueType {
+    #[default]
+    String,
+    Integer,
+    Number,
+    Boolean,
+}
+
+impl From<ValueType> for Type {
+    fn from(value: ValueType) -> Self {
+        match value {
+            ValueType::String => Type::String,
+            ValueType::Integer => Type::Integer,
+            ValueType::Number => Type::Number,
+            ValueType::Boolean => Type::Boolean,
+        }
+    }
+}
+
+impl FieldKind {
+    /// A select field accepting `values`.
+    pub fn select<I, S>(values: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self::Select {
+            values: values.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    /// A computed field of `value_type` evaluating `expression`.
+    pub fn computed(expression: impl Into<String>, value_type: ValueType) -> Self {
+        Self::Computed {
+            expression: expression.into(),
+            value_type,
+        }
+    }
+
+    /// Checks a written `value` against the field's rules. For select fields `null` is
+    /// always accepted; whether a column may be empty is up to its `NOT NULL`
+    /// constraint. Computed fields can't be written.
+    pub fn check(&self, value: &serde_json::Value) -> Result<(), String> {
+        match self {
+            Self::Select { values } => match value {
+                serde_json::Value::Null => Ok(()),
+                serde_json::Value::String(value) if values.contains(value) => Ok(()),
+                _ => Err(format!("must be one of: {}", values.join(", "))),
+            },
+            Self::Computed { .. } => Err("is computed and can't be written".to_string()),
+        }
+    }
+
+    /// Checks the definition itself: a select field needs values, and a computed field
+    /// a single expression without statement separators, comments or subqueries.
+    pub fn check_definition(&self) -> Result<(), String> {
+        match self {
+            Self::Select { values } if values.is_empty() => {
+                Err("a select field needs at least one value".to_string())
+            }
+            Self::Select { .. } => Ok(()),
+            Self::Computed { expression, .. } => check_expression(expression),
+        }
+    }
+
+    /// The OpenAPI schema of the field's values.
+    pub fn schema(&self) -> Schema {
+        match self {
+            Self::Select { values } => ObjectBuilder::new()
+                .schema_type(Type::String)
+                .enum_values(Some(values.iter().cloned()))
+                .into(),
+            Self::Computed { value_type, .. } => ObjectBuilder::new()
+                .schema_type(Type::from(*value_type))
+                .read_only(Some(true))
+                .into(),
+        }
+    }
+}
+
+/// Scans `expression` outside of string literals and quoted identifiers for anything
+/// that would let it escape the select list it is inserted into.
+fn check_expression(expression: &str) -> Result<(), String> {
+    if expression.trim().is_empty() {
+        return Err("expression must not be empty".to_string());
+    }
+
+    let mut depth = 0usize;
+    let mut word = String::new();
+    let mut chars = expression.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_ascii_alphanumeric() || c == '_' {
+            word.push(c);
+            continue;
+        }
+        if word.eq_ignore_ascii_case("select") {
+            return Err("expression must not contain subqueries".to_string());
+        }
+        word.clear();
+
+        match c {
+            '\'' | '"' | '`' | '[' => {
+                let end = if c == '[' { ']' } else { c };
+                loop {
+                    match chars.next() {
+                        Some(next) if next == end && chars.peek() == Some(&end) => {
+                            chars.next();
+                        }
+                        Some(next) if next == end => break,
+                        Some(_) => {}
+                        None => return Err("expression has an unterminated quote".to_string()),
+                    }
+                }
+            }
+            '(' => depth += 1,
+            ')' => {
+                depth = depth
+                    .checked_sub(1)
+                    .ok_or_else(|| "expression has unbalanced parentheses".to_string())?;
+            }
+            ';' => return Err("expression must not contain ';'".to_string()),
+            '-' if chars.peek() == Some(&'-') => {
+                return Err("expression must not contain comments".to_string());
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                return Err("expression must not contain comments".to_string());
+            }
+            _ => {}
+        }
+    }
+    if word.eq_ignore_ascii_case("select") {
+        return Err("expression must not contain subqueries".to_string());
+    }
+    if depth > 0 {
+        return Err("expression has unbalanced parentheses".to_string());
+    }
+    Ok(())
+}
+
+/// A managed field of a table.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct Field {
+    pub table_name: String,
+    pub column_name: String,
+    pub kind: FieldKind,
+}
+
+/// A value rejected by [`TableFields::validate`].
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct FieldViolation {
+    pub column: String,
+    pub message: String,
+}
+
+/// The managed fields of one table, loaded with [`Fields::for_table`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TableFields {
+    fields: Vec<Field>,
+}
+
+impl TableFields {
+    /// Returns the field of `column`, if it is managed.
+    pub fn get(&self, column: &str) -> Option<&Field> {
+        self.fields.iter().find(|field| field.column_name == column)
+    }
+
+    /// Returns every managed field, ordered by column.
+    pub fn fields(&self) -> &[Field] {
+        &self.fields
+    }
+
+    /// Checks the columns of a record about to be inserted or updated. Columns that
+    /// aren't managed, or aren't part of `record`, are not checked.
+    ///
+    /// # Errors
+    ///
+    /// Returns one [`FieldViolation`] per rejected column.
+    pub fn validate(
+        &self,
+        record: &serde_json::Map<String, serde_json::Value>,
+    ) -> Result<(), Vec<FieldViolation>> {
+        let violations: Vec<_> = self
+            .fields
+            .iter()
+            .filter_map(|field| {
+                let value = record.get(&field.column_name)?;
+                field.kind.check(value).err().map(|message| FieldViolation {
+                    column: field.column_name.clone(),
+                    message,
+                })
+            })
+            .collect();
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+
+    /// Replaces the properties of managed columns in the table's object `schema` and
+    /// adds the computed fields. Select fields that aren't in `schema` are not added.
+    pub fn apply(&self, schema: &mut Object) {
+        for field in &self.fields {
+            let property = RefOr::T(field.kind.schema());
+            match field.kind {
+                FieldKind::Computed { .. } => {
+                    schema
+                        .properties
+                        .insert(field.column_name.clone(), property);
+                }
+                FieldKind::Select { .. } => {
+                    if let Some(existing) = schema.properties.get_mut(&field.column_name) {
+                        *existing = property;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Adds the computed fields to the columns selected by `stmt`, each aliased to its
+    /// field name.
+    pub fn select(&self, stmt: &mut SelectStatement) {
+        for field in &self.fields {
+            if let FieldKind::Computed { expression, .. } = &field.kind {
+                stmt.expr_as(
+                    Expr::cust(format!("({})", expression)),
+                    Alias::new(&field.column_name),
+                );
+            }
+        }
+    }
+}
+
+/// Managed field definitions persisted in SQLite.
+#[derive(Debug, Clone)]
+pub struct Fields {
+    db: Pool<Sqlite>,
+}
+
+type FieldRow = (String, String, Json<FieldKind>);
+
+impl Fields {
+    /// Opens the store, creating the `_fields` table if it does not exist.
+    pub async fn open(db: Pool<Sqlite>) -> Result<Self, sqlx::Error> {
+        sqlx::query(&create_fields_table().to_string(SqliteQueryBuilder))
+            .execute(&db)
+            .await?;
+
+        Ok(Self { db })
+    }
+
+    /// Defines `column` of `table` as a managed field, replacing any previous
+    /// definition.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`StoreError::Invalid`] if the definition is rejected by
+    /// [`FieldKind::check_definition`], or if a computed field's expression doesn't
+    /// compile against `table`, e.g. because it names a missing column.
+    pub async fn define(
+        &self,
+        table: &str,
+        column: &str,
+        kind: FieldKind,
+    ) -> Result<Field, StoreError> {
+        kind.check_definition().map_err(StoreError::Invalid)?;
+        if let FieldKind::Computed { expression, .. } = &kind {
+            sqlx::query(&format!(
+                "SELECT ({}) FROM {} LIMIT 0",
+                expression,
+                quote_ident(table)
+            ))
+            .execute(&self.db)
+            .await
+            .map_err(|err| match err {
+                sqlx::Error::Database(err) => StoreError::Invalid(format!(
+                    "expression of {} is invalid: {}",
+                    column,
+                    err.message()
+                )),
+                err => StoreError::Database(err),
+            })?;
+        }
+
+        sqlx::query(
+            "INSERT INTO _fields (table_name, column_name, kind) VALUES (?, ?, ?) \
+             ON CONFLICT (table_name, column_name) DO UPDATE SET kind = excluded.kind",
+        )
+        .bind(table)
+        .bind(column)
+        .bind(Json(&kind))
+        .execute(&self.db)
+        .await?;
+
+        Ok(Field {
+            table_name: table.to_string(),
+            column_name: column.to_string(),
+            kind,
+        })
+    }
+
+    /// Returns the managed fields of `table`.
+    pub async fn for_table(&self, table: &str) -> Result<TableFields, sqlx::Error> {
+        let rows: Vec<FieldRow> = sqlx::query_as(
+            "SELECT table_name, column_name, kind FROM _fields \
+             WHERE table_name = ? ORDER BY column_name",
+        )
+        .bind(table)
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(TableFields {
+            fields: rows.into_iter().map(into_field).collect(),
+        })
+    }
+
+    /// Returns the managed fields of every table, ordered by table and column.
+    pub async fn list(&self) -> Result<Vec<Field>, sqlx::Error> {
+        let rows: Vec<FieldRow> = sqlx::query_as(
+            "SELECT table_name, column_name, kind FROM _fields ORDER BY table_name, column_name",
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(rows.into_iter().map(into_field).collect())
+    }
+
+    /// Removes the definition of `column`, returning whether it was managed. The column
+    /// itself is left untouched.
+    pub async fn remove(&self, table: &str, column: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM _fields WHERE table_name = ? AND column_name = ?")
+            .bind(table)
+            .bind(column)
+            .execute(&self.db)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+fn into_field((table_name, column_name, Json(kind)): FieldRow) -> Field {
+    Field {
+        table_name,
+        column_name,
+        kind,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use utoipa::openapi::schema::SchemaType;
+
+    #[sqlx::test]
+    async fn test_define_and_validate_select(db: Pool<Sqlite>) -> Result<(), StoreError> {
+        let fields = Fields::open(db).await?;
+        fields
+            .define("posts", "status", FieldKind::select(["draft"]))
+            .await?;
+        fields
+            .define("posts", "status", FieldKind::select(["draft", "published"]))
+            .await?;
+        fields
+            .define("comments", "status", FieldKind::select(["visible"]))
+            .await?;
+
+        let posts = fields.for_table("posts").await?;
+        assert_eq!(posts.fields().len(), 1);
+        assert_eq!(
+            posts.get("status").unwrap().kind,
+            FieldKind::select(["draft", "published"])
+        );
+
+        let valid = json!({ "title": "Hello", "status": "published" });
+        assert!(posts.validate(valid.as_object().unwrap()).is_ok());
+        let empty = json!({ "status": null });
+        assert!(posts.validate(empty.as_object().unwrap()).is_ok());
+
+        let invalid = json!({ "status": "archived" });
+        let violations = posts.validate(invalid.as_object().unwrap()).unwrap_err();
+        assert_eq!(
+            violations,
+            [FieldViolation {
+                column: "status".to_string(),
+                message: "must be one of: draft, published".to_string(),
+            }]
+        );
+
+        assert!(fields.remove("posts", "status").await?);
+        assert!(!fields.remove("posts", "status").await?);
+        assert!(fields.for_table("posts").await?.fields().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_publishes_enum() {
+        let table = TableFields {
+            fields: vec![Field {
+                table_name: "posts".to_string(),
+                column_name: "status".to_string(),
+                kind: FieldKind::select(["draft", "published"]),
+            }],
+        };
+
+        let mut schema = ObjectBuilder::new()
+            .property("title", ObjectBuilder::new().schema_type(Type::String))
+            .property("status", ObjectBuilder::new().schema_type(Type::String))
+            .build();
+        table.apply(&mut schema);
+
+        let RefOr::T(Schema::Object(status)) = &schema.properties["status"] else {
+            panic!("status is not an object schema");
+        };
+        assert!(status.schema_type == SchemaType::Type(Type::String));
+        assert_eq!(
+            status.enum_values,
+            Some(vec![json!("draft"), json!("published")])
+        );
+        assert!(!schema.properties.contains_key("views"));
+    }
+
+    #[sqlx::test]
+    async fn test_computed_fields(db: Pool<Sqlite>) -> Result<(), StoreError> {
+        sqlx::query("CREATE TABLE people (id INTEGER PRIMARY KEY, first TEXT, last TEXT)")
+            .execute(&db)
+            .await?;
+        sqlx::query("INSERT INTO people (first, last) VALUES ('Ada', 'Lovelace')")
+            .execute(&db)
+            .await?;
+
+        let fields = Fields::open(db.clone()).await?;
+        fields
+            .define(
+                "people",
+                "full_name",
+                FieldKind::computed("first || ' ' || last", ValueType::String),
+            )
+            .await?;
+        let people = fields.for_table("people").await?;
+
+        let mut stmt = sea_query::Query::select();
+        stmt.column(Alias::new("id")).from(Alias::new("people"));
+        people.select(&mut stmt);
+        let (id, full_name): (i64, String) = sqlx::query_as(&stmt.to_string(SqliteQueryBuilder))
+            .fetch_one(&db)
+            .await?;
+        assert_eq!((id, full_name.as_str()), (1, "Ada Lovelace"));
+
+        let mut schema = ObjectBuilder::new()
+            .property("id", ObjectBuilder::new().schema_type(Type::Integer))
+            .build();
+        people.apply(&mut schema);
+        let RefOr::T(Schema::Object(full_name)) = &schema.properties["full_name"] else {
+            panic!("full_name is not an object schema");
+        };
+        assert_eq!(full_name.read_only, Some(true));
+
+        let write = json!({ "full_name": "Grace Hopper" });
+        let violations = people.validate(write.as_object().unwrap()).unwrap_err();
+        assert_eq!(violations[0].column, "full_name");
+
+        for expression in [
+            "first); DROP TABLE people; --",
+            "first -- comment",
+            "(SELECT password FROM users)",
+            "upper(first",
+            "'unterminated",
+            "middle",
+        ] {
+            let kind = FieldKind::computed(expression, ValueType::String);
+            assert!(
+                matches!(
+                    fields.define("people", "bad", kind).await,
+                    Err(StoreError::Invalid(_))
+                ),
+                "{expression}"
+            );
+        }
+        fields
+            .define(
+                "people",
+                "quoted",
+                FieldKind::computed("first || ';--' || \"last\"", ValueType::String),
+            )
+            .await?;
+        assert!(
+            FieldKind::select(Vec::<String>::new())
+                .check_definition()
+                .is_err()
+        );
+        Ok(())
+    }
+}
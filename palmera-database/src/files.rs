@@ -0,0 +1,540 @@
+//! # File metadata search
+//!
+//! The SQLite `_files` table keeps the bucket, name, MIME type, size and upload time of
+//! every stored file, so admin UIs can browse and manage assets without listing each
+//! storage backend. The bucket routes of `palmera-storage` keep it current through
+//! [`FileIndex::record`] and [`FileIndex::remove`] when an `Extension<Arc<FileIndex>>`
+//! is layered on them, and serve [`FileIndex::search`] as `GET /files`, limited to the
+//! buckets the caller may list:
+//!
+//! ```text
+//! GET /files?filter=mime='image/png' && size>1048576&sort=-size&page=2&per_page=20
+//! ```
+//!
+//! Filters use the fexpr syntax of the record filters. A filter compares the columns
+//! `bucket`, `name`, `mime`, `size` and `created_at` with `=`, `!=`, `<`, `<=`, `>`,
+//! `>=`, `~` (like) or `!~` (not like) to a quoted string, a number or `null`, and
+//! combines comparisons with `&&`, `||` and parentheses. Strings are quoted with `'` or
+//! `"`, escaping the quote with a backslash, and `//` starts a comment. `~` matches the
+//! text anywhere in the value unless it has its own `%` wildcards. `created_at` is
+//! compared to an RFC 3339 time. The `?=` "any of" operators don't apply to these
+//! single valued columns and are refused. `sort` lists columns, each prefixed with `-`
+//! for descending order, and defaults to the newest files first. The filter's values
+//! are bound as parameters, never spliced into the statement.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # async fn run(db: sqlx::Pool<sqlx::Sqlite>) -> Result<(), palmera_database::error::StoreError> {
+//! use palmera_database::files::{FileIndex, FileSearch};
+//!
+//! let index = FileIndex::open(db).await?;
+//! let search = FileSearch {
+//!     filter: Some("mime ~ 'image/%' && size > 1048576".to_string()),
+//!     ..Default::default()
+//! };
+//! let large_images = index.search(&search, &["avatars"]).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use chrono::{DateTime, Utc};
+use sea_query::SqliteQueryBuilder;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{Pool, Sqlite, types::Json as SqlJson};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    error::StoreError,
+    sqlite::helpers::{create_files_table, quote_ident},
+};
+
+/// Files per page when a search doesn't say.
+pub const DEFAULT_PER_PAGE: u32 = 50;
+
+/// Most files a page can hold.
+pub const MAX_PER_PAGE: u32 = 500;
+
+/// Columns filters and sorts can use.
+const COLUMNS: &[&str] = &["bucket", "name", "mime", "size", "created_at"];
+
+const DEFAULT_SORT: &str = "-created_at";
+
+/// Metadata of a stored file.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+pub struct FileMetadata {
+    pub bucket: String,
+    pub name: String,
+    pub mime: String,
+    /// Size in bytes.
+    pub size: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Query of [`FileIndex::search`].
+#[derive(Debug, Clone, Default, Deserialize, IntoParams, PartialEq)]
+pub struct FileSearch {
+    /// Filter expression, e.g. `mime='image/png' && size>1048576`.
+    pub filter: Option<String>,
+    /// Comma separated columns, `-` prefixed for descending order, `-created_at` by
+    /// default.
+    pub sort: Option<String>,
+    /// Page to return, starting at 1.
+    pub page: Option<u32>,
+    /// Files per page, 50 by default and at most 500.
+    pub per_page: Option<u32>,
+}
+
+/// A page of search results.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+pub struct FilePage {
+    pub items: Vec<FileMetadata>,
+    pub page: u32,
+    pub per_page: u32,
+    /// Files matching the filter, on every page.
+    pub total: u64,
+}
+
+type FileRow = (String, String, String, i64, i64);
+
+/// File metadata persisted in SQLite.
+#[derive(Debug, Clone)]
+pub struct FileIndex {
+    db: Pool<Sqlite>,
+}
+
+impl FileIndex {
+    /// Opens the index, creating the `_files` table if it does not exist.
+    pub async fn open(db: Pool<Sqlite>) -> Result<Self, sqlx::Error> {
+        sqlx::query(&create_files_table().to_string(SqliteQueryBuilder))
+            .execute(&db)
+            .await?;
+
+        Ok(Self { db })
+    }
+
+    /// Records a stored file, replacing the metadata of the file it overwrote.
+    pub async fn record(&self, file: &FileMetadata) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO _files (bucket, name, mime, size, created_at) VALUES (?, ?, ?, ?, ?) \
+             ON CONFLICT (bucket, name) DO UPDATE SET mime = excluded.mime, \
+             size = excluded.size, created_at = excluded.created_at",
+        )
+        .bind(&file.bucket)
+        .bind(&file.name)
+        .bind(&file.mime)
+        .bind(file.size)
+        .bind(file.created_at.timestamp())
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    /// Forgets a deleted file, returning whether it was recorded.
+    pub async fn remove(&self, bucket: &str, name: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM _files WHERE bucket = ? AND name = ?")
+            .bind(bucket)
+            .bind(name)
+            .execute(&self.db)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Returns a page of the files of `buckets` matching `search`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`StoreError::Invalid`] if the filter or sort is invalid.
+    pub async fn search(
+        &self,
+        search: &FileSearch,
+        buckets: &[&str],
+    ) -> Result<FilePage, StoreError> {
+        let (filter, params) = match search.filter.as_deref().map(str::trim) {
+            Some(filter) if !filter.is_empty() => parse_filter(filter)?,
+            _ => ("1".to_string(), Vec::new()),
+        };
+        let condition = format!("bucket IN (SELECT value FROM json_each(?2)) AND ({filter})");
+        let order = order_by(search.sort.as_deref().unwrap_or(DEFAULT_SORT))?;
+        let per_page = search
+            .per_page
+            .unwrap_or(DEFAULT_PER_PAGE)
+            .clamp(1, MAX_PER_PAGE);
+        let page = search.page.unwrap_or(1).max(1);
+        let params = SqlJson(params);
+        let buckets = SqlJson(buckets);
+
+        let total: i64 =
+            sqlx::query_scalar(&format!("SELECT count(*) FROM _files WHERE {condition}"))
+                .bind(&params)
+                .bind(&buckets)
+                .fetch_one(&self.db)
+                .await?;
+        let rows: Vec<FileRow> = sqlx::query_as(&format!(
+            "SELECT bucket, name, mime, size, created_at FROM _files WHERE {condition} \
+             ORDER BY {order} LIMIT ?3 OFFSET ?4"
+        ))
+        .bind(&params)
+        .bind(&buckets)
+        .bind(i64::from(per_page))
+        .bind(i64::from(page - 1) * i64::from(per_page))
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(FilePage {
+            items: rows
+                .into_iter()
+                .map(|(bucket, name, mime, size, created_at)| FileMetadata {
+                    bucket,
+                    name,
+                    mime,
+                    size,
+                    created_at: DateTime::from_timestamp(created_at, 0).unwrap_or_default(),
+                })
+                .collect(),
+            page,
+            per_page,
+            total: total as u64,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Name(String),
+    Text(String),
+    Number(serde_json::Number),
+    Symbol(&'static str),
+}
+
+/// Operators and punctuation of the fexpr grammar, longest first so `<=` isn't read as
+/// `<`. The `?` "any of" operators are read so they can be refused by name.
+const SYMBOLS: &[&str] = &[
+    "?!=", "?!~", "?<=", "?>=", "&&", "||", "!=", "!~", "<=", ">=", "?=", "?~", "?<", "?>", "=",
+    "<", ">", "~", "(", ")",
+];
+
+const COMPARISONS: &[&str] = &["=", "!=", "<", "<=", ">", ">=", "~", "!~"];
+
+fn tokenize(filter: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = filter.trim_start();
+
+    while let Some(c) = rest.chars().next() {
+        let end = if rest.starts_with("//") {
+            rest.find('\n').unwrap_or(rest.len())
+        } else if let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol)) {
+            tokens.push(Token::Symbol(symbol));
+            symbol.len()
+        } else if c == '\'' || c == '"' {
+            // A backslash escapes the quote, as in fexpr.
+            let mut text = String::new();
+            let mut chars = rest.char_indices().skip(1);
+            let end = loop {
+                match chars.next() {
+                    Some((i, quote)) if quote == c => break i + 1,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, escaped)) if escaped == c => text.push(c),
+                        Some((_, escaped)) => {
+                            text.push('\\');
+                            text.push(escaped);
+                        }
+                        None => return Err("unterminated string".to_string()),
+                    },
+                    Some((_, ch)) => text.push(ch),
+                    None => return Err("unterminated string".to_string()),
+                }
+            };
+            tokens.push(Token::Text(text));
+            end
+        } else if c.is_ascii_digit() || c == '-' {
+            let end = rest[1..]
+                .find(|c: char| !c.is_ascii_digit() && c != '.')
+                .map_or(rest.len(), |i| i + 1);
+            let number = rest[..end]
+                .parse::<serde_json::Number>()
+                .map_err(|_| format!("invalid number {:?}", &rest[..end]))?;
+            tokens.push(Token::Number(number));
+            end
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let end = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            tokens.push(Token::Name(rest[..end].to_string()));
+            end
+        } else {
+            return Err(format!("unexpected {:?}", c));
+        };
+        rest = rest[end..].trim_start();
+    }
+
+    Ok(tokens)
+}
+
+/// The `LIKE` pattern of a `~` comparison: the text anywhere in the value, unless it
+/// has its own `%` wildcards, as in fexpr.
+fn like_pattern(text: &str) -> String {
+    if text.contains('%') {
+        text.to_string()
+    } else {
+        let escaped = text
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_");
+        format!("%{escaped}%")
+    }
+}
+
+/// Turns a filter into SQL conditions, reading the values as the JSON array bound to
+/// `?1`.
+struct Parser {
+    tokens: std::vec::IntoIter<Token>,
+    peeked: Option<Token>,
+    params: Vec<serde_json::Value>,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        self.peeked.take().or_else(|| self.tokens.next())
+    }
+
+    fn eat(&mut self, symbol: &str) -> bool {
+        match self.next() {
+            Some(Token::Symbol(found)) if found == symbol => true,
+            token => {
+                self.peeked = token;
+                false
+            }
+        }
+    }
+
+    fn or(&mut self) -> Result<String, String> {
+        let mut sql = self.and()?;
+        while self.eat("||") {
+            sql = format!("{sql} OR {}", self.and()?);
+        }
+        Ok(sql)
+    }
+
+    fn and(&mut self) -> Result<String, String> {
+        let mut sql = self.comparison()?;
+        while self.eat("&&") {
+            sql = format!("{sql} AND {}", self.comparison()?);
+        }
+        Ok(sql)
+    }
+
+    fn comparison(&mut self) -> Result<String, String> {
+        if self.eat("(") {
+            let sql = self.or()?;
+            if !self.eat(")") {
+                return Err("missing )".to_string());
+            }
+            return Ok(format!("({sql})"));
+        }
+
+        let Some(Token::Name(column)) = self.next() else {
+            return Err("expected a column".to_string());
+        };
+        if !COLUMNS.contains(&column.as_str()) {
+            return Err(format!("unknown column {:?}", column));
+        }
+        let op = match self.next() {
+            Some(Token::Symbol(op)) if COMPARISONS.contains(&op) => op,
+            Some(Token::Symbol(op)) if op.starts_with('?') => {
+                return Err(format!("{op} is not supported"));
+            }
+            _ => return Err(format!("expected a comparison after {:?}", column)),
+        };
+        let quoted = quote_ident(&column);
+        let value = match (self.next(), column.as_str()) {
+            (Some(Token::Name(null)), _) if null == "null" => {
+                return match op {
+                    "=" => Ok(format!("{quoted} IS NULL")),
+                    "!=" => Ok(format!("{quoted} IS NOT NULL")),
+                    _ => Err(format!("null can't be compared with {op}")),
+                };
+            }
+            (Some(Token::Text(text)), _) if op.ends_with('~') => json!(like_pattern(&text)),
+            (Some(Token::Text(time)), "created_at") => DateTime::parse_from_rfc3339(&time)
+                .map(|time| json!(time.timestamp()))
+                .map_err(|_| format!("{:?} is not an RFC 3339 time", time))?,
+            (Some(Token::Text(text)), _) => json!(text),
+            (Some(Token::Number(number)), _) => json!(number),
+            _ => return Err(format!("expected a value after {:?} {}", column, op)),
+        };
+
+        let param = format!("json_extract(?1, '$[{}]')", self.params.len());
+        self.params.push(value);
+        Ok(match op {
+            "~" => format!("{quoted} LIKE {param} ESCAPE '\\'"),
+            "!~" => format!("{quoted} NOT LIKE {param} ESCAPE '\\'"),
+            op => format!("{quoted} {op} {param}"),
+        })
+    }
+}
+
+/// The SQL condition of `filter` and the values to bind to `?1` as a JSON array.
+fn parse_filter(filter: &str) -> Result<(String, Vec<serde_json::Value>), StoreError> {
+    let invalid = |err: String| StoreError::Invalid(format!("invalid filter: {}", err));
+    let mut parser = Parser {
+        tokens: tokenize(filter).map_err(invalid)?.into_iter(),
+        peeked: None,
+        params: Vec::new(),
+    };
+    let condition = parser.or().map_err(invalid)?;
+    if let Some(token) = parser.next() {
+        return Err(invalid(format!("unexpected {:?}", token)));
+    }
+    Ok((condition, parser.params))
+}
+
+/// The `ORDER BY` terms of `sort`, ending with the key so pages don't overlap.
+fn order_by(sort: &str) -> Result<String, StoreError> {
+    let mut terms = Vec::new();
+    for column in sort
+        .split(',')
+        .map(str::trim)
+        .filter(|column| !column.is_empty())
+    {
+        let (column, direction) = match column.strip_prefix('-') {
+            Some(column) => (column, "DESC"),
+            None => (column, "ASC"),
+        };
+        if !COLUMNS.contains(&column) {
+            return Err(StoreError::Invalid(format!("cannot sort by {:?}", column)));
+        }
+        terms.push(format!("{} {direction}", quote_ident(column)));
+    }
+    terms.push("bucket ASC, name ASC".to_string());
+    Ok(terms.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_filter() {
+        let (sql, params) = parse_filter("mime='image/png' && size>1048576").unwrap();
+        assert_eq!(
+            sql,
+            r#""mime" = json_extract(?1, '$[0]') AND "size" > json_extract(?1, '$[1]')"#
+        );
+        assert_eq!(params, [json!("image/png"), json!(1048576)]);
+
+        let (sql, params) = parse_filter(
+            r#"(name ~ 'it\'s' || bucket != "avatars") && created_at >= '2025-01-01T00:00:00Z'"#,
+        )
+        .unwrap();
+        assert_eq!(
+            sql,
+            r#"("name" LIKE json_extract(?1, '$[0]') ESCAPE '\' OR "bucket" != json_extract(?1, '$[1]')) AND "created_at" >= json_extract(?1, '$[2]')"#
+        );
+        assert_eq!(
+            params,
+            [json!("%it's%"), json!("avatars"), json!(1735689600)]
+        );
+
+        let (sql, params) =
+            parse_filter("mime !~ 'image/%' && name != null // not the placeholders").unwrap();
+        assert_eq!(
+            sql,
+            r#""mime" NOT LIKE json_extract(?1, '$[0]') ESCAPE '\' AND "name" IS NOT NULL"#
+        );
+        assert_eq!(params, [json!("image/%")]);
+        assert_eq!(like_pattern("50%_off"), "50%_off");
+        assert_eq!(like_pattern("a_b"), "%a\\_b%");
+
+        for invalid in [
+            "owner = 'me'",
+            "size >",
+            "size > 1 &&",
+            "(size > 1",
+            "size > 1 size",
+            "name = 'open",
+            "created_at > 'yesterday'",
+            "size; DROP TABLE _files",
+            "bucket ?= 'avatars'",
+            "size > null",
+            "size > 1.2.3",
+        ] {
+            assert!(
+                matches!(parse_filter(invalid), Err(StoreError::Invalid(_))),
+                "{invalid}"
+            );
+        }
+    }
+
+    fn file(bucket: &str, name: &str, mime: &str, size: i64, created_at: i64) -> FileMetadata {
+        FileMetadata {
+            bucket: bucket.to_string(),
+            name: name.to_string(),
+            mime: mime.to_string(),
+            size,
+            created_at: DateTime::from_timestamp(created_at, 0).unwrap(),
+        }
+    }
+
+    #[sqlx::test]
+    async fn test_search(db: Pool<Sqlite>) -> Result<(), StoreError> {
+        let index = FileIndex::open(db).await?;
+        index
+            .record(&file("avatars", "a.png", "image/png", 2 << 20, 1))
+            .await?;
+        index
+            .record(&file("avatars", "b.png", "image/png", 512, 2))
+            .await?;
+        index
+            .record(&file("avatars", "c.jpg", "image/jpeg", 4 << 20, 3))
+            .await?;
+        index
+            .record(&file("invoices", "1.pdf", "application/pdf", 3 << 20, 4))
+            .await?;
+        // Overwriting a file replaces its metadata.
+        index
+            .record(&file("avatars", "b.png", "image/png", 8 << 20, 5))
+            .await?;
+        assert!(index.remove("invoices", "1.pdf").await?);
+        assert!(!index.remove("invoices", "1.pdf").await?);
+
+        let all = index
+            .search(&FileSearch::default(), &["avatars", "invoices"])
+            .await?;
+        assert_eq!(all.total, 3);
+        let names: Vec<_> = all.items.iter().map(|file| file.name.as_str()).collect();
+        assert_eq!(names, ["b.png", "c.jpg", "a.png"]);
+        // Only the given buckets are searched.
+        assert_eq!(
+            index
+                .search(&FileSearch::default(), &["invoices"])
+                .await?
+                .total,
+            0
+        );
+
+        let large_pngs = FileSearch {
+            filter: Some("mime='image/png' && size>1048576".to_string()),
+            sort: Some("size".to_string()),
+            per_page: Some(1),
+            page: Some(2),
+        };
+        let page = index.search(&large_pngs, &["avatars"]).await?;
+        assert_eq!((page.total, page.page, page.per_page), (2, 2, 1));
+        assert_eq!(
+            page.items,
+            [file("avatars", "b.png", "image/png", 8 << 20, 5)]
+        );
+
+        let unknown = FileSearch {
+            sort: Some("-owner".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            index.search(&unknown, &["avatars"]).await,
+            Err(StoreError::Invalid(_))
+        ));
+        Ok(())
+    }
+}
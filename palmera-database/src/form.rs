@@ -0,0 +1,416 @@
+//! # Form bodies
+//!
+//! Standard HTML forms can't send JSON, so record routes also accept
+//! `multipart/form-data` and `application/x-www-form-urlencoded` bodies through the
+//! [`FormRecord`] extractor; `PATCH /{schema}/{table}/{id}` of
+//! [`crate::postgres::sync`] reads such bodies as the new column values. Bracketed field
+//! names are mapped into nested JSON before the record is validated and written, the way
+//! PHP and Rails read forms:
+//!
+//! | Field name | Record |
+//! |------------|--------|
+//! | `title=Hi` | `{ "title": "Hi" }` |
+//! | `address[city]=Paris` | `{ "address": { "city": "Paris" } }` |
+//! | `tags[]=a`, `tags[]=b` | `{ "tags": ["a", "b"] }` |
+//! | `items[1][name]=b`, `items[0][name]=a` | `{ "items": [{ "name": "a" }, { "name": "b" }] }` |
+//!
+//! Values are strings, as the form sent them. `[]` appends to a list and can only end a
+//! name. Names that clash, such as `address=x` next to `address[city]=y`, or a plain name
+//! sent twice, are rejected with `422 Unprocessable Entity`. Names are at most
+//! [`MAX_DEPTH`] segments deep and indexes at most [`MAX_INDEX`]. Lists are kept sparse
+//! while the form is read and compacted afterwards, dropping the gaps between indexes:
+//! `items[5]=x` alone gives `{ "items": ["x"] }`. The record thus holds no more values
+//! than the form sent, however large its indexes. Multipart parts carrying a file are
+//! left to the storage routes and skipped.
+//!
+//! # Example
+//!
+//! ```rust
+//! use axum::{Json, Router, routing::post};
+//! use palmera_database::form::FormRecord;
+//!
+//! let app: Router = Router::new().route(
+//!     "/records/contacts",
+//!     post(|FormRecord(record): FormRecord| async move { Json(record) }),
+//! );
+//! ```
+
+use std::collections::BTreeMap;
+
+use axum::{
+    Form,
+    extract::{FromRequest, Multipart, Request},
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use serde_json::{Map, Value};
+
+/// Most segments a field name may have, e.g. 3 for `a[b][c]`.
+pub const MAX_DEPTH: usize = 8;
+
+/// Largest list index a field name may use.
+pub const MAX_INDEX: usize = 1000;
+
+/// A record read from a form body.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FormRecord(pub Map<String, Value>);
+
+/// Why a form body couldn't be read into a record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FormError {
+    /// The body isn't a well-formed form.
+    Malformed(String),
+    /// A field name is invalid or clashes with another one.
+    Field(String),
+}
+
+impl FormError {
+    /// HTTP status the error is reported with.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::Malformed(_) => StatusCode::BAD_REQUEST,
+            Self::Field(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        }
+    }
+}
+
+impl std::fmt::Display for FormError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Malformed(message) | Self::Field(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for FormError {}
+
+impl IntoResponse for FormError {
+    fn into_response(self) -> Response {
+        (self.status(), self.to_string()).into_response()
+    }
+}
+
+/// A segment of a field name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Key(String),
+    Index(usize),
+    /// `[]`
+    Push,
+}
+
+/// Splits a field name such as `items[0][name]` into its segments.
+fn segments(name: &str) -> Result<Vec<Segment>, FormError> {
+    let invalid = || FormError::Field(format!("invalid field name {name:?}"));
+    let (head, mut rest) = name.split_at(name.find('[').unwrap_or(name.len()));
+    if head.is_empty() {
+        return Err(invalid());
+    }
+
+    let mut segments = vec![Segment::Key(head.to_string())];
+    while !rest.is_empty() {
+        let inner = rest.strip_prefix('[').ok_or_else(invalid)?;
+        let (inner, after) = inner.split_once(']').ok_or_else(invalid)?;
+        rest = after;
+
+        if segments.last() == Some(&Segment::Push) {
+            return Err(FormError::Field(format!(
+                "`[]` can only end a field name, in {name:?}"
+            )));
+        }
+        segments.push(if inner.is_empty() {
+            Segment::Push
+        } else if inner.bytes().all(|byte| byte.is_ascii_digit()) {
+            match inner.parse() {
+                Ok(index) if index <= MAX_INDEX => Segment::Index(index),
+                _ => {
+                    return Err(FormError::Field(format!(
+                        "index of {name:?} exceeds {MAX_INDEX}"
+                    )));
+                }
+            }
+        } else {
+            Segment::Key(inner.to_string())
+        });
+    }
+
+    if segments.len() > MAX_DEPTH {
+        return Err(FormError::Field(format!(
+            "field name {name:?} is nested deeper than {MAX_DEPTH}"
+        )));
+    }
+    Ok(segments)
+}
+
+/// A record being read from a form. Lists are keyed by index until [`Node::into_value`]
+/// compacts them, so a large index doesn't materialize the values before it.
+#[derive(Debug, Default)]
+enum Node {
+    #[default]
+    Empty,
+    Value(Value),
+    Object(BTreeMap<String, Node>),
+    List(BTreeMap<usize, Node>),
+}
+
+impl Node {
+    fn into_value(self) -> Value {
+        match self {
+            Self::Empty => Value::Null,
+            Self::Value(value) => value,
+            Self::Object(fields) => Value::Object(
+                fields
+                    .into_iter()
+                    .map(|(key, node)| (key, node.into_value()))
+                    .collect(),
+            ),
+            Self::List(items) => Value::Array(items.into_values().map(Node::into_value).collect()),
+        }
+    }
+}
+
+/// Sets `value` at the path `segments` below `slot`.
+fn insert(
+    slot: &mut Node,
+    segments: &[Segment],
+    value: Value,
+    name: &str,
+) -> Result<(), FormError> {
+    let clash = || FormError::Field(format!("field {name:?} clashes with another field"));
+    let Some((segment, rest)) = segments.split_first() else {
+        if !matches!(slot, Node::Empty) {
+            return Err(FormError::Field(format!(
+                "field {name:?} is sent twice; name it \"{name}[]\" for a list"
+            )));
+        }
+        *slot = Node::Value(value);
+        return Ok(());
+    };
+
+    match segment {
+        Segment::Key(key) => {
+            if matches!(slot, Node::Empty) {
+                *slot = Node::Object(BTreeMap::new());
+            }
+            let Node::Object(fields) = slot else {
+                return Err(clash());
+            };
+            insert(fields.entry(key.clone()).or_default(), rest, value, name)
+        }
+        Segment::Index(index) => {
+            if matches!(slot, Node::Empty) {
+                *slot = Node::List(BTreeMap::new());
+            }
+            let Node::List(items) = slot else {
+                return Err(clash());
+            };
+            insert(items.entry(*index).or_default(), rest, value, name)
+        }
+        Segment::Push => {
+            if matches!(slot, Node::Empty) {
+                *slot = Node::List(BTreeMap::new());
+            }
+            let Node::List(items) = slot else {
+                return Err(clash());
+            };
+            let next = items.last_key_value().map_or(0, |(index, _)| index + 1);
+            items.insert(next, Node::Value(value));
+            Ok(())
+        }
+    }
+}
+
+/// Maps form fields, in the order they were sent, into a record.
+pub fn nest(
+    fields: impl IntoIterator<Item = (String, String)>,
+) -> Result<Map<String, Value>, FormError> {
+    let mut record = Node::Object(BTreeMap::new());
+    for (name, value) in fields {
+        insert(&mut record, &segments(&name)?, Value::String(value), &name)?;
+    }
+    let Value::Object(record) = record.into_value() else {
+        unreachable!("the root is an object")
+    };
+    Ok(record)
+}
+
+impl<S: Send + Sync> FromRequest<S> for FormRecord {
+    type Rejection = Response;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let content_type = request
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .map(|value| value.trim().to_ascii_lowercase())
+            .unwrap_or_default();
+
+        let fields = match content_type.as_str() {
+            "multipart/form-data" => {
+                let mut multipart = Multipart::from_request(request, state)
+                    .await
+                    .map_err(IntoResponse::into_response)?;
+                let malformed = |err: axum::extract::multipart::MultipartError| {
+                    FormError::Malformed(err.body_text()).into_response()
+                };
+
+                let mut fields = Vec::new();
+                while let Some(field) = multipart.next_field().await.map_err(malformed)? {
+                    let Some(name) = field.name().map(str::to_string) else {
+                        continue;
+                    };
+                    if field.file_name().is_some() {
+                        continue;
+                    }
+                    fields.push((name, field.text().await.map_err(malformed)?));
+                }
+                fields
+            }
+            "application/x-www-form-urlencoded" => {
+                let Form(fields) = Form::<Vec<(String, String)>>::from_request(request, state)
+                    .await
+                    .map_err(IntoResponse::into_response)?;
+                fields
+            }
+            _ => return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response()),
+        };
+
+        nest(fields).map(Self).map_err(IntoResponse::into_response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Json, Router, body::Body, routing::post};
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    fn fields(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_nest() {
+        let record = nest(fields(&[
+            ("name", "Ada"),
+            ("address[city]", "London"),
+            ("address[geo][lat]", "51.5"),
+            ("tags[]", "math"),
+            ("tags[]", "engines"),
+            ("items[1][name]", "b"),
+            ("items[0][name]", "a"),
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            Value::Object(record),
+            json!({
+                "name": "Ada",
+                "address": { "city": "London", "geo": { "lat": "51.5" } },
+                "tags": ["math", "engines"],
+                "items": [{ "name": "a" }, { "name": "b" }],
+            })
+        );
+
+        // Gaps between indexes are dropped, and large indexes cost nothing.
+        let record = nest(fields(&[
+            ("tags[]", "a"),
+            ("tags[7]", "b"),
+            ("tags[]", "c"),
+            ("grid[1000][1000][1000][1000][1000][1000][1000]", "x"),
+        ]))
+        .unwrap();
+        assert_eq!(
+            Value::Object(record),
+            json!({ "tags": ["a", "b", "c"], "grid": [[[[[[["x"]]]]]]] })
+        );
+    }
+
+    #[test]
+    fn test_rejects_invalid_names() {
+        let rejected = |pairs: &[(&str, &str)]| nest(fields(pairs)).unwrap_err();
+
+        for name in ["", "[a]", "a[b", "a]b", "a[][b]", "a[1001]"] {
+            assert!(
+                matches!(rejected(&[(name, "x")]), FormError::Field(_)),
+                "{name}"
+            );
+        }
+        assert!(matches!(
+            rejected(&[("a[b][c][d][e][f][g][h]", "x")]),
+            FormError::Field(_)
+        ));
+        assert!(matches!(
+            rejected(&[("address", "x"), ("address[city]", "y")]),
+            FormError::Field(_)
+        ));
+        assert!(matches!(
+            rejected(&[("tags[]", "x"), ("tags[a]", "y")]),
+            FormError::Field(_)
+        ));
+        assert!(matches!(
+            rejected(&[("name", "a"), ("name", "b")]),
+            FormError::Field(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_extract_form_record() {
+        let app = Router::new().route(
+            "/",
+            post(|FormRecord(record): FormRecord| async move { Json(record) }),
+        );
+        let call = |content_type: &str, body: &'static str| {
+            let request = Request::post("/")
+                .header(header::CONTENT_TYPE, content_type)
+                .body(Body::from(body))
+                .unwrap();
+            let app = app.clone();
+            async move { app.oneshot(request).await.unwrap() }
+        };
+        let json = |response: Response| async move {
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            serde_json::from_slice::<Value>(&body).unwrap()
+        };
+
+        let multipart = "--X\r\n\
+            Content-Disposition: form-data; name=\"address[city]\"\r\n\r\n\
+            Paris\r\n\
+            --X\r\n\
+            Content-Disposition: form-data; name=\"tags[]\"\r\n\r\n\
+            a\r\n\
+            --X\r\n\
+            Content-Disposition: form-data; name=\"avatar\"; filename=\"me.png\"\r\n\
+            Content-Type: image/png\r\n\r\n\
+            png\r\n\
+            --X--\r\n";
+        let response = call("multipart/form-data; boundary=X", multipart).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            json(response).await,
+            json!({ "address": { "city": "Paris" }, "tags": ["a"] })
+        );
+
+        let response = call(
+            "application/x-www-form-urlencoded",
+            "title=Hi&tags%5B%5D=a&tags%5B%5D=b",
+        )
+        .await;
+        assert_eq!(
+            json(response).await,
+            json!({ "title": "Hi", "tags": ["a", "b"] })
+        );
+
+        let clash = call("application/x-www-form-urlencoded", "a=1&a%5Bb%5D=2").await;
+        assert_eq!(clash.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let json_body = call("application/json", "{}").await;
+        assert_eq!(json_body.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+}
@@ -0,0 +1,608 @@
+//! # Row history
+//!
+//! Tables can opt in to history tracking with [`History::enable`]: every update or
+//! delete of one of their rows then copies the row as it was before the change into the
+//! `{table}__history` table, with the action, the time, the actor who made it and the
+//! subject it was made for, who differ when e.g. an admin impersonates a user. The
+//! copy is made by triggers, so writes that bypass Palmera are tracked too.
+//!
+//! Both are read from the `_history_actor` table, which writers fill with [`set_actor`]
+//! in the transaction making the change and clear before committing.
+//! [`PalmeraClient::with_history`](crate::embedded::PalmeraClient::with_history) does
+//! so for each [`Session`](crate::embedded::Session); other writes are recorded without
+//! an actor.
+//!
+//! [`History::list`] returns the versions of a record, newest first, and
+//! [`History::restore`] writes one of them back, recreating the record if it was
+//! deleted. A restore is a change like any other and is itself recorded, so it can be
+//! undone. The `/admin/history` routes of [`crate::admin`] expose both.
+//!
+//! The triggers copy the columns the table had when history was enabled. Altering the
+//! table through [`crate::ddl`] re-creates them for its new columns, and renaming it
+//! renames its history table along; after altering it otherwise, enable history again
+//! to pick up added columns. Blob values are kept as hex text. Records are identified by
+//! their [`PRIMARY_KEY`] column.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # async fn run(db: sqlx::Pool<sqlx::Sqlite>) -> Result<(), Box<dyn std::error::Error>> {
+//! use std::sync::Arc;
+//!
+//! use palmera_database::{embedded::PalmeraClient, history::History};
+//! use serde_json::json;
+//!
+//! let history = Arc::new(History::open(db.clone()).await?);
+//! history.enable("notes").await?;
+//!
+//! let client = PalmeraClient::open(db).await?.with_history(history.clone());
+//! client.as_user("ann").update("notes", 1, json!({ "title": "Groceries" })).await?;
+//!
+//! let versions = history.list("notes", "1").await?;
+//! history.restore("notes", "1", versions[0].version, Some("ann")).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use chrono::{DateTime, Utc};
+use sea_query::SqliteQueryBuilder;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite, SqliteConnection, types::Json};
+use utoipa::ToSchema;
+
+use crate::{
+    ddl,
+    error::StoreError,
+    manifest::affinity,
+    postgres::batch::PRIMARY_KEY,
+    sqlite::helpers::{create_history_actor_table, quote_ident, table_columns},
+};
+
+/// Suffix of the tables holding the history of a table.
+pub const HISTORY_SUFFIX: &str = "__history";
+
+/// Change that produced a [`HistoryEntry`].
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HistoryAction {
+    Update,
+    Delete,
+}
+
+/// A previous version of a record.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct HistoryEntry {
+    /// Identifies the version within its table's history. Later versions have larger
+    /// numbers.
+    pub version: i64,
+    pub action: HistoryAction,
+    /// The row before the change, as a JSON object keyed by column.
+    #[schema(value_type = Object)]
+    pub record: serde_json::Value,
+    /// Who made the change, if known.
+    pub actor: Option<String>,
+    /// User the change was made for. It differs from `actor` when someone acted on the
+    /// user's behalf.
+    pub subject: Option<String>,
+    pub changed_at: DateTime<Utc>,
+}
+
+/// A record id, typed as its table stores it.
+enum RecordId {
+    Integer(i64),
+    Real(f64),
+    Text(String),
+}
+
+type EntryRow = (
+    i64,
+    String,
+    Json<serde_json::Value>,
+    Option<String>,
+    Option<String>,
+    i64,
+);
+
+/// History tracking of the tables of a SQLite database.
+#[derive(Debug, Clone)]
+pub struct History {
+    db: Pool<Sqlite>,
+}
+
+impl History {
+    /// Opens the store, creating the `_history_actor` table if it does not exist.
+    pub async fn open(db: Pool<Sqlite>) -> Result<Self, sqlx::Error> {
+        sqlx::query(&create_history_actor_table().to_string(SqliteQueryBuilder))
+            .execute(&db)
+            .await?;
+
+        Ok(Self { db })
+    }
+
+    /// Returns the tables whose history is tracked, ordered by name.
+    pub async fn tables(&self) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query_scalar(
+            "SELECT tbl_name FROM sqlite_master WHERE type = 'trigger' AND name = tbl_name || ? \
+             ORDER BY tbl_name",
+        )
+        .bind(format!("{HISTORY_SUFFIX}_update"))
+        .fetch_all(&self.db)
+        .await
+    }
+
+    /// Starts tracking the history of `table`, creating its history table if needed.
+    /// Enabling it again picks up columns added since.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`StoreError::Invalid`] if the table doesn't exist, is internal or has
+    /// no [`PRIMARY_KEY`] column.
+    pub async fn enable(&self, table: &str) -> Result<(), StoreError> {
+        let columns = table_columns(&self.db, table).await?;
+        if table.starts_with('_') || table.ends_with(HISTORY_SUFFIX) || columns.is_empty() {
+            return Err(invalid(format!("table {table:?} does not exist")));
+        }
+        if !columns.iter().any(|column| column == PRIMARY_KEY) {
+            return Err(invalid(format!(
+                "table {table:?} has no {PRIMARY_KEY:?} column"
+            )));
+        }
+
+        let history = history_table(table);
+        let mut tx = self.db.begin().await?;
+        sqlx::query(&format!(
+            "CREATE TABLE IF NOT EXISTS {history} (\
+             history_id INTEGER PRIMARY KEY AUTOINCREMENT, record_id, \
+             action TEXT NOT NULL, record TEXT NOT NULL, actor TEXT, subject TEXT, \
+             changed_at INTEGER NOT NULL)"
+        ))
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(&format!(
+            "CREATE INDEX IF NOT EXISTS {} ON {history} (record_id)",
+            quote_ident(&format!("{table}{HISTORY_SUFFIX}_record_id"))
+        ))
+        .execute(&mut *tx)
+        .await?;
+        for statement in drop_triggers(table)
+            .into_iter()
+            .chain(create_triggers(table, &columns))
+        {
+            sqlx::query(&statement).execute(&mut *tx).await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Stops tracking the history of `table`, returning whether it was tracked. The
+    /// recorded history is kept.
+    pub async fn disable(&self, table: &str) -> Result<bool, sqlx::Error> {
+        let tracked = self.tables().await?.iter().any(|name| name == table);
+        let mut tx = self.db.begin().await?;
+        for statement in drop_triggers(table) {
+            sqlx::query(&statement).execute(&mut *tx).await?;
+        }
+        tx.commit().await?;
+
+        Ok(tracked)
+    }
+
+    /// Returns the previous versions of the record of `table` identified by `id`,
+    /// newest first.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`StoreError::Invalid`] if the table has no history.
+    pub async fn list(&self, table: &str, id: &str) -> Result<Vec<HistoryEntry>, StoreError> {
+        self.check_table(table).await?;
+        let Some(id) = self.record_id(table, id).await? else {
+            return Ok(Vec::new());
+        };
+        let sql = format!(
+            "SELECT history_id, action, record, actor, subject, changed_at FROM {} \
+             WHERE record_id = ? ORDER BY history_id DESC",
+            history_table(table)
+        );
+        let query = sqlx::query_as(&sql);
+        let query = match id {
+            RecordId::Integer(id) => query.bind(id),
+            RecordId::Real(id) => query.bind(id),
+            RecordId::Text(id) => query.bind(id),
+        };
+        let rows: Vec<EntryRow> = query.fetch_all(&self.db).await?;
+
+        Ok(rows.into_iter().map(into_entry).collect())
+    }
+
+    /// Writes `version` of the record of `table` identified by `id` back, on behalf of
+    /// `actor`, recreating the record if it was deleted. Returns the restored version, or
+    /// `None` if the record has no such version.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`StoreError::Invalid`] if the table has no history.
+    pub async fn restore(
+        &self,
+        table: &str,
+        id: &str,
+        version: i64,
+        actor: Option<&str>,
+    ) -> Result<Option<serde_json::Value>, StoreError> {
+        self.check_table(table).await?;
+        let Some(record_id) = self.record_id(table, id).await? else {
+            return Ok(None);
+        };
+        let sql = format!(
+            "SELECT record FROM {} WHERE history_id = ? AND record_id = ?",
+            history_table(table)
+        );
+        let query = sqlx::query_as(&sql).bind(version);
+        let query = match record_id {
+            RecordId::Integer(id) => query.bind(id),
+            RecordId::Real(id) => query.bind(id),
+            RecordId::Text(id) => query.bind(id),
+        };
+        let record: Option<(Json<serde_json::Map<String, serde_json::Value>>,)> =
+            query.fetch_optional(&self.db).await?;
+        let Some((Json(record),)) = record else {
+            return Ok(None);
+        };
+
+        // Columns dropped since the version was recorded are left out.
+        let columns: Vec<(String, String)> =
+            sqlx::query_as("SELECT name, type FROM pragma_table_info(?) ORDER BY cid")
+                .bind(table)
+                .fetch_all(&self.db)
+                .await?;
+        let columns: Vec<_> = columns
+            .into_iter()
+            .filter(|(column, _)| record.contains_key(column))
+            .collect();
+        let values = columns.iter().map(|(column, declared)| {
+            let value = format!(
+                "json_extract(?1, '$.\"{}\"')",
+                column.replace('\'', "''").replace('"', "\\\"")
+            );
+            if declared.to_ascii_uppercase().contains("BLOB") {
+                format!("unhex({value})")
+            } else {
+                value
+            }
+        });
+        let names: Vec<_> = columns
+            .iter()
+            .map(|(column, _)| quote_ident(column))
+            .collect();
+        let updates: Vec<_> = names
+            .iter()
+            .map(|name| format!("{name} = excluded.{name}"))
+            .collect();
+
+        let mut tx = self.db.begin().await?;
+        set_actor(&mut tx, actor, actor).await?;
+        sqlx::query(&format!(
+            "INSERT INTO {} ({}) VALUES ({}) ON CONFLICT ({}) DO UPDATE SET {}",
+            quote_ident(table),
+            names.join(", "),
+            values.collect::<Vec<_>>().join(", "),
+            quote_ident(PRIMARY_KEY),
+            updates.join(", "),
+        ))
+        .bind(Json(&record))
+        .execute(&mut *tx)
+        .await?;
+        set_actor(&mut tx, None, None).await?;
+        tx.commit().await?;
+
+        Ok(Some(serde_json::Value::Object(record)))
+    }
+
+    /// Converts `id` to the type the table's [`PRIMARY_KEY`] stores it in, so it can be
+    /// compared with `record_id` through its index. Returns `None` for ids no record of
+    /// the table can have.
+    async fn record_id(&self, table: &str, id: &str) -> Result<Option<RecordId>, sqlx::Error> {
+        let declared: Option<String> =
+            sqlx::query_scalar("SELECT type FROM pragma_table_info(?) WHERE name = ?")
+                .bind(table)
+                .bind(PRIMARY_KEY)
+                .fetch_optional(&self.db)
+                .await?;
+        let integer = id.parse().ok().map(RecordId::Integer);
+        let real = || id.parse().ok().map(RecordId::Real);
+        Ok(match declared.as_deref().map(affinity) {
+            Some("INTEGER") => integer,
+            Some("REAL") => real(),
+            Some("NUMERIC") => integer
+                .or_else(real)
+                .or(Some(RecordId::Text(id.to_string()))),
+            // Text and untyped keys are stored as given.
+            _ => Some(RecordId::Text(id.to_string())),
+        })
+    }
+
+    async fn check_table(&self, table: &str) -> Result<(), StoreError> {
+        if table_columns(&self.db, table).await?.is_empty()
+            || !ddl::table_exists(&self.db, &format!("{table}{HISTORY_SUFFIX}")).await?
+        {
+            return Err(invalid(format!("table {table:?} has no history")));
+        }
+        Ok(())
+    }
+}
+
+/// Sets the actor and the subject recorded with the changes `conn` makes next, or
+/// clears them with `None`. Call it inside the transaction making the changes, and clear
+/// them before committing, so that later writes aren't attributed to them.
+pub async fn set_actor(
+    conn: &mut SqliteConnection,
+    actor: Option<&str>,
+    subject: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    match (actor, subject) {
+        (None, None) => {
+            sqlx::query("DELETE FROM _history_actor")
+                .execute(conn)
+                .await?
+        }
+        _ => {
+            sqlx::query(
+                "INSERT INTO _history_actor (id, actor, subject) VALUES (1, ?, ?) \
+                 ON CONFLICT (id) DO UPDATE SET actor = excluded.actor, \
+                 subject = excluded.subject",
+            )
+            .bind(actor)
+            .bind(subject)
+            .execute(conn)
+            .await?
+        }
+    };
+    Ok(())
+}
+
+/// Returns whether the history of `table` is tracked.
+pub(crate) async fn is_tracked(
+    conn: &mut SqliteConnection,
+    table: &str,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'trigger' AND name = ?)",
+    )
+    .bind(format!("{table}{HISTORY_SUFFIX}_update"))
+    .fetch_one(conn)
+    .await
+}
+
+/// Statements dropping the history triggers of `table`.
+pub(crate) fn drop_triggers(table: &str) -> Vec<String> {
+    ["update", "delete"]
+        .map(|action| format!("DROP TRIGGER IF EXISTS {}", trigger_name(table, action)))
+        .to_vec()
+}
+
+/// Statements creating the triggers copying `columns` of `table` into its history table
+/// on updates and deletes.
+pub(crate) fn create_triggers(table: &str, columns: &[String]) -> Vec<String> {
+    let record = format!(
+        "json_object({})",
+        columns
+            .iter()
+            .map(|column| {
+                let value = format!("OLD.{}", quote_ident(column));
+                format!(
+                    "'{}', iif(typeof({value}) = 'blob', hex({value}), {value})",
+                    column.replace('\'', "''")
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    ["update", "delete"]
+        .map(|action| {
+            format!(
+                "CREATE TRIGGER {} AFTER {} ON {} BEGIN \
+                 INSERT INTO {} (record_id, action, record, actor, subject, changed_at) \
+                 VALUES (OLD.{}, '{action}', {record}, \
+                 (SELECT actor FROM _history_actor WHERE id = 1), \
+                 (SELECT subject FROM _history_actor WHERE id = 1), \
+                 CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER)); END",
+                trigger_name(table, action),
+                action.to_uppercase(),
+                quote_ident(table),
+                history_table(table),
+                quote_ident(PRIMARY_KEY),
+            )
+        })
+        .to_vec()
+}
+
+/// Statement renaming the history table of `from` after the table was renamed to `to`.
+pub(crate) fn rename_history(from: &str, to: &str) -> String {
+    format!(
+        "ALTER TABLE {} RENAME TO {}",
+        history_table(from),
+        history_table(to)
+    )
+}
+
+fn history_table(table: &str) -> String {
+    quote_ident(&format!("{table}{HISTORY_SUFFIX}"))
+}
+
+fn trigger_name(table: &str, action: &str) -> String {
+    quote_ident(&format!("{table}{HISTORY_SUFFIX}_{action}"))
+}
+
+fn into_entry(
+    (version, action, Json(record), actor, subject, changed_at): EntryRow,
+) -> HistoryEntry {
+    HistoryEntry {
+        version,
+        action: match action.as_str() {
+            "delete" => HistoryAction::Delete,
+            _ => HistoryAction::Update,
+        },
+        record,
+        actor,
+        subject,
+        changed_at: DateTime::from_timestamp_millis(changed_at).unwrap_or_default(),
+    }
+}
+
+fn invalid(message: String) -> StoreError {
+    StoreError::Invalid(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embedded::PalmeraClient;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    async fn setup(db: &Pool<Sqlite>) -> Result<Arc<History>, StoreError> {
+        sqlx::raw_sql(
+            "CREATE TABLE notes (id INTEGER PRIMARY KEY, title TEXT NOT NULL, \
+             done BOOLEAN NOT NULL DEFAULT 0, attachment BLOB); \
+             INSERT INTO notes (id, title, attachment) VALUES (1, 'draft', NULL), (2, 'other', x'00ff');",
+        )
+        .execute(db)
+        .await?;
+        let history = Arc::new(History::open(db.clone()).await?);
+        history.enable("notes").await?;
+        Ok(history)
+    }
+
+    #[sqlx::test]
+    async fn test_history(db: Pool<Sqlite>) -> Result<(), Box<dyn std::error::Error>> {
+        let history = setup(&db).await?;
+        assert_eq!(history.tables().await?, ["notes"]);
+        assert!(history.enable("missing").await.is_err());
+        assert!(history.list("other", "1").await.is_err());
+
+        let client = PalmeraClient::open(db.clone())
+            .await?
+            .with_history(history.clone());
+        let ann = client.as_user("ann");
+        ann.update("notes", 1, json!({ "title": "final" })).await?;
+        // Writes outside Palmera are recorded without an actor.
+        sqlx::query("UPDATE notes SET done = 1 WHERE id = 1")
+            .execute(&db)
+            .await?;
+        // An admin impersonating ann is recorded as the actor, with ann as the subject.
+        ann.with_actor("admin").delete("notes", 1).await?;
+
+        let versions = history.list("notes", "1").await?;
+        assert_eq!(versions.len(), 3);
+        assert_eq!(versions[0].action, HistoryAction::Delete);
+        assert_eq!(versions[0].actor.as_deref(), Some("admin"));
+        assert_eq!(versions[0].subject.as_deref(), Some("ann"));
+        assert_eq!(versions[0].record["done"], 1);
+        assert_eq!(versions[1].actor, None);
+        assert_eq!(versions[2].actor.as_deref(), Some("ann"));
+        assert_eq!(versions[2].subject.as_deref(), Some("ann"));
+        assert_eq!(
+            versions[2].record,
+            json!({ "id": 1, "title": "draft", "done": 0, "attachment": null })
+        );
+        assert!(history.list("notes", "2").await?.is_empty());
+
+        let restored = history
+            .restore("notes", "1", versions[2].version, Some("bob"))
+            .await?
+            .unwrap();
+        assert_eq!(restored["title"], "draft");
+        assert!(
+            history
+                .restore("notes", "2", versions[2].version, None)
+                .await?
+                .is_none()
+        );
+
+        // Restoring over the recreated record is recorded too.
+        history
+            .restore("notes", "1", versions[1].version, Some("bob"))
+            .await?;
+        let versions = history.list("notes", "1").await?;
+        assert_eq!(versions.len(), 4);
+        assert_eq!(versions[0].actor.as_deref(), Some("bob"));
+        assert_eq!(versions[0].subject.as_deref(), Some("bob"));
+        assert_eq!(versions[0].record["title"], "draft");
+        let actors: i64 = sqlx::query_scalar("SELECT count(*) FROM _history_actor")
+            .fetch_one(&db)
+            .await?;
+        assert_eq!(actors, 0);
+
+        // Blobs are kept as hex.
+        sqlx::query("UPDATE notes SET attachment = NULL WHERE id = 2")
+            .execute(&db)
+            .await?;
+        let versions = history.list("notes", "2").await?;
+        assert_eq!(versions[0].record["attachment"], "00FF");
+        history
+            .restore("notes", "2", versions[0].version, None)
+            .await?;
+        let attachment: Vec<u8> = sqlx::query_scalar("SELECT attachment FROM notes WHERE id = 2")
+            .fetch_one(&db)
+            .await?;
+        assert_eq!(attachment, [0x00, 0xff]);
+
+        // Ids are bound as the table stores them, and looked up through the index.
+        assert!(matches!(
+            history.record_id("notes", "2").await?,
+            Some(RecordId::Integer(2))
+        ));
+        assert!(history.record_id("notes", "two").await?.is_none());
+        assert!(history.list("notes", "two").await?.is_empty());
+        let plan: Vec<(i64, i64, i64, String)> = sqlx::query_as(
+            "EXPLAIN QUERY PLAN SELECT record FROM notes__history WHERE record_id = ?",
+        )
+        .bind(2)
+        .fetch_all(&db)
+        .await?;
+        assert!(plan[0].3.contains("USING INDEX notes__history_record_id"));
+
+        assert!(history.disable("notes").await?);
+        sqlx::query("UPDATE notes SET done = 0")
+            .execute(&db)
+            .await?;
+        assert_eq!(history.list("notes", "1").await?.len(), 4);
+        assert!(history.tables().await?.is_empty());
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_alter_tracked_table(db: Pool<Sqlite>) -> Result<(), Box<dyn std::error::Error>> {
+        let history = setup(&db).await?;
+        let change = ddl::AlterTable {
+            operations: vec![
+                ddl::AlterOperation::AddColumn {
+                    column: serde_json::from_value(json!({ "name": "tags", "data_type": "text" }))?,
+                },
+                ddl::AlterOperation::DropColumn {
+                    name: "done".to_string(),
+                },
+                ddl::AlterOperation::RenameTable {
+                    to: "memos".to_string(),
+                },
+            ],
+        };
+        let migration = ddl::plan_alter(&db, "notes", &change).await?;
+        ddl::apply(&db, &migration).await?;
+        assert_eq!(history.tables().await?, ["memos"]);
+
+        sqlx::query("UPDATE memos SET tags = 'home' WHERE id = 1")
+            .execute(&db)
+            .await?;
+        sqlx::query("UPDATE memos SET title = 'kept' WHERE id = 1")
+            .execute(&db)
+            .await?;
+        let versions = history.list("memos", "1").await?;
+        assert_eq!(versions.len(), 2);
+        assert_eq!(
+            versions[0].record,
+            json!({ "id": 1, "title": "draft", "attachment": null, "tags": "home" })
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,280 @@
+//! # Bulk row import
+//!
+//! Inserting thousands of rows one `INSERT` at a time is dominated by round trips.
+//! This module provides the fast paths used by imports and seeding:
+//!
+//! - [`copy_postgres`] streams rows to Postgres with `COPY ... FROM STDIN` in CSV format.
+//! - [`insert_sqlite`] writes multi-row `INSERT`s in batches inside a single transaction.
+//!
+//! Rows are given as JSON values aligned with the column list. Both functions report
+//! progress after every chunk through an [`ImportProgress`] callback and are
+//! all-or-nothing: a failing row aborts the whole import.
+
+use sea_query::{Alias, Query, SqliteQueryBuilder, Value};
+use serde::Serialize;
+use sqlx::{Pool, Postgres, Sqlite};
+
+use crate::{
+    error::StoreError,
+    postgres::helpers::{quote_ident, quote_qualified},
+};
+
+/// Number of rows sent per chunk when no explicit batch size is given.
+pub const DEFAULT_BATCH_SIZE: usize = 1000;
+
+/// Progress of a running import.
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+pub struct ImportProgress {
+    /// Rows written so far.
+    pub processed: u64,
+    /// Total rows in the import.
+    pub total: u64,
+}
+
+/// Streams `rows` into `table` using `COPY FROM STDIN`.
+///
+/// `table` may be schema qualified (`auth.users`). Returns the number of rows copied.
+///
+/// # Errors
+///
+/// Returns an error if a row's length does not match `columns` or Postgres rejects the
+/// data; in that case no rows are imported.
+pub async fn copy_postgres<F>(
+    db: &Pool<Postgres>,
+    table: &str,
+    columns: &[&str],
+    rows: &[Vec<serde_json::Value>],
+    batch_size: usize,
+    mut on_progress: F,
+) -> Result<u64, StoreError>
+where
+    F: FnMut(ImportProgress),
+{
+    check_row_lengths(columns, rows)?;
+
+    let statement = format!(
+        "COPY {} ({}) FROM STDIN (FORMAT csv)",
+        quote_qualified(table),
+        columns
+            .iter()
+            .map(|column| quote_ident(column))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    let mut conn = db.acquire().await?;
+    let mut copy = conn.copy_in_raw(&statement).await?;
+
+    let total = rows.len() as u64;
+    let mut processed = 0;
+
+    for chunk in rows.chunks(batch_size.max(1)) {
+        let mut buf = String::new();
+        for row in chunk {
+            buf.push_str(&csv_row(row));
+        }
+
+        if let Err(err) = copy.send(buf.into_bytes()).await {
+            copy.abort(err.to_string()).await?;
+            return Err(err.into());
+        }
+
+        processed += chunk.len() as u64;
+        on_progress(ImportProgress { processed, total });
+    }
+
+    Ok(copy.finish().await?)
+}
+
+/// Inserts `rows` into `table` with batched multi-row `INSERT`s in one transaction.
+///
+/// Returns the number of rows inserted.
+///
+/// # Errors
+///
+/// Returns an error if a row's length does not match `columns` or an insert fails; the
+/// transaction is rolled back and no rows are imported.
+pub async fn insert_sqlite<F>(
+    db: &Pool<Sqlite>,
+    table: &str,
+    columns: &[&str],
+    rows: &[Vec<serde_json::Value>],
+    batch_size: usize,
+    mut on_progress: F,
+) -> Result<u64, StoreError>
+where
+    F: FnMut(ImportProgress),
+{
+    check_row_lengths(columns, rows)?;
+
+    let mut tx = db.begin().await?;
+
+    let total = rows.len() as u64;
+    let mut processed = 0;
+
+    for chunk in rows.chunks(batch_size.max(1)) {
+        let mut insert = Query::insert();
+        insert
+            .into_table(Alias::new(table))
+            .columns(columns.iter().map(|column| Alias::new(*column)));
+
+        for row in chunk {
+            insert
+                .values(row.iter().map(|value| json_to_value(value).into()))
+                .map_err(|err| StoreError::Invalid(err.to_string()))?;
+        }
+
+        let sql = insert.to_string(SqliteQueryBuilder);
+        sqlx::query(&sql).execute(&mut *tx).await?;
+
+        processed += chunk.len() as u64;
+        on_progress(ImportProgress { processed, total });
+    }
+
+    tx.commit().await?;
+
+    Ok(processed)
+}
+
+fn check_row_lengths(columns: &[&str], rows: &[Vec<serde_json::Value>]) -> Result<(), StoreError> {
+    match rows.iter().position(|row| row.len() != columns.len()) {
+        Some(index) => Err(StoreError::Invalid(format!(
+            "row {index} has {} values, expected {}",
+            rows[index].len(),
+            columns.len()
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Encodes a row as a CSV line where SQL `NULL` is an unquoted empty field.
+fn csv_row(row: &[serde_json::Value]) -> String {
+    let fields: Vec<String> = row
+        .iter()
+        .map(|value| match value {
+            serde_json::Value::Null => String::new(),
+            serde_json::Value::String(s) => format!("\"{}\"", s.replace('"', "\"\"")),
+            serde_json::Value::Bool(_) | serde_json::Value::Number(_) => value.to_string(),
+            other => format!("\"{}\"", other.to_string().replace('"', "\"\"")),
+        })
+        .collect();
+
+    format!("{}\n", fields.join(","))
+}
+
+fn json_to_value(value: &serde_json::Value) -> Value {
+    match value {
+        serde_json::Value::Null => Value::String(None),
+        serde_json::Value::Bool(b) => Value::Bool(Some(*b)),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => Value::BigInt(Some(i)),
+            None => Value::Double(n.as_f64()),
+        },
+        serde_json::Value::String(s) => Value::String(Some(Box::new(s.clone()))),
+        other => Value::String(Some(Box::new(other.to_string()))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn sample_rows(count: usize) -> Vec<Vec<serde_json::Value>> {
+        (0..count)
+            .map(|i| vec![json!(i), json!(format!("item \"{i}\"")), json!(null)])
+            .collect()
+    }
+
+    #[test]
+    fn test_csv_row_escaping() {
+        let row = vec![json!(1), json!("a,\"b\""), json!(null), json!({"k": "v"})];
+        assert_eq!(
+            csv_row(&row),
+            "1,\"a,\"\"b\"\"\",,\"{\"\"k\"\":\"\"v\"\"}\"\n"
+        );
+    }
+
+    #[sqlx::test]
+    async fn test_copy_postgres(db: Pool<Postgres>) -> Result<(), StoreError> {
+        sqlx::query("CREATE TABLE items (id bigint, name text, note text)")
+            .execute(&db)
+            .await?;
+
+        let mut progress = vec![];
+        let copied = copy_postgres(
+            &db,
+            "items",
+            &["id", "name", "note"],
+            &sample_rows(25),
+            10,
+            |p| progress.push(p.processed),
+        )
+        .await?;
+
+        assert_eq!(copied, 25);
+        assert_eq!(progress, vec![10, 20, 25]);
+
+        let (name, note): (String, Option<String>) =
+            sqlx::query_as("SELECT name, note FROM items WHERE id = 3")
+                .fetch_one(&db)
+                .await?;
+        assert_eq!(name, "item \"3\"");
+        assert!(note.is_none());
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_copy_postgres_rejects_ragged_rows(db: Pool<Postgres>) -> Result<(), StoreError> {
+        let rows = vec![vec![json!(1)]];
+        let result = copy_postgres(&db, "items", &["id", "name"], &rows, 10, |_| {}).await;
+        assert!(result.is_err());
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_insert_sqlite(db: Pool<Sqlite>) -> Result<(), StoreError> {
+        sqlx::query("CREATE TABLE items (id integer, name text, note text)")
+            .execute(&db)
+            .await?;
+
+        let mut batches = 0;
+        let inserted = insert_sqlite(
+            &db,
+            "items",
+            &["id", "name", "note"],
+            &sample_rows(25),
+            10,
+            |_| batches += 1,
+        )
+        .await?;
+
+        assert_eq!(inserted, 25);
+        assert_eq!(batches, 3);
+
+        let count: i64 = sqlx::query_scalar("SELECT count(*) FROM items")
+            .fetch_one(&db)
+            .await?;
+        assert_eq!(count, 25);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_insert_sqlite_is_all_or_nothing(db: Pool<Sqlite>) -> Result<(), StoreError> {
+        sqlx::query("CREATE TABLE items (id integer not null, name text, note text)")
+            .execute(&db)
+            .await?;
+
+        let mut rows = sample_rows(15);
+        rows[12][0] = json!(null);
+
+        let result = insert_sqlite(&db, "items", &["id", "name", "note"], &rows, 10, |_| {}).await;
+        assert!(result.is_err());
+
+        let count: i64 = sqlx::query_scalar("SELECT count(*) FROM items")
+            .fetch_one(&db)
+            .await?;
+        assert_eq!(count, 0);
+        Ok(())
+    }
+}
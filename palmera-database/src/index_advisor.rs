@@ -0,0 +1,597 @@
+//! # Index advisor
+//!
+//! Suggests indexes for the columns the app's queries keep filtering and sorting on.
+//! Two sources feed it:
+//!
+//! - [`QueryUsage`] counts, per table and column, how often list requests filtered and
+//!   sorted on it. It lives in the SQLite `_query_usage` table, so counts survive
+//!   restarts; [`SavedQueries`](crate::saved_queries::SavedQueries) records its runs in
+//!   it when given one with `with_usage`.
+//! - The [`SlowQueryLog`](crate::instrument::SlowQueryLog): the table, `WHERE` and
+//!   `ORDER BY` columns of each slow query count as slow uses of those columns.
+//!
+//! [`QueryUsage::suggestions`] proposes a single column index for each column used at
+//! least a given number of times, leaving out primary keys and columns already leading
+//! an index. The suggestions are served by `GET /admin/index-suggestions` in
+//! [`crate::admin`], and printed as a SQL script by the `palmera-database` command line
+//! tool:
+//!
+//! ```text
+//! palmera-database index-suggestions DATABASE_URL [--min-uses N]
+//! ```
+//!
+//! The command has no access to the slow query log of a running app, so it only reports
+//! recorded usage.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use sea_query::SqliteQueryBuilder;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite};
+use utoipa::ToSchema;
+
+use crate::{
+    instrument::SlowQuery,
+    sqlite::helpers::{create_query_usage_table, quote_ident},
+};
+
+/// Uses of a column before an index is suggested, unless told otherwise.
+pub const DEFAULT_MIN_USES: u64 = 10;
+
+/// How often list requests filtered and sorted on a column.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+pub struct ColumnUsage {
+    pub table: String,
+    pub column: String,
+    pub filtered: u64,
+    pub sorted: u64,
+}
+
+/// A missing index worth creating.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+pub struct IndexSuggestion {
+    pub table: String,
+    pub column: String,
+    /// Times list requests filtered on the column.
+    pub filtered: u64,
+    /// Times list requests sorted on the column.
+    pub sorted: u64,
+    /// Slow queries filtering or sorting on the column.
+    pub slow_queries: u64,
+    /// Statement creating the index.
+    pub sql: String,
+}
+
+impl IndexSuggestion {
+    fn uses(&self) -> u64 {
+        self.filtered + self.sorted + self.slow_queries
+    }
+}
+
+/// Filter and sort usage persisted in SQLite.
+#[derive(Debug, Clone)]
+pub struct QueryUsage {
+    db: Pool<Sqlite>,
+}
+
+impl QueryUsage {
+    /// Opens the store, creating the `_query_usage` table if it does not exist.
+    pub async fn open(db: Pool<Sqlite>) -> Result<Self, sqlx::Error> {
+        sqlx::query(&create_query_usage_table().to_string(SqliteQueryBuilder))
+            .execute(&db)
+            .await?;
+
+        Ok(Self { db })
+    }
+
+    /// Counts a list request on `table` filtering on `filtered` and sorting on `sorted`.
+    /// Sort columns may carry the `-` prefix of descending order.
+    pub async fn record<'a>(
+        &self,
+        table: &str,
+        filtered: impl IntoIterator<Item = &'a str>,
+        sorted: impl IntoIterator<Item = &'a str>,
+    ) -> Result<(), sqlx::Error> {
+        let mut uses: BTreeMap<&str, (i64, i64)> = BTreeMap::new();
+        for column in filtered {
+            uses.entry(column).or_default().0 = 1;
+        }
+        for column in sorted {
+            let column = column.strip_prefix('-').unwrap_or(column);
+            uses.entry(column).or_default().1 = 1;
+        }
+        if uses.is_empty() {
+            return Ok(());
+        }
+
+        let mut tx = self.db.begin().await?;
+        for (column, (filtered, sorted)) in uses {
+            sqlx::query(
+                "INSERT INTO _query_usage (table_name, column_name, filtered, sorted) \
+                 VALUES (?, ?, ?, ?) \
+                 ON CONFLICT (table_name, column_name) DO UPDATE SET \
+                     filtered = filtered + excluded.filtered, sorted = sorted + excluded.sorted",
+            )
+            .bind(table)
+            .bind(column)
+            .bind(filtered)
+            .bind(sorted)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await
+    }
+
+    /// Returns the recorded usage, ordered by table and column.
+    pub async fn usage(&self) -> Result<Vec<ColumnUsage>, sqlx::Error> {
+        let rows: Vec<(String, String, i64, i64)> = sqlx::query_as(
+            "SELECT table_name, column_name, filtered, sorted FROM _query_usage \
+             ORDER BY table_name, column_name",
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(table, column, filtered, sorted)| ColumnUsage {
+                table,
+                column,
+                filtered: filtered as u64,
+                sorted: sorted as u64,
+            })
+            .collect())
+    }
+
+    /// Forgets the recorded usage, e.g. after the suggested indexes were created.
+    pub async fn clear(&self) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM _query_usage")
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    /// Suggests an index for each column filtered, sorted or slowly queried on at least
+    /// `min_uses` times, most used first.
+    pub async fn suggestions(
+        &self,
+        slow_queries: &[SlowQuery],
+        min_uses: u64,
+    ) -> Result<Vec<IndexSuggestion>, sqlx::Error> {
+        let mut counts: BTreeMap<(String, String), (u64, u64, u64)> = BTreeMap::new();
+        for usage in self.usage().await? {
+            let count = counts.entry((usage.table, usage.column)).or_default();
+            count.0 += usage.filtered;
+            count.1 += usage.sorted;
+        }
+        for query in slow_queries {
+            let Some(shape) = QueryShape::parse(&query.sql) else {
+                continue;
+            };
+            let columns: BTreeSet<_> = shape.filtered.iter().chain(&shape.sorted).collect();
+            for column in columns {
+                counts
+                    .entry((shape.table.clone(), column.clone()))
+                    .or_default()
+                    .2 += 1;
+            }
+        }
+
+        let mut suggestions = Vec::new();
+        let mut tables: BTreeMap<String, Option<TableIndexes>> = BTreeMap::new();
+        for ((table, column), (filtered, sorted, slow_queries)) in counts {
+            if filtered + sorted + slow_queries < min_uses {
+                continue;
+            }
+            if !tables.contains_key(&table) {
+                let indexes = TableIndexes::load(&self.db, &table).await?;
+                tables.insert(table.clone(), indexes);
+            }
+            // Tables that no longer exist are skipped, as are covered columns.
+            let Some(Some(indexes)) = tables.get(&table) else {
+                continue;
+            };
+            if !indexes.needs_index(&column) {
+                continue;
+            }
+
+            suggestions.push(IndexSuggestion {
+                sql: format!(
+                    "CREATE INDEX {} ON {} ({})",
+                    quote_ident(&format!("{table}_{column}_idx")),
+                    quote_ident(&table),
+                    quote_ident(&column)
+                ),
+                table,
+                column,
+                filtered,
+                sorted,
+                slow_queries,
+            });
+        }
+        suggestions.sort_by_key(|suggestion| std::cmp::Reverse(suggestion.uses()));
+        Ok(suggestions)
+    }
+}
+
+/// Renders `suggestions` as a SQL script, each statement commented with its uses.
+pub fn report(suggestions: &[IndexSuggestion]) -> String {
+    if suggestions.is_empty() {
+        return "-- No index suggestions.\n".to_string();
+    }
+    suggestions
+        .iter()
+        .map(|suggestion| {
+            format!(
+                "-- {}.{}: filtered {}, sorted {}, slow queries {}\n{};\n",
+                suggestion.table,
+                suggestion.column,
+                suggestion.filtered,
+                suggestion.sorted,
+                suggestion.slow_queries,
+                suggestion.sql
+            )
+        })
+        .collect()
+}
+
+/// Columns of a table and those leading one of its indexes.
+struct TableIndexes {
+    columns: Vec<String>,
+    primary_key: Vec<String>,
+    indexed: Vec<String>,
+}
+
+impl TableIndexes {
+    /// Loads the indexes of `table`, `None` if it doesn't exist.
+    async fn load(db: &Pool<Sqlite>, table: &str) -> Result<Option<Self>, sqlx::Error> {
+        let columns: Vec<(String, i64)> =
+            sqlx::query_as("SELECT name, pk FROM pragma_table_info(?) ORDER BY cid")
+                .bind(table)
+                .fetch_all(db)
+                .await?;
+        if columns.is_empty() {
+            return Ok(None);
+        }
+        let indexed = sqlx::query_scalar(
+            "SELECT info.name FROM pragma_index_list(?1) AS list, \
+                 pragma_index_info(list.name) AS info \
+             WHERE info.seqno = 0",
+        )
+        .bind(table)
+        .fetch_all(db)
+        .await?;
+
+        Ok(Some(Self {
+            primary_key: columns
+                .iter()
+                .filter(|(_, pk)| *pk == 1)
+                .map(|(name, _)| name.clone())
+                .collect(),
+            columns: columns.into_iter().map(|(name, _)| name).collect(),
+            indexed,
+        }))
+    }
+
+    fn needs_index(&self, column: &str) -> bool {
+        let is = |name: &String| name == column;
+        self.columns.iter().any(is)
+            && !self.primary_key.iter().any(is)
+            && !self.indexed.iter().any(is)
+    }
+}
+
+/// The table of a query and the columns its `WHERE` and `ORDER BY` clauses use.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct QueryShape {
+    table: String,
+    filtered: Vec<String>,
+    sorted: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    /// A name, and whether it was quoted and so can't be a keyword.
+    Name(String, bool),
+    Symbol(String),
+    Literal,
+}
+
+impl Token {
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self, Self::Name(name, false) if name.eq_ignore_ascii_case(keyword))
+    }
+}
+
+/// Words ending a clause or that can't be column names.
+const KEYWORDS: &[&str] = &[
+    "and",
+    "or",
+    "not",
+    "is",
+    "null",
+    "in",
+    "like",
+    "glob",
+    "between",
+    "true",
+    "false",
+    "asc",
+    "desc",
+    "nulls",
+    "first",
+    "last",
+    "collate",
+    "limit",
+    "offset",
+    "group",
+    "having",
+    "order",
+    "by",
+    "returning",
+    "union",
+    "window",
+    "exists",
+    "select",
+    "where",
+    "from",
+];
+
+const COMPARISONS: &[&str] = &["=", "==", "!=", "<>", "<", "<=", ">", ">="];
+
+impl QueryShape {
+    /// Reads the shape of a single table query, `None` if it has no `FROM` clause.
+    /// Columns qualified with a table or schema are reduced to their name.
+    fn parse(sql: &str) -> Option<Self> {
+        let tokens = tokenize(sql);
+        let from = tokens.iter().position(|token| token.is_keyword("from"))?;
+        let Some(Token::Name(table, _)) = tokens.get(from + 1) else {
+            return None;
+        };
+        let mut shape = Self {
+            table: table.clone(),
+            filtered: Vec::new(),
+            sorted: Vec::new(),
+        };
+
+        enum Clause {
+            Other,
+            Where,
+            OrderBy,
+        }
+        let mut clause = Clause::Other;
+        let mut index = from + 2;
+        while let Some(token) = tokens.get(index) {
+            let next = tokens.get(index + 1);
+            index += 1;
+            if token.is_keyword("where") {
+                clause = Clause::Where;
+                continue;
+            }
+            if token.is_keyword("order") && next.is_some_and(|next| next.is_keyword("by")) {
+                clause = Clause::OrderBy;
+                index += 1;
+                continue;
+            }
+            if [
+                "group",
+                "having",
+                "limit",
+                "offset",
+                "returning",
+                "union",
+                "window",
+            ]
+            .iter()
+            .any(|keyword| token.is_keyword(keyword))
+            {
+                clause = Clause::Other;
+                continue;
+            }
+
+            let Token::Name(name, quoted) = token else {
+                continue;
+            };
+            let keyword = !quoted
+                && KEYWORDS
+                    .iter()
+                    .any(|keyword| name.eq_ignore_ascii_case(keyword));
+            // Function calls such as `lower(title)` aren't columns.
+            let call = matches!(next, Some(Token::Symbol(symbol)) if symbol == "(");
+            if keyword || call {
+                continue;
+            }
+            let columns = match clause {
+                Clause::Other => continue,
+                Clause::Where => {
+                    let compared = match next {
+                        Some(Token::Symbol(symbol)) => COMPARISONS.contains(&symbol.as_str()),
+                        Some(next) => ["is", "in", "like", "glob", "between", "not"]
+                            .iter()
+                            .any(|keyword| next.is_keyword(keyword)),
+                        None => false,
+                    };
+                    if !compared {
+                        continue;
+                    }
+                    &mut shape.filtered
+                }
+                Clause::OrderBy => &mut shape.sorted,
+            };
+            if !columns.contains(name) {
+                columns.push(name.clone());
+            }
+        }
+
+        Some(shape)
+    }
+}
+
+/// Splits `sql` into names, symbols and literals. Qualified names (`"t"."id"`) are
+/// reduced to their last part.
+fn tokenize(sql: &str) -> Vec<Token> {
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut chars = sql.chars().peekable();
+    while let Some(char) = chars.next() {
+        let token = match char {
+            char if char.is_whitespace() => continue,
+            '"' | '`' | '[' => {
+                let close = if char == '[' { ']' } else { char };
+                let mut name = String::new();
+                while let Some(char) = chars.next() {
+                    if char == close {
+                        // A doubled quote escapes itself.
+                        if close != ']' && chars.peek() == Some(&close) {
+                            chars.next();
+                        } else {
+                            break;
+                        }
+                    }
+                    name.push(char);
+                }
+                Token::Name(name, true)
+            }
+            '\'' => {
+                while let Some(char) = chars.next() {
+                    if char == '\'' {
+                        if chars.peek() == Some(&'\'') {
+                            chars.next();
+                        } else {
+                            break;
+                        }
+                    }
+                }
+                Token::Literal
+            }
+            char if char.is_ascii_digit() || char == '?' || char == '$' || char == ':' => {
+                while chars
+                    .peek()
+                    .is_some_and(|char| char.is_alphanumeric() || *char == '_' || *char == '.')
+                {
+                    chars.next();
+                }
+                Token::Literal
+            }
+            char if char.is_alphabetic() || char == '_' => {
+                let mut name = char.to_string();
+                while let Some(char) = chars.next_if(|char| char.is_alphanumeric() || *char == '_')
+                {
+                    name.push(char);
+                }
+                Token::Name(name, false)
+            }
+            '<' | '>' | '=' | '!' => {
+                let mut symbol = char.to_string();
+                while let Some(char) = chars.next_if(|char| matches!(char, '<' | '>' | '=')) {
+                    symbol.push(char);
+                }
+                Token::Symbol(symbol)
+            }
+            char => Token::Symbol(char.to_string()),
+        };
+
+        if let Token::Name(..) = token
+            && let [.., Token::Name(..), Token::Symbol(dot)] = tokens.as_slice()
+            && dot == "."
+        {
+            tokens.truncate(tokens.len() - 2);
+        }
+        tokens.push(token);
+    }
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn slow(sql: &str) -> SlowQuery {
+        SlowQuery {
+            sql: sql.to_string(),
+            params: Vec::new(),
+            duration_ms: 500,
+            plan: None,
+            recorded_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_query_shape() {
+        let shape = QueryShape::parse(
+            r#"SELECT json_object('id', "id") FROM "main"."tickets" AS t
+               WHERE t."status" IS json_extract(?1, '$."status"') AND lower(title) = 'x'
+                 AND priority >= 2 AND "owner" IN (SELECT id FROM users) AND done NOT NULL
+               ORDER BY "created" DESC, t.priority LIMIT 20"#,
+        )
+        .unwrap();
+        assert_eq!(shape.table, "tickets");
+        assert_eq!(shape.filtered, ["status", "priority", "owner", "done"]);
+        assert_eq!(shape.sorted, ["created", "priority"]);
+
+        assert_eq!(QueryShape::parse("SELECT 1"), None);
+    }
+
+    #[sqlx::test]
+    async fn test_suggestions(db: Pool<Sqlite>) -> sqlx::Result<()> {
+        sqlx::raw_sql(
+            "CREATE TABLE tickets (id INTEGER PRIMARY KEY, status TEXT, title TEXT, \
+                 created TEXT, code TEXT UNIQUE); \
+             CREATE INDEX tickets_title ON tickets (title);",
+        )
+        .execute(&db)
+        .await?;
+        let usage = QueryUsage::open(db).await?;
+        for _ in 0..2 {
+            usage
+                .record("tickets", ["status", "title", "id"], ["-created", "code"])
+                .await?;
+        }
+        usage.record("tickets", ["status"], []).await?;
+        usage.record("archived", ["status"], ["created"]).await?;
+        assert_eq!(
+            usage.usage().await?[2],
+            ColumnUsage {
+                table: "tickets".to_string(),
+                column: "code".to_string(),
+                filtered: 0,
+                sorted: 2,
+            }
+        );
+
+        let slow_queries = [slow(
+            "SELECT * FROM tickets WHERE created > ?1 ORDER BY created",
+        )];
+        let suggestions = usage.suggestions(&slow_queries, 3).await?;
+        assert_eq!(
+            suggestions,
+            [
+                IndexSuggestion {
+                    table: "tickets".to_string(),
+                    column: "created".to_string(),
+                    filtered: 0,
+                    sorted: 2,
+                    slow_queries: 1,
+                    sql: r#"CREATE INDEX "tickets_created_idx" ON "tickets" ("created")"#
+                        .to_string(),
+                },
+                IndexSuggestion {
+                    table: "tickets".to_string(),
+                    column: "status".to_string(),
+                    filtered: 3,
+                    sorted: 0,
+                    slow_queries: 0,
+                    sql: r#"CREATE INDEX "tickets_status_idx" ON "tickets" ("status")"#.to_string(),
+                },
+            ]
+        );
+        assert_eq!(
+            report(&suggestions[1..]),
+            "-- tickets.status: filtered 3, sorted 0, slow queries 0\n\
+             CREATE INDEX \"tickets_status_idx\" ON \"tickets\" (\"status\");\n"
+        );
+
+        usage.clear().await?;
+        assert!(usage.suggestions(&[], 1).await?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,223 @@
+//! # Query instrumentation
+//!
+//! [`SlowQueryLog`] times queries and keeps the most recent ones that exceeded a
+//! configurable threshold, together with their parameters and, optionally, the plan
+//! returned by `EXPLAIN`. Every slow query is also emitted as a `tracing` warning.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # async fn run(db: sqlx::Pool<sqlx::Postgres>) -> Result<(), sqlx::Error> {
+//! use palmera_database::instrument::SlowQueryLog;
+//! use std::time::Duration;
+//!
+//! let log = SlowQueryLog::new(Duration::from_millis(200)).with_plan_capture(true);
+//! let sql = "SELECT count(*) FROM pg_class";
+//! let count: i64 = log
+//!     .observe_postgres(&db, sql, &[], sqlx::query_scalar(sql).fetch_one(&db))
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    cmp::Reverse,
+    collections::VecDeque,
+    future::Future,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use utoipa::ToSchema;
+
+/// A query that took longer than the log's threshold.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct SlowQuery {
+    /// SQL text as sent to the database.
+    pub sql: String,
+    /// Bound parameters, rendered as strings.
+    pub params: Vec<String>,
+    /// Wall-clock execution time in milliseconds.
+    pub duration_ms: u64,
+    /// Output of `EXPLAIN` for the query, when plan capture is enabled.
+    pub plan: Option<String>,
+    /// When the query finished (UTC).
+    pub recorded_at: DateTime<Utc>,
+}
+
+/// Bounded, thread-safe log of slow queries.
+#[derive(Debug)]
+pub struct SlowQueryLog {
+    threshold: Duration,
+    capacity: usize,
+    capture_plan: bool,
+    entries: Mutex<VecDeque<SlowQuery>>,
+}
+
+impl SlowQueryLog {
+    /// Creates a log recording queries slower than `threshold`, keeping the latest 100.
+    pub fn new(threshold: Duration) -> Self {
+        Self {
+            threshold,
+            capacity: 100,
+            capture_plan: false,
+            entries: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Sets how many slow queries are retained before the oldest are dropped.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Enables running `EXPLAIN` for slow queries in [`observe_postgres`](Self::observe_postgres).
+    pub fn with_plan_capture(mut self, capture_plan: bool) -> Self {
+        self.capture_plan = capture_plan;
+        self
+    }
+
+    /// Minimum duration for a query to be considered slow.
+    pub fn threshold(&self) -> Duration {
+        self.threshold
+    }
+
+    /// Returns `true` if a query running for `elapsed` should be recorded.
+    pub fn is_slow(&self, elapsed: Duration) -> bool {
+        elapsed >= self.threshold
+    }
+
+    /// Records a query if `elapsed` exceeds the threshold.
+    pub fn record(&self, sql: &str, params: &[String], elapsed: Duration, plan: Option<String>) {
+        if !self.is_slow(elapsed) {
+            return;
+        }
+
+        let duration_ms = elapsed.as_millis() as u64;
+
+        tracing::warn!(sql, ?params, duration_ms, "slow query");
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.push_back(SlowQuery {
+            sql: sql.to_string(),
+            params: params.to_vec(),
+            duration_ms,
+            plan,
+            recorded_at: Utc::now(),
+        });
+        while entries.len() > self.capacity {
+            entries.pop_front();
+        }
+    }
+
+    /// Returns the recorded slow queries, slowest first.
+    pub fn entries(&self) -> Vec<SlowQuery> {
+        let mut entries: Vec<SlowQuery> = self.entries.lock().unwrap().iter().cloned().collect();
+        entries.sort_by_key(|entry| Reverse(entry.duration_ms));
+        entries
+    }
+
+    /// Removes all recorded queries.
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    /// Awaits `query`, recording it as `sql` with `params` if it was slow.
+    pub async fn observe<T, F>(&self, sql: &str, params: &[String], query: F) -> T
+    where
+        F: Future<Output = T>,
+    {
+        let start = Instant::now();
+        let result = query.await;
+        self.record(sql, params, start.elapsed(), None);
+        result
+    }
+
+    /// Like [`observe`](Self::observe), additionally capturing the Postgres plan of slow
+    /// queries when plan capture is enabled.
+    ///
+    /// `EXPLAIN` is only run for queries without bind parameters, since the plan of a
+    /// parameterized statement cannot be requested without re-binding its values.
+    pub async fn observe_postgres<T, F>(
+        &self,
+        db: &Pool<Postgres>,
+        sql: &str,
+        params: &[String],
+        query: F,
+    ) -> T
+    where
+        F: Future<Output = T>,
+    {
+        let start = Instant::now();
+        let result = query.await;
+        let elapsed = start.elapsed();
+
+        let plan = if self.capture_plan && params.is_empty() && self.is_slow(elapsed) {
+            explain_postgres(db, sql).await.ok()
+        } else {
+            None
+        };
+
+        self.record(sql, params, elapsed, plan);
+        result
+    }
+}
+
+/// Returns the textual `EXPLAIN` output for `sql`.
+pub async fn explain_postgres(db: &Pool<Postgres>, sql: &str) -> Result<String, sqlx::Error> {
+    let lines: Vec<String> = sqlx::query_scalar(&format!("EXPLAIN {sql}"))
+        .fetch_all(db)
+        .await?;
+
+    Ok(lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_ignores_fast_queries() {
+        let log = SlowQueryLog::new(Duration::from_millis(100));
+        log.record("SELECT 1", &[], Duration::from_millis(5), None);
+        assert!(log.entries().is_empty());
+    }
+
+    #[test]
+    fn test_record_keeps_latest_and_sorts_slowest_first() {
+        let log = SlowQueryLog::new(Duration::from_millis(10)).with_capacity(2);
+        log.record("SELECT 1", &[], Duration::from_millis(50), None);
+        log.record(
+            "SELECT 2",
+            &["a".to_string()],
+            Duration::from_millis(20),
+            None,
+        );
+        log.record("SELECT 3", &[], Duration::from_millis(30), None);
+
+        let entries = log.entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].sql, "SELECT 3");
+        assert_eq!(entries[1].sql, "SELECT 2");
+        assert_eq!(entries[1].params, vec!["a".to_string()]);
+    }
+
+    #[sqlx::test]
+    async fn test_observe_postgres_captures_plan(db: Pool<Postgres>) -> sqlx::Result<()> {
+        let log = SlowQueryLog::new(Duration::ZERO).with_plan_capture(true);
+        let sql = "SELECT count(*) FROM pg_class";
+
+        let count: i64 = log
+            .observe_postgres(&db, sql, &[], sqlx::query_scalar(sql).fetch_one(&db))
+            .await?;
+        assert!(count > 0);
+
+        let entries = log.entries();
+        assert_eq!(entries.len(), 1);
+        assert!(entries[0].plan.as_deref().unwrap().contains("Aggregate"));
+        Ok(())
+    }
+}
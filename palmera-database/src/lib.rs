@@ -1 +1,40 @@
+pub mod admin;
+pub mod cdc;
+pub mod codegen;
+pub mod column_jobs;
+pub mod comments;
+pub mod config_bundle;
+pub mod constraint;
+pub mod ddl;
+pub mod embedded;
+pub mod error;
+pub mod explain;
+pub mod export;
+pub mod exposure;
+pub mod feature_flags;
+pub mod fields;
+pub mod files;
+pub mod form;
+pub mod history;
+pub mod import;
+pub mod index_advisor;
+pub mod instrument;
+pub mod manifest;
+#[cfg(feature = "mysql")]
+pub mod mysql;
+pub mod notifications;
+pub mod outbox;
+pub mod pools;
+pub mod query_cost;
+pub mod postgres;
+pub mod quotas;
+pub mod retention;
+pub mod saved_queries;
+pub mod settings;
 pub mod sqlite;
+pub mod statement_cache;
+pub mod stats;
+pub mod supervisor;
+pub mod templates;
+pub mod trash;
+pub mod versioning;
@@ -0,0 +1,798 @@
+//! # Schema manifests
+//!
+//! Declares the SQLite schema in a versioned TOML or JSON file kept in git, instead of
+//! building it through the `/admin/tables` routes. A [`SchemaManifest`] lists tables
+//! with their columns and indexes, and the policies of [`crate::embedded`]:
+//!
+//! ```toml
+//! version = 3
+//!
+//! [[tables]]
+//! name = "posts"
+//! columns = [
+//!   { name = "id", data_type = "integer", primary_key = true },
+//!   { name = "title", data_type = "text", not_null = true, default = "" },
+//!   { name = "owner", data_type = "text" },
+//! ]
+//! indexes = [{ name = "posts_owner", columns = ["owner"] }]
+//!
+//! [[policies]]
+//! name = "owners read posts"
+//! table_name = "posts"
+//! operation = "select"
+//! using_expr = "owner = auth.uid()"
+//! ```
+//!
+//! [`plan`] diffs the manifest against the live database into a [`Migration`] that
+//! creates missing tables, with their `checks` and `foreign_keys`, missing columns and
+//! indexes, and brings the policies of the tables the manifest names in line with it,
+//! deleting the ones it doesn't list. Deleting a policy is destructive, so [`apply`]
+//! refuses to unless forced. Nothing else is dropped: live columns the manifest doesn't
+//! declare, or declares with another type, and live indexes defined otherwise than
+//! declared are reported as [`ManifestPlan::drift`] for a human to resolve with the
+//! admin routes. Applying an up to date manifest is a no-op, so [`apply_file`] can run on
+//! every startup.
+//!
+//! Applied manifests are recorded in the `_schema_manifests` table. A manifest older than
+//! the last one applied, or changed without bumping its version, is rejected, so an old
+//! deploy can't roll policies back.
+//!
+//! The `palmera-database schema-apply` command plans or applies a manifest from the
+//! command line, e.g. to review the changes of a pull request.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # async fn run(db: sqlx::Pool<sqlx::Sqlite>) -> Result<(), palmera_database::ddl::SchemaError> {
+//! use palmera_database::manifest;
+//!
+//! let plan = manifest::apply_file(&db, "schema.toml", false).await?;
+//! for drift in &plan.drift {
+//!     tracing::warn!(%drift, "schema drifted from the manifest");
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{collections::HashSet, path::Path};
+
+use sea_query::{Alias, Expr, Index, OnConflict, Query, SqliteQueryBuilder};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{Executor, Pool, Sqlite, SqliteConnection};
+
+use crate::{
+    ddl::{
+        self, AlterOperation, AlterTable, ColumnDefinition, ColumnType, CreateTable, ForeignKey,
+        Migration, SchemaError,
+    },
+    sqlite::helpers::{create_policy_table, create_schema_manifests_table},
+};
+
+/// The schema a database should have.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SchemaManifest {
+    /// Bumped on every change to the manifest.
+    pub version: u32,
+    #[serde(default)]
+    pub tables: Vec<TableManifest>,
+    #[serde(default)]
+    pub policies: Vec<PolicyManifest>,
+}
+
+/// A table, its constraints and its indexes.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TableManifest {
+    pub name: String,
+    pub columns: Vec<ColumnDefinition>,
+    /// `CHECK` constraints, as SQL expressions, created with the table.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub checks: Vec<String>,
+    /// Foreign keys, created with the table.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub foreign_keys: Vec<ForeignKey>,
+    #[serde(default)]
+    pub indexes: Vec<IndexManifest>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct IndexManifest {
+    pub name: String,
+    pub columns: Vec<String>,
+    #[serde(default)]
+    pub unique: bool,
+}
+
+/// A row of the `_policies` table, identified by its name.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PolicyManifest {
+    pub name: String,
+    pub table_name: String,
+    /// `select`, `insert`, `update`, `delete` or `all`.
+    pub operation: String,
+    /// `PERMISSIVE` or `RESTRICTIVE`.
+    #[serde(default = "permissive")]
+    pub policy_type: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub using_expr: Option<String>,
+    #[serde(default)]
+    pub check_expr: Option<String>,
+}
+
+fn permissive() -> String {
+    "PERMISSIVE".to_string()
+}
+
+fn enabled() -> bool {
+    true
+}
+
+const OPERATIONS: [&str; 5] = ["select", "insert", "update", "delete", "all"];
+
+impl SchemaManifest {
+    /// Parses a manifest, as TOML if `path` ends with `.toml` and as JSON if it ends with
+    /// `.json`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`SchemaError::Invalid`] for other extensions and malformed manifests.
+    pub fn parse(path: &Path, source: &str) -> Result<Self, SchemaError> {
+        let invalid = |err: &dyn std::fmt::Display| {
+            SchemaError::Invalid(format!("invalid manifest {}: {}", path.display(), err))
+        };
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => toml::from_str(source).map_err(|err| invalid(&err)),
+            Some("json") => serde_json::from_str(source).map_err(|err| invalid(&err)),
+            _ => Err(invalid(&"expected a .toml or .json file")),
+        }
+    }
+
+    /// Reads and parses the manifest at `path`.
+    pub async fn load(path: impl AsRef<Path>) -> Result<Self, SchemaError> {
+        let path = path.as_ref();
+        let source = tokio::fs::read_to_string(path).await.map_err(|err| {
+            SchemaError::Invalid(format!("can't read manifest {}: {}", path.display(), err))
+        })?;
+        Self::parse(path, &source)
+    }
+
+    /// Hex SHA-256 of the manifest's content, telling versions apart.
+    fn checksum(&self) -> String {
+        let json = serde_json::to_vec(self).unwrap_or_default();
+        Sha256::digest(json)
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    pub(crate) fn check(&self) -> Result<(), SchemaError> {
+        let mut tables = HashSet::new();
+        for table in &self.tables {
+            if !tables.insert(&table.name) {
+                return Err(SchemaError::Invalid(format!(
+                    "table {} is declared twice",
+                    table.name
+                )));
+            }
+            for index in &table.indexes {
+                if index.columns.is_empty() {
+                    return Err(SchemaError::Invalid(format!(
+                        "index {} needs at least one column",
+                        index.name
+                    )));
+                }
+            }
+        }
+
+        let mut policies = HashSet::new();
+        for policy in &self.policies {
+            if !policies.insert(&policy.name) {
+                return Err(SchemaError::Invalid(format!(
+                    "policy {} is declared twice",
+                    policy.name
+                )));
+            }
+            if !OPERATIONS.contains(&policy.operation.as_str()) {
+                return Err(SchemaError::Invalid(format!(
+                    "policy {} has unknown operation {}",
+                    policy.name, policy.operation
+                )));
+            }
+            if !matches!(policy.policy_type.as_str(), "PERMISSIVE" | "RESTRICTIVE") {
+                return Err(SchemaError::Invalid(format!(
+                    "policy {} has unknown type {}",
+                    policy.name, policy.policy_type
+                )));
+            }
+            if policy.using_expr.is_none() && policy.check_expr.is_none() {
+                return Err(SchemaError::Invalid(format!(
+                    "policy {} needs using_expr or check_expr",
+                    policy.name
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// What applying a manifest changes.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct ManifestPlan {
+    pub version: u32,
+    /// Statements bringing the database in line with the manifest.
+    pub migration: Migration,
+    /// Differences the manifest does not resolve, e.g. live columns it doesn't declare.
+    pub drift: Vec<String>,
+}
+
+impl ManifestPlan {
+    /// Returns whether the database already matches the manifest.
+    pub fn is_empty(&self) -> bool {
+        self.migration.statements.is_empty()
+    }
+}
+
+/// Plans bringing the database in line with `manifest`.
+///
+/// # Errors
+///
+/// Returns [`SchemaError::Invalid`] for inconsistent manifests and
+/// [`SchemaError::Conflict`] if a newer manifest, or another one of the same version,
+/// was applied.
+pub async fn plan(
+    db: &Pool<Sqlite>,
+    manifest: &SchemaManifest,
+) -> Result<ManifestPlan, SchemaError> {
+    plan_versioned(&mut *db.acquire().await?, manifest).await
+}
+
+/// Checks `manifest` and its version, and plans it on `conn`.
+async fn plan_versioned(
+    conn: &mut SqliteConnection,
+    manifest: &SchemaManifest,
+) -> Result<ManifestPlan, SchemaError> {
+    manifest.check()?;
+    check_version(&mut *conn, manifest).await?;
+    plan_schema(conn, manifest).await
+}
+
+/// Plans bringing the database in line with a checked `manifest`, whatever its version.
+pub(crate) async fn plan_schema(
+    conn: &mut SqliteConnection,
+    manifest: &SchemaManifest,
+) -> Result<ManifestPlan, SchemaError> {
+    let mut plan = ManifestPlan {
+        version: manifest.version,
+        ..ManifestPlan::default()
+    };
+    for table in &manifest.tables {
+        plan_table(&mut *conn, table, &mut plan).await?;
+    }
+    plan_policies(conn, manifest, &mut plan.migration).await?;
+
+    Ok(plan)
+}
+
+async fn check_version(
+    conn: &mut SqliteConnection,
+    manifest: &SchemaManifest,
+) -> Result<(), SchemaError> {
+    if !ddl::table_exists(&mut *conn, "_schema_manifests").await? {
+        return Ok(());
+    }
+    let applied: Option<(i64, String)> = sqlx::query_as(
+        "SELECT version, checksum FROM _schema_manifests ORDER BY version DESC LIMIT 1",
+    )
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    match applied {
+        Some((version, _)) if i64::from(manifest.version) < version => {
+            Err(SchemaError::Conflict(format!(
+                "manifest version {} is older than the applied version {}",
+                manifest.version, version
+            )))
+        }
+        Some((version, checksum))
+            if i64::from(manifest.version) == version && checksum != manifest.checksum() =>
+        {
+            Err(SchemaError::Conflict(format!(
+                "manifest version {} was applied with other content; bump the version",
+                version
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+async fn plan_table(
+    conn: &mut SqliteConnection,
+    table: &TableManifest,
+    plan: &mut ManifestPlan,
+) -> Result<(), SchemaError> {
+    if !ddl::table_exists(&mut *conn, &table.name).await? {
+        let create = CreateTable {
+            name: table.name.clone(),
+            columns: table.columns.clone(),
+            checks: table.checks.clone(),
+            foreign_keys: table.foreign_keys.clone(),
+        };
+        extend(
+            &mut plan.migration,
+            ddl::plan_create_in(&mut *conn, &create).await?,
+        );
+        for index in &table.indexes {
+            plan.migration
+                .statements
+                .push(create_index(&table.name, index));
+        }
+        return Ok(());
+    }
+
+    let live: Vec<(String, String)> =
+        sqlx::query_as("SELECT name, type FROM pragma_table_info(?) ORDER BY cid")
+            .bind(&table.name)
+            .fetch_all(&mut *conn)
+            .await?;
+
+    let mut operations = Vec::new();
+    for column in &table.columns {
+        match live.iter().find(|(name, _)| *name == column.name) {
+            None => operations.push(AlterOperation::AddColumn {
+                column: column.clone(),
+            }),
+            Some((_, live_type)) if affinity(live_type) != affinity(declared_type(column)) => {
+                plan.drift.push(format!(
+                    "column {}.{} is {} but declared {:?}",
+                    table.name, column.name, live_type, column.data_type
+                ));
+            }
+            Some(_) => {}
+        }
+    }
+    for (name, _) in &live {
+        if !table.columns.iter().any(|column| column.name == *name) {
+            plan.drift
+                .push(format!("column {}.{} is not declared", table.name, name));
+        }
+    }
+    if !operations.is_empty() {
+        let change = AlterTable { operations };
+        extend(
+            &mut plan.migration,
+            ddl::plan_alter_in(&mut *conn, &table.name, &change).await?,
+        );
+    }
+
+    for index in &table.indexes {
+        let indexed: Option<String> = sqlx::query_scalar(
+            "SELECT tbl_name FROM sqlite_master WHERE type = 'index' AND name = ?",
+        )
+        .bind(&index.name)
+        .fetch_optional(&mut *conn)
+        .await?;
+        if let Some(indexed) = indexed {
+            if indexed != table.name {
+                plan.drift.push(format!(
+                    "index {} is on table {} but declared on {}",
+                    index.name, indexed, table.name
+                ));
+                continue;
+            }
+            let unique: bool =
+                sqlx::query_scalar("SELECT \"unique\" FROM pragma_index_list(?) WHERE name = ?")
+                    .bind(&table.name)
+                    .bind(&index.name)
+                    .fetch_one(&mut *conn)
+                    .await?;
+            let columns: Vec<String> = sqlx::query_scalar(
+                "SELECT coalesce(name, '<expression>') FROM pragma_index_info(?) ORDER BY seqno",
+            )
+            .bind(&index.name)
+            .fetch_all(&mut *conn)
+            .await?;
+            if unique != index.unique || columns != index.columns {
+                plan.drift.push(format!(
+                    "index {} is {} but declared {}",
+                    index.name,
+                    index_definition(unique, &columns),
+                    index_definition(index.unique, &index.columns)
+                ));
+            }
+            continue;
+        }
+
+        plan.migration
+            .statements
+            .push(create_index(&table.name, index));
+        let rows: i64 =
+            sqlx::query_scalar(&format!("SELECT count(*) FROM {}", ddl::quote(&table.name)))
+                .fetch_one(&mut *conn)
+                .await?;
+        if rows > 0 {
+            plan.migration.warnings.push(ddl::Warning {
+                risk: ddl::Risk::Locking,
+                statement: plan.migration.statements.len() - 1,
+                message: format!(
+                    "index {} reads the {} rows of table {}",
+                    index.name, rows, table.name
+                ),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+type PolicyRow = (
+    String,
+    String,
+    String,
+    String,
+    Option<String>,
+    bool,
+    Option<String>,
+    Option<String>,
+);
+
+async fn plan_policies(
+    conn: &mut SqliteConnection,
+    manifest: &SchemaManifest,
+    migration: &mut Migration,
+) -> Result<(), SchemaError> {
+    let managed: HashSet<&str> = manifest
+        .tables
+        .iter()
+        .map(|table| table.name.as_str())
+        .chain(
+            manifest
+                .policies
+                .iter()
+                .map(|policy| policy.table_name.as_str()),
+        )
+        .collect();
+
+    let live = if ddl::table_exists(&mut *conn, "_policies").await? {
+        live_policies(&mut *conn).await?
+    } else {
+        if manifest.policies.is_empty() {
+            return Ok(());
+        }
+        migration
+            .statements
+            .push(create_policy_table().to_string(SqliteQueryBuilder));
+        Vec::new()
+    };
+
+    for policy in &live {
+        let declared = manifest.policies.iter().any(|p| p.name == policy.name);
+        if managed.contains(policy.table_name.as_str()) && !declared {
+            let statement = Query::delete()
+                .from_table(Alias::new("_policies"))
+                .and_where(Expr::col(Alias::new("name")).eq(policy.name.as_str()))
+                .to_string(SqliteQueryBuilder);
+            migration.statements.push(statement);
+            migration.warnings.push(ddl::Warning {
+                risk: ddl::Risk::Destructive,
+                statement: migration.statements.len() - 1,
+                message: format!(
+                    "drops policy {} of table {}, which the manifest doesn't list",
+                    policy.name, policy.table_name
+                ),
+            });
+        }
+    }
+    for policy in &manifest.policies {
+        if !live.contains(policy) {
+            migration.statements.push(upsert_policy(policy));
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the policies stored in `_policies`, which must exist, ordered by name.
+pub(crate) async fn live_policies<'e, E>(db: E) -> Result<Vec<PolicyManifest>, sqlx::Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let rows: Vec<PolicyRow> = sqlx::query_as(
+        "SELECT name, table_name, operation, policy_type, description, is_enabled, \
+         using_expr, check_expr FROM _policies ORDER BY name",
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(
+            |(name, table_name, operation, policy_type, description, enabled, using, check)| {
+                PolicyManifest {
+                    name,
+                    table_name,
+                    operation,
+                    policy_type,
+                    description,
+                    enabled,
+                    using_expr: using,
+                    check_expr: check,
+                }
+            },
+        )
+        .collect())
+}
+
+fn upsert_policy(policy: &PolicyManifest) -> String {
+    let columns = [
+        "name",
+        "table_name",
+        "operation",
+        "policy_type",
+        "description",
+        "is_enabled",
+        "using_expr",
+        "check_expr",
+    ];
+    Query::insert()
+        .into_table(Alias::new("_policies"))
+        .columns(columns.map(Alias::new))
+        .values_panic([
+            policy.name.as_str().into(),
+            policy.table_name.as_str().into(),
+            policy.operation.as_str().into(),
+            policy.policy_type.as_str().into(),
+            policy.description.clone().into(),
+            i32::from(policy.enabled).into(),
+            policy.using_expr.clone().into(),
+            policy.check_expr.clone().into(),
+        ])
+        .on_conflict(
+            OnConflict::column(Alias::new("name"))
+                .update_columns(columns[1..].iter().map(|column| Alias::new(*column)))
+                .to_owned(),
+        )
+        .to_string(SqliteQueryBuilder)
+}
+
+fn create_index(table: &str, index: &IndexManifest) -> String {
+    let mut create = Index::create();
+    create
+        .name(&index.name)
+        .table(Alias::new(table))
+        .if_not_exists();
+    for column in &index.columns {
+        create.col(Alias::new(column));
+    }
+    if index.unique {
+        create.unique();
+    }
+    create.to_string(SqliteQueryBuilder)
+}
+
+/// Describes an index in drift reports, e.g. `unique (owner, slug)`.
+fn index_definition(unique: bool, columns: &[String]) -> String {
+    let unique = if unique { "unique " } else { "" };
+    format!("{}({})", unique, columns.join(", "))
+}
+
+/// Appends the statements of `other`, renumbering its warnings.
+fn extend(migration: &mut Migration, other: Migration) {
+    let offset = migration.statements.len();
+    migration.statements.extend(other.statements);
+    migration
+        .warnings
+        .extend(other.warnings.into_iter().map(|mut warning| {
+            warning.statement += offset;
+            warning
+        }));
+}
+
+/// Type name a declared column is created with.
+fn declared_type(column: &ColumnDefinition) -> &'static str {
+    match column.data_type {
+        ColumnType::Text => "text",
+        ColumnType::Integer => "integer",
+        ColumnType::Real => "double",
+        ColumnType::Boolean => "boolean",
+        ColumnType::Blob => "blob",
+        ColumnType::Json => "json_text",
+        ColumnType::Timestamp => "timestamp_text",
+    }
+}
+
+/// SQLite type affinity of a declared type name, as in section 3.1 of the SQLite
+/// datatype documentation.
+pub(crate) fn affinity(type_name: &str) -> &'static str {
+    let type_name = type_name.to_ascii_uppercase();
+    if type_name.contains("INT") {
+        "INTEGER"
+    } else if ["CHAR", "CLOB", "TEXT"]
+        .iter()
+        .any(|part| type_name.contains(part))
+    {
+        "TEXT"
+    } else if type_name.is_empty() || type_name.contains("BLOB") {
+        "BLOB"
+    } else if ["REAL", "FLOA", "DOUB"]
+        .iter()
+        .any(|part| type_name.contains(part))
+    {
+        "REAL"
+    } else {
+        "NUMERIC"
+    }
+}
+
+/// Brings the database in line with `manifest` and records it, in one transaction that
+/// also checks the version and plans the changes, so concurrent writers can't slip in
+/// between.
+///
+/// # Errors
+///
+/// Returns [`SchemaError::Unsafe`] if the plan deletes policies and `force` is not set.
+pub async fn apply(
+    db: &Pool<Sqlite>,
+    manifest: &SchemaManifest,
+    force: bool,
+) -> Result<ManifestPlan, SchemaError> {
+    let mut tx = db.begin().await?;
+    let plan = plan_versioned(&mut *tx, manifest).await?;
+    plan.migration.check(force)?;
+
+    for statement in &plan.migration.statements {
+        sqlx::query(statement).execute(&mut *tx).await?;
+    }
+    sqlx::query(&create_schema_manifests_table().to_string(SqliteQueryBuilder))
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(
+        "INSERT INTO _schema_manifests (version, checksum, applied_at) VALUES (?, ?, ?) \
+         ON CONFLICT (version) DO NOTHING",
+    )
+    .bind(i64::from(manifest.version))
+    .bind(manifest.checksum())
+    .bind(chrono::Utc::now().timestamp())
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(plan)
+}
+
+/// Loads the manifest at `path` and applies it, e.g. on startup.
+pub async fn apply_file(
+    db: &Pool<Sqlite>,
+    path: impl AsRef<Path>,
+    force: bool,
+) -> Result<ManifestPlan, SchemaError> {
+    let manifest = SchemaManifest::load(path).await?;
+    apply(db, &manifest, force).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANIFEST: &str = r#"
+        version = 1
+
+        [[tables]]
+        name = "posts"
+        columns = [
+          { name = "id", data_type = "integer", primary_key = true },
+          { name = "title", data_type = "text", not_null = true, default = "" },
+          { name = "owner", data_type = "text" },
+        ]
+        indexes = [{ name = "posts_owner", columns = ["owner"] }]
+
+        [[policies]]
+        name = "owners read posts"
+        table_name = "posts"
+        operation = "select"
+        using_expr = "owner = auth.uid()"
+    "#;
+
+    fn manifest(source: &str) -> SchemaManifest {
+        SchemaManifest::parse(Path::new("schema.toml"), source).unwrap()
+    }
+
+    #[sqlx::test]
+    async fn test_apply_manifest(db: Pool<Sqlite>) -> Result<(), SchemaError> {
+        let first = manifest(MANIFEST);
+        let applied = apply(&db, &first, false).await?;
+        assert_eq!(applied.migration.statements.len(), 4);
+        assert!(applied.drift.is_empty());
+
+        // Applying it again changes nothing.
+        assert!(apply(&db, &first, false).await?.is_empty());
+
+        sqlx::query("ALTER TABLE posts ADD COLUMN legacy TEXT")
+            .execute(&db)
+            .await?;
+        sqlx::query("DROP INDEX posts_owner").execute(&db).await?;
+        sqlx::query("CREATE UNIQUE INDEX posts_owner ON posts (owner, title)")
+            .execute(&db)
+            .await?;
+        sqlx::query(
+            "INSERT INTO _policies (name, table_name, operation, using_expr) \
+             VALUES ('anyone reads posts', 'posts', 'select', '1')",
+        )
+        .execute(&db)
+        .await?;
+
+        let second = manifest(
+            &MANIFEST
+                .replace("version = 1", "version = 2")
+                .replace(
+                    r#"{ name = "owner", data_type = "text" },"#,
+                    r#"{ name = "owner", data_type = "text" },
+                       { name = "views", data_type = "integer", not_null = true, default = 0 },"#,
+                )
+                .replace("owner = auth.uid()", "owner = auth.uid() OR 0"),
+        );
+        let changes = plan(&db, &second).await?;
+        assert_eq!(
+            changes.drift,
+            [
+                "column posts.legacy is not declared",
+                "index posts_owner is unique (owner, title) but declared (owner)"
+            ]
+        );
+        assert_eq!(changes.migration.statements.len(), 3);
+        assert_eq!(
+            changes.migration.statements[0],
+            r#"ALTER TABLE "posts" ADD COLUMN "views" integer NOT NULL DEFAULT 0"#
+        );
+        assert_eq!(changes.migration.warnings.len(), 1);
+        assert_eq!(changes.migration.warnings[0].statement, 1);
+
+        assert!(matches!(
+            apply(&db, &second, false).await,
+            Err(SchemaError::Unsafe(_))
+        ));
+        apply(&db, &second, true).await?;
+        let policies: Vec<(String, String)> =
+            sqlx::query_as("SELECT name, using_expr FROM _policies")
+                .fetch_all(&db)
+                .await?;
+        assert_eq!(
+            policies,
+            [(
+                "owners read posts".to_string(),
+                "owner = auth.uid() OR 0".to_string()
+            )]
+        );
+
+        // Older manifests, or changed ones under the same version, are rejected.
+        assert!(matches!(
+            plan(&db, &first).await,
+            Err(SchemaError::Conflict(_))
+        ));
+        let changed = manifest(&MANIFEST.replace("version = 1", "version = 2"));
+        assert!(matches!(
+            plan(&db, &changed).await,
+            Err(SchemaError::Conflict(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_manifest() {
+        let json = serde_json::to_string(&manifest(MANIFEST)).unwrap();
+        let parsed = SchemaManifest::parse(Path::new("schema.json"), &json).unwrap();
+        assert_eq!(parsed, manifest(MANIFEST));
+        assert!(SchemaManifest::parse(Path::new("schema.yaml"), "").is_err());
+
+        let invalid =
+            manifest(&MANIFEST.replace(r#"operation = "select""#, r#"operation = "read""#));
+        assert!(matches!(invalid.check(), Err(SchemaError::Invalid(_))));
+        assert_eq!(affinity("VARCHAR(20)"), "TEXT");
+        assert_eq!(affinity("double"), "REAL");
+        assert_eq!(affinity("boolean"), "NUMERIC");
+    }
+}
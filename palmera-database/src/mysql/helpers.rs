@@ -0,0 +1,23 @@
+/// Quotes a MySQL identifier with backticks, doubling embedded backticks.
+pub fn quote_ident(ident: &str) -> String {
+    format!("`{}`", ident.replace('`', "``"))
+}
+
+/// Quotes a possibly database qualified name (`shop.orders`) part by part.
+pub fn quote_qualified(name: &str) -> String {
+    name.split('.')
+        .map(quote_ident)
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quote_qualified() {
+        assert_eq!(quote_qualified("shop.orders"), "`shop`.`orders`");
+        assert_eq!(quote_qualified("we`ird"), "`we``ird`");
+    }
+}
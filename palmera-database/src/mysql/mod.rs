@@ -0,0 +1,5 @@
+//! MySQL and MariaDB support, built with the `mysql` feature.
+
+pub mod helpers;
+pub mod records;
+pub mod schemas;
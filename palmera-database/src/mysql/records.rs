@@ -0,0 +1,282 @@
+//! # MySQL records
+//!
+//! Record operations on the tables of a MySQL or MariaDB database, returning records as
+//! JSON objects built with `JSON_OBJECT`, like the other backends. Records are
+//! identified by their `id` column. Tables starting with `_` are internal and reported
+//! as missing, and record keys that aren't columns of the table are rejected.
+//!
+//! MySQL has no `RETURNING`, so writes run in a transaction that reads the record back:
+//! inserts by the `id` given or `LAST_INSERT_ID()`, deletes before deleting. Values are
+//! bound as text MySQL converts to the column's type. Booleans are stored as
+//! `tinyint(1)` and come back as `0` and `1`.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # async fn run(db: sqlx::Pool<sqlx::MySql>) -> Result<(), palmera_database::error::StoreError> {
+//! use palmera_database::mysql::records;
+//! use serde_json::json;
+//!
+//! let note = records::create(&db, "notes", json!({ "title": "Groceries" })).await?;
+//! records::update(&db, "notes", note["id"].clone(), json!({ "done": true })).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use serde_json::{Map, Value};
+use sqlx::{MySql, MySqlConnection, Pool, types::Json};
+
+use super::{helpers::quote_ident, schemas::get_table_info};
+use crate::{error::StoreError, postgres::batch::PRIMARY_KEY};
+
+/// Columns of a table, needed to build statements.
+struct Table {
+    name: String,
+    columns: Vec<String>,
+}
+
+impl Table {
+    async fn load(db: &Pool<MySql>, table: &str) -> Result<Self, StoreError> {
+        let missing = || StoreError::NotFound(format!("table {} does not exist", table));
+        if table.starts_with('_') {
+            return Err(missing());
+        }
+        let info = match get_table_info(db, table).await {
+            Ok(info) => info.table_details,
+            Err(sqlx::Error::RowNotFound) => return Err(missing()),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Self {
+            name: info.name,
+            columns: info
+                .columns
+                .into_iter()
+                .map(|column| column.column_name)
+                .collect(),
+        })
+    }
+
+    /// `JSON_OBJECT(...)` of a row.
+    fn record(&self) -> String {
+        let pairs: Vec<_> = self
+            .columns
+            .iter()
+            .map(|column| {
+                format!(
+                    "'{}', {}",
+                    column.replace('\\', "\\\\").replace('\'', "''"),
+                    quote_ident(column)
+                )
+            })
+            .collect();
+        format!("JSON_OBJECT({})", pairs.join(", "))
+    }
+
+    /// The columns of `record`, which must be an object of columns of the table.
+    fn values(&self, record: Value) -> Result<Map<String, Value>, StoreError> {
+        let Value::Object(record) = record else {
+            return Err(StoreError::Invalid("record must be an object".to_string()));
+        };
+        if let Some(unknown) = record.keys().find(|key| !self.columns.contains(key)) {
+            return Err(StoreError::Invalid(format!(
+                "table {} has no column {}",
+                self.name, unknown
+            )));
+        }
+        Ok(record)
+    }
+
+    async fn read(
+        &self,
+        conn: &mut MySqlConnection,
+        id: &Value,
+        lock: bool,
+    ) -> Result<Value, StoreError> {
+        let sql = format!(
+            "SELECT {} FROM {} WHERE {} = ?{}",
+            self.record(),
+            quote_ident(&self.name),
+            quote_ident(PRIMARY_KEY),
+            if lock { " FOR UPDATE" } else { "" },
+        );
+        let row: Option<Json<Value>> = sqlx::query_scalar(&sql)
+            .bind(sql_value(id))
+            .fetch_optional(&mut *conn)
+            .await?;
+        row.map(|Json(row)| row)
+            .ok_or_else(|| StoreError::NotFound(format!("record {} does not exist", id)))
+    }
+}
+
+/// Converts a JSON value to the text MySQL converts to the column's type, booleans to
+/// `1` and `0` and arrays and objects to JSON text.
+fn sql_value(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::Bool(value) => Some(if *value { "1" } else { "0" }.to_string()),
+        Value::String(value) => Some(value.clone()),
+        value => Some(value.to_string()),
+    }
+}
+
+/// Lists the records of `table`, ordered by id.
+pub async fn list(db: &Pool<MySql>, table: &str) -> Result<Vec<Value>, StoreError> {
+    let table = Table::load(db, table).await?;
+    let rows: Vec<Json<Value>> = sqlx::query_scalar(&format!(
+        "SELECT {} FROM {} ORDER BY {}",
+        table.record(),
+        quote_ident(&table.name),
+        quote_ident(PRIMARY_KEY),
+    ))
+    .fetch_all(db)
+    .await?;
+    Ok(rows.into_iter().map(|Json(row)| row).collect())
+}
+
+/// Returns the record of `table` with `id`.
+pub async fn get(db: &Pool<MySql>, table: &str, id: impl Into<Value>) -> Result<Value, StoreError> {
+    let table = Table::load(db, table).await?;
+    let mut conn = db.acquire().await?;
+    table.read(&mut conn, &id.into(), false).await
+}
+
+/// Inserts `record` into `table` and returns it as stored.
+pub async fn create(db: &Pool<MySql>, table: &str, record: Value) -> Result<Value, StoreError> {
+    let table = Table::load(db, table).await?;
+    let record = table.values(record)?;
+
+    let mut tx = db.begin().await?;
+    let sql = format!(
+        "INSERT INTO {} ({}) VALUES ({})",
+        quote_ident(&table.name),
+        record
+            .keys()
+            .map(|column| quote_ident(column))
+            .collect::<Vec<_>>()
+            .join(", "),
+        vec!["?"; record.len()].join(", "),
+    );
+    let mut query = sqlx::query(&sql);
+    for value in record.values() {
+        query = query.bind(sql_value(value));
+    }
+    let result = query.execute(&mut *tx).await?;
+    let id = match record.get(PRIMARY_KEY) {
+        Some(id) => id.clone(),
+        None => Value::from(result.last_insert_id()),
+    };
+    let stored = table.read(&mut tx, &id, false).await?;
+    tx.commit().await?;
+    Ok(stored)
+}
+
+/// Changes the columns in `changes` of the record of `table` with `id`, returning the
+/// updated record.
+pub async fn update(
+    db: &Pool<MySql>,
+    table: &str,
+    id: impl Into<Value>,
+    changes: Value,
+) -> Result<Value, StoreError> {
+    let table = Table::load(db, table).await?;
+    let changes = table.values(changes)?;
+    let id = id.into();
+
+    let mut tx = db.begin().await?;
+    // Fails with NotFound before writing, since MySQL counts only changed rows.
+    table.read(&mut tx, &id, true).await?;
+    if !changes.is_empty() {
+        let sql = format!(
+            "UPDATE {} SET {} WHERE {} = ?",
+            quote_ident(&table.name),
+            changes
+                .keys()
+                .map(|column| format!("{} = ?", quote_ident(column)))
+                .collect::<Vec<_>>()
+                .join(", "),
+            quote_ident(PRIMARY_KEY),
+        );
+        let mut query = sqlx::query(&sql);
+        for value in changes.values().chain([&id]) {
+            query = query.bind(sql_value(value));
+        }
+        query.execute(&mut *tx).await?;
+    }
+    let stored = table.read(&mut tx, &id, false).await?;
+    tx.commit().await?;
+    Ok(stored)
+}
+
+/// Deletes the record of `table` with `id`, returning it as it was.
+pub async fn delete(
+    db: &Pool<MySql>,
+    table: &str,
+    id: impl Into<Value>,
+) -> Result<Value, StoreError> {
+    let table = Table::load(db, table).await?;
+    let id = id.into();
+
+    let mut tx = db.begin().await?;
+    let deleted = table.read(&mut tx, &id, true).await?;
+    let sql = format!(
+        "DELETE FROM {} WHERE {} = ?",
+        quote_ident(&table.name),
+        quote_ident(PRIMARY_KEY),
+    );
+    sqlx::query(&sql)
+        .bind(sql_value(&id))
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(deleted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[sqlx::test]
+    async fn test_crud(db: Pool<MySql>) -> Result<(), StoreError> {
+        sqlx::query(
+            "CREATE TABLE notes (id INTEGER PRIMARY KEY AUTO_INCREMENT, title TEXT NOT NULL, \
+             owner TEXT, status VARCHAR(16) DEFAULT 'draft')",
+        )
+        .execute(&db)
+        .await?;
+
+        let note = create(&db, "notes", json!({ "title": "Groceries" })).await?;
+        assert_eq!(
+            note,
+            json!({ "id": 1, "title": "Groceries", "owner": null, "status": "draft" })
+        );
+
+        let note = update(&db, "notes", 1, json!({ "status": "done" })).await?;
+        assert_eq!(note["status"], "done");
+        assert_eq!(get(&db, "notes", 1).await?, note);
+        assert_eq!(list(&db, "notes").await?, std::slice::from_ref(&note));
+
+        assert_eq!(delete(&db, "notes", 1).await?, note);
+        assert!(matches!(
+            get(&db, "notes", 1).await,
+            Err(StoreError::NotFound(_))
+        ));
+        assert!(matches!(
+            update(&db, "notes", 1, json!({ "status": "done" })).await,
+            Err(StoreError::NotFound(_))
+        ));
+        assert!(matches!(
+            create(&db, "notes", json!({ "secret": 1 })).await,
+            Err(StoreError::Invalid(_))
+        ));
+        assert!(matches!(
+            list(&db, "_migrations").await,
+            Err(StoreError::NotFound(_))
+        ));
+        assert!(matches!(
+            list(&db, "missing").await,
+            Err(StoreError::NotFound(_))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,114 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, MySql, Pool};
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct TableDetails {
+    pub name: String,
+    pub r#type: Option<String>,
+    pub schema: Option<String>,
+    pub engine: Option<String>,
+    #[sqlx(json)]
+    pub columns: Vec<ColumnDetails>,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct ColumnDetails {
+    pub column_id: Option<i64>,
+    pub column_name: String,
+    /// Full column type, e.g. `varchar(255)` or `int unsigned`.
+    pub data_type: String,
+    pub is_not_null: i16,
+    pub default_value: Option<String>,
+    pub is_primary_key: i16,
+    pub is_auto_increment: i16,
+    /// Expression of a generated column.
+    pub generation_expression: Option<String>,
+    pub is_foreign_key: i16,
+    pub reference_table: Option<String>,
+    pub reference_column: Option<String>,
+    pub foreign_key_on_update: Option<String>,
+    pub foreign_key_on_delete: Option<String>,
+    pub part_of_index: Option<String>,
+}
+
+#[derive(Debug, FromRow)]
+pub struct TableOutput {
+    #[sqlx(json)]
+    pub table_details: TableDetails,
+}
+
+/// Names of the base tables in the connection's current database.
+pub async fn list_tables(db: &Pool<MySql>) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT CAST(TABLE_NAME AS CHAR) FROM information_schema.TABLES \
+         WHERE TABLE_SCHEMA = DATABASE() AND TABLE_TYPE = 'BASE TABLE' ORDER BY TABLE_NAME",
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Describes the table `name` of the connection's current database from
+/// `information_schema`.
+pub async fn get_table_info(db: &Pool<MySql>, name: &str) -> Result<TableOutput, sqlx::Error> {
+    let sql = r#"
+    SELECT
+      JSON_OBJECT(
+            'name', t.TABLE_NAME,
+            'type', t.TABLE_TYPE,
+            'schema', t.TABLE_SCHEMA,
+            'engine', t.ENGINE,
+            'columns', (
+                SELECT JSON_ARRAYAGG(
+                    JSON_OBJECT(
+                        'column_id', c.ORDINAL_POSITION,
+                        'column_name', c.COLUMN_NAME,
+                        'data_type', c.COLUMN_TYPE,
+                        'is_not_null', c.IS_NULLABLE = 'NO',
+                        'default_value', c.COLUMN_DEFAULT,
+                        'is_primary_key', c.COLUMN_KEY = 'PRI',
+                        'is_auto_increment', c.EXTRA LIKE '%auto_increment%',
+                        'generation_expression', NULLIF(c.GENERATION_EXPRESSION, ''),
+                        'is_foreign_key', k.REFERENCED_TABLE_NAME IS NOT NULL,
+                        'reference_table', k.REFERENCED_TABLE_NAME,
+                        'reference_column', k.REFERENCED_COLUMN_NAME,
+                        'foreign_key_on_update', r.UPDATE_RULE,
+                        'foreign_key_on_delete', r.DELETE_RULE,
+                        'part_of_index', (
+                            SELECT GROUP_CONCAT(DISTINCT s.INDEX_NAME)
+                            FROM information_schema.STATISTICS AS s
+                            WHERE s.TABLE_SCHEMA = c.TABLE_SCHEMA
+                              AND s.TABLE_NAME = c.TABLE_NAME
+                              AND s.COLUMN_NAME = c.COLUMN_NAME
+                        )
+                    )
+                )
+                FROM information_schema.COLUMNS AS c
+                LEFT JOIN information_schema.KEY_COLUMN_USAGE AS k
+                  ON k.TABLE_SCHEMA = c.TABLE_SCHEMA
+                 AND k.TABLE_NAME = c.TABLE_NAME
+                 AND k.COLUMN_NAME = c.COLUMN_NAME
+                 AND k.REFERENCED_TABLE_NAME IS NOT NULL
+                LEFT JOIN information_schema.REFERENTIAL_CONSTRAINTS AS r
+                  ON r.CONSTRAINT_SCHEMA = k.CONSTRAINT_SCHEMA
+                 AND r.CONSTRAINT_NAME = k.CONSTRAINT_NAME
+                WHERE c.TABLE_SCHEMA = t.TABLE_SCHEMA AND c.TABLE_NAME = t.TABLE_NAME
+            )
+        ) AS table_details
+    FROM
+        information_schema.TABLES AS t
+    WHERE
+        t.TABLE_SCHEMA = DATABASE() AND t.TABLE_NAME = ?;
+    "#;
+
+    let mut result = sqlx::query_as::<MySql, TableOutput>(sql)
+        .bind(name)
+        .fetch_one(db)
+        .await?;
+
+    // JSON_ARRAYAGG does not guarantee the order of its input rows.
+    result
+        .table_details
+        .columns
+        .sort_by_key(|column| column.column_id);
+    Ok(result)
+}
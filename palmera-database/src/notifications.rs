@@ -0,0 +1,371 @@
+//! # In-app notifications
+//!
+//! Server code and hooks create notifications for users with [`Notifications::create`],
+//! e.g. when a comment is added to a user's post. They are kept in the SQLite
+//! `_notifications` table until deleted, and listed, marked read and deleted per user.
+//!
+//! New notifications are also published to the subscribers of
+//! [`Notifications::subscribe`], the way row changes are on a
+//! [`ChangeFeed`](crate::cdc::ChangeFeed). `palmera_core::notifications` serves the
+//! `/me/notifications` routes of the signed in user and forwards new notifications to
+//! the realtime channel named by [`channel`], which only the user may subscribe to.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # async fn run(db: sqlx::Pool<sqlx::Sqlite>) -> Result<(), sqlx::Error> {
+//! use chrono::Utc;
+//! use palmera_database::notifications::{NewNotification, Notifications};
+//!
+//! let store = Notifications::open(db).await?;
+//! store
+//!     .create(
+//!         NewNotification::new("user-1", "comment", "Ann replied to your post")
+//!             .with_data(serde_json::json!({ "post_id": 42 })),
+//!         Utc::now(),
+//!     )
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use chrono::{DateTime, Utc};
+use sea_query::SqliteQueryBuilder;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite, types::Json as SqlJson};
+use tokio::sync::broadcast;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::sqlite::helpers::create_notifications_table;
+
+/// Notifications listed when a filter has no limit.
+pub const DEFAULT_LIST_LIMIT: u32 = 50;
+
+/// Most notifications listed at once.
+pub const MAX_LIST_LIMIT: u32 = 500;
+
+/// New notifications buffered per lagging subscriber.
+const FEED_CAPACITY: usize = 256;
+
+/// A notification for a user.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct Notification {
+    pub id: i64,
+    pub user_id: String,
+    /// What the notification is about, e.g. `comment`, for clients to pick an icon or
+    /// a link.
+    pub kind: String,
+    pub title: String,
+    pub body: Option<String>,
+    /// Details for the client, e.g. the id of the record to open.
+    #[schema(value_type = Object)]
+    pub data: serde_json::Value,
+    pub created_at: DateTime<Utc>,
+    /// When the user read it, `None` while unread.
+    pub read_at: Option<DateTime<Utc>>,
+}
+
+/// A notification to create.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NewNotification {
+    pub user_id: String,
+    pub kind: String,
+    pub title: String,
+    pub body: Option<String>,
+    pub data: serde_json::Value,
+}
+
+impl NewNotification {
+    pub fn new(user_id: &str, kind: &str, title: &str) -> Self {
+        Self {
+            user_id: user_id.to_string(),
+            kind: kind.to_string(),
+            title: title.to_string(),
+            body: None,
+            data: serde_json::Value::Object(Default::default()),
+        }
+    }
+
+    pub fn with_body(mut self, body: &str) -> Self {
+        self.body = Some(body.to_string());
+        self
+    }
+
+    pub fn with_data(mut self, data: serde_json::Value) -> Self {
+        self.data = data;
+        self
+    }
+}
+
+/// Narrows [`Notifications::list`].
+#[derive(Debug, Clone, Default, Deserialize, IntoParams, PartialEq)]
+pub struct NotificationFilter {
+    /// Only unread notifications.
+    #[serde(default)]
+    pub unread: bool,
+    /// Only notifications older than the one with this id, to page through them.
+    pub before: Option<i64>,
+    /// Maximum number of notifications, [`DEFAULT_LIST_LIMIT`] by default and
+    /// [`MAX_LIST_LIMIT`] at most.
+    pub limit: Option<u32>,
+}
+
+/// The realtime channel a user's new notifications are pushed to.
+pub fn channel(user_id: &str) -> String {
+    format!("notifications:{}", user_id)
+}
+
+type NotificationRow = (
+    i64,
+    String,
+    String,
+    String,
+    Option<String>,
+    SqlJson<serde_json::Value>,
+    i64,
+    Option<i64>,
+);
+
+const NOTIFICATION_COLUMNS: &str = "id, user_id, kind, title, body, data, created_at, read_at";
+
+fn notification(
+    (id, user_id, kind, title, body, data, created_at, read_at): NotificationRow,
+) -> Notification {
+    Notification {
+        id,
+        user_id,
+        kind,
+        title,
+        body,
+        data: data.0,
+        created_at: DateTime::from_timestamp(created_at, 0).unwrap_or_default(),
+        read_at: read_at.and_then(|read_at| DateTime::from_timestamp(read_at, 0)),
+    }
+}
+
+/// Notifications persisted in SQLite.
+#[derive(Debug, Clone)]
+pub struct Notifications {
+    db: Pool<Sqlite>,
+    sender: broadcast::Sender<Notification>,
+}
+
+impl Notifications {
+    /// Opens the store, creating the `_notifications` table if it does not exist.
+    pub async fn open(db: Pool<Sqlite>) -> Result<Self, sqlx::Error> {
+        sqlx::query(&create_notifications_table().to_string(SqliteQueryBuilder))
+            .execute(&db)
+            .await?;
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS _notifications_user_id_idx \
+             ON _notifications (user_id, id)",
+        )
+        .execute(&db)
+        .await?;
+
+        Ok(Self {
+            db,
+            sender: broadcast::channel(FEED_CAPACITY).0,
+        })
+    }
+
+    /// Receives every notification created from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Notification> {
+        self.sender.subscribe()
+    }
+
+    /// Creates a notification and publishes it to the subscribers.
+    pub async fn create(
+        &self,
+        new: NewNotification,
+        now: DateTime<Utc>,
+    ) -> Result<Notification, sqlx::Error> {
+        let id: i64 = sqlx::query_scalar(
+            "INSERT INTO _notifications (user_id, kind, title, body, data, created_at) \
+             VALUES (?, ?, ?, ?, ?, ?) RETURNING id",
+        )
+        .bind(&new.user_id)
+        .bind(&new.kind)
+        .bind(&new.title)
+        .bind(&new.body)
+        .bind(SqlJson(&new.data))
+        .bind(now.timestamp())
+        .fetch_one(&self.db)
+        .await?;
+
+        let notification = Notification {
+            id,
+            user_id: new.user_id,
+            kind: new.kind,
+            title: new.title,
+            body: new.body,
+            data: new.data,
+            created_at: DateTime::from_timestamp(now.timestamp(), 0).unwrap_or_default(),
+            read_at: None,
+        };
+        let _ = self.sender.send(notification.clone());
+        Ok(notification)
+    }
+
+    /// Lists the notifications of `user_id`, newest first.
+    pub async fn list(
+        &self,
+        user_id: &str,
+        filter: &NotificationFilter,
+    ) -> Result<Vec<Notification>, sqlx::Error> {
+        let rows: Vec<NotificationRow> = sqlx::query_as(&format!(
+            "SELECT {NOTIFICATION_COLUMNS} FROM _notifications \
+             WHERE user_id = ?1 AND (NOT ?2 OR read_at IS NULL) AND (?3 IS NULL OR id < ?3) \
+             ORDER BY id DESC LIMIT ?4"
+        ))
+        .bind(user_id)
+        .bind(filter.unread)
+        .bind(filter.before)
+        .bind(i64::from(
+            filter
+                .limit
+                .unwrap_or(DEFAULT_LIST_LIMIT)
+                .min(MAX_LIST_LIMIT),
+        ))
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(rows.into_iter().map(notification).collect())
+    }
+
+    /// Counts the unread notifications of `user_id`.
+    pub async fn unread_count(&self, user_id: &str) -> Result<u64, sqlx::Error> {
+        let count: i64 = sqlx::query_scalar(
+            "SELECT count(*) FROM _notifications WHERE user_id = ? AND read_at IS NULL",
+        )
+        .bind(user_id)
+        .fetch_one(&self.db)
+        .await?;
+        Ok(count as u64)
+    }
+
+    /// Marks a notification of `user_id` read, returning whether it exists. Reading it
+    /// again keeps the first read time.
+    pub async fn mark_read(
+        &self,
+        user_id: &str,
+        id: i64,
+        now: DateTime<Utc>,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            "UPDATE _notifications SET read_at = coalesce(read_at, ?) \
+             WHERE id = ? AND user_id = ?",
+        )
+        .bind(now.timestamp())
+        .bind(id)
+        .bind(user_id)
+        .execute(&self.db)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Marks every unread notification of `user_id` read, returning how many there were.
+    pub async fn mark_all_read(
+        &self,
+        user_id: &str,
+        now: DateTime<Utc>,
+    ) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query(
+            "UPDATE _notifications SET read_at = ? WHERE user_id = ? AND read_at IS NULL",
+        )
+        .bind(now.timestamp())
+        .bind(user_id)
+        .execute(&self.db)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Deletes a notification of `user_id`, returning whether it existed.
+    pub async fn remove(&self, user_id: &str, id: i64) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM _notifications WHERE id = ? AND user_id = ?")
+            .bind(id)
+            .bind(user_id)
+            .execute(&self.db)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[sqlx::test]
+    async fn test_notifications(db: Pool<Sqlite>) -> sqlx::Result<()> {
+        let store = Notifications::open(db).await?;
+        let mut feed = store.subscribe();
+        let now = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+
+        let first = store
+            .create(
+                NewNotification::new("ann", "comment", "Bob replied")
+                    .with_body("Nice post!")
+                    .with_data(json!({ "post_id": 42 })),
+                now,
+            )
+            .await?;
+        let second = store
+            .create(
+                NewNotification::new("ann", "mention", "Eve mentioned you"),
+                now,
+            )
+            .await?;
+        store
+            .create(NewNotification::new("bob", "comment", "Ann replied"), now)
+            .await?;
+
+        assert_eq!(feed.recv().await.unwrap(), first);
+        assert_eq!(feed.recv().await.unwrap().id, second.id);
+        assert_eq!(channel(&first.user_id), "notifications:ann");
+
+        let listed = store.list("ann", &NotificationFilter::default()).await?;
+        assert_eq!(listed, [second.clone(), first.clone()]);
+        assert_eq!(store.unread_count("ann").await?, 2);
+
+        // Other users' notifications can't be touched.
+        assert!(!store.mark_read("bob", first.id, now).await?);
+        assert!(store.mark_read("ann", first.id, now).await?);
+        let unread = NotificationFilter {
+            unread: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            store.list("ann", &unread).await?,
+            std::slice::from_ref(&second)
+        );
+        let older = NotificationFilter {
+            before: Some(second.id),
+            ..Default::default()
+        };
+        assert_eq!(store.list("ann", &older).await?[0].read_at, Some(now));
+        let one = NotificationFilter {
+            limit: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(
+            store.list("ann", &one).await?,
+            std::slice::from_ref(&second)
+        );
+
+        assert_eq!(store.mark_all_read("ann", now).await?, 1);
+        assert_eq!(store.unread_count("ann").await?, 0);
+        assert_eq!(store.unread_count("bob").await?, 1);
+
+        assert!(store.remove("ann", first.id).await?);
+        assert!(!store.remove("ann", first.id).await?);
+        assert_eq!(
+            store
+                .list("ann", &NotificationFilter::default())
+                .await?
+                .len(),
+            1
+        );
+        Ok(())
+    }
+}
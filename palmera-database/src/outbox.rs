@@ -0,0 +1,503 @@
+//! # Transactional outbox
+//!
+//! [`RecordHooks`](crate::embedded::RecordHooks) run in memory after a commit, so a
+//! change is never announced if the process stops in between. With an [`Outbox`] attached through
+//! [`PalmeraClient::with_outbox`](crate::embedded::PalmeraClient::with_outbox), every
+//! change is also written to the SQLite `_outbox` table in the transaction making it:
+//! the event exists if and only if the change was committed.
+//!
+//! The Postgres record routes, `POST /batch`, the sync pushes and patches and
+//! `POST /{schema}/{table}/{id}/duplicate`, record their changes the same way when an
+//! `Extension<Arc<Outbox>>` opened with [`Outbox::open_postgres`] is layered on them:
+//! the events are written to the `palmera_outbox` table of the Postgres database, in the
+//! transaction making the changes, and relayed from there. Sandboxed writes are rolled
+//! back together with their events. The events name the user and actor of the request's
+//! [`SessionContext`](crate::postgres::session::SessionContext).
+//!
+//! A relay, [`spawn_outbox_relay`], hands pending events in order to the publishers
+//! registered with [`Outbox::with_publisher`], e.g. an event bus or a webhook sender,
+//! and deletes each event once every publisher accepted it. A failing event is retried
+//! with exponential backoff and holds back the events after it, up to
+//! [`Outbox::with_max_attempts`] attempts; it is then left in the table as a dead
+//! letter, see [`Outbox::dead_letters`].
+//!
+//! Delivery is at least once: an event whose publishers succeeded is delivered again if
+//! the process stops before it is deleted, or if another publisher failed. Consumers
+//! deduplicate by [`OutboxEvent::id`].
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # async fn run(db: sqlx::Pool<sqlx::Sqlite>) -> Result<(), palmera_database::embedded::ClientError> {
+//! use std::{sync::Arc, time::Duration};
+//! use palmera_database::{
+//!     embedded::PalmeraClient,
+//!     outbox::{Outbox, spawn_outbox_relay},
+//! };
+//!
+//! let outbox = Arc::new(Outbox::open(db.clone()).await?.with_publisher(|event| async move {
+//!     tracing::info!(id = event.id, table = %event.change.table, "record changed");
+//!     Ok(())
+//! }));
+//! let client = PalmeraClient::open(db).await?.with_outbox(outbox.clone());
+//!
+//! spawn_outbox_relay(outbox, Duration::from_secs(5));
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{future::Future, pin::Pin, sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use sea_query::SqliteQueryBuilder;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, Pool, Postgres, Sqlite, SqliteConnection, types::Json};
+use tokio::{sync::Notify, task::JoinHandle};
+
+use crate::{embedded::RecordChange, sqlite::helpers::create_outbox_table};
+
+/// Events handed to the publishers per relay pass unless configured otherwise.
+pub const DEFAULT_BATCH_SIZE: u32 = 100;
+
+/// Attempts made to deliver an event unless configured otherwise.
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 10;
+
+/// Longest wait between two attempts at delivering an event.
+const MAX_BACKOFF: Duration = Duration::from_secs(3600);
+
+/// Error returned by a publisher.
+pub type PublishError = Box<dyn std::error::Error + Send + Sync>;
+
+type PublishFuture = Pin<Box<dyn Future<Output = Result<(), PublishError>> + Send>>;
+type Publisher = Box<dyn Fn(OutboxEvent) -> PublishFuture + Send + Sync>;
+
+/// A committed change waiting to be published.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct OutboxEvent {
+    /// Increases with every event, in commit order.
+    pub id: i64,
+    pub change: RecordChange,
+    /// Failed attempts at delivering the event.
+    pub attempts: u32,
+    /// Error of the last failed attempt.
+    pub error: Option<String>,
+    /// When the relay hands the event to the publishers next.
+    pub next_attempt: DateTime<Utc>,
+    pub created: DateTime<Utc>,
+}
+
+type EventRow = (i64, Json<RecordChange>, i64, Option<String>, i64, i64);
+
+const EVENT_COLUMNS: &str = "id, change, attempts, error, next_attempt, created";
+
+const CREATE_POSTGRES_TABLE: &str = "CREATE TABLE IF NOT EXISTS public.palmera_outbox (\
+     id bigserial PRIMARY KEY, change jsonb NOT NULL, attempts bigint NOT NULL DEFAULT 0, \
+     error text, next_attempt bigint NOT NULL, created bigint NOT NULL)";
+
+/// The database holding the events.
+#[derive(Debug)]
+enum Store {
+    Sqlite(Pool<Sqlite>),
+    Postgres(Pool<Postgres>),
+}
+
+impl Store {
+    fn table(&self) -> &'static str {
+        match self {
+            Store::Sqlite(_) => "_outbox",
+            Store::Postgres(_) => "public.palmera_outbox",
+        }
+    }
+}
+
+/// Runs `$body` with `$db` bound to the pool of `$store`, whichever database it is.
+/// Statements use `$1` style placeholders, which both databases accept.
+macro_rules! on_store {
+    ($store:expr, $db:ident => $body:expr) => {
+        match $store {
+            Store::Sqlite($db) => $body,
+            Store::Postgres($db) => $body,
+        }
+    };
+}
+
+/// Outbox of record changes persisted in SQLite or Postgres.
+pub struct Outbox {
+    db: Store,
+    publishers: Vec<Publisher>,
+    batch_size: u32,
+    max_attempts: u32,
+    /// Wakes the relay once a change with an event is committed.
+    pending: Notify,
+}
+
+impl std::fmt::Debug for Outbox {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Outbox")
+            .field("publishers", &self.publishers.len())
+            .field("batch_size", &self.batch_size)
+            .field("max_attempts", &self.max_attempts)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Outbox {
+    /// Opens the outbox, creating the `_outbox` table if it does not exist.
+    pub async fn open(db: Pool<Sqlite>) -> Result<Self, sqlx::Error> {
+        sqlx::query(&create_outbox_table().to_string(SqliteQueryBuilder))
+            .execute(&db)
+            .await?;
+
+        Ok(Self::new(Store::Sqlite(db)))
+    }
+
+    /// Opens the outbox of the changes made through the Postgres routes, creating the
+    /// `palmera_outbox` table in Postgres if it does not exist.
+    pub async fn open_postgres(db: Pool<Postgres>) -> Result<Self, sqlx::Error> {
+        sqlx::query(CREATE_POSTGRES_TABLE).execute(&db).await?;
+
+        Ok(Self::new(Store::Postgres(db)))
+    }
+
+    fn new(db: Store) -> Self {
+        Self {
+            db,
+            publishers: vec![],
+            batch_size: DEFAULT_BATCH_SIZE,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            pending: Notify::new(),
+        }
+    }
+
+    /// Adds a publisher every event is handed to.
+    pub fn with_publisher<F, Fut>(mut self, publisher: F) -> Self
+    where
+        F: Fn(OutboxEvent) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), PublishError>> + Send + 'static,
+    {
+        self.publishers
+            .push(Box::new(move |event| Box::pin(publisher(event))));
+        self
+    }
+
+    pub fn with_batch_size(mut self, batch_size: u32) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Writes `change` to the outbox on `conn`, which should be the transaction making
+    /// the change.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`sqlx::Error::Configuration`] if the outbox was opened on Postgres.
+    pub async fn record(
+        &self,
+        conn: &mut SqliteConnection,
+        change: &RecordChange,
+    ) -> Result<i64, sqlx::Error> {
+        if !matches!(self.db, Store::Sqlite(_)) {
+            return Err(sqlx::Error::Configuration(
+                "the outbox is not stored in SQLite".into(),
+            ));
+        }
+        sqlx::query_scalar(&self.insert_event())
+            .bind(Json(change))
+            .bind(Utc::now().timestamp_millis())
+            .fetch_one(conn)
+            .await
+    }
+
+    /// Writes `change` to the outbox on `conn`, which should be the Postgres
+    /// transaction making the change.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`sqlx::Error::Configuration`] unless the outbox was opened with
+    /// [`Outbox::open_postgres`].
+    pub async fn record_postgres(
+        &self,
+        conn: &mut PgConnection,
+        change: &RecordChange,
+    ) -> Result<i64, sqlx::Error> {
+        if !matches!(self.db, Store::Postgres(_)) {
+            return Err(sqlx::Error::Configuration(
+                "the outbox is not stored in Postgres".into(),
+            ));
+        }
+        sqlx::query_scalar(&self.insert_event())
+            .bind(Json(change))
+            .bind(Utc::now().timestamp_millis())
+            .fetch_one(conn)
+            .await
+    }
+
+    fn insert_event(&self) -> String {
+        format!(
+            "INSERT INTO {} (change, created, next_attempt) VALUES ($1, $2, $2) RETURNING id",
+            self.db.table()
+        )
+    }
+
+    /// Wakes the relay, e.g. after committing a transaction that recorded events.
+    pub fn wake(&self) {
+        self.pending.notify_one();
+    }
+
+    /// Events not delivered yet, oldest first, including dead letters.
+    pub async fn pending(&self) -> Result<Vec<OutboxEvent>, sqlx::Error> {
+        let sql = format!(
+            "SELECT {EVENT_COLUMNS} FROM {} ORDER BY id",
+            self.db.table()
+        );
+        let rows: Vec<EventRow> =
+            on_store!(&self.db, db => sqlx::query_as(&sql).fetch_all(db).await?);
+
+        Ok(rows.into_iter().map(into_event).collect())
+    }
+
+    /// Events that ran out of attempts, oldest first.
+    pub async fn dead_letters(&self) -> Result<Vec<OutboxEvent>, sqlx::Error> {
+        let sql = format!(
+            "SELECT {EVENT_COLUMNS} FROM {} WHERE attempts >= $1 ORDER BY id",
+            self.db.table()
+        );
+        let rows: Vec<EventRow> = on_store!(&self.db, db => {
+            sqlx::query_as(&sql)
+                .bind(i64::from(self.max_attempts))
+                .fetch_all(db)
+                .await?
+        });
+
+        Ok(rows.into_iter().map(into_event).collect())
+    }
+
+    /// Gives the dead letter `id` a fresh set of attempts, returning whether it existed.
+    pub async fn retry(&self, id: i64) -> Result<bool, sqlx::Error> {
+        let sql = format!(
+            "UPDATE {} SET attempts = 0, next_attempt = $1 WHERE id = $2 AND attempts >= $3",
+            self.db.table()
+        );
+        let retried = on_store!(&self.db, db => {
+            sqlx::query(&sql)
+                .bind(Utc::now().timestamp_millis())
+                .bind(id)
+                .bind(i64::from(self.max_attempts))
+                .execute(db)
+                .await?
+                .rows_affected()
+        }) > 0;
+        if retried {
+            self.wake();
+        }
+        Ok(retried)
+    }
+
+    /// Hands up to a batch of due events to the publishers in order, returning how many
+    /// were delivered. The first event that fails is scheduled for a retry and ends the
+    /// pass, so events are never delivered out of order.
+    pub async fn relay(&self, now: DateTime<Utc>) -> Result<usize, sqlx::Error> {
+        let sql = format!(
+            "SELECT {EVENT_COLUMNS} FROM {} WHERE attempts < $1 ORDER BY id LIMIT $2",
+            self.db.table()
+        );
+        let rows: Vec<EventRow> = on_store!(&self.db, db => {
+            sqlx::query_as(&sql)
+                .bind(i64::from(self.max_attempts))
+                .bind(i64::from(self.batch_size))
+                .fetch_all(db)
+                .await?
+        });
+
+        let mut delivered = 0;
+        for event in rows.into_iter().map(into_event) {
+            if event.next_attempt > now {
+                break;
+            }
+            let id = event.id;
+
+            match self.publish(event.clone()).await {
+                Ok(()) => {
+                    on_store!(&self.db, db => {
+                        sqlx::query(&format!("DELETE FROM {} WHERE id = $1", self.db.table()))
+                            .bind(id)
+                            .execute(db)
+                            .await?;
+                    });
+                    delivered += 1;
+                }
+                Err(err) => {
+                    let attempts = event.attempts + 1;
+                    let backoff = Duration::from_secs(1 << attempts.min(12)).min(MAX_BACKOFF);
+                    let next_attempt = now + backoff;
+                    tracing::warn!(event = id, attempts, error = %err, "outbox delivery failed");
+                    let sql = format!(
+                        "UPDATE {} SET attempts = $1, error = $2, next_attempt = $3 WHERE id = $4",
+                        self.db.table()
+                    );
+                    on_store!(&self.db, db => {
+                        sqlx::query(&sql)
+                            .bind(i64::from(attempts))
+                            .bind(err.to_string())
+                            .bind(next_attempt.timestamp_millis())
+                            .bind(id)
+                            .execute(db)
+                            .await?;
+                    });
+                    break;
+                }
+            }
+        }
+
+        Ok(delivered)
+    }
+
+    async fn publish(&self, event: OutboxEvent) -> Result<(), PublishError> {
+        for publisher in &self.publishers {
+            publisher(event.clone()).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes `changes` to `outbox`, if one is attached, on the Postgres transaction
+/// making them.
+pub(crate) async fn record_changes(
+    outbox: Option<&Outbox>,
+    conn: &mut PgConnection,
+    changes: impl IntoIterator<Item = RecordChange>,
+) -> Result<(), sqlx::Error> {
+    if let Some(outbox) = outbox {
+        for change in changes {
+            outbox.record_postgres(conn, &change).await?;
+        }
+    }
+    Ok(())
+}
+
+fn into_event((id, Json(change), attempts, error, next_attempt, created): EventRow) -> OutboxEvent {
+    OutboxEvent {
+        id,
+        change,
+        attempts: attempts as u32,
+        error,
+        next_attempt: DateTime::from_timestamp_millis(next_attempt).unwrap_or_default(),
+        created: DateTime::from_timestamp_millis(created).unwrap_or_default(),
+    }
+}
+
+/// Relays outbox events whenever a change is committed through a client using
+/// `outbox`, and at least every `interval` to retry failed events.
+///
+/// Failures are logged and retried on the next pass.
+pub fn spawn_outbox_relay(outbox: Arc<Outbox>, interval: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                _ = outbox.pending.notified() => {}
+            }
+
+            // Keep going while full batches are delivered.
+            loop {
+                match outbox.relay(Utc::now()).await {
+                    Ok(delivered) if delivered == outbox.batch_size as usize => {}
+                    Ok(delivered) => {
+                        if delivered > 0 {
+                            tracing::debug!(delivered, "outbox events relayed");
+                        }
+                        break;
+                    }
+                    Err(err) => {
+                        tracing::warn!(error = %err, "outbox relay failed");
+                        break;
+                    }
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embedded::{ChangeAction, PalmeraClient};
+    use serde_json::json;
+    use std::sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    };
+
+    #[sqlx::test]
+    async fn test_relay(db: Pool<Sqlite>) -> Result<(), crate::embedded::ClientError> {
+        sqlx::query("CREATE TABLE notes (id INTEGER PRIMARY KEY, title TEXT NOT NULL)")
+            .execute(&db)
+            .await?;
+        let published = Arc::new(Mutex::new(Vec::new()));
+        let failing = Arc::new(AtomicBool::new(true));
+        let (seen, fail) = (published.clone(), failing.clone());
+        let outbox = Arc::new(
+            Outbox::open(db.clone())
+                .await?
+                .with_max_attempts(2)
+                .with_publisher(move |event| {
+                    let (seen, fail) = (seen.clone(), fail.clone());
+                    async move {
+                        if fail.load(Ordering::SeqCst) {
+                            return Err("bus unavailable".into());
+                        }
+                        seen.lock().unwrap().push((event.id, event.change.action));
+                        Ok(())
+                    }
+                }),
+        );
+        let client = PalmeraClient::open(db.clone())
+            .await?
+            .with_outbox(outbox.clone());
+        let session = client.anonymous();
+
+        let note = session.create("notes", json!({ "title": "a" })).await?;
+        session
+            .update("notes", note["id"].clone(), json!({ "title": "b" }))
+            .await?;
+        // A rolled back write leaves no event behind.
+        assert!(session.create("notes", json!({})).await.is_err());
+        assert_eq!(outbox.pending().await?.len(), 2);
+
+        let now = Utc::now();
+        assert_eq!(outbox.relay(now).await?, 0);
+        let pending = outbox.pending().await?;
+        assert_eq!(pending[0].attempts, 1);
+        assert_eq!(pending[0].error.as_deref(), Some("bus unavailable"));
+        // Not due again until the backoff passed.
+        assert_eq!(outbox.relay(now).await?, 0);
+        assert_eq!(outbox.pending().await?[0].attempts, 1);
+
+        // The second failure turns the event into a dead letter and unblocks the next.
+        let later = now + Duration::from_secs(60);
+        assert_eq!(outbox.relay(later).await?, 0);
+        assert_eq!(outbox.dead_letters().await?.len(), 1);
+
+        failing.store(false, Ordering::SeqCst);
+        let later = later + Duration::from_secs(60);
+        assert_eq!(outbox.relay(later).await?, 1);
+        assert!(outbox.retry(pending[0].id).await?);
+        assert_eq!(outbox.relay(Utc::now()).await?, 1);
+        assert!(outbox.pending().await?.is_empty());
+        assert_eq!(
+            *published.lock().unwrap(),
+            [
+                (pending[1].id, ChangeAction::Update),
+                (pending[0].id, ChangeAction::Create)
+            ]
+        );
+        Ok(())
+    }
+}
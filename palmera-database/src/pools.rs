@@ -0,0 +1,270 @@
+//! # Connection pool partitioning
+//!
+//! A single pool shared by everything lets an export or a retention run that holds its
+//! connections for minutes starve the request handlers. [`PoolPartitions`] opens one
+//! pool per [`Workload`] against the same database, each with its own [`PoolConfig`]:
+//!
+//! | Workload | Used by | Default size |
+//! |----------|---------|--------------|
+//! | [`Workload::Api`] | request handlers | 10 connections, 5s acquire timeout |
+//! | [`Workload::Background`] | retention, column jobs, the outbox relay, imports | 4 connections, 60s |
+//! | [`Workload::Realtime`] | CDC and sync consumers holding a connection open | 2 connections, 30s |
+//!
+//! Hand each component the pool of its workload: the API pool as the routers'
+//! `Extension`, the background pool to stores running jobs, and so on. Exhausting one
+//! partition then only slows down that workload.
+//!
+//! Each partition opens its own connections, so an in-memory SQLite database, which
+//! exists per connection, can't be partitioned.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # async fn run() -> Result<(), sqlx::Error> {
+//! use std::time::Duration;
+//! use axum::{Extension, Router};
+//! use palmera_database::pools::{PoolConfig, PoolPartitions, Workload};
+//! use sqlx::Postgres;
+//!
+//! let pools = PoolPartitions::<Postgres>::new()
+//!     .with_config(Workload::Background, PoolConfig::new(8).with_acquire_timeout(Duration::from_secs(120)))
+//!     .connect("postgres://localhost/app")
+//!     .await?;
+//!
+//! let app: Router = Router::new().layer(Extension(pools.api().clone()));
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{collections::HashMap, str::FromStr, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use sqlx::{Connection, Database, Pool, pool::PoolOptions};
+use utoipa::ToSchema;
+
+/// Kind of work a pool serves.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Workload {
+    /// Interactive API requests.
+    Api,
+    /// Jobs and long running queries.
+    Background,
+    /// Consumers keeping a connection for change streams.
+    Realtime,
+}
+
+impl Workload {
+    pub const ALL: [Workload; 3] = [Self::Api, Self::Background, Self::Realtime];
+}
+
+/// Size and timeouts of a pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolConfig {
+    pub max_connections: u32,
+    /// Connections kept open while idle.
+    pub min_connections: u32,
+    /// Longest a caller waits for a connection before getting [`sqlx::Error::PoolTimedOut`].
+    pub acquire_timeout: Duration,
+    /// Idle connections above the minimum are closed after this long.
+    pub idle_timeout: Option<Duration>,
+}
+
+impl PoolConfig {
+    pub fn new(max_connections: u32) -> Self {
+        Self {
+            max_connections,
+            min_connections: 0,
+            acquire_timeout: Duration::from_secs(30),
+            idle_timeout: Some(Duration::from_secs(600)),
+        }
+    }
+
+    pub fn with_min_connections(mut self, min_connections: u32) -> Self {
+        self.min_connections = min_connections;
+        self
+    }
+
+    pub fn with_acquire_timeout(mut self, timeout: Duration) -> Self {
+        self.acquire_timeout = timeout;
+        self
+    }
+
+    pub fn with_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Default configuration of `workload`.
+    pub fn default_for(workload: Workload) -> Self {
+        match workload {
+            Workload::Api => Self::new(10).with_acquire_timeout(Duration::from_secs(5)),
+            Workload::Background => Self::new(4).with_acquire_timeout(Duration::from_secs(60)),
+            Workload::Realtime => Self::new(2),
+        }
+    }
+
+    fn options<DB: Database>(&self) -> PoolOptions<DB> {
+        PoolOptions::new()
+            .max_connections(self.max_connections)
+            .min_connections(self.min_connections)
+            .acquire_timeout(self.acquire_timeout)
+            .idle_timeout(self.idle_timeout)
+    }
+}
+
+/// Usage of a partition, e.g. for a status page.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+pub struct PoolStats {
+    pub workload: Workload,
+    pub max_connections: u32,
+    /// Open connections, in use or idle.
+    pub size: u32,
+    pub idle: u32,
+}
+
+/// Configuration of the pools to open per workload.
+#[derive(Debug, Clone)]
+pub struct PoolPartitions<DB: Database> {
+    configs: HashMap<Workload, PoolConfig>,
+    marker: std::marker::PhantomData<DB>,
+}
+
+impl<DB: Database> Default for PoolPartitions<DB> {
+    fn default() -> Self {
+        Self {
+            configs: Workload::ALL
+                .into_iter()
+                .map(|workload| (workload, PoolConfig::default_for(workload)))
+                .collect(),
+            marker: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<DB: Database> PoolPartitions<DB> {
+    /// Partitions with the default configuration of each workload.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_config(mut self, workload: Workload, config: PoolConfig) -> Self {
+        self.configs.insert(workload, config);
+        self
+    }
+
+    pub fn config(&self, workload: Workload) -> PoolConfig {
+        self.configs[&workload]
+    }
+
+    /// Opens the pools against the database at `url`.
+    pub async fn connect(&self, url: &str) -> Result<Pools<DB>, sqlx::Error> {
+        let options = <DB::Connection as Connection>::Options::from_str(url)?;
+        self.connect_with(options).await
+    }
+
+    /// Opens the pools with `options`.
+    pub async fn connect_with(
+        &self,
+        options: <DB::Connection as Connection>::Options,
+    ) -> Result<Pools<DB>, sqlx::Error> {
+        let mut pools = HashMap::new();
+        for workload in Workload::ALL {
+            let config = self.config(workload);
+            let pool = config.options().connect_with(options.clone()).await?;
+            pools.insert(workload, (config, pool));
+        }
+        Ok(Pools { pools })
+    }
+}
+
+/// One pool per workload, all connected to the same database.
+#[derive(Debug, Clone)]
+pub struct Pools<DB: Database> {
+    pools: HashMap<Workload, (PoolConfig, Pool<DB>)>,
+}
+
+impl<DB: Database> Pools<DB> {
+    pub fn get(&self, workload: Workload) -> &Pool<DB> {
+        &self.pools[&workload].1
+    }
+
+    /// Pool of request handlers.
+    pub fn api(&self) -> &Pool<DB> {
+        self.get(Workload::Api)
+    }
+
+    /// Pool of jobs and long running queries.
+    pub fn background(&self) -> &Pool<DB> {
+        self.get(Workload::Background)
+    }
+
+    /// Pool of change stream consumers.
+    pub fn realtime(&self) -> &Pool<DB> {
+        self.get(Workload::Realtime)
+    }
+
+    /// Usage of every partition, in the order of [`Workload::ALL`].
+    pub fn stats(&self) -> Vec<PoolStats> {
+        Workload::ALL
+            .into_iter()
+            .map(|workload| {
+                let (config, pool) = &self.pools[&workload];
+                PoolStats {
+                    workload,
+                    max_connections: config.max_connections,
+                    size: pool.size(),
+                    idle: pool.num_idle() as u32,
+                }
+            })
+            .collect()
+    }
+
+    /// Closes every pool, waiting for connections in use to be returned.
+    pub async fn close(&self) {
+        for (_, pool) in self.pools.values() {
+            pool.close().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::{Sqlite, sqlite::SqliteConnectOptions};
+
+    #[sqlx::test]
+    async fn test_background_work_cannot_exhaust_api(
+        _: sqlx::pool::PoolOptions<Sqlite>,
+        options: SqliteConnectOptions,
+    ) -> sqlx::Result<()> {
+        let pools = PoolPartitions::<Sqlite>::new()
+            .with_config(
+                Workload::Background,
+                PoolConfig::new(1).with_acquire_timeout(Duration::from_millis(50)),
+            )
+            .with_config(Workload::Api, PoolConfig::new(2))
+            .connect_with(options)
+            .await?;
+
+        let _job = pools.background().acquire().await?;
+        assert!(matches!(
+            pools.background().acquire().await,
+            Err(sqlx::Error::PoolTimedOut)
+        ));
+
+        let answer: i64 = sqlx::query_scalar("SELECT 42")
+            .fetch_one(pools.api())
+            .await?;
+        assert_eq!(answer, 42);
+
+        let stats = pools.stats();
+        assert_eq!(stats[1].workload, Workload::Background);
+        assert_eq!(
+            (stats[1].max_connections, stats[1].size, stats[1].idle),
+            (1, 1, 0)
+        );
+        assert_eq!(stats[2].max_connections, 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,820 @@
+//! # Transaction batches
+//!
+//! `POST /batch` runs an ordered list of create, update and delete operations across
+//! tables in a single transaction. Either every operation is applied or none is: the
+//! response lists the resulting record of each operation in order, or the first one
+//! that failed and why.
+//!
+//! Records are identified by their [`PRIMARY_KEY`] column. Values are JSON and are
+//! converted to the column types with `jsonb_populate_record`, so an operation can only
+//! write columns that exist on its table.
+//!
+//! When a [`SessionContext`] extension is present, the batch runs in its session so
+//! row level security and the statement timeout apply. With an
+//! [`Outbox`](crate::outbox::Outbox) extension, each operation's change is recorded in
+//! the outbox in the batch's transaction.
+//!
+//! With a [`Quotas`](crate::quotas::Quotas) extension, the records the batch creates are reserved against the
+//! [`RecordQuota`]'s limits before it runs, and the batch is rejected with `402 Payment
+//! Required` if a table has no room. Records the batch deletes are given back once it
+//! commits.
+//!
+//! With an [`Exposures`] extension, every operation is checked against its table's
+//! [`TableExposure`](crate::exposure::TableExposure) before the batch runs: operations on
+//! disabled tables fail with `404 Not Found` and those on read-only tables with `405 Method
+//! Not Allowed`, like the REST API's own routes. Internal tables, whose names start with
+//! `_`, and the Postgres system schemas are never reachable through a batch.
+//!
+//! In [`sandbox`] mode the batch runs and returns its results, but is rolled back and
+//! its reservation given back.
+//!
+//! # Example
+//!
+//! ```json
+//! [
+//!   { "op": "create", "table": "orders", "data": { "id": 7, "total": 30 } },
+//!   { "op": "update", "table": "stock", "id": 3, "data": { "quantity": 9 } },
+//!   { "op": "delete", "table": "carts", "id": 12 }
+//! ]
+//! ```
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use axum::{Extension, Json, http::StatusCode, middleware};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, Pool, Postgres, types::Json as SqlJson};
+use utoipa::ToSchema;
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+use crate::{
+    constraint::ConstraintViolation,
+    embedded::{ChangeAction, RecordChange},
+    exposure::{Exposures, TableExposure},
+    outbox::{self, Outbox},
+    postgres::{
+        helpers::{quote_ident, quote_qualified},
+        sandbox::{self, Sandbox},
+        session::{SessionContext, begin_session},
+    },
+    quotas::{QuotaError, RecordQuota},
+};
+
+/// Column identifying the records of an update or delete.
+pub const PRIMARY_KEY: &str = "id";
+
+/// Most operations accepted in one batch.
+pub const MAX_OPERATIONS: usize = 100;
+
+type Record = serde_json::Map<String, serde_json::Value>;
+
+/// A write in a batch.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Operation {
+    /// Inserts `data` into `table`.
+    Create {
+        table: String,
+        #[schema(value_type = Object)]
+        data: Record,
+    },
+    /// Sets the columns in `data` on the record of `table` identified by `id`.
+    Update {
+        table: String,
+        #[schema(value_type = Object)]
+        id: serde_json::Value,
+        #[schema(value_type = Object)]
+        data: Record,
+    },
+    /// Deletes the record of `table` identified by `id`.
+    Delete {
+        table: String,
+        #[schema(value_type = Object)]
+        id: serde_json::Value,
+    },
+}
+
+/// The record written by an operation, after defaults and triggers ran. For deletes it
+/// is the removed record.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct OperationResult {
+    pub index: usize,
+    #[schema(value_type = Object)]
+    pub record: serde_json::Value,
+}
+
+/// The operation that aborted a batch.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct BatchFailure {
+    /// Position of the operation in the batch, or `None` if the batch itself was
+    /// rejected.
+    pub index: Option<usize>,
+    pub status: u16,
+    pub message: String,
+    /// The constraint the operation violated, if that is why it failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub violation: Option<ConstraintViolation>,
+}
+
+impl BatchFailure {
+    fn new(index: Option<usize>, status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            index,
+            status: status.as_u16(),
+            message: message.into(),
+            violation: None,
+        }
+    }
+
+    fn from_sqlx(index: usize, err: sqlx::Error) -> Self {
+        if let Some(violation) = ConstraintViolation::from_sqlx(&err) {
+            return Self {
+                violation: Some(violation.clone()),
+                ..Self::new(Some(index), violation.status(), violation.message)
+            };
+        }
+
+        let code = err
+            .as_database_error()
+            .and_then(|err| err.code())
+            .map(|code| code.into_owned())
+            .unwrap_or_default();
+
+        let status = match code.as_str() {
+            "23505" => StatusCode::CONFLICT,
+            "42501" => StatusCode::FORBIDDEN,
+            "42P01" => StatusCode::NOT_FOUND,
+            code if code.starts_with("22") || code.starts_with("23") || code == "42703" => {
+                StatusCode::BAD_REQUEST
+            }
+            _ => {
+                tracing::error!(%err, index, "batch operation failed");
+                return Self::new(
+                    Some(index),
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "internal error",
+                );
+            }
+        };
+        Self::new(Some(index), status, err.to_string())
+    }
+
+    fn from_quota(err: QuotaError) -> Self {
+        if let QuotaError::Database(err) = &err {
+            tracing::error!(%err, "batch quota check failed");
+            return Self::new(None, StatusCode::INTERNAL_SERVER_ERROR, "internal error");
+        }
+        Self::new(None, err.status(), err.to_string())
+    }
+
+    fn status(&self) -> StatusCode {
+        StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
+/// Runs `operations` in order on `conn`, which should be inside a transaction the
+/// caller rolls back on error.
+///
+/// # Errors
+///
+/// Returns the [`BatchFailure`] of the first operation that failed, or that targeted a
+/// record that does not exist.
+pub async fn execute(
+    conn: &mut PgConnection,
+    operations: &[Operation],
+) -> Result<Vec<OperationResult>, BatchFailure> {
+    let mut results = Vec::with_capacity(operations.len());
+
+    for (index, operation) in operations.iter().enumerate() {
+        let record = run(conn, operation)
+            .await
+            .map_err(|err| BatchFailure::from_sqlx(index, err))?
+            .ok_or_else(|| {
+                BatchFailure::new(Some(index), StatusCode::NOT_FOUND, "record not found")
+            })?;
+        results.push(OperationResult { index, record });
+    }
+
+    Ok(results)
+}
+
+async fn run(
+    conn: &mut PgConnection,
+    operation: &Operation,
+) -> Result<Option<serde_json::Value>, sqlx::Error> {
+    let (sql, data, id) = match operation {
+        Operation::Create { table, data } => {
+            let table = quote_qualified(table);
+            let columns = columns(data);
+            (
+                format!(
+                    "INSERT INTO {table} ({columns}) \
+                     SELECT {columns} FROM jsonb_populate_record(NULL::{table}, $1) \
+                     RETURNING to_jsonb({table}.*)"
+                ),
+                Some(data),
+                None,
+            )
+        }
+        Operation::Update { table, id, data } => {
+            let table = quote_qualified(table);
+            let columns = columns(data);
+            (
+                format!(
+                    "UPDATE {table} SET ({columns}) = \
+                     (SELECT {columns} FROM jsonb_populate_record(NULL::{table}, $1)) \
+                     WHERE {} \
+                     RETURNING to_jsonb({table}.*)",
+                    matches_id(&table, "$2")
+                ),
+                Some(data),
+                Some(id),
+            )
+        }
+        Operation::Delete { table, id } => {
+            let table = quote_qualified(table);
+            (
+                format!(
+                    "DELETE FROM {table} WHERE {} RETURNING to_jsonb({table}.*)",
+                    matches_id(&table, "$2")
+                ),
+                None,
+                Some(id),
+            )
+        }
+    };
+
+    let record: Option<SqlJson<serde_json::Value>> = sqlx::query_scalar(&sql)
+        .bind(data.map(SqlJson))
+        .bind(id.map(SqlJson))
+        .fetch_optional(conn)
+        .await?;
+
+    Ok(record.map(|record| record.0))
+}
+
+/// Quoted, comma separated column names of `data`.
+fn columns(data: &Record) -> String {
+    data.keys()
+        .map(|key| quote_ident(key))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Condition matching the record of `table` whose primary key is the JSON value of the
+/// SQL expression `id`, e.g. a placeholder such as `$2`, converted to the key's column
+/// type.
+pub(crate) fn matches_id(table: &str, id: &str) -> String {
+    let key = quote_ident(PRIMARY_KEY);
+    format!(
+        "{key} = (SELECT {key} FROM jsonb_populate_record(NULL::{table}, \
+         jsonb_build_object('{PRIMARY_KEY}', {id}::jsonb)))"
+    )
+}
+
+/// Runs a batch of writes in a single transaction.
+#[utoipa::path(
+    post,
+    path = "/batch",
+    request_body = Vec<Operation>,
+    responses(
+        (status = 200, body = Vec<OperationResult>),
+        (status = 400, body = BatchFailure),
+        (status = 402, body = BatchFailure),
+        (status = 404, body = BatchFailure),
+        (status = 405, body = BatchFailure),
+        (status = 409, body = BatchFailure),
+        (status = 422, body = BatchFailure)
+    )
+)]
+async fn run_batch(
+    Extension(db): Extension<Pool<Postgres>>,
+    session: Option<Extension<SessionContext>>,
+    quota: Option<RecordQuota>,
+    outbox: Option<Extension<Arc<Outbox>>>,
+    exposures: Option<Extension<Arc<Exposures>>>,
+    sandbox: Sandbox,
+    Json(operations): Json<Vec<Operation>>,
+) -> Result<Json<Vec<OperationResult>>, (StatusCode, Json<BatchFailure>)> {
+    let fail = |failure: BatchFailure| (failure.status(), Json(failure));
+
+    if operations.len() > MAX_OPERATIONS {
+        return Err(fail(BatchFailure::new(
+            None,
+            StatusCode::BAD_REQUEST,
+            format!("a batch may hold at most {} operations", MAX_OPERATIONS),
+        )));
+    }
+    if let Some(index) = operations.iter().position(is_empty) {
+        return Err(fail(BatchFailure::new(
+            Some(index),
+            StatusCode::BAD_REQUEST,
+            "data must not be empty",
+        )));
+    }
+    let exposures = exposures.map(|Extension(exposures)| exposures);
+    for (index, operation) in operations.iter().enumerate() {
+        check_exposure(exposures.as_deref(), index, operation).map_err(fail)?;
+    }
+
+    let internal = |err: sqlx::Error| {
+        // Deferred constraints are only checked on commit.
+        if let Some(violation) = ConstraintViolation::from_sqlx(&err) {
+            return fail(BatchFailure {
+                violation: Some(violation.clone()),
+                ..BatchFailure::new(None, violation.status(), violation.message)
+            });
+        }
+        tracing::error!(%err, "batch transaction failed");
+        fail(BatchFailure::new(
+            None,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal error",
+        ))
+    };
+
+    let created = count_records(&operations, |operation| {
+        matches!(operation, Operation::Create { .. })
+    });
+    if let Some(quota) = &quota {
+        quota
+            .reserve(&created)
+            .await
+            .map_err(|err| fail(BatchFailure::from_quota(err)))?;
+    }
+
+    let outbox = outbox.map(|Extension(outbox)| outbox);
+    let committed = async {
+        let (user_id, actor_id) = session
+            .as_ref()
+            .map(|Extension(ctx)| (ctx.user_id.clone(), ctx.actor_id.clone()))
+            .unwrap_or_default();
+        let mut tx = match session {
+            Some(Extension(ctx)) => begin_session(&db, &ctx).await,
+            None => db.begin().await,
+        }
+        .map_err(internal)?;
+
+        // Dropping the transaction on failure rolls every operation back.
+        let results = execute(&mut tx, &operations).await.map_err(fail)?;
+        let changes = operations.iter().zip(&results).map(|(operation, result)| {
+            let (table, action) = match operation {
+                Operation::Create { table, .. } => (table, ChangeAction::Create),
+                Operation::Update { table, .. } => (table, ChangeAction::Update),
+                Operation::Delete { table, .. } => (table, ChangeAction::Delete),
+            };
+            RecordChange {
+                table: table.clone(),
+                action,
+                record: result.record.clone(),
+                user_id: user_id.clone(),
+                actor_id: actor_id.clone(),
+            }
+        });
+        outbox::record_changes(outbox.as_deref(), &mut tx, changes)
+            .await
+            .map_err(internal)?;
+        sandbox.finish(tx).await.map_err(internal)?;
+        Ok(results)
+    }
+    .await;
+    if let (Ok(_), Some(outbox)) = (&committed, &outbox) {
+        outbox.wake();
+    }
+
+    if let Some(quota) = &quota {
+        let deleted = count_records(&operations, |operation| {
+            matches!(operation, Operation::Delete { .. })
+        });
+        quota
+            .settle(
+                &created,
+                &deleted,
+                committed.is_ok() && !sandbox.is_enabled(),
+            )
+            .await;
+    }
+
+    committed.map(Json)
+}
+
+/// Number of operations matching `filter`, by table.
+fn count_records(
+    operations: &[Operation],
+    filter: impl Fn(&Operation) -> bool,
+) -> BTreeMap<String, u64> {
+    let mut counts = BTreeMap::new();
+    for operation in operations.iter().filter(|operation| filter(operation)) {
+        let (Operation::Create { table, .. }
+        | Operation::Update { table, .. }
+        | Operation::Delete { table, .. }) = operation;
+        *counts.entry(table.clone()).or_insert(0) += 1;
+    }
+    counts
+}
+
+/// Fails with `404 Not Found` if the table of `operation` is internal, a system table or
+/// disabled by `exposures`, and with `405 Method Not Allowed` if it is read-only.
+fn check_exposure(
+    exposures: Option<&Exposures>,
+    index: usize,
+    operation: &Operation,
+) -> Result<(), BatchFailure> {
+    let (Operation::Create { table, .. }
+    | Operation::Update { table, .. }
+    | Operation::Delete { table, .. }) = operation;
+    let not_found = || {
+        BatchFailure::new(
+            Some(index),
+            StatusCode::NOT_FOUND,
+            format!("table {:?} does not exist", table),
+        )
+    };
+
+    let (schema, name) = table.split_once('.').unwrap_or(("public", table.as_str()));
+    if name.starts_with('_')
+        || schema.starts_with("pg_")
+        || schema == "information_schema"
+        || name.contains('.')
+    {
+        return Err(not_found());
+    }
+
+    match exposures.and_then(|exposures| exposure_of(exposures, schema, name)) {
+        Some(exposure) if !exposure.enabled => Err(not_found()),
+        Some(exposure) if exposure.read_only => Err(BatchFailure::new(
+            Some(index),
+            StatusCode::METHOD_NOT_ALLOWED,
+            format!("table {:?} is read-only", table),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Settings of `schema.name`, which are stored without the schema for `public` tables
+/// exposed by their bare name.
+fn exposure_of(exposures: &Exposures, schema: &str, name: &str) -> Option<TableExposure> {
+    exposures
+        .get(&format!("{}.{}", schema, name))
+        .or_else(|| (schema == "public").then(|| exposures.get(name)).flatten())
+}
+
+fn is_empty(operation: &Operation) -> bool {
+    match operation {
+        Operation::Create { data, .. } | Operation::Update { data, .. } => data.is_empty(),
+        Operation::Delete { .. } => false,
+    }
+}
+
+pub fn router() -> OpenApiRouter {
+    OpenApiRouter::new()
+        .routes(routes!(run_batch))
+        .layer(middleware::from_fn(sandbox::mark_responses))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quotas::Quotas;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    async fn setup(db: &Pool<Postgres>) -> sqlx::Result<()> {
+        sqlx::query(
+            "CREATE TABLE accounts (id integer PRIMARY KEY, name text NOT NULL, \
+             balance integer NOT NULL DEFAULT 0 CHECK (balance >= 0))",
+        )
+        .execute(db)
+        .await?;
+        sqlx::query("INSERT INTO accounts (id, name, balance) VALUES (1, 'a', 10), (2, 'b', 0)")
+            .execute(db)
+            .await?;
+        Ok(())
+    }
+
+    fn operations(value: serde_json::Value) -> Json<Vec<Operation>> {
+        Json(serde_json::from_value(value).unwrap())
+    }
+
+    async fn balances(db: &Pool<Postgres>) -> sqlx::Result<Vec<(i32, i32)>> {
+        sqlx::query_as("SELECT id, balance FROM accounts ORDER BY id")
+            .fetch_all(db)
+            .await
+    }
+
+    #[sqlx::test]
+    async fn test_batch_commits_all_operations(db: Pool<Postgres>) -> sqlx::Result<()> {
+        setup(&db).await?;
+
+        let Json(results) = run_batch(
+            Extension(db.clone()),
+            None,
+            None,
+            None,
+            None,
+            Sandbox::default(),
+            operations(json!([
+                { "op": "update", "table": "accounts", "id": 1, "data": { "balance": 5 } },
+                { "op": "update", "table": "accounts", "id": "2", "data": { "balance": 5 } },
+                { "op": "create", "table": "accounts", "data": { "id": 3, "name": "c" } },
+                { "op": "delete", "table": "public.accounts", "id": 3 },
+                { "op": "update", "table": "accounts", "id": 2, "data": { "id": 4 } },
+            ])),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(results.len(), 5);
+        assert_eq!(results[1].record["balance"], 5);
+        assert_eq!(
+            results[2].record,
+            json!({ "id": 3, "name": "c", "balance": 0 })
+        );
+        assert_eq!(results[4].index, 4);
+        assert_eq!(balances(&db).await?, [(1, 5), (4, 5)]);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_batch_rolls_back_on_failure(db: Pool<Postgres>) -> sqlx::Result<()> {
+        setup(&db).await?;
+
+        let (status, Json(failure)) = run_batch(
+            Extension(db.clone()),
+            None,
+            None,
+            None,
+            None,
+            Sandbox::default(),
+            operations(json!([
+                { "op": "update", "table": "accounts", "id": 2, "data": { "balance": 20 } },
+                { "op": "update", "table": "accounts", "id": 1, "data": { "balance": -10 } },
+            ])),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(failure.index, Some(1));
+        assert_eq!(
+            failure.violation.unwrap().constraint.as_deref(),
+            Some("accounts_balance_check")
+        );
+        assert_eq!(balances(&db).await?, [(1, 10), (2, 0)]);
+
+        let (status, Json(failure)) = run_batch(
+            Extension(db.clone()),
+            None,
+            None,
+            None,
+            None,
+            Sandbox::default(),
+            operations(json!([
+                { "op": "create", "table": "accounts", "data": { "id": 3, "name": "c" } },
+                { "op": "delete", "table": "accounts", "id": 99 },
+            ])),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(failure.index, Some(1));
+
+        let (status, _) = run_batch(
+            Extension(db.clone()),
+            None,
+            None,
+            None,
+            None,
+            Sandbox::default(),
+            operations(json!([
+                { "op": "create", "table": "accounts", "data": { "id": 1, "name": "dup" } },
+            ])),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        assert_eq!(balances(&db).await?, [(1, 10), (2, 0)]);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_batch_exposure(db: Pool<Postgres>) -> Result<(), crate::error::StoreError> {
+        setup(&db).await?;
+        for table in ["audit", "logs"] {
+            sqlx::query(&format!("CREATE TABLE {} (id integer PRIMARY KEY)", table))
+                .execute(&db)
+                .await?;
+        }
+        let sqlite = sqlx::SqlitePool::connect("sqlite::memory:").await?;
+        let exposures = Arc::new(Exposures::open(sqlite).await?);
+        exposures
+            .set(TableExposure::new("public.accounts").with_enabled(false))
+            .await?;
+        exposures
+            .set(TableExposure::new("audit").with_read_only(true))
+            .await?;
+        let batch = |value: serde_json::Value| {
+            run_batch(
+                Extension(db.clone()),
+                None,
+                None,
+                None,
+                Some(Extension(exposures.clone())),
+                Sandbox::default(),
+                operations(value),
+            )
+        };
+
+        for (value, status) in [
+            (
+                json!([
+                    { "op": "create", "table": "logs", "data": { "id": 1 } },
+                    { "op": "update", "table": "accounts", "id": 1, "data": { "balance": 5 } },
+                ]),
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                json!([
+                    { "op": "create", "table": "logs", "data": { "id": 1 } },
+                    { "op": "delete", "table": "public.audit", "id": 1 },
+                ]),
+                StatusCode::METHOD_NOT_ALLOWED,
+            ),
+            (
+                json!([
+                    { "op": "create", "table": "logs", "data": { "id": 1 } },
+                    { "op": "delete", "table": "pg_catalog.pg_class", "id": 1 },
+                ]),
+                StatusCode::NOT_FOUND,
+            ),
+        ] {
+            let (got, Json(failure)) = batch(value).await.unwrap_err();
+            assert_eq!(got, status);
+            assert_eq!(failure.index, Some(1));
+        }
+        assert_eq!(balances(&db).await?, [(1, 10), (2, 0)]);
+
+        exposures.remove("audit").await?;
+        assert!(
+            batch(json!([{ "op": "create", "table": "audit", "data": { "id": 1 } }]))
+                .await
+                .is_ok()
+        );
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_sandboxed_batch(db: Pool<Postgres>) -> sqlx::Result<()> {
+        setup(&db).await?;
+        let sqlite = sqlx::SqlitePool::connect("sqlite::memory:").await?;
+        let quotas = Arc::new(Quotas::open(sqlite).await?);
+
+        let Json(results) = run_batch(
+            Extension(db.clone()),
+            None,
+            Some(RecordQuota {
+                quotas: quotas.clone(),
+                subject: "ann".to_string(),
+            }),
+            None,
+            None,
+            Sandbox(true),
+            operations(json!([
+                { "op": "update", "table": "accounts", "id": 1, "data": { "balance": 5 } },
+                { "op": "create", "table": "accounts", "data": { "id": 3, "name": "c" } },
+            ])),
+        )
+        .await
+        .unwrap();
+        assert_eq!(results[0].record["balance"], 5);
+        assert_eq!(
+            results[1].record,
+            json!({ "id": 3, "name": "c", "balance": 0 })
+        );
+        assert_eq!(balances(&db).await?, [(1, 10), (2, 0)]);
+        let usage = quotas.usage("ann", chrono::Utc::now()).await?;
+        assert_eq!(usage.records.get("accounts").copied().unwrap_or(0), 0);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_batch_quota(db: Pool<Postgres>) -> Result<(), crate::error::StoreError> {
+        setup(&db).await?;
+        let sqlite = sqlx::SqlitePool::connect("sqlite::memory:").await?;
+        let quotas = Arc::new(Quotas::open(sqlite).await?);
+        quotas
+            .set_limits(
+                "ann",
+                crate::quotas::QuotaLimits::default().with_records("accounts", 1),
+            )
+            .await?;
+        let batch = |value: serde_json::Value| {
+            run_batch(
+                Extension(db.clone()),
+                None,
+                Some(RecordQuota {
+                    quotas: quotas.clone(),
+                    subject: "ann".to_string(),
+                }),
+                None,
+                None,
+                Sandbox::default(),
+                operations(value),
+            )
+        };
+
+        let (status, _) = batch(json!([
+            { "op": "create", "table": "accounts", "data": { "id": 3, "name": "c" } },
+            { "op": "create", "table": "accounts", "data": { "id": 4, "name": "d" } },
+        ]))
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::PAYMENT_REQUIRED);
+
+        // A failed batch gives its reservation back.
+        let (status, _) = batch(json!([
+            { "op": "create", "table": "accounts", "data": { "id": 1, "name": "dup" } },
+        ]))
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let Json(results) = batch(json!([
+            { "op": "create", "table": "public.accounts", "data": { "id": 3, "name": "c" } },
+        ]))
+        .await
+        .unwrap();
+        assert_eq!(results.len(), 1);
+        let usage = quotas.usage("ann", chrono::Utc::now()).await?;
+        assert_eq!(usage.records["accounts"], 1);
+
+        // Deletes are only given back once the batch committed.
+        let (status, _) = batch(json!([
+            { "op": "delete", "table": "accounts", "id": 3 },
+            { "op": "create", "table": "accounts", "data": { "id": 4, "name": "d" } },
+        ]))
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::PAYMENT_REQUIRED);
+        assert!(
+            batch(json!([{ "op": "delete", "table": "accounts", "id": 3 }]))
+                .await
+                .is_ok()
+        );
+        assert!(
+            batch(json!([
+                { "op": "create", "table": "accounts", "data": { "id": 4, "name": "d" } },
+            ]))
+            .await
+            .is_ok()
+        );
+        assert_eq!(balances(&db).await?, [(1, 10), (2, 0), (4, 0)]);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_batch_outbox(db: Pool<Postgres>) -> sqlx::Result<()> {
+        setup(&db).await?;
+        let outbox = Arc::new(Outbox::open_postgres(db.clone()).await?);
+        let batch = |value: serde_json::Value| {
+            run_batch(
+                Extension(db.clone()),
+                None,
+                None,
+                Some(Extension(outbox.clone())),
+                None,
+                Sandbox::default(),
+                operations(value),
+            )
+        };
+
+        batch(json!([
+            { "op": "update", "table": "accounts", "id": 1, "data": { "balance": 5 } },
+            { "op": "delete", "table": "accounts", "id": 2 },
+        ]))
+        .await
+        .unwrap();
+        // A rolled back batch leaves no event behind.
+        let (status, _) = batch(json!([
+            { "op": "create", "table": "accounts", "data": { "id": 3, "name": "c" } },
+            { "op": "create", "table": "accounts", "data": { "id": 1, "name": "dup" } },
+        ]))
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let events = outbox.pending().await?;
+        let changes: Vec<_> = events
+            .iter()
+            .map(|event| (event.change.action, event.change.record["id"].clone()))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                (ChangeAction::Update, json!(1)),
+                (ChangeAction::Delete, json!(2))
+            ]
+        );
+        assert_eq!(events[0].change.table, "accounts");
+        Ok(())
+    }
+}
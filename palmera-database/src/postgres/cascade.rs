@@ -0,0 +1,452 @@
+//! # Cascading delete preview
+//!
+//! Deleting a record can remove or modify rows of other tables through the `ON DELETE`
+//! actions of the foreign keys referencing it. [`preview`] walks the foreign keys in
+//! the catalog and reports, per constraint, how many dependent rows would be deleted,
+//! set to `NULL` or to their default, and whether a `RESTRICT` or `NO ACTION` key
+//! would block the delete, so admin UIs can warn before a destructive delete.
+//!
+//! The route `DELETE /{schema}/{table}/{id}` deletes a record and returns it. With
+//! `?preview_cascade=true` it returns the [`CascadePreview`] instead and deletes
+//! nothing. Records are identified by their [`PRIMARY_KEY`] column, and when a
+//! [`SessionContext`] extension is present both run in its session. In [`sandbox`] mode
+//! the delete returns the record but is rolled back. Otherwise the record is given back
+//! to the [`RecordQuota`] of the caller; rows removed by the cascade are not, as they
+//! may have been created by someone else.
+//!
+//! Cascades are followed [`MAX_DEPTH`] levels deep, which also bounds cycles of
+//! foreign keys.
+
+use std::collections::BTreeMap;
+
+use axum::{
+    Extension, Json,
+    extract::{Path, Query},
+    http::StatusCode,
+    middleware,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, Pool, Postgres, types::Json as SqlJson};
+use utoipa::{IntoParams, ToSchema};
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+use crate::{
+    constraint::ConstraintViolation,
+    postgres::{
+        batch::{PRIMARY_KEY, matches_id},
+        helpers::{quote_ident, quote_qualified},
+        sandbox::{self, Sandbox},
+        session::{SessionContext, begin_session},
+    },
+    quotas::RecordQuota,
+};
+
+/// Levels of cascading deletes followed by [`preview`].
+pub const MAX_DEPTH: usize = 16;
+
+/// Primary keys listed per [`CascadeEffect`].
+pub const MAX_IDS: usize = 100;
+
+/// What a foreign key does to the rows referencing a deleted row.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CascadeAction {
+    Delete,
+    SetNull,
+    SetDefault,
+    /// `RESTRICT` or `NO ACTION`: the delete fails while such rows exist.
+    Restrict,
+}
+
+impl CascadeAction {
+    fn from_catalog(action: &str) -> Self {
+        match action {
+            "c" => Self::Delete,
+            "n" => Self::SetNull,
+            "d" => Self::SetDefault,
+            _ => Self::Restrict,
+        }
+    }
+}
+
+/// Rows of a table affected through one foreign key.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct CascadeEffect {
+    /// Schema qualified table holding the rows.
+    pub table: String,
+    pub constraint: String,
+    pub action: CascadeAction,
+    pub rows: u64,
+    /// Primary keys of the first [`MAX_IDS`] rows.
+    #[schema(value_type = Vec<Object>)]
+    pub ids: Vec<serde_json::Value>,
+    /// Number of cascading deletes leading to these rows, 1 for direct references.
+    pub depth: usize,
+}
+
+/// The effects of deleting a record.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct CascadePreview {
+    pub table: String,
+    #[schema(value_type = Object)]
+    pub id: serde_json::Value,
+    pub effects: Vec<CascadeEffect>,
+    /// Whether a restricting foreign key makes the delete fail.
+    pub blocked: bool,
+}
+
+/// A foreign key referencing a table.
+#[derive(Debug, sqlx::FromRow)]
+struct ForeignKey {
+    name: String,
+    table: String,
+    action: String,
+    columns: Vec<String>,
+    referenced: Vec<String>,
+}
+
+async fn referencing_keys(
+    conn: &mut PgConnection,
+    table: &str,
+) -> Result<Vec<ForeignKey>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT c.conname::text AS name, n.nspname || '.' || r.relname AS table, \
+             c.confdeltype::text AS action, \
+             ARRAY(SELECT a.attname::text FROM unnest(c.conkey) WITH ORDINALITY k(attnum, ord) \
+                   JOIN pg_attribute a ON a.attrelid = c.conrelid AND a.attnum = k.attnum \
+                   ORDER BY k.ord) AS columns, \
+             ARRAY(SELECT a.attname::text FROM unnest(c.confkey) WITH ORDINALITY k(attnum, ord) \
+                   JOIN pg_attribute a ON a.attrelid = c.confrelid AND a.attnum = k.attnum \
+                   ORDER BY k.ord) AS referenced \
+         FROM pg_constraint c \
+         JOIN pg_class r ON r.oid = c.conrelid \
+         JOIN pg_namespace n ON n.oid = r.relnamespace \
+         WHERE c.contype = 'f' AND c.confrelid = $1::regclass \
+         ORDER BY n.nspname, r.relname, c.conname",
+    )
+    .bind(quote_qualified(table))
+    .fetch_all(conn)
+    .await
+}
+
+fn column_list(columns: &[String]) -> String {
+    columns
+        .iter()
+        .map(|column| quote_ident(column))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Reports what deleting the record of `table` identified by `id` would do to the rows
+/// referencing it, or `None` if there is no such record.
+pub async fn preview(
+    conn: &mut PgConnection,
+    table: &str,
+    id: &serde_json::Value,
+) -> Result<Option<CascadePreview>, sqlx::Error> {
+    let name = quote_qualified(table);
+    // The rows being deleted at each level, as a condition on their table. `$1` is the
+    // id of the record.
+    let root = matches_id(&name, "$1");
+    let exists: bool = sqlx::query_scalar(&format!(
+        "SELECT EXISTS (SELECT 1 FROM {name} WHERE {root})"
+    ))
+    .bind(SqlJson(id))
+    .fetch_one(&mut *conn)
+    .await?;
+    if !exists {
+        return Ok(None);
+    }
+
+    let mut effects = Vec::new();
+    let mut pending = vec![(table.to_string(), root, 1)];
+    while let Some((parent, deleted, depth)) = pending.pop() {
+        let parent_name = quote_qualified(&parent);
+        for key in referencing_keys(conn, &parent).await? {
+            let child = quote_qualified(&key.table);
+            let condition = format!(
+                "({}) IN (SELECT {} FROM {parent_name} WHERE {deleted})",
+                column_list(&key.columns),
+                column_list(&key.referenced),
+            );
+            let (rows, ids): (i64, Vec<SqlJson<serde_json::Value>>) = sqlx::query_as(&format!(
+                "SELECT count(*), coalesce((SELECT array_agg(id) FROM ( \
+                     SELECT to_jsonb(t) -> '{PRIMARY_KEY}' AS id FROM {child} AS t \
+                     WHERE {condition} ORDER BY 1 LIMIT {MAX_IDS}) ids), '{{}}') \
+                 FROM {child} WHERE {condition}"
+            ))
+            .bind(SqlJson(id))
+            .fetch_one(&mut *conn)
+            .await?;
+            if rows == 0 {
+                continue;
+            }
+
+            let action = CascadeAction::from_catalog(&key.action);
+            if action == CascadeAction::Delete && depth < MAX_DEPTH {
+                pending.push((key.table.clone(), condition, depth + 1));
+            }
+            effects.push(CascadeEffect {
+                table: key.table,
+                constraint: key.name,
+                action,
+                rows: rows as u64,
+                ids: ids.into_iter().map(|id| id.0).collect(),
+                depth,
+            });
+        }
+    }
+
+    effects.sort_by(|a, b| (a.depth, &a.table).cmp(&(b.depth, &b.table)));
+    Ok(Some(CascadePreview {
+        table: table.to_string(),
+        id: id.clone(),
+        blocked: effects
+            .iter()
+            .any(|effect| effect.action == CascadeAction::Restrict),
+        effects,
+    }))
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+struct DeleteOptions {
+    /// Report the rows the delete would affect instead of deleting.
+    #[serde(default)]
+    preview_cascade: bool,
+}
+
+/// Deletes a record, or with `preview_cascade` reports what deleting it would do.
+#[utoipa::path(
+    delete,
+    path = "/{schema}/{table}/{id}",
+    params(DeleteOptions),
+    responses(
+        (status = 200, description = "The deleted record, or the preview", body = Object),
+        (status = 404),
+        (status = 409, description = "Referenced by rows of a restricting foreign key", body = ConstraintViolation)
+    )
+)]
+async fn delete_record(
+    Extension(db): Extension<Pool<Postgres>>,
+    session: Option<Extension<SessionContext>>,
+    sandbox: Sandbox,
+    quota: Option<RecordQuota>,
+    Path((schema, table, id)): Path<(String, String, String)>,
+    Query(options): Query<DeleteOptions>,
+) -> Result<Json<serde_json::Value>, Response> {
+    let table = format!("{}.{}", schema, table);
+    let internal = |err: sqlx::Error| {
+        if let Some(violation) = ConstraintViolation::from_sqlx(&err) {
+            return violation.into_response();
+        }
+        let code = err
+            .as_database_error()
+            .and_then(|err| err.code())
+            .map(|code| code.into_owned())
+            .unwrap_or_default();
+        match code.as_str() {
+            "42P01" => StatusCode::NOT_FOUND,
+            "42501" => StatusCode::FORBIDDEN,
+            code if code.starts_with("22") => StatusCode::BAD_REQUEST,
+            _ => {
+                tracing::error!(%err, table, "record delete failed");
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+        .into_response()
+    };
+
+    let mut tx = match session {
+        Some(Extension(ctx)) => begin_session(&db, &ctx).await,
+        None => db.begin().await,
+    }
+    .map_err(internal)?;
+    let id = serde_json::Value::String(id);
+
+    if options.preview_cascade {
+        let preview = preview(&mut tx, &table, &id)
+            .await
+            .map_err(internal)?
+            .ok_or(StatusCode::NOT_FOUND.into_response())?;
+        return Ok(Json(
+            serde_json::to_value(preview)
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?,
+        ));
+    }
+
+    let name = quote_qualified(&table);
+    let deleted: Option<SqlJson<serde_json::Value>> = sqlx::query_scalar(&format!(
+        "DELETE FROM {name} AS t WHERE {} RETURNING to_jsonb(t)",
+        matches_id(&name, "$1")
+    ))
+    .bind(SqlJson(&id))
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(internal)?;
+    let deleted = deleted.ok_or(StatusCode::NOT_FOUND.into_response())?;
+    sandbox.finish(tx).await.map_err(internal)?;
+    if let Some(quota) = &quota {
+        let released = BTreeMap::from([(table.clone(), 1)]);
+        quota
+            .settle(&BTreeMap::new(), &released, !sandbox.is_enabled())
+            .await;
+    }
+
+    Ok(Json(deleted.0))
+}
+
+pub fn router() -> OpenApiRouter {
+    OpenApiRouter::new()
+        .routes(routes!(delete_record))
+        .layer(middleware::from_fn(sandbox::mark_responses))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    async fn setup(db: &Pool<Postgres>) -> sqlx::Result<()> {
+        for statement in [
+            "CREATE TABLE authors (id serial PRIMARY KEY, name text)",
+            "CREATE TABLE posts (id serial PRIMARY KEY, \
+             author_id int REFERENCES authors ON DELETE CASCADE)",
+            "CREATE TABLE comments (id serial PRIMARY KEY, \
+             post_id int REFERENCES posts ON DELETE CASCADE, \
+             reply_to int REFERENCES comments ON DELETE SET NULL)",
+            "CREATE TABLE invoices (id serial PRIMARY KEY, author_id int REFERENCES authors)",
+            "INSERT INTO authors (name) VALUES ('ann'), ('bob')",
+            "INSERT INTO posts (author_id) VALUES (1), (1), (2)",
+            "INSERT INTO comments (post_id, reply_to) VALUES (1, NULL), (1, 1), (3, NULL)",
+        ] {
+            sqlx::query(statement).execute(db).await?;
+        }
+        Ok(())
+    }
+
+    fn summary(preview: &CascadePreview) -> Vec<(String, CascadeAction, u64, usize)> {
+        preview
+            .effects
+            .iter()
+            .map(|effect| {
+                (
+                    effect.table.clone(),
+                    effect.action,
+                    effect.rows,
+                    effect.depth,
+                )
+            })
+            .collect()
+    }
+
+    #[sqlx::test]
+    async fn test_preview(db: Pool<Postgres>) -> sqlx::Result<()> {
+        setup(&db).await?;
+        let mut conn = db.acquire().await?;
+
+        let preview = preview(&mut conn, "public.authors", &json!("1"))
+            .await?
+            .unwrap();
+        assert_eq!(
+            summary(&preview),
+            [
+                ("public.posts".to_string(), CascadeAction::Delete, 2, 1),
+                ("public.comments".to_string(), CascadeAction::Delete, 2, 2),
+                ("public.comments".to_string(), CascadeAction::SetNull, 1, 3),
+            ]
+        );
+        assert_eq!(preview.effects[0].ids, [json!(1), json!(2)]);
+        assert!(!preview.blocked);
+
+        sqlx::query("INSERT INTO invoices (author_id) VALUES (2)")
+            .execute(&mut *conn)
+            .await?;
+        let blocked = super::preview(&mut conn, "public.authors", &json!(2))
+            .await?
+            .unwrap();
+        assert!(blocked.blocked);
+        assert!(
+            super::preview(&mut conn, "public.authors", &json!(99))
+                .await?
+                .is_none()
+        );
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_delete_route(db: Pool<Postgres>) -> sqlx::Result<()> {
+        setup(&db).await?;
+        sqlx::query("INSERT INTO invoices (author_id) VALUES (2)")
+            .execute(&db)
+            .await?;
+        let path = |id: &str| Path(("public".to_string(), "authors".to_string(), id.to_string()));
+
+        let Json(preview) = delete_record(
+            Extension(db.clone()),
+            None,
+            Sandbox::default(),
+            None,
+            path("1"),
+            Query(DeleteOptions {
+                preview_cascade: true,
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(preview["effects"].as_array().unwrap().len(), 3);
+        let posts: i64 = sqlx::query_scalar("SELECT count(*) FROM posts")
+            .fetch_one(&db)
+            .await?;
+        assert_eq!(posts, 3);
+
+        let Json(previewed) = delete_record(
+            Extension(db.clone()),
+            None,
+            Sandbox(true),
+            None,
+            path("1"),
+            Query(DeleteOptions::default()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(previewed, json!({ "id": 1, "name": "ann" }));
+        let posts: i64 = sqlx::query_scalar("SELECT count(*) FROM posts")
+            .fetch_one(&db)
+            .await?;
+        assert_eq!(posts, 3);
+
+        let Json(deleted) = delete_record(
+            Extension(db.clone()),
+            None,
+            Sandbox::default(),
+            None,
+            path("1"),
+            Query(DeleteOptions::default()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(deleted, json!({ "id": 1, "name": "ann" }));
+        let blocked = delete_record(
+            Extension(db.clone()),
+            None,
+            Sandbox::default(),
+            None,
+            path("2"),
+            Query(DeleteOptions::default()),
+        )
+        .await;
+        assert_eq!(blocked.unwrap_err().status(), StatusCode::CONFLICT);
+        let missing = delete_record(
+            Extension(db),
+            None,
+            Sandbox::default(),
+            None,
+            path("1"),
+            Query(DeleteOptions::default()),
+        )
+        .await;
+        assert_eq!(missing.unwrap_err().status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+}
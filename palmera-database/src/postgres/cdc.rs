@@ -0,0 +1,143 @@
+//! # Postgres change capture
+//!
+//! [`install_trigger`] attaches a row trigger to a table that publishes every insert,
+//! update and delete with `pg_notify` on the [`CHANGE_CHANNEL`] channel. [`listen`]
+//! subscribes to that channel and forwards the decoded events to a [`ChangeFeed`].
+//!
+//! `NOTIFY` payloads are limited to 8000 bytes; for rows larger than that the event is
+//! sent without `record`/`old_record` and consumers should re-read the row if needed.
+
+use sqlx::{Pool, Postgres, postgres::PgListener};
+use tokio::task::JoinHandle;
+
+use crate::{
+    cdc::{ChangeEvent, ChangeFeed},
+    postgres::helpers::quote_qualified,
+};
+
+/// Notification channel used by the change trigger.
+pub const CHANGE_CHANNEL: &str = "palmera_changes";
+
+const NOTIFY_FUNCTION: &str = r#"
+CREATE OR REPLACE FUNCTION palmera_notify_change() RETURNS trigger AS $$
+DECLARE
+    payload text;
+BEGIN
+    payload := json_build_object(
+        'schema', TG_TABLE_SCHEMA,
+        'table', TG_TABLE_NAME,
+        'operation', TG_OP,
+        'record', CASE WHEN TG_OP <> 'DELETE' THEN row_to_json(NEW) END,
+        'old_record', CASE WHEN TG_OP <> 'INSERT' THEN row_to_json(OLD) END
+    )::text;
+
+    IF octet_length(payload) > 7900 THEN
+        payload := json_build_object(
+            'schema', TG_TABLE_SCHEMA,
+            'table', TG_TABLE_NAME,
+            'operation', TG_OP
+        )::text;
+    END IF;
+
+    PERFORM pg_notify('palmera_changes', payload);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+"#;
+
+/// Installs the change trigger on `table` (optionally schema qualified).
+///
+/// Safe to call repeatedly; both the trigger function and the trigger are replaced.
+pub async fn install_trigger(db: &Pool<Postgres>, table: &str) -> Result<(), sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    sqlx::query(NOTIFY_FUNCTION).execute(&mut *tx).await?;
+    sqlx::query(&format!(
+        "CREATE OR REPLACE TRIGGER palmera_cdc AFTER INSERT OR UPDATE OR DELETE ON {} \
+         FOR EACH ROW EXECUTE FUNCTION palmera_notify_change()",
+        quote_qualified(table)
+    ))
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await
+}
+
+/// Removes the change trigger from `table`.
+pub async fn remove_trigger(db: &Pool<Postgres>, table: &str) -> Result<(), sqlx::Error> {
+    sqlx::query(&format!(
+        "DROP TRIGGER IF EXISTS palmera_cdc ON {}",
+        quote_qualified(table)
+    ))
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Starts listening for change notifications and publishes them to `feed`.
+///
+/// The returned task runs until the listener fails. Notifications that cannot be
+/// decoded are skipped.
+pub async fn listen(
+    db: &Pool<Postgres>,
+    feed: ChangeFeed,
+) -> Result<JoinHandle<Result<(), sqlx::Error>>, sqlx::Error> {
+    let mut listener = PgListener::connect_with(db).await?;
+    listener.listen(CHANGE_CHANNEL).await?;
+
+    Ok(tokio::spawn(async move {
+        loop {
+            let notification = listener.recv().await?;
+            if let Ok(event) = serde_json::from_str::<ChangeEvent>(notification.payload()) {
+                feed.publish(event);
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cdc::ChangeOperation;
+    use std::time::Duration;
+
+    #[sqlx::test]
+    async fn test_trigger_publishes_changes(db: Pool<Postgres>) -> sqlx::Result<()> {
+        sqlx::query("CREATE TABLE notes (id int primary key, body text)")
+            .execute(&db)
+            .await?;
+        install_trigger(&db, "notes").await?;
+
+        let feed = ChangeFeed::new(16);
+        let mut events = feed.subscribe();
+        let task = listen(&db, feed).await?;
+
+        sqlx::query("INSERT INTO notes VALUES (1, 'hello')")
+            .execute(&db)
+            .await?;
+        sqlx::query("DELETE FROM notes WHERE id = 1")
+            .execute(&db)
+            .await?;
+
+        let inserted = tokio::time::timeout(Duration::from_secs(5), events.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(inserted.table, "notes");
+        assert_eq!(inserted.operation, ChangeOperation::Insert);
+        assert_eq!(inserted.record.unwrap()["body"], "hello");
+
+        let deleted = tokio::time::timeout(Duration::from_secs(5), events.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(deleted.operation, ChangeOperation::Delete);
+        assert!(deleted.record.is_none());
+        assert_eq!(deleted.old_record.unwrap()["id"], 1);
+
+        task.abort();
+        remove_trigger(&db, "notes").await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,130 @@
+//! # Server-side cursors
+//!
+//! Exports of very large tables can't be collected in memory, nor held in a single
+//! result set the driver buffers. [`cursor`] declares a server-side cursor for a query
+//! and fetches it in batches, yielding rows as each batch arrives. The rows feed
+//! [`export::rows_with`](crate::export::rows_with) to stream them to the client.
+//!
+//! The cursor lives in a read-only transaction on its own task and connection; it is
+//! closed once the query is exhausted or the stream is dropped, e.g. because the client
+//! disconnected.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # async fn run(db: sqlx::Pool<sqlx::Postgres>) {
+//! use futures::StreamExt;
+//! use palmera_database::postgres::cursor::cursor;
+//!
+//! let rows = cursor::<(i64, String)>(db, "SELECT id, title FROM posts".to_string(), 1000);
+//! let mut rows = Box::pin(rows);
+//! while let Some(row) = rows.next().await {
+//!     let (id, title) = row.unwrap();
+//! }
+//! # }
+//! ```
+
+use futures::{Stream, TryStreamExt};
+use sqlx::{FromRow, Pool, Postgres, postgres::PgRow};
+use tokio::sync::mpsc;
+
+use crate::export::receive;
+
+/// Rows fetched per round trip when no batch size is given.
+pub const DEFAULT_BATCH_SIZE: u32 = 1000;
+
+const CURSOR_NAME: &str = "palmera_export";
+
+/// Runs `sql` through a server-side cursor, fetching `batch_size` rows at a time.
+pub fn cursor<T>(
+    db: Pool<Postgres>,
+    sql: String,
+    batch_size: u32,
+) -> impl Stream<Item = Result<T, sqlx::Error>> + Send + 'static
+where
+    T: for<'r> FromRow<'r, PgRow> + Send + Unpin + 'static,
+{
+    let batch_size = batch_size.max(1);
+    // Holds one batch, so fetching the next overlaps with sending the current one.
+    let (sender, receiver) = mpsc::channel(batch_size as usize);
+
+    tokio::spawn(async move {
+        if let Err(err) = run_cursor(&db, &sql, batch_size, &sender).await {
+            let _ = sender.send(Err(err)).await;
+        }
+    });
+
+    receive(receiver)
+}
+
+async fn run_cursor<T>(
+    db: &Pool<Postgres>,
+    sql: &str,
+    batch_size: u32,
+    sender: &mpsc::Sender<Result<T, sqlx::Error>>,
+) -> Result<(), sqlx::Error>
+where
+    T: for<'r> FromRow<'r, PgRow> + Send + Unpin,
+{
+    let mut tx = db.begin().await?;
+    sqlx::query("SET TRANSACTION READ ONLY")
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(&format!(
+        "DECLARE {} NO SCROLL CURSOR FOR {}",
+        CURSOR_NAME, sql
+    ))
+    .execute(&mut *tx)
+    .await?;
+
+    let fetch = format!("FETCH FORWARD {} FROM {}", batch_size, CURSOR_NAME);
+    loop {
+        let mut rows = sqlx::query_as::<_, T>(&fetch).fetch(&mut *tx);
+        let mut fetched = 0;
+
+        while let Some(row) = rows.try_next().await? {
+            fetched += 1;
+            if sender.send(Ok(row)).await.is_err() {
+                // The stream was dropped; rolling back closes the cursor.
+                return Ok(());
+            }
+        }
+
+        if fetched < batch_size {
+            break;
+        }
+    }
+
+    tx.commit().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    #[sqlx::test]
+    async fn test_cursor_fetches_in_batches(db: Pool<Postgres>) -> sqlx::Result<()> {
+        let rows: Vec<(i32,)> =
+            cursor::<(i32,)>(db.clone(), "SELECT generate_series(1, 25)".to_string(), 10)
+                .try_collect()
+                .await?;
+        assert_eq!(rows.len(), 25);
+        assert_eq!(rows[24], (25,));
+
+        // Dropping the stream early releases the connection.
+        let mut rows = Box::pin(cursor::<(i32,)>(
+            db.clone(),
+            "SELECT generate_series(1, 1000000)".to_string(),
+            10,
+        ));
+        assert_eq!(rows.next().await.unwrap()?, (1,));
+        drop(rows);
+
+        let error = cursor::<(i32,)>(db, "SELECT * FROM missing".to_string(), 10)
+            .try_collect::<Vec<_>>()
+            .await;
+        assert!(error.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,517 @@
+//! # Record duplication
+//!
+//! `POST /{schema}/{table}/{id}/duplicate` clones a record, backing "duplicate item"
+//! features. Columns covered by a primary key or unique index, identity columns and
+//! generated columns are left out of the copy, so the database fills them from their
+//! defaults as for any new record. The response is the new record.
+//!
+//! With `?copy_files=true`, the files referenced by the columns registered with
+//! [`Duplication::with_files`] are deep-copied through the [`Duplication::with_copier`]
+//! callback, which copies the object in storage and returns the new key; the clone then
+//! points at its own copies instead of sharing files with the original. Listeners
+//! registered with [`Duplication::on_create`] run once the clone is committed, like
+//! the create hooks of other writes.
+//!
+//! Records are identified by their [`PRIMARY_KEY`] column. When a [`SessionContext`]
+//! extension is present, the copy runs in its session so row level security applies.
+//! In [`sandbox`] mode the clone is returned and rolled back, sharing the files of the
+//! original and without notifying listeners.
+//!
+//! With a [`Quotas`](crate::quotas::Quotas) extension, the clone is reserved against
+//! the [`RecordQuota`]'s limit of its table first, and the route answers `402 Payment
+//! Required` if the table has no room. With an [`Outbox`] extension, the clone is
+//! recorded in the outbox in the transaction creating it.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # async fn run(db: sqlx::Pool<sqlx::Postgres>) {
+//! use std::sync::Arc;
+//!
+//! use axum::Extension;
+//! use palmera_database::postgres::duplicate::{self, Duplication};
+//!
+//! let duplication = Duplication::new()
+//!     .with_files("public.products", &["image"])
+//!     .with_copier(|key| async move {
+//!         // Copy the object in storage and return the key of the copy.
+//!         Ok(format!("{key}-copy"))
+//!     });
+//! duplication.on_create(|record| tracing::info!(table = %record.table, "record duplicated"));
+//!
+//! let (router, _api) = duplicate::router()
+//!     .layer(Extension(Arc::new(duplication)))
+//!     .layer(Extension(db))
+//!     .split_for_parts();
+//! # }
+//! ```
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    pin::Pin,
+    sync::{Arc, RwLock},
+};
+
+use axum::{
+    Extension, Json,
+    extract::{Path, Query},
+    http::StatusCode,
+    middleware,
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use sqlx::{PgConnection, Pool, Postgres, types::Json as SqlJson};
+use utoipa::IntoParams;
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+use crate::{
+    constraint::ConstraintViolation,
+    embedded::{ChangeAction, RecordChange},
+    outbox::{self, Outbox},
+    postgres::{
+        batch::{PRIMARY_KEY, matches_id},
+        helpers::{quote_ident, quote_qualified},
+        sandbox::{self, Sandbox},
+        session::{SessionContext, begin_session},
+    },
+    quotas::RecordQuota,
+};
+
+/// Error returned by a file copier.
+pub type CopyError = Box<dyn std::error::Error + Send + Sync>;
+
+type CopyFuture = Pin<Box<dyn Future<Output = Result<String, CopyError>> + Send>>;
+type Copier = Arc<dyn Fn(String) -> CopyFuture + Send + Sync>;
+type Listener = Box<dyn Fn(&DuplicatedRecord) + Send + Sync>;
+
+/// A committed clone, passed to [`Duplication::on_create`] listeners.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DuplicatedRecord {
+    /// Schema qualified table name.
+    pub table: String,
+    /// Primary key of the original record.
+    pub source_id: String,
+    pub record: serde_json::Value,
+}
+
+/// File columns, file copier and create listeners used by the duplicate route.
+#[derive(Default)]
+pub struct Duplication {
+    files: HashMap<String, Vec<String>>,
+    copier: Option<Copier>,
+    listeners: RwLock<Vec<Listener>>,
+}
+
+impl std::fmt::Debug for Duplication {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Duplication")
+            .field("files", &self.files)
+            .field("listeners", &self.listeners.read().unwrap().len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Duplication {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Declares the columns of `table` (`schema.table`) holding storage keys.
+    pub fn with_files(mut self, table: &str, columns: &[&str]) -> Self {
+        self.files.insert(
+            table.to_string(),
+            columns.iter().map(|column| column.to_string()).collect(),
+        );
+        self
+    }
+
+    /// Sets the callback copying a stored file, given its key, and returning the key of
+    /// the copy.
+    pub fn with_copier<F, Fut>(mut self, copier: F) -> Self
+    where
+        F: Fn(String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<String, CopyError>> + Send + 'static,
+    {
+        self.copier = Some(Arc::new(move |key| Box::pin(copier(key))));
+        self
+    }
+
+    /// Registers `listener` to run after a clone is committed.
+    pub fn on_create<F>(&self, listener: F)
+    where
+        F: Fn(&DuplicatedRecord) + Send + Sync + 'static,
+    {
+        self.listeners.write().unwrap().push(Box::new(listener));
+    }
+
+    fn file_columns(&self, table: &str) -> &[String] {
+        self.files.get(table).map(Vec::as_slice).unwrap_or_default()
+    }
+}
+
+/// Columns of `table` copied by [`duplicate`]: every column except those in a unique
+/// index, identity columns and generated columns.
+pub async fn copied_columns(
+    conn: &mut PgConnection,
+    table: &str,
+) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT a.attname::text FROM pg_attribute a \
+         WHERE a.attrelid = $1::regclass AND a.attnum > 0 AND NOT a.attisdropped \
+           AND a.attidentity = '' AND a.attgenerated = '' \
+           AND NOT EXISTS ( \
+               SELECT 1 FROM pg_index i \
+               WHERE i.indrelid = a.attrelid AND i.indisunique AND a.attnum = ANY(i.indkey)) \
+         ORDER BY a.attnum",
+    )
+    .bind(quote_qualified(table))
+    .fetch_all(conn)
+    .await
+}
+
+/// Inserts a copy of the record of `table` identified by `id` and returns the copy, or
+/// `None` if there is no such record.
+pub async fn duplicate(
+    conn: &mut PgConnection,
+    table: &str,
+    id: &serde_json::Value,
+) -> Result<Option<serde_json::Value>, sqlx::Error> {
+    let name = quote_qualified(table);
+    let columns = copied_columns(conn, table)
+        .await?
+        .iter()
+        .map(|column| quote_ident(column))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let sql = if columns.is_empty() {
+        format!(
+            "INSERT INTO {name} AS t SELECT FROM {name} WHERE {} RETURNING to_jsonb(t)",
+            matches_id(&name, "$1")
+        )
+    } else {
+        format!(
+            "INSERT INTO {name} AS t ({columns}) SELECT {columns} FROM {name} WHERE {} \
+             RETURNING to_jsonb(t)",
+            matches_id(&name, "$1")
+        )
+    };
+    let record: Option<SqlJson<serde_json::Value>> = sqlx::query_scalar(&sql)
+        .bind(SqlJson(id))
+        .fetch_optional(conn)
+        .await?;
+
+    Ok(record.map(|record| record.0))
+}
+
+/// Points `column` of the record identified by `id` at `key`, returning the record.
+async fn set_file(
+    conn: &mut PgConnection,
+    table: &str,
+    id: &serde_json::Value,
+    column: &str,
+    key: &str,
+) -> Result<serde_json::Value, sqlx::Error> {
+    let name = quote_qualified(table);
+    let record: SqlJson<serde_json::Value> = sqlx::query_scalar(&format!(
+        "UPDATE {name} AS t SET {} = $1 WHERE {} RETURNING to_jsonb(t)",
+        quote_ident(column),
+        matches_id(&name, "$2"),
+    ))
+    .bind(key)
+    .bind(SqlJson(id))
+    .fetch_one(conn)
+    .await?;
+
+    Ok(record.0)
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+struct DuplicateOptions {
+    /// Copy the files referenced by the record instead of sharing them.
+    #[serde(default)]
+    copy_files: bool,
+}
+
+/// Clones a record.
+#[utoipa::path(
+    post,
+    path = "/{schema}/{table}/{id}/duplicate",
+    params(DuplicateOptions),
+    responses(
+        (status = 201, body = Object),
+        (status = 402),
+        (status = 404),
+        (status = 409, body = ConstraintViolation),
+        (status = 422, body = ConstraintViolation),
+        (status = 502)
+    )
+)]
+async fn duplicate_record(
+    Extension(duplication): Extension<Arc<Duplication>>,
+    Extension(db): Extension<Pool<Postgres>>,
+    session: Option<Extension<SessionContext>>,
+    sandbox: Sandbox,
+    quota: Option<RecordQuota>,
+    outbox: Option<Extension<Arc<Outbox>>>,
+    Path((schema, table, id)): Path<(String, String, String)>,
+    Query(options): Query<DuplicateOptions>,
+) -> Result<(StatusCode, Json<serde_json::Value>), Response> {
+    let table = format!("{}.{}", schema, table);
+    let internal = |err: sqlx::Error| {
+        // E.g. a NOT NULL column left out of the copy because it is unique.
+        if let Some(violation) = ConstraintViolation::from_sqlx(&err) {
+            return violation.into_response();
+        }
+        let code = err
+            .as_database_error()
+            .and_then(|err| err.code())
+            .map(|code| code.into_owned())
+            .unwrap_or_default();
+        match code.as_str() {
+            "42P01" => StatusCode::NOT_FOUND,
+            "42501" => StatusCode::FORBIDDEN,
+            code if code.starts_with("22") || code.starts_with("23") => StatusCode::BAD_REQUEST,
+            _ => {
+                tracing::error!(%err, table, "record duplication failed");
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+        .into_response()
+    };
+
+    let created = BTreeMap::from([(table.clone(), 1)]);
+    if let Some(quota) = &quota {
+        quota
+            .reserve(&created)
+            .await
+            .map_err(IntoResponse::into_response)?;
+    }
+
+    let outbox = outbox.map(|Extension(outbox)| outbox);
+    let committed = async {
+        let (user_id, actor_id) = session
+            .as_ref()
+            .map(|Extension(ctx)| (ctx.user_id.clone(), ctx.actor_id.clone()))
+            .unwrap_or_default();
+        let mut tx = match session {
+            Some(Extension(ctx)) => begin_session(&db, &ctx).await,
+            None => db.begin().await,
+        }
+        .map_err(internal)?;
+
+        let source_id = serde_json::Value::String(id.clone());
+        let mut record = duplicate(&mut tx, &table, &source_id)
+            .await
+            .map_err(internal)?
+            .ok_or(StatusCode::NOT_FOUND.into_response())?;
+
+        // Copies would outlive the rolled back clone, so a sandboxed clone shares its files.
+        if options.copy_files && !sandbox.is_enabled() {
+            let copier = duplication
+                .copier
+                .clone()
+                .ok_or(StatusCode::NOT_IMPLEMENTED.into_response())?;
+            let new_id = record[PRIMARY_KEY].clone();
+
+            for column in duplication.file_columns(&table) {
+                let Some(key) = record.get(column).and_then(|key| key.as_str()) else {
+                    continue;
+                };
+                // Files copied before a later failure are left behind in storage.
+                let copy = copier(key.to_string()).await.map_err(|err| {
+                    tracing::error!(%err, table, column, "copying a duplicated file failed");
+                    StatusCode::BAD_GATEWAY.into_response()
+                })?;
+                record = set_file(&mut tx, &table, &new_id, column, &copy)
+                    .await
+                    .map_err(internal)?;
+            }
+        }
+
+        let change = RecordChange {
+            table: table.clone(),
+            action: ChangeAction::Create,
+            record: record.clone(),
+            user_id,
+            actor_id,
+        };
+        outbox::record_changes(outbox.as_deref(), &mut tx, [change])
+            .await
+            .map_err(internal)?;
+        sandbox.finish(tx).await.map_err(internal)?;
+        Ok(record)
+    }
+    .await;
+    if let (Ok(_), Some(outbox)) = (&committed, &outbox) {
+        outbox.wake();
+    }
+
+    // A clone rolled back, by a failure or the sandbox, gives its reservation back.
+    if let Some(quota) = &quota {
+        let committed = committed.is_ok() && !sandbox.is_enabled();
+        quota.settle(&created, &BTreeMap::new(), committed).await;
+    }
+    let record = committed?;
+    if sandbox.is_enabled() {
+        return Ok((StatusCode::CREATED, Json(record)));
+    }
+
+    let duplicated = DuplicatedRecord {
+        table,
+        source_id: id,
+        record,
+    };
+    for listener in duplication.listeners.read().unwrap().iter() {
+        listener(&duplicated);
+    }
+
+    Ok((StatusCode::CREATED, Json(duplicated.record)))
+}
+
+pub fn router() -> OpenApiRouter {
+    OpenApiRouter::new()
+        .routes(routes!(duplicate_record))
+        .layer(middleware::from_fn(sandbox::mark_responses))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::Mutex;
+
+    async fn setup(db: &Pool<Postgres>) -> sqlx::Result<()> {
+        sqlx::query(
+            "CREATE TABLE products (id serial PRIMARY KEY, sku text UNIQUE, name text NOT NULL, \
+             image text, label text GENERATED ALWAYS AS (upper(name)) STORED)",
+        )
+        .execute(db)
+        .await?;
+        sqlx::query(
+            "INSERT INTO products (sku, name, image) VALUES ('A-1', 'chair', 'images/chair.png')",
+        )
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+
+    fn path(id: &str) -> Path<(String, String, String)> {
+        Path(("public".to_string(), "products".to_string(), id.to_string()))
+    }
+
+    #[sqlx::test]
+    async fn test_duplicate(db: Pool<Postgres>) -> sqlx::Result<()> {
+        setup(&db).await?;
+        let mut conn = db.acquire().await?;
+
+        assert_eq!(
+            copied_columns(&mut conn, "public.products").await?,
+            ["name", "image"]
+        );
+
+        let copy = duplicate(&mut conn, "public.products", &json!("1"))
+            .await?
+            .unwrap();
+        assert_eq!(
+            copy,
+            json!({ "id": 2, "sku": null, "name": "chair", "image": "images/chair.png", "label": "CHAIR" })
+        );
+        assert!(
+            duplicate(&mut conn, "public.products", &json!(99))
+                .await?
+                .is_none()
+        );
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_duplicate_route(db: Pool<Postgres>) -> sqlx::Result<()> {
+        setup(&db).await?;
+        let copied = Arc::new(Mutex::new(Vec::new()));
+        let seen = copied.clone();
+        let duplication = Duplication::new()
+            .with_files("public.products", &["image"])
+            .with_copier(move |key| {
+                seen.lock().unwrap().push(key.clone());
+                async move { Ok(format!("{key}.copy")) }
+            });
+        let created = Arc::new(Mutex::new(Vec::new()));
+        let seen = created.clone();
+        duplication.on_create(move |record| seen.lock().unwrap().push(record.source_id.clone()));
+        let duplication = Arc::new(duplication);
+        let outbox = Arc::new(Outbox::open_postgres(db.clone()).await?);
+
+        let (status, Json(record)) = duplicate_record(
+            Extension(duplication.clone()),
+            Extension(db.clone()),
+            None,
+            Sandbox::default(),
+            None,
+            Some(Extension(outbox.clone())),
+            path("1"),
+            Query(DuplicateOptions { copy_files: true }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(record["image"], "images/chair.png.copy");
+        assert_eq!(*copied.lock().unwrap(), ["images/chair.png"]);
+        assert_eq!(*created.lock().unwrap(), ["1"]);
+
+        let (_, Json(shared)) = duplicate_record(
+            Extension(duplication.clone()),
+            Extension(db.clone()),
+            None,
+            Sandbox::default(),
+            None,
+            None,
+            path("1"),
+            Query(DuplicateOptions::default()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(shared["image"], "images/chair.png");
+
+        // A sandboxed clone is rolled back, without copying files or notifying.
+        let (_, Json(previewed)) = duplicate_record(
+            Extension(duplication.clone()),
+            Extension(db.clone()),
+            None,
+            Sandbox(true),
+            None,
+            Some(Extension(outbox.clone())),
+            path("1"),
+            Query(DuplicateOptions { copy_files: true }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(previewed["image"], "images/chair.png");
+        assert_eq!(copied.lock().unwrap().len(), 1);
+        assert_eq!(created.lock().unwrap().len(), 2);
+        let products: i64 = sqlx::query_scalar("SELECT count(*) FROM products")
+            .fetch_one(&db)
+            .await?;
+        assert_eq!(products, 3);
+        // The sandboxed clone's event was rolled back with it.
+        let events = outbox.pending().await?;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].change.action, ChangeAction::Create);
+        assert_eq!(events[0].change.record, record);
+
+        let missing = duplicate_record(
+            Extension(duplication),
+            Extension(db),
+            None,
+            Sandbox::default(),
+            None,
+            None,
+            path("99"),
+            Query(DuplicateOptions::default()),
+        )
+        .await;
+        assert_eq!(missing.unwrap_err().status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+}
@@ -0,0 +1,23 @@
+/// Quotes a Postgres identifier, doubling embedded quotes.
+pub fn quote_ident(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+/// Quotes a possibly schema qualified name (`auth.users`) part by part.
+pub fn quote_qualified(name: &str) -> String {
+    name.split('.')
+        .map(quote_ident)
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quote_qualified() {
+        assert_eq!(quote_qualified("auth.users"), r#""auth"."users""#);
+        assert_eq!(quote_qualified(r#"we"ird"#), r#""we""ird""#);
+    }
+}
@@ -0,0 +1,205 @@
+//! # Query limits and cancellation
+//!
+//! Dynamically generated queries are driven by client supplied filters, so a single
+//! request can ask the database for far more work than intended. [`QueryLimits`] bounds
+//! each request with a statement timeout and a maximum row count, and [`CancelGuard`]
+//! cancels the backend query when the request future is dropped (e.g. because the
+//! client disconnected) instead of letting it run to completion.
+
+use std::time::Duration;
+
+use sea_query::SelectStatement;
+use sqlx::{PgConnection, Pool, Postgres};
+
+/// Per-request bounds applied to generated queries.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QueryLimits {
+    /// Maximum time a single statement may run, applied as a transaction-local
+    /// `statement_timeout`. `None` keeps the server default.
+    pub statement_timeout: Option<Duration>,
+    /// Maximum number of rows a list query may return. `None` means unbounded.
+    pub max_rows: Option<u64>,
+}
+
+impl Default for QueryLimits {
+    fn default() -> Self {
+        Self {
+            statement_timeout: Some(Duration::from_secs(30)),
+            max_rows: Some(1000),
+        }
+    }
+}
+
+impl QueryLimits {
+    /// Limits that leave every query unbounded.
+    pub fn unbounded() -> Self {
+        Self {
+            statement_timeout: None,
+            max_rows: None,
+        }
+    }
+
+    /// Returns the effective row limit for a query that asked for `requested` rows.
+    ///
+    /// The requested limit is honored when it does not exceed `max_rows`; otherwise, or
+    /// when no limit was requested, `max_rows` is used.
+    pub fn row_limit(&self, requested: Option<u64>) -> Option<u64> {
+        match (requested, self.max_rows) {
+            (Some(requested), Some(max)) => Some(requested.min(max)),
+            (requested, max) => requested.or(max),
+        }
+    }
+
+    /// Applies the effective row limit to a select statement.
+    pub fn apply(&self, stmt: &mut SelectStatement, requested: Option<u64>) {
+        if let Some(limit) = self.row_limit(requested) {
+            stmt.limit(limit);
+        }
+    }
+
+    /// Sets the transaction-local `statement_timeout` on `conn`.
+    ///
+    /// Must be called inside a transaction for the setting to be scoped to the request.
+    pub async fn apply_timeout(&self, conn: &mut PgConnection) -> Result<(), sqlx::Error> {
+        if let Some(timeout) = self.statement_timeout {
+            sqlx::query("SELECT set_config('statement_timeout', $1, true)")
+                .bind(timeout.as_millis().to_string())
+                .execute(conn)
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Cancels the backend's running statement when dropped while armed.
+///
+/// Dropping a sqlx future only stops polling it; the statement keeps running on the
+/// server until it finishes or times out. Create a guard before running an expensive
+/// query and [`disarm`](CancelGuard::disarm) it once the results are in. If the request
+/// is abandoned first, the guard issues `pg_cancel_backend` from a separate connection.
+pub struct CancelGuard {
+    db: Pool<Postgres>,
+    pid: i32,
+    armed: bool,
+}
+
+impl CancelGuard {
+    /// Creates an armed guard for the backend serving `conn`.
+    pub async fn new(db: &Pool<Postgres>, conn: &mut PgConnection) -> Result<Self, sqlx::Error> {
+        let pid: i32 = sqlx::query_scalar("SELECT pg_backend_pid()")
+            .fetch_one(conn)
+            .await?;
+
+        Ok(Self::for_backend(db, pid))
+    }
+
+    /// Creates an armed guard for a known backend process id.
+    pub fn for_backend(db: &Pool<Postgres>, pid: i32) -> Self {
+        Self {
+            db: db.clone(),
+            pid,
+            armed: true,
+        }
+    }
+
+    /// Backend process id this guard cancels.
+    pub fn pid(&self) -> i32 {
+        self.pid
+    }
+
+    /// Marks the query as finished so dropping the guard does nothing.
+    pub fn disarm(mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+
+        // Cancellation is best effort: without a runtime the statement timeout still applies.
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            let db = self.db.clone();
+            let pid = self.pid;
+            handle.spawn(async move {
+                let _ = sqlx::query("SELECT pg_cancel_backend($1)")
+                    .bind(pid)
+                    .execute(&db)
+                    .await;
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sea_query::{Alias, Asterisk, PostgresQueryBuilder, Query};
+
+    #[test]
+    fn test_row_limit_clamps_to_max() {
+        let limits = QueryLimits {
+            statement_timeout: None,
+            max_rows: Some(100),
+        };
+        assert_eq!(limits.row_limit(Some(10)), Some(10));
+        assert_eq!(limits.row_limit(Some(500)), Some(100));
+        assert_eq!(limits.row_limit(None), Some(100));
+        assert_eq!(QueryLimits::unbounded().row_limit(None), None);
+        assert_eq!(QueryLimits::unbounded().row_limit(Some(7)), Some(7));
+    }
+
+    #[test]
+    fn test_apply_sets_limit() {
+        let mut stmt = Query::select()
+            .column(Asterisk)
+            .from(Alias::new("posts"))
+            .to_owned();
+        QueryLimits::default().apply(&mut stmt, Some(5000));
+        assert_eq!(
+            stmt.to_string(PostgresQueryBuilder),
+            r#"SELECT * FROM "posts" LIMIT 1000"#
+        );
+    }
+
+    #[sqlx::test]
+    async fn test_statement_timeout(db: Pool<Postgres>) -> sqlx::Result<()> {
+        let limits = QueryLimits {
+            statement_timeout: Some(Duration::from_millis(100)),
+            max_rows: None,
+        };
+        let mut tx = db.begin().await?;
+        limits.apply_timeout(&mut tx).await?;
+
+        let err = sqlx::query("SELECT pg_sleep(2)")
+            .execute(&mut *tx)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("statement timeout"));
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_dropped_guard_cancels_query(db: Pool<Postgres>) -> sqlx::Result<()> {
+        let mut conn = db.acquire().await?;
+        let guard = CancelGuard::new(&db, &mut conn).await?;
+
+        let query = tokio::spawn(async move {
+            sqlx::query("SELECT pg_sleep(10)")
+                .execute(&mut *conn)
+                .await
+                .map(|_| ())
+        });
+
+        // Give the backend a moment to start sleeping before cancelling it.
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        drop(guard);
+
+        let err = query.await.unwrap().unwrap_err();
+        assert!(err.to_string().contains("canceling statement"));
+        Ok(())
+    }
+}
@@ -0,0 +1,307 @@
+//! # Conflict resolution
+//!
+//! Decides what happens to a write based on an outdated version of its row: a change
+//! pushed through [`crate::postgres::sync`], or a `PATCH` whose `If-Match` version is no
+//! longer current. [`MergeStrategies`] picks a [`MergeStrategy`] per table:
+//!
+//! | Strategy | Outcome |
+//! |----------|---------|
+//! | [`MergeStrategy::Manual`] | the write is rejected with the conflict details (default) |
+//! | [`MergeStrategy::ServerWins`] | the write is dropped, the server's row is kept |
+//! | [`MergeStrategy::ClientWins`] | the write is applied over the server's row |
+//! | [`MergeStrategy::LastWriteWinsByColumn`] | each written column keeps its latest value |
+//! | [`MergeStrategy::Custom`] | a callback returns the [`Resolution`] |
+//!
+//! Last write wins by column compares the time the client made the write,
+//! [`PushChange::changed_at`], with the time the server last wrote each column since the
+//! write's base version. Columns the server hasn't touched since always take the
+//! client's value. A row the server deleted after the client's write stays deleted; one
+//! deleted before it can't be merged with a partial record and is left for manual
+//! resolution.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! use std::sync::Arc;
+//! use axum::Extension;
+//! use palmera_database::postgres::{
+//!     merge::{MergeStrategies, MergeStrategy, Resolution},
+//!     sync,
+//! };
+//!
+//! let strategies = MergeStrategies::new()
+//!     .with_table("public.todos", MergeStrategy::LastWriteWinsByColumn)
+//!     .with_table(
+//!         "public.documents",
+//!         MergeStrategy::custom(|conflict, change| match &conflict.record {
+//!             Some(_) if change.record.is_some() => Resolution::Client,
+//!             _ => Resolution::Manual,
+//!         }),
+//!     );
+//! let router = sync::router().layer(Extension(Arc::new(strategies)));
+//! ```
+
+use std::{collections::HashMap, sync::Arc};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::postgres::sync::{PushChange, SyncConflict, SyncOperation};
+
+type Resolver = Arc<dyn Fn(&SyncConflict, &PushChange) -> Resolution + Send + Sync>;
+
+/// How conflicting writes to a table are resolved.
+#[derive(Clone, Default)]
+pub enum MergeStrategy {
+    #[default]
+    Manual,
+    ServerWins,
+    ClientWins,
+    LastWriteWinsByColumn,
+    Custom(Resolver),
+}
+
+impl std::fmt::Debug for MergeStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Manual => f.write_str("Manual"),
+            Self::ServerWins => f.write_str("ServerWins"),
+            Self::ClientWins => f.write_str("ClientWins"),
+            Self::LastWriteWinsByColumn => f.write_str("LastWriteWinsByColumn"),
+            Self::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+impl MergeStrategy {
+    /// Resolves conflicts with `resolver`, given the conflict and the client's write.
+    pub fn custom<F>(resolver: F) -> Self
+    where
+        F: Fn(&SyncConflict, &PushChange) -> Resolution + Send + Sync + 'static,
+    {
+        Self::Custom(Arc::new(resolver))
+    }
+}
+
+/// Merge strategies of the synced tables.
+#[derive(Debug, Clone, Default)]
+pub struct MergeStrategies {
+    default: MergeStrategy,
+    tables: HashMap<String, MergeStrategy>,
+}
+
+impl MergeStrategies {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the strategy of tables without one of their own.
+    pub fn with_default(mut self, strategy: MergeStrategy) -> Self {
+        self.default = strategy;
+        self
+    }
+
+    /// Sets the strategy of `table` (`schema.table`).
+    pub fn with_table(mut self, table: &str, strategy: MergeStrategy) -> Self {
+        self.tables.insert(table.to_string(), strategy);
+        self
+    }
+
+    pub fn get(&self, table: &str) -> &MergeStrategy {
+        self.tables.get(table).unwrap_or(&self.default)
+    }
+}
+
+/// What to do with a conflicting write.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Resolution {
+    /// Leave it for the client: the conflict is returned.
+    Manual,
+    /// Drop the write and keep the server's row.
+    Server,
+    /// Apply the write as it is.
+    Client,
+    /// Write these columns to the row instead.
+    Merge(serde_json::Map<String, serde_json::Value>),
+}
+
+/// How an applied conflict was resolved, as reported to the client.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Resolved {
+    Server,
+    Client,
+    Merged,
+}
+
+/// When the server wrote a row since a write's base version.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ServerWrites {
+    /// Latest write per column.
+    pub columns: HashMap<String, DateTime<Utc>>,
+    /// Latest write of the whole row: an insert, a delete, or an update whose columns
+    /// are unknown.
+    pub row: Option<DateTime<Utc>>,
+}
+
+impl ServerWrites {
+    /// Latest server write of `column`.
+    fn column(&self, column: &str) -> Option<DateTime<Utc>> {
+        self.columns.get(column).copied().max(self.row)
+    }
+
+    /// Latest server write of any column.
+    fn latest(&self) -> Option<DateTime<Utc>> {
+        self.columns.values().copied().max().max(self.row)
+    }
+}
+
+/// Resolves `conflict` between the server's row and `change` with `strategy`.
+pub(crate) fn resolve(
+    strategy: &MergeStrategy,
+    conflict: &SyncConflict,
+    change: &PushChange,
+    writes: &ServerWrites,
+) -> Resolution {
+    match strategy {
+        MergeStrategy::Manual => Resolution::Manual,
+        MergeStrategy::ServerWins => Resolution::Server,
+        MergeStrategy::ClientWins => Resolution::Client,
+        MergeStrategy::Custom(resolver) => resolver(conflict, change),
+        MergeStrategy::LastWriteWinsByColumn => {
+            let written = change.changed_at.unwrap_or_else(Utc::now);
+            let newer = |server: Option<DateTime<Utc>>| server.is_some_and(|at| at > written);
+
+            match (change.operation, &conflict.record) {
+                (SyncOperation::Delete, _) if newer(writes.latest()) => Resolution::Server,
+                (SyncOperation::Delete, _) => Resolution::Client,
+                (SyncOperation::Upsert, None) if newer(writes.latest()) => Resolution::Server,
+                (SyncOperation::Upsert, None) => Resolution::Manual,
+                (SyncOperation::Upsert, Some(_)) => {
+                    let record = change.record.clone().unwrap_or_default();
+                    let kept = record
+                        .iter()
+                        .filter(|(column, _)| !newer(writes.column(column)))
+                        .map(|(column, value)| (column.clone(), value.clone()))
+                        .collect::<serde_json::Map<_, _>>();
+
+                    if kept.is_empty() {
+                        Resolution::Server
+                    } else if kept.len() == record.len() {
+                        Resolution::Client
+                    } else {
+                        Resolution::Merge(kept)
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use serde_json::json;
+
+    #[test]
+    fn test_last_write_wins_by_column() {
+        let now = Utc::now();
+        let conflict = SyncConflict {
+            id: json!(1),
+            base_version: Some(1),
+            version: 3,
+            record: Some(json!({ "id": 1, "title": "server", "done": true })),
+        };
+        let change = PushChange {
+            id: json!(1),
+            base_version: Some(1),
+            operation: SyncOperation::Upsert,
+            record: json!({ "title": "client", "done": false, "notes": "x" })
+                .as_object()
+                .cloned(),
+            changed_at: Some(now),
+        };
+        let writes = ServerWrites {
+            columns: HashMap::from([
+                ("title".to_string(), now - Duration::minutes(1)),
+                ("done".to_string(), now + Duration::minutes(1)),
+            ]),
+            row: None,
+        };
+        let strategy = MergeStrategy::LastWriteWinsByColumn;
+
+        assert_eq!(
+            resolve(&strategy, &conflict, &change, &writes),
+            Resolution::Merge(
+                json!({ "title": "client", "notes": "x" })
+                    .as_object()
+                    .cloned()
+                    .unwrap()
+            )
+        );
+
+        let older = ServerWrites {
+            row: Some(now - Duration::minutes(5)),
+            ..Default::default()
+        };
+        assert_eq!(
+            resolve(&strategy, &conflict, &change, &older),
+            Resolution::Client
+        );
+
+        let deleted = SyncConflict {
+            record: None,
+            ..conflict.clone()
+        };
+        let later = ServerWrites {
+            row: Some(now + Duration::minutes(5)),
+            ..Default::default()
+        };
+        assert_eq!(
+            resolve(&strategy, &deleted, &change, &later),
+            Resolution::Server
+        );
+        assert_eq!(
+            resolve(&strategy, &deleted, &change, &older),
+            Resolution::Manual
+        );
+    }
+
+    #[test]
+    fn test_strategies() {
+        let strategies = MergeStrategies::new()
+            .with_default(MergeStrategy::ServerWins)
+            .with_table(
+                "public.notes",
+                MergeStrategy::custom(|conflict, _| match conflict.version {
+                    version if version > 10 => Resolution::Manual,
+                    _ => Resolution::Client,
+                }),
+            );
+        let conflict = SyncConflict {
+            id: json!(1),
+            base_version: None,
+            version: 11,
+            record: None,
+        };
+        let change = PushChange {
+            id: json!(1),
+            base_version: None,
+            operation: SyncOperation::Delete,
+            record: None,
+            changed_at: None,
+        };
+        let writes = ServerWrites::default();
+
+        assert_eq!(
+            resolve(strategies.get("public.todos"), &conflict, &change, &writes),
+            Resolution::Server
+        );
+        assert_eq!(
+            resolve(strategies.get("public.notes"), &conflict, &change, &writes),
+            Resolution::Manual
+        );
+    }
+}
@@ -0,0 +1,14 @@
+pub mod batch;
+pub mod cascade;
+pub mod cdc;
+pub mod cursor;
+pub mod duplicate;
+pub mod helpers;
+pub mod limits;
+pub mod merge;
+pub mod patch;
+pub mod privacy;
+pub mod rpc;
+pub mod sandbox;
+pub mod session;
+pub mod sync;
@@ -0,0 +1,502 @@
+//! # Partial updates
+//!
+//! `PATCH /{schema}/{table}/{id}` of [`crate::postgres::sync`] reads its body according
+//! to its `Content-Type`:
+//!
+//! | Content type | Body |
+//! |--------------|------|
+//! | `application/json` | the new values of the written columns |
+//! | [`MERGE_PATCH`] | a JSON Merge Patch (RFC 7396) of the record |
+//! | [`JSON_PATCH`] | a JSON Patch (RFC 6902): a list of [`PatchOperation`]s |
+//! | form bodies | the new values of the written columns, see [`FormRecord`] |
+//!
+//! Every format is applied to the record as it is when the request runs, and only the
+//! columns whose value changes are written, with an `UPDATE` of the row. A patch that
+//! changes nothing writes nothing, and the row keeps its version. Paths below a column
+//! reach into its `json` or `jsonb` value: `/settings/theme` changes the `theme` key of
+//! the `settings` column and keeps its other keys. Removing a column, or setting it to
+//! `null` in a merge patch, writes `NULL`.
+//!
+//! A patch that can't be applied, e.g. one naming an unknown column or a missing path,
+//! is answered with `422 Unprocessable Entity`, and one whose `test` operation fails
+//! with `409 Conflict`. Nothing is written in either case.
+//!
+//! # Example
+//!
+//! ```http
+//! PATCH /public/profiles/7 HTTP/1.1
+//! Content-Type: application/json-patch+json
+//!
+//! [{ "op": "test", "path": "/plan", "value": "free" },
+//!  { "op": "replace", "path": "/settings/theme", "value": "dark" }]
+//! ```
+
+use axum::{
+    body::Bytes,
+    extract::{FromRequest, Request},
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use utoipa::ToSchema;
+
+use crate::form::FormRecord;
+
+/// Content type of JSON Merge Patch bodies.
+pub const MERGE_PATCH: &str = "application/merge-patch+json";
+
+/// Content type of JSON Patch bodies.
+pub const JSON_PATCH: &str = "application/json-patch+json";
+
+/// Body of a `PATCH` request.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Patch {
+    /// New values of the written columns.
+    Columns(Map<String, Value>),
+    /// A JSON Merge Patch of the record.
+    Merge(Map<String, Value>),
+    /// A JSON Patch of the record.
+    Operations(Vec<PatchOperation>),
+}
+
+/// An operation of a JSON Patch. Paths are JSON Pointers into the record.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum PatchOperation {
+    Add {
+        path: String,
+        #[schema(value_type = Object)]
+        value: Value,
+    },
+    Remove {
+        path: String,
+    },
+    Replace {
+        path: String,
+        #[schema(value_type = Object)]
+        value: Value,
+    },
+    Move {
+        from: String,
+        path: String,
+    },
+    Copy {
+        from: String,
+        path: String,
+    },
+    Test {
+        path: String,
+        #[schema(value_type = Object)]
+        value: Value,
+    },
+}
+
+/// Why a patch couldn't be applied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatchError {
+    /// The patch doesn't fit the record, e.g. it names a missing path.
+    Invalid(String),
+    /// A `test` operation failed.
+    TestFailed(String),
+}
+
+impl PatchError {
+    /// HTTP status the error is reported with.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::Invalid(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::TestFailed(_) => StatusCode::CONFLICT,
+        }
+    }
+}
+
+impl std::fmt::Display for PatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Invalid(message) => f.write_str(message),
+            Self::TestFailed(path) => write!(f, "test of {path:?} failed"),
+        }
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+impl IntoResponse for PatchError {
+    fn into_response(self) -> Response {
+        (self.status(), self.to_string()).into_response()
+    }
+}
+
+impl Patch {
+    /// Applies the patch to `record`, returning the columns whose value changes and their
+    /// new values.
+    pub fn apply(self, record: &Map<String, Value>) -> Result<Map<String, Value>, PatchError> {
+        let patched = match self {
+            Self::Columns(columns) => {
+                let mut patched = record.clone();
+                patched.extend(columns);
+                patched
+            }
+            Self::Merge(patch) => {
+                let mut patched = record.clone();
+                for (column, value) in patch {
+                    let current = patched
+                        .get_mut(&column)
+                        .ok_or_else(|| unknown_column(&column))?;
+                    merge(current, value);
+                }
+                patched
+            }
+            Self::Operations(operations) => {
+                let mut patched = Value::Object(record.clone());
+                for operation in operations {
+                    apply_operation(&mut patched, operation)?;
+                }
+                let Value::Object(patched) = patched else {
+                    return Err(PatchError::Invalid("the record must stay an object".into()));
+                };
+                patched
+            }
+        };
+
+        if let Some(column) = patched.keys().find(|column| !record.contains_key(*column)) {
+            return Err(unknown_column(column));
+        }
+        Ok(record
+            .iter()
+            .filter_map(|(column, value)| match patched.get(column) {
+                Some(new) if new == value => None,
+                Some(new) => Some((column.clone(), new.clone())),
+                // Columns can't be removed, only emptied.
+                None => Some((column.clone(), Value::Null)),
+            })
+            .collect())
+    }
+}
+
+impl<S: Send + Sync> FromRequest<S> for Patch {
+    type Rejection = Response;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let content_type = request
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .map(|value| value.trim().to_ascii_lowercase())
+            .unwrap_or_default();
+        if matches!(
+            content_type.as_str(),
+            "multipart/form-data" | "application/x-www-form-urlencoded"
+        ) {
+            let FormRecord(record) = FormRecord::from_request(request, state).await?;
+            return Ok(Self::Columns(record));
+        }
+        let body = Bytes::from_request(request, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+
+        match content_type.as_str() {
+            "application/json" => serde_json::from_slice(&body).map(Self::Columns),
+            MERGE_PATCH => serde_json::from_slice(&body).map(Self::Merge),
+            JSON_PATCH => serde_json::from_slice(&body).map(Self::Operations),
+            _ => return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response()),
+        }
+        .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()).into_response())
+    }
+}
+
+/// Applies the merge patch `patch` to `target`, as RFC 7396 describes.
+fn merge(target: &mut Value, patch: Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch;
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    let Value::Object(target) = target else {
+        unreachable!()
+    };
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(&key);
+        } else {
+            merge(target.entry(key).or_insert(Value::Null), value);
+        }
+    }
+}
+
+fn apply_operation(document: &mut Value, operation: PatchOperation) -> Result<(), PatchError> {
+    match operation {
+        PatchOperation::Add { path, value } => add(document, &path, value),
+        PatchOperation::Remove { path } => remove(document, &path).map(drop),
+        PatchOperation::Replace { path, value } => {
+            *lookup(document, &path)? = value;
+            Ok(())
+        }
+        PatchOperation::Move { from, path } => {
+            if path.starts_with(&format!("{from}/")) {
+                return Err(PatchError::Invalid(format!(
+                    "can't move {from:?} into itself"
+                )));
+            }
+            let value = remove(document, &from)?;
+            add(document, &path, value)
+        }
+        PatchOperation::Copy { from, path } => {
+            let value = lookup(document, &from)?.clone();
+            add(document, &path, value)
+        }
+        PatchOperation::Test { path, value } => {
+            if *lookup(document, &path)? == value {
+                Ok(())
+            } else {
+                Err(PatchError::TestFailed(path))
+            }
+        }
+    }
+}
+
+/// Splits a JSON Pointer into its unescaped tokens.
+fn tokens(path: &str) -> Result<Vec<String>, PatchError> {
+    if path.is_empty() {
+        return Ok(Vec::new());
+    }
+    let Some(path) = path.strip_prefix('/') else {
+        return Err(PatchError::Invalid(format!("invalid path {path:?}")));
+    };
+    Ok(path
+        .split('/')
+        .map(|token| token.replace("~1", "/").replace("~0", "~"))
+        .collect())
+}
+
+fn lookup<'a>(document: &'a mut Value, path: &str) -> Result<&'a mut Value, PatchError> {
+    let mut value = document;
+    for token in tokens(path)? {
+        value = match value {
+            Value::Object(object) => object.get_mut(&token),
+            Value::Array(array) => token
+                .parse::<usize>()
+                .ok()
+                .and_then(|index| array.get_mut(index)),
+            _ => None,
+        }
+        .ok_or_else(|| missing(path))?;
+    }
+    Ok(value)
+}
+
+/// The parent of the value at `path` and the last token of `path`.
+fn parent<'a>(document: &'a mut Value, path: &str) -> Result<(&'a mut Value, String), PatchError> {
+    let mut tokens = tokens(path)?;
+    let last = tokens
+        .pop()
+        .ok_or_else(|| PatchError::Invalid("the record can't be replaced".into()))?;
+    let mut value = document;
+    for token in &tokens {
+        value = match value {
+            Value::Object(object) => object.get_mut(token),
+            Value::Array(array) => token
+                .parse::<usize>()
+                .ok()
+                .and_then(|index| array.get_mut(index)),
+            _ => None,
+        }
+        .ok_or_else(|| missing(path))?;
+    }
+    Ok((value, last))
+}
+
+fn add(document: &mut Value, path: &str, value: Value) -> Result<(), PatchError> {
+    let (parent, last) = parent(document, path)?;
+    match parent {
+        Value::Object(object) => {
+            object.insert(last, value);
+        }
+        Value::Array(array) => {
+            let index = match last.as_str() {
+                "-" => array.len(),
+                index => index
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|index| *index <= array.len())
+                    .ok_or_else(|| missing(path))?,
+            };
+            array.insert(index, value);
+        }
+        _ => return Err(missing(path)),
+    }
+    Ok(())
+}
+
+fn remove(document: &mut Value, path: &str) -> Result<Value, PatchError> {
+    let (parent, last) = parent(document, path)?;
+    match parent {
+        Value::Object(object) => object.remove(&last),
+        Value::Array(array) => last
+            .parse::<usize>()
+            .ok()
+            .filter(|index| *index < array.len())
+            .map(|index| array.remove(index)),
+        _ => None,
+    }
+    .ok_or_else(|| missing(path))
+}
+
+fn missing(path: &str) -> PatchError {
+    PatchError::Invalid(format!("path {path:?} does not exist"))
+}
+
+fn unknown_column(column: &str) -> PatchError {
+    PatchError::Invalid(format!("column {column:?} does not exist"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn record() -> Map<String, Value> {
+        json!({
+            "id": 1,
+            "title": "a",
+            "settings": { "theme": "light", "tags": ["x", "y"] },
+        })
+        .as_object()
+        .cloned()
+        .unwrap()
+    }
+
+    fn apply(patch: Patch) -> Result<Value, PatchError> {
+        patch.apply(&record()).map(Value::Object)
+    }
+
+    #[test]
+    fn test_columns() {
+        let patch = |columns: Value| Patch::Columns(columns.as_object().cloned().unwrap());
+        assert_eq!(
+            apply(patch(json!({ "id": 1, "title": "b" }))),
+            Ok(json!({ "title": "b" }))
+        );
+        assert_eq!(apply(patch(json!({ "title": "a" }))), Ok(json!({})));
+        assert!(apply(patch(json!({ "missing": 1 }))).is_err());
+    }
+
+    #[test]
+    fn test_merge_patch() {
+        let patch = |patch: Value| Patch::Merge(patch.as_object().cloned().unwrap());
+        assert_eq!(
+            apply(patch(json!({
+                "title": "a",
+                "settings": { "theme": null, "lang": "en" },
+            }))),
+            Ok(json!({ "settings": { "tags": ["x", "y"], "lang": "en" } }))
+        );
+        assert_eq!(
+            apply(patch(json!({ "title": null }))),
+            Ok(json!({ "title": null }))
+        );
+        assert!(apply(patch(json!({ "missing": 1 }))).is_err());
+    }
+
+    #[test]
+    fn test_json_patch() {
+        let patch =
+            |operations: Value| Patch::Operations(serde_json::from_value(operations).unwrap());
+        assert_eq!(
+            apply(patch(json!([
+                { "op": "test", "path": "/title", "value": "a" },
+                { "op": "replace", "path": "/settings/theme", "value": "dark" },
+                { "op": "add", "path": "/settings/tags/-", "value": "z" },
+                { "op": "remove", "path": "/settings/tags/0" },
+                { "op": "copy", "from": "/settings/theme", "path": "/settings/previous" },
+            ]))),
+            Ok(json!({
+                "settings": { "theme": "dark", "previous": "dark", "tags": ["y", "z"] },
+            }))
+        );
+        assert_eq!(
+            apply(patch(json!([
+                { "op": "move", "from": "/settings/theme", "path": "/title" },
+            ]))),
+            Ok(json!({ "title": "light", "settings": { "tags": ["x", "y"] } }))
+        );
+        assert_eq!(
+            apply(patch(json!([{ "op": "remove", "path": "/title" }]))),
+            Ok(json!({ "title": null }))
+        );
+
+        let failed = apply(patch(
+            json!([{ "op": "test", "path": "/title", "value": "b" }]),
+        ));
+        assert_eq!(failed.unwrap_err().status(), StatusCode::CONFLICT);
+        for invalid in [
+            json!([{ "op": "replace", "path": "/settings/font", "value": 1 }]),
+            json!([{ "op": "add", "path": "/missing", "value": 1 }]),
+            json!([{ "op": "add", "path": "/settings/tags/5", "value": 1 }]),
+            json!([{ "op": "remove", "path": "" }]),
+            json!([{ "op": "move", "from": "/settings", "path": "/settings/copy" }]),
+        ] {
+            let err = apply(patch(invalid)).unwrap_err();
+            assert_eq!(err.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_from_request() {
+        let request = |content_type: &str, body: &str| {
+            Request::builder()
+                .header(header::CONTENT_TYPE, content_type)
+                .body(axum::body::Body::from(body.to_string()))
+                .unwrap()
+        };
+        let patch = Patch::from_request(request(MERGE_PATCH, r#"{"title":"b"}"#), &())
+            .await
+            .unwrap();
+        assert!(matches!(patch, Patch::Merge(_)));
+        let patch = Patch::from_request(
+            request(
+                "application/x-www-form-urlencoded",
+                "title=b&settings%5Btheme%5D=dark",
+            ),
+            &(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            patch,
+            Patch::Columns(
+                json!({ "title": "b", "settings": { "theme": "dark" } })
+                    .as_object()
+                    .cloned()
+                    .unwrap()
+            )
+        );
+        let patch = Patch::from_request(
+            request(
+                "application/json-patch+json; charset=utf-8",
+                r#"[{"op":"remove","path":"/title"}]"#,
+            ),
+            &(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            patch,
+            Patch::Operations(vec![PatchOperation::Remove {
+                path: "/title".into()
+            }])
+        );
+
+        let rejected = Patch::from_request(request("text/plain", "title=b"), &()).await;
+        assert_eq!(
+            rejected.unwrap_err().status(),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+        let rejected = Patch::from_request(request(JSON_PATCH, r#"{"op":"remove"}"#), &()).await;
+        assert_eq!(rejected.unwrap_err().status(), StatusCode::BAD_REQUEST);
+    }
+}
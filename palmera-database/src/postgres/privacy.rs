@@ -0,0 +1,624 @@
+//! # User data export and erasure
+//!
+//! Tooling for data subject requests. Tables holding personal data are declared in a
+//! [`Privacy`] registry as [`OwnedTable`]s, naming the column that references the owning
+//! user. From that ownership metadata:
+//!
+//! - `GET /me/export` returns every record the signed-in user owns as one JSON archive,
+//!   along with their files ([`export`]).
+//! - `POST /admin/users/{id}/erase` deletes or anonymizes the user's records in a single
+//!   transaction ([`erase`]), then deletes their files, and answers with an
+//!   [`ErasureReport`] signed with the registry's key, which can later be checked with
+//!   [`Privacy::verify`].
+//!
+//! File contents live in the storage backend rather than the database. The registry
+//! reaches them through the [`FileStore`] set with [`Privacy::with_file_store`], such as
+//! the buckets of `palmera_storage::bucket::Buckets`: the export includes each file's
+//! contents, base64 encoded, and erasure deletes the files once the records are gone.
+//! Files that could not be deleted, all of them without a store, are listed in the
+//! report's `undeleted_files`.
+//!
+//! `/me/export` reads the user id from the [`SessionContext`] extension. Like the other
+//! admin routes, the erasure route does no authorization of its own and is expected to
+//! be mounted behind the application's admin guard.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # async fn run(db: sqlx::Pool<sqlx::Postgres>) {
+//! use std::sync::Arc;
+//!
+//! use axum::Extension;
+//! use palmera_database::postgres::privacy::{self, Erasure, OwnedTable, Privacy};
+//!
+//! let privacy = Privacy::new(b"report signing key")
+//!     .with_table(OwnedTable::new("posts", "author_id").with_files(&["cover"]))
+//!     .with_table(OwnedTable::new("orders", "customer_id").with_erasure(Erasure::Anonymize {
+//!         columns: vec!["customer_id".to_string(), "shipping_address".to_string()],
+//!     }));
+//! let (router, _api) = privacy::router()
+//!     .layer(Extension(Arc::new(privacy)))
+//!     .layer(Extension(db))
+//!     .split_for_parts();
+//! # }
+//! ```
+
+use std::{collections::BTreeMap, future::Future, pin::Pin, sync::Arc};
+
+use axum::{
+    Extension, Json,
+    extract::Path,
+    http::{StatusCode, header},
+    response::IntoResponse,
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::{PgConnection, Pool, Postgres};
+use utoipa::ToSchema;
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+use crate::postgres::{
+    helpers::{quote_ident, quote_qualified},
+    session::SessionContext,
+};
+
+/// Error of a [`FileStore`] operation.
+pub type FileError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Future returned by [`FileStore`] methods.
+pub type FileFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, FileError>> + Send + 'a>>;
+
+/// Where the files named by the file columns of [`OwnedTable`]s are stored.
+pub trait FileStore: Send + Sync {
+    /// Contents of the file `key`, or `None` if there is none.
+    fn read<'a>(&'a self, key: &'a str) -> FileFuture<'a, Option<Vec<u8>>>;
+
+    /// Deletes the file `key`. Deleting a file that doesn't exist succeeds.
+    fn delete<'a>(&'a self, key: &'a str) -> FileFuture<'a, ()>;
+}
+
+/// What erasure does to a user's records in a table.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Erasure {
+    #[default]
+    Delete,
+    /// Sets `columns` to `NULL` and keeps the rows, e.g. for records that must be
+    /// retained for accounting. Include the owner column to detach the rows from the
+    /// user.
+    Anonymize { columns: Vec<String> },
+}
+
+/// A table whose rows belong to the user referenced by `owner_column`.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+pub struct OwnedTable {
+    /// Table name, optionally schema qualified.
+    pub table: String,
+    pub owner_column: String,
+    pub erasure: Erasure,
+    /// Columns holding storage keys of the user's files.
+    pub file_columns: Vec<String>,
+}
+
+impl OwnedTable {
+    /// A table whose rows are deleted on erasure.
+    pub fn new(table: &str, owner_column: &str) -> Self {
+        Self {
+            table: table.to_string(),
+            owner_column: owner_column.to_string(),
+            erasure: Erasure::default(),
+            file_columns: Vec::new(),
+        }
+    }
+
+    pub fn with_erasure(mut self, erasure: Erasure) -> Self {
+        self.erasure = erasure;
+        self
+    }
+
+    pub fn with_files(mut self, columns: &[&str]) -> Self {
+        self.file_columns = columns.iter().map(|column| column.to_string()).collect();
+        self
+    }
+
+    /// Condition matching the rows owned by the user bound to `$1`.
+    fn owned_by(&self) -> String {
+        format!("{}::text = $1", quote_ident(&self.owner_column))
+    }
+}
+
+/// A file referenced by a user's record.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+pub struct FileRef {
+    pub table: String,
+    pub column: String,
+    /// Storage key of the file.
+    pub key: String,
+    /// Contents of the file, base64 encoded; set in exports if the [`FileStore`] has it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+}
+
+/// Everything a user owns, as produced by [`export`].
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct UserExport {
+    pub user_id: String,
+    pub exported_at: DateTime<Utc>,
+    /// The user's records by table.
+    #[schema(value_type = Object)]
+    pub tables: BTreeMap<String, Vec<serde_json::Value>>,
+    pub files: Vec<FileRef>,
+}
+
+/// Records of a table affected by an erasure.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+pub struct ErasedTable {
+    pub table: String,
+    pub erasure: Erasure,
+    pub rows: u64,
+}
+
+/// Outcome of [`erase`].
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct ErasureReport {
+    pub user_id: String,
+    pub completed_at: DateTime<Utc>,
+    pub tables: Vec<ErasedTable>,
+    /// Files of the user.
+    pub files: Vec<FileRef>,
+    /// Files that could not be deleted from storage, to be removed by hand.
+    #[serde(default)]
+    pub undeleted_files: Vec<FileRef>,
+}
+
+/// An [`ErasureReport`] with the hex HMAC-SHA256 of its JSON encoding.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct SignedReport {
+    pub report: ErasureReport,
+    pub signature: String,
+}
+
+/// Tables holding user data, where their files are stored, and the key erasure reports
+/// are signed with.
+#[derive(Clone)]
+pub struct Privacy {
+    tables: Vec<OwnedTable>,
+    file_store: Option<Arc<dyn FileStore>>,
+    key: Vec<u8>,
+}
+
+impl std::fmt::Debug for Privacy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Privacy")
+            .field("tables", &self.tables)
+            .field("file_store", &self.file_store.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Privacy {
+    /// Creates a registry without tables, signing reports with `key`.
+    pub fn new(key: &[u8]) -> Self {
+        Self {
+            tables: Vec::new(),
+            file_store: None,
+            key: key.to_vec(),
+        }
+    }
+
+    pub fn with_table(mut self, table: OwnedTable) -> Self {
+        self.tables.push(table);
+        self
+    }
+
+    /// Reads and deletes the files of the file columns through `store`.
+    pub fn with_file_store(mut self, store: Arc<dyn FileStore>) -> Self {
+        self.file_store = Some(store);
+        self
+    }
+
+    /// Fills in the contents of `files` from the [`FileStore`]. Files it doesn't have,
+    /// and all of them without a store, are left without contents.
+    pub async fn read_files(&self, files: &mut [FileRef]) -> Result<(), FileError> {
+        let Some(store) = &self.file_store else {
+            return Ok(());
+        };
+        for file in files {
+            file.content = store
+                .read(&file.key)
+                .await?
+                .map(|bytes| STANDARD.encode(bytes));
+        }
+        Ok(())
+    }
+
+    /// Deletes `files` from the [`FileStore`], returning those that could not be deleted;
+    /// all of them without a store.
+    pub async fn delete_files(&self, files: &[FileRef]) -> Vec<FileRef> {
+        let Some(store) = &self.file_store else {
+            return files.to_vec();
+        };
+        let mut undeleted = Vec::new();
+        for file in files {
+            if let Err(err) = store.delete(&file.key).await {
+                tracing::warn!(%err, key = file.key, "failed to delete an erased user's file");
+                undeleted.push(file.clone());
+            }
+        }
+        undeleted
+    }
+
+    pub fn tables(&self) -> &[OwnedTable] {
+        &self.tables
+    }
+
+    /// Signs `report`.
+    pub fn sign(&self, report: ErasureReport) -> SignedReport {
+        let signature = self
+            .mac(&report)
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        SignedReport { report, signature }
+    }
+
+    /// Returns whether `signed` was signed with this registry's key and left unchanged.
+    pub fn verify(&self, signed: &SignedReport) -> bool {
+        let signature = &signed.signature;
+        let Some(bytes) = (0..signature.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(signature.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<_>>>()
+        else {
+            return false;
+        };
+        self.mac(&signed.report).verify_slice(&bytes).is_ok()
+    }
+
+    fn mac(&self, report: &ErasureReport) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(&serde_json::to_vec(report).expect("reports serialize to JSON"));
+        mac
+    }
+}
+
+/// Reads the file keys of `user_id` in every table of `privacy`.
+async fn files(
+    conn: &mut PgConnection,
+    privacy: &Privacy,
+    user_id: &str,
+) -> Result<Vec<FileRef>, sqlx::Error> {
+    let mut files = Vec::new();
+    for table in &privacy.tables {
+        for column in &table.file_columns {
+            let keys: Vec<String> = sqlx::query_scalar(&format!(
+                "SELECT {column}::text FROM {} WHERE {} AND {column} IS NOT NULL",
+                quote_qualified(&table.table),
+                table.owned_by(),
+                column = quote_ident(column),
+            ))
+            .bind(user_id)
+            .fetch_all(&mut *conn)
+            .await?;
+
+            files.extend(keys.into_iter().map(|key| FileRef {
+                table: table.table.clone(),
+                column: column.clone(),
+                key,
+                content: None,
+            }));
+        }
+    }
+    Ok(files)
+}
+
+/// Collects the records and file keys owned by `user_id`. The file contents are read
+/// separately, with [`Privacy::read_files`].
+pub async fn export(
+    conn: &mut PgConnection,
+    privacy: &Privacy,
+    user_id: &str,
+) -> Result<UserExport, sqlx::Error> {
+    let mut tables = BTreeMap::new();
+    for table in &privacy.tables {
+        let rows: Vec<serde_json::Value> = sqlx::query_scalar(&format!(
+            "SELECT to_jsonb(t) FROM {} t WHERE {}",
+            quote_qualified(&table.table),
+            table.owned_by(),
+        ))
+        .bind(user_id)
+        .fetch_all(&mut *conn)
+        .await?;
+        tables.insert(table.table.clone(), rows);
+    }
+
+    Ok(UserExport {
+        user_id: user_id.to_string(),
+        exported_at: Utc::now(),
+        tables,
+        files: files(conn, privacy, user_id).await?,
+    })
+}
+
+/// Deletes or anonymizes the records of `user_id` in every table of `privacy`. Run it
+/// in a transaction so a failure in one table leaves the others untouched, and delete
+/// the reported files with [`Privacy::delete_files`] once it is committed.
+pub async fn erase(
+    conn: &mut PgConnection,
+    privacy: &Privacy,
+    user_id: &str,
+) -> Result<ErasureReport, sqlx::Error> {
+    let files = files(conn, privacy, user_id).await?;
+
+    let mut tables = Vec::with_capacity(privacy.tables.len());
+    for table in &privacy.tables {
+        let name = quote_qualified(&table.table);
+        let sql = match &table.erasure {
+            Erasure::Delete => format!("DELETE FROM {} WHERE {}", name, table.owned_by()),
+            Erasure::Anonymize { columns } => format!(
+                "UPDATE {} SET {} WHERE {}",
+                name,
+                columns
+                    .iter()
+                    .map(|column| format!("{} = NULL", quote_ident(column)))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                table.owned_by(),
+            ),
+        };
+        let result = sqlx::query(&sql).bind(user_id).execute(&mut *conn).await?;
+
+        tables.push(ErasedTable {
+            table: table.table.clone(),
+            erasure: table.erasure.clone(),
+            rows: result.rows_affected(),
+        });
+    }
+
+    Ok(ErasureReport {
+        user_id: user_id.to_string(),
+        completed_at: Utc::now(),
+        tables,
+        files,
+        undeleted_files: Vec::new(),
+    })
+}
+
+/// Downloads every record and file owned by the signed-in user.
+#[utoipa::path(
+    get,
+    path = "/me/export",
+    responses((status = 200, body = UserExport), (status = 401)),
+    security(("bearer_auth" = []))
+)]
+async fn export_me(
+    Extension(privacy): Extension<Arc<Privacy>>,
+    Extension(db): Extension<Pool<Postgres>>,
+    session: Option<Extension<SessionContext>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let user_id = session
+        .and_then(|Extension(ctx)| ctx.user_id)
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+
+    let mut tx = db
+        .begin()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let mut export = export(&mut tx, &privacy, &user_id).await.map_err(|err| {
+        tracing::error!(%err, user_id, "user export failed");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    privacy.read_files(&mut export.files).await.map_err(|err| {
+        tracing::error!(%err, user_id, "reading the user's files failed");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok((
+        [(
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"export.json\"",
+        )],
+        Json(export),
+    ))
+}
+
+/// Erases a user's records and files and returns the signed completion report.
+#[utoipa::path(
+    post,
+    path = "/admin/users/{id}/erase",
+    responses((status = 200, body = SignedReport))
+)]
+async fn erase_user(
+    Extension(privacy): Extension<Arc<Privacy>>,
+    Extension(db): Extension<Pool<Postgres>>,
+    Path(id): Path<String>,
+) -> Result<Json<SignedReport>, StatusCode> {
+    let result = async {
+        let mut tx = db.begin().await?;
+        let report = erase(&mut tx, &privacy, &id).await?;
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(report)
+    }
+    .await;
+
+    match result {
+        Ok(mut report) => {
+            report.undeleted_files = privacy.delete_files(&report.files).await;
+            tracing::info!(user_id = id, "user data erased");
+            Ok(Json(privacy.sign(report)))
+        }
+        Err(err) => {
+            tracing::error!(%err, user_id = id, "user erasure failed");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+pub fn router() -> OpenApiRouter {
+    OpenApiRouter::new()
+        .routes(routes!(export_me))
+        .routes(routes!(erase_user))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::{collections::HashMap, sync::Mutex};
+
+    /// Files kept in memory, shared with the test through the `Arc`.
+    #[derive(Default, Clone)]
+    struct MemoryFiles(Arc<Mutex<HashMap<String, Vec<u8>>>>);
+
+    impl FileStore for MemoryFiles {
+        fn read<'a>(&'a self, key: &'a str) -> FileFuture<'a, Option<Vec<u8>>> {
+            let file = self.0.lock().unwrap().get(key).cloned();
+            Box::pin(async move { Ok(file) })
+        }
+
+        fn delete<'a>(&'a self, key: &'a str) -> FileFuture<'a, ()> {
+            self.0.lock().unwrap().remove(key);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    const ALICE: &str = "7f1c1f4e-0c4b-4c4e-9a43-0e0d8d5e2a01";
+    const BOB: &str = "1b9d6bcd-bbfd-4b2d-9b5d-ab8dfbbd4bed";
+
+    async fn setup(db: &Pool<Postgres>) -> sqlx::Result<Privacy> {
+        setup_with(db, MemoryFiles::default()).await
+    }
+
+    async fn setup_with(db: &Pool<Postgres>, files: MemoryFiles) -> sqlx::Result<Privacy> {
+        for sql in [
+            "CREATE TABLE posts (id serial PRIMARY KEY, author_id uuid NOT NULL, title text, \
+             cover text)",
+            "CREATE SCHEMA billing",
+            "CREATE TABLE billing.orders (id serial PRIMARY KEY, customer_id uuid, \
+             address text, total integer)",
+        ] {
+            sqlx::query(sql).execute(db).await?;
+        }
+        for (user, title, cover) in [
+            (ALICE, "hello", Some("covers/a.png")),
+            (ALICE, "again", None),
+            (BOB, "bob's", Some("covers/b.png")),
+        ] {
+            sqlx::query("INSERT INTO posts (author_id, title, cover) VALUES ($1::uuid, $2, $3)")
+                .bind(user)
+                .bind(title)
+                .bind(cover)
+                .execute(db)
+                .await?;
+        }
+        sqlx::query(
+            "INSERT INTO billing.orders (customer_id, address, total) VALUES ($1::uuid, 'Main St', 42)",
+        )
+        .bind(ALICE)
+        .execute(db)
+        .await?;
+
+        for key in ["covers/a.png", "covers/b.png"] {
+            files
+                .0
+                .lock()
+                .unwrap()
+                .insert(key.to_string(), b"png".to_vec());
+        }
+
+        Ok(Privacy::new(b"secret")
+            .with_file_store(Arc::new(files))
+            .with_table(OwnedTable::new("posts", "author_id").with_files(&["cover"]))
+            .with_table(
+                OwnedTable::new("billing.orders", "customer_id").with_erasure(Erasure::Anonymize {
+                    columns: vec!["customer_id".to_string(), "address".to_string()],
+                }),
+            ))
+    }
+
+    #[sqlx::test]
+    async fn test_export(db: Pool<Postgres>) -> sqlx::Result<()> {
+        let privacy = setup(&db).await?;
+        let mut conn = db.acquire().await?;
+
+        let mut export = export(&mut conn, &privacy, ALICE).await?;
+        assert_eq!(export.tables["posts"].len(), 2);
+        assert_eq!(export.tables["posts"][0]["title"], "hello");
+        assert_eq!(export.tables["billing.orders"][0]["total"], json!(42));
+        privacy.read_files(&mut export.files).await.unwrap();
+        assert_eq!(
+            export.files,
+            [FileRef {
+                table: "posts".to_string(),
+                column: "cover".to_string(),
+                key: "covers/a.png".to_string(),
+                content: Some(STANDARD.encode(b"png")),
+            }]
+        );
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_erase(db: Pool<Postgres>) -> sqlx::Result<()> {
+        let files = MemoryFiles::default();
+        let privacy = Arc::new(setup_with(&db, files.clone()).await?);
+
+        let Json(signed) = erase_user(
+            Extension(privacy.clone()),
+            Extension(db.clone()),
+            Path(ALICE.to_string()),
+        )
+        .await
+        .unwrap();
+        assert!(privacy.verify(&signed));
+        assert_eq!(signed.report.files.len(), 1);
+        assert!(signed.report.undeleted_files.is_empty());
+        let stored: Vec<_> = files.0.lock().unwrap().keys().cloned().collect();
+        assert_eq!(stored, ["covers/b.png"]);
+        assert_eq!(
+            signed
+                .report
+                .tables
+                .iter()
+                .map(|table| table.rows)
+                .collect::<Vec<_>>(),
+            [2, 1]
+        );
+
+        let titles: Vec<String> = sqlx::query_scalar("SELECT title FROM posts")
+            .fetch_all(&db)
+            .await?;
+        assert_eq!(titles, ["bob's"]);
+        let order: (Option<String>, Option<String>, i32) =
+            sqlx::query_as("SELECT customer_id::text, address, total FROM billing.orders")
+                .fetch_one(&db)
+                .await?;
+        assert_eq!(order, (None, None, 42));
+
+        let mut tampered = signed.clone();
+        tampered.report.tables[0].rows = 0;
+        assert!(!privacy.verify(&tampered));
+        assert!(!Privacy::new(b"other").verify(&signed));
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_export_requires_user(db: Pool<Postgres>) -> sqlx::Result<()> {
+        let privacy = Arc::new(setup(&db).await?);
+
+        let anonymous = export_me(
+            Extension(privacy),
+            Extension(db),
+            Some(Extension(SessionContext::anonymous("anon"))),
+        )
+        .await;
+        assert_eq!(anonymous.err(), Some(StatusCode::UNAUTHORIZED));
+        Ok(())
+    }
+}
@@ -0,0 +1,518 @@
+//! # Remote procedure calls
+//!
+//! Exposes stored SQL functions as `POST /rpc/{function}` endpoints, so business logic
+//! written in the database is reachable through the API. Only functions allow-listed
+//! in an [`RpcRegistry`] can be called; their arguments and result shape are
+//! introspected from `pg_proc` with [`describe`].
+//!
+//! The request body is a JSON object of named arguments, each cast to the parameter's
+//! type. Arguments with a default may be omitted. Depending on the function the
+//! response is:
+//!
+//! - an array of rows for set-returning functions (`RETURNS SETOF` / `RETURNS TABLE`),
+//! - an object for functions returning a single composite row,
+//! - the bare value for functions returning a scalar.
+//!
+//! When a [`SessionContext`] extension is present, the call runs in its session so
+//! row level security and the statement timeout apply. In [`sandbox`] mode the call
+//! returns its result, but whatever the function wrote is rolled back.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # async fn run(db: sqlx::Pool<sqlx::Postgres>) {
+//! use std::sync::Arc;
+//!
+//! use axum::Extension;
+//! use palmera_database::postgres::rpc::{self, RpcRegistry};
+//!
+//! let registry = RpcRegistry::new().allow("search_posts").allow("billing.invoice_total");
+//! let (router, _api) = rpc::router()
+//!     .layer(Extension(Arc::new(registry)))
+//!     .layer(Extension(db))
+//!     .split_for_parts();
+//! # }
+//! ```
+
+use std::{collections::HashSet, sync::Arc};
+
+use axum::{Extension, Json, extract::Path, http::StatusCode, middleware};
+use serde::Serialize;
+use sqlx::{PgConnection, Pool, Postgres, postgres::types::Oid};
+use utoipa::ToSchema;
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+use crate::postgres::{
+    helpers::{quote_ident, quote_qualified},
+    sandbox::{self, Sandbox},
+    session::{SessionContext, begin_session},
+};
+
+/// Schema of functions named without one.
+pub const DEFAULT_SCHEMA: &str = "public";
+
+/// Functions that may be called through `/rpc`.
+#[derive(Debug, Clone, Default)]
+pub struct RpcRegistry {
+    functions: HashSet<String>,
+}
+
+impl RpcRegistry {
+    /// Creates a registry allowing no functions.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allows calling `function`, optionally schema qualified (`billing.invoice_total`).
+    pub fn allow(mut self, function: &str) -> Self {
+        self.functions.insert(qualify(function));
+        self
+    }
+
+    /// Returns whether `function` may be called.
+    pub fn is_allowed(&self, function: &str) -> bool {
+        self.functions.contains(&qualify(function))
+    }
+}
+
+fn qualify(function: &str) -> String {
+    if function.contains('.') {
+        function.to_string()
+    } else {
+        format!("{}.{}", DEFAULT_SCHEMA, function)
+    }
+}
+
+/// An input parameter of a function.
+#[derive(Debug, Clone, Serialize, ToSchema, PartialEq)]
+pub struct Argument {
+    pub name: String,
+    /// Type of the parameter as written by `format_type`, e.g. `integer` or `text[]`.
+    pub data_type: String,
+    /// Whether the parameter has a default and may be omitted.
+    pub has_default: bool,
+}
+
+/// Shape of a function's result.
+#[derive(Debug, Clone, Copy, Serialize, ToSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Returns {
+    /// A single value.
+    Scalar,
+    /// A single composite row.
+    Row,
+    /// Any number of rows.
+    Rows,
+}
+
+/// A callable function, as introspected by [`describe`].
+#[derive(Debug, Clone, Serialize, ToSchema, PartialEq)]
+pub struct Function {
+    pub schema: String,
+    pub name: String,
+    pub arguments: Vec<Argument>,
+    pub returns: Returns,
+}
+
+/// Errors from calling a function.
+#[derive(Debug)]
+pub enum RpcError {
+    /// The function does not exist or is not allow-listed.
+    NotFound,
+    /// The function is overloaded, so named arguments can't select one version.
+    Ambiguous,
+    /// An argument is unknown, missing or has the wrong shape.
+    InvalidArgument(String),
+    Database(sqlx::Error),
+}
+
+impl RpcError {
+    /// HTTP status the error is reported with.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::Ambiguous | Self::InvalidArgument(_) => StatusCode::BAD_REQUEST,
+            Self::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl std::fmt::Display for RpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotFound => f.write_str("function not found"),
+            Self::Ambiguous => f.write_str("function is overloaded"),
+            Self::InvalidArgument(message) => f.write_str(message),
+            Self::Database(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+impl From<sqlx::Error> for RpcError {
+    fn from(err: sqlx::Error) -> Self {
+        Self::Database(err)
+    }
+}
+
+/// Introspects `function` from `pg_proc`. Returns `None` if it does not exist.
+///
+/// # Errors
+///
+/// Returns [`RpcError::Ambiguous`] if the function is overloaded.
+pub async fn describe(
+    conn: &mut PgConnection,
+    function: &str,
+) -> Result<Option<Function>, RpcError> {
+    let qualified = qualify(function);
+    let (schema, name) = qualified.split_once('.').unwrap_or_default();
+
+    let found: Vec<(Oid, bool, bool, i16)> = sqlx::query_as(
+        "SELECT p.oid, p.proretset, \
+                t.typtype = 'c' OR p.prorettype = 'record'::regtype, \
+                p.pronargdefaults \
+         FROM pg_proc p \
+         JOIN pg_namespace n ON n.oid = p.pronamespace \
+         JOIN pg_type t ON t.oid = p.prorettype \
+         WHERE n.nspname = $1 AND p.proname = $2 AND p.prokind = 'f'",
+    )
+    .bind(schema)
+    .bind(name)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let (oid, set, composite, defaults) = match found.as_slice() {
+        [] => return Ok(None),
+        [found] => *found,
+        _ => return Err(RpcError::Ambiguous),
+    };
+
+    let arguments: Vec<(Option<String>, String)> = sqlx::query_as(
+        "SELECT arg.name, format_type(arg.type, NULL) \
+         FROM pg_proc p \
+         CROSS JOIN LATERAL unnest( \
+             coalesce(p.proallargtypes, p.proargtypes::oid[]), p.proargnames, p.proargmodes \
+         ) WITH ORDINALITY AS arg(type, name, mode, position) \
+         WHERE p.oid = $1 AND coalesce(arg.mode, 'i') IN ('i', 'b', 'v') \
+         ORDER BY arg.position",
+    )
+    .bind(oid)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let required = arguments.len().saturating_sub(defaults as usize);
+    let arguments = arguments
+        .into_iter()
+        .enumerate()
+        .map(|(index, (name, data_type))| Argument {
+            name: name.unwrap_or_default(),
+            data_type,
+            has_default: index >= required,
+        })
+        .collect();
+
+    Ok(Some(Function {
+        schema: schema.to_string(),
+        name: name.to_string(),
+        arguments,
+        returns: match (set, composite) {
+            (true, _) => Returns::Rows,
+            (false, true) => Returns::Row,
+            (false, false) => Returns::Scalar,
+        },
+    }))
+}
+
+/// Calls `function` with the named arguments in `args` and returns its result as JSON.
+///
+/// # Errors
+///
+/// Returns [`RpcError::InvalidArgument`] if `args` names an unknown parameter, omits
+/// one without a default, or has a value that can't be cast to its type.
+pub async fn call(
+    conn: &mut PgConnection,
+    function: &Function,
+    args: &serde_json::Map<String, serde_json::Value>,
+) -> Result<serde_json::Value, RpcError> {
+    if let Some(unknown) = args
+        .keys()
+        .find(|key| !function.arguments.iter().any(|arg| &arg.name == *key))
+    {
+        return Err(RpcError::InvalidArgument(format!(
+            "unknown argument: {}",
+            unknown
+        )));
+    }
+
+    let mut params = Vec::new();
+    for arg in &function.arguments {
+        if args.contains_key(&arg.name) {
+            params.push(format!("{} => {}", quote_ident(&arg.name), cast(arg)));
+        } else if !arg.has_default {
+            return Err(RpcError::InvalidArgument(format!(
+                "missing argument: {}",
+                arg.name
+            )));
+        }
+    }
+
+    let call = format!(
+        "{}({})",
+        quote_qualified(&format!("{}.{}", function.schema, function.name)),
+        params.join(", ")
+    );
+    let sql = match function.returns {
+        Returns::Rows => format!(
+            "SELECT coalesce(jsonb_agg(to_jsonb(r)), '[]'::jsonb) FROM {} AS r",
+            call
+        ),
+        Returns::Row => format!("SELECT to_jsonb(r) FROM {} AS r", call),
+        Returns::Scalar => format!("SELECT to_jsonb({})", call),
+    };
+
+    let result: Option<sqlx::types::Json<serde_json::Value>> = sqlx::query_scalar(&sql)
+        .bind(sqlx::types::Json(args))
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(
+            |err| match err.as_database_error().and_then(|err| err.code()) {
+                // Class 22, data exceptions: an argument that can't be cast to its type.
+                Some(code) if code.starts_with("22") => RpcError::InvalidArgument(err.to_string()),
+                _ => RpcError::Database(err),
+            },
+        )?;
+
+    Ok(result.map(|result| result.0).unwrap_or_default())
+}
+
+/// The expression reading `arg` from the JSON arguments bound to `$1`.
+fn cast(arg: &Argument) -> String {
+    let key = arg.name.replace('\'', "''");
+    if arg.data_type == "json" || arg.data_type == "jsonb" {
+        format!("($1::jsonb -> '{}')::{}", key, arg.data_type)
+    } else if arg.data_type.ends_with("[]") {
+        format!(
+            "ARRAY(SELECT jsonb_array_elements_text($1::jsonb -> '{}'))::{}",
+            key, arg.data_type
+        )
+    } else {
+        format!("($1::jsonb ->> '{}')::{}", key, arg.data_type)
+    }
+}
+
+/// Calls an allow-listed function with the JSON request body as named arguments.
+#[utoipa::path(
+    post,
+    path = "/rpc/{function}",
+    request_body = Object,
+    responses(
+        (status = 200, body = Object),
+        (status = 400),
+        (status = 404)
+    )
+)]
+async fn call_function(
+    Extension(registry): Extension<Arc<RpcRegistry>>,
+    Extension(db): Extension<Pool<Postgres>>,
+    session: Option<Extension<SessionContext>>,
+    sandbox: Sandbox,
+    Path(function): Path<String>,
+    Json(args): Json<serde_json::Map<String, serde_json::Value>>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    if !registry.is_allowed(&function) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let result = async {
+        let mut tx = match session {
+            Some(Extension(ctx)) => begin_session(&db, &ctx).await?,
+            None => db.begin().await?,
+        };
+        let function = describe(&mut tx, &function)
+            .await?
+            .ok_or(RpcError::NotFound)?;
+        let result = call(&mut tx, &function, &args).await?;
+        sandbox.finish(tx).await?;
+        Ok::<_, RpcError>(result)
+    }
+    .await;
+
+    result.map(Json).map_err(|err| {
+        if let RpcError::Database(err) = &err {
+            tracing::error!(%err, function, "rpc call failed");
+        }
+        err.status()
+    })
+}
+
+pub fn router() -> OpenApiRouter {
+    OpenApiRouter::new()
+        .routes(routes!(call_function))
+        .layer(middleware::from_fn(sandbox::mark_responses))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    async fn setup(db: &Pool<Postgres>) -> sqlx::Result<()> {
+        for sql in [
+            "CREATE FUNCTION add(a integer, b integer DEFAULT 1) RETURNS integer \
+             LANGUAGE sql AS 'SELECT a + b'",
+            "CREATE FUNCTION series(n integer) RETURNS TABLE (i integer, label text) \
+             LANGUAGE sql AS 'SELECT i, ''#'' || i FROM generate_series(1, n) i'",
+            "CREATE FUNCTION pair(tags text[]) RETURNS TABLE (first text, total integer) \
+             LANGUAGE sql AS 'SELECT tags[1], cardinality(tags)' ROWS 1",
+            "CREATE FUNCTION echo(payload jsonb) RETURNS jsonb LANGUAGE sql AS 'SELECT payload'",
+        ] {
+            sqlx::query(sql).execute(db).await?;
+        }
+        Ok(())
+    }
+
+    async fn rpc(
+        db: &Pool<Postgres>,
+        function: &str,
+        args: serde_json::Value,
+    ) -> Result<serde_json::Value, RpcError> {
+        let mut conn = db.acquire().await?;
+        let function = describe(&mut conn, function)
+            .await?
+            .ok_or(RpcError::NotFound)?;
+        call(&mut conn, &function, args.as_object().unwrap()).await
+    }
+
+    #[sqlx::test]
+    async fn test_describe(db: Pool<Postgres>) -> sqlx::Result<()> {
+        setup(&db).await?;
+        let mut conn = db.acquire().await?;
+
+        let add = describe(&mut conn, "add").await.unwrap().unwrap();
+        assert_eq!(add.returns, Returns::Scalar);
+        assert_eq!(
+            add.arguments,
+            [
+                Argument {
+                    name: "a".to_string(),
+                    data_type: "integer".to_string(),
+                    has_default: false,
+                },
+                Argument {
+                    name: "b".to_string(),
+                    data_type: "integer".to_string(),
+                    has_default: true,
+                },
+            ]
+        );
+
+        let series = describe(&mut conn, "public.series").await.unwrap().unwrap();
+        assert_eq!(series.returns, Returns::Rows);
+        assert_eq!(series.arguments.len(), 1);
+
+        assert!(describe(&mut conn, "missing").await.unwrap().is_none());
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_call(db: Pool<Postgres>) -> sqlx::Result<()> {
+        setup(&db).await?;
+
+        assert_eq!(rpc(&db, "add", json!({ "a": 2 })).await.unwrap(), json!(3));
+        assert_eq!(
+            rpc(&db, "add", json!({ "a": 2, "b": 5 })).await.unwrap(),
+            json!(7)
+        );
+        assert_eq!(
+            rpc(&db, "series", json!({ "n": 2 })).await.unwrap(),
+            json!([{ "i": 1, "label": "#1" }, { "i": 2, "label": "#2" }])
+        );
+        assert_eq!(
+            rpc(&db, "pair", json!({ "tags": ["a", "b"] }))
+                .await
+                .unwrap(),
+            json!([{ "first": "a", "total": 2 }])
+        );
+        assert_eq!(
+            rpc(&db, "echo", json!({ "payload": { "nested": [1] } }))
+                .await
+                .unwrap(),
+            json!({ "nested": [1] })
+        );
+
+        assert!(matches!(
+            rpc(&db, "add", json!({})).await,
+            Err(RpcError::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            rpc(&db, "add", json!({ "a": 1, "c": 2 })).await,
+            Err(RpcError::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            rpc(&db, "add", json!({ "a": "one" })).await,
+            Err(RpcError::InvalidArgument(_))
+        ));
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_endpoint_requires_allow_list(db: Pool<Postgres>) -> sqlx::Result<()> {
+        setup(&db).await?;
+        let registry = Arc::new(RpcRegistry::new().allow("public.add"));
+        let args = || Json(json!({ "a": 1 }).as_object().unwrap().clone());
+
+        let Json(result) = call_function(
+            Extension(registry.clone()),
+            Extension(db.clone()),
+            None,
+            Sandbox::default(),
+            Path("add".to_string()),
+            args(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result, json!(2));
+
+        let status = call_function(
+            Extension(registry),
+            Extension(db),
+            None,
+            Sandbox::default(),
+            Path("series".to_string()),
+            args(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_sandboxed_call(db: Pool<Postgres>) -> sqlx::Result<()> {
+        for sql in [
+            "CREATE TABLE counters (n integer NOT NULL)",
+            "CREATE FUNCTION bump() RETURNS integer LANGUAGE sql \
+             AS 'INSERT INTO counters VALUES (1) RETURNING (SELECT count(*) + 1 FROM counters)'",
+        ] {
+            sqlx::query(sql).execute(&db).await?;
+        }
+        let registry = Arc::new(RpcRegistry::new().allow("bump"));
+        let bump = |sandbox| {
+            call_function(
+                Extension(registry.clone()),
+                Extension(db.clone()),
+                None,
+                Sandbox(sandbox),
+                Path("bump".to_string()),
+                Json(serde_json::Map::new()),
+            )
+        };
+
+        assert_eq!(bump(true).await.unwrap().0, json!(1));
+        assert_eq!(bump(true).await.unwrap().0, json!(1));
+        assert_eq!(bump(false).await.unwrap().0, json!(1));
+        assert_eq!(bump(true).await.unwrap().0, json!(2));
+        Ok(())
+    }
+}
@@ -0,0 +1,150 @@
+//! # Sandbox mode
+//!
+//! A write request carrying the [`SANDBOX_HEADER`] header set to `true` runs as usual,
+//! but its transaction is always rolled back: the response is what the write would have
+//! returned, and nothing is persisted. Developers and admin UIs use it to preview the
+//! effects of a write, including defaults, triggers and constraint violations, without
+//! touching the data.
+//!
+//! Handlers take the [`Sandbox`] extractor and end their transaction with
+//! [`Sandbox::finish`] instead of committing it. Deferred constraints are checked before
+//! the rollback, so a sandboxed write fails exactly like the real one would. Side effects
+//! outside the database, such as quota usage, copied files or change listeners, are
+//! skipped.
+//!
+//! [`mark_responses`] echoes the header on the responses of sandboxed requests, so
+//! clients can tell a preview from a committed write. The batch, delete, duplicate, RPC
+//! and sync routes of [`crate::postgres`] honor the header.
+//!
+//! # Example
+//!
+//! ```http
+//! POST /batch HTTP/1.1
+//! X-Palmera-Sandbox: true
+//!
+//! [{ "op": "create", "table": "orders", "data": { "total": 30 } }]
+//! ```
+
+use std::convert::Infallible;
+
+use axum::{
+    extract::{FromRequestParts, Request},
+    http::{HeaderMap, HeaderValue, request::Parts},
+    middleware::Next,
+    response::Response,
+};
+use sqlx::{Postgres, Transaction};
+
+/// Header requesting, and on responses confirming, sandbox mode.
+pub const SANDBOX_HEADER: &str = "x-palmera-sandbox";
+
+/// Whether a request runs in sandbox mode.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Sandbox(pub bool);
+
+impl Sandbox {
+    /// Reads the [`SANDBOX_HEADER`] of a request. `true` and `1` enable sandbox mode.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let enabled = headers
+            .get(SANDBOX_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| {
+                let value = value.trim();
+                value.eq_ignore_ascii_case("true") || value == "1"
+            });
+        Self(enabled)
+    }
+
+    pub fn is_enabled(self) -> bool {
+        self.0
+    }
+
+    /// Commits `tx`, or in sandbox mode checks its deferred constraints and rolls it
+    /// back.
+    pub async fn finish(self, mut tx: Transaction<'_, Postgres>) -> Result<(), sqlx::Error> {
+        if !self.0 {
+            return tx.commit().await;
+        }
+        sqlx::query("SET CONSTRAINTS ALL IMMEDIATE")
+            .execute(&mut *tx)
+            .await?;
+        tx.rollback().await
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Sandbox {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self::from_headers(&parts.headers))
+    }
+}
+
+/// Middleware setting [`SANDBOX_HEADER`] to `true` on the responses of sandboxed
+/// requests.
+pub async fn mark_responses(sandbox: Sandbox, request: Request, next: Next) -> Response {
+    let mut response = next.run(request).await;
+    if sandbox.is_enabled() {
+        response
+            .headers_mut()
+            .insert(SANDBOX_HEADER, HeaderValue::from_static("true"));
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::Pool;
+
+    #[test]
+    fn test_from_headers() {
+        let mut headers = HeaderMap::new();
+        assert!(!Sandbox::from_headers(&headers).is_enabled());
+        headers.insert(SANDBOX_HEADER, HeaderValue::from_static("True"));
+        assert!(Sandbox::from_headers(&headers).is_enabled());
+        headers.insert(SANDBOX_HEADER, HeaderValue::from_static("1"));
+        assert!(Sandbox::from_headers(&headers).is_enabled());
+        headers.insert(SANDBOX_HEADER, HeaderValue::from_static("false"));
+        assert!(!Sandbox::from_headers(&headers).is_enabled());
+    }
+
+    #[sqlx::test]
+    async fn test_finish(db: Pool<Postgres>) -> sqlx::Result<()> {
+        for sql in [
+            "CREATE TABLE parents (id integer PRIMARY KEY)",
+            "CREATE TABLE children (id integer PRIMARY KEY, parent integer \
+             REFERENCES parents DEFERRABLE INITIALLY DEFERRED)",
+        ] {
+            sqlx::query(sql).execute(&db).await?;
+        }
+
+        let mut tx = db.begin().await?;
+        sqlx::query("INSERT INTO parents VALUES (1)")
+            .execute(&mut *tx)
+            .await?;
+        Sandbox(true).finish(tx).await?;
+        let count: i64 = sqlx::query_scalar("SELECT count(*) FROM parents")
+            .fetch_one(&db)
+            .await?;
+        assert_eq!(count, 0);
+
+        // Deferred constraints still fail the preview.
+        let mut tx = db.begin().await?;
+        sqlx::query("INSERT INTO children VALUES (1, 7)")
+            .execute(&mut *tx)
+            .await?;
+        assert!(Sandbox(true).finish(tx).await.is_err());
+
+        let mut tx = db.begin().await?;
+        sqlx::query("INSERT INTO parents VALUES (1)")
+            .execute(&mut *tx)
+            .await?;
+        Sandbox(false).finish(tx).await?;
+        let count: i64 = sqlx::query_scalar("SELECT count(*) FROM parents")
+            .fetch_one(&db)
+            .await?;
+        assert_eq!(count, 1);
+        Ok(())
+    }
+}
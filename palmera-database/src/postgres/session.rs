@@ -0,0 +1,195 @@
+//! # Per-request Postgres sessions
+//!
+//! Queries issued on behalf of an API caller should not run with the privileges of the
+//! pool's login role. This module opens a transaction, switches it to a restricted role
+//! and exposes the caller's id as the `palmera.user_id` setting, so native row level
+//! security policies can reference the current user:
+//!
+//! ```sql
+//! create policy owner_only on posts
+//!   using (owner_id = current_setting('palmera.user_id', true)::uuid);
+//! ```
+//!
+//! When someone acts on the user's behalf, e.g. an admin impersonating them or an
+//! integration's API key, the context also names that actor, exposed as the
+//! `palmera.actor_id` setting; it holds the user's id otherwise, so audit triggers can
+//! always read who made a change while policies keep applying to the user.
+//!
+//! The context also carries the request's [`QueryLimits`], whose statement timeout is
+//! applied to the same transaction.
+//!
+//! All settings are transaction-local and are discarded on commit or rollback, so the
+//! underlying connection goes back to the pool untouched.
+
+use sqlx::{Pool, Postgres, Transaction};
+
+use crate::postgres::limits::QueryLimits;
+
+/// Name of the setting holding the current user's id inside a session transaction.
+pub const USER_ID_SETTING: &str = "palmera.user_id";
+
+/// Name of the setting holding the id of who acts for the current user inside a session
+/// transaction.
+pub const ACTOR_ID_SETTING: &str = "palmera.actor_id";
+
+/// Identity a request executes under.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionContext {
+    /// Postgres role the transaction switches to (e.g. `authenticated` or `anon`).
+    pub role: String,
+    /// Id of the authenticated user, if any.
+    pub user_id: Option<String>,
+    /// Id of who acts on the user's behalf, if not the user themselves.
+    pub actor_id: Option<String>,
+    /// Timeout and row bounds for queries run in this session.
+    pub limits: QueryLimits,
+}
+
+impl SessionContext {
+    /// Creates a context for an authenticated user.
+    pub fn new(role: &str, user_id: &str) -> Self {
+        Self {
+            role: role.to_string(),
+            user_id: Some(user_id.to_string()),
+            actor_id: None,
+            limits: QueryLimits::default(),
+        }
+    }
+
+    /// Creates a context for an unauthenticated caller.
+    pub fn anonymous(role: &str) -> Self {
+        Self {
+            role: role.to_string(),
+            user_id: None,
+            actor_id: None,
+            limits: QueryLimits::default(),
+        }
+    }
+
+    /// Attributes the session's changes to `actor_id` acting on behalf of its user.
+    pub fn with_actor(mut self, actor_id: &str) -> Self {
+        self.actor_id = Some(actor_id.to_string());
+        self
+    }
+
+    /// Id of who makes the changes: the actor, or else the user.
+    pub fn actor(&self) -> Option<&str> {
+        self.actor_id.as_deref().or(self.user_id.as_deref())
+    }
+
+    /// Replaces the query limits of this context.
+    pub fn with_limits(mut self, limits: QueryLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+}
+
+/// Begins a transaction running as `ctx.role` with `palmera.user_id` set to `ctx.user_id`,
+/// `palmera.actor_id` to [`SessionContext::actor`] and the statement timeout from
+/// `ctx.limits`.
+///
+/// The role is applied through `set_config('role', ...)`, the bindable equivalent of
+/// `SET LOCAL ROLE`. For anonymous contexts `palmera.user_id` is set to an empty string,
+/// which `current_setting('palmera.user_id', true)` policies should treat as no user.
+///
+/// # Errors
+///
+/// Returns an error if the transaction cannot be started or the pool's login role is not
+/// allowed to switch to `ctx.role`.
+pub async fn begin_session<'a>(
+    db: &'a Pool<Postgres>,
+    ctx: &SessionContext,
+) -> Result<Transaction<'a, Postgres>, sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    sqlx::query(
+        "SELECT set_config('role', $1, true), set_config($2, $3, true), \
+         set_config($4, $5, true)",
+    )
+    .bind(&ctx.role)
+    .bind(USER_ID_SETTING)
+    .bind(ctx.user_id.as_deref().unwrap_or_default())
+    .bind(ACTOR_ID_SETTING)
+    .bind(ctx.actor().unwrap_or_default())
+    .execute(&mut *tx)
+    .await?;
+
+    ctx.limits.apply_timeout(&mut tx).await?;
+
+    Ok(tx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[sqlx::test]
+    async fn test_session_sets_role_and_user(db: Pool<Postgres>) -> sqlx::Result<()> {
+        let ctx = SessionContext::new("pg_monitor", "42");
+        let mut tx = begin_session(&db, &ctx).await?;
+
+        let (role, user_id): (String, String) =
+            sqlx::query_as("SELECT current_user::text, current_setting('palmera.user_id', true)")
+                .fetch_one(&mut *tx)
+                .await?;
+
+        assert_eq!(role, "pg_monitor");
+        assert_eq!(user_id, "42");
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_session_sets_actor(db: Pool<Postgres>) -> sqlx::Result<()> {
+        const ACTOR: &str = "SELECT current_setting('palmera.actor_id', true)";
+
+        let ctx = SessionContext::new("pg_monitor", "42");
+        let mut tx = begin_session(&db, &ctx).await?;
+        let actor_id: String = sqlx::query_scalar(ACTOR).fetch_one(&mut *tx).await?;
+        assert_eq!(actor_id, "42");
+        tx.rollback().await?;
+
+        let ctx = ctx.with_actor("7");
+        let mut tx = begin_session(&db, &ctx).await?;
+        let actor_id: String = sqlx::query_scalar(ACTOR).fetch_one(&mut *tx).await?;
+        assert_eq!(actor_id, "7");
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_session_settings_are_transaction_local(db: Pool<Postgres>) -> sqlx::Result<()> {
+        let mut conn = db.acquire().await?;
+        let login_role: String = sqlx::query_scalar("SELECT current_user::text")
+            .fetch_one(&mut *conn)
+            .await?;
+        drop(conn);
+
+        let tx = begin_session(&db, &SessionContext::anonymous("pg_monitor")).await?;
+        tx.rollback().await?;
+
+        let (role, user_id): (String, Option<String>) =
+            sqlx::query_as("SELECT current_user::text, current_setting('palmera.user_id', true)")
+                .fetch_one(&db)
+                .await?;
+
+        assert_eq!(role, login_role);
+        assert!(user_id.is_none_or(|id| id.is_empty()));
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_session_applies_statement_timeout(db: Pool<Postgres>) -> sqlx::Result<()> {
+        let limits = QueryLimits {
+            statement_timeout: Some(std::time::Duration::from_millis(1500)),
+            max_rows: None,
+        };
+        let ctx = SessionContext::anonymous("pg_monitor").with_limits(limits);
+        let mut tx = begin_session(&db, &ctx).await?;
+
+        let timeout: String = sqlx::query_scalar("SHOW statement_timeout")
+            .fetch_one(&mut *tx)
+            .await?;
+
+        assert_eq!(timeout, "1500ms");
+        Ok(())
+    }
+}
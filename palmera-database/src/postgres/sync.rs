@@ -0,0 +1,1438 @@
+//! # Offline sync
+//!
+//! The server half of an offline-first client: `GET /{schema}/{table}/changes` pulls the
+//! records changed since a cursor, and `POST /{schema}/{table}/changes` pushes local
+//! writes with conflict detection.
+//!
+//! [`install`] attaches a trigger to a table that logs the id of every written row in
+//! the [`CHANGELOG`] table, under a version increasing with each write across tables,
+//! together with the id of the writing transaction. The latest version logged for a row
+//! is the row's version. A pull returns the rows logged after its `since` cursor, each
+//! as it is now: an `upsert` with the record, or a `delete` if the row is gone. The
+//! returned [`SyncCursor`] is passed as `since` on the next pull.
+//!
+//! Versions are taken when a row is written, not when its transaction commits, so a
+//! write can become visible after later versions were pulled. Pulls therefore follow
+//! the changelog in transaction order and stop at the oldest transaction still running
+//! (`pg_snapshot_xmin`): a change is only returned once every transaction that could
+//! log before it has ended, so none is skipped. A long running transaction delays
+//! pulls until it ends.
+//!
+//! A pushed change names the version of the row the client last pulled as
+//! `base_version`, or none for a row it created. If the row has been written since, the
+//! table's [`MergeStrategy`] resolves the conflict, see [`crate::postgres::merge`]. By
+//! default the change is not applied and comes back as a [`SyncConflict`] holding the
+//! server's record; other changes of the push are applied in one transaction.
+//!
+//! `PATCH /{schema}/{table}/{id}` updates a single row of a synced table. With an
+//! `If-Match` header naming the version the update is based on, a concurrent write is
+//! resolved the same way, and a conflict left for manual resolution is answered with
+//! `409 Conflict` and the [`SyncConflict`]. The response's `ETag` is the row's new
+//! version. Besides the new column values, as JSON or as an HTML form, the body can be a
+//! JSON Merge Patch or a JSON Patch; see [`crate::postgres::patch`].
+//!
+//! Records are identified by their [`PRIMARY_KEY`] column. When a [`SessionContext`]
+//! extension is present, pulls and pushes run in its session so row level security
+//! applies: rows the caller can't see are left out of pulls, so their ids aren't
+//! disclosed. Deletes are pulled by id, as the deleted row can't be checked anymore.
+//! The session role needs `SELECT` on the changelog, which the trigger writes with the
+//! rights of its owner. [`compact`] drops log entries superseded by a newer write of the
+//! same row, which pulls never return.
+//!
+//! Pushes and patches honor [`sandbox`] mode: their result is returned and rolled back.
+//! With a [`Quotas`](crate::quotas::Quotas) extension, the rows a push inserts are
+//! charged to the [`RecordQuota`] before it commits, and the push is rolled back with
+//! `402 Payment Required` if the table has no room. Rows it deletes are given back.
+//!
+//! # Example
+//!
+//! ```json
+//! { "changes": [{ "version": 12, "id": 3, "operation": "upsert", "record": { "id": 3 } }],
+//!   "cursor": "7312.12", "has_more": false }
+//! ```
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    str::FromStr,
+    sync::Arc,
+};
+
+use axum::{
+    Extension, Json,
+    extract::{Path, Query},
+    http::{HeaderMap, StatusCode, header},
+    middleware,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, Pool, Postgres, Transaction, types::Json as SqlJson};
+use utoipa::{IntoParams, ToSchema};
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+use crate::{
+    constraint::ConstraintViolation,
+    embedded::{ChangeAction, RecordChange},
+    outbox::{self, Outbox},
+    postgres::{
+        batch::{PRIMARY_KEY, matches_id},
+        helpers::{quote_ident, quote_qualified},
+        merge::{self, MergeStrategies, MergeStrategy, Resolution, Resolved, ServerWrites},
+        patch::{Patch, PatchOperation},
+        sandbox::{self, Sandbox},
+        session::{SessionContext, begin_session},
+    },
+    quotas::RecordQuota,
+};
+
+/// Table logging the versions of synced rows.
+pub const CHANGELOG: &str = "palmera_sync_changes";
+
+/// Changes returned by a pull unless the client asks for fewer.
+pub const MAX_PULL: u32 = 1000;
+
+/// Most changes accepted in one push.
+pub const MAX_PUSH: usize = 100;
+
+const LOG_FUNCTION: &str = r#"
+CREATE OR REPLACE FUNCTION palmera_log_sync_change() RETURNS trigger
+SECURITY DEFINER SET search_path = public AS $$
+DECLARE
+    changed jsonb;
+BEGIN
+    IF TG_OP = 'UPDATE' THEN
+        SELECT coalesce(jsonb_agg(n.key), '[]') INTO changed
+        FROM jsonb_each(to_jsonb(NEW)) n
+        WHERE n.value IS DISTINCT FROM to_jsonb(OLD) -> n.key;
+    END IF;
+    INSERT INTO palmera_sync_changes (table_name, row_id, columns, deleted)
+    VALUES (
+        TG_TABLE_SCHEMA || '.' || TG_TABLE_NAME,
+        CASE WHEN TG_OP = 'DELETE' THEN to_jsonb(OLD) ELSE to_jsonb(NEW) END -> 'id',
+        changed,
+        TG_OP = 'DELETE'
+    );
+    IF TG_OP = 'UPDATE' AND to_jsonb(OLD) -> 'id' IS DISTINCT FROM to_jsonb(NEW) -> 'id' THEN
+        INSERT INTO palmera_sync_changes (table_name, row_id, deleted)
+        VALUES (TG_TABLE_SCHEMA || '.' || TG_TABLE_NAME, to_jsonb(OLD) -> 'id', true);
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+"#;
+
+/// Starts logging the changes of `table` (`schema.table`) for sync.
+///
+/// Safe to call repeatedly; the changelog is created if needed and the trigger replaced.
+pub async fn install(db: &Pool<Postgres>, table: &str) -> Result<(), sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    sqlx::query(&format!(
+        "CREATE TABLE IF NOT EXISTS public.{CHANGELOG} (\
+             version bigserial PRIMARY KEY, \
+             table_name text NOT NULL, \
+             row_id jsonb NOT NULL, \
+             columns jsonb, \
+             changed_at timestamptz NOT NULL DEFAULT now())"
+    ))
+    .execute(&mut *tx)
+    .await?;
+    // Changelogs created before pulls followed transactions lack these columns. Their
+    // entries count as logged by the installing transaction, and as deletes if the row
+    // is gone.
+    sqlx::query(&format!(
+        "ALTER TABLE public.{CHANGELOG} \
+             ADD COLUMN IF NOT EXISTS xid xid8 NOT NULL DEFAULT pg_current_xact_id(), \
+             ADD COLUMN IF NOT EXISTS deleted boolean"
+    ))
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(&format!(
+        "CREATE INDEX IF NOT EXISTS {CHANGELOG}_row ON public.{CHANGELOG} (table_name, row_id, version)"
+    ))
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(&format!(
+        "CREATE INDEX IF NOT EXISTS {CHANGELOG}_xid ON public.{CHANGELOG} (table_name, xid, version)"
+    ))
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(LOG_FUNCTION).execute(&mut *tx).await?;
+    sqlx::query(&format!(
+        "CREATE OR REPLACE TRIGGER palmera_sync AFTER INSERT OR UPDATE OR DELETE ON {} \
+         FOR EACH ROW EXECUTE FUNCTION palmera_log_sync_change()",
+        quote_qualified(table)
+    ))
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await
+}
+
+/// Stops logging the changes of `table`. Its log entries are kept.
+pub async fn remove(db: &Pool<Postgres>, table: &str) -> Result<(), sqlx::Error> {
+    sqlx::query(&format!(
+        "DROP TRIGGER IF EXISTS palmera_sync ON {}",
+        quote_qualified(table)
+    ))
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Deletes log entries superseded by a newer write of the same row, returning how many
+/// were removed.
+///
+/// The columns written by the removed entries are forgotten: the surviving entry counts
+/// as a write of the whole row when merging by column.
+pub async fn compact(db: &Pool<Postgres>) -> Result<u64, sqlx::Error> {
+    let mut tx = db.begin().await?;
+    sqlx::query(&format!(
+        "UPDATE public.{CHANGELOG} c SET columns = NULL \
+         WHERE EXISTS (SELECT 1 FROM public.{CHANGELOG} o \
+             WHERE o.table_name = c.table_name AND o.row_id = c.row_id AND o.version < c.version)"
+    ))
+    .execute(&mut *tx)
+    .await?;
+    let result = sqlx::query(&format!(
+        "DELETE FROM public.{CHANGELOG} c WHERE EXISTS (\
+             SELECT 1 FROM public.{CHANGELOG} n \
+             WHERE n.table_name = c.table_name AND n.row_id = c.row_id AND n.version > c.version)"
+    ))
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(result.rows_affected())
+}
+
+/// What happened to a row since the cursor.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncOperation {
+    /// The row exists; `record` is its current value.
+    Upsert,
+    /// The row was deleted, or the caller may no longer see it.
+    Delete,
+}
+
+/// A row changed since the cursor of a pull.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct SyncChange {
+    pub version: i64,
+    #[schema(value_type = Object)]
+    pub id: serde_json::Value,
+    pub operation: SyncOperation,
+    #[schema(value_type = Option<Object>)]
+    pub record: Option<serde_json::Value>,
+}
+
+/// Position of a pull in the changelog: the transaction that logged the last change
+/// returned, and its version. Sent as the string `"{xid}.{version}"`; the default is the
+/// start of the changelog.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct SyncCursor {
+    pub xid: i64,
+    pub version: i64,
+}
+
+impl fmt::Display for SyncCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.xid, self.version)
+    }
+}
+
+impl FromStr for SyncCursor {
+    type Err = String;
+
+    fn from_str(cursor: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid sync cursor {cursor:?}");
+        let (xid, version) = cursor.split_once('.').ok_or_else(invalid)?;
+        Ok(Self {
+            xid: xid.parse().map_err(|_| invalid())?,
+            version: version.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl TryFrom<String> for SyncCursor {
+    type Error = String;
+
+    fn try_from(cursor: String) -> Result<Self, Self::Error> {
+        cursor.parse()
+    }
+}
+
+impl From<SyncCursor> for String {
+    fn from(cursor: SyncCursor) -> Self {
+        cursor.to_string()
+    }
+}
+
+/// The rows changed since a cursor.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct ChangeSet {
+    pub changes: Vec<SyncChange>,
+    /// Cursor to pull the next changes from.
+    #[schema(value_type = String)]
+    pub cursor: SyncCursor,
+    /// Whether more changes are waiting past `cursor`.
+    pub has_more: bool,
+}
+
+/// A changelog entry with the row it logged, if the caller can see it.
+type ChangeRow = (
+    i64,
+    i64,
+    SqlJson<serde_json::Value>,
+    Option<SqlJson<serde_json::Value>>,
+);
+
+/// Returns up to `limit` rows of `table` changed after the cursor `since`, in the order
+/// their transactions logged them. Changes of transactions that may still be followed
+/// by an earlier one are left for a later pull, and rows hidden from the caller by row
+/// level security are left out.
+pub async fn changes(
+    conn: &mut PgConnection,
+    table: &str,
+    since: SyncCursor,
+    limit: u32,
+) -> Result<ChangeSet, sqlx::Error> {
+    let name = quote_qualified(table);
+    let rows: Vec<ChangeRow> = sqlx::query_as(&format!(
+        "SELECT c.xid::text::bigint, c.version, c.row_id, to_jsonb(t) \
+             FROM public.{CHANGELOG} c \
+             LEFT JOIN LATERAL (SELECT * FROM {name} WHERE {}) t ON true \
+             WHERE c.table_name = $1 \
+             AND (c.xid, c.version) > ($2::text::xid8, $3) \
+             AND c.xid < pg_snapshot_xmin(pg_current_snapshot()) \
+             AND c.version = (SELECT max(version) FROM public.{CHANGELOG} l \
+                 WHERE l.table_name = c.table_name AND l.row_id = c.row_id) \
+             AND (to_jsonb(t) IS NOT NULL OR coalesce(c.deleted, true)) \
+             ORDER BY c.xid, c.version LIMIT $4",
+        matches_id(&name, "c.row_id")
+    ))
+    .bind(table)
+    .bind(since.xid.to_string())
+    .bind(since.version)
+    .bind(i64::from(limit) + 1)
+    .fetch_all(conn)
+    .await?;
+
+    let has_more = rows.len() > limit as usize;
+    let mut cursor = since;
+    let changes = rows
+        .into_iter()
+        .take(limit as usize)
+        .map(|(xid, version, SqlJson(id), record)| {
+            cursor = SyncCursor { xid, version };
+            SyncChange {
+                version,
+                id,
+                operation: match record {
+                    Some(_) => SyncOperation::Upsert,
+                    None => SyncOperation::Delete,
+                },
+                record: record.map(|SqlJson(record)| record),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    Ok(ChangeSet {
+        changes,
+        cursor,
+        has_more,
+    })
+}
+
+/// A local write pushed by a client.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct PushChange {
+    #[schema(value_type = Object)]
+    pub id: serde_json::Value,
+    /// Version of the row the write is based on, `None` for a row created offline.
+    #[serde(default)]
+    pub base_version: Option<i64>,
+    pub operation: SyncOperation,
+    /// Columns of the row for upserts.
+    #[serde(default)]
+    #[schema(value_type = Option<Object>)]
+    pub record: Option<serde_json::Map<String, serde_json::Value>>,
+    /// When the client made the write, used to merge by column. Defaults to the time of
+    /// the push.
+    #[serde(default)]
+    pub changed_at: Option<DateTime<Utc>>,
+}
+
+/// A pushed change that was applied, or whose conflict was resolved.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct AppliedChange {
+    #[schema(value_type = Object)]
+    pub id: serde_json::Value,
+    /// Version of the row after the write.
+    pub version: i64,
+    /// The row after the write, `None` if it was deleted.
+    #[schema(value_type = Option<Object>)]
+    pub record: Option<serde_json::Value>,
+    /// How a conflict with a newer server version was resolved, if there was one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resolved: Option<Resolved>,
+}
+
+/// A pushed change based on an outdated version of its row.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct SyncConflict {
+    #[schema(value_type = Object)]
+    pub id: serde_json::Value,
+    pub base_version: Option<i64>,
+    /// Current version of the row.
+    pub version: i64,
+    /// The row as it is on the server, `None` if it was deleted.
+    #[schema(value_type = Option<Object>)]
+    pub record: Option<serde_json::Value>,
+}
+
+/// Outcome of a push.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct PushResult {
+    pub applied: Vec<AppliedChange>,
+    pub conflicts: Vec<SyncConflict>,
+    /// Rows the push inserted, charged to the records quota.
+    #[serde(skip)]
+    pub created: u64,
+    /// Rows the push deleted, given back to the records quota.
+    #[serde(skip)]
+    pub deleted: u64,
+    /// The writes of the push in order, with the row after the write or before a delete,
+    /// recorded in the outbox.
+    #[serde(skip)]
+    pub writes: Vec<(ChangeAction, serde_json::Value)>,
+}
+
+/// Applies `changes` to `table` on `conn`, which should be inside a transaction.
+/// Changes whose row was written after their base version are resolved with
+/// `strategy`; those left for manual resolution are skipped and reported.
+pub async fn push(
+    conn: &mut PgConnection,
+    table: &str,
+    changes: &[PushChange],
+    strategy: &MergeStrategy,
+) -> Result<PushResult, sqlx::Error> {
+    let name = quote_qualified(table);
+    let mut result = PushResult::default();
+
+    for change in changes {
+        // Serializes concurrent pushes of the same row until the transaction ends.
+        sqlx::query("SELECT pg_advisory_xact_lock(hashtextextended($1, 0))")
+            .bind(format!("{}:{}", table, change.id))
+            .execute(&mut *conn)
+            .await?;
+
+        let version = row_version(conn, table, &change.id).await?;
+        let mut resolved = None;
+        let mut write = Some(change.clone());
+        if let Some(version) = version.filter(|version| Some(*version) > change.base_version) {
+            let conflict = SyncConflict {
+                id: change.id.clone(),
+                base_version: change.base_version,
+                version,
+                record: current_record(conn, &name, &change.id).await?,
+            };
+            let writes = server_writes(conn, table, change).await?;
+
+            match merge::resolve(strategy, &conflict, change, &writes) {
+                Resolution::Manual => {
+                    result.conflicts.push(conflict);
+                    continue;
+                }
+                Resolution::Server => {
+                    resolved = Some(Resolved::Server);
+                    write = None;
+                }
+                Resolution::Client => resolved = Some(Resolved::Client),
+                Resolution::Merge(record) => {
+                    resolved = Some(Resolved::Merged);
+                    write = Some(PushChange {
+                        operation: SyncOperation::Upsert,
+                        record: Some(record),
+                        ..change.clone()
+                    });
+                }
+            }
+        }
+
+        let written = match write {
+            Some(PushChange {
+                operation: SyncOperation::Upsert,
+                record,
+                ..
+            }) => {
+                let mut record = record.unwrap_or_default();
+                record.insert(PRIMARY_KEY.to_string(), change.id.clone());
+                if upsert(conn, &name, &change.id, &record).await? {
+                    result.created += 1;
+                    Some(ChangeAction::Create)
+                } else {
+                    Some(ChangeAction::Update)
+                }
+            }
+            Some(PushChange {
+                operation: SyncOperation::Delete,
+                ..
+            }) => {
+                let deleted: Option<SqlJson<serde_json::Value>> = sqlx::query_scalar(&format!(
+                    "DELETE FROM {name} t WHERE {} RETURNING to_jsonb(t)",
+                    matches_id(&name, "$1")
+                ))
+                .bind(SqlJson(&change.id))
+                .fetch_optional(&mut *conn)
+                .await?;
+                if let Some(SqlJson(deleted)) = deleted {
+                    result.deleted += 1;
+                    result.writes.push((ChangeAction::Delete, deleted));
+                }
+                None
+            }
+            None => None,
+        };
+        let record = current_record(conn, &name, &change.id).await?;
+        if let (Some(action), Some(record)) = (written, &record) {
+            result.writes.push((action, record.clone()));
+        }
+        result.applied.push(AppliedChange {
+            id: change.id.clone(),
+            version: row_version(conn, table, &change.id)
+                .await?
+                .unwrap_or_default(),
+            record,
+            resolved,
+        });
+    }
+
+    Ok(result)
+}
+
+/// The row of the quoted table `name` with `id`, if it exists.
+async fn current_record(
+    conn: &mut PgConnection,
+    name: &str,
+    id: &serde_json::Value,
+) -> Result<Option<serde_json::Value>, sqlx::Error> {
+    let record: Option<SqlJson<serde_json::Value>> = sqlx::query_scalar(&format!(
+        "SELECT to_jsonb(t) FROM {name} t WHERE {}",
+        matches_id(name, "$1")
+    ))
+    .bind(SqlJson(id))
+    .fetch_optional(conn)
+    .await?;
+
+    Ok(record.map(|SqlJson(record)| record))
+}
+
+/// Like [`current_record`], but locks the row until the transaction ends, so a patch
+/// computed from it can't overwrite a concurrent write.
+async fn locked_record(
+    conn: &mut PgConnection,
+    name: &str,
+    id: &serde_json::Value,
+) -> Result<Option<serde_json::Value>, sqlx::Error> {
+    let record: Option<SqlJson<serde_json::Value>> = sqlx::query_scalar(&format!(
+        "SELECT to_jsonb(t) FROM {name} t WHERE {} FOR UPDATE",
+        matches_id(name, "$1")
+    ))
+    .bind(SqlJson(id))
+    .fetch_optional(conn)
+    .await?;
+
+    Ok(record.map(|SqlJson(record)| record))
+}
+
+/// Time in milliseconds and written columns of a changelog entry.
+type WriteRow = (i64, Option<SqlJson<Vec<String>>>);
+
+/// When the server wrote the row of `change` since its base version.
+async fn server_writes(
+    conn: &mut PgConnection,
+    table: &str,
+    change: &PushChange,
+) -> Result<ServerWrites, sqlx::Error> {
+    let entries: Vec<WriteRow> = sqlx::query_as(&format!(
+        "SELECT (extract(epoch FROM changed_at) * 1000)::bigint, columns \
+         FROM public.{CHANGELOG} \
+         WHERE table_name = $1 AND row_id = $2 AND version > $3"
+    ))
+    .bind(table)
+    .bind(SqlJson(&change.id))
+    .bind(change.base_version.unwrap_or_default())
+    .fetch_all(conn)
+    .await?;
+
+    let mut writes = ServerWrites {
+        columns: HashMap::new(),
+        row: None,
+    };
+    for (changed_at, columns) in entries {
+        let changed_at = DateTime::from_timestamp_millis(changed_at).unwrap_or_default();
+        match columns {
+            Some(SqlJson(columns)) => {
+                for column in columns {
+                    let latest = writes.columns.entry(column).or_insert(changed_at);
+                    *latest = (*latest).max(changed_at);
+                }
+            }
+            None => writes.row = writes.row.max(Some(changed_at)),
+        }
+    }
+
+    Ok(writes)
+}
+
+/// Latest logged version of the row of `table` with `id`.
+async fn row_version(
+    conn: &mut PgConnection,
+    table: &str,
+    id: &serde_json::Value,
+) -> Result<Option<i64>, sqlx::Error> {
+    sqlx::query_scalar(&format!(
+        "SELECT max(version) FROM public.{CHANGELOG} WHERE table_name = $1 AND row_id = $2"
+    ))
+    .bind(table)
+    .bind(SqlJson(id))
+    .fetch_one(conn)
+    .await
+}
+
+/// Writes `record` to the row of the quoted table `name` with `id`: updates the columns
+/// whose value changes if the row exists, and inserts it otherwise. A row left as it is
+/// isn't written, so its version stays the same. Returns whether the row was inserted.
+async fn upsert(
+    conn: &mut PgConnection,
+    name: &str,
+    id: &serde_json::Value,
+    record: &serde_json::Map<String, serde_json::Value>,
+) -> Result<bool, sqlx::Error> {
+    let Some(current) = current_record(conn, name, id).await? else {
+        let columns = columns(record.keys());
+        sqlx::query(&format!(
+            "INSERT INTO {name} ({columns}) \
+             SELECT {columns} FROM jsonb_populate_record(NULL::{name}, $1)"
+        ))
+        .bind(SqlJson(record))
+        .execute(conn)
+        .await?;
+        return Ok(true);
+    };
+
+    let changed = record
+        .iter()
+        .filter(|(column, value)| current.get(column.as_str()) != Some(value))
+        .map(|(column, _)| column)
+        .collect::<Vec<_>>();
+    if changed.is_empty() {
+        return Ok(false);
+    }
+    let columns = columns(changed);
+    sqlx::query(&format!(
+        "UPDATE {name} SET ({columns}) = \
+         (SELECT {columns} FROM jsonb_populate_record(NULL::{name}, $1)) \
+         WHERE {}",
+        matches_id(name, "$2")
+    ))
+    .bind(SqlJson(record))
+    .bind(SqlJson(id))
+    .execute(conn)
+    .await?;
+
+    Ok(false)
+}
+
+/// Commits the push `result` made on `tx`, or rolls it back in sandbox mode. The rows
+/// it inserted are reserved against `quota` first, and the push is rolled back with
+/// `402 Payment Required` if the table has no room; the rows it deleted are given back
+/// once it commits. Its writes are recorded in `outbox` in the same transaction.
+async fn finish_push(
+    mut tx: Transaction<'_, Postgres>,
+    sandbox: Sandbox,
+    quota: Option<&RecordQuota>,
+    outbox: Option<&Outbox>,
+    session: Option<&SessionContext>,
+    table: &str,
+    result: &PushResult,
+) -> Result<(), Response> {
+    let changes = result.writes.iter().map(|(action, record)| RecordChange {
+        table: table.to_string(),
+        action: *action,
+        record: record.clone(),
+        user_id: session.and_then(|ctx| ctx.user_id.clone()),
+        actor_id: session.and_then(|ctx| ctx.actor_id.clone()),
+    });
+    outbox::record_changes(outbox, &mut tx, changes)
+        .await
+        .map_err(internal)?;
+
+    let committed = match quota {
+        None => sandbox.finish(tx).await,
+        Some(quota) => {
+            let created = BTreeMap::from([(table.to_string(), result.created)]);
+            let deleted = BTreeMap::from([(table.to_string(), result.deleted)]);
+            quota
+                .reserve(&created)
+                .await
+                .map_err(IntoResponse::into_response)?;
+
+            let committed = sandbox.finish(tx).await;
+            quota
+                .settle(
+                    &created,
+                    &deleted,
+                    committed.is_ok() && !sandbox.is_enabled(),
+                )
+                .await;
+            committed
+        }
+    };
+    if let (Ok(()), Some(outbox)) = (&committed, outbox) {
+        outbox.wake();
+    }
+    committed.map_err(internal)
+}
+
+/// Quoted, comma separated `columns`.
+fn columns<'a>(columns: impl IntoIterator<Item = &'a String>) -> String {
+    columns
+        .into_iter()
+        .map(|column| quote_ident(column))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn internal(err: sqlx::Error) -> Response {
+    if let Some(violation) = ConstraintViolation::from_sqlx(&err) {
+        return violation.into_response();
+    }
+    let code = err
+        .as_database_error()
+        .and_then(|err| err.code())
+        .map(|code| code.into_owned())
+        .unwrap_or_default();
+    match code.as_str() {
+        "42P01" => StatusCode::NOT_FOUND,
+        "42501" => StatusCode::FORBIDDEN,
+        code if code.starts_with("22") || code == "42703" => StatusCode::BAD_REQUEST,
+        _ => {
+            tracing::error!(%err, "sync failed");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+    .into_response()
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+struct PullParams {
+    /// Cursor returned by the previous pull, none for the first.
+    #[serde(default)]
+    #[param(value_type = Option<String>)]
+    since: SyncCursor,
+    /// Most changes to return, at most 1000.
+    limit: Option<u32>,
+}
+
+/// Pulls the records changed since a cursor.
+#[utoipa::path(
+    get,
+    path = "/{schema}/{table}/changes",
+    params(PullParams),
+    responses((status = 200, body = ChangeSet), (status = 400), (status = 404))
+)]
+async fn pull_changes(
+    Extension(db): Extension<Pool<Postgres>>,
+    session: Option<Extension<SessionContext>>,
+    Path((schema, table)): Path<(String, String)>,
+    Query(params): Query<PullParams>,
+) -> Result<Json<ChangeSet>, Response> {
+    let table = format!("{}.{}", schema, table);
+    let limit = params.limit.unwrap_or(MAX_PULL).clamp(1, MAX_PULL);
+
+    let mut tx = match session {
+        Some(Extension(ctx)) => begin_session(&db, &ctx).await,
+        None => db.begin().await,
+    }
+    .map_err(internal)?;
+    let changes = changes(&mut tx, &table, params.since, limit)
+        .await
+        .map_err(internal)?;
+    tx.commit().await.map_err(internal)?;
+
+    Ok(Json(changes))
+}
+
+/// Pushes local writes, reporting those that conflict with newer server versions.
+#[utoipa::path(
+    post,
+    path = "/{schema}/{table}/changes",
+    request_body = Vec<PushChange>,
+    responses(
+        (status = 200, body = PushResult),
+        (status = 400),
+        (status = 402),
+        (status = 404),
+        (status = 409, body = ConstraintViolation),
+        (status = 422, body = ConstraintViolation)
+    )
+)]
+async fn push_changes(
+    Extension(db): Extension<Pool<Postgres>>,
+    session: Option<Extension<SessionContext>>,
+    sandbox: Sandbox,
+    quota: Option<RecordQuota>,
+    outbox: Option<Extension<Arc<Outbox>>>,
+    strategies: Option<Extension<Arc<MergeStrategies>>>,
+    Path((schema, table)): Path<(String, String)>,
+    Json(changes): Json<Vec<PushChange>>,
+) -> Result<Json<PushResult>, Response> {
+    let table = format!("{}.{}", schema, table);
+    if changes.len() > MAX_PUSH {
+        return Err(StatusCode::BAD_REQUEST.into_response());
+    }
+    let strategy = strategies
+        .map(|Extension(strategies)| strategies.get(&table).clone())
+        .unwrap_or_default();
+
+    let session = session.map(|Extension(ctx)| ctx);
+    let mut tx = match &session {
+        Some(ctx) => begin_session(&db, ctx).await,
+        None => db.begin().await,
+    }
+    .map_err(internal)?;
+    let result = push(&mut tx, &table, &changes, &strategy)
+        .await
+        .map_err(internal)?;
+    finish_push(
+        tx,
+        sandbox,
+        quota.as_ref(),
+        outbox.as_ref().map(|Extension(outbox)| outbox.as_ref()),
+        session.as_ref(),
+        &table,
+        &result,
+    )
+    .await?;
+
+    Ok(Json(result))
+}
+
+/// Updates a row of a synced table, resolving conflicts with the `If-Match` version.
+#[utoipa::path(
+    patch,
+    path = "/{schema}/{table}/{id}",
+    request_body(content(
+        (Object = "application/json"),
+        (Object = "application/merge-patch+json"),
+        (Vec<PatchOperation> = "application/json-patch+json"),
+        (Object = "application/x-www-form-urlencoded"),
+        (Object = "multipart/form-data")
+    )),
+    params(("If-Match" = Option<String>, Header, description = "Version the update is based on")),
+    responses(
+        (status = 200, body = Object),
+        (status = 400),
+        (status = 402),
+        (status = 404),
+        (status = 409, body = SyncConflict),
+        (status = 415),
+        (status = 422, body = ConstraintViolation)
+    )
+)]
+async fn patch_record(
+    Extension(db): Extension<Pool<Postgres>>,
+    session: Option<Extension<SessionContext>>,
+    sandbox: Sandbox,
+    quota: Option<RecordQuota>,
+    outbox: Option<Extension<Arc<Outbox>>>,
+    strategies: Option<Extension<Arc<MergeStrategies>>>,
+    Path((schema, table, id)): Path<(String, String, String)>,
+    headers: HeaderMap,
+    patch: Patch,
+) -> Result<Response, Response> {
+    let table = format!("{}.{}", schema, table);
+    let base_version = match headers.get(header::IF_MATCH) {
+        Some(value) => Some(
+            value
+                .to_str()
+                .ok()
+                .and_then(|value| value.trim_matches('"').parse::<i64>().ok())
+                .ok_or(StatusCode::BAD_REQUEST.into_response())?,
+        ),
+        None => None,
+    };
+    let strategy = strategies
+        .map(|Extension(strategies)| strategies.get(&table).clone())
+        .unwrap_or_default();
+
+    let session = session.map(|Extension(ctx)| ctx);
+    let mut tx = match &session {
+        Some(ctx) => begin_session(&db, ctx).await,
+        None => db.begin().await,
+    }
+    .map_err(internal)?;
+    let current = locked_record(&mut tx, &quote_qualified(&table), &id.into())
+        .await
+        .map_err(internal)?
+        .ok_or(StatusCode::NOT_FOUND.into_response())?;
+    // The key as the changelog holds it, e.g. a number rather than the path's string.
+    let id = current[PRIMARY_KEY].clone();
+    let record = patch
+        .apply(current.as_object().expect("rows are objects"))
+        .map_err(IntoResponse::into_response)?;
+    let version = row_version(&mut tx, &table, &id).await.map_err(internal)?;
+    if record.is_empty() {
+        // Nothing changes, so nothing is written and the version stays.
+        sandbox.finish(tx).await.map_err(internal)?;
+        return Ok((
+            [(header::ETAG, format!("\"{}\"", version.unwrap_or_default()))],
+            Json(Some(current)),
+        )
+            .into_response());
+    }
+    // Without a version the update applies to whatever the row holds now.
+    let base_version = base_version.or(version);
+
+    let change = PushChange {
+        id,
+        base_version,
+        operation: SyncOperation::Upsert,
+        record: Some(record),
+        changed_at: None,
+    };
+    let mut result = push(&mut tx, &table, &[change], &strategy)
+        .await
+        .map_err(internal)?;
+    if let Some(conflict) = result.conflicts.pop() {
+        return Err((StatusCode::CONFLICT, Json(conflict)).into_response());
+    }
+    finish_push(
+        tx,
+        sandbox,
+        quota.as_ref(),
+        outbox.as_ref().map(|Extension(outbox)| outbox.as_ref()),
+        session.as_ref(),
+        &table,
+        &result,
+    )
+    .await?;
+
+    let applied = result.applied.pop().expect("a change was pushed");
+    Ok((
+        [(header::ETAG, format!("\"{}\"", applied.version))],
+        Json(applied.record),
+    )
+        .into_response())
+}
+
+pub fn router() -> OpenApiRouter {
+    OpenApiRouter::new()
+        .routes(routes!(pull_changes, push_changes))
+        .routes(routes!(patch_record))
+        .layer(middleware::from_fn(sandbox::mark_responses))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    async fn setup(db: &Pool<Postgres>) -> sqlx::Result<()> {
+        sqlx::query("CREATE TABLE todos (id integer PRIMARY KEY, title text NOT NULL, done boolean NOT NULL DEFAULT false)")
+            .execute(db)
+            .await?;
+        install(db, "public.todos").await
+    }
+
+    async fn pull(db: &Pool<Postgres>, since: SyncCursor, limit: u32) -> sqlx::Result<ChangeSet> {
+        let mut conn = db.acquire().await?;
+        changes(&mut conn, "public.todos", since, limit).await
+    }
+
+    #[sqlx::test]
+    async fn test_pull(db: Pool<Postgres>) -> sqlx::Result<()> {
+        setup(&db).await?;
+        sqlx::raw_sql(
+            "INSERT INTO todos (id, title) VALUES (1, 'a'), (2, 'b'), (3, 'c'); \
+             UPDATE todos SET done = true WHERE id = 1; \
+             DELETE FROM todos WHERE id = 2;",
+        )
+        .execute(&db)
+        .await?;
+
+        let first = pull(&db, SyncCursor::default(), 2).await?;
+        assert!(first.has_more);
+        assert_eq!(first.changes[0].id, json!(3));
+        assert_eq!(first.changes[1].id, json!(1));
+        assert_eq!(first.changes[1].record.as_ref().unwrap()["done"], true);
+
+        let rest = pull(&db, first.cursor, 10).await?;
+        assert!(!rest.has_more);
+        assert_eq!(rest.changes.len(), 1);
+        assert_eq!(rest.changes[0].operation, SyncOperation::Delete);
+        assert_eq!(rest.changes[0].record, None);
+        assert_eq!(pull(&db, rest.cursor, 10).await?.changes, []);
+
+        // Compaction keeps only what pulls return.
+        assert_eq!(compact(&db).await?, 2);
+        assert_eq!(pull(&db, SyncCursor::default(), 10).await?.changes.len(), 3);
+
+        let cursor: SyncCursor = serde_json::from_value(json!(rest.cursor.to_string())).unwrap();
+        assert_eq!(cursor, rest.cursor);
+        assert!("12".parse::<SyncCursor>().is_err());
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_pull_waits_for_running_transactions(db: Pool<Postgres>) -> sqlx::Result<()> {
+        setup(&db).await?;
+        // Takes version 1, but commits after the write taking version 2.
+        let mut slow = db.begin().await?;
+        sqlx::query("INSERT INTO todos (id, title) VALUES (1, 'slow')")
+            .execute(&mut *slow)
+            .await?;
+        sqlx::query("INSERT INTO todos (id, title) VALUES (2, 'fast')")
+            .execute(&db)
+            .await?;
+
+        let early = pull(&db, SyncCursor::default(), 10).await?;
+        assert_eq!(early.changes, []);
+        assert_eq!(early.cursor, SyncCursor::default());
+
+        slow.commit().await?;
+        let ids = pull(&db, early.cursor, 10)
+            .await?
+            .changes
+            .into_iter()
+            .map(|change| change.id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, [json!(1), json!(2)]);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_pull_hides_invisible_rows(db: Pool<Postgres>) -> sqlx::Result<()> {
+        setup(&db).await?;
+        sqlx::raw_sql(&format!(
+            "INSERT INTO todos (id, title, done) VALUES (1, 'a', true), (2, 'b', false), (3, 'c', true); \
+             DELETE FROM todos WHERE id = 3; \
+             CREATE POLICY done_only ON todos FOR SELECT USING (done); \
+             ALTER TABLE todos ENABLE ROW LEVEL SECURITY; \
+             GRANT SELECT ON todos, public.{CHANGELOG} TO pg_monitor;"
+        ))
+        .execute(&db)
+        .await?;
+
+        let mut tx = begin_session(&db, &SessionContext::anonymous("pg_monitor")).await?;
+        let changes = changes(&mut tx, "public.todos", SyncCursor::default(), 10).await?;
+        let pulled = changes
+            .changes
+            .iter()
+            .map(|change| (change.id.clone(), change.operation))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            pulled,
+            [
+                (json!(1), SyncOperation::Upsert),
+                (json!(3), SyncOperation::Delete)
+            ]
+        );
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_push_conflicts(db: Pool<Postgres>) -> sqlx::Result<()> {
+        setup(&db).await?;
+        sqlx::query("INSERT INTO todos (id, title) VALUES (1, 'a')")
+            .execute(&db)
+            .await?;
+        let base = pull(&db, SyncCursor::default(), 10).await?.changes[0].version;
+        sqlx::query("UPDATE todos SET title = 'server' WHERE id = 1")
+            .execute(&db)
+            .await?;
+
+        let mut tx = db.begin().await?;
+        let result = push(
+            &mut tx,
+            "public.todos",
+            &[
+                PushChange {
+                    id: json!(1),
+                    base_version: Some(base),
+                    operation: SyncOperation::Upsert,
+                    record: json!({ "title": "client" }).as_object().cloned(),
+                    changed_at: None,
+                },
+                PushChange {
+                    id: json!(2),
+                    base_version: None,
+                    operation: SyncOperation::Upsert,
+                    record: json!({ "title": "new" }).as_object().cloned(),
+                    changed_at: None,
+                },
+            ],
+            &MergeStrategy::Manual,
+        )
+        .await?;
+        tx.commit().await?;
+
+        assert_eq!(result.applied.len(), 1);
+        assert_eq!(result.applied[0].id, json!(2));
+        assert_eq!(result.conflicts.len(), 1);
+        let conflict = &result.conflicts[0];
+        assert_eq!(conflict.base_version, Some(base));
+        assert!(conflict.version > base);
+        assert_eq!(conflict.record.as_ref().unwrap()["title"], "server");
+        assert_eq!(
+            result.writes,
+            [(
+                ChangeAction::Create,
+                json!({ "id": 2, "title": "new", "done": false })
+            )]
+        );
+
+        // Rebased on the current version, the write goes through.
+        let mut tx = db.begin().await?;
+        let result = push(
+            &mut tx,
+            "public.todos",
+            &[PushChange {
+                id: json!(1),
+                base_version: Some(conflict.version),
+                operation: SyncOperation::Delete,
+                record: None,
+                changed_at: None,
+            }],
+            &MergeStrategy::Manual,
+        )
+        .await?;
+        tx.commit().await?;
+        assert!(result.conflicts.is_empty());
+        assert!(result.applied[0].version > conflict.version);
+        assert_eq!(
+            result.writes,
+            [(
+                ChangeAction::Delete,
+                json!({ "id": 1, "title": "server", "done": false })
+            )]
+        );
+
+        let ids: Vec<i32> = sqlx::query_scalar("SELECT id FROM todos")
+            .fetch_all(&db)
+            .await?;
+        assert_eq!(ids, [2]);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_push_quota(db: Pool<Postgres>) -> Result<(), crate::error::StoreError> {
+        setup(&db).await?;
+        let sqlite = sqlx::SqlitePool::connect("sqlite::memory:").await?;
+        let quotas = Arc::new(crate::quotas::Quotas::open(sqlite).await?);
+        quotas
+            .set_limits(
+                "ann",
+                crate::quotas::QuotaLimits::default().with_records("todos", 1),
+            )
+            .await?;
+        let change = |id: i64, operation: SyncOperation| PushChange {
+            id: json!(id),
+            base_version: None,
+            operation,
+            record: json!({ "title": "new" }).as_object().cloned(),
+            changed_at: None,
+        };
+        let push = |changes: Vec<PushChange>| {
+            push_changes(
+                Extension(db.clone()),
+                None,
+                Sandbox::default(),
+                Some(RecordQuota {
+                    quotas: quotas.clone(),
+                    subject: "ann".to_string(),
+                }),
+                None,
+                None,
+                Path(("public".to_string(), "todos".to_string())),
+                Json(changes),
+            )
+        };
+        let count = || async {
+            sqlx::query_scalar::<_, i64>("SELECT count(*) FROM todos")
+                .fetch_one(&db)
+                .await
+        };
+
+        let rejected = push(vec![
+            change(1, SyncOperation::Upsert),
+            change(2, SyncOperation::Upsert),
+        ])
+        .await
+        .unwrap_err();
+        assert_eq!(rejected.status(), StatusCode::PAYMENT_REQUIRED);
+        assert_eq!(count().await?, 0);
+
+        push(vec![change(1, SyncOperation::Upsert)]).await.unwrap();
+        // Pushing a row that exists creates nothing.
+        push(vec![change(1, SyncOperation::Upsert)]).await.unwrap();
+        let usage = quotas.usage("ann", chrono::Utc::now()).await?;
+        assert_eq!(usage.records["todos"], 1);
+
+        push(vec![change(1, SyncOperation::Delete)]).await.unwrap();
+        push(vec![change(2, SyncOperation::Upsert)]).await.unwrap();
+        assert_eq!(count().await?, 1);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_push_partial_update(db: Pool<Postgres>) -> sqlx::Result<()> {
+        setup(&db).await?;
+        sqlx::query("INSERT INTO todos (id, title) VALUES (1, 'a')")
+            .execute(&db)
+            .await?;
+        let base = pull(&db, SyncCursor::default(), 10).await?.changes[0].version;
+
+        // `title` is NOT NULL, so the row must be updated rather than inserted anew.
+        let change = PushChange {
+            id: json!(1),
+            base_version: Some(base),
+            operation: SyncOperation::Upsert,
+            record: json!({ "done": true }).as_object().cloned(),
+            changed_at: None,
+        };
+        let mut tx = db.begin().await?;
+        let result = push(
+            &mut tx,
+            "public.todos",
+            &[change.clone()],
+            &MergeStrategy::Manual,
+        )
+        .await?;
+        tx.commit().await?;
+        let applied = &result.applied[0];
+        assert!(applied.version > base);
+        assert_eq!(
+            applied.record,
+            Some(json!({ "id": 1, "title": "a", "done": true }))
+        );
+
+        // Pushing the same values again writes nothing.
+        let mut tx = db.begin().await?;
+        let again = push(
+            &mut tx,
+            "public.todos",
+            &[PushChange {
+                base_version: Some(applied.version),
+                ..change
+            }],
+            &MergeStrategy::Manual,
+        )
+        .await?;
+        tx.commit().await?;
+        assert_eq!(again.applied[0].version, applied.version);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_merge_strategies(db: Pool<Postgres>) -> sqlx::Result<()> {
+        setup(&db).await?;
+        sqlx::query("INSERT INTO todos (id, title) VALUES (1, 'a')")
+            .execute(&db)
+            .await?;
+        let base = pull(&db, SyncCursor::default(), 10).await?.changes[0].version;
+        let edited = Utc::now() - chrono::Duration::seconds(1);
+        sqlx::query("UPDATE todos SET done = true WHERE id = 1")
+            .execute(&db)
+            .await?;
+
+        // The client's offline edit predates the server's write of `done`.
+        let mut tx = db.begin().await?;
+        let result = push(
+            &mut tx,
+            "public.todos",
+            &[PushChange {
+                id: json!(1),
+                base_version: Some(base),
+                operation: SyncOperation::Upsert,
+                record: json!({ "title": "client", "done": false })
+                    .as_object()
+                    .cloned(),
+                changed_at: Some(edited),
+            }],
+            &MergeStrategy::LastWriteWinsByColumn,
+        )
+        .await?;
+        tx.commit().await?;
+        let applied = &result.applied[0];
+        assert_eq!(applied.resolved, Some(Resolved::Merged));
+        assert_eq!(
+            applied.record,
+            Some(json!({ "id": 1, "title": "client", "done": true }))
+        );
+
+        let patch = |if_match: Option<i64>, strategies: Option<MergeStrategies>| {
+            let mut headers = HeaderMap::new();
+            if let Some(version) = if_match {
+                headers.insert(header::IF_MATCH, format!("\"{version}\"").parse().unwrap());
+            }
+            patch_record(
+                Extension(db.clone()),
+                None,
+                Sandbox::default(),
+                None,
+                None,
+                strategies.map(|strategies| Extension(Arc::new(strategies))),
+                Path(("public".to_string(), "todos".to_string(), "1".to_string())),
+                headers,
+                Patch::Columns(json!({ "title": "patched" }).as_object().cloned().unwrap()),
+            )
+        };
+
+        let stale = patch(Some(base), None).await.unwrap_err();
+        assert_eq!(stale.status(), StatusCode::CONFLICT);
+        let kept = patch(
+            Some(base),
+            Some(MergeStrategies::new().with_default(MergeStrategy::ServerWins)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            kept.headers()[header::ETAG],
+            format!("\"{}\"", applied.version)
+        );
+
+        let current = patch(Some(applied.version), None).await.unwrap();
+        assert_eq!(current.status(), StatusCode::OK);
+        let title: String = sqlx::query_scalar("SELECT title FROM todos WHERE id = 1")
+            .fetch_one(&db)
+            .await?;
+        assert_eq!(title, "patched");
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_patch_not_null_columns(db: Pool<Postgres>) -> sqlx::Result<()> {
+        setup(&db).await?;
+        sqlx::query("INSERT INTO todos (id, title) VALUES (1, 'a')")
+            .execute(&db)
+            .await?;
+        let patch = |columns: serde_json::Value| {
+            patch_record(
+                Extension(db.clone()),
+                None,
+                Sandbox::default(),
+                None,
+                None,
+                None,
+                Path(("public".to_string(), "todos".to_string(), "1".to_string())),
+                HeaderMap::new(),
+                Patch::Columns(columns.as_object().cloned().unwrap()),
+            )
+        };
+
+        // `title` is NOT NULL and left out of the patch.
+        let done = patch(json!({ "done": true })).await.unwrap();
+        assert_eq!(done.status(), StatusCode::OK);
+        let etag = done.headers()[header::ETAG].clone();
+        let (title, finished): (String, bool) =
+            sqlx::query_as("SELECT title, done FROM todos WHERE id = 1")
+                .fetch_one(&db)
+                .await?;
+        assert_eq!((title.as_str(), finished), ("a", true));
+
+        // A patch changing nothing isn't written.
+        let logged: i64 = sqlx::query_scalar(&format!("SELECT count(*) FROM {CHANGELOG}"))
+            .fetch_one(&db)
+            .await?;
+        let unchanged = patch(json!({ "done": true })).await.unwrap();
+        assert_eq!(unchanged.status(), StatusCode::OK);
+        assert_eq!(unchanged.headers()[header::ETAG], etag);
+        let count: i64 = sqlx::query_scalar(&format!("SELECT count(*) FROM {CHANGELOG}"))
+            .fetch_one(&db)
+            .await?;
+        assert_eq!(count, logged);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_patch_formats(db: Pool<Postgres>) -> sqlx::Result<()> {
+        sqlx::query(
+            "CREATE TABLE profiles (id integer PRIMARY KEY, name text, settings jsonb NOT NULL)",
+        )
+        .execute(&db)
+        .await?;
+        install(&db, "public.profiles").await?;
+        sqlx::query(
+            r#"INSERT INTO profiles VALUES (1, 'ann', '{"theme": "light", "lang": "en"}')"#,
+        )
+        .execute(&db)
+        .await?;
+        let patch_sandboxed = |patch: Patch, sandbox: Sandbox| {
+            patch_record(
+                Extension(db.clone()),
+                None,
+                sandbox,
+                None,
+                None,
+                None,
+                Path((
+                    "public".to_string(),
+                    "profiles".to_string(),
+                    "1".to_string(),
+                )),
+                HeaderMap::new(),
+                patch,
+            )
+        };
+        let patch = |patch: Patch| patch_sandboxed(patch, Sandbox::default());
+
+        let operations = serde_json::from_value(json!([
+            { "op": "test", "path": "/name", "value": "ann" },
+            { "op": "replace", "path": "/settings/theme", "value": "dark" },
+        ]))
+        .unwrap();
+        patch(Patch::Operations(operations)).await.unwrap();
+        let merge = json!({ "name": null, "settings": { "lang": null } });
+        patch(Patch::Merge(merge.as_object().cloned().unwrap()))
+            .await
+            .unwrap();
+        let (name, SqlJson(settings)): (Option<String>, SqlJson<serde_json::Value>) =
+            sqlx::query_as("SELECT name, settings FROM profiles WHERE id = 1")
+                .fetch_one(&db)
+                .await?;
+        assert_eq!(name, None);
+        assert_eq!(settings, json!({ "theme": "dark" }));
+
+        let operations = serde_json::from_value(json!([
+            { "op": "test", "path": "/name", "value": "ann" },
+        ]))
+        .unwrap();
+        let failed = patch(Patch::Operations(operations)).await.unwrap_err();
+        assert_eq!(failed.status(), StatusCode::CONFLICT);
+
+        // A sandboxed patch returns the patched row without keeping it.
+        let preview = patch_sandboxed(
+            Patch::Columns(json!({ "name": "bob" }).as_object().cloned().unwrap()),
+            Sandbox(true),
+        )
+        .await
+        .unwrap();
+        let body = axum::body::to_bytes(preview.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let preview: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(preview["name"], "bob");
+        let name: Option<String> = sqlx::query_scalar("SELECT name FROM profiles WHERE id = 1")
+            .fetch_one(&db)
+            .await?;
+        assert_eq!(name, None);
+        Ok(())
+    }
+}
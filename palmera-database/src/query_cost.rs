@@ -0,0 +1,461 @@
+//! # Query cost accounting
+//!
+//! Filters and sorts on unindexed columns of large tables are cheap to request and
+//! expensive to answer. [`estimate`] turns the plan SQLite picks for a statement into a
+//! [`QueryCost`]: the rows its loops are expected to read, joins included, plus the rows
+//! it sorts or groups in temporary b-trees. The loops are matched to the tables they
+//! read by [`name_relations`], from the cursors the statement's bytecode opens, since
+//! the plan's text names aliases rather than tables. Tables are sized from `sqlite_stat1` when
+//! `ANALYZE` ran, and from their largest rowid otherwise; an indexed lookup is assumed
+//! to read one row in [`SEARCH_SELECTIVITY`], a primary key lookup a single row.
+//!
+//! Handlers running list queries tag their response with the cost as a [`QueryCost`]
+//! extension; see `GET /records/{table}`, with or without a saved view, in
+//! [`crate::saved_queries`]. The
+//! [`limit_query_cost`] middleware then charges it to the caller's budget in [`Quotas`],
+//! set with [`QuotaLimits::with_query_cost`], and reports it in the [`COST_HEADER`],
+//! [`BUDGET_REMAINING_HEADER`] and [`BUDGET_RESET_HEADER`] headers.
+//! The limit is soft: requests are let through while any budget is left, and once it
+//! is spent they are answered with `429 Too Many Requests` until the period ends.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # async fn run(
+//! #     db: sqlx::Pool<sqlx::Sqlite>,
+//! #     api: axum::Router,
+//! # ) -> Result<(), Box<dyn std::error::Error>> {
+//! use std::sync::Arc;
+//!
+//! use axum::middleware;
+//! use palmera_database::{
+//!     query_cost::limit_query_cost,
+//!     quotas::{DEFAULT_SUBJECT, QuotaLimits, Quotas},
+//! };
+//!
+//! let quotas = Arc::new(Quotas::open(db).await?);
+//! quotas
+//!     .set_limits(
+//!         DEFAULT_SUBJECT,
+//!         QuotaLimits::default()
+//!             .with_requests(10_000, 3600)
+//!             .with_query_cost(5_000_000),
+//!     )
+//!     .await?;
+//! let app = api.layer(middleware::from_fn_with_state(quotas, limit_query_cost));
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`QuotaLimits::with_query_cost`]: crate::quotas::QuotaLimits::with_query_cost
+
+use std::{collections::HashMap, ops::Add, sync::Arc};
+
+use axum::{
+    extract::{Request, State},
+    http::HeaderValue,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite, SqliteConnection};
+use utoipa::ToSchema;
+
+use crate::{
+    explain::PlanStep,
+    quotas::{QuotaSubject, Quotas},
+    sqlite::helpers::quote_ident,
+};
+
+/// Response header holding the cost of the queries a request ran.
+pub const COST_HEADER: &str = "x-query-cost";
+
+/// Response header holding the query cost left this period, for limited callers.
+pub const BUDGET_REMAINING_HEADER: &str = "x-query-budget-remaining";
+
+/// Response header holding the seconds until the query budget resets, for limited
+/// callers.
+pub const BUDGET_RESET_HEADER: &str = "x-query-budget-reset";
+
+/// Fraction of a table, as one in this many rows, an indexed lookup is assumed to read.
+pub const SEARCH_SELECTIVITY: u64 = 10;
+
+/// Estimated cost of answering a request.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+pub struct QueryCost {
+    /// Rows the statements' loops are expected to read.
+    pub rows_scanned: u64,
+    /// Tables read in nested loops inside another table's loop.
+    pub joins: u32,
+    /// Temporary b-trees built for `GROUP BY` or `DISTINCT`.
+    pub aggregates: u32,
+    /// Temporary b-trees built for `ORDER BY`.
+    pub sorts: u32,
+    /// Rows scanned plus rows sorted or grouped, at least 1.
+    pub cost: u64,
+}
+
+/// Adds up the costs of several statements answering one request.
+impl Add for QueryCost {
+    type Output = QueryCost;
+
+    fn add(self, other: QueryCost) -> QueryCost {
+        QueryCost {
+            rows_scanned: self.rows_scanned.saturating_add(other.rows_scanned),
+            joins: self.joins.saturating_add(other.joins),
+            aggregates: self.aggregates.saturating_add(other.aggregates),
+            sorts: self.sorts.saturating_add(other.sorts),
+            cost: self.cost.saturating_add(other.cost),
+        }
+    }
+}
+
+/// Sets the [`PlanStep::relation`] of the `SCAN` and `SEARCH` steps of `plan` from the
+/// root pages of the cursors opened by the statement's bytecode, the `p2` operands of
+/// its `OpenRead` and `OpenWrite` instructions in `EXPLAIN` order.
+///
+/// The loops open their cursors in the order of the plan: a table's cursor is followed
+/// by the cursor of the index it is searched with, and an index read on its own stands
+/// for its table.
+pub async fn name_relations(
+    conn: &mut SqliteConnection,
+    roots: &[i64],
+    plan: &mut [PlanStep],
+) -> Result<(), sqlx::Error> {
+    let schema: Vec<(i64, String, String)> =
+        sqlx::query_as("SELECT rootpage, type, tbl_name FROM sqlite_schema WHERE rootpage > 0")
+            .fetch_all(&mut *conn)
+            .await?;
+
+    let mut relations = Vec::new();
+    let mut previous: Option<(&str, &str)> = None;
+    for root in roots {
+        let Some((_, kind, table)) = schema.iter().find(|(page, _, _)| page == root) else {
+            continue;
+        };
+        if kind == "index" && previous == Some(("table", table.as_str())) {
+            previous = None;
+            continue;
+        }
+        relations.push(table.clone());
+        previous = Some((kind.as_str(), table.as_str()));
+    }
+
+    let mut relations = relations.into_iter();
+    for step in plan.iter_mut().filter(|step| reads_table(&step.detail)) {
+        step.relation = relations.next();
+    }
+    Ok(())
+}
+
+/// Returns whether a plan step loops over a table, rather than a subquery or constant
+/// rows, which are costed by their own steps.
+fn reads_table(detail: &str) -> bool {
+    let mut words = detail.split_whitespace();
+    matches!(words.next(), Some("SCAN" | "SEARCH"))
+        && words
+            .next()
+            .is_some_and(|name| !name.starts_with('(') && name != "CONSTANT")
+}
+
+/// Estimates the cost of a statement from its `EXPLAIN QUERY PLAN` steps, e.g. those
+/// of an [`Explanation`](crate::explain::Explanation). Loops over steps without a
+/// [`PlanStep::relation`] aren't costed.
+pub async fn estimate(db: &Pool<Sqlite>, plan: &[PlanStep]) -> Result<QueryCost, sqlx::Error> {
+    let mut sizes = HashMap::new();
+    let mut cost = QueryCost::default();
+    let mut loops = 0u32;
+    // Rows produced by the loops so far, each inner loop running once per outer row.
+    let mut produced = 1u64;
+    let mut sorted = 0u64;
+
+    for step in plan {
+        let mut words = step.detail.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some(kind @ ("SCAN" | "SEARCH")), _) => {
+                let Some(table) = step.relation.as_deref() else {
+                    continue;
+                };
+                let rows = match sizes.get(table) {
+                    Some(rows) => *rows,
+                    None => {
+                        let rows = table_rows(db, table).await?;
+                        sizes.insert(table.to_string(), rows);
+                        rows
+                    }
+                };
+                let per_loop = if kind == "SCAN" {
+                    rows
+                } else if step.detail.contains("(rowid=?)") || step.detail.contains("PRIMARY KEY") {
+                    1
+                } else {
+                    if step.detail.contains("AUTOMATIC") {
+                        // Building the automatic index reads the whole table once.
+                        cost.rows_scanned = cost.rows_scanned.saturating_add(rows);
+                    }
+                    (rows / SEARCH_SELECTIVITY).max(1)
+                };
+
+                if loops > 0 {
+                    cost.joins += 1;
+                }
+                loops += 1;
+                cost.rows_scanned = cost
+                    .rows_scanned
+                    .saturating_add(produced.saturating_mul(per_loop));
+                produced = produced.saturating_mul(per_loop.max(1));
+            }
+            (Some("USE"), Some("TEMP")) => {
+                if step.detail.ends_with("ORDER BY") {
+                    cost.sorts += 1;
+                } else {
+                    cost.aggregates += 1;
+                }
+                sorted = sorted.saturating_add(produced);
+            }
+            _ => {}
+        }
+    }
+
+    cost.cost = cost.rows_scanned.saturating_add(sorted).max(1);
+    Ok(cost)
+}
+
+/// Estimated number of rows of `table`.
+async fn table_rows(db: &Pool<Sqlite>, table: &str) -> Result<u64, sqlx::Error> {
+    let analyzed = crate::ddl::table_exists(db, "sqlite_stat1").await?;
+    if analyzed {
+        // Each row of `sqlite_stat1` starts with the row count of its table.
+        let rows: Option<i64> =
+            sqlx::query_scalar("SELECT CAST(stat AS INTEGER) FROM sqlite_stat1 WHERE tbl = ?")
+                .bind(table)
+                .fetch_optional(db)
+                .await?;
+        if let Some(rows) = rows {
+            return Ok(rows.max(0) as u64);
+        }
+    }
+
+    // The largest rowid bounds the row count without reading the table. Tables without
+    // rowids are counted.
+    let rows: Result<Option<i64>, _> =
+        sqlx::query_scalar(&format!("SELECT max(rowid) FROM {}", quote_ident(table)))
+            .fetch_one(db)
+            .await;
+    let rows = match rows {
+        Ok(rows) => rows.unwrap_or(0),
+        Err(_) => {
+            sqlx::query_scalar::<_, i64>(&format!("SELECT count(*) FROM {}", quote_ident(table)))
+                .fetch_one(db)
+                .await?
+        }
+    };
+    Ok(rows.max(0) as u64)
+}
+
+/// Axum middleware enforcing the query budgets of [`Quotas`]: it rejects requests of
+/// subjects who spent theirs with `429 Too Many Requests`, and charges the [`QueryCost`]
+/// extension of each response, reporting it in the response headers.
+pub async fn limit_query_cost(
+    State(quotas): State<Arc<Quotas>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let subject = QuotaSubject::from_extensions(request.extensions());
+    if let Some(QuotaSubject(subject)) = &subject
+        && let Err(err) = quotas.check_query_budget(subject, Utc::now()).await
+    {
+        return err.into_response();
+    }
+
+    let mut response = next.run(request).await;
+    let Some(cost) = response.extensions().get::<QueryCost>().copied() else {
+        return response;
+    };
+    let headers = response.headers_mut();
+    headers.insert(COST_HEADER, HeaderValue::from(cost.cost));
+
+    let Some(QuotaSubject(subject)) = subject else {
+        return response;
+    };
+    let now = Utc::now();
+    match quotas.charge_query_cost(&subject, cost.cost, now).await {
+        Ok(budget) => {
+            if let Some(remaining) = budget.remaining() {
+                let reset = (budget.period_ends - now).num_seconds().max(0);
+                headers.insert(BUDGET_REMAINING_HEADER, HeaderValue::from(remaining));
+                headers.insert(BUDGET_RESET_HEADER, HeaderValue::from(reset));
+            }
+        }
+        Err(err) => tracing::warn!(%err, subject, "failed to charge query cost"),
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        explain::{ExplainRequest, explain},
+        postgres::session::SessionContext,
+        quotas::QuotaLimits,
+    };
+    use axum::{Extension, Router, body::Body, http::StatusCode, middleware, routing::get};
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    #[sqlx::test]
+    async fn test_estimate(db: Pool<Sqlite>) -> Result<(), crate::error::StoreError> {
+        sqlx::raw_sql(
+            "CREATE TABLE tickets (id INTEGER PRIMARY KEY, status TEXT, title TEXT); \
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 500) \
+             INSERT INTO tickets (status, title) SELECT 'open', 't' || i FROM n;",
+        )
+        .execute(&db)
+        .await?;
+        let request = ExplainRequest {
+            table: "tickets".to_string(),
+            filter: json!({ "status": "open" }).as_object().cloned().unwrap(),
+            sort: vec!["-title".to_string()],
+            ..Default::default()
+        };
+
+        let scan = explain(&db, &request).await?.cost;
+        assert_eq!(scan.rows_scanned, 500);
+        assert_eq!(scan.sorts, 1);
+        assert_eq!(scan.cost, 1000);
+
+        sqlx::query("CREATE INDEX tickets_status ON tickets (status)")
+            .execute(&db)
+            .await?;
+        let search = explain(
+            &db,
+            &ExplainRequest {
+                sort: vec![],
+                ..request.clone()
+            },
+        )
+        .await?
+        .cost;
+        assert_eq!(search.rows_scanned, 50);
+        assert_eq!(search.cost, 50);
+
+        let lookup = explain(
+            &db,
+            &ExplainRequest {
+                table: "tickets".to_string(),
+                filter: json!({ "id": 7 }).as_object().cloned().unwrap(),
+                ..Default::default()
+            },
+        )
+        .await?
+        .cost;
+        assert_eq!(lookup.cost, 1);
+
+        let plan = [
+            PlanStep {
+                id: 2,
+                parent: 0,
+                detail: "SCAN a".to_string(),
+                relation: Some("tickets".to_string()),
+            },
+            PlanStep {
+                id: 3,
+                parent: 0,
+                detail: "SEARCH b USING INTEGER PRIMARY KEY (rowid=?)".to_string(),
+                relation: Some("tickets".to_string()),
+            },
+        ];
+        let join = estimate(&db, &plan).await?;
+        assert_eq!(join.joins, 1);
+        assert_eq!(join.rows_scanned, 1000);
+
+        // The plan names aliases, the bytecode the tables.
+        let sql = "SELECT * FROM tickets AS a JOIN tickets AS b ON a.id = b.id \
+                   WHERE b.status = 'open'";
+        let mut conn = db.acquire().await?;
+        let steps: Vec<(i64, i64, i64, String)> =
+            sqlx::query_as(&format!("EXPLAIN QUERY PLAN {sql}"))
+                .fetch_all(&mut *conn)
+                .await?;
+        let mut aliased: Vec<PlanStep> = steps
+            .into_iter()
+            .map(|(id, parent, _, detail)| PlanStep {
+                id,
+                parent,
+                detail,
+                relation: None,
+            })
+            .collect();
+        let roots: Vec<i64> =
+            sqlx::query_as::<_, (i64, String, i64, i64)>(&format!("EXPLAIN {sql}"))
+                .fetch_all(&mut *conn)
+                .await?
+                .into_iter()
+                .filter(|(_, opcode, _, _)| opcode == "OpenRead")
+                .map(|(_, _, _, root)| root)
+                .collect();
+        name_relations(&mut conn, &roots, &mut aliased).await?;
+        assert!(
+            aliased
+                .iter()
+                .all(|step| step.relation.as_deref() == Some("tickets"))
+        );
+        assert_eq!(estimate(&db, &aliased).await?.joins, 1);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_limit_query_cost(db: Pool<Sqlite>) -> Result<(), crate::error::StoreError> {
+        let quotas = Arc::new(Quotas::open(db).await?);
+        quotas
+            .set_limits("ann", QuotaLimits::default().with_query_cost(100))
+            .await?;
+
+        let cost = QueryCost {
+            rows_scanned: 60,
+            cost: 60,
+            ..Default::default()
+        };
+        let app = Router::new()
+            .route("/", get(move || async move { (Extension(cost), "ok") }))
+            .route("/free", get(|| async { "ok" }))
+            .layer(middleware::from_fn_with_state(
+                quotas.clone(),
+                limit_query_cost,
+            ));
+        let call = |uri: &str, subject: Option<&str>| {
+            let mut request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+            if let Some(subject) = subject {
+                request
+                    .extensions_mut()
+                    .insert(SessionContext::new("authenticated", subject));
+            }
+            app.clone().oneshot(request)
+        };
+
+        let response = call("/", Some("ann")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[COST_HEADER], "60");
+        assert_eq!(response.headers()[BUDGET_REMAINING_HEADER], "40");
+        assert!(response.headers().contains_key(BUDGET_RESET_HEADER));
+
+        // The budget is soft: the second request overspends it, the third is refused.
+        let response = call("/", Some("ann")).await.unwrap();
+        assert_eq!(response.headers()[BUDGET_REMAINING_HEADER], "0");
+        let response = call("/free", Some("ann")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let usage = quotas.usage("ann", Utc::now()).await?;
+        assert_eq!(usage.query_cost, 120);
+
+        // Unlimited and anonymous callers only get the cost.
+        let response = call("/", Some("bob")).await.unwrap();
+        assert_eq!(response.headers()[COST_HEADER], "60");
+        assert!(!response.headers().contains_key(BUDGET_REMAINING_HEADER));
+        let response = call("/", None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!response.headers().contains_key(BUDGET_REMAINING_HEADER));
+        Ok(())
+    }
+}
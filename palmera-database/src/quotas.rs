@@ -0,0 +1,919 @@
+//! # Quotas
+//!
+//! Caps what a user or tenant, the quota's subject, may consume: how many records it may
+//! create in each table, how many bytes it may store and how many API requests it may
+//! make per period. Limits are kept in the SQLite `_quotas` table and consumption in
+//! `_quota_usage`, both managed by the [`Quotas`] store. Subjects without limits of
+//! their own fall back to those of [`DEFAULT_SUBJECT`], and are unlimited without
+//! either.
+//!
+//! Limits are enforced where the resources are consumed:
+//!
+//! - The Postgres write routes, `POST /batch`, the sync pushes and patches and
+//!   `POST /{schema}/{table}/{id}/duplicate`, reserve the records they create through
+//!   [`RecordQuota`] before committing, and answer `402 Payment Required` if a table's
+//!   limit would be exceeded. Records deleted by them or by
+//!   `DELETE /{schema}/{table}/{id}` are given back.
+//! - Storage uploads charge their size through `palmera_storage::quota::StorageQuota`,
+//!   wired to [`Quotas::reserve_storage`] and [`Quotas::release_storage`].
+//! - The [`limit_requests`] middleware counts the requests of each period and answers
+//!   `429 Too Many Requests`, with a `Retry-After` header, once the limit is reached.
+//! - The [`limit_query_cost`](crate::query_cost::limit_query_cost) middleware charges
+//!   the estimated cost of the queries each request ran against a per period budget,
+//!   and answers `429 Too Many Requests` once it is spent.
+//!
+//! The subject is the [`QuotaSubject`] extension when a tenant middleware sets one, and
+//! the [`SessionContext`] user otherwise. Requests without either are not counted.
+//!
+//! `GET /me/usage` of [`router`] reports the caller's usage next to their limits, and
+//! `/admin/quotas` in [`crate::admin`] manages limits.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # async fn run(
+//! #     db: sqlx::Pool<sqlx::Sqlite>,
+//! #     api: axum::Router,
+//! # ) -> Result<(), Box<dyn std::error::Error>> {
+//! use std::sync::Arc;
+//!
+//! use axum::{Extension, middleware};
+//! use palmera_database::quotas::{self, DEFAULT_SUBJECT, QuotaLimits, Quotas, limit_requests};
+//!
+//! let store = Arc::new(Quotas::open(db).await?);
+//! store
+//!     .set_limits(
+//!         DEFAULT_SUBJECT,
+//!         QuotaLimits::default()
+//!             .with_records("public.posts", 100)
+//!             .with_storage_bytes(100 << 20)
+//!             .with_requests(1000, 3600),
+//!     )
+//!     .await?;
+//!
+//! let (usage, _api) = quotas::router()
+//!     .layer(Extension(store.clone()))
+//!     .split_for_parts();
+//! let app = api
+//!     .merge(usage)
+//!     .layer(middleware::from_fn_with_state(store, limit_requests));
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{collections::BTreeMap, convert::Infallible, fmt, sync::Arc};
+
+use axum::{
+    Extension, Json,
+    extract::{OptionalFromRequestParts, Request, State},
+    http::{Extensions, StatusCode, header, request::Parts},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use sea_query::SqliteQueryBuilder;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite, SqliteConnection, types::Json as SqlJson};
+use utoipa::ToSchema;
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+use crate::{
+    error::StoreError,
+    postgres::session::SessionContext,
+    sqlite::helpers::{create_quota_usage_table, create_quotas_table},
+};
+
+/// Subject whose limits apply to subjects without limits of their own.
+pub const DEFAULT_SUBJECT: &str = "*";
+
+/// Length of a request period unless limits set one, an hour.
+pub const DEFAULT_PERIOD_SECS: u64 = 3600;
+
+const RECORDS_METRIC: &str = "records:";
+const STORAGE_METRIC: &str = "storage_bytes";
+const REQUESTS_METRIC: &str = "requests";
+const QUERY_COST_METRIC: &str = "query_cost";
+
+/// What a subject may consume.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+pub struct QuotaLimits {
+    /// Most records the subject may create in each table, by table name. Tables of the
+    /// `public` schema may be named without it.
+    #[serde(default)]
+    pub records: BTreeMap<String, u64>,
+    /// Most bytes the subject may store. `None` means unlimited.
+    #[serde(default)]
+    pub storage_bytes: Option<u64>,
+    /// Most requests the subject may make per period. `None` means unlimited.
+    #[serde(default)]
+    pub requests: Option<u64>,
+    /// Most query cost the subject may spend per period, see [`crate::query_cost`].
+    /// `None` means unlimited.
+    #[serde(default)]
+    pub query_cost: Option<u64>,
+    /// Length of a request period, in seconds.
+    #[serde(default = "default_period_secs")]
+    pub period_secs: u64,
+}
+
+fn default_period_secs() -> u64 {
+    DEFAULT_PERIOD_SECS
+}
+
+impl Default for QuotaLimits {
+    fn default() -> Self {
+        Self {
+            records: BTreeMap::new(),
+            storage_bytes: None,
+            requests: None,
+            query_cost: None,
+            period_secs: DEFAULT_PERIOD_SECS,
+        }
+    }
+}
+
+impl QuotaLimits {
+    pub fn with_records(mut self, table: &str, limit: u64) -> Self {
+        self.records.insert(table_key(table).to_string(), limit);
+        self
+    }
+
+    pub fn with_storage_bytes(mut self, limit: u64) -> Self {
+        self.storage_bytes = Some(limit);
+        self
+    }
+
+    /// Allows `limit` requests every `period_secs` seconds.
+    pub fn with_requests(mut self, limit: u64, period_secs: u64) -> Self {
+        self.requests = Some(limit);
+        self.period_secs = period_secs;
+        self
+    }
+
+    /// Allows spending `limit` query cost per period.
+    pub fn with_query_cost(mut self, limit: u64) -> Self {
+        self.query_cost = Some(limit);
+        self
+    }
+
+    fn limit(&self, metric: &str) -> Option<u64> {
+        match metric.strip_prefix(RECORDS_METRIC) {
+            Some(table) => self.records.get(table).copied(),
+            None if metric == STORAGE_METRIC => self.storage_bytes,
+            None if metric == QUERY_COST_METRIC => self.query_cost,
+            None => self.requests,
+        }
+    }
+}
+
+/// The limits set for a subject.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+pub struct SubjectLimits {
+    pub subject: String,
+    pub limits: QuotaLimits,
+}
+
+/// What a subject consumed, next to its limits.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+pub struct QuotaUsage {
+    pub subject: String,
+    /// Limits in effect, the defaults' if the subject has none of its own.
+    pub limits: QuotaLimits,
+    /// Records created and not deleted, by table.
+    pub records: BTreeMap<String, u64>,
+    pub storage_bytes: u64,
+    /// Requests made in the current period.
+    pub requests: u64,
+    /// Query cost spent in the current period.
+    #[serde(default)]
+    pub query_cost: u64,
+    /// When the current period ends.
+    pub period_ends: DateTime<Utc>,
+}
+
+/// A subject's query budget for the current period.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+pub struct QueryBudget {
+    /// `None` when the subject's query cost is unlimited.
+    pub limit: Option<u64>,
+    pub spent: u64,
+    pub period_ends: DateTime<Utc>,
+}
+
+impl QueryBudget {
+    /// Query cost left this period, `None` when unlimited.
+    pub fn remaining(&self) -> Option<u64> {
+        self.limit.map(|limit| limit.saturating_sub(self.spent))
+    }
+}
+
+/// Why a subject may not consume more.
+#[derive(Debug)]
+pub enum QuotaError {
+    /// Consuming would exceed the `limit` of `metric`.
+    Exceeded {
+        metric: String,
+        limit: u64,
+    },
+    /// The request limit of the period was reached; the next period starts in
+    /// `retry_after` seconds.
+    RateLimited {
+        retry_after: u64,
+    },
+    Database(sqlx::Error),
+}
+
+impl QuotaError {
+    /// HTTP status the request should be answered with.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            QuotaError::Exceeded { .. } => StatusCode::PAYMENT_REQUIRED,
+            QuotaError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            QuotaError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl fmt::Display for QuotaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuotaError::Exceeded { metric, limit } => {
+                write!(f, "quota of {} exceeded for {}", limit, metric)
+            }
+            QuotaError::RateLimited { retry_after } => {
+                write!(f, "request quota exceeded, retry in {}s", retry_after)
+            }
+            QuotaError::Database(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for QuotaError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            QuotaError::Database(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<sqlx::Error> for QuotaError {
+    fn from(err: sqlx::Error) -> Self {
+        QuotaError::Database(err)
+    }
+}
+
+impl IntoResponse for QuotaError {
+    fn into_response(self) -> Response {
+        match self {
+            QuotaError::Database(err) => {
+                tracing::error!(%err, "quota check failed");
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+            QuotaError::RateLimited { retry_after } => (
+                self.status(),
+                [(header::RETRY_AFTER, retry_after.to_string())],
+                self.to_string(),
+            )
+                .into_response(),
+            err => (err.status(), err.to_string()).into_response(),
+        }
+    }
+}
+
+/// The user or tenant a request consumes quota for.
+///
+/// Extracting `Option<QuotaSubject>` yields the `QuotaSubject` extension if a middleware
+/// set one, e.g. to charge a whole tenant, and the [`SessionContext`] user otherwise.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotaSubject(pub String);
+
+impl QuotaSubject {
+    pub fn from_extensions(extensions: &Extensions) -> Option<Self> {
+        if let Some(subject) = extensions.get::<QuotaSubject>() {
+            return Some(subject.clone());
+        }
+        let user_id = extensions.get::<SessionContext>()?.user_id.clone()?;
+        Some(Self(user_id))
+    }
+}
+
+impl<S: Send + Sync> OptionalFromRequestParts<S> for QuotaSubject {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> Result<Option<Self>, Self::Rejection> {
+        Ok(Self::from_extensions(&parts.extensions))
+    }
+}
+
+/// The records quota a write is charged against: the [`Quotas`] extension and the
+/// [`QuotaSubject`]. Extracting `Option<RecordQuota>` yields `None` without either, and
+/// the write goes uncounted.
+///
+/// The Postgres write routes reserve the records they create with [`Self::reserve`]
+/// before committing, and [`Self::settle`] them once their transaction is over.
+#[derive(Debug, Clone)]
+pub struct RecordQuota {
+    pub quotas: Arc<Quotas>,
+    pub subject: String,
+}
+
+impl RecordQuota {
+    /// Reserves the `created` records, counted by table. Either every table has room
+    /// and all are reserved, or none is.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`QuotaError::Exceeded`] for the first table without room.
+    pub async fn reserve(&self, created: &BTreeMap<String, u64>) -> Result<(), QuotaError> {
+        if created.values().all(|count| *count == 0) {
+            return Ok(());
+        }
+        self.quotas.reserve_records(&self.subject, created).await
+    }
+
+    /// Gives back the reserved `created` records if the write was rolled back, and the
+    /// `deleted` ones if it was `committed`.
+    pub async fn settle(
+        &self,
+        created: &BTreeMap<String, u64>,
+        deleted: &BTreeMap<String, u64>,
+        committed: bool,
+    ) {
+        let released = if committed { deleted } else { created };
+        if released.values().all(|count| *count == 0) {
+            return;
+        }
+        if let Err(err) = self.quotas.release_records(&self.subject, released).await {
+            tracing::warn!(%err, "failed to release records from the quota");
+        }
+    }
+}
+
+impl<S: Send + Sync> OptionalFromRequestParts<S> for RecordQuota {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> Result<Option<Self>, Self::Rejection> {
+        let Some(quotas) = parts.extensions.get::<Arc<Quotas>>() else {
+            return Ok(None);
+        };
+        Ok(
+            QuotaSubject::from_extensions(&parts.extensions).map(|QuotaSubject(subject)| Self {
+                quotas: quotas.clone(),
+                subject,
+            }),
+        )
+    }
+}
+
+/// Quota limits and usage persisted in SQLite.
+#[derive(Debug, Clone)]
+pub struct Quotas {
+    db: Pool<Sqlite>,
+}
+
+impl Quotas {
+    /// Opens the store, creating the `_quotas` and `_quota_usage` tables if they do not
+    /// exist.
+    pub async fn open(db: Pool<Sqlite>) -> Result<Self, sqlx::Error> {
+        sqlx::query(&create_quotas_table().to_string(SqliteQueryBuilder))
+            .execute(&db)
+            .await?;
+        sqlx::query(&create_quota_usage_table().to_string(SqliteQueryBuilder))
+            .execute(&db)
+            .await?;
+
+        Ok(Self { db })
+    }
+
+    /// Returns the limits set for each subject, ordered by subject.
+    pub async fn list(&self) -> Result<Vec<SubjectLimits>, sqlx::Error> {
+        let rows: Vec<(String, SqlJson<QuotaLimits>)> =
+            sqlx::query_as("SELECT subject, limits FROM _quotas ORDER BY subject")
+                .fetch_all(&self.db)
+                .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(subject, limits)| SubjectLimits {
+                subject,
+                limits: limits.0,
+            })
+            .collect())
+    }
+
+    /// Returns the limits in effect for `subject`: its own, else the defaults, else
+    /// none.
+    pub async fn limits(&self, subject: &str) -> Result<QuotaLimits, sqlx::Error> {
+        let limits: Option<SqlJson<QuotaLimits>> = sqlx::query_scalar(
+            "SELECT limits FROM _quotas WHERE subject IN (?, ?) ORDER BY subject = ? LIMIT 1",
+        )
+        .bind(subject)
+        .bind(DEFAULT_SUBJECT)
+        .bind(DEFAULT_SUBJECT)
+        .fetch_optional(&self.db)
+        .await?;
+
+        Ok(limits.map(|limits| limits.0).unwrap_or_default())
+    }
+
+    /// Sets the limits of `subject`, [`DEFAULT_SUBJECT`] for the defaults.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`StoreError::Invalid`] if the subject is empty or the period is zero.
+    pub async fn set_limits(
+        &self,
+        subject: &str,
+        mut limits: QuotaLimits,
+    ) -> Result<QuotaLimits, StoreError> {
+        if subject.trim().is_empty() {
+            return Err(invalid("subject must not be empty".to_string()));
+        }
+        if limits.period_secs == 0 {
+            return Err(invalid("period_secs must be positive".to_string()));
+        }
+        limits.records = limits
+            .records
+            .into_iter()
+            .map(|(table, limit)| (table_key(&table).to_string(), limit))
+            .collect();
+
+        sqlx::query(
+            "INSERT INTO _quotas (subject, limits, updated) VALUES (?, ?, ?) \
+             ON CONFLICT (subject) DO UPDATE SET limits = excluded.limits, updated = excluded.updated",
+        )
+        .bind(subject)
+        .bind(SqlJson(&limits))
+        .bind(Utc::now().timestamp())
+        .execute(&self.db)
+        .await?;
+
+        Ok(limits)
+    }
+
+    /// Removes the limits of `subject`, returning whether it had any. Its usage is kept.
+    pub async fn remove_limits(&self, subject: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM _quotas WHERE subject = ?")
+            .bind(subject)
+            .execute(&self.db)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Returns what `subject` consumed as of `now`.
+    pub async fn usage(
+        &self,
+        subject: &str,
+        now: DateTime<Utc>,
+    ) -> Result<QuotaUsage, sqlx::Error> {
+        let limits = self.limits(subject).await?;
+        let period_end = period_end(now, limits.period_secs);
+        let rows: Vec<(String, i64)> = sqlx::query_as(
+            "SELECT metric, amount FROM _quota_usage \
+             WHERE subject = ? AND period_end IN (0, ?) ORDER BY metric",
+        )
+        .bind(subject)
+        .bind(period_end)
+        .fetch_all(&self.db)
+        .await?;
+
+        let mut usage = QuotaUsage {
+            subject: subject.to_string(),
+            limits,
+            records: BTreeMap::new(),
+            storage_bytes: 0,
+            requests: 0,
+            query_cost: 0,
+            period_ends: DateTime::from_timestamp(period_end, 0).unwrap_or(now),
+        };
+        for (metric, amount) in rows {
+            let amount = amount.max(0) as u64;
+            match metric.strip_prefix(RECORDS_METRIC) {
+                Some(table) => {
+                    usage.records.insert(table.to_string(), amount);
+                }
+                None if metric == STORAGE_METRIC => usage.storage_bytes = amount,
+                None if metric == QUERY_COST_METRIC => usage.query_cost = amount,
+                None => usage.requests = amount,
+            }
+        }
+
+        Ok(usage)
+    }
+
+    /// Reserves the records `subject` is about to create, counted by table. Either every
+    /// table has room and all are reserved, or none is.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`QuotaError::Exceeded`] for the first table without room.
+    pub async fn reserve_records(
+        &self,
+        subject: &str,
+        counts: &BTreeMap<String, u64>,
+    ) -> Result<(), QuotaError> {
+        let limits = self.limits(subject).await?;
+        let mut tx = self.db.begin().await?;
+        for (table, count) in counts {
+            let metric = format!("{}{}", RECORDS_METRIC, table_key(table));
+            reserve(&mut tx, subject, &metric, 0, *count, limits.limit(&metric)).await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Gives back records `subject` deleted, or reserved without creating them.
+    pub async fn release_records(
+        &self,
+        subject: &str,
+        counts: &BTreeMap<String, u64>,
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = self.db.begin().await?;
+        for (table, count) in counts {
+            let metric = format!("{}{}", RECORDS_METRIC, table_key(table));
+            release(&mut tx, subject, &metric, *count).await?;
+        }
+        tx.commit().await
+    }
+
+    /// Reserves `bytes` of storage for `subject`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`QuotaError::Exceeded`] if the subject's storage limit has no room.
+    pub async fn reserve_storage(&self, subject: &str, bytes: u64) -> Result<(), QuotaError> {
+        let limit = self.limits(subject).await?.storage_bytes;
+        let mut conn = self.db.acquire().await?;
+        reserve(&mut conn, subject, STORAGE_METRIC, 0, bytes, limit).await
+    }
+
+    /// Gives back `bytes` of storage `subject` freed.
+    pub async fn release_storage(&self, subject: &str, bytes: u64) -> Result<(), sqlx::Error> {
+        let mut conn = self.db.acquire().await?;
+        release(&mut conn, subject, STORAGE_METRIC, bytes).await
+    }
+
+    /// Counts a request of `subject` made at `now`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`QuotaError::RateLimited`] if the subject already made as many requests
+    /// as its limit allows this period.
+    pub async fn hit(&self, subject: &str, now: DateTime<Utc>) -> Result<(), QuotaError> {
+        let limits = self.limits(subject).await?;
+        let period_end = period_end(now, limits.period_secs);
+        let mut conn = self.db.acquire().await?;
+        match reserve(
+            &mut conn,
+            subject,
+            REQUESTS_METRIC,
+            period_end,
+            1,
+            limits.requests,
+        )
+        .await
+        {
+            Err(QuotaError::Exceeded { .. }) => Err(QuotaError::RateLimited {
+                retry_after: (period_end - now.timestamp()).max(1) as u64,
+            }),
+            result => result,
+        }
+    }
+
+    /// Checks that `subject` has query cost left this period. The budget is soft: a
+    /// request is let through while any is left and charged in full afterwards, so the
+    /// last request of a period may overspend it.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`QuotaError::RateLimited`] once the subject spent its budget.
+    pub async fn check_query_budget(
+        &self,
+        subject: &str,
+        now: DateTime<Utc>,
+    ) -> Result<(), QuotaError> {
+        let limits = self.limits(subject).await?;
+        let Some(limit) = limits.query_cost else {
+            return Ok(());
+        };
+        let period_end = period_end(now, limits.period_secs);
+        let spent = spent(&self.db, subject, QUERY_COST_METRIC, period_end).await?;
+        if spent >= limit {
+            return Err(QuotaError::RateLimited {
+                retry_after: (period_end - now.timestamp()).max(1) as u64,
+            });
+        }
+        Ok(())
+    }
+
+    /// Charges `cost` to the query budget of `subject` for the period containing `now`,
+    /// returning what is left of it.
+    pub async fn charge_query_cost(
+        &self,
+        subject: &str,
+        cost: u64,
+        now: DateTime<Utc>,
+    ) -> Result<QueryBudget, sqlx::Error> {
+        let limits = self.limits(subject).await?;
+        let period_end = period_end(now, limits.period_secs);
+        let mut conn = self.db.acquire().await?;
+        // Without a limit, reserving can only fail on the database.
+        if let Err(QuotaError::Database(err)) = reserve(
+            &mut conn,
+            subject,
+            QUERY_COST_METRIC,
+            period_end,
+            cost,
+            None,
+        )
+        .await
+        {
+            return Err(err);
+        }
+        let spent = spent(&self.db, subject, QUERY_COST_METRIC, period_end).await?;
+
+        Ok(QueryBudget {
+            limit: limits.query_cost,
+            spent,
+            period_ends: DateTime::from_timestamp(period_end, 0).unwrap_or(now),
+        })
+    }
+
+    /// Deletes the request counts of periods ended by `now`, returning how many were
+    /// deleted.
+    pub async fn prune(&self, now: DateTime<Utc>) -> Result<u64, sqlx::Error> {
+        let result =
+            sqlx::query("DELETE FROM _quota_usage WHERE period_end <> 0 AND period_end <= ?")
+                .bind(now.timestamp())
+                .execute(&self.db)
+                .await?;
+
+        Ok(result.rows_affected())
+    }
+}
+
+/// Adds `amount` to the usage of `metric` unless that would exceed `limit`. Cumulative
+/// metrics are kept with a `period_end` of 0.
+async fn reserve(
+    conn: &mut SqliteConnection,
+    subject: &str,
+    metric: &str,
+    period_end: i64,
+    amount: u64,
+    limit: Option<u64>,
+) -> Result<(), QuotaError> {
+    let max = limit.map_or(i64::MAX, |limit| limit.min(i64::MAX as u64) as i64);
+    let result = sqlx::query(
+        "INSERT INTO _quota_usage (subject, metric, period_end, amount) \
+         SELECT ?1, ?2, ?3, ?4 WHERE ?4 <= ?5 \
+         ON CONFLICT (subject, metric, period_end) DO UPDATE \
+         SET amount = amount + excluded.amount WHERE amount + excluded.amount <= ?5",
+    )
+    .bind(subject)
+    .bind(metric)
+    .bind(period_end)
+    .bind(amount.min(i64::MAX as u64) as i64)
+    .bind(max)
+    .execute(conn)
+    .await?;
+
+    match (result.rows_affected(), limit) {
+        (0, Some(limit)) => Err(QuotaError::Exceeded {
+            metric: metric.to_string(),
+            limit,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Amount of `metric` used by `subject` in the period ending at `period_end`.
+async fn spent(
+    db: &Pool<Sqlite>,
+    subject: &str,
+    metric: &str,
+    period_end: i64,
+) -> Result<u64, sqlx::Error> {
+    let amount: Option<i64> = sqlx::query_scalar(
+        "SELECT amount FROM _quota_usage WHERE subject = ? AND metric = ? AND period_end = ?",
+    )
+    .bind(subject)
+    .bind(metric)
+    .bind(period_end)
+    .fetch_optional(db)
+    .await?;
+
+    Ok(amount.unwrap_or(0).max(0) as u64)
+}
+
+async fn release(
+    conn: &mut SqliteConnection,
+    subject: &str,
+    metric: &str,
+    amount: u64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE _quota_usage SET amount = max(amount - ?, 0) \
+         WHERE subject = ? AND metric = ? AND period_end = 0",
+    )
+    .bind(amount.min(i64::MAX as u64) as i64)
+    .bind(subject)
+    .bind(metric)
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Quota key of `table`, without the implied `public` schema.
+fn table_key(table: &str) -> &str {
+    table.strip_prefix("public.").unwrap_or(table)
+}
+
+/// End of the period of `period_secs` seconds containing `now`, as a Unix timestamp.
+fn period_end(now: DateTime<Utc>, period_secs: u64) -> i64 {
+    let period = period_secs.clamp(1, i64::MAX as u64) as i64;
+    (now.timestamp().div_euclid(period) + 1) * period
+}
+
+fn invalid(message: String) -> StoreError {
+    StoreError::Invalid(message)
+}
+
+/// Axum middleware counting the requests of each subject with `quotas`, rejecting them
+/// with `429 Too Many Requests` once a subject reached its limit for the period.
+pub async fn limit_requests(
+    State(quotas): State<Arc<Quotas>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if let Some(QuotaSubject(subject)) = QuotaSubject::from_extensions(request.extensions())
+        && let Err(err) = quotas.hit(&subject, Utc::now()).await
+    {
+        return err.into_response();
+    }
+    next.run(request).await
+}
+
+/// Reports the caller's usage and limits.
+#[utoipa::path(
+    get,
+    path = "/me/usage",
+    responses((status = 200, body = QuotaUsage), (status = 401))
+)]
+async fn get_usage(
+    Extension(quotas): Extension<Arc<Quotas>>,
+    subject: Option<QuotaSubject>,
+) -> Result<Json<QuotaUsage>, StatusCode> {
+    let QuotaSubject(subject) = subject.ok_or(StatusCode::UNAUTHORIZED)?;
+    quotas
+        .usage(&subject, Utc::now())
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+pub fn router() -> OpenApiRouter {
+    OpenApiRouter::new().routes(routes!(get_usage))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Router, body::Body, middleware, routing::get};
+    use tower::ServiceExt;
+
+    fn counts(table: &str, count: u64) -> BTreeMap<String, u64> {
+        BTreeMap::from([(table.to_string(), count)])
+    }
+
+    #[sqlx::test]
+    async fn test_records_and_storage(db: Pool<Sqlite>) -> Result<(), StoreError> {
+        let quotas = Quotas::open(db).await?;
+        quotas
+            .set_limits(
+                DEFAULT_SUBJECT,
+                QuotaLimits::default().with_records("posts", 2),
+            )
+            .await?;
+        quotas
+            .set_limits(
+                "ann",
+                QuotaLimits::default()
+                    .with_records("public.posts", 3)
+                    .with_storage_bytes(10),
+            )
+            .await?;
+        assert!(quotas.set_limits("", QuotaLimits::default()).await.is_err());
+
+        quotas
+            .reserve_records("ann", &counts("posts", 2))
+            .await
+            .unwrap();
+        let over = quotas
+            .reserve_records("ann", &counts("public.posts", 2))
+            .await;
+        assert!(matches!(over, Err(QuotaError::Exceeded { limit: 3, .. })));
+        // Nothing is reserved when one table has no room.
+        let mut both = counts("comments", 5);
+        both.insert("posts".to_string(), 2);
+        assert!(quotas.reserve_records("ann", &both).await.is_err());
+        quotas.release_records("ann", &counts("posts", 1)).await?;
+
+        quotas.reserve_storage("ann", 8).await.unwrap();
+        let over = quotas.reserve_storage("ann", 3).await.unwrap_err();
+        assert_eq!(over.status(), StatusCode::PAYMENT_REQUIRED);
+        quotas.release_storage("ann", 20).await?;
+
+        let usage = quotas.usage("ann", Utc::now()).await?;
+        assert_eq!(usage.records, counts("posts", 1));
+        assert_eq!(usage.storage_bytes, 0);
+        assert_eq!(usage.limits.records, counts("posts", 3));
+
+        // Other subjects fall back to the defaults.
+        quotas
+            .reserve_records("bob", &counts("posts", 2))
+            .await
+            .unwrap();
+        assert!(
+            quotas
+                .reserve_records("bob", &counts("posts", 1))
+                .await
+                .is_err()
+        );
+        quotas.reserve_storage("bob", u64::MAX).await.unwrap();
+
+        assert!(quotas.remove_limits("ann").await?);
+        assert_eq!(quotas.limits("ann").await?.records, counts("posts", 2));
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_limit_requests(db: Pool<Sqlite>) -> Result<(), StoreError> {
+        let quotas = Arc::new(Quotas::open(db).await?);
+        quotas
+            .set_limits("ann", QuotaLimits::default().with_requests(2, 60))
+            .await?;
+
+        let app =
+            Router::new()
+                .route("/", get(|| async { "ok" }))
+                .layer(middleware::from_fn_with_state(
+                    quotas.clone(),
+                    limit_requests,
+                ));
+        let call = |subject: Option<&str>| {
+            let mut request = Request::builder().uri("/").body(Body::empty()).unwrap();
+            if let Some(subject) = subject {
+                request
+                    .extensions_mut()
+                    .insert(SessionContext::new("authenticated", subject));
+            }
+            app.clone().oneshot(request)
+        };
+
+        assert_eq!(call(Some("ann")).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(call(Some("ann")).await.unwrap().status(), StatusCode::OK);
+        let limited = call(Some("ann")).await.unwrap();
+        assert_eq!(limited.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = limited.headers()[header::RETRY_AFTER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((1..=60).contains(&retry_after));
+        // Anonymous requests and subjects without limits pass.
+        assert_eq!(call(None).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(call(Some("bob")).await.unwrap().status(), StatusCode::OK);
+
+        let Json(usage) = get_usage(
+            Extension(quotas.clone()),
+            Some(QuotaSubject("ann".to_string())),
+        )
+        .await
+        .unwrap();
+        assert_eq!(usage.requests, 2);
+        assert_eq!(
+            get_usage(Extension(quotas.clone()), None)
+                .await
+                .unwrap_err(),
+            StatusCode::UNAUTHORIZED
+        );
+
+        // The next period starts over.
+        let later = Utc::now() + chrono::Duration::seconds(60);
+        quotas.hit("ann", later).await.unwrap();
+        assert_eq!(quotas.usage("ann", later).await?.requests, 1);
+        assert!(quotas.prune(later).await? >= 1);
+        assert_eq!(quotas.usage("ann", Utc::now()).await?.requests, 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,585 @@
+//! # Data retention
+//!
+//! [`RetentionRule`]s purge rows older than a maximum age from a table, e.g. keeping 30
+//! days of `logs`. Expired rows are either deleted or moved to an archive table. The
+//! rules live in the SQLite `_retention` table, managed by the [`Retention`] store and the
+//! `/admin/retention` routes in [`crate::admin`].
+//!
+//! [`spawn_retention`] applies every enabled rule periodically. Rows are purged in
+//! batches of [`RetentionRule::batch_size`], each in its own transaction, so a large
+//! backlog doesn't hold a write lock for long. Listeners registered with
+//! [`Retention::before_purge`] see every batch before it is removed, e.g. to copy the
+//! rows elsewhere.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # async fn run(db: sqlx::Pool<sqlx::Sqlite>) -> Result<(), Box<dyn std::error::Error>> {
+//! use std::{sync::Arc, time::Duration};
+//! use palmera_database::retention::{Retention, RetentionRule, spawn_retention};
+//!
+//! let retention = Arc::new(Retention::open(db).await?);
+//! retention.before_purge(|batch| {
+//!     tracing::info!(table = %batch.table_name, rows = batch.rows.len(), "purging")
+//! });
+//! retention.set(RetentionRule::new("logs", "created", 30)).await?;
+//!
+//! spawn_retention(retention, Duration::from_secs(3600));
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{sync::Arc, sync::RwLock, time::Duration};
+
+use chrono::{DateTime, Utc};
+use sea_query::SqliteQueryBuilder;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite, types::Json};
+use tokio::task::JoinHandle;
+use utoipa::ToSchema;
+
+use crate::{
+    error::StoreError,
+    postgres::helpers::quote_ident,
+    sqlite::helpers::{create_retention_table, row_json, table_columns},
+};
+
+/// Rows purged per transaction unless a rule says otherwise.
+pub const DEFAULT_BATCH_SIZE: u32 = 1000;
+
+/// How the timestamp column of a table stores time.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TimestampFormat {
+    /// Text SQLite's date functions understand, such as `2025-06-01 12:00:00` or
+    /// RFC 3339.
+    #[default]
+    Text,
+    /// Seconds since the Unix epoch.
+    UnixSeconds,
+    /// Milliseconds since the Unix epoch.
+    UnixMillis,
+}
+
+impl TimestampFormat {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Text => "text",
+            Self::UnixSeconds => "unix_seconds",
+            Self::UnixMillis => "unix_millis",
+        }
+    }
+
+    fn parse(format: &str) -> Result<Self, sqlx::Error> {
+        match format {
+            "text" => Ok(Self::Text),
+            "unix_seconds" => Ok(Self::UnixSeconds),
+            "unix_millis" => Ok(Self::UnixMillis),
+            other => Err(sqlx::Error::Decode(
+                format!("unknown timestamp format {other:?}").into(),
+            )),
+        }
+    }
+
+    /// Condition matching rows of `column` older than the Unix timestamp bound as `?`.
+    fn older_than(&self, column: &str) -> String {
+        let column = quote_ident(column);
+        match self {
+            Self::Text => format!("CAST(strftime('%s', {column}) AS INTEGER) < ?"),
+            Self::UnixSeconds => format!("{column} < ?"),
+            Self::UnixMillis => format!("{column} < ? * 1000"),
+        }
+    }
+}
+
+/// What happens to expired rows.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RetentionAction {
+    #[default]
+    Delete,
+    /// Moves the rows to the table `into`, created with the source table's columns if
+    /// it does not exist.
+    Archive { into: String },
+}
+
+/// Purges rows of `table_name` whose `timestamp_column` is older than `max_age_days`.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+pub struct RetentionRule {
+    pub table_name: String,
+    pub timestamp_column: String,
+    #[serde(default)]
+    pub timestamp_format: TimestampFormat,
+    pub max_age_days: u32,
+    #[serde(default)]
+    pub action: RetentionAction,
+    /// Rows purged per transaction.
+    #[serde(default = "default_batch_size")]
+    pub batch_size: u32,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_batch_size() -> u32 {
+    DEFAULT_BATCH_SIZE
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl RetentionRule {
+    /// An enabled rule deleting rows of `table` once `column` is older than
+    /// `max_age_days`.
+    pub fn new(table: &str, column: &str, max_age_days: u32) -> Self {
+        Self {
+            table_name: table.to_string(),
+            timestamp_column: column.to_string(),
+            timestamp_format: TimestampFormat::default(),
+            max_age_days,
+            action: RetentionAction::default(),
+            batch_size: DEFAULT_BATCH_SIZE,
+            enabled: true,
+        }
+    }
+
+    pub fn with_format(mut self, format: TimestampFormat) -> Self {
+        self.timestamp_format = format;
+        self
+    }
+
+    pub fn with_action(mut self, action: RetentionAction) -> Self {
+        self.action = action;
+        self
+    }
+
+    pub fn with_batch_size(mut self, batch_size: u32) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    pub fn with_enabled(mut self, enabled: bool) -> Self {
+        self.enabled = enabled;
+        self
+    }
+
+    /// Rows with a timestamp before this are expired at `now`.
+    pub fn cutoff(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        now - chrono::Duration::days(self.max_age_days.into())
+    }
+}
+
+/// Rows about to be purged, passed to [`Retention::before_purge`] listeners.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PurgeBatch {
+    pub table_name: String,
+    pub action: RetentionAction,
+    /// The rows as JSON objects keyed by column.
+    pub rows: Vec<serde_json::Value>,
+}
+
+/// Rows purged from a table by one run of its rule.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+pub struct PurgeReport {
+    pub table_name: String,
+    pub purged: u64,
+}
+
+type Listener = Box<dyn Fn(&PurgeBatch) + Send + Sync>;
+
+type RetentionRow = (
+    String,
+    String,
+    String,
+    u32,
+    Json<RetentionAction>,
+    u32,
+    bool,
+);
+
+/// Retention rules persisted in SQLite.
+pub struct Retention {
+    db: Pool<Sqlite>,
+    listeners: RwLock<Vec<Listener>>,
+}
+
+impl std::fmt::Debug for Retention {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Retention")
+            .field("listeners", &self.listeners.read().unwrap().len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Retention {
+    /// Opens the store, creating the `_retention` table if it does not exist.
+    pub async fn open(db: Pool<Sqlite>) -> Result<Self, sqlx::Error> {
+        sqlx::query(&create_retention_table().to_string(SqliteQueryBuilder))
+            .execute(&db)
+            .await?;
+
+        Ok(Self {
+            db,
+            listeners: RwLock::new(Vec::new()),
+        })
+    }
+
+    /// Registers `listener` to run before each batch is purged, inside the batch's
+    /// transaction.
+    pub fn before_purge<F>(&self, listener: F)
+    where
+        F: Fn(&PurgeBatch) + Send + Sync + 'static,
+    {
+        self.listeners.write().unwrap().push(Box::new(listener));
+    }
+
+    /// Returns the rule of `table`, if it has one.
+    pub async fn get(&self, table: &str) -> Result<Option<RetentionRule>, sqlx::Error> {
+        let row: Option<RetentionRow> = sqlx::query_as(
+            "SELECT table_name, timestamp_column, timestamp_format, max_age_days, action, \
+             batch_size, enabled FROM _retention WHERE table_name = ?",
+        )
+        .bind(table)
+        .fetch_optional(&self.db)
+        .await?;
+
+        row.map(into_rule).transpose()
+    }
+
+    /// Returns every rule, ordered by table.
+    pub async fn list(&self) -> Result<Vec<RetentionRule>, sqlx::Error> {
+        let rows: Vec<RetentionRow> = sqlx::query_as(
+            "SELECT table_name, timestamp_column, timestamp_format, max_age_days, action, \
+             batch_size, enabled FROM _retention ORDER BY table_name",
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        rows.into_iter().map(into_rule).collect()
+    }
+
+    /// Stores `rule`, replacing the previous rule of its table.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`StoreError::Invalid`] if the table or its timestamp column doesn't
+    /// exist, the batch size is zero, or the rule would archive a table into itself.
+    pub async fn set(&self, rule: RetentionRule) -> Result<RetentionRule, StoreError> {
+        let columns = table_columns(&self.db, &rule.table_name).await?;
+        if columns.is_empty() {
+            return Err(invalid(format!(
+                "table {:?} does not exist",
+                rule.table_name
+            )));
+        }
+        if !columns.contains(&rule.timestamp_column) {
+            return Err(invalid(format!(
+                "table {:?} has no column {:?}",
+                rule.table_name, rule.timestamp_column
+            )));
+        }
+        if rule.batch_size == 0 {
+            return Err(invalid("batch_size must be at least 1".to_string()));
+        }
+        if let RetentionAction::Archive { into } = &rule.action
+            && *into == rule.table_name
+        {
+            return Err(invalid(
+                "a table cannot be archived into itself".to_string(),
+            ));
+        }
+
+        sqlx::query(
+            "INSERT INTO _retention (table_name, timestamp_column, timestamp_format, \
+             max_age_days, action, batch_size, enabled) VALUES (?, ?, ?, ?, ?, ?, ?) \
+             ON CONFLICT (table_name) DO UPDATE SET timestamp_column = excluded.timestamp_column, \
+             timestamp_format = excluded.timestamp_format, max_age_days = excluded.max_age_days, \
+             action = excluded.action, batch_size = excluded.batch_size, enabled = excluded.enabled",
+        )
+        .bind(&rule.table_name)
+        .bind(&rule.timestamp_column)
+        .bind(rule.timestamp_format.as_str())
+        .bind(rule.max_age_days)
+        .bind(Json(&rule.action))
+        .bind(rule.batch_size)
+        .bind(rule.enabled)
+        .execute(&self.db)
+        .await?;
+
+        Ok(rule)
+    }
+
+    /// Removes the rule of `table`, returning whether it had one.
+    pub async fn remove(&self, table: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM _retention WHERE table_name = ?")
+            .bind(table)
+            .execute(&self.db)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Applies every enabled rule as of `now`. A failing rule is logged and skipped so
+    /// it doesn't hold back the others.
+    pub async fn run(&self, now: DateTime<Utc>) -> Result<Vec<PurgeReport>, sqlx::Error> {
+        let mut reports = Vec::new();
+        for rule in self.list().await?.into_iter().filter(|rule| rule.enabled) {
+            match self.apply(&rule, now).await {
+                Ok(report) => reports.push(report),
+                Err(err) => {
+                    tracing::warn!(table = %rule.table_name, error = %err, "retention rule failed")
+                }
+            }
+        }
+        Ok(reports)
+    }
+
+    /// Purges the rows `rule` considers expired at `now`, batch by batch.
+    pub async fn apply(
+        &self,
+        rule: &RetentionRule,
+        now: DateTime<Utc>,
+    ) -> Result<PurgeReport, sqlx::Error> {
+        let table = quote_ident(&rule.table_name);
+        let columns = table_columns(&self.db, &rule.table_name).await?;
+        let select = format!(
+            "SELECT rowid, {} FROM {table} WHERE {} ORDER BY rowid LIMIT ?",
+            row_json(&columns),
+            rule.timestamp_format.older_than(&rule.timestamp_column),
+        );
+        let expired = "rowid IN (SELECT value FROM json_each(?))";
+
+        if let RetentionAction::Archive { into } = &rule.action {
+            sqlx::query(&format!(
+                "CREATE TABLE IF NOT EXISTS {} AS SELECT * FROM {table} WHERE 0",
+                quote_ident(into)
+            ))
+            .execute(&self.db)
+            .await?;
+        }
+
+        let cutoff = rule.cutoff(now).timestamp();
+        let mut purged = 0;
+        loop {
+            let mut tx = self.db.begin().await?;
+            let rows: Vec<(i64, Json<serde_json::Value>)> = sqlx::query_as(&select)
+                .bind(cutoff)
+                .bind(rule.batch_size)
+                .fetch_all(&mut *tx)
+                .await?;
+            if rows.is_empty() {
+                break;
+            }
+
+            let (ids, rows): (Vec<_>, Vec<_>) =
+                rows.into_iter().map(|(id, Json(row))| (id, row)).unzip();
+            let batch = PurgeBatch {
+                table_name: rule.table_name.clone(),
+                action: rule.action.clone(),
+                rows,
+            };
+            for listener in self.listeners.read().unwrap().iter() {
+                listener(&batch);
+            }
+
+            let ids = Json(&ids);
+            if let RetentionAction::Archive { into } = &rule.action {
+                sqlx::query(&format!(
+                    "INSERT INTO {} SELECT * FROM {table} WHERE {expired}",
+                    quote_ident(into)
+                ))
+                .bind(ids)
+                .execute(&mut *tx)
+                .await?;
+            }
+            let result = sqlx::query(&format!("DELETE FROM {table} WHERE {expired}"))
+                .bind(ids)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+
+            purged += result.rows_affected();
+            if batch.rows.len() < rule.batch_size as usize {
+                break;
+            }
+        }
+
+        Ok(PurgeReport {
+            table_name: rule.table_name.clone(),
+            purged,
+        })
+    }
+}
+
+fn into_rule(
+    (table_name, timestamp_column, format, max_age_days, Json(action), batch_size, enabled): RetentionRow,
+) -> Result<RetentionRule, sqlx::Error> {
+    Ok(RetentionRule {
+        table_name,
+        timestamp_column,
+        timestamp_format: TimestampFormat::parse(&format)?,
+        max_age_days,
+        action,
+        batch_size,
+        enabled,
+    })
+}
+
+fn invalid(message: String) -> StoreError {
+    StoreError::Invalid(message)
+}
+
+/// Applies the retention rules every `interval`.
+///
+/// Failures are logged and retried on the next tick.
+pub fn spawn_retention(retention: Arc<Retention>, interval: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+
+        loop {
+            ticker.tick().await;
+
+            match retention.run(Utc::now()).await {
+                Ok(reports) => {
+                    for report in reports.iter().filter(|report| report.purged > 0) {
+                        tracing::debug!(
+                            table = %report.table_name,
+                            purged = report.purged,
+                            "retention rule applied"
+                        );
+                    }
+                }
+                Err(err) => tracing::warn!(error = %err, "retention run failed"),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::sync::Mutex;
+
+    async fn create_logs(db: &Pool<Sqlite>) -> sqlx::Result<()> {
+        sqlx::query("CREATE TABLE logs (id INTEGER PRIMARY KEY, message TEXT, created TEXT)")
+            .execute(db)
+            .await?;
+        for (message, created) in [
+            ("old", "2025-01-01 00:00:00"),
+            ("older", "2024-12-01T08:30:00Z"),
+            ("recent", "2025-06-01 00:00:00"),
+            ("undated", ""),
+        ] {
+            sqlx::query("INSERT INTO logs (message, created) VALUES (?, NULLIF(?, ''))")
+                .bind(message)
+                .bind(created)
+                .execute(db)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn messages(db: &Pool<Sqlite>, table: &str) -> sqlx::Result<Vec<String>> {
+        sqlx::query_scalar(&format!("SELECT message FROM {table} ORDER BY id"))
+            .fetch_all(db)
+            .await
+    }
+
+    fn now() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2025, 6, 15, 0, 0, 0).unwrap()
+    }
+
+    #[sqlx::test]
+    async fn test_delete_in_batches(db: Pool<Sqlite>) -> sqlx::Result<()> {
+        create_logs(&db).await?;
+        let retention = Retention::open(db.clone()).await?;
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let seen = batches.clone();
+        retention.before_purge(move |batch| seen.lock().unwrap().push(batch.rows.clone()));
+
+        let rule = RetentionRule::new("logs", "created", 30).with_batch_size(1);
+        let report = retention.apply(&rule, now()).await?;
+
+        assert_eq!(report.purged, 2);
+        assert_eq!(messages(&db, "logs").await?, ["recent", "undated"]);
+        let batches = batches.lock().unwrap();
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0][0]["message"], "old");
+        assert_eq!(batches[1][0]["message"], "older");
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_archive(db: Pool<Sqlite>) -> sqlx::Result<()> {
+        create_logs(&db).await?;
+        let retention = Retention::open(db.clone()).await?;
+
+        let rule =
+            RetentionRule::new("logs", "created", 30).with_action(RetentionAction::Archive {
+                into: "logs_archive".to_string(),
+            });
+        assert_eq!(retention.apply(&rule, now()).await?.purged, 2);
+        assert_eq!(messages(&db, "logs_archive").await?, ["old", "older"]);
+        assert_eq!(messages(&db, "logs").await?, ["recent", "undated"]);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_unix_timestamps(db: Pool<Sqlite>) -> sqlx::Result<()> {
+        sqlx::query("CREATE TABLE events (id INTEGER PRIMARY KEY, message TEXT, at INTEGER)")
+            .execute(&db)
+            .await?;
+        let cutoff = now() - chrono::Duration::days(7);
+        for (message, at) in [
+            ("old", cutoff - chrono::Duration::seconds(1)),
+            ("new", cutoff),
+        ] {
+            sqlx::query("INSERT INTO events (message, at) VALUES (?, ?)")
+                .bind(message)
+                .bind(at.timestamp_millis())
+                .execute(&db)
+                .await?;
+        }
+        let retention = Retention::open(db.clone()).await?;
+
+        let rule = RetentionRule::new("events", "at", 7).with_format(TimestampFormat::UnixMillis);
+        assert_eq!(retention.apply(&rule, now()).await?.purged, 1);
+        assert_eq!(messages(&db, "events").await?, ["new"]);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_rules(db: Pool<Sqlite>) -> Result<(), StoreError> {
+        create_logs(&db).await?;
+        let retention = Retention::open(db.clone()).await?;
+
+        let rule = RetentionRule::new("logs", "created", 30);
+        retention.set(rule.clone()).await?;
+        retention
+            .set(RetentionRule::new("logs", "created", 90).with_enabled(false))
+            .await?;
+        assert_eq!(retention.list().await?.len(), 1);
+        assert!(!retention.get("logs").await?.unwrap().enabled);
+        assert!(retention.run(now()).await?.is_empty());
+
+        retention.set(rule).await?;
+        let reports = retention.run(now()).await?;
+        assert_eq!(reports[0].purged, 2);
+
+        for invalid in [
+            RetentionRule::new("missing", "created", 30),
+            RetentionRule::new("logs", "missing", 30),
+            RetentionRule::new("logs", "created", 30).with_batch_size(0),
+        ] {
+            assert!(matches!(
+                retention.set(invalid).await,
+                Err(StoreError::Invalid(_))
+            ));
+        }
+
+        assert!(retention.remove("logs").await?);
+        assert!(!retention.remove("logs").await?);
+        Ok(())
+    }
+}
@@ -0,0 +1,875 @@
+//! # Saved queries
+//!
+//! Users can save a named combination of filter, sort and fields for a table, such as
+//! `my-open-tickets`, and recall it later by name with `?view=my-open-tickets`. Saved
+//! queries live in the SQLite `_saved_queries` table, managed by the [`SavedQueries`]
+//! store and the routes of [`router`]:
+//!
+//! - `GET /saved-queries/{table}` lists the queries the caller can use on a table.
+//! - `GET /saved-queries/{table}/{name}` returns one of them.
+//! - `PUT /saved-queries/{table}/{name}` saves one of the caller's queries.
+//! - `DELETE /saved-queries/{table}/{name}` removes one of the caller's queries.
+//! - `GET /records/{table}` lists the records of a table, or with `?view={name}` the
+//!   records of a saved query. The response carries the [`QueryCost`] of the query for
+//!   [`limit_query_cost`](crate::query_cost::limit_query_cost).
+//!
+//! A query's [`Sharing`] decides who else may use it: nobody, callers with a given
+//! role, or everybody. Names are unique per owner, so when several visible queries
+//! share a name the caller's own query wins, then role shared ones, then public ones.
+//!
+//! The routes identify the caller through the [`SessionContext`] extension and answer
+//! `401 Unauthorized` without a user. Other list routes can accept [`ViewParams`] too
+//! and hand the view to [`SavedQueries::resolve`].
+//!
+//! Saved queries read records like the record routes do: internal tables, whose names
+//! start with `_`, and tables disabled in the [`Exposures`] given with
+//! [`SavedQueries::with_exposures`] can't be queried, and a run only returns the records
+//! the caller's `select` policies of `_policies` let them see, as in
+//! [`crate::embedded`].
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # async fn run(db: sqlx::Pool<sqlx::Sqlite>) -> Result<(), sqlx::Error> {
+//! use std::sync::Arc;
+//!
+//! use axum::Extension;
+//! use palmera_database::saved_queries::{self, SavedQueries};
+//!
+//! let saved = Arc::new(SavedQueries::open(db).await?);
+//! let (router, _api) = saved_queries::router()
+//!     .layer(Extension(saved))
+//!     .split_for_parts();
+//! # Ok(())
+//! # }
+//! ```
+
+use std::sync::Arc;
+
+use axum::{
+    Extension, Json,
+    extract::{Path, Query},
+    http::StatusCode,
+};
+use sea_query::SqliteQueryBuilder;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite, types::Json as SqlJson};
+use utoipa::{IntoParams, ToSchema};
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+use crate::{
+    embedded::{self, AUTH_CTE},
+    error::StoreError,
+    explain::{ExplainRequest, explain_as},
+    exposure::Exposures,
+    index_advisor::QueryUsage,
+    postgres::{helpers::quote_ident, session::SessionContext},
+    query_cost::QueryCost,
+    sqlite::helpers::{create_policy_table, create_saved_queries_table, row_json, table_columns},
+};
+
+/// Who besides its owner may use a saved query.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Sharing {
+    #[default]
+    Private,
+    /// Callers whose session runs as `role`.
+    Role {
+        role: String,
+    },
+    Public,
+}
+
+/// A named filter, sort and field selection for a table.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct SavedQuery {
+    pub name: String,
+    pub table_name: String,
+    pub owner_id: String,
+    /// Column values records must match, e.g. `{"status": "open"}`. `null` matches
+    /// records without a value.
+    #[schema(value_type = Object)]
+    pub filter: serde_json::Map<String, serde_json::Value>,
+    /// Columns to order by, each prefixed with `-` for descending order.
+    pub sort: Vec<String>,
+    /// Columns to return, every column when empty.
+    pub fields: Vec<String>,
+    pub sharing: Sharing,
+}
+
+/// Recalls a saved query on list routes.
+#[derive(Debug, Clone, Default, Deserialize, IntoParams, PartialEq, Eq)]
+pub struct ViewParams {
+    /// Name of a saved query to apply.
+    pub view: Option<String>,
+}
+
+type SavedQueryRow = (
+    String,
+    String,
+    String,
+    SqlJson<serde_json::Map<String, serde_json::Value>>,
+    SqlJson<Vec<String>>,
+    SqlJson<Vec<String>>,
+    SqlJson<Sharing>,
+);
+
+/// Rank of the queries visible to `?2` (user id) with role `?3` as they shadow each
+/// other, lowest first, or `NULL` if not visible.
+const VISIBILITY: &str = "CASE \
+     WHEN owner_id = ?2 THEN 0 \
+     WHEN json_extract(sharing, '$.type') = 'role' AND json_extract(sharing, '$.role') = ?3 THEN 1 \
+     WHEN json_extract(sharing, '$.type') = 'public' THEN 2 END";
+
+/// Saved queries persisted in SQLite.
+#[derive(Debug, Clone)]
+pub struct SavedQueries {
+    db: Pool<Sqlite>,
+    usage: Option<Arc<QueryUsage>>,
+    exposures: Option<Arc<Exposures>>,
+}
+
+impl SavedQueries {
+    /// Opens the store, creating the `_saved_queries` and `_policies` tables if they do
+    /// not exist.
+    pub async fn open(db: Pool<Sqlite>) -> Result<Self, sqlx::Error> {
+        sqlx::query(&create_saved_queries_table().to_string(SqliteQueryBuilder))
+            .execute(&db)
+            .await?;
+        sqlx::query(&create_policy_table().to_string(SqliteQueryBuilder))
+            .execute(&db)
+            .await?;
+
+        Ok(Self {
+            db,
+            usage: None,
+            exposures: None,
+        })
+    }
+
+    /// Counts the columns runs filter and sort on in `usage`, for the index advisor.
+    pub fn with_usage(mut self, usage: Arc<QueryUsage>) -> Self {
+        self.usage = Some(usage);
+        self
+    }
+
+    /// Refuses queries on the tables `exposures` disables.
+    pub fn with_exposures(mut self, exposures: Arc<Exposures>) -> Self {
+        self.exposures = Some(exposures);
+        self
+    }
+
+    /// Returns the queries on `table` visible to `viewer`, ordered by name. Of queries
+    /// sharing a name, only the one taking precedence is listed.
+    pub async fn list(
+        &self,
+        viewer: &SessionContext,
+        table: &str,
+    ) -> Result<Vec<SavedQuery>, sqlx::Error> {
+        let rows: Vec<SavedQueryRow> = sqlx::query_as(&format!(
+            "SELECT name, table_name, owner_id, filter, sort, fields, sharing FROM ( \
+                 SELECT *, row_number() OVER (PARTITION BY name ORDER BY {VISIBILITY}, owner_id) AS rank \
+                 FROM _saved_queries WHERE table_name = ?1 AND {VISIBILITY} IS NOT NULL) \
+             WHERE rank = 1 ORDER BY name"
+        ))
+        .bind(table)
+        .bind(viewer.user_id.as_deref())
+        .bind(&viewer.role)
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(rows.into_iter().map(into_query).collect())
+    }
+
+    /// Returns the query named `name` on `table` taking precedence for `viewer`.
+    pub async fn get(
+        &self,
+        viewer: &SessionContext,
+        table: &str,
+        name: &str,
+    ) -> Result<Option<SavedQuery>, sqlx::Error> {
+        let row: Option<SavedQueryRow> = sqlx::query_as(&format!(
+            "SELECT name, table_name, owner_id, filter, sort, fields, sharing \
+             FROM _saved_queries WHERE table_name = ?1 AND name = ?4 AND {VISIBILITY} IS NOT NULL \
+             ORDER BY {VISIBILITY}, owner_id LIMIT 1"
+        ))
+        .bind(table)
+        .bind(viewer.user_id.as_deref())
+        .bind(&viewer.role)
+        .bind(name)
+        .fetch_optional(&self.db)
+        .await?;
+
+        Ok(row.map(into_query))
+    }
+
+    /// Returns the query requested by `params`, if any.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`sqlx::Error::RowNotFound`] if the view names no query visible to
+    /// `viewer`.
+    pub async fn resolve(
+        &self,
+        viewer: &SessionContext,
+        table: &str,
+        params: &ViewParams,
+    ) -> Result<Option<SavedQuery>, sqlx::Error> {
+        match &params.view {
+            Some(view) => self
+                .get(viewer, table, view)
+                .await?
+                .map(Some)
+                .ok_or(sqlx::Error::RowNotFound),
+            None => Ok(None),
+        }
+    }
+
+    /// Stores `query`, replacing the owner's query of the same name.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`StoreError::NotFound`] if the table is internal or not exposed, and
+    /// [`StoreError::Invalid`] if the name is empty, the table doesn't exist or the query
+    /// references a column the table doesn't have.
+    pub async fn save(&self, query: SavedQuery) -> Result<SavedQuery, StoreError> {
+        if query.name.trim().is_empty() {
+            return Err(invalid("name must not be empty".to_string()));
+        }
+        self.check_table(&query.table_name)?;
+        check_columns(
+            &self.db,
+            &query.table_name,
+            &query.filter,
+            &query.sort,
+            &query.fields,
+        )
+        .await?;
+
+        sqlx::query(
+            "INSERT INTO _saved_queries (owner_id, table_name, name, filter, sort, fields, sharing) \
+             VALUES (?, ?, ?, ?, ?, ?, ?) \
+             ON CONFLICT (owner_id, table_name, name) DO UPDATE SET filter = excluded.filter, \
+             sort = excluded.sort, fields = excluded.fields, sharing = excluded.sharing",
+        )
+        .bind(&query.owner_id)
+        .bind(&query.table_name)
+        .bind(&query.name)
+        .bind(SqlJson(&query.filter))
+        .bind(SqlJson(&query.sort))
+        .bind(SqlJson(&query.fields))
+        .bind(SqlJson(&query.sharing))
+        .execute(&self.db)
+        .await?;
+
+        Ok(query)
+    }
+
+    /// Removes the query `name` of `owner_id` on `table`, returning whether it existed.
+    pub async fn remove(
+        &self,
+        owner_id: &str,
+        table: &str,
+        name: &str,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            "DELETE FROM _saved_queries WHERE owner_id = ? AND table_name = ? AND name = ?",
+        )
+        .bind(owner_id)
+        .bind(table)
+        .bind(name)
+        .execute(&self.db)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Returns the records of the query's table visible to `viewer` and matching its
+    /// filter, in its order and with its fields.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`StoreError::NotFound`] if the table is internal or not exposed.
+    pub async fn run(
+        &self,
+        viewer: &SessionContext,
+        query: &SavedQuery,
+    ) -> Result<Vec<serde_json::Value>, StoreError> {
+        self.check_table(&query.table_name)?;
+        let fields = if query.fields.is_empty() {
+            table_columns(&self.db, &query.table_name).await?
+        } else {
+            query.fields.clone()
+        };
+        let mut conn = self.db.acquire().await?;
+        let visible = embedded::using(&mut conn, &query.table_name, "select").await?;
+        let sql = select_sql(
+            &query.table_name,
+            &fields,
+            &query.filter,
+            &query.sort,
+            &visible,
+        );
+
+        let rows: Vec<SqlJson<serde_json::Value>> = sqlx::query_scalar(&sql)
+            .bind(viewer.user_id.as_deref())
+            .bind(SqlJson(&query.filter))
+            .fetch_all(&mut *conn)
+            .await?;
+        drop(conn);
+
+        if let Some(usage) = &self.usage {
+            let filtered = query.filter.keys().map(String::as_str);
+            let sorted = query.sort.iter().map(String::as_str);
+            if let Err(err) = usage.record(&query.table_name, filtered, sorted).await {
+                tracing::warn!(%err, "failed to record query usage");
+            }
+        }
+
+        Ok(rows.into_iter().map(|row| row.0).collect())
+    }
+
+    /// Estimates the cost of running `query` for `viewer`.
+    pub async fn cost(
+        &self,
+        viewer: &SessionContext,
+        query: &SavedQuery,
+    ) -> Result<QueryCost, StoreError> {
+        self.check_table(&query.table_name)?;
+        let request = ExplainRequest {
+            table: query.table_name.clone(),
+            filter: query.filter.clone(),
+            sort: query.sort.clone(),
+            fields: query.fields.clone(),
+            analyze: false,
+        };
+        let visible = {
+            let mut conn = self.db.acquire().await?;
+            embedded::using(&mut conn, &query.table_name, "select").await?
+        };
+        let explanation =
+            explain_as(&self.db, &request, viewer.user_id.as_deref(), &visible).await?;
+        Ok(explanation.cost)
+    }
+
+    /// Fails with [`StoreError::NotFound`] for internal tables and tables the exposure
+    /// settings disable.
+    fn check_table(&self, table: &str) -> Result<(), StoreError> {
+        let disabled = self
+            .exposures
+            .as_ref()
+            .and_then(|exposures| exposures.get(table))
+            .is_some_and(|exposure| !exposure.enabled);
+        if table.starts_with('_') || disabled {
+            return Err(StoreError::NotFound(format!(
+                "table {table:?} does not exist"
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Checks that `table` exists and has every column a filter, sort and field selection
+/// reference, returning its columns.
+///
+/// # Errors
+///
+/// Returns [`StoreError::Invalid`] naming the missing table or column.
+pub(crate) async fn check_columns(
+    db: &Pool<Sqlite>,
+    table: &str,
+    filter: &serde_json::Map<String, serde_json::Value>,
+    sort: &[String],
+    fields: &[String],
+) -> Result<Vec<String>, StoreError> {
+    let columns = table_columns(db, table).await?;
+    if columns.is_empty() {
+        return Err(invalid(format!("table {table:?} does not exist")));
+    }
+    let referenced = filter
+        .keys()
+        .chain(fields)
+        .map(String::as_str)
+        .chain(sort.iter().map(|column| sort_column(column).0));
+    for column in referenced {
+        if !columns.iter().any(|existing| existing == column) {
+            return Err(invalid(format!("table {table:?} has no column {column:?}")));
+        }
+    }
+
+    Ok(columns)
+}
+
+/// The statement listing `fields` of the records of `table` matching `filter` and the
+/// `visible` policy condition, in the order of `sort`. The caller's id is bound to `?1`
+/// as in [`AUTH_CTE`], the filter to `?2` as JSON.
+pub(crate) fn select_sql(
+    table: &str,
+    fields: &[String],
+    filter: &serde_json::Map<String, serde_json::Value>,
+    sort: &[String],
+    visible: &str,
+) -> String {
+    let mut sql = format!(
+        "{AUTH_CTE}SELECT {} FROM {}",
+        row_json(fields),
+        quote_ident(table)
+    );
+    let conditions = (visible != "1")
+        .then(|| visible.to_string())
+        .into_iter()
+        .chain(filter.keys().map(|column| {
+            format!(
+                "{} IS json_extract(?2, '$.\"{}\"')",
+                quote_ident(column),
+                column.replace('\'', "''").replace('"', "\\\"")
+            )
+        }))
+        .collect::<Vec<_>>();
+    if !conditions.is_empty() {
+        sql.push_str(&format!(" WHERE {}", conditions.join(" AND ")));
+    }
+    if !sort.is_empty() {
+        let order = sort
+            .iter()
+            .map(|column| {
+                let (column, descending) = sort_column(column);
+                let direction = if descending { "DESC" } else { "ASC" };
+                format!("{} {direction}", quote_ident(column))
+            })
+            .collect::<Vec<_>>();
+        sql.push_str(&format!(" ORDER BY {}", order.join(", ")));
+    }
+    sql
+}
+
+/// Splits a sort entry into its column and whether it sorts descending.
+fn sort_column(entry: &str) -> (&str, bool) {
+    match entry.strip_prefix('-') {
+        Some(column) => (column, true),
+        None => (entry, false),
+    }
+}
+
+fn into_query(
+    (name, table_name, owner_id, filter, sort, fields, sharing): SavedQueryRow,
+) -> SavedQuery {
+    SavedQuery {
+        name,
+        table_name,
+        owner_id,
+        filter: filter.0,
+        sort: sort.0,
+        fields: fields.0,
+        sharing: sharing.0,
+    }
+}
+
+fn invalid(message: String) -> StoreError {
+    StoreError::Invalid(message)
+}
+
+/// The caller's session, or `401 Unauthorized` without an authenticated user.
+fn viewer(
+    session: Option<Extension<SessionContext>>,
+) -> Result<(SessionContext, String), StatusCode> {
+    let Extension(ctx) = session.ok_or(StatusCode::UNAUTHORIZED)?;
+    let user_id = ctx.user_id.clone().ok_or(StatusCode::UNAUTHORIZED)?;
+    Ok((ctx, user_id))
+}
+
+/// Lists the saved queries of a table available to the caller.
+#[utoipa::path(
+    get,
+    path = "/saved-queries/{table}",
+    responses((status = 200, body = Vec<SavedQuery>), (status = 401))
+)]
+async fn list_saved_queries(
+    Extension(saved): Extension<Arc<SavedQueries>>,
+    session: Option<Extension<SessionContext>>,
+    Path(table): Path<String>,
+) -> Result<Json<Vec<SavedQuery>>, StatusCode> {
+    let (ctx, _) = viewer(session)?;
+    saved
+        .list(&ctx, &table)
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Returns a saved query available to the caller.
+#[utoipa::path(
+    get,
+    path = "/saved-queries/{table}/{name}",
+    responses((status = 200, body = SavedQuery), (status = 401), (status = 404))
+)]
+async fn get_saved_query(
+    Extension(saved): Extension<Arc<SavedQueries>>,
+    session: Option<Extension<SessionContext>>,
+    Path((table, name)): Path<(String, String)>,
+) -> Result<Json<SavedQuery>, StatusCode> {
+    let (ctx, _) = viewer(session)?;
+    saved
+        .get(&ctx, &table, &name)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+#[derive(Debug, Default, Deserialize, ToSchema)]
+struct SavedQuerySettings {
+    #[serde(default)]
+    #[schema(value_type = Object)]
+    filter: serde_json::Map<String, serde_json::Value>,
+    #[serde(default)]
+    sort: Vec<String>,
+    #[serde(default)]
+    fields: Vec<String>,
+    #[serde(default)]
+    sharing: Sharing,
+}
+
+/// Saves one of the caller's queries, replacing the previous one of that name.
+#[utoipa::path(
+    put,
+    path = "/saved-queries/{table}/{name}",
+    request_body = SavedQuerySettings,
+    responses((status = 200, body = SavedQuery), (status = 400), (status = 401))
+)]
+async fn put_saved_query(
+    Extension(saved): Extension<Arc<SavedQueries>>,
+    session: Option<Extension<SessionContext>>,
+    Path((table, name)): Path<(String, String)>,
+    Json(settings): Json<SavedQuerySettings>,
+) -> Result<Json<SavedQuery>, StatusCode> {
+    let (_, user_id) = viewer(session)?;
+    let query = SavedQuery {
+        name,
+        table_name: table,
+        owner_id: user_id,
+        filter: settings.filter,
+        sort: settings.sort,
+        fields: settings.fields,
+        sharing: settings.sharing,
+    };
+
+    saved
+        .save(query)
+        .await
+        .map(Json)
+        .map_err(|err| err.status())
+}
+
+/// Removes one of the caller's queries.
+#[utoipa::path(
+    delete,
+    path = "/saved-queries/{table}/{name}",
+    responses((status = 204), (status = 401), (status = 404))
+)]
+async fn delete_saved_query(
+    Extension(saved): Extension<Arc<SavedQueries>>,
+    session: Option<Extension<SessionContext>>,
+    Path((table, name)): Path<(String, String)>,
+) -> StatusCode {
+    let user_id = match viewer(session) {
+        Ok((_, user_id)) => user_id,
+        Err(status) => return status,
+    };
+    match saved.remove(&user_id, &table, &name).await {
+        Ok(true) => StatusCode::NO_CONTENT,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Lists the records of a table, or those of a saved query with a view, e.g.
+/// `GET /records/tickets?view=my-open-tickets`.
+#[utoipa::path(
+    get,
+    path = "/records/{table}",
+    params(ViewParams),
+    responses((status = 200, body = Vec<Object>), (status = 400), (status = 401), (status = 404))
+)]
+async fn run_saved_query(
+    Extension(saved): Extension<Arc<SavedQueries>>,
+    session: Option<Extension<SessionContext>>,
+    Path(table): Path<String>,
+    Query(params): Query<ViewParams>,
+) -> Result<(Extension<QueryCost>, Json<Vec<serde_json::Value>>), StatusCode> {
+    let (ctx, user_id) = viewer(session)?;
+    let query = saved
+        .resolve(&ctx, &table, &params)
+        .await
+        .map_err(|err| match err {
+            sqlx::Error::RowNotFound => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        })?
+        .unwrap_or_else(|| SavedQuery {
+            name: String::new(),
+            table_name: table.clone(),
+            owner_id: user_id,
+            filter: serde_json::Map::new(),
+            sort: Vec::new(),
+            fields: Vec::new(),
+            sharing: Sharing::Private,
+        });
+
+    let failed = |err: StoreError| {
+        if let StoreError::Database(err) = &err {
+            tracing::error!(%err, table, view = query.name, "saved query failed");
+        }
+        err.status()
+    };
+    let cost = saved.cost(&ctx, &query).await.map_err(failed)?;
+    let records = saved.run(&ctx, &query).await.map_err(failed)?;
+    Ok((Extension(cost), Json(records)))
+}
+
+pub fn router() -> OpenApiRouter {
+    OpenApiRouter::new()
+        .routes(routes!(list_saved_queries))
+        .routes(routes!(run_saved_query))
+        .routes(routes!(
+            get_saved_query,
+            put_saved_query,
+            delete_saved_query
+        ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exposure::TableExposure;
+    use serde_json::json;
+
+    async fn setup(db: &Pool<Sqlite>) -> sqlx::Result<SavedQueries> {
+        sqlx::query(
+            "CREATE TABLE tickets (id INTEGER PRIMARY KEY, title TEXT, status TEXT, assignee TEXT)",
+        )
+        .execute(db)
+        .await?;
+        sqlx::query(
+            "INSERT INTO tickets (title, status, assignee) VALUES \
+             ('a', 'open', 'ann'), ('b', 'closed', 'ann'), ('c', 'open', 'bob'), ('d', 'open', 'ann')",
+        )
+        .execute(db)
+        .await?;
+        SavedQueries::open(db.clone()).await
+    }
+
+    fn query(owner: &str, name: &str, sharing: Sharing) -> SavedQuery {
+        SavedQuery {
+            name: name.to_string(),
+            table_name: "tickets".to_string(),
+            owner_id: owner.to_string(),
+            filter: json!({ "status": "open", "assignee": "ann" })
+                .as_object()
+                .unwrap()
+                .clone(),
+            sort: vec!["-title".to_string()],
+            fields: vec!["id".to_string(), "title".to_string()],
+            sharing,
+        }
+    }
+
+    #[sqlx::test]
+    async fn test_sharing(db: Pool<Sqlite>) -> Result<(), StoreError> {
+        let saved = setup(&db).await?;
+        saved.save(query("ann", "mine", Sharing::Private)).await?;
+        saved
+            .save(query(
+                "ann",
+                "team",
+                Sharing::Role {
+                    role: "support".to_string(),
+                },
+            ))
+            .await?;
+        saved
+            .save(query("ann", "everyone", Sharing::Public))
+            .await?;
+        let mut own = query("bob", "everyone", Sharing::Private);
+        own.sort = vec!["title".to_string()];
+        saved.save(own.clone()).await?;
+
+        let names = |queries: Vec<SavedQuery>| {
+            queries
+                .into_iter()
+                .map(|query| format!("{}/{}", query.owner_id, query.name))
+                .collect::<Vec<_>>()
+        };
+        let ann = SessionContext::new("authenticated", "ann");
+        assert_eq!(
+            names(saved.list(&ann, "tickets").await?),
+            ["ann/everyone", "ann/mine", "ann/team"]
+        );
+        let bob = SessionContext::new("support", "bob");
+        assert_eq!(
+            names(saved.list(&bob, "tickets").await?),
+            ["bob/everyone", "ann/team"]
+        );
+        assert_eq!(saved.get(&bob, "tickets", "everyone").await?, Some(own));
+        let carl = SessionContext::new("authenticated", "carl");
+        assert_eq!(names(saved.list(&carl, "tickets").await?), ["ann/everyone"]);
+        assert!(saved.get(&carl, "tickets", "mine").await?.is_none());
+
+        let view = ViewParams {
+            view: Some("team".to_string()),
+        };
+        assert!(matches!(
+            saved.resolve(&carl, "tickets", &view).await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+        let team = saved.resolve(&bob, "tickets", &view).await?.unwrap();
+        assert_eq!(
+            saved.run(&bob, &team).await?,
+            [
+                json!({ "id": 4, "title": "d" }),
+                json!({ "id": 1, "title": "a" })
+            ]
+        );
+
+        assert!(saved.remove("ann", "tickets", "mine").await?);
+        assert!(!saved.remove("bob", "tickets", "team").await?);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_policies_and_exposure(db: Pool<Sqlite>) -> Result<(), StoreError> {
+        let exposures = Arc::new(Exposures::open(db.clone()).await?);
+        let saved = setup(&db).await?.with_exposures(exposures.clone());
+        sqlx::query(
+            "INSERT INTO _policies (name, table_name, operation, using_expr) \
+             VALUES ('assigned', 'tickets', 'select', 'assignee = (SELECT user_id FROM _auth)')",
+        )
+        .execute(&db)
+        .await?;
+        let everything = SavedQuery {
+            filter: Default::default(),
+            sort: vec!["id".to_string()],
+            fields: vec!["title".to_string()],
+            ..query("ann", "all", Sharing::Public)
+        };
+        saved.save(everything.clone()).await?;
+
+        // Runs only return the records the caller's policies let them see.
+        let bob = SessionContext::new("authenticated", "bob");
+        assert_eq!(
+            saved.run(&bob, &everything).await?,
+            [json!({ "title": "c" })]
+        );
+        let anonymous = SessionContext::anonymous("anon");
+        assert!(saved.run(&anonymous, &everything).await?.is_empty());
+        assert_eq!(saved.cost(&bob, &everything).await?.rows_scanned, 4);
+
+        // Internal and disabled tables can't be queried.
+        let internal = SavedQuery {
+            table_name: "_policies".to_string(),
+            fields: vec!["using_expr".to_string()],
+            sort: Vec::new(),
+            ..everything.clone()
+        };
+        assert!(matches!(
+            saved.save(internal).await,
+            Err(StoreError::NotFound(_))
+        ));
+        exposures
+            .set(TableExposure::new("tickets").with_enabled(false))
+            .await?;
+        assert!(matches!(
+            saved.save(everything.clone()).await,
+            Err(StoreError::NotFound(_))
+        ));
+        assert!(matches!(
+            saved.run(&bob, &everything).await,
+            Err(StoreError::NotFound(_))
+        ));
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_routes(db: Pool<Sqlite>) -> Result<(), StoreError> {
+        let saved = Arc::new(setup(&db).await?);
+        let session = || Some(Extension(SessionContext::new("authenticated", "ann")));
+        let path = |name: &str| Path(("tickets".to_string(), name.to_string()));
+        let settings = |column: &str| SavedQuerySettings {
+            sort: vec![format!("-{column}")],
+            ..Default::default()
+        };
+
+        let Json(query) = put_saved_query(
+            Extension(saved.clone()),
+            session(),
+            path("latest"),
+            Json(settings("id")),
+        )
+        .await
+        .unwrap();
+        assert_eq!(query.owner_id, "ann");
+        let invalid = put_saved_query(
+            Extension(saved.clone()),
+            session(),
+            path("broken"),
+            Json(settings("missing")),
+        )
+        .await;
+        assert_eq!(invalid.unwrap_err(), StatusCode::BAD_REQUEST);
+        let anonymous = put_saved_query(
+            Extension(saved.clone()),
+            Some(Extension(SessionContext::anonymous("anon"))),
+            path("latest"),
+            Json(settings("id")),
+        )
+        .await;
+        assert_eq!(anonymous.unwrap_err(), StatusCode::UNAUTHORIZED);
+
+        let (Extension(cost), Json(records)) = run_saved_query(
+            Extension(saved.clone()),
+            session(),
+            Path("tickets".to_string()),
+            Query(ViewParams {
+                view: Some("latest".to_string()),
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[0]["title"], "d");
+        // Sorting on the primary key needs no temporary b-tree.
+        assert_eq!((cost.rows_scanned, cost.sorts), (4, 0));
+
+        // Without a view the whole table is listed, and costed.
+        let (Extension(cost), Json(records)) = run_saved_query(
+            Extension(saved.clone()),
+            session(),
+            Path("tickets".to_string()),
+            Query(ViewParams::default()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(records.len(), 4);
+        assert_eq!(cost.rows_scanned, 4);
+
+        let Json(listed) = list_saved_queries(
+            Extension(saved.clone()),
+            session(),
+            Path("tickets".to_string()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(listed, [query]);
+        assert_eq!(
+            get_saved_query(Extension(saved.clone()), session(), path("other"))
+                .await
+                .unwrap_err(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            delete_saved_query(Extension(saved), session(), path("latest")).await,
+            StatusCode::NO_CONTENT
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,353 @@
+//! # Instance settings
+//!
+//! [`Settings`] is a key/value store over the SQLite `_settings` table for options that
+//! operators change at runtime, such as the app name, SMTP credentials or upload limits.
+//! Values are stored as JSON and read back into any deserializable type. Listeners
+//! registered with [`Settings::on_change`] run after every committed change, so
+//! components can pick up new values without a restart.
+//!
+//! [`is_secret`] tells the keys holding credentials apart by their name.
+//!
+//! The `/admin/settings` routes in [`crate::admin`] expose the store over HTTP.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # async fn run(db: sqlx::Pool<sqlx::Sqlite>) -> Result<(), sqlx::Error> {
+//! use palmera_database::settings::Settings;
+//!
+//! let settings = Settings::open(db).await?;
+//! settings.on_change(|change| tracing::info!(key = %change.key, "setting changed"));
+//!
+//! settings.set("upload_limit", &10_485_760u64).await?;
+//! let limit: u64 = settings.get("upload_limit").await?.unwrap_or(5_242_880);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::sync::RwLock;
+
+use chrono::{DateTime, Utc};
+use futures::{Stream, StreamExt};
+use sea_query::SqliteQueryBuilder;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use sqlx::{Pool, Sqlite, types::Json};
+use utoipa::ToSchema;
+
+use tokio::sync::mpsc;
+
+use crate::{
+    export::{ROW_BUFFER, receive},
+    sqlite::helpers::create_settings_table,
+};
+
+/// Markers of the keys whose values are credentials, see [`is_secret`].
+const SECRET_MARKERS: [&str; 5] = ["password", "secret", "token", "api_key", "private_key"];
+
+/// Returns whether the setting `key` holds a credential, judging by its name: keys
+/// containing `password`, `secret`, `token`, `api_key` or `private_key`, in any case.
+pub fn is_secret(key: &str) -> bool {
+    let key = key.to_ascii_lowercase();
+    SECRET_MARKERS.iter().any(|marker| key.contains(marker))
+}
+
+/// A stored setting.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct Setting {
+    pub key: String,
+    /// The stored value. Write-only for secret keys (see [`is_secret`]): the admin
+    /// routes return `null` in its place, see [`Setting::redacted`].
+    #[schema(value_type = Object)]
+    pub value: serde_json::Value,
+    /// When the setting was last written (UTC).
+    pub updated: DateTime<Utc>,
+}
+
+impl Setting {
+    /// This setting with its value replaced by `null` if its key is a secret.
+    pub fn redacted(mut self) -> Self {
+        if is_secret(&self.key) {
+            self.value = serde_json::Value::Null;
+        }
+        self
+    }
+}
+
+/// A committed change to a setting, passed to [`Settings::on_change`] listeners.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SettingChange {
+    pub key: String,
+    /// Previous value, or `None` if the setting was created.
+    pub old: Option<serde_json::Value>,
+    /// New value, or `None` if the setting was removed.
+    pub new: Option<serde_json::Value>,
+}
+
+type Listener = Box<dyn Fn(&SettingChange) + Send + Sync>;
+
+/// Runtime key/value settings persisted in SQLite.
+pub struct Settings {
+    db: Pool<Sqlite>,
+    listeners: RwLock<Vec<Listener>>,
+}
+
+impl std::fmt::Debug for Settings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Settings")
+            .field("listeners", &self.listeners.read().unwrap().len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Settings {
+    /// Opens the store, creating the `_settings` table if it does not exist.
+    pub async fn open(db: Pool<Sqlite>) -> Result<Self, sqlx::Error> {
+        sqlx::query(&create_settings_table().to_string(SqliteQueryBuilder))
+            .execute(&db)
+            .await?;
+
+        Ok(Self {
+            db,
+            listeners: RwLock::new(Vec::new()),
+        })
+    }
+
+    /// Registers `listener` to run after every change that altered a value.
+    pub fn on_change<F>(&self, listener: F)
+    where
+        F: Fn(&SettingChange) + Send + Sync + 'static,
+    {
+        self.listeners.write().unwrap().push(Box::new(listener));
+    }
+
+    /// Returns the value of `key` decoded as `T`, or `None` if it is not set.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`sqlx::Error::Decode`] if the stored value does not match `T`.
+    pub async fn get<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, sqlx::Error> {
+        self.get_value(key)
+            .await?
+            .map(|value| {
+                serde_json::from_value(value).map_err(|err| sqlx::Error::Decode(err.into()))
+            })
+            .transpose()
+    }
+
+    /// Returns the raw JSON value of `key`.
+    pub async fn get_value(&self, key: &str) -> Result<Option<serde_json::Value>, sqlx::Error> {
+        Ok(self.find(key).await?.map(|setting| setting.value))
+    }
+
+    /// Returns `key` with its value and modification time.
+    pub async fn find(&self, key: &str) -> Result<Option<Setting>, sqlx::Error> {
+        let row: Option<(String, Json<serde_json::Value>, i64)> =
+            sqlx::query_as("SELECT key, value, updated FROM _settings WHERE key = ?")
+                .bind(key)
+                .fetch_optional(&self.db)
+                .await?;
+
+        Ok(row.map(into_setting))
+    }
+
+    /// Returns every setting, ordered by key.
+    pub async fn list(&self) -> Result<Vec<Setting>, sqlx::Error> {
+        let rows: Vec<(String, Json<serde_json::Value>, i64)> =
+            sqlx::query_as("SELECT key, value, updated FROM _settings ORDER BY key")
+                .fetch_all(&self.db)
+                .await?;
+
+        Ok(rows.into_iter().map(into_setting).collect())
+    }
+
+    /// Like [`Self::list`], yielding settings as they are read instead of collecting
+    /// them, e.g. for [`export::rows`](crate::export::rows). The query runs on its own
+    /// task, which stops once the stream is dropped.
+    pub fn stream(&self) -> impl Stream<Item = Result<Setting, sqlx::Error>> + Send + 'static {
+        let db = self.db.clone();
+        let (sender, receiver) = mpsc::channel(ROW_BUFFER);
+
+        tokio::spawn(async move {
+            let mut rows = sqlx::query_as::<_, (String, Json<serde_json::Value>, i64)>(
+                "SELECT key, value, updated FROM _settings ORDER BY key",
+            )
+            .fetch(&db);
+
+            while let Some(row) = rows.next().await {
+                if sender.send(row.map(into_setting)).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        receive(receiver)
+    }
+
+    /// Stores `value` under `key`, replacing any previous value.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`sqlx::Error::Encode`] if `value` cannot be serialized to JSON.
+    pub async fn set<T: Serialize>(&self, key: &str, value: &T) -> Result<Setting, sqlx::Error> {
+        let value = serde_json::to_value(value).map_err(|err| sqlx::Error::Encode(err.into()))?;
+        self.set_value(key, value).await
+    }
+
+    /// Stores the raw JSON `value` under `key`.
+    pub async fn set_value(
+        &self,
+        key: &str,
+        value: serde_json::Value,
+    ) -> Result<Setting, sqlx::Error> {
+        let updated = Utc::now();
+        let mut tx = self.db.begin().await?;
+
+        let old: Option<Json<serde_json::Value>> =
+            sqlx::query_scalar("SELECT value FROM _settings WHERE key = ?")
+                .bind(key)
+                .fetch_optional(&mut *tx)
+                .await?;
+
+        sqlx::query(
+            "INSERT INTO _settings (key, value, updated) VALUES (?, ?, ?) \
+             ON CONFLICT (key) DO UPDATE SET value = excluded.value, updated = excluded.updated",
+        )
+        .bind(key)
+        .bind(Json(&value))
+        .bind(updated.timestamp_millis())
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        let old = old.map(|Json(old)| old);
+        if old.as_ref() != Some(&value) {
+            self.notify(SettingChange {
+                key: key.to_string(),
+                old,
+                new: Some(value.clone()),
+            });
+        }
+
+        Ok(Setting {
+            key: key.to_string(),
+            value,
+            updated,
+        })
+    }
+
+    /// Removes `key`, returning whether it was set.
+    pub async fn remove(&self, key: &str) -> Result<bool, sqlx::Error> {
+        let old: Option<Json<serde_json::Value>> =
+            sqlx::query_scalar("DELETE FROM _settings WHERE key = ? RETURNING value")
+                .bind(key)
+                .fetch_optional(&self.db)
+                .await?;
+
+        let Some(Json(old)) = old else {
+            return Ok(false);
+        };
+
+        self.notify(SettingChange {
+            key: key.to_string(),
+            old: Some(old),
+            new: None,
+        });
+
+        Ok(true)
+    }
+
+    fn notify(&self, change: SettingChange) {
+        for listener in self.listeners.read().unwrap().iter() {
+            listener(&change);
+        }
+    }
+}
+
+fn into_setting((key, Json(value), updated): (String, Json<serde_json::Value>, i64)) -> Setting {
+    Setting {
+        key,
+        value,
+        updated: DateTime::from_timestamp_millis(updated).unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct Smtp {
+        host: String,
+        port: u16,
+    }
+
+    #[sqlx::test]
+    async fn test_typed_get_and_set(db: Pool<Sqlite>) -> sqlx::Result<()> {
+        let settings = Settings::open(db).await?;
+        assert_eq!(settings.get::<String>("app_name").await?, None);
+
+        let smtp = Smtp {
+            host: "smtp.example.com".to_string(),
+            port: 587,
+        };
+        settings.set("smtp", &smtp).await?;
+        settings.set("app_name", &"Palmera").await?;
+
+        assert_eq!(settings.get::<Smtp>("smtp").await?, Some(smtp));
+        assert_eq!(
+            settings.get::<String>("app_name").await?.as_deref(),
+            Some("Palmera")
+        );
+        assert!(matches!(
+            settings.get::<u64>("app_name").await,
+            Err(sqlx::Error::Decode(_))
+        ));
+
+        let keys: Vec<_> = settings.list().await?.into_iter().map(|s| s.key).collect();
+        assert_eq!(keys, ["app_name", "smtp"]);
+
+        assert!(settings.remove("smtp").await?);
+        assert!(!settings.remove("smtp").await?);
+        assert_eq!(settings.get_value("smtp").await?, None);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_change_listeners(db: Pool<Sqlite>) -> sqlx::Result<()> {
+        let settings = Settings::open(db).await?;
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let seen = changes.clone();
+        settings.on_change(move |change| seen.lock().unwrap().push(change.clone()));
+
+        settings.set("upload_limit", &1024).await?;
+        settings.set("upload_limit", &1024).await?;
+        settings.set("upload_limit", &2048).await?;
+        settings.remove("upload_limit").await?;
+
+        let changes = changes.lock().unwrap();
+        assert_eq!(
+            *changes,
+            [
+                SettingChange {
+                    key: "upload_limit".to_string(),
+                    old: None,
+                    new: Some(json!(1024)),
+                },
+                SettingChange {
+                    key: "upload_limit".to_string(),
+                    old: Some(json!(1024)),
+                    new: Some(json!(2048)),
+                },
+                SettingChange {
+                    key: "upload_limit".to_string(),
+                    old: Some(json!(2048)),
+                    new: None,
+                },
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,92 @@
+//! # SQLite change capture
+//!
+//! SQLite reports row changes through a per-connection update hook, so the hook has to
+//! be installed on every pooled connection, typically from
+//! [`SqlitePoolOptions::after_connect`](sqlx::sqlite::SqlitePoolOptions::after_connect).
+//!
+//! The hook only knows the table and rowid of the changed row, and it fires when the
+//! statement runs rather than on commit, so consumers should re-read the row and
+//! tolerate events for changes that were later rolled back.
+
+use sqlx::{
+    SqliteConnection,
+    sqlite::{SqliteOperation, UpdateHookResult},
+};
+
+use crate::cdc::{ChangeEvent, ChangeFeed, ChangeOperation};
+
+/// Installs an update hook on `conn` that publishes every change to `feed`.
+///
+/// Changes to SQLite's own `sqlite_*` tables are ignored.
+pub async fn install_update_hook(
+    conn: &mut SqliteConnection,
+    feed: &ChangeFeed,
+) -> Result<(), sqlx::Error> {
+    let feed = feed.clone();
+    let mut handle = conn.lock_handle().await?;
+
+    handle.set_update_hook(move |change: UpdateHookResult| {
+        if let Some(event) = to_change_event(&change) {
+            feed.publish(event);
+        }
+    });
+
+    Ok(())
+}
+
+fn to_change_event(change: &UpdateHookResult) -> Option<ChangeEvent> {
+    if change.table.starts_with("sqlite_") {
+        return None;
+    }
+
+    let operation = match change.operation {
+        SqliteOperation::Insert => ChangeOperation::Insert,
+        SqliteOperation::Update => ChangeOperation::Update,
+        SqliteOperation::Delete => ChangeOperation::Delete,
+        SqliteOperation::Unknown(_) => return None,
+    };
+
+    Some(ChangeEvent {
+        schema: change.database.to_string(),
+        table: change.table.to_string(),
+        operation,
+        record: None,
+        old_record: None,
+        rowid: Some(change.rowid),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::{Pool, Sqlite};
+
+    #[sqlx::test]
+    async fn test_update_hook_publishes_changes(db: Pool<Sqlite>) -> sqlx::Result<()> {
+        let feed = ChangeFeed::new(16);
+        let mut events = feed.subscribe();
+
+        let mut conn = db.acquire().await?;
+        install_update_hook(&mut conn, &feed).await?;
+
+        sqlx::query("CREATE TABLE notes (id integer primary key, body text)")
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query("INSERT INTO notes (body) VALUES ('hello')")
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query("UPDATE notes SET body = 'bye'")
+            .execute(&mut *conn)
+            .await?;
+
+        let inserted = events.try_recv().unwrap();
+        assert_eq!(inserted.schema, "main");
+        assert_eq!(inserted.table, "notes");
+        assert_eq!(inserted.operation, ChangeOperation::Insert);
+        assert_eq!(inserted.rowid, Some(1));
+
+        let updated = events.try_recv().unwrap();
+        assert_eq!(updated.operation, ChangeOperation::Update);
+        Ok(())
+    }
+}
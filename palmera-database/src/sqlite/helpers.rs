@@ -1,4 +1,10 @@
-use sea_query::{Alias, ColumnDef, Expr, Table, TableCreateStatement};
+use sea_query::{Alias, ColumnDef, Expr, Index, Table, TableCreateStatement};
+use sqlx::{Pool, Sqlite};
+
+/// Quotes a SQLite identifier with double quotes, doubling embedded ones.
+pub fn quote_ident(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
 
 pub fn create_policy_table() -> TableCreateStatement {
     Table::create()
@@ -19,14 +25,16 @@ pub fn create_policy_table() -> TableCreateStatement {
             ColumnDef::new("operation")
                 .string()
                 .not_null()
-                .check("operation IN ('select', 'update', 'insert', 'delete', 'all')"),
+                .check(Expr::cust(
+                    "operation IN ('select', 'update', 'insert', 'delete', 'all')",
+                )),
         )
         .col(
             ColumnDef::new("policy_type")
                 .string()
                 .not_null()
                 .default("PERMISSIVE")
-                .check("policy_type IN ('PERMISSIVE', 'RESTRICTIVE')"),
+                .check(Expr::cust("policy_type IN ('PERMISSIVE', 'RESTRICTIVE')")),
         )
         .col(ColumnDef::new("using_expr").string().null())
         .col(ColumnDef::new("check_expr").string().null())
@@ -35,3 +43,323 @@ pub fn create_policy_table() -> TableCreateStatement {
         ))
         .to_owned()
 }
+
+pub fn create_settings_table() -> TableCreateStatement {
+    Table::create()
+        .table(Alias::new("_settings"))
+        .if_not_exists()
+        .col(ColumnDef::new("key").string().not_null().primary_key())
+        .col(ColumnDef::new("value").json().not_null())
+        .col(ColumnDef::new("updated").big_integer().not_null())
+        .to_owned()
+}
+
+pub fn create_fields_table() -> TableCreateStatement {
+    Table::create()
+        .table(Alias::new("_fields"))
+        .if_not_exists()
+        .col(ColumnDef::new("table_name").string().not_null())
+        .col(ColumnDef::new("column_name").string().not_null())
+        .col(ColumnDef::new("kind").json().not_null())
+        .primary_key(Index::create().col("table_name").col("column_name"))
+        .to_owned()
+}
+
+pub fn create_comments_table() -> TableCreateStatement {
+    Table::create()
+        .table(Alias::new("_comments"))
+        .if_not_exists()
+        .col(ColumnDef::new("table_name").string().not_null())
+        // Empty for the comment of the table itself.
+        .col(
+            ColumnDef::new("column_name")
+                .string()
+                .not_null()
+                .default(""),
+        )
+        .col(ColumnDef::new("comment").string().not_null())
+        .primary_key(Index::create().col("table_name").col("column_name"))
+        .to_owned()
+}
+
+pub fn create_exposure_table() -> TableCreateStatement {
+    Table::create()
+        .table(Alias::new("_exposures"))
+        .if_not_exists()
+        .col(
+            ColumnDef::new("table_name")
+                .string()
+                .not_null()
+                .primary_key(),
+        )
+        .col(ColumnDef::new("enabled").boolean().not_null().default(true))
+        .col(
+            ColumnDef::new("read_only")
+                .boolean()
+                .not_null()
+                .default(false),
+        )
+        .col(ColumnDef::new("alias").string().null().unique_key())
+        .to_owned()
+}
+
+pub fn create_retention_table() -> TableCreateStatement {
+    Table::create()
+        .table(Alias::new("_retention"))
+        .if_not_exists()
+        .col(
+            ColumnDef::new("table_name")
+                .string()
+                .not_null()
+                .primary_key(),
+        )
+        .col(ColumnDef::new("timestamp_column").string().not_null())
+        .col(ColumnDef::new("timestamp_format").string().not_null())
+        .col(ColumnDef::new("max_age_days").integer().not_null())
+        .col(ColumnDef::new("action").json().not_null())
+        .col(ColumnDef::new("batch_size").integer().not_null())
+        .col(ColumnDef::new("enabled").boolean().not_null().default(true))
+        .to_owned()
+}
+
+pub fn create_saved_queries_table() -> TableCreateStatement {
+    Table::create()
+        .table(Alias::new("_saved_queries"))
+        .if_not_exists()
+        .col(ColumnDef::new("owner_id").string().not_null())
+        .col(ColumnDef::new("table_name").string().not_null())
+        .col(ColumnDef::new("name").string().not_null())
+        .col(ColumnDef::new("filter").json().not_null())
+        .col(ColumnDef::new("sort").json().not_null())
+        .col(ColumnDef::new("fields").json().not_null())
+        .col(ColumnDef::new("sharing").json().not_null())
+        .primary_key(
+            Index::create()
+                .col("owner_id")
+                .col("table_name")
+                .col("name"),
+        )
+        .to_owned()
+}
+
+pub fn create_query_usage_table() -> TableCreateStatement {
+    Table::create()
+        .table(Alias::new("_query_usage"))
+        .if_not_exists()
+        .col(ColumnDef::new("table_name").string().not_null())
+        .col(ColumnDef::new("column_name").string().not_null())
+        .col(ColumnDef::new("filtered").integer().not_null().default(0))
+        .col(ColumnDef::new("sorted").integer().not_null().default(0))
+        .primary_key(Index::create().col("table_name").col("column_name"))
+        .to_owned()
+}
+
+pub fn create_column_jobs_table() -> TableCreateStatement {
+    Table::create()
+        .table(Alias::new("_column_jobs"))
+        .if_not_exists()
+        .col(
+            ColumnDef::new("id")
+                .integer()
+                .not_null()
+                .auto_increment()
+                .primary_key(),
+        )
+        .col(ColumnDef::new("table_name").string().not_null())
+        .col(ColumnDef::new("change").json().not_null())
+        .col(ColumnDef::new("step").string().not_null())
+        .col(ColumnDef::new("status").string().not_null())
+        .col(
+            ColumnDef::new("last_rowid")
+                .big_integer()
+                .not_null()
+                .default(0),
+        )
+        .col(
+            ColumnDef::new("processed")
+                .big_integer()
+                .not_null()
+                .default(0),
+        )
+        .col(ColumnDef::new("total").big_integer().not_null().default(0))
+        .col(ColumnDef::new("error").string().null())
+        .col(ColumnDef::new("request_id").string().null())
+        .col(ColumnDef::new("updated").big_integer().not_null())
+        .to_owned()
+}
+
+pub fn create_outbox_table() -> TableCreateStatement {
+    Table::create()
+        .table(Alias::new("_outbox"))
+        .if_not_exists()
+        .col(
+            ColumnDef::new("id")
+                .integer()
+                .not_null()
+                .auto_increment()
+                .primary_key(),
+        )
+        .col(ColumnDef::new("change").json().not_null())
+        .col(ColumnDef::new("attempts").integer().not_null().default(0))
+        .col(ColumnDef::new("error").string().null())
+        .col(ColumnDef::new("next_attempt").big_integer().not_null())
+        .col(ColumnDef::new("created").big_integer().not_null())
+        .to_owned()
+}
+
+pub fn create_files_table() -> TableCreateStatement {
+    Table::create()
+        .table(Alias::new("_files"))
+        .if_not_exists()
+        .col(ColumnDef::new("bucket").string().not_null())
+        .col(ColumnDef::new("name").string().not_null())
+        .col(ColumnDef::new("mime").string().not_null())
+        .col(ColumnDef::new("size").big_integer().not_null())
+        .col(ColumnDef::new("created_at").big_integer().not_null())
+        .primary_key(Index::create().col("bucket").col("name"))
+        .to_owned()
+}
+
+pub fn create_notifications_table() -> TableCreateStatement {
+    Table::create()
+        .table(Alias::new("_notifications"))
+        .if_not_exists()
+        .col(
+            ColumnDef::new("id")
+                .integer()
+                .not_null()
+                .auto_increment()
+                .primary_key(),
+        )
+        .col(ColumnDef::new("user_id").string().not_null())
+        .col(ColumnDef::new("kind").string().not_null())
+        .col(ColumnDef::new("title").string().not_null())
+        .col(ColumnDef::new("body").string().null())
+        .col(ColumnDef::new("data").json().not_null())
+        .col(ColumnDef::new("created_at").big_integer().not_null())
+        .col(ColumnDef::new("read_at").big_integer().null())
+        .to_owned()
+}
+
+pub fn create_quotas_table() -> TableCreateStatement {
+    Table::create()
+        .table(Alias::new("_quotas"))
+        .if_not_exists()
+        .col(ColumnDef::new("subject").string().not_null().primary_key())
+        .col(ColumnDef::new("limits").json().not_null())
+        .col(ColumnDef::new("updated").big_integer().not_null())
+        .to_owned()
+}
+
+pub fn create_quota_usage_table() -> TableCreateStatement {
+    Table::create()
+        .table(Alias::new("_quota_usage"))
+        .if_not_exists()
+        .col(ColumnDef::new("subject").string().not_null())
+        .col(ColumnDef::new("metric").string().not_null())
+        .col(ColumnDef::new("period_end").big_integer().not_null())
+        .col(ColumnDef::new("amount").big_integer().not_null())
+        .primary_key(
+            Index::create()
+                .col("subject")
+                .col("metric")
+                .col("period_end"),
+        )
+        .to_owned()
+}
+
+pub fn create_feature_flags_table() -> TableCreateStatement {
+    Table::create()
+        .table(Alias::new("_feature_flags"))
+        .if_not_exists()
+        .col(ColumnDef::new("key").string().not_null().primary_key())
+        .col(ColumnDef::new("description").string().null())
+        .col(ColumnDef::new("enabled").boolean().not_null().default(true))
+        .col(ColumnDef::new("rules").json().not_null())
+        .to_owned()
+}
+
+pub fn create_schema_manifests_table() -> TableCreateStatement {
+    Table::create()
+        .table(Alias::new("_schema_manifests"))
+        .if_not_exists()
+        .col(
+            ColumnDef::new("version")
+                .big_integer()
+                .not_null()
+                .primary_key(),
+        )
+        .col(ColumnDef::new("checksum").string().not_null())
+        .col(ColumnDef::new("applied_at").big_integer().not_null())
+        .to_owned()
+}
+
+pub fn create_history_actor_table() -> TableCreateStatement {
+    Table::create()
+        .table(Alias::new("_history_actor"))
+        .if_not_exists()
+        .col(
+            ColumnDef::new("id")
+                .integer()
+                .not_null()
+                .primary_key()
+                .check(Expr::cust("id = 1")),
+        )
+        .col(ColumnDef::new("actor").string().null())
+        .col(ColumnDef::new("subject").string().null())
+        .to_owned()
+}
+
+/// Column names of `table`, empty if it does not exist.
+pub async fn table_columns(db: &Pool<Sqlite>, table: &str) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar("SELECT name FROM pragma_table_info(?) ORDER BY cid")
+        .bind(table)
+        .fetch_all(db)
+        .await
+}
+
+/// A `json_object(...)` expression turning a row of `columns` into a JSON object.
+pub fn row_json(columns: &[String]) -> String {
+    let pairs = columns
+        .iter()
+        .map(|column| format!("'{}', {}", column.replace('\'', "''"), quote_ident(column)))
+        .collect::<Vec<_>>();
+    format!("json_object({})", pairs.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sea_query::SqliteQueryBuilder;
+
+    #[sqlx::test]
+    async fn test_policy_table_checks(db: Pool<Sqlite>) -> sqlx::Result<()> {
+        sqlx::query(&create_policy_table().to_string(SqliteQueryBuilder))
+            .execute(&db)
+            .await?;
+        let insert = |name: &'static str, operation: &'static str, using: Option<&'static str>| {
+            sqlx::query(
+                "INSERT INTO _policies (name, table_name, operation, using_expr) \
+                 VALUES (?, 'notes', ?, ?)",
+            )
+            .bind(name)
+            .bind(operation)
+            .bind(using)
+            .execute(&db)
+        };
+
+        insert("owner reads", "select", Some("1")).await?;
+        assert!(
+            insert("bad operation", "truncate", Some("1"))
+                .await
+                .is_err()
+        );
+        assert!(insert("no expression", "select", None).await.is_err());
+
+        let policy_type: String = sqlx::query_scalar("SELECT policy_type FROM _policies")
+            .fetch_one(&db)
+            .await?;
+        assert_eq!(policy_type, "PERMISSIVE");
+        Ok(())
+    }
+}
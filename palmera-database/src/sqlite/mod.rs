@@ -1,2 +1,4 @@
+pub mod cdc;
 pub mod helpers;
 pub mod schemas;
+pub mod snapshot;
@@ -0,0 +1,324 @@
+//! # SQLite snapshots and point-in-time restore
+//!
+//! Copying a live SQLite file (and its `-wal`) with `cp` can produce a corrupt backup.
+//! [`backup`] uses SQLite's online backup API instead, which copies a consistent view of
+//! the database while other connections keep reading and writing; in WAL mode writers
+//! are not blocked at all.
+//!
+//! A [`SnapshotStore`] keeps timestamped snapshots in a directory. [`spawn_snapshots`]
+//! takes one periodically and prunes old ones, and [`SnapshotStore::restore_at`] puts
+//! back the newest snapshot taken at or before a given time. With the server stopped,
+//! the same restore can be run from the command line:
+//!
+//! ```text
+//! palmera-database restore DATABASE_FILE --snapshots DIR --at 2025-06-01T12:00:00Z
+//! ```
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! use std::time::Duration;
+//! use chrono::{TimeZone, Utc};
+//! use palmera_database::sqlite::snapshot::{SnapshotStore, spawn_snapshots};
+//! use sqlx::SqlitePool;
+//!
+//! # async fn example(db: SqlitePool) -> Result<(), sqlx::Error> {
+//! let store = SnapshotStore::new("/var/lib/palmera/snapshots");
+//! spawn_snapshots(db, store.clone(), Duration::from_secs(3600), 48);
+//!
+//! // Later, with the server stopped:
+//! let at = Utc.with_ymd_and_hms(2025, 6, 1, 12, 0, 0).unwrap();
+//! store.restore_at(at, "/var/lib/palmera/data.db").await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    ffi::CStr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use libsqlite3_sys::{
+    SQLITE_BUSY, SQLITE_DONE, SQLITE_LOCKED, SQLITE_OK, sqlite3_backup_finish, sqlite3_backup_init,
+    sqlite3_backup_step, sqlite3_errmsg,
+};
+use sqlx::{
+    Connection, Pool, Sqlite, SqliteConnection,
+    sqlite::{LockedSqliteHandle, SqliteConnectOptions},
+};
+use tokio::task::JoinHandle;
+
+const SNAPSHOT_PREFIX: &str = "snapshot-";
+const SNAPSHOT_SUFFIX: &str = ".db";
+
+/// Copies the database behind `conn` to `dest` with the online backup API.
+///
+/// `dest` is created if it does not exist and overwritten if it does.
+pub async fn backup(conn: &mut SqliteConnection, dest: &Path) -> Result<(), sqlx::Error> {
+    let mut dest = SqliteConnection::connect_with(
+        &SqliteConnectOptions::new()
+            .filename(dest)
+            .create_if_missing(true),
+    )
+    .await?;
+
+    {
+        let mut source = conn.lock_handle().await?;
+        let mut target = dest.lock_handle().await?;
+        copy_database(&mut source, &mut target)?;
+    }
+
+    dest.close().await
+}
+
+fn copy_database(
+    source: &mut LockedSqliteHandle<'_>,
+    target: &mut LockedSqliteHandle<'_>,
+) -> Result<(), sqlx::Error> {
+    let main = c"main";
+    let source = source.as_raw_handle().as_ptr();
+    let target = target.as_raw_handle().as_ptr();
+
+    // SAFETY: both handles are locked for the duration of this function, so sqlx's
+    // worker threads make no calls on them, and the backup object is finished before
+    // the locks are released.
+    unsafe {
+        let backup = sqlite3_backup_init(target, main.as_ptr(), source, main.as_ptr());
+        if backup.is_null() {
+            return Err(sqlite_error(target));
+        }
+
+        let step = loop {
+            match sqlite3_backup_step(backup, -1) {
+                SQLITE_BUSY | SQLITE_LOCKED => std::thread::sleep(Duration::from_millis(10)),
+                rc => break rc,
+            }
+        };
+
+        if sqlite3_backup_finish(backup) != SQLITE_OK || step != SQLITE_DONE {
+            return Err(sqlite_error(target));
+        }
+    }
+
+    Ok(())
+}
+
+/// # Safety
+///
+/// `db` must be a valid, locked connection handle.
+unsafe fn sqlite_error(db: *mut libsqlite3_sys::sqlite3) -> sqlx::Error {
+    let message = unsafe { CStr::from_ptr(sqlite3_errmsg(db)) };
+    sqlx::Error::Protocol(format!(
+        "sqlite backup failed: {}",
+        message.to_string_lossy()
+    ))
+}
+
+/// A snapshot on disk.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub path: PathBuf,
+    pub taken_at: DateTime<Utc>,
+}
+
+/// Directory of timestamped snapshots named `snapshot-<unix millis>.db`.
+#[derive(Debug, Clone)]
+pub struct SnapshotStore {
+    dir: PathBuf,
+}
+
+impl SnapshotStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Takes a snapshot of the database behind `conn`.
+    pub async fn snapshot(&self, conn: &mut SqliteConnection) -> Result<Snapshot, sqlx::Error> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+
+        // Snapshot names only carry millisecond precision.
+        let taken_at = DateTime::from_timestamp_millis(Utc::now().timestamp_millis())
+            .expect("current time is in range");
+        let path = self.dir.join(format!(
+            "{}{}{}",
+            SNAPSHOT_PREFIX,
+            taken_at.timestamp_millis(),
+            SNAPSHOT_SUFFIX
+        ));
+
+        backup(conn, &path).await?;
+
+        Ok(Snapshot { path, taken_at })
+    }
+
+    /// Lists snapshots, oldest first.
+    pub async fn list(&self) -> Result<Vec<Snapshot>, sqlx::Error> {
+        let mut entries = match tokio::fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut snapshots = vec![];
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            let taken_at = name
+                .to_str()
+                .and_then(|name| name.strip_prefix(SNAPSHOT_PREFIX))
+                .and_then(|name| name.strip_suffix(SNAPSHOT_SUFFIX))
+                .and_then(|millis| millis.parse().ok())
+                .and_then(DateTime::from_timestamp_millis);
+
+            if let Some(taken_at) = taken_at {
+                snapshots.push(Snapshot {
+                    path: entry.path(),
+                    taken_at,
+                });
+            }
+        }
+
+        snapshots.sort_by_key(|snapshot| snapshot.taken_at);
+
+        Ok(snapshots)
+    }
+
+    /// Returns the newest snapshot taken at or before `at`.
+    pub async fn find(&self, at: DateTime<Utc>) -> Result<Option<Snapshot>, sqlx::Error> {
+        Ok(self
+            .list()
+            .await?
+            .into_iter()
+            .rev()
+            .find(|snapshot| snapshot.taken_at <= at))
+    }
+
+    /// Deletes all but the `keep` newest snapshots, returning how many were removed.
+    pub async fn prune(&self, keep: usize) -> Result<usize, sqlx::Error> {
+        let snapshots = self.list().await?;
+        let expired = snapshots.len().saturating_sub(keep);
+
+        for snapshot in &snapshots[..expired] {
+            tokio::fs::remove_file(&snapshot.path).await?;
+        }
+
+        Ok(expired)
+    }
+
+    /// Replaces the database file at `target` with the newest snapshot taken at or
+    /// before `at`, returning the snapshot used.
+    ///
+    /// The server must be stopped: the target's `-wal` and `-shm` files are removed so
+    /// that SQLite does not replay pages from the replaced database.
+    pub async fn restore_at(
+        &self,
+        at: DateTime<Utc>,
+        target: impl AsRef<Path>,
+    ) -> Result<Snapshot, sqlx::Error> {
+        let target = target.as_ref();
+        let snapshot = self.find(at).await?.ok_or_else(|| {
+            sqlx::Error::Configuration(format!("no snapshot taken at or before {}", at).into())
+        })?;
+
+        for suffix in ["-wal", "-shm"] {
+            let mut sidecar = target.as_os_str().to_owned();
+            sidecar.push(suffix);
+            match tokio::fs::remove_file(&sidecar).await {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+        }
+
+        tokio::fs::copy(&snapshot.path, target).await?;
+
+        Ok(snapshot)
+    }
+}
+
+/// Takes a snapshot of `db` every `interval`, keeping the `keep` newest ones.
+///
+/// Failures are logged and retried on the next tick.
+pub fn spawn_snapshots(
+    db: Pool<Sqlite>,
+    store: SnapshotStore,
+    interval: Duration,
+    keep: usize,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+
+        loop {
+            ticker.tick().await;
+
+            let result = async {
+                let mut conn = db.acquire().await?;
+                let snapshot = store.snapshot(&mut conn).await?;
+                store.prune(keep).await?;
+                Ok::<_, sqlx::Error>(snapshot)
+            }
+            .await;
+
+            match result {
+                Ok(snapshot) => {
+                    tracing::debug!(path = %snapshot.path.display(), "sqlite snapshot taken")
+                }
+                Err(err) => tracing::warn!(error = %err, "sqlite snapshot failed"),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(test: &str) -> SnapshotStore {
+        let dir = std::env::temp_dir().join(format!("palmera-snapshots-{}", test));
+        _ = std::fs::remove_dir_all(&dir);
+        SnapshotStore::new(dir)
+    }
+
+    async fn count_notes(path: &Path) -> Result<i64, sqlx::Error> {
+        let mut conn =
+            SqliteConnection::connect_with(&SqliteConnectOptions::new().filename(path)).await?;
+        sqlx::query_scalar("SELECT count(*) FROM notes")
+            .fetch_one(&mut conn)
+            .await
+    }
+
+    #[sqlx::test]
+    async fn test_snapshot_and_restore(db: Pool<Sqlite>) -> sqlx::Result<()> {
+        let store = store("restore");
+        let mut conn = db.acquire().await?;
+
+        sqlx::query("CREATE TABLE notes (body text)")
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query("INSERT INTO notes VALUES ('one')")
+            .execute(&mut *conn)
+            .await?;
+        let first = store.snapshot(&mut conn).await?;
+
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        sqlx::query("INSERT INTO notes VALUES ('two')")
+            .execute(&mut *conn)
+            .await?;
+        let second = store.snapshot(&mut conn).await?;
+
+        assert_eq!(count_notes(&first.path).await?, 1);
+        assert_eq!(count_notes(&second.path).await?, 2);
+
+        let target = store.dir.join("restored.db");
+        let restored = store.restore_at(first.taken_at, &target).await?;
+        assert_eq!(restored, first);
+        assert_eq!(count_notes(&target).await?, 1);
+
+        let before_any = first.taken_at - chrono::Duration::seconds(1);
+        assert!(store.restore_at(before_any, &target).await.is_err());
+
+        assert_eq!(store.prune(1).await?, 1);
+        assert_eq!(store.list().await?, vec![second]);
+        Ok(())
+    }
+}
@@ -0,0 +1,216 @@
+//! # Statement cache for dynamic queries
+//!
+//! sqlx keeps prepared statements per connection, keyed by the SQL text. Dynamic queries
+//! only benefit from that when the same request shape always renders to the same SQL with
+//! its values passed as bind parameters. [`StatementCache`] maps a normalized
+//! [`QueryShape`] to the SQL rendered for it, so hot endpoints skip rebuilding the query
+//! text and hand sqlx an identical statement every time. The cache is a bounded LRU and
+//! tracks its hit rate.
+//!
+//! # Example
+//!
+//! ```rust
+//! use palmera_database::statement_cache::{QueryShape, StatementCache};
+//!
+//! let cache = StatementCache::new(64);
+//! let shape = QueryShape::new("posts", &["id", "title"], "author = ?");
+//! let sql = cache.get_or_insert(&shape, || {
+//!     r#"SELECT "id", "title" FROM "posts" WHERE "author" = $1"#.to_string()
+//! });
+//! assert!(sql.contains("$1"));
+//! assert_eq!(cache.stats().misses, 1);
+//! ```
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use serde::Serialize;
+use utoipa::ToSchema;
+
+/// The parts of a request that determine the SQL text, independent of bound values.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct QueryShape {
+    /// Target table, including its schema if any.
+    pub table: String,
+    /// Selected columns, in order.
+    pub columns: Vec<String>,
+    /// Filter expression with every literal replaced by a placeholder.
+    pub filter: String,
+}
+
+impl QueryShape {
+    pub fn new(table: &str, columns: &[&str], filter: &str) -> Self {
+        Self {
+            table: table.to_string(),
+            columns: columns.iter().map(|c| c.to_string()).collect(),
+            filter: filter.to_string(),
+        }
+    }
+}
+
+/// Snapshot of the cache counters.
+#[derive(Debug, Clone, Copy, Default, Serialize, ToSchema, PartialEq)]
+pub struct StatementCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// Number of shapes currently cached.
+    pub size: usize,
+    pub capacity: usize,
+    /// `hits / (hits + misses)`, or `0.0` before the first lookup.
+    pub hit_rate: f64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    sql: Arc<str>,
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    entries: HashMap<QueryShape, Entry>,
+    clock: u64,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+/// Bounded LRU cache from [`QueryShape`] to rendered SQL.
+#[derive(Debug)]
+pub struct StatementCache {
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+impl StatementCache {
+    /// Creates a cache holding at most `capacity` shapes.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// Returns the SQL cached for `shape`, rendering and caching it with `build` on a miss.
+    pub fn get_or_insert<F>(&self, shape: &QueryShape, build: F) -> Arc<str>
+    where
+        F: FnOnce() -> String,
+    {
+        let mut inner = self.inner.lock().unwrap();
+        inner.clock += 1;
+        let now = inner.clock;
+
+        if let Some(entry) = inner.entries.get_mut(shape) {
+            entry.last_used = now;
+            let sql = entry.sql.clone();
+            inner.hits += 1;
+            return sql;
+        }
+
+        inner.misses += 1;
+        let sql: Arc<str> = build().into();
+
+        if self.capacity == 0 {
+            return sql;
+        }
+
+        if inner.entries.len() >= self.capacity {
+            let oldest = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(shape, _)| shape.clone());
+            if let Some(oldest) = oldest {
+                inner.entries.remove(&oldest);
+                inner.evictions += 1;
+            }
+        }
+
+        inner.entries.insert(
+            shape.clone(),
+            Entry {
+                sql: sql.clone(),
+                last_used: now,
+            },
+        );
+
+        sql
+    }
+
+    /// Drops every cached statement for `table`, e.g. after its schema changed.
+    pub fn invalidate_table(&self, table: &str) {
+        self.inner
+            .lock()
+            .unwrap()
+            .entries
+            .retain(|shape, _| shape.table != table);
+    }
+
+    /// Returns the current counters.
+    pub fn stats(&self) -> StatementCacheStats {
+        let inner = self.inner.lock().unwrap();
+        let lookups = inner.hits + inner.misses;
+
+        StatementCacheStats {
+            hits: inner.hits,
+            misses: inner.misses,
+            evictions: inner.evictions,
+            size: inner.entries.len(),
+            capacity: self.capacity,
+            hit_rate: if lookups == 0 {
+                0.0
+            } else {
+                inner.hits as f64 / lookups as f64
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shape(table: &str) -> QueryShape {
+        QueryShape::new(table, &["id"], "id = ?")
+    }
+
+    #[test]
+    fn test_hit_reuses_cached_sql() {
+        let cache = StatementCache::new(4);
+        let first = cache.get_or_insert(&shape("posts"), || "SELECT 1".to_string());
+        let second = cache.get_or_insert(&shape("posts"), || unreachable!());
+
+        assert!(Arc::ptr_eq(&first, &second));
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+        assert_eq!(stats.hit_rate, 0.5);
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let cache = StatementCache::new(2);
+        cache.get_or_insert(&shape("a"), || "a".to_string());
+        cache.get_or_insert(&shape("b"), || "b".to_string());
+        // touch "a" so "b" becomes the eviction candidate
+        cache.get_or_insert(&shape("a"), || unreachable!());
+        cache.get_or_insert(&shape("c"), || "c".to_string());
+
+        let stats = cache.stats();
+        assert_eq!(stats.size, 2);
+        assert_eq!(stats.evictions, 1);
+
+        let rebuilt = cache.get_or_insert(&shape("b"), || "b2".to_string());
+        assert_eq!(&*rebuilt, "b2");
+    }
+
+    #[test]
+    fn test_invalidate_table() {
+        let cache = StatementCache::new(4);
+        cache.get_or_insert(&shape("posts"), || "SELECT 1".to_string());
+        cache.invalidate_table("posts");
+        assert_eq!(cache.stats().size, 0);
+    }
+}
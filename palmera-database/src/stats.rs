@@ -0,0 +1,113 @@
+//! # Table statistics
+//!
+//! Record counts per table for dashboards, served by `/admin/stats/tables` in
+//! [`crate::admin`]. Counts are exact, so each call scans every table; internal tables
+//! (prefixed with `_` in SQLite) are left out.
+
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres, Sqlite};
+use utoipa::ToSchema;
+
+use crate::postgres::helpers::quote_ident;
+
+/// Number of records in a table.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+pub struct TableCount {
+    pub table: String,
+    pub records: i64,
+}
+
+/// Counts the records of every user table in a SQLite database, ordered by table.
+pub async fn sqlite_table_counts(db: &Pool<Sqlite>) -> Result<Vec<TableCount>, sqlx::Error> {
+    let tables: Vec<String> = sqlx::query_scalar(
+        "SELECT name FROM sqlite_master \
+         WHERE type = 'table' AND name NOT LIKE '\\_%' ESCAPE '\\' AND name NOT LIKE 'sqlite_%' \
+         ORDER BY name",
+    )
+    .fetch_all(db)
+    .await?;
+
+    let mut counts = Vec::with_capacity(tables.len());
+    for table in tables {
+        let records = sqlx::query_scalar(&format!("SELECT count(*) FROM {}", quote_ident(&table)))
+            .fetch_one(db)
+            .await?;
+        counts.push(TableCount { table, records });
+    }
+    Ok(counts)
+}
+
+/// Counts the records of every table in `schema` of a Postgres database, ordered by
+/// table.
+pub async fn postgres_table_counts(
+    db: &Pool<Postgres>,
+    schema: &str,
+) -> Result<Vec<TableCount>, sqlx::Error> {
+    sqlx::query_as::<_, (String, i64)>(
+        "SELECT table_name::text, \
+                (xpath('/row/count/text()', query_to_xml( \
+                    format('SELECT count(*) FROM %I.%I', table_schema, table_name), \
+                    false, true, '')))[1]::text::bigint \
+         FROM information_schema.tables \
+         WHERE table_schema = $1 AND table_type = 'BASE TABLE' \
+         ORDER BY table_name",
+    )
+    .bind(schema)
+    .fetch_all(db)
+    .await
+    .map(|rows| {
+        rows.into_iter()
+            .map(|(table, records)| TableCount { table, records })
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn count(table: &str, records: i64) -> TableCount {
+        TableCount {
+            table: table.to_string(),
+            records,
+        }
+    }
+
+    #[sqlx::test]
+    async fn test_sqlite_table_counts(db: Pool<Sqlite>) -> sqlx::Result<()> {
+        sqlx::query("CREATE TABLE posts (id INTEGER PRIMARY KEY)")
+            .execute(&db)
+            .await?;
+        sqlx::query("CREATE TABLE _settings (key TEXT)")
+            .execute(&db)
+            .await?;
+        sqlx::query("INSERT INTO posts DEFAULT VALUES")
+            .execute(&db)
+            .await?;
+        sqlx::query("INSERT INTO posts DEFAULT VALUES")
+            .execute(&db)
+            .await?;
+
+        assert_eq!(sqlite_table_counts(&db).await?, [count("posts", 2)]);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_postgres_table_counts(db: Pool<Postgres>) -> sqlx::Result<()> {
+        sqlx::query("CREATE TABLE posts (id serial PRIMARY KEY)")
+            .execute(&db)
+            .await?;
+        sqlx::query("CREATE TABLE \"Empty Table\" (id integer)")
+            .execute(&db)
+            .await?;
+        sqlx::query("INSERT INTO posts DEFAULT VALUES")
+            .execute(&db)
+            .await?;
+
+        assert_eq!(
+            postgres_table_counts(&db, "public").await?,
+            [count("Empty Table", 0), count("posts", 1)]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,312 @@
+//! # Connection health supervisor
+//!
+//! A [`Supervisor`] pings the database on an interval. When a ping fails it marks the
+//! app as not ready, fires the [`Supervisor::on_db_unavailable`] listeners and keeps
+//! retrying with exponential backoff; sqlx pools open fresh connections on demand, so
+//! the first successful ping means the pool has reconnected. The app is then marked
+//! ready again and the [`Supervisor::on_db_recovered`] listeners run. Transient outages
+//! therefore don't require a process restart.
+//!
+//! [`Readiness`] is shared with the supervisor; serve it with [`readiness`] so load
+//! balancers stop routing to the instance while the database is away.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # async fn run(db: sqlx::Pool<sqlx::Postgres>) {
+//! use axum::{Extension, Router, routing::get};
+//! use palmera_database::supervisor::{Supervisor, readiness};
+//!
+//! let supervisor = Supervisor::new(db);
+//! supervisor.on_db_unavailable(|event| tracing::error!(error = %event.error, "database down"));
+//! supervisor.on_db_recovered(|event| tracing::info!(downtime = ?event.downtime, "database back"));
+//!
+//! let app: Router = Router::new()
+//!     .route("/ready", get(readiness))
+//!     .layer(Extension(supervisor.readiness()));
+//! supervisor.spawn();
+//! # }
+//! ```
+
+use std::{
+    future::Future,
+    sync::{
+        Arc, RwLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use axum::{Extension, http::StatusCode};
+use sqlx::{Connection, Database, Pool};
+use tokio::{task::JoinHandle, time::Instant};
+
+/// Whether the database is reachable, as last seen by a [`Supervisor`].
+#[derive(Debug, Clone)]
+pub struct Readiness(Arc<AtomicBool>);
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn set(&self, ready: bool) {
+        self.0.store(ready, Ordering::Relaxed);
+    }
+}
+
+/// Answers `200 OK` while the database is reachable and `503 Service Unavailable`
+/// otherwise.
+pub async fn readiness(Extension(readiness): Extension<Readiness>) -> StatusCode {
+    if readiness.is_ready() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+/// Passed to [`Supervisor::on_db_unavailable`] listeners when a ping fails.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DbUnavailable {
+    pub error: String,
+}
+
+/// Passed to [`Supervisor::on_db_recovered`] listeners once pings succeed again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DbRecovered {
+    /// Time since the failed ping.
+    pub downtime: Duration,
+    /// Reconnection attempts it took, including the successful one.
+    pub attempts: u32,
+}
+
+type Listener<T> = Box<dyn Fn(&T) + Send + Sync>;
+
+struct Listeners {
+    unavailable: RwLock<Vec<Listener<DbUnavailable>>>,
+    recovered: RwLock<Vec<Listener<DbRecovered>>>,
+}
+
+/// Monitors the health of a pool.
+pub struct Supervisor<DB: Database> {
+    db: Pool<DB>,
+    interval: Duration,
+    min_backoff: Duration,
+    max_backoff: Duration,
+    readiness: Readiness,
+    listeners: Arc<Listeners>,
+}
+
+impl<DB: Database> std::fmt::Debug for Supervisor<DB> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Supervisor")
+            .field("interval", &self.interval)
+            .field("min_backoff", &self.min_backoff)
+            .field("max_backoff", &self.max_backoff)
+            .field("ready", &self.readiness.is_ready())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<DB: Database> Supervisor<DB> {
+    /// Supervises `db`, pinging it every 5 seconds and retrying between 500 milliseconds
+    /// and 30 seconds apart while it is unavailable. The app starts out ready.
+    pub fn new(db: Pool<DB>) -> Self {
+        Self {
+            db,
+            interval: Duration::from_secs(5),
+            min_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            readiness: Readiness(Arc::new(AtomicBool::new(true))),
+            listeners: Arc::new(Listeners {
+                unavailable: RwLock::new(Vec::new()),
+                recovered: RwLock::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// Time between pings while the database is healthy.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Delay before the first reconnection attempt, doubled after each failed one up to
+    /// `max`.
+    pub fn with_backoff(mut self, min: Duration, max: Duration) -> Self {
+        self.min_backoff = min;
+        self.max_backoff = max.max(min);
+        self
+    }
+
+    pub fn readiness(&self) -> Readiness {
+        self.readiness.clone()
+    }
+
+    /// Registers `listener` to run when the database becomes unavailable.
+    pub fn on_db_unavailable<F>(&self, listener: F)
+    where
+        F: Fn(&DbUnavailable) + Send + Sync + 'static,
+    {
+        self.listeners
+            .unavailable
+            .write()
+            .unwrap()
+            .push(Box::new(listener));
+    }
+
+    /// Registers `listener` to run when the database is reachable again.
+    pub fn on_db_recovered<F>(&self, listener: F)
+    where
+        F: Fn(&DbRecovered) + Send + Sync + 'static,
+    {
+        self.listeners
+            .recovered
+            .write()
+            .unwrap()
+            .push(Box::new(listener));
+    }
+
+    /// Starts supervising on a background task.
+    pub fn spawn(self) -> JoinHandle<()> {
+        let db = self.db.clone();
+        tokio::spawn(self.run(move || {
+            let db = db.clone();
+            async move {
+                let mut conn = db.acquire().await?;
+                if let Err(err) = conn.ping().await {
+                    // Don't hand the broken connection back to the pool.
+                    conn.detach();
+                    return Err(err);
+                }
+                Ok(())
+            }
+        }))
+    }
+
+    async fn run<F, Fut>(self, mut ping: F)
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<(), sqlx::Error>>,
+    {
+        loop {
+            tokio::time::sleep(self.interval).await;
+
+            let Err(err) = ping().await else {
+                continue;
+            };
+            let since = Instant::now();
+            tracing::warn!(error = %err, "database unavailable");
+            self.readiness.set(false);
+            let event = DbUnavailable {
+                error: err.to_string(),
+            };
+            for listener in self.listeners.unavailable.read().unwrap().iter() {
+                listener(&event);
+            }
+
+            let mut backoff = self.min_backoff;
+            let mut attempts = 0;
+            loop {
+                tokio::time::sleep(backoff).await;
+                attempts += 1;
+                match ping().await {
+                    Ok(()) => break,
+                    Err(err) => {
+                        tracing::debug!(error = %err, attempts, "database reconnection failed");
+                        backoff = (backoff * 2).min(self.max_backoff);
+                    }
+                }
+            }
+
+            let event = DbRecovered {
+                downtime: since.elapsed(),
+                attempts,
+            };
+            tracing::info!(downtime = ?event.downtime, attempts, "database recovered");
+            self.readiness.set(true);
+            for listener in self.listeners.recovered.read().unwrap().iter() {
+                listener(&event);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    use sqlx::{Sqlite, sqlite::SqlitePoolOptions};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_outage_and_recovery() {
+        let db = SqlitePoolOptions::new()
+            .connect_lazy("sqlite::memory:")
+            .unwrap();
+        let supervisor = Supervisor::<Sqlite>::new(db)
+            .with_interval(Duration::from_secs(5))
+            .with_backoff(Duration::from_secs(1), Duration::from_secs(3));
+        let readiness = supervisor.readiness();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let seen = events.clone();
+        supervisor.on_db_unavailable(move |event| {
+            seen.lock()
+                .unwrap()
+                .push(format!("unavailable: {}", event.error))
+        });
+        let seen = events.clone();
+        supervisor.on_db_recovered(move |event| {
+            seen.lock().unwrap().push(format!(
+                "recovered after {:?} in {}",
+                event.downtime, event.attempts
+            ))
+        });
+
+        // Healthy, then down for three pings, then healthy again.
+        let mut script = vec![true, false, false, false, true].into_iter();
+        let task = tokio::spawn(supervisor.run(move || {
+            let healthy = script.next().unwrap_or(true);
+            async move {
+                if healthy {
+                    Ok(())
+                } else {
+                    Err(sqlx::Error::PoolTimedOut)
+                }
+            }
+        }));
+
+        tokio::time::sleep(Duration::from_secs(11)).await;
+        assert!(!readiness.is_ready());
+        assert_eq!(
+            readiness_status(&readiness).await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        // Reconnection attempts after 1, 2 and 3 (capped) seconds.
+        tokio::time::sleep(Duration::from_secs(6)).await;
+        assert!(readiness.is_ready());
+        assert_eq!(readiness_status(&readiness).await, StatusCode::OK);
+        task.abort();
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 2);
+        assert!(events[0].starts_with("unavailable: pool timed out"));
+        assert_eq!(events[1], "recovered after 6s in 3");
+    }
+
+    async fn readiness_status(ready: &Readiness) -> StatusCode {
+        readiness(Extension(ready.clone())).await
+    }
+
+    #[sqlx::test]
+    async fn test_ping(db: sqlx::Pool<Sqlite>) {
+        let supervisor = Supervisor::new(db).with_interval(Duration::from_millis(10));
+        let readiness = supervisor.readiness();
+        let task = supervisor.spawn();
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(readiness.is_ready());
+        task.abort();
+    }
+}
@@ -0,0 +1,227 @@
+//! # Project templates
+//!
+//! Starting points for new projects, so users can explore the API against a working
+//! schema instead of an empty database. Each [`ProjectTemplate`] bundles
+//!
+//! - a schema manifest with tables, indexes and policies (see [`crate::manifest`]),
+//! - seed rows for those tables, and
+//! - bucket declarations in the JSON format of `palmera_storage::bucket`.
+//!
+//! [`init`] applies the manifest through the schema-as-code engine, like any other
+//! manifest, and seeds the tables that are still empty, so running it twice doesn't
+//! duplicate rows. The manifest and buckets are also meant to be written into the
+//! project, see [`ProjectTemplate::files`], where they become the project's own.
+//!
+//! Buckets are not created by [`init`]: they are declared by the app's storage
+//! configuration rather than stored in the database, so only `buckets.json` is written.
+//! The app declares them by loading that file into `palmera_storage::bucket::Buckets`.
+//!
+//! The `palmera-database init` command does both from the command line.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # async fn run(db: sqlx::Pool<sqlx::Sqlite>) -> Result<(), palmera_database::ddl::SchemaError> {
+//! use palmera_database::templates;
+//!
+//! let blog = templates::find("blog").expect("built-in template");
+//! let report = templates::init(&db, blog).await?;
+//! for (table, rows) in &report.seeded {
+//!     tracing::info!(%table, rows, "seeded");
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite};
+
+use crate::{
+    ddl::{self, SchemaError},
+    import::{self, DEFAULT_BATCH_SIZE},
+    manifest::{self, ManifestPlan, SchemaManifest},
+};
+
+/// Name of the manifest file a template writes into a project.
+pub const SCHEMA_FILE: &str = "schema.toml";
+
+/// Name of the bucket declarations file a template writes into a project.
+pub const BUCKETS_FILE: &str = "buckets.json";
+
+/// A built-in project template.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProjectTemplate {
+    pub name: &'static str,
+    pub description: &'static str,
+    schema: &'static str,
+    seed: &'static str,
+    buckets: &'static str,
+}
+
+/// The built-in templates.
+pub const TEMPLATES: [ProjectTemplate; 3] = [
+    ProjectTemplate {
+        name: "blog",
+        description: "posts and comments, with drafts only visible to their author",
+        schema: include_str!("../templates/blog/schema.toml"),
+        seed: include_str!("../templates/blog/seed.json"),
+        buckets: include_str!("../templates/blog/buckets.json"),
+    },
+    ProjectTemplate {
+        name: "crm",
+        description: "companies, contacts and deals, each visible to its owner",
+        schema: include_str!("../templates/crm/schema.toml"),
+        seed: include_str!("../templates/crm/seed.json"),
+        buckets: include_str!("../templates/crm/buckets.json"),
+    },
+    ProjectTemplate {
+        name: "chat",
+        description: "rooms, members and messages, readable by the members of a room",
+        schema: include_str!("../templates/chat/schema.toml"),
+        seed: include_str!("../templates/chat/seed.json"),
+        buckets: include_str!("../templates/chat/buckets.json"),
+    },
+];
+
+/// Returns the built-in template called `name`.
+pub fn find(name: &str) -> Option<&'static ProjectTemplate> {
+    TEMPLATES.iter().find(|template| template.name == name)
+}
+
+/// Rows to insert into a table, aligned with its column list.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SeedTable {
+    pub table: String,
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<serde_json::Value>>,
+}
+
+impl ProjectTemplate {
+    /// The template's schema manifest.
+    pub fn manifest(&self) -> Result<SchemaManifest, SchemaError> {
+        SchemaManifest::parse(Path::new(SCHEMA_FILE), self.schema)
+    }
+
+    /// The template's seed rows, in insertion order.
+    pub fn seed(&self) -> Result<Vec<SeedTable>, SchemaError> {
+        serde_json::from_str(self.seed).map_err(|err| {
+            SchemaError::Invalid(format!("invalid seed of template {}: {}", self.name, err))
+        })
+    }
+
+    /// The template's bucket declarations, as JSON that deserializes into
+    /// `palmera_storage::bucket::BucketConfig`s by bucket name.
+    pub fn buckets(&self) -> &'static str {
+        self.buckets
+    }
+
+    /// Files to write into a new project, as names and contents.
+    pub fn files(&self) -> [(&'static str, &'static str); 2] {
+        [(SCHEMA_FILE, self.schema), (BUCKETS_FILE, self.buckets)]
+    }
+}
+
+/// What [`init`] changed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct InitReport {
+    /// The applied manifest's changes.
+    pub plan: ManifestPlan,
+    /// Tables that were seeded, with the number of rows inserted.
+    pub seeded: Vec<(String, u64)>,
+}
+
+/// Applies `template`'s manifest to the database and seeds its empty tables.
+///
+/// # Errors
+///
+/// Returns the errors of [`manifest::apply`], e.g. [`SchemaError::Conflict`] if another
+/// manifest was already applied, and [`SchemaError::Database`] if a seed row is rejected.
+/// The manifest stays applied when seeding fails.
+pub async fn init(
+    db: &Pool<Sqlite>,
+    template: &ProjectTemplate,
+) -> Result<InitReport, SchemaError> {
+    let seed = template.seed()?;
+    let plan = manifest::apply(db, &template.manifest()?, false).await?;
+
+    let mut seeded = Vec::new();
+    for table in seed {
+        let rows: i64 = sqlx::query_scalar(&format!(
+            "SELECT count(*) FROM {}",
+            ddl::quote(&table.table)
+        ))
+        .fetch_one(db)
+        .await?;
+        if rows > 0 {
+            continue;
+        }
+
+        let columns: Vec<&str> = table.columns.iter().map(String::as_str).collect();
+        let inserted = import::insert_sqlite(
+            db,
+            &table.table,
+            &columns,
+            &table.rows,
+            DEFAULT_BATCH_SIZE,
+            |_| {},
+        )
+        .await?;
+        seeded.push((table.table, inserted));
+    }
+
+    Ok(InitReport { plan, seeded })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_templates_are_valid() {
+        for template in &TEMPLATES {
+            let manifest = template.manifest().unwrap();
+            manifest.check().unwrap();
+            for seed in template.seed().unwrap() {
+                assert!(
+                    manifest.tables.iter().any(|table| table.name == seed.table),
+                    "{} seeds undeclared table {}",
+                    template.name,
+                    seed.table
+                );
+            }
+            let buckets: serde_json::Value = serde_json::from_str(template.buckets).unwrap();
+            assert!(buckets.is_object(), "{}", template.name);
+        }
+        assert!(find("blog").is_some());
+        assert!(find("wiki").is_none());
+    }
+
+    #[sqlx::test]
+    async fn test_init(db: Pool<Sqlite>) -> Result<(), SchemaError> {
+        let blog = find("blog").unwrap();
+        let report = init(&db, blog).await?;
+        assert!(!report.plan.is_empty());
+        assert_eq!(
+            report.seeded,
+            [("posts".to_string(), 2), ("comments".to_string(), 1)]
+        );
+
+        // Running it again leaves the seeded tables alone.
+        let again = init(&db, blog).await?;
+        assert!(again.plan.is_empty());
+        assert!(again.seeded.is_empty());
+        let posts: i64 = sqlx::query_scalar("SELECT count(*) FROM posts")
+            .fetch_one(&db)
+            .await?;
+        assert_eq!(posts, 2);
+
+        // A second template's manifest conflicts with the applied one.
+        assert!(matches!(
+            init(&db, find("crm").unwrap()).await,
+            Err(SchemaError::Conflict(_))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,388 @@
+//! # Trash
+//!
+//! Tables with a [`DELETED_AT`] column support soft deletes: [`Trash::soft_delete`]
+//! stamps the column with the time of deletion, in seconds since the Unix epoch, and
+//! leaves the row in place. Rows with a `deleted_at` are in the trash until they are
+//! restored, which clears the column, or purged for good.
+//!
+//! The `/admin/trash` routes in [`crate::admin`] list the trash across tables, restore
+//! and purge rows. [`spawn_trash_purge`] empties the trash of rows deleted more than
+//! [`Trash::with_purge_after`] days ago.
+//!
+//! Rows are identified by their [`PRIMARY_KEY`] column. The
+//! [`PalmeraClient`](crate::embedded::PalmeraClient) hides the rows in the trash and
+//! moves the records it deletes there.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # async fn run(db: sqlx::Pool<sqlx::Sqlite>) -> Result<(), Box<dyn std::error::Error>> {
+//! use std::{sync::Arc, time::Duration};
+//! use palmera_database::trash::{Trash, spawn_trash_purge};
+//!
+//! let trash = Arc::new(Trash::new(db).with_purge_after(14));
+//! trash.soft_delete("posts", "1", chrono::Utc::now()).await?;
+//!
+//! spawn_trash_purge(trash, Duration::from_secs(3600));
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite, types::Json};
+use tokio::task::JoinHandle;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    error::StoreError,
+    postgres::batch::PRIMARY_KEY,
+    retention::PurgeReport,
+    sqlite::helpers::{quote_ident, row_json, table_columns},
+};
+
+/// Column marking a row as soft deleted.
+pub const DELETED_AT: &str = "deleted_at";
+
+/// Rows kept in the trash unless configured otherwise.
+pub const DEFAULT_PURGE_AFTER_DAYS: u32 = 30;
+
+/// Rows listed when a filter has no limit.
+pub const DEFAULT_LIST_LIMIT: u32 = 100;
+
+/// A soft-deleted row.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct TrashedRecord {
+    pub table: String,
+    #[schema(value_type = Object)]
+    pub id: serde_json::Value,
+    pub deleted_at: DateTime<Utc>,
+    /// The row as a JSON object keyed by column.
+    #[schema(value_type = Object)]
+    pub record: serde_json::Value,
+}
+
+/// Narrows [`Trash::list`].
+#[derive(Debug, Clone, Default, Deserialize, IntoParams, PartialEq)]
+pub struct TrashFilter {
+    /// Only rows of this table.
+    pub table: Option<String>,
+    /// Only rows deleted at or after this time.
+    pub deleted_after: Option<DateTime<Utc>>,
+    /// Only rows deleted before this time.
+    pub deleted_before: Option<DateTime<Utc>>,
+    /// Maximum number of rows, 100 by default.
+    pub limit: Option<u32>,
+}
+
+/// Soft deletes of the tables of a SQLite database.
+#[derive(Debug, Clone)]
+pub struct Trash {
+    db: Pool<Sqlite>,
+    purge_after_days: u32,
+}
+
+impl Trash {
+    /// Manages the trash of `db`, purging rows deleted more than
+    /// [`DEFAULT_PURGE_AFTER_DAYS`] ago.
+    pub fn new(db: Pool<Sqlite>) -> Self {
+        Self {
+            db,
+            purge_after_days: DEFAULT_PURGE_AFTER_DAYS,
+        }
+    }
+
+    pub fn with_purge_after(mut self, days: u32) -> Self {
+        self.purge_after_days = days;
+        self
+    }
+
+    /// Returns the tables supporting soft deletes, ordered by name.
+    pub async fn tables(&self) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query_scalar(
+            "SELECT m.name FROM sqlite_master m \
+             WHERE m.type = 'table' AND m.name NOT LIKE '\\_%' ESCAPE '\\' \
+               AND m.name NOT LIKE 'sqlite\\_%' ESCAPE '\\' \
+               AND EXISTS (SELECT 1 FROM pragma_table_info(m.name) WHERE name = ?) \
+             ORDER BY m.name",
+        )
+        .bind(DELETED_AT)
+        .fetch_all(&self.db)
+        .await
+    }
+
+    /// Moves the row of `table` identified by `id` to the trash, returning whether
+    /// there was such a row outside the trash.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`StoreError::Invalid`] if the table doesn't support soft deletes.
+    pub async fn soft_delete(
+        &self,
+        table: &str,
+        id: &str,
+        now: DateTime<Utc>,
+    ) -> Result<bool, StoreError> {
+        self.check_table(table).await?;
+        let result = sqlx::query(&format!(
+            "UPDATE {} SET {} = ? WHERE {} = ? AND {} IS NULL",
+            quote_ident(table),
+            quote_ident(DELETED_AT),
+            quote_ident(PRIMARY_KEY),
+            quote_ident(DELETED_AT),
+        ))
+        .bind(now.timestamp())
+        .bind(id)
+        .execute(&self.db)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Lists the trash, most recently deleted first.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`StoreError::Invalid`] if the filter names a table that doesn't
+    /// support soft deletes.
+    pub async fn list(&self, filter: &TrashFilter) -> Result<Vec<TrashedRecord>, StoreError> {
+        let tables = match &filter.table {
+            Some(table) => {
+                self.check_table(table).await?;
+                vec![table.clone()]
+            }
+            None => self.tables().await?,
+        };
+        let limit = filter.limit.unwrap_or(DEFAULT_LIST_LIMIT) as usize;
+
+        let mut records = Vec::new();
+        for table in tables {
+            let columns = table_columns(&self.db, &table).await?;
+            let deleted_at = quote_ident(DELETED_AT);
+            let rows: Vec<(i64, Json<serde_json::Value>)> = sqlx::query_as(&format!(
+                "SELECT {deleted_at}, {} FROM {} WHERE {deleted_at} IS NOT NULL \
+                 AND (?1 IS NULL OR {deleted_at} >= ?1) AND (?2 IS NULL OR {deleted_at} < ?2) \
+                 ORDER BY {deleted_at} DESC LIMIT ?3",
+                row_json(&columns),
+                quote_ident(&table),
+            ))
+            .bind(filter.deleted_after.map(|time| time.timestamp()))
+            .bind(filter.deleted_before.map(|time| time.timestamp()))
+            .bind(limit as i64)
+            .fetch_all(&self.db)
+            .await?;
+
+            records.extend(
+                rows.into_iter()
+                    .map(|(deleted_at, Json(record))| TrashedRecord {
+                        table: table.clone(),
+                        id: record[PRIMARY_KEY].clone(),
+                        deleted_at: DateTime::from_timestamp(deleted_at, 0).unwrap_or_default(),
+                        record,
+                    }),
+            );
+        }
+
+        records.sort_by_key(|record| std::cmp::Reverse(record.deleted_at));
+        records.truncate(limit);
+        Ok(records)
+    }
+
+    /// Takes the row of `table` identified by `id` out of the trash, returning whether
+    /// it was in the trash.
+    pub async fn restore(&self, table: &str, id: &str) -> Result<bool, StoreError> {
+        self.check_table(table).await?;
+        let result = sqlx::query(&format!(
+            "UPDATE {} SET {} = NULL WHERE {} = ? AND {} IS NOT NULL",
+            quote_ident(table),
+            quote_ident(DELETED_AT),
+            quote_ident(PRIMARY_KEY),
+            quote_ident(DELETED_AT),
+        ))
+        .bind(id)
+        .execute(&self.db)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Permanently deletes the row of `table` identified by `id` if it is in the trash,
+    /// returning whether it was.
+    pub async fn purge(&self, table: &str, id: &str) -> Result<bool, StoreError> {
+        self.check_table(table).await?;
+        let result = sqlx::query(&format!(
+            "DELETE FROM {} WHERE {} = ? AND {} IS NOT NULL",
+            quote_ident(table),
+            quote_ident(PRIMARY_KEY),
+            quote_ident(DELETED_AT),
+        ))
+        .bind(id)
+        .execute(&self.db)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Permanently deletes the rows that have been in the trash for longer than the
+    /// purge delay at `now`. Tables without expired rows are left out of the reports.
+    pub async fn purge_expired(&self, now: DateTime<Utc>) -> Result<Vec<PurgeReport>, sqlx::Error> {
+        let cutoff = now - chrono::Duration::days(self.purge_after_days.into());
+
+        let mut reports = Vec::new();
+        for table in self.tables().await? {
+            let result = sqlx::query(&format!(
+                "DELETE FROM {} WHERE {} < ?",
+                quote_ident(&table),
+                quote_ident(DELETED_AT),
+            ))
+            .bind(cutoff.timestamp())
+            .execute(&self.db)
+            .await?;
+
+            if result.rows_affected() > 0 {
+                reports.push(PurgeReport {
+                    table_name: table,
+                    purged: result.rows_affected(),
+                });
+            }
+        }
+        Ok(reports)
+    }
+
+    async fn check_table(&self, table: &str) -> Result<(), StoreError> {
+        let columns = table_columns(&self.db, table).await?;
+        if !columns.iter().any(|column| column == DELETED_AT) {
+            return Err(StoreError::Invalid(format!(
+                "table {table:?} does not support soft deletes"
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Purges expired rows from the trash every `interval`.
+///
+/// Failures are logged and retried on the next tick.
+pub fn spawn_trash_purge(trash: Arc<Trash>, interval: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+
+        loop {
+            ticker.tick().await;
+
+            match trash.purge_expired(Utc::now()).await {
+                Ok(reports) => {
+                    for report in reports {
+                        tracing::debug!(
+                            table = %report.table_name,
+                            purged = report.purged,
+                            "trash purged"
+                        );
+                    }
+                }
+                Err(err) => tracing::warn!(error = %err, "trash purge failed"),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    async fn setup(db: &Pool<Sqlite>) -> sqlx::Result<()> {
+        sqlx::query("CREATE TABLE posts (id INTEGER PRIMARY KEY, title TEXT, deleted_at INTEGER)")
+            .execute(db)
+            .await?;
+        sqlx::query("CREATE TABLE notes (id TEXT PRIMARY KEY, body TEXT, deleted_at INTEGER)")
+            .execute(db)
+            .await?;
+        sqlx::query("CREATE TABLE tags (id INTEGER PRIMARY KEY, name TEXT)")
+            .execute(db)
+            .await?;
+        sqlx::query("INSERT INTO posts (title) VALUES ('first'), ('second'), ('third')")
+            .execute(db)
+            .await?;
+        sqlx::query("INSERT INTO notes (id, body) VALUES ('n1', 'hello')")
+            .execute(db)
+            .await?;
+        Ok(())
+    }
+
+    fn day(day: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2025, 6, day, 0, 0, 0).unwrap()
+    }
+
+    #[sqlx::test]
+    async fn test_trash_workflow(db: Pool<Sqlite>) -> Result<(), StoreError> {
+        setup(&db).await?;
+        let trash = Trash::new(db.clone());
+        assert_eq!(trash.tables().await?, ["notes", "posts"]);
+
+        assert!(trash.soft_delete("posts", "1", day(1)).await?);
+        assert!(!trash.soft_delete("posts", "1", day(2)).await?);
+        assert!(trash.soft_delete("posts", "2", day(3)).await?);
+        assert!(trash.soft_delete("notes", "n1", day(2)).await?);
+        assert!(matches!(
+            trash.soft_delete("tags", "1", day(1)).await,
+            Err(StoreError::Invalid(_))
+        ));
+
+        let all = trash.list(&TrashFilter::default()).await?;
+        let ids = all
+            .iter()
+            .map(|record| record.id.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, [serde_json::json!(2), "n1".into(), 1.into()]);
+        assert_eq!(all[0].record["title"], "second");
+        assert_eq!(all[0].deleted_at, day(3));
+
+        let filtered = trash
+            .list(&TrashFilter {
+                table: Some("posts".to_string()),
+                deleted_before: Some(day(3)),
+                ..Default::default()
+            })
+            .await?;
+        assert_eq!(filtered.len(), 1);
+        assert_eq!(filtered[0].id, 1);
+
+        assert!(trash.restore("posts", "1").await?);
+        assert!(!trash.restore("posts", "3").await?);
+        assert!(!trash.purge("posts", "3").await?);
+        assert!(trash.purge("notes", "n1").await?);
+
+        let remaining: Vec<String> = sqlx::query_scalar("SELECT title FROM posts ORDER BY id")
+            .fetch_all(&db)
+            .await?;
+        assert_eq!(remaining, ["first", "second", "third"]);
+        assert_eq!(trash.list(&TrashFilter::default()).await?.len(), 1);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_purge_expired(db: Pool<Sqlite>) -> Result<(), StoreError> {
+        setup(&db).await?;
+        let trash = Trash::new(db.clone()).with_purge_after(7);
+        trash.soft_delete("posts", "1", day(1)).await?;
+        trash.soft_delete("posts", "2", day(10)).await?;
+
+        let reports = trash.purge_expired(day(12)).await?;
+        assert_eq!(
+            reports,
+            [PurgeReport {
+                table_name: "posts".to_string(),
+                purged: 1
+            }]
+        );
+
+        let titles: Vec<String> = sqlx::query_scalar("SELECT title FROM posts ORDER BY id")
+            .fetch_all(&db)
+            .await?;
+        assert_eq!(titles, ["second", "third"]);
+        Ok(())
+    }
+}
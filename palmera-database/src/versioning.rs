@@ -0,0 +1,297 @@
+//! # API versions
+//!
+//! Breaking changes to the REST API ship as a new version next to the old one, so
+//! clients can move over before the old routes go away. [`ApiVersions`] nests each
+//! version's router under its prefix (`/v1`, `/v2`, optionally below a base path such
+//! as `/api`) and adds the [`ApiVersion`] being served to the request extensions, so
+//! handlers shared between versions can tell them apart.
+//!
+//! Routes on their way out are wrapped with [`deprecate`], either one route at a time
+//! or a whole version. Their responses then carry
+//!
+//! - `Deprecation: @<unix time>` ([RFC 9745]), the date the route was deprecated,
+//! - `Sunset: <HTTP date>` ([RFC 8594]), when it will stop working, if known, and
+//! - `Link: <...>; rel="deprecation"`, pointing to migration notes, if given,
+//!
+//! and their operations are marked `deprecated` in the OpenAPI document, with the
+//! sunset in an `x-sunset` extension.
+//!
+//! [RFC 9745]: https://www.rfc-editor.org/rfc/rfc9745
+//! [RFC 8594]: https://www.rfc-editor.org/rfc/rfc8594
+//!
+//! # Example
+//!
+//! ```rust
+//! use chrono::{TimeZone, Utc};
+//! use palmera_database::versioning::{ApiVersions, Deprecation, deprecate};
+//! use utoipa_axum::router::OpenApiRouter;
+//!
+//! # fn routes() -> OpenApiRouter { OpenApiRouter::new() }
+//! let v1 = deprecate(
+//!     routes(),
+//!     Deprecation::new(Utc.with_ymd_and_hms(2025, 7, 1, 0, 0, 0).unwrap())
+//!         .sunset(Utc.with_ymd_and_hms(2026, 1, 1, 0, 0, 0).unwrap())
+//!         .link("https://example.com/docs/migrate-to-v2"),
+//! );
+//! let (router, openapi) = ApiVersions::new()
+//!     .prefix("/api")
+//!     .version(1, v1)
+//!     .version(2, routes())
+//!     .into_router()
+//!     .split_for_parts();
+//! ```
+
+use std::sync::Arc;
+
+use axum::{
+    Extension,
+    extract::{Request, State},
+    http::{HeaderMap, HeaderValue, header},
+    middleware::{self, Next},
+    response::Response,
+};
+use chrono::{DateTime, Utc};
+use utoipa::openapi::{Deprecated, path::Operation};
+use utoipa_axum::router::OpenApiRouter;
+
+/// Version of the API a request is served by, added to the request extensions by
+/// [`ApiVersions`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ApiVersion(pub u32);
+
+impl ApiVersion {
+    /// Path prefix of the version, e.g. `/v1`.
+    pub fn prefix(&self) -> String {
+        format!("/v{}", self.0)
+    }
+}
+
+/// When and how a route is deprecated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Deprecation {
+    /// When the route was, or will be, deprecated.
+    pub date: DateTime<Utc>,
+    /// When the route will stop working.
+    pub sunset: Option<DateTime<Utc>>,
+    /// Where clients learn how to migrate.
+    pub link: Option<String>,
+}
+
+impl Deprecation {
+    pub fn new(date: DateTime<Utc>) -> Self {
+        Self {
+            date,
+            sunset: None,
+            link: None,
+        }
+    }
+
+    pub fn sunset(mut self, sunset: DateTime<Utc>) -> Self {
+        self.sunset = Some(sunset);
+        self
+    }
+
+    pub fn link(mut self, link: impl Into<String>) -> Self {
+        self.link = Some(link.into());
+        self
+    }
+
+    /// Adds the `Deprecation`, `Sunset` and `Link` headers to `headers`.
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        let deprecation = format!("@{}", self.date.timestamp());
+        if let Ok(value) = HeaderValue::from_str(&deprecation) {
+            headers.insert("deprecation", value);
+        }
+        if let Some(sunset) = self.sunset {
+            let sunset = sunset.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+            if let Ok(value) = HeaderValue::from_str(&sunset) {
+                headers.insert("sunset", value);
+            }
+        }
+        if let Some(link) = &self.link {
+            let link = format!("<{link}>; rel=\"deprecation\"");
+            if let Ok(value) = HeaderValue::from_str(&link) {
+                headers.append(header::LINK, value);
+            }
+        }
+    }
+
+    /// Marks `operation` deprecated in the OpenAPI document.
+    fn document(&self, operation: &mut Operation) {
+        operation.deprecated = Some(Deprecated::True);
+        if let Some(sunset) = self.sunset {
+            operation
+                .extensions
+                .get_or_insert_with(Default::default)
+                .insert("x-sunset".to_string(), sunset.to_rfc3339().into());
+        }
+    }
+}
+
+async fn emit_deprecation(
+    State(deprecation): State<Arc<Deprecation>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let mut response = next.run(request).await;
+    deprecation.apply(response.headers_mut());
+    response
+}
+
+/// Deprecates every route of `router`: their responses carry the deprecation headers
+/// and their operations are marked deprecated in its OpenAPI document.
+pub fn deprecate(mut router: OpenApiRouter, deprecation: Deprecation) -> OpenApiRouter {
+    for item in router.get_openapi_mut().paths.paths.values_mut() {
+        let operations = [
+            &mut item.get,
+            &mut item.put,
+            &mut item.post,
+            &mut item.delete,
+            &mut item.options,
+            &mut item.head,
+            &mut item.patch,
+            &mut item.trace,
+        ];
+        for operation in operations.into_iter().flatten() {
+            deprecation.document(operation);
+        }
+    }
+
+    router.layer(middleware::from_fn_with_state(
+        Arc::new(deprecation),
+        emit_deprecation,
+    ))
+}
+
+/// The versions of an API, served side by side under their prefixes.
+#[derive(Default)]
+pub struct ApiVersions {
+    prefix: String,
+    versions: Vec<(ApiVersion, OpenApiRouter)>,
+}
+
+impl ApiVersions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Base path the version prefixes are nested under, e.g. `/api` for `/api/v1`.
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Serves `router` as version `number`, under `/v{number}`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if version `number` was already added.
+    pub fn version(mut self, number: u32, router: OpenApiRouter) -> Self {
+        let version = ApiVersion(number);
+        assert!(
+            self.versions
+                .iter()
+                .all(|(existing, _)| *existing != version),
+            "API version {number} is added twice"
+        );
+        self.versions.push((version, router));
+        self
+    }
+
+    /// The highest version added.
+    pub fn latest(&self) -> Option<ApiVersion> {
+        self.versions.iter().map(|(version, _)| *version).max()
+    }
+
+    /// Full path of `path` in `version`, e.g. `/api/v2/records`.
+    pub fn path(&self, version: ApiVersion, path: &str) -> String {
+        format!("{}{}{}", self.prefix, version.prefix(), path)
+    }
+
+    /// A router serving every version, with their OpenAPI paths prefixed alike.
+    pub fn into_router(self) -> OpenApiRouter {
+        let mut router = OpenApiRouter::new();
+        for (version, routes) in self.versions {
+            let path = format!("{}{}", self.prefix, version.prefix());
+            router = router.nest(&path, routes.layer(Extension(version)));
+        }
+        router
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::StatusCode};
+    use chrono::TimeZone;
+    use tower::ServiceExt;
+    use utoipa_axum::routes;
+
+    /// Lists posts.
+    #[utoipa::path(get, path = "/posts", responses((status = 200, body = String)))]
+    async fn list_posts(Extension(version): Extension<ApiVersion>) -> String {
+        format!("posts v{}", version.0)
+    }
+
+    fn routes() -> OpenApiRouter {
+        OpenApiRouter::new().routes(routes!(list_posts))
+    }
+
+    fn date(year: i32, month: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(year, month, 1, 0, 0, 0).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_versions() {
+        let deprecation = Deprecation::new(date(2025, 7))
+            .sunset(date(2026, 1))
+            .link("https://example.com/migrate");
+        let versions = ApiVersions::new()
+            .prefix("/api/")
+            .version(1, deprecate(routes(), deprecation))
+            .version(2, routes());
+        assert_eq!(versions.latest(), Some(ApiVersion(2)));
+        assert_eq!(versions.path(ApiVersion(2), "/posts"), "/api/v2/posts");
+        let (router, openapi) = versions.into_router().split_for_parts();
+
+        let call = |path: &'static str| {
+            let router = router.clone();
+            async move {
+                let request = Request::get(path).body(Body::empty()).unwrap();
+                router.oneshot(request).await.unwrap()
+            }
+        };
+
+        let old = call("/api/v1/posts").await;
+        assert_eq!(old.status(), StatusCode::OK);
+        assert_eq!(old.headers()["deprecation"], "@1751328000");
+        assert_eq!(old.headers()["sunset"], "Thu, 01 Jan 2026 00:00:00 GMT");
+        assert_eq!(
+            old.headers()[header::LINK],
+            "<https://example.com/migrate>; rel=\"deprecation\""
+        );
+        let body = axum::body::to_bytes(old.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"posts v1");
+
+        let new = call("/api/v2/posts").await;
+        assert_eq!(new.status(), StatusCode::OK);
+        assert!(!new.headers().contains_key("deprecation"));
+
+        let v1 = openapi.paths.paths["/api/v1/posts"].get.as_ref().unwrap();
+        assert!(matches!(v1.deprecated, Some(Deprecated::True)));
+        assert_eq!(
+            v1.extensions.as_ref().unwrap()["x-sunset"],
+            "2026-01-01T00:00:00+00:00"
+        );
+        let v2 = openapi.paths.paths["/api/v2/posts"].get.as_ref().unwrap();
+        assert!(v2.deprecated.is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "added twice")]
+    fn test_duplicate_version() {
+        let _ = ApiVersions::new().version(1, routes()).version(1, routes());
+    }
+}
@@ -0,0 +1,101 @@
+//! Compiles the Rust models [`codegen::rust`] generates for a schema with names Rust
+//! can't use as they are, kept in `tests/codegen/models.rs`, and checks that the file is
+//! what the generator currently emits. Regenerate it with `UPDATE_MODELS=1 cargo test`.
+
+use std::path::Path;
+
+use palmera_database::{
+    codegen,
+    fields::{Field, FieldKind, ValueType},
+    manifest::SchemaManifest,
+};
+
+#[allow(dead_code, clippy::all)]
+#[rustfmt::skip]
+#[path = "codegen/models.rs"]
+mod models;
+
+const MANIFEST: &str = r#"
+    version = 1
+
+    [[tables]]
+    name = "blog_posts"
+    columns = [
+      { name = "id", data_type = "integer", primary_key = true },
+      { name = "title", data_type = "text", not_null = true },
+      { name = "type", data_type = "text" },
+      { name = "gen", data_type = "text" },
+      { name = "isDraft", data_type = "boolean", not_null = true },
+      { name = "is_draft", data_type = "integer" },
+      { name = "meta", data_type = "json" },
+      { name = "published_at", data_type = "timestamp" },
+    ]
+
+    [[tables]]
+    name = "blog-posts"
+    columns = [
+      { name = "id", data_type = "text", primary_key = true },
+      { name = "body", data_type = "blob", not_null = true },
+      { name = "score", data_type = "real" },
+    ]
+
+    [[tables]]
+    name = "session"
+    columns = [
+      { name = "self", data_type = "text" },
+      { name = "try", data_type = "boolean" },
+    ]
+"#;
+
+#[test]
+fn test_generated_models() {
+    let schema = SchemaManifest::parse(Path::new("schema.toml"), MANIFEST).unwrap();
+    let fields = [
+        Field {
+            table_name: "blog_posts".into(),
+            column_name: "type".into(),
+            kind: FieldKind::select(["news", "how-to"]),
+        },
+        Field {
+            table_name: "blog_posts".into(),
+            column_name: "slug".into(),
+            kind: FieldKind::computed("lower(title)", ValueType::String),
+        },
+        Field {
+            table_name: "blog_posts".into(),
+            column_name: "featured".into(),
+            kind: FieldKind::computed("title LIKE '%!'", ValueType::Boolean),
+        },
+    ];
+    let code = codegen::rust(&schema.tables, &fields);
+
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/codegen/models.rs");
+    if std::env::var_os("UPDATE_MODELS").is_some() {
+        std::fs::write(&path, &code).unwrap();
+    }
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        code,
+        "tests/codegen/models.rs is stale, regenerate it with UPDATE_MODELS=1"
+    );
+
+    // The names that collided or are keywords.
+    let post = models::BlogPosts {
+        id: None,
+        title: "Hello".into(),
+        r#type: Some(models::BlogPostsType::HowTo),
+        r#gen: None,
+        is_draft: true,
+        is_draft_2: Some(1),
+        meta: None,
+        published_at: None,
+        slug: None,
+        featured: None,
+    };
+    let record = codegen::encode(&post).unwrap();
+    assert_eq!(record["type"], "how-to");
+    assert_eq!(record["isDraft"], true);
+    assert_eq!(record["is_draft"], 1);
+    assert_eq!(models::BlogPosts2::TABLE, "blog-posts");
+    assert_eq!(models::Session2::TABLE, "session");
+}
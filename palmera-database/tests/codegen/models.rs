@@ -0,0 +1,150 @@
+// Generated by `palmera-database codegen --lang rust`. Do not edit.
+
+#[allow(unused_imports)]
+use palmera_database::embedded::{ClientError, Session};
+use serde::{Deserialize, Serialize};
+
+/// Values of `type`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+pub enum BlogPostsType {
+    #[serde(rename = "news")]
+    #[sqlx(rename = "news")]
+    News,
+    #[serde(rename = "how-to")]
+    #[sqlx(rename = "how-to")]
+    HowTo,
+}
+
+/// A record of table `blog_posts`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, sqlx::FromRow)]
+pub struct BlogPosts {
+    #[serde(default)]
+    pub id: Option<i64>,
+    pub title: String,
+    #[serde(default)]
+    #[serde(rename = "type")]
+    #[sqlx(rename = "type")]
+    pub r#type: Option<BlogPostsType>,
+    #[serde(default)]
+    #[serde(rename = "gen")]
+    #[sqlx(rename = "gen")]
+    pub r#gen: Option<String>,
+    #[serde(deserialize_with = "palmera_database::codegen::de::bool")]
+    #[serde(rename = "isDraft")]
+    #[sqlx(rename = "isDraft")]
+    pub is_draft: bool,
+    #[serde(default)]
+    #[serde(rename = "is_draft")]
+    #[sqlx(rename = "is_draft")]
+    pub is_draft_2: Option<i64>,
+    #[serde(default, deserialize_with = "palmera_database::codegen::de::option_json")]
+    pub meta: Option<sqlx::types::Json<serde_json::Value>>,
+    #[serde(default)]
+    pub published_at: Option<String>,
+    #[serde(default, skip_serializing)]
+    #[sqlx(default)]
+    pub slug: Option<String>,
+    #[serde(default, skip_serializing, deserialize_with = "palmera_database::codegen::de::option_bool")]
+    #[sqlx(default)]
+    pub featured: Option<bool>,
+}
+
+impl BlogPosts {
+    pub const TABLE: &'static str = "blog_posts";
+
+    /// Lists the records visible to `session`, ordered by id.
+    pub async fn list(session: &Session<'_>) -> Result<Vec<Self>, ClientError> {
+        session
+            .list(Self::TABLE)
+            .await?
+            .into_iter()
+            .map(palmera_database::codegen::decode)
+            .collect()
+    }
+
+    /// Returns the record with `id`, if `session` can see it.
+    pub async fn get(session: &Session<'_>, id: i64) -> Result<Self, ClientError> {
+        palmera_database::codegen::decode(session.get(Self::TABLE, id).await?)
+    }
+
+    /// Inserts the record and returns it as stored.
+    pub async fn create(&self, session: &Session<'_>) -> Result<Self, ClientError> {
+        let record = palmera_database::codegen::encode(self)?;
+        palmera_database::codegen::decode(session.create(Self::TABLE, record).await?)
+    }
+
+    /// Writes every column of the record and returns it as stored.
+    pub async fn update(&self, session: &Session<'_>) -> Result<Self, ClientError> {
+        let record = palmera_database::codegen::encode(self)?;
+        let id = self.id.ok_or(ClientError::NotFound)?;
+        palmera_database::codegen::decode(session.update(Self::TABLE, id, record).await?)
+    }
+
+    /// Deletes the record with `id`, returning it as it was.
+    pub async fn delete(session: &Session<'_>, id: i64) -> Result<Self, ClientError> {
+        palmera_database::codegen::decode(session.delete(Self::TABLE, id).await?)
+    }
+}
+
+/// A record of table `blog-posts`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, sqlx::FromRow)]
+pub struct BlogPosts2 {
+    pub id: String,
+    pub body: Vec<u8>,
+    #[serde(default)]
+    pub score: Option<f64>,
+}
+
+impl BlogPosts2 {
+    pub const TABLE: &'static str = "blog-posts";
+
+    /// Lists the records visible to `session`, ordered by id.
+    pub async fn list(session: &Session<'_>) -> Result<Vec<Self>, ClientError> {
+        session
+            .list(Self::TABLE)
+            .await?
+            .into_iter()
+            .map(palmera_database::codegen::decode)
+            .collect()
+    }
+
+    /// Returns the record with `id`, if `session` can see it.
+    pub async fn get(session: &Session<'_>, id: String) -> Result<Self, ClientError> {
+        palmera_database::codegen::decode(session.get(Self::TABLE, id).await?)
+    }
+
+    /// Inserts the record and returns it as stored.
+    pub async fn create(&self, session: &Session<'_>) -> Result<Self, ClientError> {
+        let record = palmera_database::codegen::encode(self)?;
+        palmera_database::codegen::decode(session.create(Self::TABLE, record).await?)
+    }
+
+    /// Writes every column of the record and returns it as stored.
+    pub async fn update(&self, session: &Session<'_>) -> Result<Self, ClientError> {
+        let record = palmera_database::codegen::encode(self)?;
+        let id = self.id.clone();
+        palmera_database::codegen::decode(session.update(Self::TABLE, id, record).await?)
+    }
+
+    /// Deletes the record with `id`, returning it as it was.
+    pub async fn delete(session: &Session<'_>, id: String) -> Result<Self, ClientError> {
+        palmera_database::codegen::decode(session.delete(Self::TABLE, id).await?)
+    }
+}
+
+/// A record of table `session`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Session2 {
+    #[serde(default)]
+    #[serde(rename = "self")]
+    #[sqlx(rename = "self")]
+    pub self_: Option<String>,
+    #[serde(default, deserialize_with = "palmera_database::codegen::de::option_bool")]
+    #[serde(rename = "try")]
+    #[sqlx(rename = "try")]
+    pub r#try: Option<bool>,
+}
+
+impl Session2 {
+    pub const TABLE: &'static str = "session";
+}
@@ -0,0 +1,214 @@
+//! # File access control
+//!
+//! Decides who may read and change the files of a [`Bucket`](crate::bucket::Bucket):
+//!
+//! - Files of a [`Visibility::Public`] bucket are read without authenticating, and are
+//!   served with [`PUBLIC_CACHE_CONTROL`] unless the bucket sets its own header.
+//! - Reading a [`Visibility::Private`] bucket requires the caller to authenticate, and
+//!   is served with [`PRIVATE_CACHE_CONTROL`] so shared caches never keep the bytes.
+//! - Uploads and deletes always require the caller to authenticate.
+//!
+//! Callers authenticate as the [`AuthClaims`] extractor checks them: a valid bearer
+//! token, or session cookie, of an account that is still active.
+//!
+//! When the caller has to authenticate, the bucket's [`FilePolicy`], if it has one,
+//! decides next, before any bytes are read or written. A policy usually looks up the
+//! record the file is linked to, e.g. with [`FilePolicy::owner`] to let only the record's
+//! owner through. A bucket without a policy lets any authenticated caller read its files
+//! and upload new ones, but only the user who uploaded a file replace or delete it; see
+//! [`FileMetadata::owner`](crate::bucket::FileMetadata::owner).
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! use palmera_storage::{access::FilePolicy, bucket::Buckets};
+//!
+//! # async fn owner_of_invoice(name: &str) -> Option<uuid::Uuid> { None }
+//! # fn example(buckets: Buckets) -> Result<Buckets, palmera_storage::bucket::BucketError> {
+//! // Invoices are named after the order they belong to, e.g. `1042.pdf`.
+//! let buckets = buckets.with_policy(
+//!     "invoices",
+//!     FilePolicy::owner(|file| async move { owner_of_invoice(file.name.as_deref()?).await }),
+//! )?;
+//! # Ok(buckets)
+//! # }
+//! ```
+
+use std::{fmt, future::Future, pin::Pin, sync::Arc};
+
+use axum::http::StatusCode;
+use palmera_auth::{extract::AuthClaims, jwt::JWTClaims};
+use uuid::Uuid;
+
+use crate::bucket::{BucketError, Visibility};
+
+/// `Cache-Control` of files served from public buckets by default.
+pub const PUBLIC_CACHE_CONTROL: &str = "public, max-age=604800";
+
+/// `Cache-Control` of files served from private buckets by default.
+pub const PRIVATE_CACHE_CONTROL: &str = "private, no-store";
+
+/// What a request does with a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileAction {
+    Read,
+    Write,
+    Delete,
+}
+
+/// A file a request wants to act on. `name` is `None` when listing the bucket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileRef {
+    pub bucket: String,
+    pub name: Option<String>,
+    pub action: FileAction,
+}
+
+type Check =
+    Arc<dyn Fn(JWTClaims, FileRef) -> Pin<Box<dyn Future<Output = bool> + Send>> + Send + Sync>;
+
+/// Decides whether an authenticated caller may act on a file.
+#[derive(Clone)]
+pub struct FilePolicy(Check);
+
+impl fmt::Debug for FilePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("FilePolicy")
+    }
+}
+
+impl FilePolicy {
+    /// Lets a caller act on a file when `check` resolves to `true`.
+    pub fn new<F, Fut>(check: F) -> Self
+    where
+        F: Fn(JWTClaims, FileRef) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = bool> + Send + 'static,
+    {
+        Self(Arc::new(move |claims, file| Box::pin(check(claims, file))))
+    }
+
+    /// Lets only the owner of the record a file is linked to act on it. `owner_of`
+    /// resolves the owner's user id, `None` if the file isn't linked to a record, which
+    /// denies access. Listing the bucket is denied, as it spans many records.
+    pub fn owner<F, Fut>(owner_of: F) -> Self
+    where
+        F: Fn(FileRef) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Option<Uuid>> + Send + 'static,
+    {
+        let owner_of = Arc::new(owner_of);
+        Self::new(move |claims, file| {
+            let owner_of = owner_of.clone();
+            async move {
+                if file.name.is_none() {
+                    return false;
+                }
+                owner_of(file).await == Some(claims.subject)
+            }
+        })
+    }
+
+    pub async fn allows(&self, claims: &JWTClaims, file: &FileRef) -> bool {
+        (self.0)(claims.clone(), file.clone()).await
+    }
+}
+
+/// Checks that a request may perform `file.action`, returning the caller's claims when
+/// it had to authenticate. `claims` is the outcome of the [`AuthClaims`] extractor.
+///
+/// # Errors
+///
+/// Returns [`BucketError::Unauthorized`] if the caller didn't authenticate, and
+/// [`BucketError::Forbidden`] if their account is disabled or `policy` turns them away.
+pub async fn authorize(
+    claims: Result<AuthClaims, StatusCode>,
+    visibility: Visibility,
+    policy: Option<&FilePolicy>,
+    file: &FileRef,
+) -> Result<Option<JWTClaims>, BucketError> {
+    if visibility == Visibility::Public && file.action == FileAction::Read {
+        return Ok(None);
+    }
+
+    let AuthClaims(claims) = claims.map_err(|status| match status {
+        StatusCode::FORBIDDEN => BucketError::Forbidden,
+        _ => BucketError::Unauthorized,
+    })?;
+
+    match policy {
+        Some(policy) if !policy.allows(&claims, file).await => Err(BucketError::Forbidden),
+        _ => Ok(Some(claims)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use palmera_auth::AuthConfig;
+
+    const SECRET: &str = "a-secret-that-is-at-least-32-bytes-long";
+
+    fn claims(subject: Uuid) -> Result<AuthClaims, StatusCode> {
+        let config = AuthConfig::builder().secret(SECRET).build().unwrap();
+        let token = config
+            .issue_token(subject, chrono::Duration::minutes(5))
+            .unwrap();
+        Ok(AuthClaims(config.verify_token(&token).unwrap()))
+    }
+
+    fn file(action: FileAction) -> FileRef {
+        FileRef {
+            bucket: "invoices".to_string(),
+            name: Some("1042.pdf".to_string()),
+            action,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_public_reads_need_no_token() {
+        let anonymous = || Err(StatusCode::UNAUTHORIZED);
+        let read = file(FileAction::Read);
+
+        let claims = authorize(anonymous(), Visibility::Public, None, &read).await;
+        assert!(claims.unwrap().is_none());
+
+        let write = authorize(
+            anonymous(),
+            Visibility::Public,
+            None,
+            &file(FileAction::Write),
+        )
+        .await;
+        assert!(matches!(write, Err(BucketError::Unauthorized)));
+        let private = authorize(anonymous(), Visibility::Private, None, &read).await;
+        assert!(matches!(private, Err(BucketError::Unauthorized)));
+        // Disabled accounts are turned away.
+        let disabled =
+            authorize(Err(StatusCode::FORBIDDEN), Visibility::Private, None, &read).await;
+        assert!(matches!(disabled, Err(BucketError::Forbidden)));
+    }
+
+    #[tokio::test]
+    async fn test_owner_policy() {
+        let owner = Uuid::new_v4();
+        let policy = FilePolicy::owner(move |file| async move {
+            (file.name.as_deref() == Some("1042.pdf")).then_some(owner)
+        });
+        let read = file(FileAction::Read);
+
+        let allowed = authorize(claims(owner), Visibility::Private, Some(&policy), &read).await;
+        assert_eq!(allowed.unwrap().unwrap().subject, owner);
+
+        let stranger = authorize(
+            claims(Uuid::new_v4()),
+            Visibility::Private,
+            Some(&policy),
+            &read,
+        )
+        .await;
+        assert!(matches!(stranger, Err(BucketError::Forbidden)));
+
+        let listing = FileRef { name: None, ..read };
+        let listed = authorize(claims(owner), Visibility::Private, Some(&policy), &listing).await;
+        assert!(matches!(listed, Err(BucketError::Forbidden)));
+    }
+}
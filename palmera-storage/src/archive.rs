@@ -0,0 +1,399 @@
+//! # ZIP archives
+//!
+//! `POST /files/archive` of [`router`] downloads several files as one ZIP archive, e.g.
+//! every attachment of a record in one click. The request names the files as
+//! `{bucket}/{name}` keys, or a record whose files an [`AttachmentResolver`] extension
+//! looks up:
+//!
+//! ```json
+//! { "files": ["invoices/1042.pdf", "avatars/ann.png"], "name": "order-1042.zip" }
+//! { "record": "orders/1042" }
+//! ```
+//!
+//! Every key is validated and every file checked against its bucket's access rules
+//! before anything is sent, so an invalid name fails the whole request with `400`, and
+//! a denied file with `401` or `403`. The archive is then
+//! assembled on the fly: files are fetched from their backends one at a time and written
+//! as they arrive, so only one of them is held in memory. Entries are stored
+//! uncompressed, as attachments are mostly already compressed images and documents, and
+//! are named by their key.
+//!
+//! The router expects the `Extension<Arc<Buckets>>` layer of [`crate::bucket::router`].
+
+use std::{collections::HashSet, fmt, future::Future, pin::Pin, sync::Arc};
+
+use axum::{
+    Extension, Json,
+    body::Body,
+    http::{HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use futures::stream;
+use palmera_auth::extract::AuthClaims;
+use serde::Deserialize;
+use utoipa::ToSchema;
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+use crate::{
+    access::FileAction,
+    bucket::{BucketError, Buckets, check_file_name},
+};
+
+/// Most files an archive can hold.
+pub const MAX_ARCHIVE_FILES: usize = 1000;
+
+const DEFAULT_ARCHIVE_NAME: &str = "files.zip";
+
+type Resolve =
+    Arc<dyn Fn(String) -> Pin<Box<dyn Future<Output = Option<Vec<String>>> + Send>> + Send + Sync>;
+
+/// Looks up the `{bucket}/{name}` keys of the files attached to a record, `None` if
+/// there is no such record.
+#[derive(Clone)]
+pub struct AttachmentResolver(Resolve);
+
+impl fmt::Debug for AttachmentResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AttachmentResolver")
+    }
+}
+
+impl AttachmentResolver {
+    pub fn new<F, Fut>(resolve: F) -> Self
+    where
+        F: Fn(String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Option<Vec<String>>> + Send + 'static,
+    {
+        Self(Arc::new(move |record| Box::pin(resolve(record))))
+    }
+
+    pub async fn resolve(&self, record: &str) -> Option<Vec<String>> {
+        (self.0)(record.to_string()).await
+    }
+}
+
+/// Body of `POST /files/archive`, naming either `files` or a `record`.
+#[derive(Debug, Clone, Default, Deserialize, ToSchema, PartialEq, Eq)]
+pub struct ArchiveRequest {
+    /// `{bucket}/{name}` keys of the files to archive.
+    #[serde(default)]
+    pub files: Vec<String>,
+    /// Record whose attachments to archive.
+    pub record: Option<String>,
+    /// File name of the download, `files.zip` by default.
+    pub name: Option<String>,
+}
+
+/// Splits a `{bucket}/{name}` key, checking the file name is one a bucket can hold.
+fn split_key(key: &str) -> Result<(&str, &str), BucketError> {
+    let (bucket, name) = key
+        .split_once('/')
+        .filter(|(bucket, _)| !bucket.is_empty())
+        .ok_or_else(|| BucketError::InvalidName(key.to_string()))?;
+    check_file_name(name)?;
+    Ok((bucket, name))
+}
+
+/// Writes a ZIP archive of stored entries, one entry at a time.
+#[derive(Debug, Default)]
+struct ZipWriter {
+    offset: u64,
+    central_directory: Vec<u8>,
+    entries: u16,
+}
+
+/// ZIP 2.0, which every reader supports.
+const ZIP_VERSION: u16 = 20;
+/// Marks entry names as UTF-8.
+const ZIP_UTF8: u16 = 1 << 11;
+/// 1980-01-01 in MS-DOS format, the earliest date ZIP can express.
+const ZIP_DATE: u16 = (1 << 5) | 1;
+
+fn u32_field(value: u64) -> std::io::Result<u32> {
+    u32::try_from(value).map_err(|_| std::io::Error::other("archive exceeds 4 GiB"))
+}
+
+impl ZipWriter {
+    /// Returns the local header of an entry of `data`, to be written before it.
+    fn entry(&mut self, name: &str, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let crc = crc32fast::hash(data);
+        let size = u32_field(data.len() as u64)?;
+        let offset = u32_field(self.offset)?;
+        let name_len = u16::try_from(name.len())
+            .map_err(|_| std::io::Error::other(format!("{:?} is too long", name)))?;
+
+        let mut header = Vec::with_capacity(30 + name.len());
+        header.extend_from_slice(&0x04034b50u32.to_le_bytes());
+        header.extend_from_slice(&ZIP_VERSION.to_le_bytes());
+        header.extend_from_slice(&ZIP_UTF8.to_le_bytes());
+        // Stored, at midnight of the date.
+        header.extend_from_slice(&[0, 0, 0, 0]);
+        header.extend_from_slice(&ZIP_DATE.to_le_bytes());
+        header.extend_from_slice(&crc.to_le_bytes());
+        header.extend_from_slice(&size.to_le_bytes());
+        header.extend_from_slice(&size.to_le_bytes());
+        header.extend_from_slice(&name_len.to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(name.as_bytes());
+
+        let central = &mut self.central_directory;
+        central.extend_from_slice(&0x02014b50u32.to_le_bytes());
+        central.extend_from_slice(&ZIP_VERSION.to_le_bytes());
+        central.extend_from_slice(&header[4..30]);
+        // Comment length, disk, internal and external attributes.
+        central.extend_from_slice(&[0; 10]);
+        central.extend_from_slice(&offset.to_le_bytes());
+        central.extend_from_slice(name.as_bytes());
+
+        self.offset += (header.len() + data.len()) as u64;
+        self.entries += 1;
+        Ok(header)
+    }
+
+    /// Returns the central directory, ending the archive.
+    fn finish(self) -> std::io::Result<Vec<u8>> {
+        let size = u32_field(self.central_directory.len() as u64)?;
+        let offset = u32_field(self.offset)?;
+
+        let mut end = self.central_directory;
+        end.extend_from_slice(&0x06054b50u32.to_le_bytes());
+        // This disk and the disk the directory starts on.
+        end.extend_from_slice(&[0, 0, 0, 0]);
+        end.extend_from_slice(&self.entries.to_le_bytes());
+        end.extend_from_slice(&self.entries.to_le_bytes());
+        end.extend_from_slice(&size.to_le_bytes());
+        end.extend_from_slice(&offset.to_le_bytes());
+        end.extend_from_slice(&0u16.to_le_bytes());
+        Ok(end)
+    }
+}
+
+/// Streams the ZIP archive of the files of `keys`, which must be valid.
+fn archive(buckets: Arc<Buckets>, keys: Vec<String>) -> Body {
+    let state = (buckets, keys.into_iter(), Some(ZipWriter::default()));
+    Body::from_stream(stream::unfold(
+        state,
+        |(buckets, mut keys, writer)| async move {
+            let mut writer = writer?;
+            let Some(key) = keys.next() else {
+                return Some((writer.finish(), (buckets, keys, None)));
+            };
+
+            let (bucket, name) = split_key(&key).expect("keys are checked up front");
+            let chunk = match buckets.get(bucket) {
+                Ok(bucket) => bucket.download(name).await.map_err(std::io::Error::other),
+                Err(err) => Err(std::io::Error::other(err)),
+            }
+            .and_then(|data| {
+                let mut chunk = writer.entry(&key, &data)?;
+                chunk.extend_from_slice(&data);
+                Ok(chunk)
+            });
+            // A failed file aborts the download, the archive being incomplete.
+            match chunk {
+                Ok(chunk) => Some((Ok(chunk), (buckets, keys, Some(writer)))),
+                Err(err) => Some((Err(err), (buckets, keys, None))),
+            }
+        },
+    ))
+}
+
+/// Downloads files, or the attachments of a record, as a ZIP archive.
+#[utoipa::path(
+    post,
+    path = "/files/archive",
+    request_body = ArchiveRequest,
+    responses(
+        (status = 200, content_type = "application/zip"),
+        (status = 400),
+        (status = 401),
+        (status = 403),
+        (status = 404)
+    )
+)]
+async fn archive_files(
+    Extension(buckets): Extension<Arc<Buckets>>,
+    claims: Result<AuthClaims, StatusCode>,
+    resolver: Option<Extension<AttachmentResolver>>,
+    Json(request): Json<ArchiveRequest>,
+) -> Result<Response, Response> {
+    let keys = match (&request.record, resolver) {
+        (None, _) => request.files,
+        (Some(_), _) if !request.files.is_empty() => {
+            return Err((StatusCode::BAD_REQUEST, "Name either files or a record").into_response());
+        }
+        (Some(record), Some(Extension(resolver))) => resolver
+            .resolve(record)
+            .await
+            .ok_or_else(|| (StatusCode::NOT_FOUND, "Unknown record").into_response())?,
+        (Some(_), None) => {
+            return Err((StatusCode::BAD_REQUEST, "Records can't be archived").into_response());
+        }
+    };
+
+    let mut seen = HashSet::new();
+    let keys: Vec<String> = keys
+        .into_iter()
+        .filter(|key| seen.insert(key.clone()))
+        .collect();
+    if keys.is_empty() || keys.len() > MAX_ARCHIVE_FILES {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("An archive holds 1 to {} files", MAX_ARCHIVE_FILES),
+        )
+            .into_response());
+    }
+    for key in &keys {
+        let (bucket, name) = split_key(key).map_err(IntoResponse::into_response)?;
+        buckets
+            .get(bucket)
+            .map_err(IntoResponse::into_response)?
+            .authorize(claims.clone(), Some(name), FileAction::Read)
+            .await
+            .map_err(IntoResponse::into_response)?;
+    }
+
+    let file_name = request
+        .name
+        .filter(|name| !name.is_empty() && !name.contains(['"', '/', '\\']))
+        .unwrap_or_else(|| DEFAULT_ARCHIVE_NAME.to_string());
+    let mut response = archive(buckets, keys).into_response();
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/zip"),
+    );
+    if let Ok(disposition) =
+        HeaderValue::from_str(&format!("attachment; filename=\"{}\"", file_name))
+    {
+        response
+            .headers_mut()
+            .insert(header::CONTENT_DISPOSITION, disposition);
+    }
+    Ok(response)
+}
+
+pub fn router() -> OpenApiRouter {
+    OpenApiRouter::new().routes(routes!(archive_files))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bucket::{BucketConfig, Visibility},
+        memory::MemoryStorage,
+    };
+
+    fn u16_at(bytes: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap())
+    }
+
+    fn u32_at(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    /// Reads the names and contents of the entries through the central directory.
+    fn entries(zip: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let end = zip.len() - 22;
+        assert_eq!(u32_at(zip, end), 0x06054b50);
+        let count = u16_at(zip, end + 10) as usize;
+        let mut at = u32_at(zip, end + 16) as usize;
+
+        (0..count)
+            .map(|_| {
+                assert_eq!(u32_at(zip, at), 0x02014b50);
+                let crc = u32_at(zip, at + 16);
+                let size = u32_at(zip, at + 24) as usize;
+                let name_len = u16_at(zip, at + 28) as usize;
+                let local = u32_at(zip, at + 42) as usize;
+                let name = String::from_utf8(zip[at + 46..at + 46 + name_len].to_vec()).unwrap();
+                at += 46 + name_len;
+
+                assert_eq!(u32_at(zip, local), 0x04034b50);
+                let data = local + 30 + u16_at(zip, local + 26) as usize;
+                let data = zip[data..data + size].to_vec();
+                assert_eq!(crc32fast::hash(&data), crc);
+                (name, data)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_archive() {
+        let buckets = Buckets::new()
+            .with_backend("memory", MemoryStorage::new())
+            .with_bucket(
+                "avatars",
+                BucketConfig {
+                    backend: "memory".to_string(),
+                    visibility: Visibility::Public,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let avatars = buckets.get("avatars").unwrap();
+        avatars
+            .upload("ann.png", "image/png", b"ann", None)
+            .await
+            .unwrap();
+        avatars
+            .upload("bob.png", "image/png", b"", None)
+            .await
+            .unwrap();
+
+        let body = archive(
+            Arc::new(buckets),
+            vec!["avatars/ann.png".to_string(), "avatars/bob.png".to_string()],
+        );
+        let zip = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        assert_eq!(
+            entries(&zip),
+            [
+                ("avatars/ann.png".to_string(), b"ann".to_vec()),
+                ("avatars/bob.png".to_string(), Vec::new())
+            ]
+        );
+
+        assert!(split_key("avatars/").is_err());
+        assert!(split_key("a/b/c.png").is_err());
+        assert_eq!(split_key("a/c.png").unwrap(), ("a", "c.png"));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_names_fail_up_front() {
+        let buckets = Buckets::new()
+            .with_backend("memory", MemoryStorage::new())
+            .with_bucket(
+                "avatars",
+                BucketConfig {
+                    backend: "memory".to_string(),
+                    visibility: Visibility::Public,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        buckets
+            .get("avatars")
+            .unwrap()
+            .upload("ann.png", "image/png", b"ann", None)
+            .await
+            .unwrap();
+        let buckets = Arc::new(buckets);
+
+        for invalid in ["avatars/..", "avatars/.palmera-meta.ann.png"] {
+            let request = ArchiveRequest {
+                files: vec!["avatars/ann.png".to_string(), invalid.to_string()],
+                ..Default::default()
+            };
+            let response = archive_files(
+                Extension(buckets.clone()),
+                Err(StatusCode::UNAUTHORIZED),
+                None,
+                Json(request),
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,341 @@
+//! # Remote backups
+//!
+//! [`BackupTarget`] uploads backup archives to any [`FileStorageHandler`] (S3 or a local
+//! path), optionally encrypting them with AES-256-GCM first, and enforces a
+//! [`BackupRetention`] policy after every upload so old archives do not pile up.
+//!
+//! Archives are stored as `<name>-<unix milliseconds>-<random suffix>.bak`, or `.bak.enc`
+//! when encrypted, so back-to-back pushes never overwrite each other. An encrypted
+//! archive is the random 12 byte nonce followed by the ciphertext.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! use std::path::PathBuf;
+//! use palmera_storage::{
+//!     backup::{BackupEncryption, BackupRetention, BackupTarget},
+//!     local::LocalStorage,
+//! };
+//!
+//! # async fn example(archive: Vec<u8>) -> palmera_storage::traits::FileResult<()> {
+//! let target = BackupTarget::new(LocalStorage::new(PathBuf::from("/var/backups")), "palmera")
+//!     .with_encryption(BackupEncryption::from_secret("a long random secret"))
+//!     .with_retention(BackupRetention::new(7, 4));
+//!
+//! let key = target.push("db", &archive).await?;
+//! let restored = target.fetch(&key).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    cmp::Reverse,
+    collections::HashSet,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use aes_gcm::{
+    Aes256Gcm, Key, Nonce,
+    aead::{Aead, AeadCore, KeyInit, OsRng, rand_core::RngCore},
+};
+use sha2::{Digest, Sha256};
+
+use crate::traits::{FileResult, FileStorageError, FileStorageHandler};
+
+const NONCE_LEN: usize = 12;
+const MILLIS_PER_DAY: u64 = 86_400_000;
+
+/// AES-256-GCM key used to encrypt archives before they leave the machine.
+#[derive(Clone)]
+pub struct BackupEncryption {
+    key: [u8; 32],
+}
+
+impl BackupEncryption {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self { key }
+    }
+
+    /// Derives the key from a secret with SHA-256. The secret should be long and
+    /// random; this is not a password hashing function.
+    pub fn from_secret(secret: &str) -> Self {
+        Self::new(Sha256::digest(secret.as_bytes()).into())
+    }
+
+    pub fn encrypt(&self, archive: &[u8]) -> FileResult<Vec<u8>> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher()
+            .encrypt(&nonce, archive)
+            .map_err(|_| FileStorageError::Encryption)?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    pub fn decrypt(&self, sealed: &[u8]) -> FileResult<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            return Err(FileStorageError::Encryption);
+        }
+
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.cipher()
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| FileStorageError::Encryption)
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&self.key))
+    }
+}
+
+/// Which archives to keep: the newest archive of each of the last `keep_daily` days and
+/// of each of the last `keep_weekly` weeks. The newest archive is always kept.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BackupRetention {
+    pub keep_daily: usize,
+    pub keep_weekly: usize,
+}
+
+impl BackupRetention {
+    pub fn new(keep_daily: usize, keep_weekly: usize) -> Self {
+        Self {
+            keep_daily,
+            keep_weekly,
+        }
+    }
+
+    /// Returns the timestamps (unix milliseconds) of the archives to delete.
+    pub fn expired(&self, timestamps: &[u64]) -> Vec<u64> {
+        let mut newest_first = timestamps.to_vec();
+        newest_first.sort_unstable_by(|a, b| b.cmp(a));
+
+        let mut keep = HashSet::new();
+        keep.extend(newest_first.first().copied());
+
+        let mut keep_newest_per = |bucket: fn(u64) -> u64, count: usize| {
+            let mut seen = HashSet::new();
+            for &timestamp in &newest_first {
+                if seen.len() == count {
+                    break;
+                }
+                if seen.insert(bucket(timestamp)) {
+                    keep.insert(timestamp);
+                }
+            }
+        };
+
+        keep_newest_per(|timestamp| timestamp / MILLIS_PER_DAY, self.keep_daily);
+        // The Unix epoch was a Thursday; shifting by three days starts weeks on Monday.
+        keep_newest_per(
+            |timestamp| (timestamp / MILLIS_PER_DAY + 3) / 7,
+            self.keep_weekly,
+        );
+
+        newest_first
+            .into_iter()
+            .filter(|timestamp| !keep.contains(timestamp))
+            .collect()
+    }
+}
+
+impl Default for BackupRetention {
+    fn default() -> Self {
+        Self::new(7, 4)
+    }
+}
+
+/// Storage location for backup archives.
+pub struct BackupTarget<S> {
+    storage: S,
+    id: String,
+    encryption: Option<BackupEncryption>,
+    retention: Option<BackupRetention>,
+}
+
+impl<S: FileStorageHandler + Sync> BackupTarget<S> {
+    /// Stores archives under `id` (a bucket for S3, a directory for local storage).
+    pub fn new(storage: S, id: &str) -> Self {
+        Self {
+            storage,
+            id: id.to_string(),
+            encryption: None,
+            retention: None,
+        }
+    }
+
+    pub fn with_encryption(mut self, encryption: BackupEncryption) -> Self {
+        self.encryption = Some(encryption);
+        self
+    }
+
+    pub fn with_retention(mut self, retention: BackupRetention) -> Self {
+        self.retention = Some(retention);
+        self
+    }
+
+    /// Uploads `archive` and prunes old archives of the same name, returning the key
+    /// the archive was stored under.
+    pub async fn push(&self, name: &str, archive: &[u8]) -> FileResult<String> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let suffix = OsRng.next_u32();
+
+        let (key, bytes) = match &self.encryption {
+            Some(encryption) => (
+                format!("{}-{}-{:08x}.bak.enc", name, timestamp, suffix),
+                encryption.encrypt(archive)?,
+            ),
+            None => (
+                format!("{}-{}-{:08x}.bak", name, timestamp, suffix),
+                archive.to_vec(),
+            ),
+        };
+
+        self.storage.upload(&self.id, &key, &bytes).await?;
+
+        if let Some(retention) = &self.retention {
+            self.prune(name, retention).await?;
+        }
+
+        Ok(key)
+    }
+
+    /// Downloads an archive, decrypting it if needed.
+    pub async fn fetch(&self, key: &str) -> FileResult<Vec<u8>> {
+        let bytes = self.storage.download(&self.id, key).await?;
+
+        match (key.ends_with(".enc"), &self.encryption) {
+            (false, _) => Ok(bytes),
+            (true, Some(encryption)) => encryption.decrypt(&bytes),
+            (true, None) => Err(FileStorageError::Encryption),
+        }
+    }
+
+    /// Lists the stored archives of `name` as `(key, unix milliseconds)`, newest first.
+    pub async fn list(&self, name: &str) -> FileResult<Vec<(String, u64)>> {
+        let mut archives = self
+            .storage
+            .list(&self.id)
+            .await?
+            .into_iter()
+            .filter_map(|key| {
+                let timestamp = parse_key(&key, name)?;
+                Some((key, timestamp))
+            })
+            .collect::<Vec<_>>();
+
+        archives.sort_by_key(|(_, timestamp)| Reverse(*timestamp));
+
+        Ok(archives)
+    }
+
+    /// Deletes the archives of `name` that fall outside `retention`, returning how many
+    /// were removed.
+    pub async fn prune(&self, name: &str, retention: &BackupRetention) -> FileResult<usize> {
+        let archives = self.list(name).await?;
+        let timestamps = archives.iter().map(|(_, ts)| *ts).collect::<Vec<_>>();
+        let expired = retention.expired(&timestamps);
+
+        let mut removed = 0;
+        for (key, _) in archives.iter().filter(|(_, ts)| expired.contains(ts)) {
+            self.storage.delete(&self.id, key).await?;
+            removed += 1;
+        }
+
+        Ok(removed)
+    }
+}
+
+/// Extracts the timestamp from an archive key of `name`.
+fn parse_key(key: &str, name: &str) -> Option<u64> {
+    let rest = key.strip_prefix(name)?.strip_prefix('-')?;
+    let timestamp = rest
+        .strip_suffix(".bak.enc")
+        .or_else(|| rest.strip_suffix(".bak"))?;
+    let (timestamp, suffix) = timestamp.split_once('-')?;
+
+    if suffix.is_empty() || !suffix.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    timestamp.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryStorage;
+
+    const DAY: u64 = MILLIS_PER_DAY;
+    const HOUR: u64 = MILLIS_PER_DAY / 24;
+
+    #[test]
+    fn test_encryption_round_trip() {
+        let encryption = BackupEncryption::from_secret("secret");
+        let sealed = encryption.encrypt(b"archive").unwrap();
+
+        assert_ne!(&sealed[NONCE_LEN..], b"archive");
+        assert_eq!(encryption.decrypt(&sealed).unwrap(), b"archive");
+        assert!(
+            BackupEncryption::from_secret("other")
+                .decrypt(&sealed)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_key() {
+        assert_eq!(
+            parse_key("db-1700000000000-0a1b2c3d.bak", "db"),
+            Some(1_700_000_000_000)
+        );
+        assert_eq!(
+            parse_key("db-1700000000000-0a1b2c3d.bak.enc", "db"),
+            Some(1_700_000_000_000)
+        );
+        assert_eq!(parse_key("dbx-1700000000000-0a1b2c3d.bak", "db"), None);
+        assert_eq!(parse_key("db-x-1700000000000-0a1b2c3d.bak", "db"), None);
+        assert_eq!(parse_key("db-1700000000000.bak", "db"), None);
+    }
+
+    #[tokio::test]
+    async fn test_back_to_back_pushes_keep_both_archives() {
+        let target = BackupTarget::new(MemoryStorage::new(), "backups");
+
+        let first = target.push("db", b"first").await.unwrap();
+        let second = target.push("db", b"second").await.unwrap();
+
+        assert_ne!(first, second);
+        assert_eq!(target.fetch(&first).await.unwrap(), b"first");
+        assert_eq!(target.fetch(&second).await.unwrap(), b"second");
+        assert_eq!(target.list("db").await.unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_retention_keeps_daily_and_weekly() {
+        // Two backups a day for 30 days, starting on a Monday.
+        let monday = 19_723 * DAY;
+        let timestamps = (0..30)
+            .flat_map(|day| [monday + day * DAY, monday + day * DAY + HOUR])
+            .collect::<Vec<_>>();
+
+        let expired = BackupRetention::new(3, 3).expired(&timestamps);
+        let kept = timestamps
+            .iter()
+            .filter(|ts| !expired.contains(ts))
+            .copied()
+            .collect::<HashSet<_>>();
+
+        // Newest of the last three days; those also cover the current and previous
+        // week, so the third week adds the newest backup of the week before.
+        let last = monday + 29 * DAY + HOUR;
+        assert_eq!(
+            kept,
+            HashSet::from([last, last - DAY, last - 2 * DAY, monday + 20 * DAY + HOUR])
+        );
+    }
+}
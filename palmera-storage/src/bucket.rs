@@ -0,0 +1,1360 @@
+//! # Buckets
+//!
+//! A [`Bucket`] is a named place for files with its own rules: the storage backend it
+//! lives on, whether it is public or private, the largest file it accepts, the MIME
+//! types it allows and the `Cache-Control` header its files are served with. Buckets are
+//! declared as [`BucketConfig`]s, typically deserialized from the app's configuration,
+//! and registered on [`Buckets`] together with the backends they refer to by name.
+//!
+//! ```json
+//! {
+//!   "avatars": {
+//!     "backend": "s3",
+//!     "visibility": "public",
+//!     "max_file_size": 2097152,
+//!     "allowed_mime_types": ["image/*"],
+//!     "cache_control": "public, max-age=86400"
+//!   },
+//!   "invoices": { "backend": "local", "allowed_mime_types": ["application/pdf"] }
+//! }
+//! ```
+//!
+//! Files of a bucket are stored under the bucket's name on its backend, each next to a
+//! [`FileMetadata`] entry recording who uploaded it, its size and the content type it
+//! is served with. The [`router`] serves them by bucket name and expects an
+//! `Extension<Arc<Buckets>>` layer:
+//!
+//! - `GET /buckets/{bucket}` lists the bucket's files.
+//! - `PUT /buckets/{bucket}/{name}` uploads a file, its type taken from `Content-Type`.
+//! - `GET /buckets/{bucket}/{name}` downloads a file.
+//! - `DELETE /buckets/{bucket}/{name}` deletes a file.
+//! - `GET /files` searches the metadata of the files of every bucket the caller may
+//!   list; see [`palmera_database::files`].
+//!
+//! With an `Extension<Arc<FileIndex>>`, uploads and deletes also update the file index
+//! searched by `GET /files`, which answers `404 Not Found` without one.
+//!
+//! Uploads are checked against the bucket's limits while the body is read, so an
+//! oversized file is rejected before it is buffered whole. Who may use the routes depends
+//! on the bucket's visibility and [`FilePolicy`]; see [`crate::access`]. With a
+//! [`StorageQuota`] extension, stored bytes also count against the quota of the file's
+//! owner; see [`crate::quota`]. While a backend wrapped in
+//! [`DegradedStorage`](crate::degradation::DegradedStorage) is unavailable, its buckets
+//! answer `503 Service Unavailable` with a `Retry-After` header.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! use std::{collections::HashMap, path::PathBuf, sync::Arc};
+//! use axum::Extension;
+//! use palmera_storage::{
+//!     bucket::{self, BucketConfig, Buckets},
+//!     local::LocalStorage,
+//!     memory::MemoryStorage,
+//! };
+//!
+//! # fn example(config: &str) -> Result<(), Box<dyn std::error::Error>> {
+//! let configs: HashMap<String, BucketConfig> = serde_json::from_str(config)?;
+//! let buckets = Buckets::new()
+//!     .with_backend("local", LocalStorage::new(PathBuf::from("uploads")))
+//!     .with_backend("memory", MemoryStorage::new())
+//!     .with_buckets(configs)?;
+//!
+//! let (router, _api) = bucket::router()
+//!     .layer(Extension(Arc::new(buckets)))
+//!     .split_for_parts();
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{collections::HashMap, fmt, future::Future, pin::Pin, sync::Arc};
+
+use axum::{
+    Extension, Json,
+    body::Body,
+    extract::{Path, Query},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+use palmera_auth::{extract::AuthClaims, jwt::JWTClaims};
+use palmera_database::{
+    embedded::{self, ClientError},
+    files::{self, FileIndex, FilePage, FileSearch},
+    postgres::privacy,
+};
+use uuid::Uuid;
+
+use crate::{
+    access::{self, FileAction, FilePolicy, FileRef, PRIVATE_CACHE_CONTROL, PUBLIC_CACHE_CONTROL},
+    constraints::{UploadConstraints, UploadViolation},
+    quota::StorageQuota,
+    traits::{FileResult, FileStorageError, FileStorageHandler},
+};
+
+/// Whether a bucket's files may be read without authenticating.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Visibility {
+    Public,
+    #[default]
+    Private,
+}
+
+/// Prefix of the entries holding the [`FileMetadata`] of a bucket's files. File names
+/// can't start with it.
+pub const METADATA_PREFIX: &str = ".palmera-meta.";
+
+/// What a bucket records about a file when it is uploaded.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileMetadata {
+    /// The user who uploaded the file. Without a [`FilePolicy`], only they may replace or
+    /// delete it.
+    #[serde(default)]
+    pub owner: Option<Uuid>,
+    /// Size of the file in bytes, charged to the owner's [`StorageQuota`].
+    #[serde(default)]
+    pub size: u64,
+    /// MIME type the file was uploaded with and is served with. Files uploaded before it
+    /// was recorded have none and are served as `application/octet-stream`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+}
+
+/// Files stored in a bucket, see [`Buckets::usage`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BucketUsage {
+    pub bucket: String,
+    pub files: u64,
+    /// Total size of the files, from their [`FileMetadata`]. Files stored on the backend
+    /// without going through the bucket count as empty.
+    pub bytes: u64,
+}
+
+/// Declaration of a bucket.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BucketConfig {
+    /// Name of the backend registered with [`Buckets::with_backend`].
+    pub backend: String,
+    #[serde(default)]
+    pub visibility: Visibility,
+    /// Largest file accepted, in bytes.
+    #[serde(default)]
+    pub max_file_size: Option<u64>,
+    /// Accepted MIME types, see [`UploadConstraints::allowed_mime_types`]. An empty list
+    /// accepts any type.
+    #[serde(default)]
+    pub allowed_mime_types: Vec<String>,
+    /// `Cache-Control` header files are served with, instead of the default of the
+    /// bucket's visibility.
+    #[serde(default)]
+    pub cache_control: Option<String>,
+}
+
+impl BucketConfig {
+    /// Limits uploads to the bucket are checked against.
+    pub fn constraints(&self) -> UploadConstraints {
+        UploadConstraints {
+            max_file_size: self.max_file_size,
+            allowed_mime_types: self.allowed_mime_types.clone(),
+            max_files: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum BucketError {
+    /// No bucket has the name.
+    UnknownBucket(String),
+    /// A bucket refers to a backend that wasn't registered.
+    UnknownBackend(String),
+    /// A bucket or file name is empty or contains a path separator.
+    InvalidName(String),
+    /// The request has no valid bearer token.
+    Unauthorized,
+    /// The bucket's policy, or the file's owner, denies the caller access to the file.
+    Forbidden,
+    /// The file breaks the bucket's limits.
+    Rejected(UploadViolation),
+    /// The caller's [`StorageQuota`] has no room for the file.
+    QuotaExceeded,
+    Storage(FileStorageError),
+}
+
+impl BucketError {
+    /// HTTP status the request should be answered with.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            BucketError::UnknownBucket(_) => StatusCode::NOT_FOUND,
+            BucketError::UnknownBackend(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            BucketError::InvalidName(_) => StatusCode::BAD_REQUEST,
+            BucketError::Unauthorized => StatusCode::UNAUTHORIZED,
+            BucketError::Forbidden => StatusCode::FORBIDDEN,
+            BucketError::QuotaExceeded => StatusCode::PAYMENT_REQUIRED,
+            BucketError::Rejected(violation) => {
+                StatusCode::from_u16(violation.status_code()).unwrap_or(StatusCode::BAD_REQUEST)
+            }
+            BucketError::Storage(err) if err.is_not_found() => StatusCode::NOT_FOUND,
+            BucketError::Storage(err) if err.is_already_exists() => StatusCode::CONFLICT,
+            BucketError::Storage(
+                FileStorageError::CircuitOpen | FileStorageError::Unavailable { .. },
+            ) => StatusCode::SERVICE_UNAVAILABLE,
+            BucketError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl fmt::Display for BucketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BucketError::UnknownBucket(name) => write!(f, "No bucket is named {}", name),
+            BucketError::UnknownBackend(name) => write!(f, "No storage backend is named {}", name),
+            BucketError::InvalidName(name) => write!(f, "{:?} is not a valid name", name),
+            BucketError::Unauthorized => write!(f, "A valid bearer token is required"),
+            BucketError::Forbidden => write!(f, "Access to the file is denied"),
+            BucketError::QuotaExceeded => write!(f, "The storage quota is exceeded"),
+            BucketError::Rejected(violation) => violation.fmt(f),
+            BucketError::Storage(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for BucketError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BucketError::Rejected(violation) => Some(violation),
+            BucketError::Storage(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<UploadViolation> for BucketError {
+    fn from(violation: UploadViolation) -> Self {
+        BucketError::Rejected(violation)
+    }
+}
+
+impl From<FileStorageError> for BucketError {
+    fn from(err: FileStorageError) -> Self {
+        BucketError::Storage(err)
+    }
+}
+
+impl IntoResponse for BucketError {
+    fn into_response(self) -> Response {
+        let mut response = (self.status_code(), self.to_string()).into_response();
+        if let BucketError::Storage(FileStorageError::Unavailable { retry_after }) = self {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, retry_after.as_secs().into());
+        }
+        response
+    }
+}
+
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Object safe form of [`FileStorageHandler`], so buckets can share backends of
+/// different types.
+pub trait StorageBackend: Send + Sync {
+    fn upload<'a>(
+        &'a self,
+        id: &'a str,
+        name: &'a str,
+        bytes: &'a [u8],
+    ) -> BoxFuture<'a, FileResult<()>>;
+    fn download<'a>(&'a self, id: &'a str, name: &'a str) -> BoxFuture<'a, FileResult<Vec<u8>>>;
+    fn list<'a>(&'a self, id: &'a str) -> BoxFuture<'a, FileResult<Vec<String>>>;
+    fn delete<'a>(&'a self, id: &'a str, name: &'a str) -> BoxFuture<'a, FileResult<()>>;
+}
+
+impl<S: FileStorageHandler + Send + Sync> StorageBackend for S {
+    fn upload<'a>(
+        &'a self,
+        id: &'a str,
+        name: &'a str,
+        bytes: &'a [u8],
+    ) -> BoxFuture<'a, FileResult<()>> {
+        Box::pin(FileStorageHandler::upload(self, id, name, bytes))
+    }
+
+    fn download<'a>(&'a self, id: &'a str, name: &'a str) -> BoxFuture<'a, FileResult<Vec<u8>>> {
+        Box::pin(FileStorageHandler::download(self, id, name))
+    }
+
+    fn list<'a>(&'a self, id: &'a str) -> BoxFuture<'a, FileResult<Vec<String>>> {
+        Box::pin(FileStorageHandler::list(self, id))
+    }
+
+    fn delete<'a>(&'a self, id: &'a str, name: &'a str) -> BoxFuture<'a, FileResult<()>> {
+        Box::pin(FileStorageHandler::delete(self, id, name))
+    }
+}
+
+/// A named bucket on its backend.
+#[derive(Clone)]
+pub struct Bucket {
+    name: String,
+    config: BucketConfig,
+    backend: Arc<dyn StorageBackend>,
+    policy: Option<FilePolicy>,
+}
+
+impl fmt::Debug for Bucket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Bucket")
+            .field("name", &self.name)
+            .field("config", &self.config)
+            .field("policy", &self.policy)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Bucket {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn config(&self) -> &BucketConfig {
+        &self.config
+    }
+
+    /// Checks that the caller with `claims` may perform `action` on the file `name`, or
+    /// on the bucket when `None`; see [`access::authorize`]. Without a policy, only the
+    /// owner of an existing file may replace or delete it.
+    pub async fn authorize(
+        &self,
+        claims: Result<AuthClaims, StatusCode>,
+        name: Option<&str>,
+        action: FileAction,
+    ) -> Result<Option<JWTClaims>, BucketError> {
+        let file = FileRef {
+            bucket: self.name.clone(),
+            name: name.map(str::to_string),
+            action,
+        };
+        let claims =
+            access::authorize(claims, self.config.visibility, self.policy.as_ref(), &file).await?;
+
+        if let (None, Some(claims), Some(name)) = (&self.policy, &claims, name)
+            && action != FileAction::Read
+            && let Some(metadata) = self.metadata(name).await?
+            && metadata.owner.is_some_and(|owner| owner != claims.subject)
+        {
+            return Err(BucketError::Forbidden);
+        }
+        Ok(claims)
+    }
+
+    /// `Cache-Control` header the bucket's files are served with.
+    pub fn cache_control(&self) -> &str {
+        match (&self.config.cache_control, self.config.visibility) {
+            (Some(cache_control), _) => cache_control,
+            (None, Visibility::Public) => PUBLIC_CACHE_CONTROL,
+            (None, Visibility::Private) => PRIVATE_CACHE_CONTROL,
+        }
+    }
+
+    /// Stores `bytes` as `name`, uploaded by `owner`, after checking them against the
+    /// bucket's limits.
+    pub async fn upload(
+        &self,
+        name: &str,
+        mime: &str,
+        bytes: &[u8],
+        owner: Option<Uuid>,
+    ) -> Result<(), BucketError> {
+        check_file_name(name)?;
+        self.config
+            .constraints()
+            .check_file(mime, bytes.len() as u64)?;
+        self.backend.upload(&self.name, name, bytes).await?;
+
+        let metadata = FileMetadata {
+            owner,
+            size: bytes.len() as u64,
+            content_type: Some(mime.to_string()),
+        };
+        let metadata = serde_json::to_vec(&metadata)
+            .map_err(|err| FileStorageError::Io(std::io::Error::other(err)))?;
+        Ok(self
+            .backend
+            .upload(&self.name, &metadata_name(name), &metadata)
+            .await?)
+    }
+
+    pub async fn download(&self, name: &str) -> Result<Vec<u8>, BucketError> {
+        check_file_name(name)?;
+        Ok(self.backend.download(&self.name, name).await?)
+    }
+
+    /// Metadata of the file `name`, if it exists and was uploaded through the bucket.
+    pub async fn metadata(&self, name: &str) -> Result<Option<FileMetadata>, BucketError> {
+        check_file_name(name)?;
+        let bytes = match self
+            .backend
+            .download(&self.name, &metadata_name(name))
+            .await
+        {
+            Err(err) if err.is_not_found() => return Ok(None),
+            bytes => bytes?,
+        };
+        serde_json::from_slice(&bytes)
+            .map(Some)
+            .map_err(|err| FileStorageError::Io(std::io::Error::other(err)).into())
+    }
+
+    /// Names of the bucket's files. A bucket nothing was uploaded to yet is empty.
+    pub async fn list(&self) -> Result<Vec<String>, BucketError> {
+        match self.backend.list(&self.name).await {
+            Err(err) if err.is_not_found() => Ok(Vec::new()),
+            result => Ok(result?
+                .into_iter()
+                .filter(|name| !name.starts_with(METADATA_PREFIX))
+                .collect()),
+        }
+    }
+
+    /// Number and total size of the bucket's files.
+    pub async fn usage(&self) -> Result<BucketUsage, BucketError> {
+        let names = self.list().await?;
+        let mut bytes = 0;
+        for name in &names {
+            if let Some(metadata) = self.metadata(name).await? {
+                bytes += metadata.size;
+            }
+        }
+
+        Ok(BucketUsage {
+            bucket: self.name.clone(),
+            files: names.len() as u64,
+            bytes,
+        })
+    }
+
+    pub async fn delete(&self, name: &str) -> Result<(), BucketError> {
+        check_file_name(name)?;
+        self.backend.delete(&self.name, name).await?;
+        match self.backend.delete(&self.name, &metadata_name(name)).await {
+            Err(err) if !err.is_not_found() => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn metadata_name(name: &str) -> String {
+    format!("{METADATA_PREFIX}{name}")
+}
+
+/// File names are bucket names that don't collide with metadata entries.
+pub(crate) fn check_file_name(name: &str) -> Result<(), BucketError> {
+    check_name(name)?;
+    if name.starts_with(METADATA_PREFIX) {
+        return Err(BucketError::InvalidName(name.to_string()));
+    }
+    Ok(())
+}
+
+/// Bucket and file names are single path segments.
+fn check_name(name: &str) -> Result<(), BucketError> {
+    let valid =
+        !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\', '\0']);
+    if valid {
+        Ok(())
+    } else {
+        Err(BucketError::InvalidName(name.to_string()))
+    }
+}
+
+/// The buckets of an app and the backends they live on.
+#[derive(Default)]
+pub struct Buckets {
+    backends: HashMap<String, Arc<dyn StorageBackend>>,
+    buckets: HashMap<String, Bucket>,
+}
+
+impl fmt::Debug for Buckets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Buckets")
+            .field("backends", &self.backends.keys().collect::<Vec<_>>())
+            .field("buckets", &self.buckets)
+            .finish()
+    }
+}
+
+impl Buckets {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a backend buckets can refer to as `name`.
+    pub fn with_backend<S: FileStorageHandler + Send + Sync + 'static>(
+        mut self,
+        name: &str,
+        storage: S,
+    ) -> Self {
+        self.backends.insert(name.to_string(), Arc::new(storage));
+        self
+    }
+
+    /// Declares the bucket `name`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`BucketError::InvalidName`] if the name isn't a single path segment and
+    /// [`BucketError::UnknownBackend`] if its backend wasn't registered.
+    pub fn with_bucket(mut self, name: &str, config: BucketConfig) -> Result<Self, BucketError> {
+        check_name(name)?;
+        let backend = self
+            .backends
+            .get(&config.backend)
+            .cloned()
+            .ok_or_else(|| BucketError::UnknownBackend(config.backend.clone()))?;
+
+        self.buckets.insert(
+            name.to_string(),
+            Bucket {
+                name: name.to_string(),
+                config,
+                backend,
+                policy: None,
+            },
+        );
+        Ok(self)
+    }
+
+    /// Declares every bucket of `configs`, keyed by name.
+    pub fn with_buckets(
+        self,
+        configs: impl IntoIterator<Item = (String, BucketConfig)>,
+    ) -> Result<Self, BucketError> {
+        configs
+            .into_iter()
+            .try_fold(self, |buckets, (name, config)| {
+                buckets.with_bucket(&name, config)
+            })
+    }
+
+    /// Sets the policy deciding who may act on the files of bucket `name`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`BucketError::UnknownBucket`] if the bucket wasn't declared.
+    pub fn with_policy(mut self, name: &str, policy: FilePolicy) -> Result<Self, BucketError> {
+        self.buckets
+            .get_mut(name)
+            .ok_or_else(|| BucketError::UnknownBucket(name.to_string()))?
+            .policy = Some(policy);
+        Ok(self)
+    }
+
+    pub fn get(&self, name: &str) -> Result<&Bucket, BucketError> {
+        self.buckets
+            .get(name)
+            .ok_or_else(|| BucketError::UnknownBucket(name.to_string()))
+    }
+
+    /// Names of the declared buckets, sorted.
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.buckets.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// Usage of every declared bucket, by name; e.g. the `storage` section of
+    /// `palmera_core::stats`.
+    pub async fn usage(&self) -> Result<Vec<BucketUsage>, BucketError> {
+        let mut usage = Vec::new();
+        for name in self.names() {
+            usage.push(self.get(name)?.usage().await?);
+        }
+        Ok(usage)
+    }
+}
+
+/// Reads and deletes the files of erased users for `palmera_database`'s privacy tooling,
+/// by keys of the form `bucket/name`.
+impl privacy::FileStore for Buckets {
+    fn read<'a>(&'a self, key: &'a str) -> privacy::FileFuture<'a, Option<Vec<u8>>> {
+        Box::pin(async move {
+            let (bucket, name) = split_key(key)?;
+            let file = match self.get(bucket)?.download(name).await {
+                Err(BucketError::Storage(err)) if err.is_not_found() => None,
+                result => Some(result?),
+            };
+            Ok::<_, privacy::FileError>(file)
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> privacy::FileFuture<'a, ()> {
+        Box::pin(async move {
+            let (bucket, name) = split_key(key)?;
+            match self.get(bucket)?.delete(name).await {
+                Err(BucketError::Storage(err)) if err.is_not_found() => {}
+                result => result?,
+            }
+            Ok::<_, privacy::FileError>(())
+        })
+    }
+}
+
+/// Serves the file operations of an embedded `PalmeraClient`, with the same bucket
+/// rules, file policies and owner checks as the routes. Quotas and the file index are
+/// not updated.
+impl embedded::Storage for Buckets {
+    fn upload<'a>(
+        &'a self,
+        user_id: Option<&'a str>,
+        bucket: &'a str,
+        name: &'a str,
+        mime: &'a str,
+        bytes: &'a [u8],
+    ) -> embedded::StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let bucket = self.get(bucket)?;
+            let claims = bucket
+                .authorize(session_claims(user_id), Some(name), FileAction::Write)
+                .await?;
+            // A replaced file stays its owner's.
+            let owner = match bucket.metadata(name).await? {
+                Some(FileMetadata {
+                    owner: Some(owner), ..
+                }) => Some(owner),
+                _ => claims.map(|claims| claims.subject),
+            };
+            Ok::<_, ClientError>(bucket.upload(name, mime, bytes, owner).await?)
+        })
+    }
+
+    fn download<'a>(
+        &'a self,
+        user_id: Option<&'a str>,
+        bucket: &'a str,
+        name: &'a str,
+    ) -> embedded::StorageFuture<'a, Vec<u8>> {
+        Box::pin(async move {
+            let bucket = self.get(bucket)?;
+            bucket
+                .authorize(session_claims(user_id), Some(name), FileAction::Read)
+                .await?;
+            Ok::<_, ClientError>(bucket.download(name).await?)
+        })
+    }
+
+    fn delete<'a>(
+        &'a self,
+        user_id: Option<&'a str>,
+        bucket: &'a str,
+        name: &'a str,
+    ) -> embedded::StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let bucket = self.get(bucket)?;
+            bucket
+                .authorize(session_claims(user_id), Some(name), FileAction::Delete)
+                .await?;
+            Ok::<_, ClientError>(bucket.delete(name).await?)
+        })
+    }
+}
+
+impl From<BucketError> for ClientError {
+    fn from(err: BucketError) -> Self {
+        match err {
+            BucketError::UnknownBucket(_) => ClientError::NotFound,
+            BucketError::Storage(err) if err.is_not_found() => ClientError::NotFound,
+            BucketError::Unauthorized => ClientError::Unauthorized,
+            BucketError::Forbidden => ClientError::Forbidden,
+            err => ClientError::Storage(Box::new(err)),
+        }
+    }
+}
+
+/// Claims of an embedded session's user, who was authenticated by the client. Users
+/// whose id is not a UUID can't have files, so they count as anonymous.
+fn session_claims(user_id: Option<&str>) -> Result<AuthClaims, StatusCode> {
+    user_id
+        .and_then(|user_id| Uuid::parse_str(user_id).ok())
+        .map(|subject| {
+            AuthClaims(JWTClaims::new(
+                subject,
+                chrono::Duration::zero(),
+                String::new(),
+                String::new(),
+            ))
+        })
+        .ok_or(StatusCode::UNAUTHORIZED)
+}
+
+/// Splits a `bucket/name` storage key.
+fn split_key(key: &str) -> Result<(&str, &str), BucketError> {
+    key.split_once('/')
+        .ok_or_else(|| BucketError::InvalidName(key.to_string()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/buckets/{bucket}",
+    responses((status = 200, body = Vec<String>), (status = 401), (status = 403), (status = 404))
+)]
+async fn list_files(
+    Extension(buckets): Extension<Arc<Buckets>>,
+    claims: Result<AuthClaims, StatusCode>,
+    Path(bucket): Path<String>,
+) -> Result<Json<Vec<String>>, BucketError> {
+    let bucket = buckets.get(&bucket)?;
+    bucket.authorize(claims, None, FileAction::Read).await?;
+    bucket.list().await.map(Json)
+}
+
+#[utoipa::path(
+    put,
+    path = "/buckets/{bucket}/{name}",
+    responses(
+        (status = 201),
+        (status = 401),
+        (status = 402),
+        (status = 403),
+        (status = 404),
+        (status = 409),
+        (status = 413),
+        (status = 415)
+    )
+)]
+async fn upload_file(
+    Extension(buckets): Extension<Arc<Buckets>>,
+    claims: Result<AuthClaims, StatusCode>,
+    quota: Option<Extension<StorageQuota>>,
+    index: Option<Extension<Arc<FileIndex>>>,
+    Path((bucket, name)): Path<(String, String)>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<StatusCode, BucketError> {
+    let bucket = buckets.get(&bucket)?;
+    let claims = bucket
+        .authorize(claims, Some(&name), FileAction::Write)
+        .await?;
+    let constraints = bucket.config().constraints();
+    let mime = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("application/octet-stream");
+    constraints.check_mime(mime)?;
+
+    let mut bytes = Vec::new();
+    let mut stream = body.into_data_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|err| FileStorageError::Io(std::io::Error::other(err)))?;
+        constraints.check_size((bytes.len() + chunk.len()) as u64)?;
+        bytes.extend_from_slice(&chunk);
+    }
+
+    // A replaced file stays its owner's, who is only charged the difference in size.
+    let size = bytes.len() as u64;
+    let (owner, previous) = match bucket.metadata(&name).await? {
+        Some(FileMetadata {
+            owner: Some(owner),
+            size,
+            ..
+        }) => (Some(owner), size),
+        _ => (claims.as_ref().map(|claims| claims.subject), 0),
+    };
+    let charged = match (&quota, owner) {
+        (Some(quota), Some(owner)) => {
+            quota.reserve(owner, size.saturating_sub(previous)).await?;
+            Some((quota, owner))
+        }
+        _ => None,
+    };
+
+    let uploaded = bucket.upload(&name, mime, &bytes, owner).await;
+    if let Some((quota, owner)) = charged {
+        match uploaded {
+            Ok(()) => quota.release(owner, previous.saturating_sub(size)).await,
+            Err(_) => quota.release(owner, size.saturating_sub(previous)).await,
+        }
+    }
+    uploaded?;
+
+    if let Some(Extension(index)) = index {
+        let file = files::FileMetadata {
+            bucket: bucket.name().to_string(),
+            name,
+            mime: mime.to_string(),
+            size: size as i64,
+            created_at: Utc::now(),
+        };
+        index.record(&file).await.map_err(index_error)?;
+    }
+    Ok(StatusCode::CREATED)
+}
+
+#[utoipa::path(
+    get,
+    path = "/buckets/{bucket}/{name}",
+    responses((status = 200), (status = 401), (status = 403), (status = 404))
+)]
+async fn download_file(
+    Extension(buckets): Extension<Arc<Buckets>>,
+    claims: Result<AuthClaims, StatusCode>,
+    Path((bucket, name)): Path<(String, String)>,
+) -> Result<Response, BucketError> {
+    let bucket = buckets.get(&bucket)?;
+    bucket
+        .authorize(claims, Some(&name), FileAction::Read)
+        .await?;
+    let bytes = bucket.download(&name).await?;
+    let content_type = bucket
+        .metadata(&name)
+        .await?
+        .and_then(|metadata| metadata.content_type)
+        .and_then(|mime| HeaderValue::from_str(&mime).ok())
+        .unwrap_or(HeaderValue::from_static("application/octet-stream"));
+
+    let mut response = bytes.into_response();
+    response
+        .headers_mut()
+        .insert(header::CONTENT_TYPE, content_type);
+    if let Ok(cache_control) = HeaderValue::from_str(bucket.cache_control()) {
+        response
+            .headers_mut()
+            .insert(header::CACHE_CONTROL, cache_control);
+    }
+    Ok(response)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/buckets/{bucket}/{name}",
+    responses((status = 204), (status = 401), (status = 403), (status = 404))
+)]
+async fn delete_file(
+    Extension(buckets): Extension<Arc<Buckets>>,
+    claims: Result<AuthClaims, StatusCode>,
+    quota: Option<Extension<StorageQuota>>,
+    index: Option<Extension<Arc<FileIndex>>>,
+    Path((bucket, name)): Path<(String, String)>,
+) -> Result<StatusCode, BucketError> {
+    let bucket = buckets.get(&bucket)?;
+    bucket
+        .authorize(claims, Some(&name), FileAction::Delete)
+        .await?;
+    let metadata = bucket.metadata(&name).await?;
+    bucket.delete(&name).await?;
+    if let (
+        Some(Extension(quota)),
+        Some(FileMetadata {
+            owner: Some(owner),
+            size,
+            ..
+        }),
+    ) = (quota, metadata)
+    {
+        quota.release(owner, size).await;
+    }
+    if let Some(Extension(index)) = index {
+        index
+            .remove(bucket.name(), &name)
+            .await
+            .map_err(index_error)?;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn index_error(err: sqlx::Error) -> BucketError {
+    FileStorageError::Io(std::io::Error::other(err)).into()
+}
+
+/// Searches the metadata of the files of the buckets the caller may list, e.g.
+/// `GET /files?filter=mime='image/png' && size>1048576&sort=-size`.
+#[utoipa::path(
+    get,
+    path = "/files",
+    params(FileSearch),
+    responses(
+        (status = 200, body = FilePage),
+        (status = 400),
+        (status = 401),
+        (status = 403),
+        (status = 404)
+    )
+)]
+async fn search_files(
+    Extension(buckets): Extension<Arc<Buckets>>,
+    claims: Result<AuthClaims, StatusCode>,
+    index: Option<Extension<Arc<FileIndex>>>,
+    Query(search): Query<FileSearch>,
+) -> Result<Json<FilePage>, Response> {
+    let Some(Extension(index)) = index else {
+        return Err(StatusCode::NOT_FOUND.into_response());
+    };
+
+    let mut readable = Vec::new();
+    let mut denied = None;
+    for name in buckets.names() {
+        let bucket = buckets.get(name).map_err(IntoResponse::into_response)?;
+        match bucket
+            .authorize(claims.clone(), None, FileAction::Read)
+            .await
+        {
+            Ok(_) => readable.push(name),
+            Err(err) => denied = denied.or(Some(err)),
+        }
+    }
+    // Callers who may not list any bucket learn why, rather than getting an empty page.
+    if let (true, Some(err)) = (readable.is_empty(), denied) {
+        return Err(err.into_response());
+    }
+
+    index
+        .search(&search, &readable)
+        .await
+        .map(Json)
+        .map_err(IntoResponse::into_response)
+}
+
+pub fn router() -> OpenApiRouter {
+    OpenApiRouter::new()
+        .routes(routes!(list_files))
+        .routes(routes!(upload_file, download_file, delete_file))
+        .routes(routes!(search_files))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryStorage;
+
+    fn buckets() -> Buckets {
+        let configs: HashMap<String, BucketConfig> = serde_json::from_str(
+            r#"{
+                "avatars": {
+                    "backend": "memory",
+                    "visibility": "public",
+                    "max_file_size": 4,
+                    "allowed_mime_types": ["image/*"],
+                    "cache_control": "public, max-age=60"
+                },
+                "invoices": { "backend": "memory" }
+            }"#,
+        )
+        .unwrap();
+
+        Buckets::new()
+            .with_backend("memory", MemoryStorage::new())
+            .with_buckets(configs)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_template_buckets() {
+        // The buckets written by `palmera-database init` are declared like any others.
+        for template in &palmera_database::templates::TEMPLATES {
+            let configs: HashMap<String, BucketConfig> =
+                serde_json::from_str(template.buckets()).unwrap();
+            assert!(!configs.is_empty(), "{}", template.name);
+            Buckets::new()
+                .with_backend("local", MemoryStorage::new())
+                .with_buckets(configs)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_config() {
+        let buckets = buckets();
+        assert_eq!(buckets.names(), ["avatars", "invoices"]);
+        assert_eq!(
+            buckets.get("avatars").unwrap().config().visibility,
+            Visibility::Public
+        );
+        assert_eq!(
+            buckets.get("invoices").unwrap().config().visibility,
+            Visibility::Private
+        );
+        assert_eq!(
+            buckets.get("missing").unwrap_err().status_code(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            buckets.get("avatars").unwrap().cache_control(),
+            "public, max-age=60"
+        );
+        assert_eq!(
+            buckets.get("invoices").unwrap().cache_control(),
+            PRIVATE_CACHE_CONTROL
+        );
+
+        let unknown = Buckets::new().with_bucket(
+            "files",
+            BucketConfig {
+                backend: "s3".to_string(),
+                ..Default::default()
+            },
+        );
+        assert!(matches!(unknown, Err(BucketError::UnknownBackend(name)) if name == "s3"));
+    }
+
+    #[tokio::test]
+    async fn test_bucket_limits() {
+        let buckets = buckets();
+        let avatars = buckets.get("avatars").unwrap();
+
+        avatars
+            .upload("me.png", "image/png", b"png", None)
+            .await
+            .unwrap();
+        assert_eq!(avatars.download("me.png").await.unwrap(), b"png");
+        assert_eq!(avatars.list().await.unwrap(), ["me.png"]);
+
+        let too_large = avatars.upload("big.png", "image/png", b"12345", None).await;
+        assert_eq!(
+            too_large.unwrap_err().status_code(),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        let wrong_type = avatars.upload("me.txt", "text/plain", b"a", None).await;
+        assert_eq!(
+            wrong_type.unwrap_err().status_code(),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+        let escaping = avatars.upload("../me.png", "image/png", b"a", None).await;
+        assert_eq!(escaping.unwrap_err().status_code(), StatusCode::BAD_REQUEST);
+        let reserved = avatars
+            .upload(&metadata_name("me.png"), "image/png", b"a", None)
+            .await;
+        assert_eq!(reserved.unwrap_err().status_code(), StatusCode::BAD_REQUEST);
+
+        // Erased users' files are read and deleted by `bucket/name` key.
+        use palmera_database::postgres::privacy::FileStore;
+        assert_eq!(
+            buckets.read("avatars/me.png").await.unwrap().as_deref(),
+            Some(&b"png"[..])
+        );
+        assert_eq!(buckets.read("avatars/gone.png").await.unwrap(), None);
+        assert!(buckets.read("me.png").await.is_err());
+        buckets.delete("avatars/gone.png").await.unwrap();
+
+        // Buckets sharing a backend don't see each other's files.
+        let invoices = buckets.get("invoices").unwrap();
+        assert!(invoices.list().await.unwrap().is_empty());
+        assert_eq!(
+            buckets.usage().await.unwrap(),
+            [
+                BucketUsage {
+                    bucket: "avatars".to_string(),
+                    files: 1,
+                    bytes: 3,
+                },
+                BucketUsage {
+                    bucket: "invoices".to_string(),
+                    files: 0,
+                    bytes: 0,
+                },
+            ]
+        );
+        assert_eq!(
+            invoices.download("me.png").await.unwrap_err().status_code(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    fn claims(subject: Uuid) -> AuthClaims {
+        let config = palmera_auth::AuthConfig::builder()
+            .secret("a-secret-that-is-at-least-32-bytes-long")
+            .build()
+            .unwrap();
+        let token = config
+            .issue_token(subject, chrono::Duration::minutes(5))
+            .unwrap();
+        AuthClaims(config.verify_token(&token).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_owner_only_writes() {
+        let buckets = buckets();
+        let invoices = buckets.get("invoices").unwrap();
+        let (owner, stranger) = (Uuid::new_v4(), Uuid::new_v4());
+        invoices
+            .upload("a.pdf", "application/pdf", b"pdf", Some(owner))
+            .await
+            .unwrap();
+        assert_eq!(
+            invoices.metadata("a.pdf").await.unwrap().unwrap().owner,
+            Some(owner)
+        );
+        assert_eq!(invoices.list().await.unwrap(), ["a.pdf"]);
+
+        let authorize =
+            |subject, action| invoices.authorize(Ok(claims(subject)), Some("a.pdf"), action);
+        assert!(authorize(stranger, FileAction::Read).await.is_ok());
+        for action in [FileAction::Write, FileAction::Delete] {
+            assert!(authorize(owner, action).await.is_ok());
+            assert!(matches!(
+                authorize(stranger, action).await,
+                Err(BucketError::Forbidden)
+            ));
+        }
+        // New files may be uploaded by anyone who authenticated.
+        let new = invoices
+            .authorize(Ok(claims(stranger)), Some("b.pdf"), FileAction::Write)
+            .await;
+        assert!(new.is_ok());
+
+        invoices.delete("a.pdf").await.unwrap();
+        assert!(invoices.metadata("a.pdf").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_download_content_type() {
+        let buckets = Arc::new(buckets());
+        let download = |name: &str| {
+            download_file(
+                Extension(buckets.clone()),
+                Err(StatusCode::UNAUTHORIZED),
+                Path(("avatars".to_string(), name.to_string())),
+            )
+        };
+        let avatars = buckets.get("avatars").unwrap();
+        avatars
+            .upload("me.png", "image/png", b"png", None)
+            .await
+            .unwrap();
+
+        let response = download("me.png").await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+
+        // Metadata written before the content type was recorded.
+        avatars
+            .backend
+            .upload("avatars", &metadata_name("me.png"), br#"{"size":3}"#)
+            .await
+            .unwrap();
+        let response = download("me.png").await.unwrap();
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/octet-stream"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_embedded_storage() {
+        use palmera_database::embedded::Storage;
+
+        let buckets = buckets();
+        let (owner, stranger) = (Uuid::new_v4().to_string(), Uuid::new_v4().to_string());
+        let (owner, stranger) = (Some(owner.as_str()), Some(stranger.as_str()));
+
+        assert!(matches!(
+            Storage::upload(
+                &buckets,
+                None,
+                "invoices",
+                "a.pdf",
+                "application/pdf",
+                b"pdf"
+            )
+            .await,
+            Err(ClientError::Unauthorized)
+        ));
+        Storage::upload(
+            &buckets,
+            owner,
+            "invoices",
+            "a.pdf",
+            "application/pdf",
+            b"pdf",
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            Storage::download(&buckets, stranger, "invoices", "a.pdf")
+                .await
+                .unwrap(),
+            b"pdf"
+        );
+        assert!(matches!(
+            Storage::delete(&buckets, stranger, "invoices", "a.pdf").await,
+            Err(ClientError::Forbidden)
+        ));
+        assert!(matches!(
+            Storage::upload(&buckets, owner, "avatars", "a.txt", "text/plain", b"a").await,
+            Err(ClientError::Storage(_))
+        ));
+        assert!(matches!(
+            Storage::download(&buckets, owner, "missing", "a.pdf").await,
+            Err(ClientError::NotFound)
+        ));
+
+        Storage::delete(&buckets, owner, "invoices", "a.pdf")
+            .await
+            .unwrap();
+        assert!(matches!(
+            Storage::download(&buckets, owner, "invoices", "a.pdf").await,
+            Err(ClientError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_storage_quota() {
+        use std::sync::Mutex;
+
+        let (owner, editor) = (Uuid::new_v4(), Uuid::new_v4());
+
+        // Bytes used by each owner, at most 5 each.
+        let used = Arc::new(Mutex::new(HashMap::<Uuid, i64>::new()));
+        let quota = StorageQuota::new({
+            let used = used.clone();
+            move |owner, bytes| {
+                let mut used = used.lock().unwrap();
+                let total = used.entry(owner).or_default();
+                let fits = *total + bytes <= 5;
+                if fits {
+                    *total += bytes;
+                }
+                async move { fits }
+            }
+        });
+        let used = |subject: Uuid| used.lock().unwrap().get(&subject).copied().unwrap_or(0);
+        // Anyone may write the shared bucket, so the editor can replace the owner's files.
+        let buckets = Arc::new(
+            buckets()
+                .with_policy("invoices", FilePolicy::new(|_, _| async { true }))
+                .unwrap(),
+        );
+        let upload = |subject: Uuid, name: &str, body: &'static str| {
+            upload_file(
+                Extension(buckets.clone()),
+                Ok(claims(subject)),
+                Some(Extension(quota.clone())),
+                None,
+                Path(("invoices".to_string(), name.to_string())),
+                HeaderMap::new(),
+                Body::from(body),
+            )
+        };
+
+        assert_eq!(
+            upload(owner, "a", "abc").await.unwrap(),
+            StatusCode::CREATED
+        );
+        let over = upload(owner, "b", "abc").await.unwrap_err();
+        assert_eq!(over.status_code(), StatusCode::PAYMENT_REQUIRED);
+        // A failed upload gives its bytes back.
+        assert!(upload(owner, "..", "de").await.is_err());
+        assert_eq!(used(owner), 3);
+        assert_eq!(
+            buckets
+                .get("invoices")
+                .unwrap()
+                .metadata("a")
+                .await
+                .unwrap(),
+            Some(FileMetadata {
+                owner: Some(owner),
+                size: 3,
+                content_type: Some("application/octet-stream".to_string()),
+            })
+        );
+
+        // Replacing a file charges its owner the difference in size, whoever replaces it.
+        assert_eq!(
+            upload(editor, "a", "abcde").await.unwrap(),
+            StatusCode::CREATED
+        );
+        assert_eq!((used(owner), used(editor)), (5, 0));
+        assert_eq!(
+            upload(editor, "a", "abc").await.unwrap(),
+            StatusCode::CREATED
+        );
+        assert_eq!(used(owner), 3);
+
+        let deleted = delete_file(
+            Extension(buckets.clone()),
+            Ok(claims(editor)),
+            Some(Extension(quota.clone())),
+            None,
+            Path(("invoices".to_string(), "a".to_string())),
+        )
+        .await;
+        assert_eq!(deleted.unwrap(), StatusCode::NO_CONTENT);
+        assert_eq!((used(owner), used(editor)), (0, 0));
+        assert_eq!(
+            upload(owner, "b", "abc").await.unwrap(),
+            StatusCode::CREATED
+        );
+    }
+
+    #[tokio::test]
+    async fn test_file_index() {
+        let db = sqlx::SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let index = Arc::new(FileIndex::open(db).await.unwrap());
+        let buckets = Arc::new(buckets());
+        let user = Uuid::new_v4();
+        let upload = |bucket: &str, name: &str, mime: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(mime));
+            upload_file(
+                Extension(buckets.clone()),
+                Ok(claims(user)),
+                None,
+                Some(Extension(index.clone())),
+                Path((bucket.to_string(), name.to_string())),
+                headers,
+                Body::from("abc"),
+            )
+        };
+        upload("avatars", "me.png", "image/png").await.unwrap();
+        upload("invoices", "a.pdf", "application/pdf")
+            .await
+            .unwrap();
+        upload("invoices", "b.pdf", "application/pdf")
+            .await
+            .unwrap();
+
+        let search = |claims: Result<AuthClaims, StatusCode>, filter: &str| {
+            search_files(
+                Extension(buckets.clone()),
+                claims,
+                Some(Extension(index.clone())),
+                Query(FileSearch {
+                    filter: Some(filter.to_string()),
+                    sort: Some("name".to_string()),
+                    ..Default::default()
+                }),
+            )
+        };
+        let Json(page) = search(Ok(claims(user)), "size = 3").await.unwrap();
+        let names: Vec<_> = page.items.iter().map(|file| file.name.as_str()).collect();
+        assert_eq!(names, ["a.pdf", "b.pdf", "me.png"]);
+        assert_eq!(page.items[2].mime, "image/png");
+
+        // Anonymous callers only see the public bucket.
+        let Json(page) = search(Err(StatusCode::UNAUTHORIZED), "size > 0")
+            .await
+            .unwrap();
+        assert_eq!(page.items.len(), 1);
+        assert_eq!(page.items[0].bucket, "avatars");
+
+        let invalid = search(Ok(claims(user)), "owner = 'me'").await.unwrap_err();
+        assert_eq!(invalid.status(), StatusCode::BAD_REQUEST);
+
+        let deleted = delete_file(
+            Extension(buckets.clone()),
+            Ok(claims(user)),
+            None,
+            Some(Extension(index.clone())),
+            Path(("invoices".to_string(), "a.pdf".to_string())),
+        )
+        .await;
+        assert_eq!(deleted.unwrap(), StatusCode::NO_CONTENT);
+        let Json(page) = search(Ok(claims(user)), "bucket = 'invoices'")
+            .await
+            .unwrap();
+        assert_eq!(page.total, 1);
+        assert_eq!(page.items[0].name, "b.pdf");
+    }
+}
@@ -0,0 +1,179 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// Limits applied to files uploaded for a table.
+///
+/// Constraints are stored as JSON in the table's metadata and checked by the upload
+/// handler before any bytes reach a storage backend. Every limit is optional; the
+/// default value accepts everything.
+///
+/// # Example
+///
+/// ```rust
+/// use palmera_storage::constraints::UploadConstraints;
+///
+/// let constraints: UploadConstraints = serde_json::from_str(
+///     r#"{ "max_file_size": 1048576, "allowed_mime_types": ["image/*"], "max_files": 3 }"#,
+/// ).unwrap();
+///
+/// assert!(constraints.check_file("image/png", 2048).is_ok());
+/// assert_eq!(constraints.check_file("text/plain", 10).unwrap_err().status_code(), 415);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UploadConstraints {
+    /// Maximum size of a single file in bytes.
+    pub max_file_size: Option<u64>,
+    /// Accepted MIME types. Entries may end in `/*` to accept a whole type family.
+    /// An empty list accepts any type.
+    pub allowed_mime_types: Vec<String>,
+    /// Maximum number of files in a single request.
+    pub max_files: Option<usize>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum UploadViolation {
+    FileTooLarge { size: u64, max: u64 },
+    UnsupportedMediaType(String),
+    TooManyFiles { count: usize, max: usize },
+}
+
+impl UploadViolation {
+    /// HTTP status the upload should be rejected with.
+    pub fn status_code(&self) -> u16 {
+        match self {
+            UploadViolation::FileTooLarge { .. } | UploadViolation::TooManyFiles { .. } => 413,
+            UploadViolation::UnsupportedMediaType(_) => 415,
+        }
+    }
+}
+
+impl fmt::Display for UploadViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UploadViolation::FileTooLarge { size, max } => write!(
+                f,
+                "File is {} bytes, the maximum allowed size is {} bytes",
+                size, max
+            ),
+            UploadViolation::UnsupportedMediaType(mime) => {
+                write!(f, "Files of type {} are not allowed", mime)
+            }
+            UploadViolation::TooManyFiles { count, max } => write!(
+                f,
+                "{} files were uploaded, at most {} are allowed",
+                count, max
+            ),
+        }
+    }
+}
+
+impl std::error::Error for UploadViolation {}
+
+impl UploadConstraints {
+    /// Checks the number of files in a request.
+    pub fn check_count(&self, count: usize) -> Result<(), UploadViolation> {
+        match self.max_files {
+            Some(max) if count > max => Err(UploadViolation::TooManyFiles { count, max }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks a file's size. Call it with the running total while streaming a file to
+    /// reject oversized uploads before they are fully read.
+    pub fn check_size(&self, size: u64) -> Result<(), UploadViolation> {
+        match self.max_file_size {
+            Some(max) if size > max => Err(UploadViolation::FileTooLarge { size, max }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks a file's MIME type; parameters such as `; charset=utf-8` are ignored.
+    pub fn check_mime(&self, mime: &str) -> Result<(), UploadViolation> {
+        if self.allowed_mime_types.is_empty() {
+            return Ok(());
+        }
+
+        let essence = mime
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+
+        let allowed = self.allowed_mime_types.iter().any(|pattern| {
+            let pattern = pattern.to_ascii_lowercase();
+            match pattern.strip_suffix("/*") {
+                Some(family) => essence.split('/').next() == Some(family),
+                None => essence == pattern,
+            }
+        });
+
+        if allowed {
+            Ok(())
+        } else {
+            Err(UploadViolation::UnsupportedMediaType(mime.to_string()))
+        }
+    }
+
+    /// Checks both the MIME type and the size of a file.
+    pub fn check_file(&self, mime: &str, size: u64) -> Result<(), UploadViolation> {
+        self.check_mime(mime)?;
+        self.check_size(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn constraints() -> UploadConstraints {
+        UploadConstraints {
+            max_file_size: Some(100),
+            allowed_mime_types: vec!["image/*".to_string(), "application/pdf".to_string()],
+            max_files: Some(2),
+        }
+    }
+
+    #[test]
+    fn test_default_accepts_everything() {
+        let constraints = UploadConstraints::default();
+        assert!(
+            constraints
+                .check_file("application/x-anything", u64::MAX)
+                .is_ok()
+        );
+        assert!(constraints.check_count(1000).is_ok());
+    }
+
+    #[test]
+    fn test_mime_patterns() {
+        let constraints = constraints();
+        assert!(constraints.check_mime("image/png").is_ok());
+        assert!(constraints.check_mime("IMAGE/JPEG").is_ok());
+        assert!(
+            constraints
+                .check_mime("application/pdf; charset=binary")
+                .is_ok()
+        );
+        assert_eq!(
+            constraints.check_mime("text/plain"),
+            Err(UploadViolation::UnsupportedMediaType(
+                "text/plain".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_size_and_count_limits() {
+        let constraints = constraints();
+        assert!(constraints.check_size(100).is_ok());
+
+        let too_large = constraints.check_size(101).unwrap_err();
+        assert_eq!(too_large.status_code(), 413);
+
+        assert!(constraints.check_count(2).is_ok());
+        assert_eq!(constraints.check_count(3).unwrap_err().status_code(), 413);
+    }
+}
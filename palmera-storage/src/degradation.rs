@@ -0,0 +1,465 @@
+//! # Degraded mode
+//!
+//! [`DegradedStorage`] wraps a backend with a health check, so an unreachable backend
+//! only takes the file routes down. While the last check failed the storage is degraded:
+//!
+//! - every call fails at once with [`FileStorageError::Unavailable`], which the bucket
+//!   routes answer with `503 Service Unavailable` and a `Retry-After` header. Record
+//!   routes don't touch file storage and keep working;
+//! - uploads made with [`DegradedStorage::upload_or_park`], e.g. by background jobs, are
+//!   parked in memory instead of failing, and uploaded by the first check that finds
+//!   the backend healthy again. Uploads failing with a transient error are parked too,
+//!   and parked uploads the backend refuses are kept for [`DegradedStorage::take_failed`].
+//!
+//! Parked uploads are held in memory, so they are capped by count and total size, 1000
+//! uploads and 64 MiB unless set with [`DegradedStorage::with_park_limits`]. Once full,
+//! `upload_or_park` fails with [`FileStorageError::Unavailable`] like any other call.
+//!
+//! The storage stays degraded until every parked upload is flushed, so a direct upload
+//! can't be overwritten by an older parked copy of the same file. A newer upload or a
+//! delete of a file drops its parked copies.
+//!
+//! The check lists a reserved namespace of the backend; a missing namespace counts as
+//! healthy. Checks run on demand with [`DegradedStorage::check`] or on an interval with
+//! [`DegradedStorage::run_health_checks`]. Parked uploads are lost on restart.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! use std::{path::PathBuf, time::Duration};
+//! use palmera_storage::{bucket::Buckets, degradation::DegradedStorage, local::LocalStorage};
+//!
+//! # async fn run() {
+//! let storage = DegradedStorage::new(LocalStorage::new(PathBuf::from("uploads")));
+//! let buckets = Buckets::new().with_backend("local", storage.clone());
+//!
+//! tokio::spawn(async move { storage.run_health_checks(Duration::from_secs(10)).await });
+//! # }
+//! ```
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use crate::traits::{FileResult, FileStorageError, FileStorageHandler};
+
+/// Namespace listed by the health check.
+pub const HEALTH_CHECK_ID: &str = "_health";
+
+/// Uploads parked at most by default.
+pub const DEFAULT_MAX_PARKED: usize = 1000;
+
+/// Total size of the parked uploads at most by default, 64 MiB.
+pub const DEFAULT_MAX_PARKED_BYTES: usize = 64 * 1024 * 1024;
+
+/// An upload waiting for the backend to recover.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParkedUpload {
+    pub id: String,
+    pub name: String,
+    pub bytes: Vec<u8>,
+}
+
+/// Outcome of [`DegradedStorage::upload_or_park`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UploadOutcome {
+    Uploaded,
+    /// The backend is unavailable; the upload is retried once it recovers.
+    Parked,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    degraded: AtomicBool,
+    /// Whether a check is uploading the parked uploads.
+    flushing: AtomicBool,
+    parked: Mutex<VecDeque<ParkedUpload>>,
+    failed: Mutex<Vec<(ParkedUpload, FileStorageError)>>,
+}
+
+impl State {
+    /// Drops the parked copies of file `name` of `id`, superseded by a newer write.
+    fn supersede(&self, id: &str, name: &str) {
+        self.parked
+            .lock()
+            .unwrap()
+            .retain(|upload| upload.id != id || upload.name != name);
+    }
+}
+
+/// A backend that degrades gracefully while its health check fails; see the
+/// [module docs](self). Clones share the backend and its health.
+#[derive(Debug)]
+pub struct DegradedStorage<S> {
+    inner: Arc<S>,
+    state: Arc<State>,
+    retry_after: Duration,
+    max_parked: usize,
+    max_parked_bytes: usize,
+}
+
+impl<S> Clone for DegradedStorage<S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            state: self.state.clone(),
+            retry_after: self.retry_after,
+            max_parked: self.max_parked,
+            max_parked_bytes: self.max_parked_bytes,
+        }
+    }
+}
+
+impl<S: FileStorageHandler + Sync> DegradedStorage<S> {
+    /// Wraps `inner`, healthy until a check fails, telling clients to retry after 30
+    /// seconds.
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            state: Arc::new(State::default()),
+            retry_after: Duration::from_secs(30),
+            max_parked: DEFAULT_MAX_PARKED,
+            max_parked_bytes: DEFAULT_MAX_PARKED_BYTES,
+        }
+    }
+
+    /// Delay reported in `Retry-After` while degraded.
+    pub fn with_retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = retry_after;
+        self
+    }
+
+    /// Parks at most `count` uploads of at most `bytes` in total.
+    pub fn with_park_limits(mut self, count: usize, bytes: usize) -> Self {
+        self.max_parked = count;
+        self.max_parked_bytes = bytes;
+        self
+    }
+
+    pub fn is_degraded(&self) -> bool {
+        self.state.degraded.load(Ordering::SeqCst)
+    }
+
+    /// Number of uploads waiting for the backend to recover.
+    pub fn parked(&self) -> usize {
+        self.state.parked.lock().unwrap().len()
+    }
+
+    /// Takes the parked uploads the backend refused once it recovered, with its errors.
+    pub fn take_failed(&self) -> Vec<(ParkedUpload, FileStorageError)> {
+        std::mem::take(&mut *self.state.failed.lock().unwrap())
+    }
+
+    /// Checks the backend's health and returns whether it is healthy. A healthy backend
+    /// receives the parked uploads, and leaves degraded mode once they are all uploaded.
+    pub async fn check(&self) -> bool {
+        let healthy = match self.inner.list(HEALTH_CHECK_ID).await {
+            Ok(_) => true,
+            Err(err) => err.is_not_found(),
+        };
+        if !healthy {
+            self.state.degraded.store(true, Ordering::SeqCst);
+        } else if self
+            .state
+            .flushing
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
+            self.flush().await;
+            self.state.flushing.store(false, Ordering::SeqCst);
+        }
+        !self.is_degraded()
+    }
+
+    /// Runs [`DegradedStorage::check`] every `interval`, forever.
+    pub async fn run_health_checks(&self, interval: Duration) {
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            self.check().await;
+        }
+    }
+
+    /// Uploads `bytes`, or parks them while the backend is unavailable, replacing the
+    /// parked copies of the same file.
+    ///
+    /// # Errors
+    ///
+    /// Fails with errors of the backend that aren't transient, e.g. a refused name, and
+    /// with [`FileStorageError::Unavailable`] if the upload can't be parked because the
+    /// parked uploads reached their limits.
+    pub async fn upload_or_park(
+        &self,
+        id: &str,
+        name: &str,
+        bytes: &[u8],
+    ) -> FileResult<UploadOutcome> {
+        if !self.is_degraded() {
+            match self.inner.upload(id, name, bytes).await {
+                Ok(()) => {
+                    self.state.supersede(id, name);
+                    return Ok(UploadOutcome::Uploaded);
+                }
+                Err(err) if !err.is_transient() => return Err(err),
+                Err(_) => {}
+            }
+        }
+
+        let mut parked = self.state.parked.lock().unwrap();
+        parked.retain(|upload| upload.id != id || upload.name != name);
+        let parked_bytes: usize = parked.iter().map(|upload| upload.bytes.len()).sum();
+        if parked.len() >= self.max_parked || parked_bytes + bytes.len() > self.max_parked_bytes {
+            return Err(FileStorageError::Unavailable {
+                retry_after: self.retry_after,
+            });
+        }
+        parked.push_back(ParkedUpload {
+            id: id.to_string(),
+            name: name.to_string(),
+            bytes: bytes.to_vec(),
+        });
+        Ok(UploadOutcome::Parked)
+    }
+
+    /// Uploads the parked uploads in order, degrading the storage until they are all
+    /// uploaded. A transient failure parks the upload again, in front, and keeps the
+    /// storage degraded.
+    async fn flush(&self) {
+        loop {
+            let upload = {
+                let mut parked = self.state.parked.lock().unwrap();
+                match parked.pop_front() {
+                    Some(upload) => {
+                        self.state.degraded.store(true, Ordering::SeqCst);
+                        upload
+                    }
+                    None => {
+                        // Cleared under the lock, so nothing is parked in between.
+                        self.state.degraded.store(false, Ordering::SeqCst);
+                        return;
+                    }
+                }
+            };
+            match self
+                .inner
+                .upload(&upload.id, &upload.name, &upload.bytes)
+                .await
+            {
+                Ok(()) => {}
+                Err(err) if err.is_transient() => {
+                    let mut parked = self.state.parked.lock().unwrap();
+                    // A newer copy parked meanwhile supersedes this one.
+                    if !parked
+                        .iter()
+                        .any(|newer| newer.id == upload.id && newer.name == upload.name)
+                    {
+                        parked.push_front(upload);
+                    }
+                    return;
+                }
+                Err(err) => self.state.failed.lock().unwrap().push((upload, err)),
+            }
+        }
+    }
+
+    fn check_available(&self) -> FileResult<()> {
+        if self.is_degraded() {
+            Err(FileStorageError::Unavailable {
+                retry_after: self.retry_after,
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<S: FileStorageHandler + Sync + Send> FileStorageHandler for DegradedStorage<S> {
+    async fn upload(&self, id: &str, name: &str, bytes: &[u8]) -> FileResult<()> {
+        self.check_available()?;
+        self.inner.upload(id, name, bytes).await?;
+        self.state.supersede(id, name);
+        Ok(())
+    }
+
+    async fn download(&self, id: &str, name: &str) -> FileResult<Vec<u8>> {
+        self.check_available()?;
+        self.inner.download(id, name).await
+    }
+
+    async fn list(&self, id: &str) -> FileResult<Vec<String>> {
+        self.check_available()?;
+        self.inner.list(id).await
+    }
+
+    async fn delete(&self, id: &str, name: &str) -> FileResult<()> {
+        self.check_available()?;
+        self.inner.delete(id, name).await?;
+        self.state.supersede(id, name);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bucket::{BucketConfig, BucketError, Buckets},
+        memory::MemoryStorage,
+    };
+    use axum::{http::StatusCode, response::IntoResponse};
+    use std::io;
+
+    /// Memory storage that can be switched off.
+    #[derive(Default)]
+    struct Switchable {
+        storage: MemoryStorage,
+        down: AtomicBool,
+    }
+
+    impl Switchable {
+        fn check(&self) -> FileResult<()> {
+            if self.down.load(Ordering::SeqCst) {
+                Err(FileStorageError::Io(
+                    io::ErrorKind::ConnectionRefused.into(),
+                ))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    impl FileStorageHandler for Switchable {
+        async fn upload(&self, id: &str, name: &str, bytes: &[u8]) -> FileResult<()> {
+            self.check()?;
+            self.storage.upload(id, name, bytes).await
+        }
+
+        async fn download(&self, id: &str, name: &str) -> FileResult<Vec<u8>> {
+            self.check()?;
+            self.storage.download(id, name).await
+        }
+
+        async fn list(&self, id: &str) -> FileResult<Vec<String>> {
+            self.check()?;
+            self.storage.list(id).await
+        }
+
+        async fn delete(&self, id: &str, name: &str) -> FileResult<()> {
+            self.check()?;
+            self.storage.delete(id, name).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_degraded_mode() {
+        let storage =
+            DegradedStorage::new(Switchable::default()).with_retry_after(Duration::from_secs(5));
+        assert!(storage.check().await);
+        assert_eq!(
+            storage.upload_or_park("files", "a", b"a").await.unwrap(),
+            UploadOutcome::Uploaded
+        );
+
+        storage.inner.down.store(true, Ordering::SeqCst);
+        assert!(!storage.check().await);
+        assert!(storage.is_degraded());
+        let err = storage.download("files", "a").await.unwrap_err();
+        assert!(matches!(
+            err,
+            FileStorageError::Unavailable { retry_after } if retry_after == Duration::from_secs(5)
+        ));
+
+        // The bucket routes answer with 503 and Retry-After.
+        let response = BucketError::Storage(err).into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()["retry-after"], "5");
+
+        assert_eq!(
+            storage.upload_or_park("files", "b", b"b").await.unwrap(),
+            UploadOutcome::Parked
+        );
+        assert_eq!(
+            storage.upload_or_park("files", "c", b"c").await.unwrap(),
+            UploadOutcome::Parked
+        );
+        assert_eq!(storage.parked(), 2);
+
+        storage.inner.down.store(false, Ordering::SeqCst);
+        assert!(storage.check().await);
+        assert!(!storage.is_degraded());
+        assert_eq!(storage.parked(), 0);
+        assert_eq!(storage.list("files").await.unwrap().len(), 3);
+        assert_eq!(storage.download("files", "c").await.unwrap(), b"c");
+    }
+
+    #[tokio::test]
+    async fn test_park_limits() {
+        let storage = DegradedStorage::new(Switchable::default()).with_park_limits(2, 3);
+        storage.inner.down.store(true, Ordering::SeqCst);
+        assert!(!storage.check().await);
+
+        assert_eq!(
+            storage.upload_or_park("files", "a", b"aa").await.unwrap(),
+            UploadOutcome::Parked
+        );
+        let err = storage
+            .upload_or_park("files", "b", b"bb")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, FileStorageError::Unavailable { .. }));
+        // A newer copy replaces the parked one instead of counting twice.
+        assert_eq!(
+            storage.upload_or_park("files", "a", b"AAA").await.unwrap(),
+            UploadOutcome::Parked
+        );
+        assert_eq!(storage.parked(), 1);
+
+        storage.inner.down.store(false, Ordering::SeqCst);
+        assert!(storage.check().await);
+        assert_eq!(storage.download("files", "a").await.unwrap(), b"AAA");
+    }
+
+    #[tokio::test]
+    async fn test_direct_upload_supersedes_parked_copy() {
+        let storage = DegradedStorage::new(Switchable::default());
+        // A transient failure parks the upload without a failed check.
+        storage.inner.down.store(true, Ordering::SeqCst);
+        assert_eq!(
+            storage.upload_or_park("files", "a", b"old").await.unwrap(),
+            UploadOutcome::Parked
+        );
+
+        storage.inner.down.store(false, Ordering::SeqCst);
+        storage.upload("files", "a", b"new").await.unwrap();
+        assert_eq!(storage.parked(), 0);
+        assert!(storage.check().await);
+        assert_eq!(storage.download("files", "a").await.unwrap(), b"new");
+    }
+
+    #[tokio::test]
+    async fn test_buckets_on_degraded_storage() {
+        let storage = DegradedStorage::new(Switchable::default());
+        let buckets = Buckets::new()
+            .with_backend("switchable", storage.clone())
+            .with_bucket(
+                "files",
+                BucketConfig {
+                    backend: "switchable".to_string(),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let bucket = buckets.get("files").unwrap();
+        bucket.upload("a", "text/plain", b"a", None).await.unwrap();
+
+        storage.inner.down.store(true, Ordering::SeqCst);
+        storage.check().await;
+        let err = bucket.download("a").await.unwrap_err();
+        assert_eq!(err.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
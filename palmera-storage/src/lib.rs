@@ -1,3 +1,14 @@
+pub mod access;
+pub mod archive;
+pub mod backup;
+pub mod bucket;
+pub mod constraints;
+pub mod degradation;
 pub mod local;
+pub mod memory;
+pub mod quota;
+pub mod resilience;
 pub mod s3;
+pub mod signed;
 pub mod traits;
+pub mod versioning;
@@ -1,4 +1,7 @@
-use std::{io, path::PathBuf};
+use std::{
+    io,
+    path::{Component, Path, PathBuf},
+};
 
 use tokio::io::AsyncWriteExt;
 
@@ -12,24 +15,34 @@ impl LocalStorage {
     pub fn new(dir: PathBuf) -> Self {
         Self { base_dir: dir }
     }
+
+    /// Returns the on-disk path of `id/name`, or `None` if either part would escape
+    /// its directory.
+    pub fn path(&self, id: &str, name: &str) -> Option<PathBuf> {
+        let is_plain = |part: &str| {
+            matches!(
+                Path::new(part).components().collect::<Vec<_>>().as_slice(),
+                [Component::Normal(_)]
+            )
+        };
+
+        (is_plain(id) && is_plain(name)).then(|| self.base_dir.join(id).join(name))
+    }
 }
 
 impl FileStorageHandler for LocalStorage {
     async fn upload(&self, id: &str, name: &str, bytes: &[u8]) -> crate::traits::FileResult<()> {
-        tokio::fs::create_dir(self.base_dir.join(id))
+        tokio::fs::create_dir_all(self.base_dir.join(id))
             .await
-            .map_err(|err| FileStorageError::Local(err))?;
+            .map_err(FileStorageError::Local)?;
 
         let path = self.base_dir.join(id).join(name);
 
         let mut file = tokio::fs::File::create_new(path)
             .await
-            .map_err(|err| FileStorageError::Local(err))?;
+            .map_err(FileStorageError::Local)?;
 
-        Ok(file
-            .write_all(bytes)
-            .await
-            .map_err(|err| FileStorageError::Local(err))?)
+        file.write_all(bytes).await.map_err(FileStorageError::Local)
     }
 
     async fn download(&self, id: &str, name: &str) -> crate::traits::FileResult<Vec<u8>> {
@@ -37,7 +50,7 @@ impl FileStorageHandler for LocalStorage {
 
         let file = tokio::fs::read(path)
             .await
-            .map_err(|err| FileStorageError::Local(err))?;
+            .map_err(FileStorageError::Local)?;
 
         Ok(file)
     }
@@ -47,14 +60,14 @@ impl FileStorageHandler for LocalStorage {
 
         let mut dir_list = tokio::fs::read_dir(dir)
             .await
-            .map_err(|err| FileStorageError::Local(err))?;
+            .map_err(FileStorageError::Local)?;
 
         let mut files = vec![];
 
         while let Some(entry) = dir_list
             .next_entry()
             .await
-            .map_err(|err| FileStorageError::Local(err))?
+            .map_err(FileStorageError::Local)?
         {
             let file_name = entry.file_name();
             let file_name_str = file_name
@@ -65,4 +78,12 @@ impl FileStorageHandler for LocalStorage {
 
         Ok(files)
     }
+
+    async fn delete(&self, id: &str, name: &str) -> crate::traits::FileResult<()> {
+        let path = self.base_dir.join(id).join(name);
+
+        tokio::fs::remove_file(path)
+            .await
+            .map_err(FileStorageError::Local)
+    }
 }
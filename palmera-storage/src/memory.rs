@@ -0,0 +1,85 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    sync::Mutex,
+};
+
+use crate::traits::{FileResult, FileStorageError, FileStorageHandler};
+
+/// Storage that keeps files in process memory, for tests and ephemeral setups.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    files: Mutex<HashMap<String, BTreeMap<String, Vec<u8>>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+fn not_found() -> FileStorageError {
+    FileStorageError::Local(io::ErrorKind::NotFound.into())
+}
+
+impl FileStorageHandler for MemoryStorage {
+    async fn upload(&self, id: &str, name: &str, bytes: &[u8]) -> FileResult<()> {
+        self.files
+            .lock()
+            .unwrap()
+            .entry(id.to_string())
+            .or_default()
+            .insert(name.to_string(), bytes.to_vec());
+
+        Ok(())
+    }
+
+    async fn download(&self, id: &str, name: &str) -> FileResult<Vec<u8>> {
+        self.files
+            .lock()
+            .unwrap()
+            .get(id)
+            .and_then(|files| files.get(name))
+            .cloned()
+            .ok_or_else(not_found)
+    }
+
+    async fn list(&self, id: &str) -> FileResult<Vec<String>> {
+        self.files
+            .lock()
+            .unwrap()
+            .get(id)
+            .map(|files| files.keys().cloned().collect())
+            .ok_or_else(not_found)
+    }
+
+    async fn delete(&self, id: &str, name: &str) -> FileResult<()> {
+        self.files
+            .lock()
+            .unwrap()
+            .get_mut(id)
+            .and_then(|files| files.remove(name))
+            .map(|_| ())
+            .ok_or_else(not_found)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_memory_storage() {
+        let storage = MemoryStorage::new();
+
+        storage.upload("docs", "b.txt", b"b").await.unwrap();
+        storage.upload("docs", "a.txt", b"a").await.unwrap();
+
+        assert_eq!(storage.download("docs", "a.txt").await.unwrap(), b"a");
+        assert_eq!(storage.list("docs").await.unwrap(), vec!["a.txt", "b.txt"]);
+
+        storage.delete("docs", "a.txt").await.unwrap();
+        assert!(storage.download("docs", "a.txt").await.is_err());
+        assert!(storage.list("missing").await.is_err());
+    }
+}
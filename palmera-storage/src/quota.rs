@@ -0,0 +1,86 @@
+//! # Storage quotas
+//!
+//! A [`StorageQuota`] extension charges the bytes users store against a quota kept
+//! elsewhere, e.g. the `palmera-database` quota store. The bucket routes charge the size
+//! of a file before uploading it, or only its growth when it replaces a file, and answer
+//! `402 Payment Required` if the quota turns it down; deleting a file gives its size
+//! back. The quota charged is that of the file's owner, the user who first uploaded it,
+//! whoever replaces or deletes it later. Sizes are taken from the file's
+//! [`FileMetadata`](crate::bucket::FileMetadata), so nothing is downloaded to charge them.
+//!
+//! # Example
+//!
+//! ```rust
+//! use std::sync::{
+//!     Arc,
+//!     atomic::{AtomicI64, Ordering},
+//! };
+//!
+//! use axum::Extension;
+//! use palmera_storage::{bucket, quota::StorageQuota};
+//!
+//! // A single shared allowance of 1 MiB.
+//! let remaining = Arc::new(AtomicI64::new(1 << 20));
+//! let quota = StorageQuota::new(move |_owner, bytes| {
+//!     let remaining = remaining.clone();
+//!     async move {
+//!         let left = remaining.fetch_sub(bytes, Ordering::SeqCst) - bytes;
+//!         if left < 0 {
+//!             remaining.fetch_add(bytes, Ordering::SeqCst);
+//!         }
+//!         left >= 0
+//!     }
+//! });
+//!
+//! let router = bucket::router().layer(Extension(quota));
+//! ```
+
+use std::{fmt, future::Future, pin::Pin, sync::Arc};
+
+use uuid::Uuid;
+
+use crate::bucket::BucketError;
+
+type Charge = Arc<dyn Fn(Uuid, i64) -> Pin<Box<dyn Future<Output = bool> + Send>> + Send + Sync>;
+
+/// Charges stored bytes to the quota of the files' owners.
+#[derive(Clone)]
+pub struct StorageQuota(Charge);
+
+impl fmt::Debug for StorageQuota {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("StorageQuota")
+    }
+}
+
+impl StorageQuota {
+    /// Charges with `charge`, called with the id of the file's owner and a number of
+    /// bytes: positive when a file is stored, resolving to whether the quota has room
+    /// for it, and negative when a file is deleted, in which case the result is ignored.
+    pub fn new<F, Fut>(charge: F) -> Self
+    where
+        F: Fn(Uuid, i64) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = bool> + Send + 'static,
+    {
+        Self(Arc::new(move |owner, bytes| Box::pin(charge(owner, bytes))))
+    }
+
+    /// Reserves `bytes` for `owner`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`BucketError::QuotaExceeded`] if the quota has no room for them.
+    pub async fn reserve(&self, owner: Uuid, bytes: u64) -> Result<(), BucketError> {
+        let bytes = i64::try_from(bytes).unwrap_or(i64::MAX);
+        match (self.0)(owner, bytes).await {
+            true => Ok(()),
+            false => Err(BucketError::QuotaExceeded),
+        }
+    }
+
+    /// Gives `bytes` back to the quota of `owner`.
+    pub async fn release(&self, owner: Uuid, bytes: u64) {
+        let bytes = i64::try_from(bytes).unwrap_or(i64::MAX);
+        (self.0)(owner, -bytes).await;
+    }
+}
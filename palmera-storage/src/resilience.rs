@@ -0,0 +1,499 @@
+//! # Retries and circuit breaking
+//!
+//! [`ResilientStorage`] wraps a [`FileStorageHandler`] so that transient failures (see
+//! [`FileStorageError::is_transient`]) are retried with exponential backoff and full
+//! jitter, and a [`CircuitBreaker`] stops sending requests to a backend that keeps
+//! failing. While the circuit is open calls fail immediately with
+//! [`FileStorageError::CircuitOpen`]; after the reset timeout a single trial call is let
+//! through to probe whether the backend has recovered.
+//!
+//! Retries, state changes and rejected calls are reported to an optional observer and
+//! counted in [`ResilienceStats`].
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! use std::{path::PathBuf, time::Duration};
+//! use palmera_storage::{
+//!     local::LocalStorage,
+//!     resilience::{CircuitBreaker, ResilientStorage, RetryPolicy},
+//! };
+//!
+//! let storage = ResilientStorage::new(LocalStorage::new(PathBuf::from("uploads")))
+//!     .with_retry(RetryPolicy::new(5, Duration::from_millis(50)))
+//!     .with_circuit_breaker(CircuitBreaker::new(10, Duration::from_secs(30)))
+//!     .with_observer(|event| println!("storage: {:?}", event));
+//! ```
+
+use std::{
+    collections::hash_map::RandomState,
+    future::Future,
+    hash::{BuildHasher, Hasher},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::traits::{FileResult, FileStorageError, FileStorageHandler};
+
+/// How transient failures are retried.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one.
+    pub max_attempts: u32,
+    /// Backoff before the first retry; doubled on every following retry.
+    pub base_delay: Duration,
+    /// Upper bound for the backoff.
+    pub max_delay: Duration,
+    /// Sleep a random duration between zero and the backoff instead of the full backoff,
+    /// so that clients failing together do not retry together.
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(5),
+            jitter: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new(max_attempts: u32, base_delay: Duration) -> Self {
+        Self {
+            max_attempts,
+            base_delay,
+            ..Default::default()
+        }
+    }
+
+    /// Disables retries.
+    pub fn none() -> Self {
+        Self::new(1, Duration::ZERO)
+    }
+
+    pub fn max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    pub fn jitter(mut self, jitter: bool) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Delay before retry number `retry` (starting at 1).
+    pub fn delay(&self, retry: u32) -> Duration {
+        let backoff = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+            .min(self.max_delay);
+
+        if self.jitter {
+            backoff.mul_f64(random_fraction())
+        } else {
+            backoff
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Calls go through; failures are being counted.
+    Closed,
+    /// Calls are rejected until the reset timeout has passed.
+    Open,
+    /// A trial call is in flight to check whether the backend recovered.
+    HalfOpen,
+}
+
+#[derive(Debug)]
+struct BreakerState {
+    state: CircuitState,
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+}
+
+/// Opens after `failure_threshold` consecutive transient failures and stays open for
+/// `reset_timeout`.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    reset_timeout: Duration,
+    inner: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, reset_timeout: Duration) -> Self {
+        Self {
+            failure_threshold: failure_threshold.max(1),
+            reset_timeout,
+            inner: Mutex::new(BreakerState {
+                state: CircuitState::Closed,
+                consecutive_failures: 0,
+                opened_at: None,
+            }),
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.inner.lock().unwrap().state
+    }
+
+    /// Returns whether a call may proceed, moving an expired open circuit to half-open.
+    fn acquire(&self) -> Result<Option<CircuitState>, ()> {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.state == CircuitState::Closed {
+            return Ok(None);
+        }
+
+        // A half-open circuit lets another trial through if the previous one never
+        // reported back, e.g. because its future was dropped.
+        let expired = inner
+            .opened_at
+            .is_none_or(|opened_at| opened_at.elapsed() >= self.reset_timeout);
+        if !expired {
+            return Err(());
+        }
+
+        inner.opened_at = Some(Instant::now());
+        if inner.state == CircuitState::HalfOpen {
+            return Ok(None);
+        }
+
+        inner.state = CircuitState::HalfOpen;
+        Ok(Some(CircuitState::HalfOpen))
+    }
+
+    /// Records a successful call, returning the new state if it changed.
+    fn record_success(&self) -> Option<CircuitState> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures = 0;
+
+        if inner.state == CircuitState::Closed {
+            return None;
+        }
+
+        inner.state = CircuitState::Closed;
+        inner.opened_at = None;
+        Some(CircuitState::Closed)
+    }
+
+    /// Records a transient failure, returning the new state if it changed.
+    fn record_failure(&self) -> Option<CircuitState> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures += 1;
+
+        let trip = inner.state == CircuitState::HalfOpen
+            || inner.consecutive_failures >= self.failure_threshold;
+
+        if !trip || inner.state == CircuitState::Open {
+            return None;
+        }
+
+        inner.state = CircuitState::Open;
+        inner.opened_at = Some(Instant::now());
+        Some(CircuitState::Open)
+    }
+}
+
+/// Something worth reporting about a storage call.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResilienceEvent {
+    /// A transient failure is about to be retried after `delay`.
+    Retry {
+        attempt: u32,
+        delay: Duration,
+        error: String,
+    },
+    /// The circuit breaker changed state.
+    CircuitStateChanged(CircuitState),
+    /// A call was rejected because the circuit is open.
+    ShortCircuited,
+}
+
+/// Counters describing how the wrapped backend has behaved.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ResilienceStats {
+    pub calls: u64,
+    pub retries: u64,
+    pub failures: u64,
+    pub short_circuited: u64,
+}
+
+type Observer = Arc<dyn Fn(&ResilienceEvent) + Send + Sync>;
+
+pub struct ResilientStorage<S> {
+    inner: S,
+    retry: RetryPolicy,
+    breaker: Option<CircuitBreaker>,
+    observer: Option<Observer>,
+    calls: AtomicU64,
+    retries: AtomicU64,
+    failures: AtomicU64,
+    short_circuited: AtomicU64,
+}
+
+impl<S: FileStorageHandler + Sync> ResilientStorage<S> {
+    /// Wraps `inner` with the default retry policy and no circuit breaker.
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            retry: RetryPolicy::default(),
+            breaker: None,
+            observer: None,
+            calls: AtomicU64::new(0),
+            retries: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+            short_circuited: AtomicU64::new(0),
+        }
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub fn with_circuit_breaker(mut self, breaker: CircuitBreaker) -> Self {
+        self.breaker = Some(breaker);
+        self
+    }
+
+    /// Calls `observer` for every [`ResilienceEvent`], e.g. to log or export metrics.
+    pub fn with_observer(
+        mut self,
+        observer: impl Fn(&ResilienceEvent) + Send + Sync + 'static,
+    ) -> Self {
+        self.observer = Some(Arc::new(observer));
+        self
+    }
+
+    pub fn circuit_state(&self) -> Option<CircuitState> {
+        self.breaker.as_ref().map(CircuitBreaker::state)
+    }
+
+    pub fn stats(&self) -> ResilienceStats {
+        ResilienceStats {
+            calls: self.calls.load(Ordering::Relaxed),
+            retries: self.retries.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+            short_circuited: self.short_circuited.load(Ordering::Relaxed),
+        }
+    }
+
+    fn emit(&self, event: ResilienceEvent) {
+        if let Some(observer) = &self.observer {
+            observer(&event);
+        }
+    }
+
+    async fn call<T, F, Fut>(&self, mut operation: F) -> FileResult<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = FileResult<T>>,
+    {
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        let mut attempt = 1;
+
+        loop {
+            if let Some(breaker) = &self.breaker {
+                match breaker.acquire() {
+                    Ok(Some(state)) => self.emit(ResilienceEvent::CircuitStateChanged(state)),
+                    Ok(None) => {}
+                    Err(()) => {
+                        self.short_circuited.fetch_add(1, Ordering::Relaxed);
+                        self.emit(ResilienceEvent::ShortCircuited);
+                        return Err(FileStorageError::CircuitOpen);
+                    }
+                }
+            }
+
+            let err = match operation().await {
+                Ok(value) => {
+                    if let Some(state) = self.breaker.as_ref().and_then(|b| b.record_success()) {
+                        self.emit(ResilienceEvent::CircuitStateChanged(state));
+                    }
+                    return Ok(value);
+                }
+                Err(err) => err,
+            };
+
+            if !err.is_transient() {
+                // The backend answered, it just said no.
+                if let Some(state) = self.breaker.as_ref().and_then(|b| b.record_success()) {
+                    self.emit(ResilienceEvent::CircuitStateChanged(state));
+                }
+                self.failures.fetch_add(1, Ordering::Relaxed);
+                return Err(err);
+            }
+
+            if let Some(state) = self.breaker.as_ref().and_then(|b| b.record_failure()) {
+                self.emit(ResilienceEvent::CircuitStateChanged(state));
+            }
+
+            if attempt >= self.retry.max_attempts {
+                self.failures.fetch_add(1, Ordering::Relaxed);
+                return Err(err);
+            }
+
+            let delay = self.retry.delay(attempt);
+            self.retries.fetch_add(1, Ordering::Relaxed);
+            self.emit(ResilienceEvent::Retry {
+                attempt,
+                delay,
+                error: err.to_string(),
+            });
+
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+impl<S: FileStorageHandler + Sync> FileStorageHandler for ResilientStorage<S> {
+    async fn upload(&self, id: &str, name: &str, bytes: &[u8]) -> FileResult<()> {
+        self.call(|| self.inner.upload(id, name, bytes)).await
+    }
+
+    async fn download(&self, id: &str, name: &str) -> FileResult<Vec<u8>> {
+        self.call(|| self.inner.download(id, name)).await
+    }
+
+    async fn list(&self, id: &str) -> FileResult<Vec<String>> {
+        self.call(|| self.inner.list(id)).await
+    }
+
+    async fn delete(&self, id: &str, name: &str) -> FileResult<()> {
+        self.call(|| self.inner.delete(id, name)).await
+    }
+}
+
+/// Random number in `[0, 1)`, good enough for spreading out retries.
+fn random_fraction() -> f64 {
+    (RandomState::new().build_hasher().finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::atomic::AtomicU32;
+
+    /// Fails the first `failures` calls with `kind`, then succeeds.
+    struct Flaky {
+        failures: u32,
+        kind: io::ErrorKind,
+        calls: AtomicU32,
+    }
+
+    impl Flaky {
+        fn new(failures: u32, kind: io::ErrorKind) -> Self {
+            Self {
+                failures,
+                kind,
+                calls: AtomicU32::new(0),
+            }
+        }
+
+        fn attempt(&self) -> FileResult<()> {
+            if self.calls.fetch_add(1, Ordering::SeqCst) < self.failures {
+                Err(FileStorageError::Io(self.kind.into()))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    impl FileStorageHandler for Flaky {
+        async fn upload(&self, _: &str, _: &str, _: &[u8]) -> FileResult<()> {
+            self.attempt()
+        }
+
+        async fn download(&self, _: &str, _: &str) -> FileResult<Vec<u8>> {
+            self.attempt().map(|_| vec![])
+        }
+
+        async fn list(&self, _: &str) -> FileResult<Vec<String>> {
+            self.attempt().map(|_| vec![])
+        }
+
+        async fn delete(&self, _: &str, _: &str) -> FileResult<()> {
+            self.attempt()
+        }
+    }
+
+    #[test]
+    fn test_backoff_is_capped() {
+        let policy = RetryPolicy::new(10, Duration::from_millis(100))
+            .max_delay(Duration::from_secs(1))
+            .jitter(false);
+
+        assert_eq!(policy.delay(1), Duration::from_millis(100));
+        assert_eq!(policy.delay(3), Duration::from_millis(400));
+        assert_eq!(policy.delay(8), Duration::from_secs(1));
+
+        let jittered = policy.jitter(true).delay(3);
+        assert!(jittered <= Duration::from_millis(400));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retries_transient_errors() {
+        let storage = ResilientStorage::new(Flaky::new(2, io::ErrorKind::TimedOut));
+
+        storage.upload("a", "b", b"").await.unwrap();
+        assert_eq!(storage.stats().retries, 2);
+        assert_eq!(storage.inner.calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_does_not_retry_permanent_errors() {
+        let storage = ResilientStorage::new(Flaky::new(1, io::ErrorKind::NotFound));
+
+        assert!(storage.download("a", "b").await.is_err());
+        assert_eq!(storage.inner.calls.load(Ordering::SeqCst), 1);
+        assert_eq!(storage.stats().failures, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_circuit_opens_and_recovers() {
+        let events = Arc::new(Mutex::new(vec![]));
+        let recorded = events.clone();
+
+        let storage = ResilientStorage::new(Flaky::new(2, io::ErrorKind::ConnectionRefused))
+            .with_retry(RetryPolicy::none())
+            .with_circuit_breaker(CircuitBreaker::new(2, Duration::from_secs(10)))
+            .with_observer(move |event| recorded.lock().unwrap().push(event.clone()));
+
+        assert!(storage.list("a").await.is_err());
+        assert!(storage.list("a").await.is_err());
+        assert_eq!(storage.circuit_state(), Some(CircuitState::Open));
+
+        assert!(matches!(
+            storage.list("a").await,
+            Err(FileStorageError::CircuitOpen)
+        ));
+        assert_eq!(storage.inner.calls.load(Ordering::SeqCst), 2);
+
+        tokio::time::advance(Duration::from_secs(11)).await;
+        assert!(storage.list("a").await.is_ok());
+        assert_eq!(storage.circuit_state(), Some(CircuitState::Closed));
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                ResilienceEvent::CircuitStateChanged(CircuitState::Open),
+                ResilienceEvent::ShortCircuited,
+                ResilienceEvent::CircuitStateChanged(CircuitState::HalfOpen),
+                ResilienceEvent::CircuitStateChanged(CircuitState::Closed),
+            ]
+        );
+    }
+}
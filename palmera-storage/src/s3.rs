@@ -26,7 +26,7 @@ impl FileStorageHandler for S3Storage {
             .bucket_exists(id)
             .send()
             .await
-            .map_err(|err| FileStorageError::S3(err))?
+            .map_err(FileStorageError::S3)?
             .exists
         {
             // create bucket
@@ -34,7 +34,7 @@ impl FileStorageHandler for S3Storage {
                 .create_bucket(id)
                 .send()
                 .await
-                .map_err(|err| FileStorageError::S3(err))?;
+                .map_err(FileStorageError::S3)?;
         }
 
         let content = ObjectContent::from(bytes.to_owned());
@@ -44,7 +44,7 @@ impl FileStorageHandler for S3Storage {
             .put_object_content(id, name, content)
             .send()
             .await
-            .map_err(|err| FileStorageError::S3(err))?;
+            .map_err(FileStorageError::S3)?;
 
         Ok(())
     }
@@ -55,18 +55,18 @@ impl FileStorageHandler for S3Storage {
             .get_object(id, name)
             .send()
             .await
-            .map_err(|err| FileStorageError::S3(err))?;
+            .map_err(FileStorageError::S3)?;
 
         let bytes = object
             .content
             .to_segmented_bytes()
             .await
-            .map_err(|err| FileStorageError::Io(err))?
+            .map_err(FileStorageError::Io)?
             .into_iter()
             .flat_map(|b| b.into_iter())
             .collect::<Vec<u8>>();
 
-        return Ok(bytes);
+        Ok(bytes)
     }
 
     async fn list(&self, id: &str) -> crate::traits::FileResult<Vec<String>> {
@@ -84,4 +84,15 @@ impl FileStorageHandler for S3Storage {
 
         Ok(result)
     }
+
+    async fn delete(&self, id: &str, name: &str) -> crate::traits::FileResult<()> {
+        _ = self
+            .client
+            .remove_object(id, name)
+            .send()
+            .await
+            .map_err(FileStorageError::S3)?;
+
+        Ok(())
+    }
 }
@@ -0,0 +1,284 @@
+//! # Signed URLs for local storage
+//!
+//! The local counterpart of S3 presigned URLs. A [`UrlSigner`] issues tokens that name a
+//! single file and an expiry time, signed with HMAC-SHA256. Anyone holding the URL
+//! `/files/signed/{token}` can download that file until the token expires, without
+//! authenticating.
+//!
+//! The [`router`] expects `Extension<Arc<UrlSigner>>` and `Extension<Arc<LocalStorage>>`
+//! layers. Tokens are checked by [`verify_signed_url`] before the file is opened, and the
+//! file is streamed from disk rather than read into memory.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! use std::{path::PathBuf, sync::Arc, time::Duration};
+//! use axum::Extension;
+//! use palmera_auth::urls::UrlBuilder;
+//! use palmera_storage::{local::LocalStorage, signed::{self, UrlSigner}};
+//!
+//! let storage = Arc::new(LocalStorage::new(PathBuf::from("uploads")));
+//! let signer = Arc::new(UrlSigner::new(b"secret"));
+//!
+//! let url = storage.signed_url(&signer, "invoices", "march.pdf", Duration::from_secs(600));
+//! assert!(url.starts_with("/files/signed/"));
+//!
+//! let urls = UrlBuilder::new("https://files.example.com").unwrap();
+//! let expires_in = Duration::from_secs(600);
+//! let url = storage.absolute_signed_url(&urls, &signer, "invoices", "march.pdf", expires_in);
+//! assert!(url.starts_with("https://files.example.com/files/signed/"));
+//!
+//! let (router, _api) = signed::router()
+//!     .layer(Extension(storage))
+//!     .layer(Extension(signer))
+//!     .split_for_parts();
+//! ```
+
+use std::{
+    fmt,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    Extension,
+    body::Body,
+    extract::{Path, Request},
+    http::{StatusCode, header},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use hmac::{Hmac, Mac};
+use palmera_auth::urls::UrlBuilder;
+use sha2::Sha256;
+use tokio_util::io::ReaderStream;
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+use crate::local::LocalStorage;
+
+/// File a verified token grants access to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SignedFile {
+    pub id: String,
+    pub name: String,
+    /// Expiry in seconds since the Unix epoch.
+    pub expires_at: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SignedUrlError {
+    Malformed,
+    InvalidSignature,
+    Expired,
+}
+
+impl SignedUrlError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            SignedUrlError::Malformed | SignedUrlError::InvalidSignature => StatusCode::FORBIDDEN,
+            SignedUrlError::Expired => StatusCode::GONE,
+        }
+    }
+}
+
+impl fmt::Display for SignedUrlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignedUrlError::Malformed => write!(f, "Malformed signed URL token"),
+            SignedUrlError::InvalidSignature => write!(f, "Invalid signed URL signature"),
+            SignedUrlError::Expired => write!(f, "Signed URL has expired"),
+        }
+    }
+}
+
+impl std::error::Error for SignedUrlError {}
+
+/// Issues and verifies signed URL tokens.
+#[derive(Clone)]
+pub struct UrlSigner {
+    key: Vec<u8>,
+}
+
+impl UrlSigner {
+    pub fn new(key: &[u8]) -> Self {
+        Self { key: key.to_vec() }
+    }
+
+    /// Returns a token granting access to `id/name` for `expires_in`.
+    pub fn sign(&self, id: &str, name: &str, expires_in: Duration) -> String {
+        let expires_at = unix_now() + expires_in.as_secs();
+        let payload = format!("{}\n{}\n{}", id, name, expires_at);
+
+        format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(&payload),
+            URL_SAFE_NO_PAD.encode(self.mac(&payload).finalize().into_bytes())
+        )
+    }
+
+    /// Checks a token's signature and expiry.
+    pub fn verify(&self, token: &str) -> Result<SignedFile, SignedUrlError> {
+        let (payload, signature) = token.split_once('.').ok_or(SignedUrlError::Malformed)?;
+        let payload = URL_SAFE_NO_PAD
+            .decode(payload)
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .ok_or(SignedUrlError::Malformed)?;
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| SignedUrlError::Malformed)?;
+
+        self.mac(&payload)
+            .verify_slice(&signature)
+            .map_err(|_| SignedUrlError::InvalidSignature)?;
+
+        let mut parts = payload.splitn(3, '\n');
+        let (Some(id), Some(name), Some(expires_at)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(SignedUrlError::Malformed);
+        };
+        let expires_at = expires_at
+            .parse::<u64>()
+            .map_err(|_| SignedUrlError::Malformed)?;
+
+        if expires_at <= unix_now() {
+            return Err(SignedUrlError::Expired);
+        }
+
+        Ok(SignedFile {
+            id: id.to_string(),
+            name: name.to_string(),
+            expires_at,
+        })
+    }
+
+    fn mac(&self, payload: &str) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(payload.as_bytes());
+        mac
+    }
+}
+
+impl LocalStorage {
+    /// Returns a URL path serving `id/name` until `expires_in` has passed.
+    pub fn signed_url(
+        &self,
+        signer: &UrlSigner,
+        id: &str,
+        name: &str,
+        expires_in: Duration,
+    ) -> String {
+        format!("/files/signed/{}", signer.sign(id, name, expires_in))
+    }
+
+    /// [`LocalStorage::signed_url`] as an absolute URL built by `urls`, for links
+    /// handed to other services or sent in emails.
+    pub fn absolute_signed_url(
+        &self,
+        urls: &UrlBuilder,
+        signer: &UrlSigner,
+        id: &str,
+        name: &str,
+        expires_in: Duration,
+    ) -> String {
+        urls.url(&self.signed_url(signer, id, name, expires_in))
+    }
+}
+
+/// Middleware rejecting requests whose token is missing, forged or expired. The verified
+/// [`SignedFile`] is added to the request extensions.
+pub async fn verify_signed_url(
+    Extension(signer): Extension<Arc<UrlSigner>>,
+    Path(token): Path<String>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    match signer.verify(&token) {
+        Ok(file) => {
+            request.extensions_mut().insert(file);
+            next.run(request).await
+        }
+        Err(err) => err.status_code().into_response(),
+    }
+}
+
+#[utoipa::path(get, path = "/files/signed/{token}")]
+async fn serve_signed_file(
+    Extension(storage): Extension<Arc<LocalStorage>>,
+    Extension(file): Extension<SignedFile>,
+) -> Result<Response, StatusCode> {
+    let path = storage
+        .path(&file.id, &file.name)
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let handle = tokio::fs::File::open(path)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    Ok((
+        [(header::CACHE_CONTROL, "private, no-store")],
+        Body::from_stream(ReaderStream::new(handle)),
+    )
+        .into_response())
+}
+
+pub fn router() -> OpenApiRouter {
+    OpenApiRouter::new()
+        .routes(routes!(serve_signed_file))
+        .route_layer(middleware::from_fn(verify_signed_url))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let signer = UrlSigner::new(b"secret");
+        let token = signer.sign("docs", "a.txt", Duration::from_secs(60));
+
+        let file = signer.verify(&token).unwrap();
+        assert_eq!(file.id, "docs");
+        assert_eq!(file.name, "a.txt");
+
+        assert_eq!(
+            UrlSigner::new(b"other").verify(&token),
+            Err(SignedUrlError::InvalidSignature)
+        );
+        assert_eq!(signer.verify("garbage"), Err(SignedUrlError::Malformed));
+    }
+
+    #[test]
+    fn test_expired_token() {
+        let signer = UrlSigner::new(b"secret");
+        let token = signer.sign("docs", "a.txt", Duration::ZERO);
+
+        assert_eq!(signer.verify(&token), Err(SignedUrlError::Expired));
+    }
+
+    #[test]
+    fn test_tampered_payload() {
+        let signer = UrlSigner::new(b"secret");
+        let token = signer.sign("docs", "a.txt", Duration::from_secs(60));
+        let (_, signature) = token.split_once('.').unwrap();
+        let forged = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode("docs\nsecret.txt\n99999999999"),
+            signature
+        );
+
+        assert_eq!(
+            signer.verify(&forged),
+            Err(SignedUrlError::InvalidSignature)
+        );
+    }
+}
@@ -5,6 +5,16 @@ pub enum FileStorageError {
     Local(std::io::Error),
     S3(minio::s3::error::Error),
     Io(std::io::Error),
+    /// The backend is failing and calls are rejected without reaching it, see
+    /// [`crate::resilience`].
+    CircuitOpen,
+    /// The backend failed its health check; calls are rejected until it recovers, see
+    /// [`crate::degradation`].
+    Unavailable {
+        retry_after: std::time::Duration,
+    },
+    /// An archive could not be encrypted or decrypted, see [`crate::backup`].
+    Encryption,
 }
 
 impl fmt::Display for FileStorageError {
@@ -13,6 +23,13 @@ impl fmt::Display for FileStorageError {
             FileStorageError::Local(e) => write!(f, "Local error: {}", e),
             FileStorageError::S3(e) => write!(f, "S3 error: {}", e),
             FileStorageError::Io(e) => write!(f, "IO error: {}", e),
+            FileStorageError::CircuitOpen => write!(f, "Storage backend is unavailable"),
+            FileStorageError::Unavailable { retry_after } => write!(
+                f,
+                "Storage backend is unavailable, retry in {} seconds",
+                retry_after.as_secs()
+            ),
+            FileStorageError::Encryption => write!(f, "Failed to encrypt or decrypt file"),
         }
     }
 }
@@ -23,6 +40,71 @@ impl std::error::Error for FileStorageError {
             FileStorageError::Local(e) => Some(e),
             FileStorageError::S3(e) => Some(e),
             FileStorageError::Io(e) => Some(e),
+            FileStorageError::CircuitOpen
+            | FileStorageError::Unavailable { .. }
+            | FileStorageError::Encryption => None,
+        }
+    }
+}
+
+impl FileStorageError {
+    /// Whether the error is likely to go away when the operation is retried, such as a
+    /// timeout or dropped connection. Missing files and permission errors are not.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            FileStorageError::Local(e) | FileStorageError::Io(e) => matches!(
+                e.kind(),
+                std::io::ErrorKind::TimedOut
+                    | std::io::ErrorKind::Interrupted
+                    | std::io::ErrorKind::WouldBlock
+                    | std::io::ErrorKind::ConnectionRefused
+                    | std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::BrokenPipe
+                    | std::io::ErrorKind::UnexpectedEof
+            ),
+            FileStorageError::S3(e) => {
+                let message = e.to_string();
+                ![
+                    "NoSuchKey",
+                    "NoSuchBucket",
+                    "AccessDenied",
+                    "InvalidAccessKeyId",
+                    "SignatureDoesNotMatch",
+                    "InvalidBucketName",
+                ]
+                .iter()
+                .any(|code| message.contains(code))
+            }
+            FileStorageError::CircuitOpen
+            | FileStorageError::Unavailable { .. }
+            | FileStorageError::Encryption => false,
+        }
+    }
+
+    /// Whether the file or its namespace doesn't exist.
+    pub fn is_not_found(&self) -> bool {
+        match self {
+            FileStorageError::Local(e) | FileStorageError::Io(e) => {
+                e.kind() == std::io::ErrorKind::NotFound
+            }
+            FileStorageError::S3(e) => {
+                let message = e.to_string();
+                message.contains("NoSuchKey") || message.contains("NoSuchBucket")
+            }
+            FileStorageError::CircuitOpen
+            | FileStorageError::Unavailable { .. }
+            | FileStorageError::Encryption => false,
+        }
+    }
+
+    /// Whether the upload was refused because a file of that name exists.
+    pub fn is_already_exists(&self) -> bool {
+        match self {
+            FileStorageError::Local(e) | FileStorageError::Io(e) => {
+                e.kind() == std::io::ErrorKind::AlreadyExists
+            }
+            _ => false,
         }
     }
 }
@@ -39,6 +121,7 @@ pub type FileResult<T> = Result<T, FileStorageError>;
 /// *   `upload`: Uploads a file to the storage.
 /// *   `download`: Downloads a file from the storage.
 /// *   `list`: Lists files in the storage.
+/// *   `delete`: Deletes a file from the storage.
 pub trait FileStorageHandler {
     /// Uploads a file to the storage.
     ///
@@ -82,4 +165,19 @@ pub trait FileStorageHandler {
     ///
     /// A `FileResult` containing a vector of file names, or an error if the listing fails.
     fn list(&self, id: &str) -> impl std::future::Future<Output = FileResult<Vec<String>>> + Send;
+    /// Deletes a file from the storage.
+    ///
+    /// # Arguments
+    ///
+    /// *   `id`: The identifier for the file's location or namespace.
+    /// *   `name`: The name of the file to be deleted.
+    ///
+    /// # Returns
+    ///
+    /// A `FileResult` indicating success or failure.
+    fn delete(
+        &self,
+        id: &str,
+        name: &str,
+    ) -> impl std::future::Future<Output = FileResult<()>> + Send;
 }
@@ -0,0 +1,291 @@
+//! # File versioning
+//!
+//! [`VersionedStorage`] wraps any [`FileStorageHandler`] so that uploading to an existing
+//! name adds a new version instead of replacing the file. Each version is stored as its
+//! own object named `<name>@v<version>`, where the version is the upload time in
+//! milliseconds since the Unix epoch, so versions sort by age and survive restarts
+//! without a separate index.
+//!
+//! Through the [`FileStorageHandler`] interface a versioned store behaves like a plain
+//! one: `download` returns the latest version and `list` returns each name once.
+//! Files uploaded before versioning was enabled are still listed and downloadable.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! use std::{path::PathBuf, time::Duration};
+//! use palmera_storage::{
+//!     local::LocalStorage,
+//!     traits::FileStorageHandler,
+//!     versioning::{RetentionPolicy, VersionedStorage},
+//! };
+//!
+//! # async fn example() -> palmera_storage::traits::FileResult<()> {
+//! let storage = VersionedStorage::new(LocalStorage::new(PathBuf::from("uploads")));
+//!
+//! storage.upload("avatars", "me.png", b"first").await?;
+//! storage.upload("avatars", "me.png", b"second").await?;
+//!
+//! let versions = storage.versions("avatars", "me.png").await?;
+//! let first = storage.download_version("avatars", "me.png", versions[0].version).await?;
+//!
+//! storage
+//!     .prune("avatars", "me.png", &RetentionPolicy::keep_last(5).max_age(Duration::from_secs(86400)))
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    collections::BTreeSet,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::traits::{FileResult, FileStorageHandler};
+
+const VERSION_SEPARATOR: &str = "@v";
+
+/// A stored version of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FileVersion {
+    /// Upload time in milliseconds since the Unix epoch.
+    pub version: u64,
+}
+
+impl FileVersion {
+    pub fn created_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.version)
+    }
+}
+
+/// Which old versions [`VersionedStorage::prune`] removes. The latest version is
+/// always kept.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RetentionPolicy {
+    /// Number of most recent versions to keep.
+    pub keep_last: Option<usize>,
+    /// Versions older than this are removed.
+    pub max_age: Option<Duration>,
+}
+
+impl RetentionPolicy {
+    pub fn keep_last(count: usize) -> Self {
+        Self {
+            keep_last: Some(count),
+            max_age: None,
+        }
+    }
+
+    pub fn max_age(mut self, age: Duration) -> Self {
+        self.max_age = Some(age);
+        self
+    }
+}
+
+pub struct VersionedStorage<S> {
+    inner: S,
+    last_version: AtomicU64,
+}
+
+impl<S: FileStorageHandler + Sync> VersionedStorage<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            last_version: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the versions of `name`, oldest first.
+    pub async fn versions(&self, id: &str, name: &str) -> FileResult<Vec<FileVersion>> {
+        let mut versions = self
+            .inner
+            .list(id)
+            .await?
+            .iter()
+            .filter_map(|key| split_key(key))
+            .filter(|(base, _)| *base == name)
+            .map(|(_, version)| FileVersion { version })
+            .collect::<Vec<_>>();
+
+        versions.sort();
+
+        Ok(versions)
+    }
+
+    /// Downloads a specific version of `name`.
+    pub async fn download_version(
+        &self,
+        id: &str,
+        name: &str,
+        version: u64,
+    ) -> FileResult<Vec<u8>> {
+        self.inner.download(id, &version_key(name, version)).await
+    }
+
+    /// Deletes the versions of `name` that fall outside `policy`, returning how many
+    /// were removed.
+    pub async fn prune(&self, id: &str, name: &str, policy: &RetentionPolicy) -> FileResult<usize> {
+        let mut versions = self.versions(id, name).await?;
+        // The latest version is never pruned.
+        versions.pop();
+
+        let keep_from = policy
+            .keep_last
+            .map(|keep| versions.len().saturating_sub(keep.saturating_sub(1)))
+            .unwrap_or(0);
+        let cutoff = policy
+            .max_age
+            .and_then(|age| SystemTime::now().checked_sub(age));
+
+        let mut removed = 0;
+        for (index, version) in versions.iter().enumerate() {
+            let too_many = index < keep_from;
+            let too_old = cutoff.is_some_and(|cutoff| version.created_at() < cutoff);
+
+            if too_many || too_old {
+                self.inner
+                    .delete(id, &version_key(name, version.version))
+                    .await?;
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Issues a version number that is unique within this process, even for uploads
+    /// made in the same millisecond.
+    fn next_version(&self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+
+        let previous = self
+            .last_version
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| {
+                Some(now.max(last + 1))
+            })
+            .unwrap_or_default();
+
+        now.max(previous + 1)
+    }
+}
+
+impl<S: FileStorageHandler + Sync> FileStorageHandler for VersionedStorage<S> {
+    async fn upload(&self, id: &str, name: &str, bytes: &[u8]) -> FileResult<()> {
+        let version = self.next_version();
+        self.inner
+            .upload(id, &version_key(name, version), bytes)
+            .await
+    }
+
+    async fn download(&self, id: &str, name: &str) -> FileResult<Vec<u8>> {
+        match self.versions(id, name).await?.last() {
+            Some(latest) => self.download_version(id, name, latest.version).await,
+            None => self.inner.download(id, name).await,
+        }
+    }
+
+    async fn list(&self, id: &str) -> FileResult<Vec<String>> {
+        let names = self
+            .inner
+            .list(id)
+            .await?
+            .into_iter()
+            .map(|key| match split_key(&key) {
+                Some((base, _)) => base.to_string(),
+                None => key,
+            })
+            .collect::<BTreeSet<_>>();
+
+        Ok(names.into_iter().collect())
+    }
+
+    /// Deletes every version of `name`.
+    async fn delete(&self, id: &str, name: &str) -> FileResult<()> {
+        for version in self.versions(id, name).await? {
+            self.inner
+                .delete(id, &version_key(name, version.version))
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+fn version_key(name: &str, version: u64) -> String {
+    format!("{}{}{}", name, VERSION_SEPARATOR, version)
+}
+
+fn split_key(key: &str) -> Option<(&str, u64)> {
+    let (base, version) = key.rsplit_once(VERSION_SEPARATOR)?;
+    Some((base, version.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::local::LocalStorage;
+
+    fn storage(test: &str) -> VersionedStorage<LocalStorage> {
+        let dir = std::env::temp_dir().join(format!("palmera-versioning-{}", test));
+        _ = std::fs::remove_dir_all(&dir);
+        VersionedStorage::new(LocalStorage::new(dir))
+    }
+
+    #[test]
+    fn test_split_key() {
+        assert_eq!(split_key("a.png@v42"), Some(("a.png", 42)));
+        assert_eq!(split_key("me@vacation.png"), None);
+        assert_eq!(split_key("a.png"), None);
+    }
+
+    #[tokio::test]
+    async fn test_upload_keeps_versions() {
+        let storage = storage("upload");
+
+        storage.upload("docs", "a.txt", b"one").await.unwrap();
+        storage.upload("docs", "a.txt", b"two").await.unwrap();
+        storage.upload("docs", "b.txt", b"other").await.unwrap();
+
+        let versions = storage.versions("docs", "a.txt").await.unwrap();
+        assert_eq!(versions.len(), 2);
+        assert_eq!(storage.download("docs", "a.txt").await.unwrap(), b"two");
+        assert_eq!(
+            storage
+                .download_version("docs", "a.txt", versions[0].version)
+                .await
+                .unwrap(),
+            b"one"
+        );
+        assert_eq!(storage.list("docs").await.unwrap(), vec!["a.txt", "b.txt"]);
+    }
+
+    #[tokio::test]
+    async fn test_prune_by_count_keeps_latest() {
+        let storage = storage("prune");
+
+        for body in ["1", "2", "3", "4"] {
+            storage
+                .upload("docs", "a.txt", body.as_bytes())
+                .await
+                .unwrap();
+        }
+
+        let removed = storage
+            .prune("docs", "a.txt", &RetentionPolicy::keep_last(2))
+            .await
+            .unwrap();
+        assert_eq!(removed, 2);
+        assert_eq!(storage.versions("docs", "a.txt").await.unwrap().len(), 2);
+
+        let removed = storage
+            .prune("docs", "a.txt", &RetentionPolicy::keep_last(0))
+            .await
+            .unwrap();
+        assert_eq!(removed, 1);
+        assert_eq!(storage.download("docs", "a.txt").await.unwrap(), b"4");
+    }
+}
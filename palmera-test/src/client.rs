@@ -0,0 +1,108 @@
+use axum::http::{HeaderMap, Method, Request, StatusCode, header};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper_util::{
+    client::legacy::{Client, connect::HttpConnector},
+    rt::TokioExecutor,
+};
+use serde::{Serialize, de::DeserializeOwned};
+
+/// HTTP client bound to a [`crate::TestApp`].
+#[derive(Clone)]
+pub struct TestClient {
+    base_url: String,
+    token: Option<String>,
+    client: Client<HttpConnector, Full<Bytes>>,
+}
+
+/// A fully buffered response.
+#[derive(Debug)]
+pub struct TestResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl TestResponse {
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    pub fn json<T: DeserializeOwned>(&self) -> serde_json::Result<T> {
+        serde_json::from_slice(&self.body)
+    }
+}
+
+impl TestClient {
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            token: None,
+            client: Client::builder(TokioExecutor::new()).build_http(),
+        }
+    }
+
+    /// Sends `token` as a bearer token with every request.
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.token = Some(token.to_string());
+        self
+    }
+
+    pub async fn get(&self, path: &str) -> anyhow::Result<TestResponse> {
+        self.send(Method::GET, path, None, Bytes::new()).await
+    }
+
+    pub async fn delete(&self, path: &str) -> anyhow::Result<TestResponse> {
+        self.send(Method::DELETE, path, None, Bytes::new()).await
+    }
+
+    pub async fn post_json<T: Serialize>(
+        &self,
+        path: &str,
+        body: &T,
+    ) -> anyhow::Result<TestResponse> {
+        let body = serde_json::to_vec(body)?;
+        self.send(Method::POST, path, Some("application/json"), body.into())
+            .await
+    }
+
+    /// Posts `body` as `application/x-www-form-urlencoded`; `body` must already be
+    /// encoded, e.g. `email=a%40b.c&password=secret`.
+    pub async fn post_form(&self, path: &str, body: &str) -> anyhow::Result<TestResponse> {
+        self.send(
+            Method::POST,
+            path,
+            Some("application/x-www-form-urlencoded"),
+            Bytes::copy_from_slice(body.as_bytes()),
+        )
+        .await
+    }
+
+    pub async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        content_type: Option<&str>,
+        body: Bytes,
+    ) -> anyhow::Result<TestResponse> {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(format!("{}{}", self.base_url, path));
+
+        if let Some(content_type) = content_type {
+            request = request.header(header::CONTENT_TYPE, content_type);
+        }
+        if let Some(token) = &self.token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+
+        let response = self.client.request(request.body(Full::new(body))?).await?;
+        let (parts, body) = response.into_parts();
+
+        Ok(TestResponse {
+            status: parts.status,
+            headers: parts.headers,
+            body: body.collect().await?.to_bytes(),
+        })
+    }
+}
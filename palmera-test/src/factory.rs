@@ -0,0 +1,58 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use chrono::Duration;
+use palmera_auth::{AuthConfig, schemas::AuthUser};
+use sqlx::{Pool, Postgres};
+
+/// Password given to every user created by a [`UserFactory`].
+pub const DEFAULT_PASSWORD: &str = "password";
+
+/// A user created for a test, with a token signed by the test app's auth config.
+#[derive(Debug, Clone)]
+pub struct TestUser {
+    pub user: AuthUser,
+    /// Plaintext password of `user`.
+    pub password: String,
+    pub token: String,
+}
+
+impl TestUser {
+    /// Inserts the user into the auth tables of `db`, for tests that exercise the
+    /// login flow against Postgres.
+    pub async fn insert(&self, db: &Pool<Postgres>) -> anyhow::Result<()> {
+        self.user.clone().insert(db).await?;
+        Ok(())
+    }
+}
+
+/// Creates users with unique emails and tokens for them.
+pub struct UserFactory {
+    auth: AuthConfig,
+    sequence: AtomicUsize,
+}
+
+impl UserFactory {
+    pub fn new(auth: AuthConfig) -> Self {
+        Self {
+            auth,
+            sequence: AtomicUsize::new(0),
+        }
+    }
+
+    /// Creates a user named `user<n>@example.com` with [`DEFAULT_PASSWORD`].
+    pub fn create(&self) -> anyhow::Result<TestUser> {
+        let n = self.sequence.fetch_add(1, Ordering::Relaxed) + 1;
+        self.create_with(&format!("user{}@example.com", n), DEFAULT_PASSWORD)
+    }
+
+    pub fn create_with(&self, email: &str, password: &str) -> anyhow::Result<TestUser> {
+        let user = AuthUser::new(email, password);
+        let token = self.auth.issue_token(user.id, Duration::hours(1))?;
+
+        Ok(TestUser {
+            user,
+            password: password.to_string(),
+            token,
+        })
+    }
+}
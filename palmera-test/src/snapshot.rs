@@ -0,0 +1,388 @@
+use std::{
+    fmt::Write as _,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+use sqlx::{
+    Column, Decode, Pool, Row, Sqlite, TypeInfo, ValueRef,
+    sqlite::{SqliteRow, SqliteValueRef},
+};
+
+/// Environment variable that, when set to `1`, makes [`Snapshot::assert_matches`]
+/// overwrite stored snapshots instead of comparing against them.
+pub const UPDATE_SNAPSHOTS: &str = "PALMERA_UPDATE_SNAPSHOTS";
+
+/// Environment variable set by CI services, in which [`Snapshot::assert_matches`] fails
+/// on missing snapshots instead of recording them.
+const CI: &str = "CI";
+
+/// Placeholder replacing the values of redacted columns.
+pub const REDACTED: &str = "[redacted]";
+
+/// Lines of unchanged context shown around each change of a snapshot diff.
+const CONTEXT: usize = 3;
+
+/// Rows of a table or query in a canonical form: objects with sorted keys, pretty
+/// printed with one value per line, so equal data always serializes to the same fixture.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    rows: Vec<Value>,
+    /// Whether the rows are kept sorted by their canonical form.
+    sorted: bool,
+}
+
+impl Snapshot {
+    /// Snapshots every row of `table`. Rows are sorted by their canonical form, again
+    /// after [`Snapshot::redact`], so the snapshot does not depend on insertion or
+    /// storage order, nor on the redacted values.
+    pub async fn table(db: &Pool<Sqlite>, table: &str) -> anyhow::Result<Self> {
+        let sql = format!("SELECT * FROM \"{}\"", table.replace('"', "\"\""));
+        let mut snapshot = Self::query(db, &sql).await?;
+        snapshot.sorted = true;
+        snapshot.sort();
+        Ok(snapshot)
+    }
+
+    /// Snapshots the rows returned by `sql`, in the order it returns them.
+    pub async fn query(db: &Pool<Sqlite>, sql: &str) -> anyhow::Result<Self> {
+        let rows = sqlx::query(sql).fetch_all(db).await?;
+        let rows = rows
+            .iter()
+            .map(row_to_json)
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            rows,
+            sorted: false,
+        })
+    }
+
+    /// Snapshots values serialized with serde, e.g. rows fetched from Postgres with
+    /// `to_jsonb`.
+    pub fn from_rows<T: Serialize>(rows: &[T]) -> anyhow::Result<Self> {
+        let rows = rows
+            .iter()
+            .map(|row| Ok(canonical(serde_json::to_value(row)?)))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            rows,
+            sorted: false,
+        })
+    }
+
+    fn sort(&mut self) {
+        self.rows
+            .sort_by_cached_key(|row| serde_json::to_string(row).unwrap_or_default());
+    }
+
+    /// Replaces the values of `columns` with [`REDACTED`] in every row, for values such
+    /// as generated ids and timestamps that differ between runs. Null values are kept.
+    pub fn redact(mut self, columns: &[&str]) -> Self {
+        for row in &mut self.rows {
+            let Value::Object(row) = row else {
+                continue;
+            };
+            for column in columns {
+                if let Some(value) = row.get_mut(*column)
+                    && !value.is_null()
+                {
+                    *value = REDACTED.into();
+                }
+            }
+        }
+        if self.sorted {
+            self.sort();
+        }
+        self
+    }
+
+    pub fn rows(&self) -> &[Value] {
+        &self.rows
+    }
+
+    /// The canonical JSON fixture of the snapshot, ending with a newline.
+    pub fn to_json(&self) -> String {
+        let mut json = serde_json::to_string_pretty(&self.rows).unwrap_or_default();
+        json.push('\n');
+        json
+    }
+
+    /// Compares the snapshot with the fixture `snapshots/<name>.json` of the crate under
+    /// test. See [`Snapshot::assert_matches_file`].
+    #[track_caller]
+    pub fn assert_matches(&self, name: &str) {
+        self.assert_matches_file(snapshot_dir().join(format!("{}.json", name)));
+    }
+
+    /// Compares the snapshot with the fixture at `path`.
+    ///
+    /// A missing fixture is written and the assertion passes, so new snapshots are
+    /// recorded by running the test once; review and commit them. In CI, when the `CI`
+    /// environment variable is set, a missing fixture fails the assertion instead. With
+    /// [`UPDATE_SNAPSHOTS`] set to `1`, existing fixtures are overwritten too.
+    ///
+    /// # Panics
+    ///
+    /// Panics with a line diff from the stored fixture to the snapshot if they differ,
+    /// if the fixture is missing and `CI` is set, or if the fixture can't be read or
+    /// written.
+    #[track_caller]
+    pub fn assert_matches_file(&self, path: impl AsRef<Path>) {
+        let update = std::env::var(UPDATE_SNAPSHOTS).is_ok_and(|value| value == "1");
+        let ci = std::env::var(CI).is_ok_and(|value| !value.is_empty() && value != "false");
+        self.compare(path.as_ref(), update, ci);
+    }
+
+    #[track_caller]
+    fn compare(&self, path: &Path, update: bool, ci: bool) {
+        let actual = self.to_json();
+
+        if !update && ci && !path.exists() {
+            panic!(
+                "snapshot {} is missing; record it by running the test locally and commit it",
+                path.display()
+            );
+        }
+        if !update && path.exists() {
+            let expected = std::fs::read_to_string(path)
+                .unwrap_or_else(|err| panic!("failed to read snapshot {}: {err}", path.display()));
+            if expected == actual {
+                return;
+            }
+            panic!(
+                "snapshot {} does not match (- stored, + actual); \
+                 rerun with {UPDATE_SNAPSHOTS}=1 to accept the change:\n{}",
+                path.display(),
+                diff(&expected, &actual)
+            );
+        }
+
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).unwrap_or_else(|err| {
+                panic!(
+                    "failed to create snapshot directory {}: {err}",
+                    dir.display()
+                )
+            });
+        }
+        std::fs::write(path, actual)
+            .unwrap_or_else(|err| panic!("failed to write snapshot {}: {err}", path.display()));
+    }
+}
+
+/// Directory holding the fixtures of [`Snapshot::assert_matches`]: `snapshots` in the
+/// directory of the crate under test.
+pub fn snapshot_dir() -> PathBuf {
+    std::env::var_os("CARGO_MANIFEST_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_default()
+        .join("snapshots")
+}
+
+fn row_to_json(row: &SqliteRow) -> anyhow::Result<Value> {
+    let mut object = Map::new();
+    for column in row.columns() {
+        let value = row.try_get_raw(column.ordinal())?;
+        object.insert(column.name().to_string(), value_to_json(value)?);
+    }
+    Ok(canonical(Value::Object(object)))
+}
+
+/// Converts a SQLite value by its storage class. Blobs become `x'…'` hex literals.
+fn value_to_json(value: SqliteValueRef<'_>) -> anyhow::Result<Value> {
+    if value.is_null() {
+        return Ok(Value::Null);
+    }
+    let storage = value.type_info().name().to_string();
+    let value = match storage.as_str() {
+        "INTEGER" => {
+            Value::from(<i64 as Decode<Sqlite>>::decode(value).map_err(anyhow::Error::msg)?)
+        }
+        "REAL" => {
+            let real = <f64 as Decode<Sqlite>>::decode(value).map_err(anyhow::Error::msg)?;
+            serde_json::Number::from_f64(real).map_or(Value::Null, Value::Number)
+        }
+        "BLOB" => {
+            let bytes = <Vec<u8> as Decode<Sqlite>>::decode(value).map_err(anyhow::Error::msg)?;
+            let mut hex = String::with_capacity(bytes.len() * 2 + 3);
+            hex.push_str("x'");
+            for byte in bytes {
+                let _ = write!(hex, "{:02x}", byte);
+            }
+            hex.push('\'');
+            Value::from(hex)
+        }
+        _ => Value::from(<String as Decode<Sqlite>>::decode(value).map_err(anyhow::Error::msg)?),
+    };
+    Ok(value)
+}
+
+/// Sorts the keys of every object in `value`.
+fn canonical(value: Value) -> Value {
+    match value {
+        Value::Object(object) => {
+            let mut entries: Vec<_> = object.into_iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (key, canonical(value)))
+                    .collect(),
+            )
+        }
+        Value::Array(values) => Value::Array(values.into_iter().map(canonical).collect()),
+        value => value,
+    }
+}
+
+/// Line diff from `expected` to `actual`, showing [`CONTEXT`] unchanged lines around each
+/// change.
+fn diff(expected: &str, actual: &str) -> String {
+    let old: Vec<&str> = expected.lines().collect();
+    let new: Vec<&str> = actual.lines().collect();
+
+    // Longest common subsequence lengths of the suffixes.
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push((' ', old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            lines.push(('-', old[i]));
+            i += 1;
+        } else {
+            lines.push(('+', new[j]));
+            j += 1;
+        }
+    }
+
+    let changed: Vec<usize> = (0..lines.len()).filter(|&n| lines[n].0 != ' ').collect();
+    let mut out = String::new();
+    let mut last = None;
+    for (n, (marker, line)) in lines.iter().enumerate() {
+        let near = changed
+            .iter()
+            .any(|&c| n + CONTEXT >= c && n <= c + CONTEXT);
+        if !near {
+            continue;
+        }
+        if last.is_some_and(|last| last + 1 != n) {
+            out.push_str("  ...\n");
+        }
+        let _ = writeln!(out, "{} {}", marker, line);
+        last = Some(n);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    #[tokio::test]
+    async fn test_table_snapshot() -> anyhow::Result<()> {
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect(crate::DATABASE_URL)
+            .await?;
+        sqlx::query(
+            "CREATE TABLE notes (id integer PRIMARY KEY, body text, score real, data blob, \
+             created_at text DEFAULT CURRENT_TIMESTAMP)",
+        )
+        .execute(&db)
+        .await?;
+        sqlx::query(
+            "INSERT INTO notes (id, body, score, data) VALUES (2, 'b', 1.5, x'00ff'), \
+             (1, 'a', NULL, NULL)",
+        )
+        .execute(&db)
+        .await?;
+
+        let snapshot = Snapshot::table(&db, "notes").await?.redact(&["created_at"]);
+        assert_eq!(
+            snapshot.rows(),
+            [
+                json!({ "body": "a", "created_at": REDACTED, "data": null, "id": 1, "score": null }),
+                json!({ "body": "b", "created_at": REDACTED, "data": "x'00ff'", "id": 2, "score": 1.5 }),
+            ]
+        );
+
+        let path = std::env::temp_dir()
+            .join(format!("palmera-snapshot-{}", uuid::Uuid::new_v4()))
+            .join("notes.json");
+        // In CI a missing fixture fails instead of being recorded.
+        let missing = std::panic::catch_unwind(|| snapshot.compare(&path, false, true));
+        assert!(missing.is_err());
+        assert!(!path.exists());
+        snapshot.compare(&path, false, false);
+        assert_eq!(std::fs::read_to_string(&path)?, snapshot.to_json());
+        snapshot.compare(&path, false, true);
+
+        sqlx::query("UPDATE notes SET body = 'c' WHERE id = 2")
+            .execute(&db)
+            .await?;
+        let changed = Snapshot::table(&db, "notes").await?.redact(&["created_at"]);
+        let panic = std::panic::catch_unwind(|| changed.compare(&path, false, false)).unwrap_err();
+        let message = panic.downcast_ref::<String>().unwrap();
+        assert!(message.contains("-     \"body\": \"b\",\n+     \"body\": \"c\","));
+
+        std::fs::remove_dir_all(path.parent().unwrap())?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_table_sorted_after_redaction() -> anyhow::Result<()> {
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect(crate::DATABASE_URL)
+            .await?;
+        sqlx::query("CREATE TABLE tokens (kind text, token text, uses integer)")
+            .execute(&db)
+            .await?;
+        sqlx::query("INSERT INTO tokens VALUES ('api', 'zz', 1), ('api', 'aa', 2)")
+            .execute(&db)
+            .await?;
+
+        let snapshot = Snapshot::table(&db, "tokens").await?.redact(&["token"]);
+        assert_eq!(
+            snapshot.rows(),
+            [
+                json!({ "kind": "api", "token": REDACTED, "uses": 1 }),
+                json!({ "kind": "api", "token": REDACTED, "uses": 2 }),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff() {
+        let expected = "a\nb\nc\nd\ne\nf\ng\nh\ni\n";
+        let actual = "a\nb\nc\nd\ne\nF\ng\nh\ni\nj\n";
+        assert_eq!(
+            diff(expected, actual),
+            "  c\n  d\n  e\n- f\n+ F\n  g\n  h\n  i\n+ j\n"
+        );
+        assert_eq!(
+            Snapshot::from_rows(&[json!({ "b": 1, "a": { "d": 2, "c": 3 } })])
+                .unwrap()
+                .to_json(),
+            "[\n  {\n    \"a\": {\n      \"c\": 3,\n      \"d\": 2\n    },\n    \"b\": 1\n  }\n]\n"
+        );
+    }
+}